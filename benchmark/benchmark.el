@@ -102,6 +102,25 @@
          (stddev (sqrt variance)))
     (list :mean mean :median median :stddev stddev :min min :max max :n n)))
 
+(defvar tramp-rpc-benchmark-payload-sizes nil
+  "Alist of (NAME . BYTES): the response size of payload benchmarks.")
+
+(defconst tramp-rpc-benchmark--names-count 5000
+  "Number of entries in the directory listed by the `dir.list' benchmarks.")
+
+(defun tramp-rpc-benchmark--response-bytes (func)
+  "Call FUNC and return the size of the largest response frame it read."
+  (let* ((largest 0)
+         (measure (lambda (orig buffer start)
+                    (prog1 (funcall orig buffer start)
+                      (with-current-buffer buffer
+                        (setq largest (max largest (- (point) start))))))))
+    (advice-add 'tramp-rpc-protocol-decode-response :around measure)
+    (unwind-protect
+        (funcall func)
+      (advice-remove 'tramp-rpc-protocol-decode-response measure))
+    largest))
+
 (defun tramp-rpc-benchmark--record (name method times)
   "Record benchmark TIMES for NAME and METHOD."
   (let ((existing (assoc name tramp-rpc-benchmark-results)))
@@ -170,7 +189,18 @@
             (write-region (point-min) (point-max) nested-file))
           (with-temp-buffer
             (insert "((nil . ((fill-column . 80))))\n")
-            (write-region (point-min) (point-max) dir-locals-file)))))))
+            (write-region (point-min) (point-max) dir-locals-file))))
+      ;; Create a large directory only for the payload size benchmarks.
+      (when (or (member "dir-list-names-binary" selected)
+                (member "dir-list-names-auto" selected)
+                (member "dir-list-attrs-serial" selected)
+                (member "dir-list-attrs-parallel" selected))
+        (let ((default-directory (tramp-rpc-benchmark--make-path method "names/")))
+          (make-directory default-directory t)
+          (process-file
+           "sh" nil nil nil "-c"
+           (format "i=0; while [ $i -lt %d ]; do : > entry-$i.txt; i=$((i+1)); done"
+                   tramp-rpc-benchmark--names-count)))))))
 
 (defun tramp-rpc-benchmark--teardown (method)
   "Clean up test environment for METHOD."
@@ -319,6 +349,60 @@ Simulates what magit does: multiple different git commands in one batch."
            ("file.writable" . ((path . ,localname)))
            ("dir.list" . ((path . ,localname) (include_attrs . :msgpack-false)))))))))
 
+(defun tramp-rpc-benchmark--dir-list-names (method names-as)
+  "Benchmark `dir.list' of the names fixture for METHOD with NAMES-AS.
+Also records the response size in `tramp-rpc-benchmark-payload-sizes'."
+  (unless (string= method "rpc")
+    (error "Payload size benchmarks only available for RPC method"))
+  (let ((dir (tramp-rpc-benchmark--make-path method "names")))
+    (with-parsed-tramp-file-name dir nil
+      (let* ((times nil)
+             (bytes (tramp-rpc-benchmark--response-bytes
+                     (lambda ()
+                       (setq times
+                             (tramp-rpc-benchmark--time
+                              (tramp-rpc--call
+                               v "dir.list"
+                               `((path . ,localname)
+                                 (include_attrs . :msgpack-false)
+                                 (names_as . ,names-as)))))))))
+        (setf (alist-get names-as tramp-rpc-benchmark-payload-sizes
+                         nil nil #'equal)
+              bytes)
+        times))))
+
+(defun tramp-rpc-benchmark--dir-list-names-binary (method)
+  "Benchmark `dir.list' with binary names for METHOD (RPC only)."
+  (tramp-rpc-benchmark--dir-list-names method "binary"))
+
+(defun tramp-rpc-benchmark--dir-list-names-auto (method)
+  "Benchmark `dir.list' with UTF-8 names as strings for METHOD (RPC only)."
+  (tramp-rpc-benchmark--dir-list-names method "auto"))
+
+(defun tramp-rpc-benchmark--dir-list-attrs (method threads)
+  "Benchmark `dir.list' with attributes of the names fixture for METHOD.
+THREADS is passed as `stat_threads': 1 stats the entries serially, 0
+lets the server spread them over several threads."
+  (unless (string= method "rpc")
+    (error "Parallel stat benchmarks only available for RPC method"))
+  (let ((dir (tramp-rpc-benchmark--make-path method "names")))
+    (with-parsed-tramp-file-name dir nil
+      (tramp-rpc-benchmark--time
+       (tramp-rpc--call
+        v "dir.list"
+        `((path . ,localname)
+          (include_attrs . t)
+          (no_cache . t)
+          (stat_threads . ,threads)))))))
+
+(defun tramp-rpc-benchmark--dir-list-attrs-serial (method)
+  "Benchmark `dir.list' with attributes stat'ed serially for METHOD (RPC only)."
+  (tramp-rpc-benchmark--dir-list-attrs method 1))
+
+(defun tramp-rpc-benchmark--dir-list-attrs-parallel (method)
+  "Benchmark `dir.list' with attributes stat'ed in parallel for METHOD (RPC only)."
+  (tramp-rpc-benchmark--dir-list-attrs method 0))
+
 (defun tramp-rpc-benchmark--sequential-mixed-ops (method)
   "Benchmark sequential mixed operations for METHOD.
 Same operations as batch-mixed-ops but done one at a time."
@@ -378,8 +462,13 @@ Same operations as batch-mixed-ops but done one at a time."
 
 (defconst tramp-rpc-benchmark--rpc-only-tests
   '(("batch-stat"         . tramp-rpc-benchmark--multiple-stats-batched)
-    ("batch-mixed-ops"    . tramp-rpc-benchmark--batch-mixed-ops))
-  "Alist of RPC-only benchmark tests for batch operations.")
+    ("batch-mixed-ops"    . tramp-rpc-benchmark--batch-mixed-ops)
+    ("dir-list-names-binary" . tramp-rpc-benchmark--dir-list-names-binary)
+    ("dir-list-names-auto" . tramp-rpc-benchmark--dir-list-names-auto)
+    ("dir-list-attrs-serial" . tramp-rpc-benchmark--dir-list-attrs-serial)
+    ("dir-list-attrs-parallel" . tramp-rpc-benchmark--dir-list-attrs-parallel))
+  "Alist of RPC-only benchmark tests for batch operations, payload size
+and parallel stats.")
 
 (defun tramp-rpc-benchmark--resolve-test-selection (selected tests)
   "Resolve SELECTED benchmark names from TESTS.
@@ -434,7 +523,12 @@ SELECTED accepts nil (all), :all, or a list of \"sshx\", \"ssh\", and/or \"rpc\"
   (message "Warming up connection for %s..." method)
   (ignore (file-exists-p (tramp-rpc-benchmark--make-path method "file0.txt")))
   
-  (dolist (test tests)
+  (dolist (test (if (string= method "rpc")
+                    tests
+                  (seq-remove (lambda (test)
+                                (assoc (car test)
+                                       tramp-rpc-benchmark--rpc-only-tests))
+                              tests)))
     (let* ((name (car test))
            (func (cdr test))
            (is-connection-test (string= name "connection-setup")))
@@ -529,6 +623,36 @@ SELECTED accepts nil (all), :all, or a list of \"sshx\", \"ssh\", and/or \"rpc\"
                           (tramp-rpc-benchmark--format-time batch-median)
                           speedup)))))
     
+    ;; Compare response sizes of binary and string names
+    (let ((binary (alist-get "binary" tramp-rpc-benchmark-payload-sizes
+                             nil nil #'equal))
+          (auto (alist-get "auto" tramp-rpc-benchmark-payload-sizes
+                           nil nil #'equal)))
+      (when (and binary auto (> binary 0))
+        (insert "\n\nPayload Size (RPC only)\n")
+        (insert "-----------------------\n")
+        (insert (format "dir.list of %d entries:  binary=%d bytes  auto=%d bytes  saved=%.1f%%\n"
+                        tramp-rpc-benchmark--names-count binary auto
+                        (* 100.0 (/ (float (- binary auto)) binary))))))
+
+    ;; Compare serial and parallel stats of a large listing
+    (let ((serial (cdr (assoc "rpc" (cdr (assoc "dir-list-attrs-serial"
+                                                 tramp-rpc-benchmark-results)))))
+          (parallel (cdr (assoc "rpc" (cdr (assoc "dir-list-attrs-parallel"
+                                                   tramp-rpc-benchmark-results))))))
+      (when (and serial parallel)
+        (let ((serial-median (plist-get (tramp-rpc-benchmark--stats serial) :median))
+              (parallel-median (plist-get (tramp-rpc-benchmark--stats parallel) :median)))
+          (insert "\n\nParallel Stats (RPC only)\n")
+          (insert "-------------------------\n")
+          (insert (format "dir.list of %d entries with attributes:  serial=%s  parallel=%s  speedup=%.1fx\n"
+                          tramp-rpc-benchmark--names-count
+                          (tramp-rpc-benchmark--format-time serial-median)
+                          (tramp-rpc-benchmark--format-time parallel-median)
+                          (if (> parallel-median 0)
+                              (/ serial-median parallel-median)
+                            0.0))))))
+
     (insert "\n\nDetailed Statistics\n")
     (insert "-------------------\n\n")
     
@@ -570,12 +694,14 @@ Batch mode example:
   (interactive)
   (let* ((selected-tests
           (tramp-rpc-benchmark--resolve-test-selection
-           benchmark-names tramp-rpc-benchmark--tests))
+           benchmark-names (append tramp-rpc-benchmark--tests
+                                   tramp-rpc-benchmark--rpc-only-tests)))
          (selected-methods
           (tramp-rpc-benchmark--resolve-method-selection methods)))
     (when (null selected-tests)
       (error "No benchmarks selected"))
-    (setq tramp-rpc-benchmark-results nil)
+    (setq tramp-rpc-benchmark-results nil
+          tramp-rpc-benchmark-payload-sizes nil)
     (message "Starting TRAMP benchmarks on %s..." tramp-rpc-benchmark-host)
     (message "Methods: %s" (mapconcat #'identity selected-methods ", "))
     (message "Tests: %s" (mapconcat #'car selected-tests ", "))
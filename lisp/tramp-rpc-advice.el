@@ -28,6 +28,7 @@
 ;; - eglot--cmd (bypass shell wrapping for RPC connections)
 ;; - magit-start-process (force pipe mode when INPUT will be piped to the process)
 ;; - vc-dir-refresh (clean up stale processes)
+;; - file-expand-wildcards (expand globs with one RPC on the remote host)
 
 ;;; Code:
 
@@ -48,6 +49,8 @@
 (declare-function tramp-rpc--debug "tramp-rpc")
 (declare-function tramp-rpc--call "tramp-rpc")
 (declare-function tramp-rpc--call-async "tramp-rpc")
+(declare-function tramp-rpc--path-to-bin "tramp-rpc")
+(declare-function tramp-rpc--decode-string "tramp-rpc")
 (declare-function tramp-rpc-file-name-p "tramp-rpc")
 
 ;; Variables from tramp-rpc.el / tramp-rpc-process.el
@@ -225,6 +228,28 @@ It will be added to `signal-process-functions'."
        (message "tramp-rpc: Error signaling process: %s" err)
        -1))))
 
+(defun tramp-rpc-handle-interrupt-process (&optional process _current-group)
+  "Handler for `interrupt-process' of TRAMP-RPC PTY processes.
+Signal the remote terminal's foreground process group directly, so
+the interrupt arrives even when a program has put the terminal in raw
+mode.  It will be added to `interrupt-process-functions'."
+  (let ((proc (cond ((processp process) process)
+                    ((bufferp process) (get-buffer-process process))
+                    ((stringp process) (or (get-process process)
+                                           (get-buffer-process process)))
+                    ((null process) (get-buffer-process (current-buffer))))))
+    (when-let* (((processp proc))
+                ((process-get proc :tramp-rpc-pty))
+                (pid (process-get proc :tramp-rpc-pid))
+                (vec (process-get proc :tramp-rpc-vec)))
+      (condition-case err
+          (progn
+            (tramp-rpc--call vec "process.interrupt_pty" `((pid . ,pid)))
+            t)
+        (error
+         (message "tramp-rpc: Error interrupting process: %s" err)
+         nil)))))
+
 ;; ============================================================================
 ;; Process metadata handlers
 ;; ============================================================================
@@ -481,6 +506,41 @@ exited (remote side finished), delete it so the refresh can proceed."
       (ignore-errors (delete-process proc))))
   (tramp-run-real-handler 'vc-dir-refresh nil))
 
+;; ============================================================================
+;; file-expand-wildcards: server-side glob expansion
+;; ============================================================================
+
+;; `file-expand-wildcards' is not a magic file name operation, so by default
+;; it lists every directory the pattern passes through and matches the names
+;; in Lisp.  `file.expand_wildcards' does it in one round-trip, with the
+;; remote shell's glob semantics (braces, `**', hidden files).
+
+(defun tramp-rpc--file-expand-wildcards-file-name-for-operation
+    (_operation pattern &rest _args)
+  "Helper function for `file-expand-wildcards' handler."
+  (if (stringp pattern) (expand-file-name pattern) ""))
+
+(defun tramp-rpc-handle-file-expand-wildcards (pattern &optional full regexp)
+  "Handler for `file-expand-wildcards' for TRAMP-RPC files.
+Expand PATTERN on the remote host.  Like the original, return absolute
+names when PATTERN is absolute or FULL is non-nil, and names relative to
+`default-directory' otherwise.  REGEXP patterns are left to the original."
+  (if regexp
+      (tramp-run-real-handler
+       #'file-expand-wildcards (list pattern full regexp))
+    (let ((expanded (expand-file-name pattern)))
+      (with-parsed-tramp-file-name expanded nil
+        (let* ((result (tramp-rpc--call
+                        v "file.expand_wildcards"
+                        `((pattern . ,(tramp-rpc--path-to-bin localname)))))
+               (names (mapcar (lambda (path)
+                                (tramp-make-tramp-file-name
+                                 v (tramp-rpc--decode-string path)))
+                              (alist-get 'paths result))))
+          (if (or full (file-name-absolute-p pattern))
+              names
+            (mapcar #'file-relative-name names)))))))
+
 ;; ============================================================================
 ;; Install and uninstall handler
 ;; ============================================================================
@@ -500,6 +560,7 @@ exited (remote side finished), delete it so the refresh can proceed."
   ;; This must be before `tramp-signal-process'.  Since tramp.el is
   ;; required, this is guaranteed.
   (add-hook 'signal-process-functions #'tramp-rpc-handle-signal-process)
+  (add-hook 'interrupt-process-functions #'tramp-rpc-handle-interrupt-process)
   (with-eval-after-load 'tramp-rpc
     (tramp-add-external-operation
      'process-status
@@ -559,7 +620,11 @@ exited (remote side finished), delete it so the refresh can proceed."
     (tramp-add-external-operation
      'vc-dir-refresh
      #'tramp-rpc-handle-vc-dir-refresh 'tramp-rpc
-     #'tramp-rpc--vc-dir-refresh-file-name-for-operation)))
+     #'tramp-rpc--vc-dir-refresh-file-name-for-operation)
+    (tramp-add-external-operation
+     'file-expand-wildcards
+     #'tramp-rpc-handle-file-expand-wildcards 'tramp-rpc
+     #'tramp-rpc--file-expand-wildcards-file-name-for-operation)))
 
 (defun tramp-rpc-handler-remove ()
   "Remove all process handler installed by tramp-rpc."
@@ -567,6 +632,8 @@ exited (remote side finished), delete it so the refresh can proceed."
   (tramp-remove-external-operation 'process-send-region 'tramp-rpc)
   (tramp-remove-external-operation 'process-send-eof 'tramp-rpc)
   (remove-hook 'signal-process-functions #'tramp-rpc-handle-signal-process)
+  (remove-hook 'interrupt-process-functions
+               #'tramp-rpc-handle-interrupt-process)
   (tramp-remove-external-operation 'process-status 'tramp-rpc)
   (tramp-remove-external-operation 'process-exit-status 'tramp-rpc)
   (tramp-remove-external-operation 'process-command 'tramp-rpc)
@@ -581,7 +648,8 @@ exited (remote side finished), delete it so the refresh can proceed."
      #'tramp-rpc-handle-python-shell--tramp-with-environment-compat))
   (tramp-remove-external-operation 'eglot--cmd 'tramp-rpc)
   (tramp-remove-external-operation 'magit-start-process 'tramp-rpc)
-  (tramp-remove-external-operation 'vc-dir-refresh 'tramp-rpc))
+  (tramp-remove-external-operation 'vc-dir-refresh 'tramp-rpc)
+  (tramp-remove-external-operation 'file-expand-wildcards 'tramp-rpc))
 
 (defcustom tramp-rpc-install-handler-on-load t
   "Whether to install process handler when tramp-rpc-advice is loaded.
@@ -39,11 +39,13 @@
 ;; Functions from tramp-rpc.el
 (declare-function tramp-rpc--debug "tramp-rpc")
 (declare-function tramp-rpc--call "tramp-rpc")
+(declare-function tramp-rpc--capability-available-p "tramp-rpc")
 (declare-function tramp-rpc--call-batch "tramp-rpc")
 (declare-function tramp-rpc--connection-key "tramp-rpc")
 (declare-function tramp-rpc--decode-output "tramp-rpc")
 (declare-function tramp-rpc--decode-string "tramp-rpc")
 (declare-function tramp-rpc--encode-path "tramp-rpc")
+(declare-function tramp-rpc--handle-password-request "tramp-rpc")
 (declare-function tramp-rpc--convert-file-attributes "tramp-rpc")
 (declare-function tramp-rpc-file-name-p "tramp-rpc")
 (declare-function tramp-rpc--canonical-watch-active-p "tramp-rpc")
@@ -336,6 +338,10 @@ PARAMS is the notification parameters."
   (cond
    ((string= method "fs.events")
     (tramp-rpc--handle-fs-events process params))
+   ((string= method "fs.resync")
+    (tramp-rpc--handle-fs-resync process params))
+   ((string= method "auth.password_request")
+    (tramp-rpc--handle-password-request process params))
    (t
     (tramp-rpc--debug "Unknown notification: %s" method))))
 
@@ -444,6 +450,25 @@ DIRECTORY itself returns the empty string.  Descendants can contain slashes."
                         (tramp-rpc--invalidate-event-path path1)))
                     (tramp-rpc--file-notify-dispatch action path path1 cookie)))))))))))
 
+(defun tramp-rpc--handle-fs-resync (process params)
+  "Handle an fs.resync notification from PROCESS with PARAMS.
+The server sends this instead of fs.events when the events would exceed
+its notification budget.  Anything below each of the listed roots may
+have changed, so their caches are dropped wholesale and file-notify
+watchers are told that the roots changed."
+  (let ((roots (alist-get 'roots params)))
+    (tramp-rpc--debug "fs.resync: %d roots" (length roots))
+    (when-let* ((vec (process-get process :tramp-rpc-vec)))
+      (unless tramp-rpc--suppress-fs-notifications
+        (tramp-rpc-magit--clear-status-cache))
+      (dolist (root roots)
+        (when-let* ((path (tramp-rpc--fs-event-path vec `((path . ,root)) 'path)))
+          (unless tramp-rpc--suppress-fs-notifications
+            (dolist (candidate (cons path (tramp-rpc--watched-directory-alias-paths
+                                           path)))
+              (tramp-rpc--invalidate-cache-for-subtree candidate)))
+          (tramp-rpc--file-notify-dispatch "changed" path))))))
+
 (defun tramp-rpc-watch-directory (directory &optional recursive)
   "Start watching DIRECTORY for filesystem changes.
 When RECURSIVE is non-nil, watch subdirectories too."
@@ -1281,7 +1306,9 @@ Sends all git commands magit will need via a single
 commands.run_parallel RPC call, then stores the results directly
 as the process-file cache.  Also fetches ancestor markers."
   (when (and (file-remote-p directory)
-             (tramp-rpc-file-name-p directory))
+             (tramp-rpc-file-name-p directory)
+             (tramp-rpc--capability-available-p
+              (tramp-dissect-file-name directory) 'git))
     ;; Suppress fs.events cache handling during prefetch.  The git commands
     ;; we run on the server touch .git/index etc., triggering inotify events
     ;; that would clear the cache we're building.
@@ -45,6 +45,7 @@
 (declare-function tramp-rpc--debug "tramp-rpc")
 (declare-function tramp-rpc--ensure-connection "tramp-rpc")
 (declare-function tramp-rpc--call "tramp-rpc")
+(declare-function tramp-rpc--capability-available-p "tramp-rpc")
 (declare-function tramp-rpc--call-fast "tramp-rpc")
 (declare-function tramp-rpc--call-async "tramp-rpc")
 (declare-function tramp-rpc--get-direnv-environment "tramp-rpc")
@@ -202,10 +203,12 @@ arrive as concurrent RPC requests."
 ;; Async Callback-based Process Reading (for LSP and interactive processes)
 ;; ============================================================================
 
-(defun tramp-rpc--start-async-read (local-process)
+(defun tramp-rpc--start-async-read (local-process &optional immediate)
   "Start an async read loop for LOCAL-PROCESS.
 Sends a blocking read request; when response arrives, delivers output
-and chains another read. This provides fast async I/O for LSP servers."
+and chains another read. This provides fast async I/O for LSP servers.
+With IMMEDIATE, the server does not wait for output, as when the last
+read reported more pending."
   (when (and (processp local-process)
              (process-live-p local-process)
              (gethash local-process tramp-rpc--async-processes))
@@ -217,7 +220,8 @@ and chains another read. This provides fast async I/O for LSP servers."
         ;; Send async read request with blocking timeout on server
         (tramp-rpc--call-async
          vec "process.read"
-         `((pid . ,pid) (timeout_ms . ,tramp-rpc-async-read-timeout-ms))
+         `((pid . ,pid)
+           (timeout_ms . ,(if immediate 0 tramp-rpc-async-read-timeout-ms)))
          (lambda (response)
            (tramp-rpc--debug "ASYNC-READ callback invoked for pid=%s" pid)
            (tramp-rpc--handle-async-read-response local-process response)))))))
@@ -314,7 +318,9 @@ RESPONSE is the decoded RPC response plist."
                            (tramp-rpc--decode-output
                             s (alist-get 'stderr_encoding result))))
                  (exited (alist-get 'exited result))
-                 (exit-code (alist-get 'exit_code result)))
+                 (exit-code (alist-get 'exit_code result))
+                 (pending (or (eq (alist-get 'stdout_pending result) t)
+                              (eq (alist-get 'stderr_pending result) t))))
 
             (tramp-rpc--debug "ASYNC-READ response: stdout=%s stderr=%s exited=%s"
                              (if stdout (length stdout) "nil")
@@ -342,8 +348,10 @@ RESPONSE is the decoded RPC response plist."
                 ;; loops that poll `process-live-p' can observe a stale live
                 ;; process and run one extra iteration.
                 (tramp-rpc--handle-process-exit local-process exit-code)
-              ;; Chain another read - use run-at-time to avoid stack overflow
-              (run-at-time 0 nil #'tramp-rpc--start-async-read local-process)))
+              ;; Chain another read - use run-at-time to avoid stack overflow.
+              ;; When output is still pending, fetch it without a long poll.
+              (run-at-time 0 nil #'tramp-rpc--start-async-read
+                           local-process pending)))
         (error
          (tramp-rpc--debug "ASYNC-READ-ERROR: %S" err)
          ;; On error, clean up
@@ -696,6 +704,8 @@ interactive terminal use.  Otherwise, uses the RPC-based PTY implementation."
       (tramp-rpc--make-direct-ssh-pty-process
        vec name buffer command coding noquery filter sentinel localname direnv-env)
     ;; Use RPC-based PTY
+    (unless (tramp-rpc--capability-available-p vec 'pty)
+      (tramp-error vec 'file-error "No pty support on the remote host"))
     (tramp-rpc--make-rpc-pty-process
      vec name buffer command coding noquery filter sentinel localname direnv-env)))
 
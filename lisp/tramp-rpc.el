@@ -454,6 +454,29 @@ shape before passing the value to `sudo -S'."
       (when (process-live-p process)
         (delete-process process)))))
 
+(defun tramp-rpc--handle-password-request (process params)
+  "Answer an auth.password_request notification from PROCESS.
+PARAMS carries the request `id'.  The server asks when a
+`process.run_sudo' (or a sudo file operation) needs the sudo
+password.  The password is read from a timer, outside the process
+filter, and sent back with auth.password_reply; quitting the prompt
+cancels the request."
+  (when-let* ((vec (process-get process :tramp-rpc-vec))
+              (id (alist-get 'id params)))
+    (run-at-time
+     0 nil
+     (lambda ()
+       (let ((password (condition-case nil
+                           (tramp-rpc--sudo-read-password
+                            vec (tramp-file-name-user vec))
+                         ((quit error) nil))))
+         (tramp-rpc--call-async
+          vec "auth.password_reply"
+          (if password
+              `((id . ,id) (password . ,password))
+            `((id . ,id) (cancel . t)))
+          #'ignore))))))
+
 (defun tramp-rpc--proxy-hop-string (vec)
   "Return VEC's hop string with its sudo rpc hop removed.
 For /rpc:gw|rpc:user@host|sudo:root@host:/path, returns \"rpc:gw|\".
@@ -690,14 +713,18 @@ Signals `remote-file-error' on compressed payload decode failures."
                           (format "Unsupported file.read compression: %s" compression))))))
       content)))
 
-(defun tramp-rpc--file-read-params (localname &optional force-uncompressed)
+(defun tramp-rpc--file-read-params (localname &optional force-uncompressed
+                                               allow-large)
   "Build params for `file.read' on LOCALNAME.
 When `tramp-rpc-compress-file-read' is non-nil, request compression unless
-FORCE-UNCOMPRESSED is non-nil."
+FORCE-UNCOMPRESSED is non-nil.  ALLOW-LARGE lifts the server's limit on
+whole-file reads, for reads the user asked for explicitly."
   (let ((params (tramp-rpc--encode-path localname)))
     (when (and tramp-rpc-compress-file-read
                (not force-uncompressed))
       (push '(compress . t) params))
+    (when allow-large
+      (push '(allow_large . t) params))
     params))
 
 ;; ============================================================================
@@ -2028,7 +2055,8 @@ ENCODING is ignored (kept for API compatibility)."
 
 (defun tramp-rpc--decode-filename (entry)
   "Get filename from directory ENTRY.
-With MessagePack, filenames come as raw bytes - decode to UTF-8."
+With MessagePack, filenames come as raw bytes - decode to UTF-8.
+With `names_as' \"auto\", names that are valid UTF-8 come as strings."
   (tramp-rpc--decode-string (alist-get 'name entry)))
 
 (defun tramp-rpc--path-to-bytes (path)
@@ -2600,7 +2628,8 @@ so a single RPC can both validate and list the directory."
                       (tramp-rpc--call v "dir.list"
                                        (append (tramp-rpc--encode-path localname)
                                                '((include_attrs . :msgpack-false)
-                                                 (include_hidden . t))))))))
+                                                 (include_hidden . t)
+                                                 (names_as . "auto"))))))))
     (when match
       (setq result (cl-remove-if-not
                     (lambda (name) (string-match-p match name))
@@ -2620,7 +2649,8 @@ so a single RPC can both validate and list the directory."
     (let* ((result (tramp-rpc--call v "dir.list"
                                     (append (tramp-rpc--encode-path localname)
                                             '((include_attrs . t)
-                                              (include_hidden . t)))))
+                                              (include_hidden . t)
+                                              (names_as . "auto")))))
            (entries (mapcar
                      (lambda (entry)
                        (let* ((name (tramp-rpc--decode-filename entry))
@@ -2660,13 +2690,15 @@ so a single RPC can both validate and list the directory."
 	       (append (tramp-rpc--call v "dir.list"
 				       (append (tramp-rpc--encode-path localname)
 					       '((include_attrs . :msgpack-false)
-                                                 (include_hidden . t))))
+                                                 (include_hidden . t)
+                                                 (names_as . "auto"))))
 		       nil)))
-          ;; Build list of names with trailing / for directories
+          ;; Build list of names with trailing / for directories,
+          ;; including symlinks that resolve to one
           (mapcar (lambda (entry)
-                    (let ((name (tramp-rpc--decode-filename entry))
-                          (file-type (alist-get 'type entry)))
-                      (if (equal file-type "directory")
+                    (let ((name (tramp-rpc--decode-filename entry)))
+                      (if (or (equal (alist-get 'type entry) "directory")
+                              (equal (alist-get 'target_type entry) "directory"))
                           (concat name "/")
 			name)))
                   entries))))))
@@ -2838,6 +2870,9 @@ network round-trip."
    ((eql os-errno 21) ; EISDIR
     (signal 'file-error
             (tramp-rpc--error-args operation "Is a directory" message filename)))
+   ((eql os-errno 122) ; EDQUOT
+    (signal 'file-error
+            (tramp-rpc--error-args operation "Disk quota exceeded" message filename)))
    ((eql os-errno 40) ; ELOOP
     (signal 'file-error
             (tramp-rpc--error-args
@@ -2864,6 +2899,17 @@ network round-trip."
       (tramp-rpc--signal-batch-error operation filename result)
     result))
 
+(defun tramp-rpc--batch-follow-stat (filename result)
+  "Return batched following `file.stat' RESULT for FILENAME, or nil.
+The server reports a dangling symlink as a file-not-found error carrying
+the link's own attributes, where a missing file just returns nil; both
+mean there is nothing to follow to."
+  (if (and (tramp-rpc--batch-error-p result)
+           (eql (plist-get result :error)
+                tramp-rpc-protocol-error-file-not-found))
+      nil
+    (tramp-rpc--batch-result-or-signal "file.stat" filename result)))
+
 (cl-defun tramp-rpc--copy-file-same-remote
     (filename newname ok-if-already-exists keep-time preserve-permissions)
   "Copy FILENAME to NEWNAME on one TRAMP-RPC remote with fewer round-trips."
@@ -3007,16 +3053,16 @@ copy options.  Fall back to the generic TRAMP handler for cross-remote copies."
                                   (_ param)))
                               (tramp-rpc--encode-path symlink-dest-localname))))
                   (tramp-rpc-clear-all-caches))
-              (let* ((source-stat (tramp-rpc--batch-result-or-signal
-                                    "file.stat" dirname source-stat-result))
+              (let* ((source-stat (tramp-rpc--batch-follow-stat
+                                    dirname source-stat-result))
                      (actual-dest-lstat
                       (tramp-rpc--batch-result-or-signal
                        "file.stat" actual-dest actual-dest-lstat-result))
                      (actual-dest-stat
-                      (tramp-rpc--batch-result-or-signal
-                       "file.stat" actual-dest actual-dest-stat-result))
-                     (parent-stat (tramp-rpc--batch-result-or-signal
-                                   "file.stat" parent parent-stat-result))
+                      (tramp-rpc--batch-follow-stat
+                       actual-dest actual-dest-stat-result))
+                     (parent-stat (tramp-rpc--batch-follow-stat
+                                   parent parent-stat-result))
                      (source-type (tramp-rpc--stat-type source-stat))
                      (actual-dest-type (tramp-rpc--stat-type actual-dest-stat))
                      (parent-type (tramp-rpc--stat-type parent-stat)))
@@ -4140,7 +4186,8 @@ round-trips for the common non-VISIT case."
     (let ((start (point))
           result)
       (with-parsed-tramp-file-name filename nil
-        (let* ((params (tramp-rpc--file-read-params localname)))
+        ;; Emacs has already applied `large-file-warning-threshold'.
+        (let* ((params (tramp-rpc--file-read-params localname nil t)))
           (when beg
             (push `(offset . ,beg) params))
           (when end
@@ -4160,7 +4207,7 @@ round-trips for the common non-VISIT case."
 (defun tramp-rpc-handle-file-local-copy (filename)
   "Create a local copy of remote FILENAME using RPC."
   (tramp-skeleton-file-local-copy filename
-    (let* ((params (tramp-rpc--file-read-params localname))
+    (let* ((params (tramp-rpc--file-read-params localname nil t))
            (result (tramp-rpc--call v "file.read" params))
            (content (tramp-rpc--extract-file-read-content result)))
       (with-temp-file tmpfile
@@ -4214,6 +4261,18 @@ round-trips for the common non-VISIT case."
             (tramp-rpc--cache-system-info
              vec (tramp-rpc--call vec "system.info" nil))))))
 
+(defun tramp-rpc--capability-available-p (vec capability)
+  "Return non-nil unless the server on VEC reports CAPABILITY as unavailable.
+CAPABILITY is a symbol naming an entry of the `capabilities' map in
+system.info, such as `watcher', `pty' or `git'.  Servers that predate
+capability probes are assumed to support everything."
+  (let* ((info (ignore-errors (tramp-rpc--system-info vec)))
+         (entry (and (listp info)
+                     (alist-get capability (alist-get 'capabilities info)))))
+    (not (and (consp entry)
+              (assq 'available entry)
+              (memq (alist-get 'available entry) '(nil :msgpack-false))))))
+
 (defun tramp-rpc-handle-get-home-directory (vec &optional user)
   "Return home directory for USER on remote host VEC using RPC.
 If USER is nil or matches the connection user, returns the current user's
@@ -4676,6 +4735,9 @@ DIRECTORY is the remote directory passed by `file-notify-add-watch'."
   ;; ensure the corresponding remote directory is watched.
   (require 'filenotify)
   (with-parsed-tramp-file-name directory nil
+    (unless (tramp-rpc--capability-available-p v 'watcher)
+      (tramp-error v 'file-notify-error
+                   "No file notification support on the remote host"))
     (let* ((watch-key (format "%s:%s" (tramp-rpc--connection-key-string v)
                               localname))
            (entry (gethash watch-key tramp-rpc--file-notify-watch-counts))
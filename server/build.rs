@@ -0,0 +1,66 @@
+//! Embed build metadata for `system.info`: the git commit, the build time,
+//! the rustc version and the target triple.
+//!
+//! Builds outside a git checkout (release tarballs, Nix) can pass the
+//! commit in `TRAMP_RPC_GIT_COMMIT`.  `SOURCE_DATE_EPOCH` replaces the
+//! build time for reproducible builds.
+
+use std::process::Command;
+
+fn main() {
+    println!("cargo:rerun-if-env-changed=TRAMP_RPC_GIT_COMMIT");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+
+    let commit = std::env::var("TRAMP_RPC_GIT_COMMIT")
+        .ok()
+        .filter(|commit| !commit.is_empty())
+        .or_else(git_commit)
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=TRAMP_RPC_GIT_COMMIT={}", commit);
+
+    let timestamp = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.trim().parse::<u64>().ok())
+        .unwrap_or_else(|| {
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs())
+        });
+    println!("cargo:rustc-env=TRAMP_RPC_BUILD_TIMESTAMP={}", timestamp);
+
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let rustc_version =
+        output(Command::new(rustc).arg("--version")).unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=TRAMP_RPC_RUSTC_VERSION={}", rustc_version);
+
+    let target = std::env::var("TARGET").unwrap_or_default();
+    println!("cargo:rustc-env=TRAMP_RPC_TARGET={}", target);
+}
+
+/// The commit checked out.
+fn git_commit() -> Option<String> {
+    // Rebuild when HEAD moves, whether to another branch or another commit.
+    // Files that do not exist are left out, as cargo would rerun every time.
+    if let Some(git_dir) = output(Command::new("git").args(["rev-parse", "--git-dir"])) {
+        let git_dir = std::path::Path::new(&git_dir);
+        let head_ref = output(Command::new("git").args(["symbolic-ref", "-q", "HEAD"]));
+        let watched = ["HEAD", "packed-refs"]
+            .into_iter()
+            .map(|name| git_dir.join(name))
+            .chain(head_ref.map(|head_ref| git_dir.join(head_ref)));
+        for path in watched.filter(|path| path.exists()) {
+            println!("cargo:rerun-if-changed={}", path.display());
+        }
+    }
+    output(Command::new("git").args(["rev-parse", "HEAD"]))
+}
+
+/// The trimmed stdout of `command`, if it ran and succeeded.
+fn output(command: &mut Command) -> Option<String> {
+    let output = command.output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let text = String::from_utf8(output.stdout).ok()?;
+    Some(text.trim().to_string()).filter(|text| !text.is_empty())
+}
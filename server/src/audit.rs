@@ -0,0 +1,381 @@
+//! Opt-in audit log of mutating operations.
+//!
+//! Enabled with `--audit-log PATH` / `TRAMP_RPC_AUDIT_LOG` at startup or with
+//! `system.set_audit_log` at runtime.  Every request listed in
+//! [`crate::mutating`] appends one line of space-separated `key=value` fields:
+//!
+//! ```text
+//! time=1760000000.123 method=file.rename src="/home/u/a" dest="/home/u/b" code=0
+//! ```
+//!
+//! Strings are double-quoted with `\"`, `\\`, `\n`, `\r` and `\t` escapes;
+//! other control characters and bytes that are not valid UTF-8 are written
+//! as `\xNN`, so the original bytes can always be recovered.  `code` is 0 on
+//! success and the RPC error code otherwise.
+//!
+//! Lines are handed to a background task through a bounded channel.  The
+//! operation itself never waits for the log or fails because of it; lines
+//! that cannot be queued or written are counted as failures in
+//! `system.stats`.
+
+use crate::msgpack_map;
+use crate::protocol::{IntoValue, RpcError};
+use rmpv::Value;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+
+/// Lines buffered before new ones are dropped (and counted as failures).
+const QUEUE_CAPACITY: usize = 1024;
+
+/// Parameters holding paths, in the order they are logged.
+const PATH_PARAMS: &[&str] = &[
+    "path",
+    "src",
+    "dest",
+    "destination",
+    "output",
+    "link_path",
+    "target",
+    "cwd",
+];
+
+/// Result fields holding the number of bytes written.
+const BYTE_RESULTS: &[&str] = &["written", "copied", "size", "bytes"];
+
+struct Sink {
+    path: PathBuf,
+    tx: mpsc::Sender<Vec<u8>>,
+    writer: tokio::task::JoinHandle<()>,
+}
+
+static SINK: Mutex<Option<Sink>> = Mutex::new(None);
+static WRITTEN: AtomicU64 = AtomicU64::new(0);
+static FAILURES: AtomicU64 = AtomicU64::new(0);
+
+fn sink() -> std::sync::MutexGuard<'static, Option<Sink>> {
+    SINK.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Start logging to `path` (appending), replacing any previous log, or stop
+/// logging when `path` is `None`.  Must be called inside the tokio runtime.
+pub fn set_log(path: Option<&Path>) -> std::io::Result<()> {
+    let Some(path) = path else {
+        *sink() = None;
+        return Ok(());
+    };
+
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    let path = std::path::absolute(path)?;
+    let (tx, writer) = spawn_writer(file);
+    *sink() = Some(Sink { path, tx, writer });
+    Ok(())
+}
+
+/// Stop logging and wait until every queued line has been written.
+pub async fn close() {
+    let Some(Sink { tx, writer, .. }) = sink().take() else {
+        return;
+    };
+    drop(tx);
+    let _ = writer.await;
+}
+
+/// Append queued lines to `file` until every sender is gone.
+fn spawn_writer(file: std::fs::File) -> (mpsc::Sender<Vec<u8>>, tokio::task::JoinHandle<()>) {
+    let (tx, mut rx) = mpsc::channel::<Vec<u8>>(QUEUE_CAPACITY);
+    let writer = tokio::spawn(async move {
+        let mut file = tokio::fs::File::from_std(file);
+        while let Some(line) = rx.recv().await {
+            match file.write_all(&line).await {
+                Ok(()) => WRITTEN.fetch_add(1, Ordering::Relaxed),
+                Err(_) => FAILURES.fetch_add(1, Ordering::Relaxed),
+            };
+        }
+        let _ = file.flush().await;
+    });
+    (tx, writer)
+}
+
+/// Whether an audit log is configured.
+fn enabled() -> bool {
+    sink().is_some()
+}
+
+/// Counters for `system.stats`.
+pub fn stats() -> Value {
+    msgpack_map! {
+        "path" => sink()
+            .as_ref()
+            .map(|s| s.path.to_string_lossy().into_owned())
+            .into_value(),
+        "written" => WRITTEN.load(Ordering::Relaxed),
+        "failures" => FAILURES.load(Ordering::Relaxed)
+    }
+}
+
+/// Handle `system.set_audit_log {path}`; a nil path turns logging off.
+pub fn handle_set_log(params: Value) -> Result<Value, RpcError> {
+    #[derive(serde::Deserialize)]
+    struct Params {
+        #[serde(default, with = "optional_path")]
+        path: Option<Vec<u8>>,
+    }
+
+    mod optional_path {
+        use serde::{Deserialize, Deserializer};
+
+        pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<Vec<u8>>, D::Error>
+        where
+            D: Deserializer<'de>,
+        {
+            #[derive(Deserialize)]
+            struct Wrapped(#[serde(with = "crate::protocol::path_or_bytes")] Vec<u8>);
+            Ok(Option::<Wrapped>::deserialize(deserializer)?.map(|Wrapped(p)| p))
+        }
+    }
+
+    let params: Params =
+        crate::protocol::from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
+    let path = params
+        .path
+        .map(|p| crate::handlers::file::bytes_to_path(&p));
+    if let Some(path) = &path {
+        crate::jail::check(path)?;
+    }
+    set_log(path.as_deref()).map_err(RpcError::io_error)?;
+    Ok(stats())
+}
+
+/// A pending audit line for one request.
+pub struct Entry {
+    line: Vec<u8>,
+}
+
+/// Start an audit line for `method` if logging is on and the method
+/// mutates state.  Call [`Entry::finish`] with the outcome.
+pub fn begin(method: &str, params: &Value) -> Option<Entry> {
+    if !enabled() {
+        return None;
+    }
+    entry(method, params)
+}
+
+fn entry(method: &str, params: &Value) -> Option<Entry> {
+    crate::mutating::effect(method)?;
+
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let mut line = format!(
+        "time={}.{:03} method={}",
+        time.as_secs(),
+        time.subsec_millis(),
+        method
+    )
+    .into_bytes();
+
+    let fields = params.as_map().map(Vec::as_slice).unwrap_or_default();
+    let field = |name: &str| {
+        fields
+            .iter()
+            .find(|(k, _)| k.as_str() == Some(name))
+            .map(|(_, v)| v)
+    };
+
+    for &name in PATH_PARAMS {
+        if let Some(raw) = field(name).and_then(value_bytes) {
+            // Symlink targets are stored verbatim; everything else is
+            // logged as the absolute path it resolves to.
+            let logged = if name == "target" {
+                raw.to_vec()
+            } else {
+                resolve(raw)
+            };
+            push_field(&mut line, name, &logged);
+        }
+    }
+    if let Some(id) = field("id").and_then(Value::as_u64) {
+        line.extend_from_slice(format!(" id={}", id).as_bytes());
+    }
+    if let Some(cmd) = field("cmd").and_then(value_bytes) {
+        push_field(&mut line, "cmd", cmd);
+    }
+    if let Some(commands) = field("commands").and_then(Value::as_array) {
+        for command in commands {
+            let Some(entries) = command.as_map() else {
+                continue;
+            };
+            let get = |name: &str| {
+                entries
+                    .iter()
+                    .find(|(k, _)| k.as_str() == Some(name))
+                    .and_then(|(_, v)| value_bytes(v))
+            };
+            if let Some(cmd) = get("cmd") {
+                push_field(&mut line, "cmd", cmd);
+            }
+            if let Some(cwd) = get("cwd") {
+                push_field(&mut line, "cwd", &resolve(cwd));
+            }
+        }
+    }
+    if let Some(content) = field("content").and_then(value_bytes) {
+        line.extend_from_slice(format!(" content_bytes={}", content.len()).as_bytes());
+    }
+
+    Some(Entry { line })
+}
+
+impl Entry {
+    /// Append the outcome and queue the line without waiting.
+    pub fn finish(self, result: &Result<Value, RpcError>) {
+        let line = self.line(result);
+        let queued = sink().as_ref().is_some_and(|s| s.tx.try_send(line).is_ok());
+        if !queued {
+            FAILURES.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn line(mut self, result: &Result<Value, RpcError>) -> Vec<u8> {
+        let code = match result {
+            Ok(value) => {
+                let fields = value.as_map().map(Vec::as_slice).unwrap_or_default();
+                for (key, value) in fields {
+                    if let (Some(key), Some(bytes)) = (key.as_str(), value.as_u64())
+                        && BYTE_RESULTS.contains(&key)
+                    {
+                        self.line
+                            .extend_from_slice(format!(" bytes={}", bytes).as_bytes());
+                    }
+                }
+                0
+            }
+            Err(error) => error.code,
+        };
+        self.line
+            .extend_from_slice(format!(" code={}\n", code).as_bytes());
+        self.line
+    }
+}
+
+fn value_bytes(value: &Value) -> Option<&[u8]> {
+    match value {
+        Value::String(s) => Some(s.as_bytes()),
+        Value::Binary(b) => Some(b),
+        _ => None,
+    }
+}
+
+/// Absolute, symlink-free form of `raw`.  The final component is kept as
+/// is, since it may not exist (yet or any more) or may itself be the link
+/// being operated on.
+fn resolve(raw: &[u8]) -> Vec<u8> {
+    let path = crate::handlers::file::bytes_to_path(raw);
+    let path = std::path::absolute(&path).unwrap_or(path);
+    let resolved = match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => std::fs::canonicalize(parent)
+            .map(|p| p.join(name))
+            .unwrap_or(path),
+        _ => path,
+    };
+    resolved.as_os_str().as_bytes().to_vec()
+}
+
+fn push_field(line: &mut Vec<u8>, key: &str, value: &[u8]) {
+    line.push(b' ');
+    line.extend_from_slice(key.as_bytes());
+    line.push(b'=');
+    push_quoted(line, value);
+}
+
+/// Quote `bytes` losslessly; see the module docs for the escapes.
+fn push_quoted(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.push(b'"');
+    for chunk in bytes.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match c {
+                '"' => out.extend_from_slice(b"\\\""),
+                '\\' => out.extend_from_slice(b"\\\\"),
+                '\n' => out.extend_from_slice(b"\\n"),
+                '\r' => out.extend_from_slice(b"\\r"),
+                '\t' => out.extend_from_slice(b"\\t"),
+                c if c.is_ascii_control() => {
+                    out.extend_from_slice(format!("\\x{:02x}", c as u8).as_bytes())
+                }
+                c => out.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+            }
+        }
+        for byte in chunk.invalid() {
+            out.extend_from_slice(format!("\\x{:02x}", byte).as_bytes());
+        }
+    }
+    out.push(b'"');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn audit_quoting_is_lossless() {
+        let mut out = Vec::new();
+        push_quoted(&mut out, b"a \"b\"\\c\n\x01\xff\xfe\xc3\xa9");
+        assert_eq!(
+            out,
+            b"\"a \\\"b\\\"\\\\c\\n\\x01\\xff\\xfe\xc3\xa9\"".to_vec()
+        );
+    }
+
+    #[test]
+    fn audit_lines_record_paths_bytes_and_codes() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir_path = std::fs::canonicalize(dir.path()).unwrap();
+        let target = dir_path.join("f\u{e9}");
+        let params = msgpack_map! {
+            "path" => Value::Binary(target.as_os_str().as_bytes().to_vec()),
+            "content" => Value::Binary(b"hello".to_vec())
+        };
+        assert!(entry("file.read", &params).is_none());
+        assert!(entry("system.reexec", &params).is_some());
+        assert!(entry("system.install_binary", &params).is_some());
+
+        let written = entry("file.write", &params)
+            .unwrap()
+            .line(&Ok(msgpack_map! { "written" => 5 }));
+        let written = String::from_utf8(written).unwrap();
+        assert!(written.contains(" method=file.write "));
+        assert!(written.contains(&format!("path=\"{}\"", target.display())));
+        assert!(written.ends_with(" content_bytes=5 bytes=5 code=0\n"));
+
+        let failed = entry("file.delete", &params)
+            .unwrap()
+            .line(&Err(RpcError::file_not_found("x")));
+        let failed = String::from_utf8(failed).unwrap();
+        assert!(failed.ends_with(&format!(" code={}\n", RpcError::FILE_NOT_FOUND)));
+    }
+
+    #[tokio::test]
+    async fn audit_writer_appends_queued_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("audit.log");
+        std::fs::write(&log, b"old\n").unwrap();
+        let file = std::fs::OpenOptions::new().append(true).open(&log).unwrap();
+
+        let (tx, writer) = spawn_writer(file);
+        tx.send(b"one\n".to_vec()).await.unwrap();
+        tx.send(b"two\n".to_vec()).await.unwrap();
+        drop(tx);
+        writer.await.unwrap();
+
+        let contents = std::fs::read_to_string(&log).unwrap();
+        assert_eq!(contents, "old\none\ntwo\n");
+    }
+}
@@ -0,0 +1,141 @@
+//! Shared-secret authentication.
+//!
+//! When the server is started with a token (`--auth-token-file PATH` or
+//! `TRAMP_RPC_AUTH_TOKEN`), a connection must present it with
+//! `system.auth {token}` before any other method is served; everything else
+//! fails with AUTH_REQUIRED until then.  Authentication is per-connection
+//! state held in a [`Session`].
+//!
+//! Tokens are compared in constant time and every failure gets the same
+//! reply.  Failed attempts are serialized per connection and delayed with
+//! exponential backoff, so a client cannot brute-force the token quickly
+//! even by pipelining requests.
+
+use crate::protocol::{RpcError, from_value};
+use rmpv::Value;
+use serde::Deserialize;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::sync::Mutex;
+
+/// Delay after the first failed attempt; doubled for every further failure.
+const BASE_FAILURE_DELAY: Duration = Duration::from_millis(100);
+
+/// Upper bound on the delay between attempts.
+const MAX_FAILURE_DELAY: Duration = Duration::from_secs(5);
+
+/// Read a token from `path`, ignoring surrounding whitespace.
+pub fn token_from_file(path: &Path) -> std::io::Result<Vec<u8>> {
+    let contents = std::fs::read(path)?;
+    Ok(contents.trim_ascii().to_vec())
+}
+
+/// Compare two byte strings in time that depends only on their lengths.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    let mut diff = a.len() ^ b.len();
+    for i in 0..a.len().max(b.len()) {
+        let x = a.get(i).copied().unwrap_or(0);
+        let y = b.get(i).copied().unwrap_or(0);
+        diff |= usize::from(x ^ y);
+    }
+    diff == 0
+}
+
+/// Authentication state of one client connection.
+pub struct Session {
+    token: Option<Vec<u8>>,
+    authenticated: AtomicBool,
+    /// Consecutive failed attempts; held across the backoff delay so
+    /// concurrent attempts queue up behind each other.
+    failures: Mutex<u32>,
+}
+
+impl Session {
+    /// A session for a server started with `token`, or an already
+    /// authenticated one when no token is configured.
+    pub fn new(token: Option<Vec<u8>>) -> Self {
+        Self {
+            authenticated: AtomicBool::new(token.is_none()),
+            token,
+            failures: Mutex::new(0),
+        }
+    }
+
+    /// The token the client has to present, if any
+    pub fn token(&self) -> Option<&[u8]> {
+        self.token.as_deref()
+    }
+
+    pub fn is_authenticated(&self) -> bool {
+        self.authenticated.load(Ordering::Acquire)
+    }
+
+    /// Handle `system.auth`.
+    pub async fn authenticate(&self, params: Value) -> Result<Value, RpcError> {
+        #[derive(Deserialize)]
+        struct Params {
+            #[serde(with = "serde_bytes")]
+            token: Vec<u8>,
+        }
+
+        let params: Params =
+            from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
+
+        let Some(expected) = &self.token else {
+            return Ok(Value::Boolean(true));
+        };
+
+        let mut failures = self.failures.lock().await;
+        if constant_time_eq(&params.token, expected) {
+            *failures = 0;
+            self.authenticated.store(true, Ordering::Release);
+            return Ok(Value::Boolean(true));
+        }
+
+        *failures = failures.saturating_add(1);
+        let delay = BASE_FAILURE_DELAY
+            .saturating_mul(1 << (*failures - 1).min(16))
+            .min(MAX_FAILURE_DELAY);
+        tokio::time::sleep(delay).await;
+        Err(RpcError::auth_required("Authentication failed"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::msgpack_map;
+
+    #[test]
+    fn constant_time_eq_compares_contents_and_lengths() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+        assert!(!constant_time_eq(b"", b"x"));
+        assert!(constant_time_eq(b"", b""));
+    }
+
+    #[tokio::test]
+    async fn session_requires_matching_token() {
+        let session = Session::new(Some(b"s3cret".to_vec()));
+        assert!(!session.is_authenticated());
+
+        let started = std::time::Instant::now();
+        let err = session
+            .authenticate(msgpack_map! { "token" => "s3cre" })
+            .await
+            .unwrap_err();
+        assert_eq!(err.code, RpcError::AUTH_REQUIRED);
+        assert!(started.elapsed() >= BASE_FAILURE_DELAY);
+        assert!(!session.is_authenticated());
+
+        session
+            .authenticate(msgpack_map! { "token" => "s3cret" })
+            .await
+            .unwrap();
+        assert!(session.is_authenticated());
+
+        assert!(Session::new(None).is_authenticated());
+    }
+}
@@ -0,0 +1,219 @@
+//! Watchdog for blocking operations.
+//!
+//! Handlers run filesystem work on tokio's blocking pool through [`run`],
+//! which records what each thread is doing, on which path and since when.
+//! A thread stuck in the kernel (a stat on a dead NFS mount) cannot be
+//! interrupted, but it can be seen: operations running longer than
+//! `--blocking-warn-secs` / `TRAMP_RPC_BLOCKING_WARN_SECS` (default 10) are
+//! listed in `system.stats` and `system.recent_requests`.
+//!
+//! With `--blocking-timeout-secs` / `TRAMP_RPC_BLOCKING_TIMEOUT_SECS` set
+//! (default 0, never), a caller that has waited that long gets a TIMEOUT
+//! error.  The thread is left to finish on its own and is counted as
+//! orphaned until it does; tokio starts new blocking threads on demand, so
+//! orphans only cost threads, but the count shows how many are lost.
+
+use crate::msgpack_map;
+use crate::protocol::RpcError;
+use rmpv::Value;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Default age after which an operation is reported as slow.
+pub const DEFAULT_WARN_SECS: u64 = 10;
+
+static WARN_SECS: AtomicU64 = AtomicU64::new(DEFAULT_WARN_SECS);
+static TIMEOUT_SECS: AtomicU64 = AtomicU64::new(0);
+
+static RUNNING: Mutex<BTreeMap<u64, Operation>> = Mutex::new(BTreeMap::new());
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+static TIMED_OUT: AtomicU64 = AtomicU64::new(0);
+
+tokio::task_local! {
+    /// Method of the request being handled, for the watchdog's reports
+    static METHOD: String;
+}
+
+/// Set the slow-operation threshold and the hard limit; a limit of 0 never
+/// times out.
+pub fn set_limits(warn_secs: u64, timeout_secs: u64) {
+    WARN_SECS.store(warn_secs, Ordering::Relaxed);
+    TIMEOUT_SECS.store(timeout_secs, Ordering::Relaxed);
+}
+
+/// Run `future` as the handler of `method`, so blocking operations it
+/// starts are reported under that method.
+pub async fn scope<F: Future>(method: &str, future: F) -> F::Output {
+    METHOD.scope(method.to_string(), future).await
+}
+
+/// A blocking operation in flight
+#[derive(Debug)]
+struct Operation {
+    method: String,
+    path: PathBuf,
+    started: Instant,
+    /// The caller timed out and no longer waits for it
+    orphaned: bool,
+}
+
+/// Removes an operation from [`RUNNING`] when its thread is done with it,
+/// even by panicking.
+struct Finished(u64);
+
+impl Drop for Finished {
+    fn drop(&mut self) {
+        running().remove(&self.0);
+    }
+}
+
+fn running() -> std::sync::MutexGuard<'static, BTreeMap<u64, Operation>> {
+    RUNNING.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Run `f` on the blocking pool as an operation on `path`.
+pub async fn run<T, F>(path: impl AsRef<Path>, f: F) -> Result<T, RpcError>
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    let limit = match TIMEOUT_SECS.load(Ordering::Relaxed) {
+        0 => None,
+        secs => Some(Duration::from_secs(secs)),
+    };
+    run_with_limit(path.as_ref(), limit, f).await
+}
+
+async fn run_with_limit<T, F>(path: &Path, limit: Option<Duration>, f: F) -> Result<T, RpcError>
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let started = Instant::now();
+    running().insert(
+        id,
+        Operation {
+            method: METHOD.try_with(|m| m.clone()).unwrap_or_default(),
+            path: path.to_path_buf(),
+            started,
+            orphaned: false,
+        },
+    );
+    let handle = tokio::task::spawn_blocking(move || {
+        let _finished = Finished(id);
+        f()
+    });
+    let joined = match limit {
+        None => handle.await,
+        Some(limit) => match tokio::time::timeout(limit, handle).await {
+            Ok(joined) => joined,
+            Err(_) => {
+                if let Some(operation) = running().get_mut(&id) {
+                    operation.orphaned = true;
+                }
+                TIMED_OUT.fetch_add(1, Ordering::Relaxed);
+                return Err(RpcError::timeout(path, started.elapsed()));
+            }
+        },
+    };
+    joined.map_err(join_error)
+}
+
+/// The error for a blocking task that did not finish.  A panic is raised
+/// again on the calling task instead, for dispatch to report.
+pub fn join_error(e: tokio::task::JoinError) -> RpcError {
+    match e.try_into_panic() {
+        Ok(payload) => std::panic::resume_unwind(payload),
+        Err(e) => RpcError::internal_error(format!("Task join error: {}", e)),
+    }
+}
+
+/// Operations running longer than the warning threshold, oldest first, as
+/// `[{method, path, elapsed_ms, orphaned}]`.
+pub fn slow() -> Value {
+    let warn = Duration::from_secs(WARN_SECS.load(Ordering::Relaxed));
+    Value::Array(
+        running()
+            .values()
+            .filter(|op| op.started.elapsed() >= warn)
+            .map(|op| {
+                msgpack_map! {
+                    "method" => op.method.as_str(),
+                    "path" => crate::protocol::path_value(&op.path),
+                    "elapsed_ms" => op.started.elapsed().as_millis() as u64,
+                    "orphaned" => op.orphaned
+                }
+            })
+            .collect(),
+    )
+}
+
+/// Limits, counts and slow operations for `system.stats`.
+pub fn stats() -> Value {
+    let (count, orphaned) = {
+        let running = running();
+        let orphaned = running.values().filter(|op| op.orphaned).count();
+        (running.len(), orphaned)
+    };
+    msgpack_map! {
+        "warn_secs" => WARN_SECS.load(Ordering::Relaxed),
+        "timeout_secs" => TIMEOUT_SECS.load(Ordering::Relaxed),
+        "running" => count as u64,
+        "orphaned" => orphaned as u64,
+        "timed_out" => TIMED_OUT.load(Ordering::Relaxed),
+        "slow" => slow()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn find(path: &Path) -> Option<bool> {
+        running()
+            .values()
+            .find(|op| op.path == path)
+            .map(|op| op.orphaned)
+    }
+
+    #[tokio::test]
+    async fn timed_out_operations_stay_orphaned_until_done() {
+        let path = Path::new("/watchdog/test/stuck");
+        let (release, wait) = std::sync::mpsc::channel::<()>();
+        let err = scope(
+            "file.stat",
+            run_with_limit(path, Some(Duration::from_millis(20)), move || {
+                let _ = wait.recv();
+            }),
+        )
+        .await
+        .expect_err("should time out");
+        assert_eq!(err.code, RpcError::TIMEOUT);
+        assert!(err.data.unwrap()["elapsed_ms"].as_u64().unwrap() >= 20);
+        assert_eq!(find(path), Some(true));
+        let method = running()
+            .values()
+            .find(|op| op.path == path)
+            .map(|op| op.method.clone());
+        assert_eq!(method.as_deref(), Some("file.stat"));
+
+        release.send(()).unwrap();
+        for _ in 0..100 {
+            if find(path).is_none() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(find(path), None);
+
+        // Without a limit the result comes back however long it takes
+        let value = run_with_limit(path, None, || 42).await.unwrap();
+        assert_eq!(value, 42);
+        assert_eq!(find(path), None);
+    }
+}
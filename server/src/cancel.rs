@@ -0,0 +1,135 @@
+//! Cancelling requests in flight.
+//!
+//! `rpc.cancel {id}` asks the request with that `id` to stop.  Cancelling
+//! is cooperative: handlers that support it check [`current`] between
+//! units of work and fail with a CANCELLED error (-32018) once it is set,
+//! reporting how far they got.  So far these are `dir.remove` with
+//! `recursive` and `dir.audit_perms`; other requests run to completion.  The items of a `batch`
+//! or of the bulk file methods share the token of the request that carries
+//! them.
+
+use crate::msgpack_map;
+use crate::protocol::{RequestId, RpcError, from_value};
+use rmpv::Value;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, LazyLock, Mutex};
+
+/// Tokens of the requests in flight, by id.  Ids are the client's business,
+/// so several requests may share one.
+static IN_FLIGHT: LazyLock<Mutex<HashMap<String, Vec<Token>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+tokio::task_local! {
+    static TOKEN: Token;
+}
+
+/// Whether a request has been asked to stop.
+#[derive(Clone, Default)]
+pub struct Token(Arc<AtomicBool>);
+
+impl Token {
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+fn in_flight() -> std::sync::MutexGuard<'static, HashMap<String, Vec<Token>>> {
+    IN_FLIGHT.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn key(id: &RequestId) -> String {
+    match id {
+        RequestId::Number(n) => format!("n{}", n),
+        RequestId::String(s) => format!("s{}", s),
+    }
+}
+
+/// Unregisters a request's token when it is done, even by panicking.
+struct Registered {
+    key: String,
+    token: Token,
+}
+
+impl Drop for Registered {
+    fn drop(&mut self) {
+        let mut in_flight = in_flight();
+        if let Some(tokens) = in_flight.get_mut(&self.key) {
+            tokens.retain(|token| !Arc::ptr_eq(&token.0, &self.token.0));
+            if tokens.is_empty() {
+                in_flight.remove(&self.key);
+            }
+        }
+    }
+}
+
+/// Run `future` as the handler of the request `id`, cancellable with
+/// `rpc.cancel` until it finishes.
+pub async fn scope<F: Future>(id: &RequestId, future: F) -> F::Output {
+    let token = Token::default();
+    let registered = Registered {
+        key: key(id),
+        token: token.clone(),
+    };
+    in_flight()
+        .entry(registered.key.clone())
+        .or_default()
+        .push(token.clone());
+    let output = TOKEN.scope(token, future).await;
+    drop(registered);
+    output
+}
+
+/// The token of the request being handled; outside of one, a token that
+/// is never cancelled.
+pub fn current() -> Token {
+    TOKEN.try_with(Clone::clone).unwrap_or_default()
+}
+
+/// Handle `rpc.cancel {id}`, returning `{cancelled}`: whether a request
+/// with that id was in flight.
+pub fn handle_cancel(params: Value) -> Result<Value, RpcError> {
+    #[derive(serde::Deserialize)]
+    struct Params {
+        id: RequestId,
+    }
+
+    let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
+    let in_flight = in_flight();
+    let tokens = in_flight.get(&key(&params.id));
+    for token in tokens.into_iter().flatten() {
+        token.cancel();
+    }
+    Ok(msgpack_map! {
+        "cancelled" => tokens.is_some()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn cancel_reaches_the_request_with_the_id() {
+        let id = RequestId::String("cancel-test".into());
+        let seen = scope(&id, async {
+            let token = current();
+            assert!(!token.is_cancelled());
+            let result = handle_cancel(msgpack_map! { "id" => "cancel-test" }).unwrap();
+            assert_eq!(result["cancelled"], Value::Boolean(true));
+            token.is_cancelled()
+        })
+        .await;
+        assert!(seen);
+
+        // Finished requests are forgotten
+        let result = handle_cancel(msgpack_map! { "id" => "cancel-test" }).unwrap();
+        assert_eq!(result["cancelled"], Value::Boolean(false));
+        assert!(!current().is_cancelled());
+    }
+}
@@ -0,0 +1,433 @@
+//! Startup settings.
+//!
+//! Each setting comes from its command-line flag, else its environment
+//! variable (`TRAMP_RPC_` and the flag name in upper case, `-` as `_`,
+//! such as `TRAMP_RPC_MAX_READ_SIZE`), else the config file, else its
+//! default.  The config file is TOML, read from `--config PATH` or else
+//! `$XDG_CONFIG_HOME/tramp-rpc/server.toml` (`~/.config` when unset); a
+//! missing default file is fine.  Its keys are the flag names with `_` for
+//! `-`:
+//!
+//! ```toml
+//! read_only = true
+//! max_read_size = 16777216
+//! env_allowlist = ["PATH", "LC_*"]
+//! ```
+//!
+//! All three are parsed by the same flags: the file's keys and the
+//! environment become `--flag=value` arguments ahead of the real ones, and
+//! a later flag overrides an earlier one.  Keys that name no flag are
+//! warned about in the log file (see [`crate::server_log`]) and otherwise
+//! ignored, so a config written for a newer server still works.  A bad
+//! value anywhere ends the server with status 2 before it serves anything.
+//!
+//! `--config`, `--auth-token-file` and `--restarted-from` are only taken
+//! from the command line.  The effective settings are reported as
+//! `settings` in `system.info`.
+
+use crate::msgpack_map;
+use crate::protocol::{IntoValue, RpcError};
+use clap::builder::BoolishValueParser;
+use clap::{CommandFactory, Parser};
+use rmpv::Value;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// The flags that the environment and the config file cannot set
+const COMMAND_LINE_ONLY: &[&str] = &["config", "auth-token-file", "restarted-from"];
+
+static READ_ONLY: AtomicBool = AtomicBool::new(false);
+
+/// The config file read at startup, and its keys that name no flag
+static CONFIG_FILE: OnceLock<(Option<PathBuf>, Vec<String>)> = OnceLock::new();
+
+/// Settings from the command line, the environment and the config file
+#[derive(Parser, Debug)]
+#[command(name = "tramp-rpc-server", version, about, args_override_self = true)]
+pub struct Settings {
+    /// Config file [default: $XDG_CONFIG_HOME/tramp-rpc/server.toml]
+    #[arg(long, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+    /// Refuse paths outside DIR
+    #[arg(long, value_name = "DIR")]
+    pub jail: Option<PathBuf>,
+    /// Refuse requests that change files, run programs or restart the server
+    #[arg(long, value_name = "BOOL", num_args = 0..=1, default_missing_value = "true",
+          value_parser = BoolishValueParser::new())]
+    pub read_only: Option<bool>,
+    /// Exit once no request has come or run for SECS (0: never)
+    #[arg(long, value_name = "SECS")]
+    pub idle_timeout_secs: Option<u64>,
+    /// Log file for the server's own warnings
+    #[arg(long, value_name = "PATH")]
+    pub log_file: Option<PathBuf>,
+    /// Log mutating requests to PATH
+    #[arg(long, value_name = "PATH")]
+    pub audit_log: Option<PathBuf>,
+    /// Require the token in PATH from clients
+    #[arg(long, value_name = "PATH")]
+    pub auth_token_file: Option<PathBuf>,
+    /// Largest request frame accepted, in bytes
+    #[arg(long, value_name = "BYTES",
+          value_parser = clap::value_parser!(u64).range(1..u64::from(crate::framing::CHECKED)))]
+    pub max_message_size: Option<u64>,
+    /// Largest file read whole without a length, in bytes
+    #[arg(long, value_name = "BYTES")]
+    pub max_read_size: Option<u64>,
+    /// Concurrent io requests (0: unlimited)
+    #[arg(long, value_name = "N")]
+    pub limit_io: Option<usize>,
+    /// Concurrent process requests (0: unlimited)
+    #[arg(long, value_name = "N")]
+    pub limit_process: Option<usize>,
+    /// Concurrent metadata requests (0: unlimited)
+    #[arg(long, value_name = "N")]
+    pub limit_metadata: Option<usize>,
+    /// Notifications sent per second (0: unlimited)
+    #[arg(long, value_name = "N")]
+    pub notify_max_messages_per_sec: Option<u64>,
+    /// Notification bytes sent per second (0: unlimited)
+    #[arg(long, value_name = "N")]
+    pub notify_max_bytes_per_sec: Option<u64>,
+    /// Report blocking operations slower than SECS
+    #[arg(long, value_name = "SECS")]
+    pub blocking_warn_secs: Option<u64>,
+    /// Fail blocking operations slower than SECS (0: never)
+    #[arg(long, value_name = "SECS")]
+    pub blocking_timeout_secs: Option<u64>,
+    /// Requests kept for system.recent_requests
+    #[arg(long, value_name = "N")]
+    pub recent_requests: Option<usize>,
+    /// Environment of spawned processes: inherit, allowlist or clear
+    #[arg(long, value_name = "POLICY")]
+    pub env_policy: Option<String>,
+    /// Variables the allowlist policy keeps, comma-separated
+    #[arg(long, value_name = "A,B")]
+    pub env_allowlist: Option<String>,
+    #[arg(long, hide = true)]
+    pub restarted_from: Option<OsString>,
+}
+
+/// Why the settings could not be loaded
+pub enum Failure {
+    /// `--help` or `--version`, to be printed
+    Display(clap::Error),
+    /// A bad flag, variable or config file, and the log file to report it
+    /// in, if one is known by then
+    Invalid {
+        message: String,
+        log_file: Option<PathBuf>,
+    },
+}
+
+/// The config file read and its keys that name no flag, for [`load`]
+pub struct Loaded {
+    pub settings: Settings,
+    pub file: Option<PathBuf>,
+    pub unknown_keys: Vec<String>,
+}
+
+fn default_path() -> Option<PathBuf> {
+    let var = |name: &str| std::env::var_os(name).filter(|value| !value.is_empty());
+    let base = var("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| var("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(base.join("tramp-rpc").join("server.toml"))
+}
+
+/// The flags the environment and the config file may set.
+fn layered_flags() -> Vec<String> {
+    Settings::command()
+        .get_arguments()
+        .filter_map(|arg| arg.get_long())
+        .filter(|flag| !COMMAND_LINE_ONLY.contains(flag))
+        .map(str::to_string)
+        .collect()
+}
+
+/// The value of config key `key` as a flag argument.
+fn flag_value(key: &str, value: &toml::Value) -> Result<String, String> {
+    match value {
+        toml::Value::String(s) => Ok(s.clone()),
+        toml::Value::Integer(n) => Ok(n.to_string()),
+        toml::Value::Boolean(b) => Ok(b.to_string()),
+        toml::Value::Array(items) => items
+            .iter()
+            .map(|item| match item {
+                toml::Value::String(s) => Ok(s.as_str()),
+                _ => Err(format!("{}: list items must be strings", key)),
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map(|items| items.join(",")),
+        _ => Err(format!(
+            "{}: expected a string, integer, boolean or list",
+            key
+        )),
+    }
+}
+
+/// Resolve the settings for the server started with `args`, reading the
+/// environment through `env`.
+fn resolve(
+    args: Vec<OsString>,
+    env: impl Fn(&str) -> Option<OsString>,
+    default_config: Option<PathBuf>,
+) -> Result<Loaded, Failure> {
+    let invalid =
+        |message: String, log_file: Option<PathBuf>| Failure::Invalid { message, log_file };
+    let cli = Settings::try_parse_from(&args).map_err(|e| match e.kind() {
+        clap::error::ErrorKind::DisplayHelp | clap::error::ErrorKind::DisplayVersion => {
+            Failure::Display(e)
+        }
+        _ => invalid(e.to_string(), None),
+    })?;
+    let early_log = cli
+        .log_file
+        .clone()
+        .or_else(|| env("TRAMP_RPC_LOG_FILE").map(PathBuf::from));
+
+    let (path, explicit) = match cli.config {
+        Some(path) => (Some(path), true),
+        None => (default_config, false),
+    };
+    let table =
+        match &path {
+            Some(path) => match std::fs::read_to_string(path) {
+                Ok(text) => Some(text.parse::<toml::Table>().map_err(|e| {
+                    invalid(format!("{}: {}", path.display(), e), early_log.clone())
+                })?),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound && !explicit => None,
+                Err(e) => {
+                    return Err(invalid(
+                        format!("{}: {}", path.display(), e),
+                        early_log.clone(),
+                    ));
+                }
+            },
+            None => None,
+        };
+
+    let flags = layered_flags();
+    let mut layered = vec![args.first().cloned().unwrap_or_default()];
+    let mut unknown_keys = Vec::new();
+    for (key, value) in table.iter().flatten() {
+        let flag = key.replace('_', "-");
+        if !flags.contains(&flag) {
+            unknown_keys.push(key.clone());
+            continue;
+        }
+        let value = flag_value(key, value).map_err(|e| invalid(e, early_log.clone()))?;
+        layered.push(format!("--{}={}", flag, value).into());
+    }
+    for flag in &flags {
+        let name = format!("TRAMP_RPC_{}", flag.to_uppercase().replace('-', "_"));
+        if let Some(value) = env(&name) {
+            let mut arg = OsString::from(format!("--{}=", flag));
+            arg.push(value);
+            layered.push(arg);
+        }
+    }
+    layered.extend(args.into_iter().skip(1));
+
+    let settings =
+        Settings::try_parse_from(layered).map_err(|e| invalid(e.to_string(), early_log))?;
+    Ok(Loaded {
+        settings,
+        file: table.is_some().then_some(path).flatten(),
+        unknown_keys,
+    })
+}
+
+/// Load the settings of this server.
+pub fn load() -> Result<Loaded, Failure> {
+    resolve(
+        std::env::args_os().collect(),
+        |name| std::env::var_os(name).filter(|value| !value.is_empty()),
+        default_path(),
+    )
+}
+
+/// Remember what [`load`] read, for `system.info`, and warn about keys of
+/// the config file that name no flag.
+pub fn loaded(file: Option<PathBuf>, unknown_keys: Vec<String>) {
+    if let Some(path) = &file {
+        for key in &unknown_keys {
+            crate::server_log::warn(&format!("{}: unknown key {} ignored", path.display(), key));
+        }
+    }
+    let _ = CONFIG_FILE.set((file, unknown_keys));
+}
+
+pub fn set_read_only(read_only: bool) {
+    READ_ONLY.store(read_only, Ordering::Relaxed);
+}
+
+/// Refuse `method` if the server is read-only and it changes files, runs
+/// a program or replaces the server.
+pub fn check_writable(method: &str) -> Result<(), RpcError> {
+    check_writable_with(READ_ONLY.load(Ordering::Relaxed), method)
+}
+
+fn check_writable_with(read_only: bool, method: &str) -> Result<(), RpcError> {
+    if !read_only || !crate::mutating::refused_when_read_only(method) {
+        return Ok(());
+    }
+    Err(read_only_error(method))
+}
+
+fn read_only_error(method: &str) -> RpcError {
+    RpcError {
+        code: RpcError::ACCESS_DENIED,
+        message: format!("Server is read-only: {} refused", method),
+        data: Some(msgpack_map! {
+            "reason" => "read_only",
+            "method" => method
+        }),
+    }
+}
+
+/// The effective settings for `system.info`, as currently in force.
+pub fn info() -> Value {
+    let path = |path: Option<&Path>| path.map(|p| p.to_string_lossy().into_owned()).into_value();
+    let (file, unknown_keys) = CONFIG_FILE.get().cloned().unwrap_or_default();
+    let blocking = crate::blocking::stats();
+    let notifications = crate::notifications::stats();
+    msgpack_map! {
+        "config_file" => path(file.as_deref()),
+        "unknown_keys" => Value::Array(unknown_keys.into_iter().map(Value::from).collect()),
+        "jail" => path(crate::jail::root()),
+        "read_only" => READ_ONLY.load(Ordering::Relaxed),
+        "idle_timeout_secs" => crate::shutdown::idle_timeout_secs(),
+        "log_file" => path(crate::server_log::path().as_deref()),
+        "audit_log" => crate::audit::stats()["path"].clone(),
+        "max_message_size" => crate::framing::max_frame() as u64,
+        "max_read_size" => crate::handlers::io::max_read_size(),
+        "limits" => crate::limits::current(),
+        "notify_max_messages_per_sec" => notifications["max_messages_per_sec"].clone(),
+        "notify_max_bytes_per_sec" => notifications["max_bytes_per_sec"].clone(),
+        "blocking_warn_secs" => blocking["warn_secs"].clone(),
+        "blocking_timeout_secs" => blocking["timeout_secs"].clone(),
+        "recent_requests" => crate::recent::capacity() as u64,
+        "env_policy" => crate::env_policy::info()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolve_with(
+        args: &[&str],
+        env: &[(&str, &str)],
+        config: Option<&str>,
+    ) -> Result<Loaded, Failure> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("server.toml");
+        if let Some(config) = config {
+            std::fs::write(&path, config).unwrap();
+        }
+        let env: Vec<(String, OsString)> = env
+            .iter()
+            .map(|(name, value)| (name.to_string(), OsString::from(value)))
+            .collect();
+        let args = std::iter::once("tramp-rpc-server")
+            .chain(args.iter().copied())
+            .map(OsString::from)
+            .collect();
+        resolve(
+            args,
+            |name| {
+                env.iter()
+                    .find(|(var, _)| var == name)
+                    .map(|(_, value)| value.clone())
+            },
+            Some(path),
+        )
+    }
+
+    fn message(failure: Failure) -> String {
+        match failure {
+            Failure::Invalid { message, .. } => message,
+            Failure::Display(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn flags_beat_the_environment_which_beats_the_file() {
+        let config = "max_read_size = 100\nrecent_requests = 7\nread_only = true\n\
+                      env_allowlist = [\"PATH\", \"LC_*\"]\nfuture_knob = 3\n";
+        let loaded = resolve_with(
+            &["--max-read-size", "300"],
+            &[
+                ("TRAMP_RPC_MAX_READ_SIZE", "200"),
+                ("TRAMP_RPC_RECENT_REQUESTS", "9"),
+            ],
+            Some(config),
+        )
+        .ok()
+        .unwrap();
+        let settings = loaded.settings;
+        assert_eq!(settings.max_read_size, Some(300));
+        assert_eq!(settings.recent_requests, Some(9));
+        assert_eq!(settings.read_only, Some(true));
+        assert_eq!(settings.env_allowlist.as_deref(), Some("PATH,LC_*"));
+        assert!(loaded.file.is_some());
+        assert_eq!(loaded.unknown_keys, vec!["future_knob".to_string()]);
+
+        // `--read-only` alone means true, and can be turned off again
+        let loaded = resolve_with(&["--read-only"], &[], None).ok().unwrap();
+        assert_eq!(loaded.settings.read_only, Some(true));
+        assert!(loaded.file.is_none());
+        let loaded = resolve_with(&["--read-only=no"], &[], Some("read_only = true"))
+            .ok()
+            .unwrap();
+        assert_eq!(loaded.settings.read_only, Some(false));
+    }
+
+    #[test]
+    fn bad_settings_are_refused() {
+        let bad_value = resolve_with(&[], &[], Some("max_read_size = \"lots\"")).err();
+        assert!(message(bad_value.unwrap()).contains("max-read-size"));
+        let bad_env = resolve_with(&[], &[("TRAMP_RPC_IDLE_TIMEOUT_SECS", "-1")], None).err();
+        assert!(message(bad_env.unwrap()).contains("idle-timeout-secs"));
+        let bad_toml = resolve_with(&[], &[], Some("read_only = ")).err();
+        assert!(matches!(bad_toml, Some(Failure::Invalid { .. })));
+        // The config file cannot name the token file
+        let token = resolve_with(&[], &[], Some("auth_token_file = \"/tmp/t\""))
+            .ok()
+            .unwrap();
+        assert!(token.settings.auth_token_file.is_none());
+        assert_eq!(token.unknown_keys, vec!["auth_token_file".to_string()]);
+        // An explicit config file must exist
+        let missing = resolve_with(&["--config", "/nonexistent/server.toml"], &[], None).err();
+        assert!(message(missing.unwrap()).contains("/nonexistent/server.toml"));
+        assert!(matches!(
+            resolve_with(&["--version"], &[], None),
+            Err(Failure::Display(_))
+        ));
+    }
+
+    #[test]
+    fn read_only_refuses_writes_processes_and_reexec() {
+        let error = read_only_error("file.write");
+        assert_eq!(error.code, RpcError::ACCESS_DENIED);
+        assert_eq!(error.data.unwrap()["reason"].as_str(), Some("read_only"));
+        // Not read-only in tests
+        assert!(check_writable("file.write").is_ok());
+        assert!(check_writable("system.reexec").is_ok());
+        for method in [
+            "file.write",
+            "process.run",
+            "shell.session_run",
+            "system.reexec",
+        ] {
+            let error = check_writable_with(true, method).expect_err(method);
+            assert_eq!(error.data.unwrap()["method"].as_str(), Some(method));
+        }
+        for method in ["file.read", "process.kill", "git.log"] {
+            assert!(check_writable_with(true, method).is_ok(), "{}", method);
+        }
+    }
+}
@@ -0,0 +1,175 @@
+//! Which server environment variables spawned processes inherit.
+//!
+//! The server runs with whatever environment SSH gave it, secrets and agent
+//! sockets included.  The policy, set with `--env-policy POLICY` /
+//! `TRAMP_RPC_ENV_POLICY` at startup or with `system.set_env_policy` at
+//! runtime, decides what of it processes started by `process.run`,
+//! `process.start`, `process.start_pty`, `shell.session_open` and
+//! `commands.run_parallel` see:
+//!
+//! - `inherit_all` (the default): everything.
+//! - `allowlist`: the variables matching one of the patterns, by default
+//!   [`DEFAULT_ALLOWLIST`]; `--env-allowlist` / `TRAMP_RPC_ENV_ALLOWLIST`
+//!   set them as a comma-separated list.  A trailing `*` matches any suffix.
+//! - `clean`: nothing.
+//!
+//! A request's `env` is applied on top, and its `clear_env` still starts
+//! from an empty environment whatever the policy.  Commands run as another
+//! user get the environment sudo or su gives them.
+
+use crate::msgpack_map;
+use crate::protocol::RpcError;
+use rmpv::Value;
+use std::ffi::OsString;
+use std::sync::RwLock;
+
+/// Variables passed by `allowlist` unless other patterns are given.
+pub const DEFAULT_ALLOWLIST: &[&str] = &["PATH", "HOME", "USER", "LANG", "LC_*", "TERM"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Policy {
+    InheritAll,
+    Allowlist(Vec<String>),
+    Clean,
+}
+
+static POLICY: RwLock<Policy> = RwLock::new(Policy::InheritAll);
+
+impl Policy {
+    /// Parse the policy named `name`; `allow` only applies to `allowlist`.
+    pub fn parse(name: &str, allow: Option<Vec<String>>) -> Result<Self, String> {
+        match name {
+            "inherit_all" => Ok(Policy::InheritAll),
+            "allowlist" => Ok(Policy::Allowlist(allow.unwrap_or_else(|| {
+                DEFAULT_ALLOWLIST.iter().map(|p| p.to_string()).collect()
+            }))),
+            "clean" => Ok(Policy::Clean),
+            other => Err(format!(
+                "env policy must be \"inherit_all\", \"allowlist\" or \"clean\", got \"{}\"",
+                other
+            )),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Policy::InheritAll => "inherit_all",
+            Policy::Allowlist(_) => "allowlist",
+            Policy::Clean => "clean",
+        }
+    }
+
+    /// The variables of `vars` a process may inherit, or `None` for all.
+    fn filter(
+        &self,
+        vars: impl Iterator<Item = (OsString, OsString)>,
+    ) -> Option<Vec<(OsString, OsString)>> {
+        match self {
+            Policy::InheritAll => None,
+            Policy::Clean => Some(Vec::new()),
+            Policy::Allowlist(patterns) => Some(
+                vars.filter(|(name, _)| {
+                    name.to_str()
+                        .is_some_and(|name| patterns.iter().any(|p| matches(p, name)))
+                })
+                .collect(),
+            ),
+        }
+    }
+}
+
+/// Whether the variable `name` matches `pattern`.
+fn matches(pattern: &str, name: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => name.starts_with(prefix),
+        None => name == pattern,
+    }
+}
+
+pub fn set(policy: Policy) {
+    *POLICY.write().unwrap_or_else(|e| e.into_inner()) = policy;
+}
+
+fn get() -> Policy {
+    POLICY.read().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+/// The environment a process starts from before its request's `env`, or
+/// `None` if it inherits the server's.  With `clear_env` it is empty.
+pub fn base(clear_env: bool) -> Option<Vec<(OsString, OsString)>> {
+    if clear_env {
+        return Some(Vec::new());
+    }
+    get().filter(std::env::vars_os())
+}
+
+/// The policy as reported by `system.info`: `{policy, allow}`, where
+/// `allow` is nil unless the policy is `allowlist`.
+pub fn info() -> Value {
+    let policy = get();
+    let allow = match &policy {
+        Policy::Allowlist(patterns) => {
+            Value::Array(patterns.iter().map(|p| p.as_str().into()).collect())
+        }
+        _ => Value::Nil,
+    };
+    msgpack_map! {
+        "policy" => policy.name(),
+        "allow" => allow
+    }
+}
+
+/// Handle `system.set_env_policy {policy, allow?}`, returning the new
+/// policy as `system.info` reports it.
+pub fn handle_set(params: Value) -> Result<Value, RpcError> {
+    #[derive(serde::Deserialize)]
+    struct Params {
+        policy: String,
+        #[serde(default)]
+        allow: Option<Vec<String>>,
+    }
+
+    let params: Params =
+        crate::protocol::from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
+    set(Policy::parse(&params.policy, params.allow).map_err(RpcError::invalid_params)?);
+    Ok(info())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(names: &[&str]) -> Vec<(OsString, OsString)> {
+        names.iter().map(|n| (n.into(), "x".into())).collect()
+    }
+
+    #[test]
+    fn allowlists_pass_matching_variables_only() {
+        let env = vars(&[
+            "PATH",
+            "LC_ALL",
+            "LC_CTYPE",
+            "SSH_AUTH_SOCK",
+            "PATHOLOGICAL",
+        ]);
+        let passed = Policy::parse("allowlist", None)
+            .unwrap()
+            .filter(env.clone().into_iter())
+            .unwrap();
+        assert_eq!(passed, vars(&["PATH", "LC_ALL", "LC_CTYPE"]));
+
+        let custom = Policy::parse("allowlist", Some(vec!["SSH_*".into()])).unwrap();
+        assert_eq!(
+            custom.filter(env.clone().into_iter()).unwrap(),
+            vars(&["SSH_AUTH_SOCK"])
+        );
+        assert_eq!(
+            Policy::parse("clean", None)
+                .unwrap()
+                .filter(env.clone().into_iter()),
+            Some(vec![])
+        );
+        assert_eq!(Policy::InheritAll.filter(env.into_iter()), None);
+        assert!(Policy::parse("some", None).is_err());
+    }
+}
@@ -0,0 +1,162 @@
+//! Frames on stdin and stdout.
+//!
+//! A frame is `<4-byte big-endian length><msgpack payload>`.  The top bit
+//! of the length marks a checked frame, which carries the CRC32C of its
+//! payload as 4 big-endian bytes after it.  Payloads are far smaller than
+//! 2 GiB, so the bit is never set otherwise.
+//!
+//! Checked frames are accepted at any time.  Once a client has negotiated
+//! the `frame_crc32c` feature (see [`crate::handshake`]), every frame the
+//! server writes is checked, from the `system.hello` reply on.  Once the
+//! client has sent one checked frame, all later ones must be checked too.
+//! A frame that fails either rule gets a PARSE_ERROR reply if its request
+//! id can still be read, and otherwise ends the connection with a
+//! `transport.error` notification.
+
+use crate::handshake::{self, Feature};
+use crate::msgpack_map;
+use crate::protocol::{Notification, RequestId};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+/// Marks a checked frame in the length prefix.
+pub const CHECKED: u32 = 1 << 31;
+
+/// Largest payload accepted by default; see [`set_max_frame`].
+pub const DEFAULT_MAX_FRAME: usize = 100 * 1024 * 1024;
+
+static MAX_FRAME: AtomicUsize = AtomicUsize::new(DEFAULT_MAX_FRAME);
+
+const CASTAGNOLI: crc::Crc<u32> = crc::Crc::<u32>::new(&crc::CRC_32_ISCSI);
+
+/// Whether the client has sent a checked frame.
+static CLIENT_CHECKS: AtomicBool = AtomicBool::new(false);
+
+/// Set the largest payload accepted, from `--max-message-size BYTES`.
+/// Larger frames are read and dropped unanswered.
+pub fn set_max_frame(limit: usize) {
+    MAX_FRAME.store(limit, Ordering::Relaxed);
+}
+
+/// The largest payload accepted.
+pub fn max_frame() -> usize {
+    MAX_FRAME.load(Ordering::Relaxed)
+}
+
+/// The bytes that go before and after `payload` in a frame written now.
+pub fn envelope(payload: &[u8]) -> ([u8; 4], Option<[u8; 4]>) {
+    let len = payload.len() as u32;
+    if handshake::enabled(Feature::FrameCrc32c) {
+        let checksum = CASTAGNOLI.checksum(payload).to_be_bytes();
+        ((len | CHECKED).to_be_bytes(), Some(checksum))
+    } else {
+        (len.to_be_bytes(), None)
+    }
+}
+
+/// The payload length a length prefix announces, and whether a checksum
+/// follows the payload.
+pub fn parse_prefix(prefix: [u8; 4]) -> (usize, bool) {
+    let prefix = u32::from_be_bytes(prefix);
+    ((prefix & !CHECKED) as usize, prefix & CHECKED != 0)
+}
+
+/// Why a frame read was rejected
+#[derive(Debug, PartialEq)]
+pub enum Rejected {
+    /// The checksum does not match the payload
+    Mismatch { expected: u32, actual: u32 },
+    /// An unchecked frame after checked ones
+    Unchecked,
+}
+
+impl Rejected {
+    pub fn message(&self) -> String {
+        match self {
+            Rejected::Mismatch { expected, actual } => format!(
+                "frame checksum mismatch: expected {:08x}, got {:08x}",
+                expected, actual
+            ),
+            Rejected::Unchecked => "frame without checksum after checked frames".to_string(),
+        }
+    }
+
+    /// The `transport.error` notification announcing the shutdown.
+    pub fn notification(&self) -> Notification {
+        let reason = match self {
+            Rejected::Mismatch { .. } => "checksum_mismatch",
+            Rejected::Unchecked => "checksum_missing",
+        };
+        Notification::new(
+            "transport.error",
+            msgpack_map! {
+                "reason" => reason,
+                "message" => self.message()
+            },
+        )
+    }
+}
+
+/// Check a frame read from the client, with the `checksum` that followed
+/// its payload if it was checked.
+pub fn verify(payload: &[u8], checksum: Option<[u8; 4]>) -> Result<(), Rejected> {
+    let Some(checksum) = checksum else {
+        return match CLIENT_CHECKS.load(Ordering::Relaxed) {
+            true => Err(Rejected::Unchecked),
+            false => Ok(()),
+        };
+    };
+    CLIENT_CHECKS.store(true, Ordering::Relaxed);
+    let expected = u32::from_be_bytes(checksum);
+    let actual = CASTAGNOLI.checksum(payload);
+    match expected == actual {
+        true => Ok(()),
+        false => Err(Rejected::Mismatch { expected, actual }),
+    }
+}
+
+/// The id of the request in a rejected `payload`, if it still decodes.
+pub fn salvage_id(payload: &[u8]) -> Option<RequestId> {
+    let value = rmpv::decode::read_value(&mut &payload[..]).ok()?;
+    match &value["id"] {
+        rmpv::Value::Integer(id) => id.as_i64().map(RequestId::Number),
+        rmpv::Value::String(id) => id.as_str().map(|id| RequestId::String(id.to_string())),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checked_frames_are_verified() {
+        // The standard CRC32C check value
+        assert_eq!(CASTAGNOLI.checksum(b"123456789"), 0xe306_9283);
+
+        assert_eq!(parse_prefix([0, 0, 1, 0]), (256, false));
+        assert_eq!(parse_prefix((256 | CHECKED).to_be_bytes()), (256, true));
+
+        let payload = rmp_serde::to_vec_named(&msgpack_map! {
+            "version" => "2.0",
+            "id" => 42,
+            "method" => "file.stat"
+        })
+        .unwrap();
+        let checksum = CASTAGNOLI.checksum(&payload).to_be_bytes();
+        assert_eq!(verify(&payload, Some(checksum)), Ok(()));
+
+        let mut corrupted = payload.clone();
+        *corrupted.last_mut().unwrap() ^= 0x20;
+        let rejected = verify(&corrupted, Some(checksum)).unwrap_err();
+        assert!(matches!(rejected, Rejected::Mismatch { .. }));
+        assert!(matches!(
+            salvage_id(&corrupted),
+            Some(RequestId::Number(42))
+        ));
+        assert!(salvage_id(&[0xc1]).is_none());
+
+        // Checked frames once, checked frames always
+        assert_eq!(verify(&payload, None), Err(Rejected::Unchecked));
+        CLIENT_CHECKS.store(false, Ordering::Relaxed);
+    }
+}
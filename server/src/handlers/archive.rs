@@ -0,0 +1,1811 @@
+//! Archive listing for TRAMP-RPC
+//!
+//! This module provides:
+//! - `archive.list`: Member list of a tar (optionally gzip, xz or zstd
+//!   compressed) or zip archive, so `tar-mode` and `archive-mode` can show
+//!   it without downloading the whole archive
+//! - `archive.extract`: Extract all or some members into a directory on
+//!   the remote host
+//! - `archive.create`: Build a tar or zip archive from remote files, into a
+//!   file or streamed back with `archive.read` (and `archive.close`)
+//!
+//! All formats are read with pure-Rust decoders; no `tar` or `unzip` needs
+//! to be installed on the remote host.
+
+use crate::jail;
+use crate::msgpack_map;
+use crate::protocol::{IntoValue, PathBytes, RpcError, from_value, path_or_bytes};
+use crate::stat_cache;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use rmpv::Value;
+use serde::Deserialize;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, mpsc};
+
+use super::HandlerResult;
+use super::file::{bytes_to_path, map_io_error};
+use super::io::set_file_times_sync_path_io;
+
+/// Archive formats recognised by their leading bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Tar,
+    TarGz,
+    TarXz,
+    TarZst,
+    Zip,
+}
+
+impl Format {
+    fn as_str(self) -> &'static str {
+        match self {
+            Format::Tar => "tar",
+            Format::TarGz => "tar.gz",
+            Format::TarXz => "tar.xz",
+            Format::TarZst => "tar.zst",
+            Format::Zip => "zip",
+        }
+    }
+
+    /// Detect the format from the first bytes of the file.  Anything that is
+    /// not compressed or zip is tried as tar, which also covers old v7 tars
+    /// without the `ustar` magic.
+    fn detect(head: &[u8]) -> Format {
+        if head.starts_with(&[0x1f, 0x8b]) {
+            Format::TarGz
+        } else if head.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            Format::TarXz
+        } else if head.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Format::TarZst
+        } else if head.starts_with(b"PK\x03\x04") || head.starts_with(b"PK\x05\x06") {
+            Format::Zip
+        } else {
+            Format::Tar
+        }
+    }
+}
+
+/// Which slice of the member list to return.
+struct Window {
+    offset: usize,
+    max_entries: usize,
+}
+
+/// Members in the window, and whether more follow it.
+struct Listing {
+    members: Vec<Value>,
+    truncated: bool,
+}
+
+/// Failure to parse an archive at `offset`.  For compressed tars the
+/// offset is into the decompressed stream.
+struct Corrupt {
+    offset: u64,
+    message: String,
+}
+
+/// An opened archive, ready to be walked.
+enum Source {
+    /// Decompressed tar stream
+    Tar(Box<dyn Read>),
+    Zip(zip::ZipArchive<BufReader<File>>),
+}
+
+fn zip_error(e: zip::result::ZipError, offset: u64) -> ArchiveError {
+    match e {
+        zip::result::ZipError::Io(e) if e.kind() != io::ErrorKind::UnexpectedEof => {
+            ArchiveError::Io(e)
+        }
+        e => ArchiveError::Corrupt(Corrupt {
+            offset,
+            message: e.to_string(),
+        }),
+    }
+}
+
+/// Open the archive at `path`, detect its format and hand it to `visit`.
+/// `stopped_early` tells whether `visit` left part of the archive unread,
+/// which a failed xz decode is then not blamed for.
+fn read_archive<T>(
+    path: &Path,
+    visit: impl FnOnce(Source) -> Result<T, ArchiveError>,
+    stopped_early: impl FnOnce(&T) -> bool,
+) -> Result<(Format, T), RpcError> {
+    let path_str = path.to_string_lossy();
+    let io_error = |e| map_io_error(e, path);
+    let mut file = File::open(path).map_err(io_error)?;
+    let mut head = [0u8; 8];
+    let len = read_head(&mut file, &mut head).map_err(io_error)?;
+    file.seek(SeekFrom::Start(0)).map_err(io_error)?;
+
+    let format = Format::detect(&head[..len]);
+    let result = match format {
+        Format::Tar => visit(Source::Tar(Box::new(BufReader::new(file)))),
+        Format::TarGz => visit(Source::Tar(Box::new(flate2::read::MultiGzDecoder::new(
+            BufReader::new(file),
+        )))),
+        Format::TarXz => match xz_reader(file) {
+            Ok((reader, decoder)) => {
+                let result = visit(Source::Tar(Box::new(reader)));
+                // The tar reader sees a failed decode as the end of the
+                // stream, so ask the decoder whether that is what happened.
+                match decoder.join() {
+                    Ok(Err(corrupt)) if !result.as_ref().is_ok_and(stopped_early) => {
+                        Err(ArchiveError::Corrupt(corrupt))
+                    }
+                    _ => result,
+                }
+            }
+            Err(e) => Err(ArchiveError::Io(e)),
+        },
+        Format::TarZst => match ruzstd::decoding::StreamingDecoder::new(BufReader::new(file)) {
+            Ok(decoder) => visit(Source::Tar(Box::new(decoder))),
+            Err(e) => Err(ArchiveError::Corrupt(Corrupt {
+                offset: 0,
+                message: format!("invalid zstd frame: {}", e),
+            })),
+        },
+        Format::Zip => match file.metadata() {
+            // The central directory is located from the end of the file.
+            Ok(meta) => zip::ZipArchive::new(BufReader::new(file))
+                .map_err(|e| zip_error(e, meta.len()))
+                .and_then(|archive| visit(Source::Zip(archive))),
+            Err(e) => Err(ArchiveError::Io(e)),
+        },
+    };
+    match result {
+        Ok(value) => Ok((format, value)),
+        Err(ArchiveError::Io(e)) => Err(io_error(e)),
+        Err(ArchiveError::Corrupt(corrupt)) => Err(RpcError::invalid_archive(
+            &path_str,
+            format.as_str(),
+            corrupt.offset,
+            &corrupt.message,
+        )),
+    }
+}
+
+fn read_head(file: &mut File, head: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < head.len() {
+        match file.read(&mut head[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(len)
+}
+
+/// Writer that counts the bytes passed through it.
+struct Counting<W> {
+    inner: W,
+    count: u64,
+}
+
+impl<W: io::Write> io::Write for Counting<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Decompress xz on a helper thread.  `lzma-rs` only decodes into a
+/// writer, so the tar reader is fed through a pipe.  The thread reports a
+/// decoding failure with the decompressed offset it got to.
+fn xz_reader(
+    file: File,
+) -> io::Result<(io::PipeReader, std::thread::JoinHandle<Result<(), Corrupt>>)> {
+    let (reader, writer) = io::pipe()?;
+    let decoder = std::thread::spawn(move || {
+        let mut input = BufReader::new(file);
+        let mut output = Counting {
+            inner: writer,
+            count: 0,
+        };
+        lzma_rs::xz_decompress(&mut input, &mut output).map_err(|e| Corrupt {
+            offset: output.count,
+            message: format!("invalid xz stream: {}", e),
+        })
+    });
+    Ok((reader, decoder))
+}
+
+enum ArchiveError {
+    Io(io::Error),
+    Corrupt(Corrupt),
+}
+
+impl From<io::Error> for ArchiveError {
+    fn from(err: io::Error) -> Self {
+        ArchiveError::Io(err)
+    }
+}
+
+impl From<Corrupt> for ArchiveError {
+    fn from(corrupt: Corrupt) -> Self {
+        ArchiveError::Corrupt(corrupt)
+    }
+}
+
+fn tar_type(entry_type: tar::EntryType) -> &'static str {
+    match entry_type {
+        tar::EntryType::Regular | tar::EntryType::Continuous => "file",
+        tar::EntryType::Directory => "directory",
+        tar::EntryType::Symlink => "symlink",
+        tar::EntryType::Link => "hardlink",
+        tar::EntryType::Char => "chardevice",
+        tar::EntryType::Block => "blockdevice",
+        tar::EntryType::Fifo => "fifo",
+        _ => "unknown",
+    }
+}
+
+/// Call `f` with each entry of a tar stream and its index, until it
+/// returns false.  Parse failures carry the offset of the failing header.
+fn each_tar_entry<R: Read>(
+    reader: R,
+    mut f: impl FnMut(usize, &mut tar::Entry<'_, R>) -> Result<bool, ArchiveError>,
+) -> Result<(), ArchiveError> {
+    let mut archive = tar::Archive::new(reader);
+    let entries = archive.entries().map_err(|e| Corrupt {
+        offset: 0,
+        message: e.to_string(),
+    })?;
+
+    // Where the next header should start, to locate a failure
+    let mut next_header = 0u64;
+    for (index, entry) in entries.enumerate() {
+        let mut entry = entry.map_err(|e| Corrupt {
+            offset: next_header,
+            message: e.to_string(),
+        })?;
+        let size = entry.header().entry_size().unwrap_or(0);
+        next_header = entry.raw_file_position() + size.div_ceil(512) * 512;
+        if !f(index, &mut entry)? {
+            break;
+        }
+    }
+    Ok(())
+}
+
+fn list_tar<R: Read>(reader: R, window: &Window) -> Result<Listing, ArchiveError> {
+    let mut members = Vec::new();
+    let mut truncated = false;
+    each_tar_entry(reader, |index, entry| {
+        if index < window.offset {
+            return Ok(true);
+        }
+        if members.len() == window.max_entries {
+            truncated = true;
+            return Ok(false);
+        }
+
+        let header = entry.header();
+        let link = entry
+            .link_name_bytes()
+            .map(|link| Value::Binary(link.into_owned()));
+        members.push(msgpack_map! {
+            "name" => Value::Binary(entry.path_bytes().into_owned()),
+            "size" => header.entry_size().unwrap_or(0),
+            "mtime" => header.mtime().ok().into_value(),
+            "mode" => header.mode().ok().into_value(),
+            "type" => tar_type(header.entry_type()),
+            "link_target" => link.unwrap_or(Value::Nil)
+        });
+        Ok(true)
+    })?;
+    Ok(Listing { members, truncated })
+}
+
+/// Seconds since the epoch for an MS-DOS timestamp, which has no time zone;
+/// it is taken as UTC.
+fn dos_time_to_unix(time: zip::DateTime) -> i64 {
+    // Days from civil, per Howard Hinnant's algorithm
+    let (mut year, month, day) = (
+        i64::from(time.year()),
+        i64::from(time.month()),
+        i64::from(time.day()),
+    );
+    if month <= 2 {
+        year -= 1;
+    }
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146_097 + doe - 719_468;
+    days * 86_400
+        + i64::from(time.hour()) * 3600
+        + i64::from(time.minute()) * 60
+        + i64::from(time.second())
+}
+
+/// Modification time of a zip member: the extended timestamp if there is
+/// one, else the MS-DOS time.
+fn zip_mtime<R: Read>(member: &zip::read::ZipFile<'_, R>) -> Option<i64> {
+    member
+        .extra_data_fields()
+        .find_map(|field| match field {
+            zip::ExtraField::ExtendedTimestamp(ts) => ts.mod_time().map(i64::from),
+            _ => None,
+        })
+        .or_else(|| member.last_modified().map(dos_time_to_unix))
+}
+
+fn list_zip(
+    mut archive: zip::ZipArchive<BufReader<File>>,
+    window: &Window,
+) -> Result<Listing, ArchiveError> {
+    let end = archive
+        .len()
+        .min(window.offset.saturating_add(window.max_entries));
+    let mut members = Vec::new();
+    let central_directory = archive.central_directory_start();
+    for index in window.offset..end {
+        let member = archive
+            .by_index_raw(index)
+            .map_err(|e| zip_error(e, central_directory))?;
+        let mtime = zip_mtime(&member);
+        let kind = if member.is_dir() {
+            "directory"
+        } else if member.is_symlink() {
+            "symlink"
+        } else {
+            "file"
+        };
+        members.push(msgpack_map! {
+            "name" => Value::Binary(member.name_raw().to_vec()),
+            "size" => member.size(),
+            "compressed_size" => member.compressed_size(),
+            "mtime" => mtime.into_value(),
+            "mode" => member.unix_mode().into_value(),
+            "type" => kind,
+            "header_offset" => member.header_start()
+        });
+    }
+    Ok(Listing {
+        members,
+        truncated: end < archive.len(),
+    })
+}
+
+/// List the members of an archive.
+///
+/// Returns `{format, members, truncated}`.  Each member has a binary `name`,
+/// `size`, `mtime`, `mode` and `type` (as for file attributes, plus
+/// "hardlink"); tar members also have `link_target`, zip members
+/// `compressed_size` and `header_offset`.  `offset` skips that many members
+/// and `max_entries` caps how many are returned, with `truncated` set when
+/// more follow.  An archive that cannot be parsed fails with
+/// [`RpcError::INVALID_ARCHIVE`], whose data names the `format` and the
+/// `offset` of the failure.
+pub async fn list(params: Value) -> HandlerResult {
+    #[derive(Deserialize)]
+    struct Params {
+        #[serde(with = "path_or_bytes")]
+        path: Vec<u8>,
+        #[serde(default)]
+        offset: usize,
+        #[serde(default = "default_max_entries")]
+        max_entries: usize,
+    }
+
+    fn default_max_entries() -> usize {
+        100_000
+    }
+
+    let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
+
+    let path = bytes_to_path(&params.path);
+    jail::check(&path)?;
+
+    let window = Window {
+        offset: params.offset,
+        max_entries: params.max_entries,
+    };
+    let (format, listing) = crate::blocking::run(path.clone(), move || {
+        read_archive(
+            &path,
+            |source| match source {
+                Source::Tar(reader) => list_tar(reader, &window),
+                Source::Zip(archive) => list_zip(archive, &window),
+            },
+            |listing| listing.truncated,
+        )
+    })
+    .await??;
+
+    Ok(msgpack_map! {
+        "format" => format.as_str(),
+        "members" => Value::Array(listing.members),
+        "truncated" => listing.truncated
+    })
+}
+
+/// What an archive member is, for extraction.
+enum Kind {
+    File,
+    Directory,
+    Symlink(Vec<u8>),
+    /// Link to an earlier member, named as in the archive
+    Hardlink(Vec<u8>),
+    Unsupported(&'static str),
+}
+
+impl Kind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Kind::File => "file",
+            Kind::Directory => "directory",
+            Kind::Symlink(_) => "symlink",
+            Kind::Hardlink(_) => "hardlink",
+            Kind::Unsupported(kind) => kind,
+        }
+    }
+}
+
+/// Relative path for member `name` with `strip` leading components
+/// removed, or `None` if nothing is left.  Absolute names and names with
+/// `..` components are rejected, so nothing lands outside the destination.
+fn safe_relative(name: &[u8], strip: usize) -> Result<Option<PathBuf>, &'static str> {
+    if name.first() == Some(&b'/') {
+        return Err("absolute path");
+    }
+    let mut components = Vec::new();
+    for component in name.split(|&b| b == b'/') {
+        match component {
+            b"" | b"." => {}
+            b".." => return Err("path escapes the destination"),
+            component => components.push(component),
+        }
+    }
+    if components.len() <= strip {
+        return Ok(None);
+    }
+    Ok(Some(
+        components[strip..]
+            .iter()
+            .map(|component| OsStr::from_bytes(component))
+            .collect(),
+    ))
+}
+
+/// Member selection by name or glob.  A pattern also selects everything
+/// under a directory it names, as with `tar -x DIR`.
+struct Selection(GlobSet);
+
+impl Selection {
+    fn new(patterns: &[String]) -> Result<Self, RpcError> {
+        let mut builder = GlobSetBuilder::new();
+        for pattern in patterns {
+            let pattern = pattern.trim_start_matches("./").trim_end_matches('/');
+            for glob in [pattern.to_string(), format!("{}/**", pattern)] {
+                builder.add(Glob::new(&glob).map_err(|e| {
+                    RpcError::invalid_params(format!("Invalid member pattern {:?}: {}", pattern, e))
+                })?);
+            }
+        }
+        let set = builder
+            .build()
+            .map_err(|e| RpcError::invalid_params(e.to_string()))?;
+        Ok(Selection(set))
+    }
+
+    fn matches(&self, name: &[u8]) -> bool {
+        let mut name = name;
+        while let Some(rest) = name.strip_prefix(b"./") {
+            name = rest;
+        }
+        while let Some(rest) = name.strip_suffix(b"/") {
+            name = rest;
+        }
+        self.0.is_match(OsStr::from_bytes(name))
+    }
+}
+
+/// Whether an existing ancestor of `relative` under `root` is a symlink.
+/// Writing through one could land anywhere, so such members are rejected.
+fn through_symlink(root: &Path, relative: &Path) -> bool {
+    let mut path = root.to_path_buf();
+    let mut components = relative.components().peekable();
+    while let Some(component) = components.next() {
+        if components.peek().is_none() {
+            break;
+        }
+        path.push(component);
+        match std::fs::symlink_metadata(&path) {
+            Ok(meta) if meta.file_type().is_symlink() => return true,
+            Ok(_) => {}
+            Err(_) => break,
+        }
+    }
+    false
+}
+
+struct Extractor {
+    destination: PathBuf,
+    selection: Option<Selection>,
+    strip_components: usize,
+    dry_run: bool,
+    created: Vec<Value>,
+    rejected: Vec<Value>,
+    bytes: u64,
+    /// Directory modes and mtimes, applied once their contents are in place
+    directories: Vec<(PathBuf, Option<u32>, Option<i64>)>,
+    /// Symlinks a dry run would have created, relative to the destination
+    planned_symlinks: HashSet<PathBuf>,
+}
+
+impl Extractor {
+    /// [`through_symlink`], also counting symlinks a dry run skipped.
+    fn through_symlink(&self, relative: &Path) -> bool {
+        through_symlink(&self.destination, relative)
+            || relative
+                .ancestors()
+                .skip(1)
+                .any(|ancestor| self.planned_symlinks.contains(ancestor))
+    }
+
+    fn reject(&mut self, name: &[u8], reason: &str) {
+        self.rejected.push(msgpack_map! {
+            "name" => Value::Binary(name.to_vec()),
+            "reason" => reason
+        });
+    }
+
+    fn member(
+        &mut self,
+        name: &[u8],
+        kind: Kind,
+        mode: Option<u32>,
+        mtime: Option<i64>,
+        size: u64,
+        content: &mut dyn Read,
+    ) -> Result<(), ArchiveError> {
+        if self
+            .selection
+            .as_ref()
+            .is_some_and(|selection| !selection.matches(name))
+        {
+            return Ok(());
+        }
+        let relative = match safe_relative(name, self.strip_components) {
+            Ok(Some(relative)) => relative,
+            Ok(None) => return Ok(()),
+            Err(reason) => {
+                self.reject(name, reason);
+                return Ok(());
+            }
+        };
+        if let Kind::Unsupported(kind) = kind {
+            self.reject(name, &format!("unsupported member type: {}", kind));
+            return Ok(());
+        }
+        if self.through_symlink(&relative) {
+            self.reject(name, "path passes through a symlink");
+            return Ok(());
+        }
+        let target = self.destination.join(&relative);
+        let link_source = match &kind {
+            Kind::Hardlink(link) => match safe_relative(link, self.strip_components) {
+                Ok(Some(link)) if !self.through_symlink(&link) => Some(self.destination.join(link)),
+                _ => {
+                    self.reject(name, "hard link target escapes the destination");
+                    return Ok(());
+                }
+            },
+            Kind::Symlink(link) => {
+                if jail::check_link_target(&target, Path::new(OsStr::from_bytes(link))).is_err() {
+                    self.reject(name, "symlink target outside the jail");
+                    return Ok(());
+                }
+                None
+            }
+            _ => None,
+        };
+        let existing = std::fs::symlink_metadata(&target).ok();
+        if existing.as_ref().is_some_and(|meta| meta.is_dir()) && !matches!(kind, Kind::Directory) {
+            self.reject(name, "would replace a directory");
+            return Ok(());
+        }
+
+        let mut written = 0;
+        if self.dry_run {
+            match kind {
+                Kind::File => written = size,
+                Kind::Symlink(_) => {
+                    self.planned_symlinks.insert(relative);
+                }
+                _ => {}
+            }
+        } else {
+            if let Some(parent) = target.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            // Replace, rather than write through, whatever is in the way.
+            if existing.is_some() && !matches!(kind, Kind::Directory) {
+                std::fs::remove_file(&target)?;
+            }
+            match &kind {
+                Kind::File => {
+                    let mode = mode.unwrap_or(0o644) & 0o777;
+                    let mut file = std::fs::OpenOptions::new()
+                        .write(true)
+                        .create_new(true)
+                        .mode(mode)
+                        .open(&target)?;
+                    written = io::copy(content, &mut file)?;
+                    // The umask may have stripped bits the archive asked for.
+                    file.set_permissions(std::fs::Permissions::from_mode(mode))?;
+                }
+                Kind::Directory => {
+                    std::fs::create_dir_all(&target)?;
+                    self.directories.push((target.clone(), mode, mtime));
+                }
+                Kind::Symlink(link) => {
+                    std::os::unix::fs::symlink(OsStr::from_bytes(link), &target)?;
+                }
+                Kind::Hardlink(_) => {
+                    if let Some(source) = &link_source {
+                        std::fs::hard_link(source, &target)?;
+                    }
+                }
+                Kind::Unsupported(_) => unreachable!("rejected above"),
+            }
+            if let (Some(mtime), false) = (mtime, matches!(kind, Kind::Directory)) {
+                set_file_times_sync_path_io(&target, mtime, 0, mtime, 0, true)?;
+            }
+        }
+
+        self.bytes += written;
+        self.created.push(msgpack_map! {
+            "path" => Value::Binary(target.as_os_str().as_bytes().to_vec()),
+            "type" => kind.as_str(),
+            "size" => written
+        });
+        Ok(())
+    }
+
+    fn tar(&mut self, reader: Box<dyn Read>) -> Result<(), ArchiveError> {
+        each_tar_entry(reader, |_, entry| {
+            let header = entry.header();
+            let (mode, mtime) = (header.mode().ok(), header.mtime().ok());
+            let size = header.entry_size().unwrap_or(0);
+            let link = || entry.link_name_bytes().unwrap_or_default().into_owned();
+            let kind = match header.entry_type() {
+                tar::EntryType::Regular | tar::EntryType::Continuous => Kind::File,
+                tar::EntryType::Directory => Kind::Directory,
+                tar::EntryType::Symlink => Kind::Symlink(link()),
+                tar::EntryType::Link => Kind::Hardlink(link()),
+                other => Kind::Unsupported(tar_type(other)),
+            };
+            let name = entry.path_bytes().into_owned();
+            self.member(&name, kind, mode, mtime.map(|t| t as i64), size, entry)?;
+            Ok(true)
+        })
+    }
+
+    fn zip(&mut self, mut archive: zip::ZipArchive<BufReader<File>>) -> Result<(), ArchiveError> {
+        let central_directory = archive.central_directory_start();
+        for index in 0..archive.len() {
+            let name = archive
+                .by_index_raw(index)
+                .map_err(|e| zip_error(e, central_directory))?
+                .name_raw()
+                .to_vec();
+            let mut member = match archive.by_index(index) {
+                Ok(member) => member,
+                Err(
+                    e @ (zip::result::ZipError::UnsupportedArchive(_)
+                    | zip::result::ZipError::CompressionMethodNotSupported(_)),
+                ) => {
+                    self.reject(&name, &e.to_string());
+                    continue;
+                }
+                Err(e) => return Err(zip_error(e, central_directory)),
+            };
+            let (mode, mtime, size) = (member.unix_mode(), zip_mtime(&member), member.size());
+            let kind = if member.is_dir() {
+                Kind::Directory
+            } else if member.is_symlink() {
+                let mut link = Vec::new();
+                member.read_to_end(&mut link)?;
+                Kind::Symlink(link)
+            } else {
+                Kind::File
+            };
+            self.member(&name, kind, mode, mtime, size, &mut member)?;
+        }
+        Ok(())
+    }
+
+    /// Apply directory modes and mtimes, innermost first so that setting
+    /// one does not disturb another.
+    fn finish_directories(&mut self) -> io::Result<()> {
+        for (dir, mode, mtime) in self.directories.drain(..).rev() {
+            if let Some(mode) = mode {
+                std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(mode & 0o1777))?;
+            }
+            if let Some(mtime) = mtime {
+                set_file_times_sync_path_io(&dir, mtime, 0, mtime, 0, true)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Extract an archive into `destination`.
+///
+/// `members` limits extraction to members matching any of the given names
+/// or globs (a directory name selects its contents too), and
+/// `strip_components` drops that many leading path components, as with
+/// `tar --strip-components`.  Modes (less setuid and setgid bits) and
+/// mtimes are preserved.  Members that
+/// would land outside `destination` (absolute names, `..`, paths through
+/// symlinks) or are devices or fifos are not extracted but listed in
+/// `rejected` as `{name, reason}`.  Returns `{created, rejected, bytes}`,
+/// `created` holding `{path, type, size}` for each file made; with
+/// `dry_run` nothing is written and `created` lists what would be.
+pub async fn extract(params: Value) -> HandlerResult {
+    #[derive(Deserialize)]
+    struct Params {
+        #[serde(with = "path_or_bytes")]
+        path: Vec<u8>,
+        #[serde(with = "path_or_bytes")]
+        destination: Vec<u8>,
+        #[serde(default)]
+        members: Option<Vec<String>>,
+        #[serde(default)]
+        strip_components: usize,
+        #[serde(default)]
+        dry_run: bool,
+    }
+
+    let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
+
+    let path = bytes_to_path(&params.path);
+    jail::check(&path)?;
+    let destination = bytes_to_path(&params.destination);
+    jail::check(&destination)?;
+    let dest_str = destination.to_string_lossy().into_owned();
+
+    let mut extractor = Extractor {
+        destination: destination.clone(),
+        selection: params.members.as_deref().map(Selection::new).transpose()?,
+        strip_components: params.strip_components,
+        dry_run: params.dry_run,
+        created: Vec::new(),
+        rejected: Vec::new(),
+        bytes: 0,
+        directories: Vec::new(),
+        planned_symlinks: HashSet::new(),
+    };
+
+    let extractor = crate::blocking::run(path.clone(), move || -> Result<Extractor, RpcError> {
+        if !extractor.dry_run {
+            std::fs::create_dir_all(&extractor.destination)
+                .map_err(|e| map_io_error(e, &dest_str))?;
+        }
+        let result = read_archive(
+            &path,
+            |source| match source {
+                Source::Tar(reader) => extractor.tar(reader),
+                Source::Zip(archive) => extractor.zip(archive),
+            },
+            |_| false,
+        );
+        // Directories made before a failure still get their modes back.
+        let finished = extractor.finish_directories();
+        result?;
+        finished.map_err(|e| map_io_error(e, &dest_str))?;
+        Ok(extractor)
+    })
+    .await?;
+    if !params.dry_run {
+        stat_cache::invalidate_tree(&destination).await;
+    }
+    let extractor = extractor?;
+
+    Ok(msgpack_map! {
+        "created" => Value::Array(extractor.created),
+        "rejected" => Value::Array(extractor.rejected),
+        "bytes" => extractor.bytes,
+        "dry_run" => params.dry_run
+    })
+}
+
+/// Largest number of `archive.create` streams open at once.
+const MAX_OPEN_STREAMS: usize = 16;
+
+/// Streams not read from for this long are dropped, which stops their
+/// writer.
+const STREAM_IDLE_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// Bytes the writer collects before handing a chunk to `archive.read`.
+const STREAM_CHUNK_SIZE: usize = 256 * 1024;
+
+fn output_format(name: &str) -> Result<Format, RpcError> {
+    match name {
+        "tar" => Ok(Format::Tar),
+        "tar.gz" | "tgz" => Ok(Format::TarGz),
+        "tar.zst" => Ok(Format::TarZst),
+        "zip" => Ok(Format::Zip),
+        other => Err(RpcError::invalid_params(format!(
+            "Unsupported output format: {}",
+            other
+        ))),
+    }
+}
+
+/// What an input file is, as seen by the archive writer.
+enum InputKind {
+    File,
+    Directory,
+    /// Only when symlinks are preserved
+    Symlink(PathBuf),
+}
+
+/// A file to put in an archive, under `name`.
+struct Input {
+    path: PathBuf,
+    name: PathBuf,
+    kind: InputKind,
+}
+
+/// Walks the files to archive, applying the include and exclude globs.
+struct Collector {
+    follow_symlinks: bool,
+    include: Option<Selection>,
+    exclude: Option<Selection>,
+    /// `(dev, ino)` of files never to archive: the output itself
+    skip: Vec<(u64, u64)>,
+    inputs: Vec<Input>,
+    /// `{path, reason}` for files that were left out
+    skipped: Vec<Value>,
+    /// `(dev, ino)` of the directories being walked, to stop symlink loops
+    ancestors: Vec<(u64, u64)>,
+}
+
+impl Collector {
+    fn skip(&mut self, path: &Path, reason: &str) {
+        self.skipped.push(msgpack_map! {
+            "path" => Value::Binary(path.as_os_str().as_bytes().to_vec()),
+            "reason" => reason
+        });
+    }
+
+    fn add(&mut self, path: &Path, name: PathBuf) -> io::Result<()> {
+        let named = !name.as_os_str().is_empty();
+        let name_bytes = name.as_os_str().as_bytes();
+        if named
+            && self
+                .exclude
+                .as_ref()
+                .is_some_and(|exclude| exclude.matches(name_bytes))
+        {
+            return Ok(());
+        }
+
+        let lstat = std::fs::symlink_metadata(path)?;
+        let meta = if self.follow_symlinks && lstat.file_type().is_symlink() {
+            if !jail::contains(path) {
+                self.skip(path, "symlink target outside the jail");
+                return Ok(());
+            }
+            match std::fs::metadata(path) {
+                Ok(meta) => meta,
+                Err(_) => {
+                    self.skip(path, "dangling symlink");
+                    return Ok(());
+                }
+            }
+        } else {
+            lstat
+        };
+        if self.skip.contains(&(meta.dev(), meta.ino())) {
+            return Ok(());
+        }
+
+        let file_type = meta.file_type();
+        if file_type.is_dir() {
+            let key = (meta.dev(), meta.ino());
+            if self.ancestors.contains(&key) {
+                self.skip(path, "symlink loop");
+                return Ok(());
+            }
+            // With include globs, directories only appear through the files
+            // selected in them.
+            if named && self.include.is_none() {
+                self.inputs.push(Input {
+                    path: path.to_path_buf(),
+                    name: name.clone(),
+                    kind: InputKind::Directory,
+                });
+            }
+
+            let mut children = std::fs::read_dir(path)?
+                .map(|entry| entry.map(|entry| entry.file_name()))
+                .collect::<io::Result<Vec<_>>>()?;
+            children.sort();
+            self.ancestors.push(key);
+            for child in children {
+                let child_path = path.join(&child);
+                if let Err(e) = self.add(&child_path, name.join(&child)) {
+                    self.skip(&child_path, &e.to_string());
+                }
+            }
+            self.ancestors.pop();
+            return Ok(());
+        }
+
+        if !file_type.is_file() && !file_type.is_symlink() {
+            self.skip(path, "not a regular file, directory or symlink");
+            return Ok(());
+        }
+        if self
+            .include
+            .as_ref()
+            .is_some_and(|include| !include.matches(name_bytes))
+        {
+            return Ok(());
+        }
+        let kind = if file_type.is_symlink() {
+            InputKind::Symlink(std::fs::read_link(path)?)
+        } else {
+            InputKind::File
+        };
+        self.inputs.push(Input {
+            path: path.to_path_buf(),
+            name,
+            kind,
+        });
+        Ok(())
+    }
+}
+
+/// Write `inputs` to `sink` as a `format` archive.
+fn write_archive<W: Write>(
+    format: Format,
+    inputs: &[Input],
+    follow_symlinks: bool,
+    sink: W,
+) -> io::Result<W> {
+    match format {
+        Format::Tar => write_tar(inputs, follow_symlinks, sink),
+        Format::TarGz => write_tar(
+            inputs,
+            follow_symlinks,
+            flate2::write::GzEncoder::new(sink, flate2::Compression::default()),
+        )?
+        .finish(),
+        Format::TarZst => write_zst(inputs, follow_symlinks, sink),
+        Format::Zip => write_zip(inputs, follow_symlinks, sink),
+        Format::TarXz => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "xz output is not supported",
+        )),
+    }
+}
+
+fn write_tar<W: Write>(inputs: &[Input], follow_symlinks: bool, sink: W) -> io::Result<W> {
+    let mut builder = tar::Builder::new(sink);
+    builder.follow_symlinks(follow_symlinks);
+    for input in inputs {
+        match input.kind {
+            InputKind::Directory => builder.append_dir(&input.name, &input.path)?,
+            _ => builder.append_path_with_name(&input.path, &input.name)?,
+        }
+    }
+    builder.into_inner()
+}
+
+/// Reader or writer that records its first error instead of returning it.
+///
+/// The zstd encoder panics on I/O errors, which would abort the server, so
+/// it only ever sees successful reads (a failed one looks like the end of
+/// the input) and writes (discarded after a failure).
+struct Guarded<'a, T> {
+    inner: T,
+    error: &'a RefCell<Option<io::Error>>,
+}
+
+impl<T: Read> Read for Guarded<'_, T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.error.borrow().is_some() {
+            return Ok(0);
+        }
+        loop {
+            match self.inner.read(buf) {
+                Ok(n) => return Ok(n),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    *self.error.borrow_mut() = Some(e);
+                    return Ok(0);
+                }
+            }
+        }
+    }
+}
+
+impl<T: Write> Write for Guarded<'_, T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.error.borrow().is_none()
+            && let Err(e) = self.inner.write_all(buf)
+        {
+            *self.error.borrow_mut() = Some(e);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.error.borrow().is_none()
+            && let Err(e) = self.inner.flush()
+        {
+            *self.error.borrow_mut() = Some(e);
+        }
+        Ok(())
+    }
+}
+
+/// `ruzstd` compresses from a reader, so the tar is written on a scoped
+/// thread and fed to it through a pipe.
+fn write_zst<W: Write>(inputs: &[Input], follow_symlinks: bool, sink: W) -> io::Result<W> {
+    let (reader, writer) = io::pipe()?;
+    std::thread::scope(|scope| {
+        let tar = scope.spawn(move || write_tar(inputs, follow_symlinks, writer).map(drop));
+
+        let error = RefCell::new(None);
+        let source = Guarded {
+            inner: reader,
+            error: &error,
+        };
+        let mut drain = Guarded {
+            inner: sink,
+            error: &error,
+        };
+        ruzstd::encoding::compress(
+            source,
+            &mut drain,
+            ruzstd::encoding::CompressionLevel::Fastest,
+        );
+        // `compress` dropped the pipe's read end, so a tar writer stuck on a
+        // failed sink has already been unblocked.
+        let written = tar
+            .join()
+            .unwrap_or_else(|_| Err(io::Error::other("tar writer panicked")));
+        let sink = drain.inner;
+        match error.into_inner() {
+            Some(e) => Err(e),
+            None => written.map(|()| sink),
+        }
+    })
+}
+
+/// Seconds since the epoch as an MS-DOS timestamp, if representable
+/// (1980-2107).  The inverse of [`dos_time_to_unix`].
+fn unix_to_dos_time(secs: i64) -> Option<zip::DateTime> {
+    // Civil from days, per Howard Hinnant's algorithm
+    let (days, secs_of_day) = (secs.div_euclid(86_400), secs.rem_euclid(86_400));
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    zip::DateTime::from_date_and_time(
+        u16::try_from(year).ok()?,
+        month as u8,
+        day as u8,
+        (secs_of_day / 3600) as u8,
+        (secs_of_day / 60 % 60) as u8,
+        (secs_of_day % 60) as u8,
+    )
+    .ok()
+}
+
+fn write_zip<W: Write>(inputs: &[Input], follow_symlinks: bool, sink: W) -> io::Result<W> {
+    let mut zip = zip::ZipWriter::new_stream(sink);
+    for input in inputs {
+        let meta = if follow_symlinks {
+            std::fs::metadata(&input.path)?
+        } else {
+            std::fs::symlink_metadata(&input.path)?
+        };
+        let mut options = zip::write::SimpleFileOptions::default()
+            .compression_method(zip::CompressionMethod::Deflated)
+            .unix_permissions(meta.mode() & 0o7777);
+        if let Some(mtime) = unix_to_dos_time(meta.mtime()) {
+            options = options.last_modified_time(mtime);
+        }
+        let name = input.name.to_string_lossy();
+        match &input.kind {
+            InputKind::Directory => zip.add_directory(name, options)?,
+            InputKind::Symlink(target) => {
+                zip.add_symlink(name, target.to_string_lossy(), options)?
+            }
+            InputKind::File => {
+                zip.start_file(name, options.large_file(meta.len() >= u64::from(u32::MAX)))?;
+                io::copy(&mut File::open(&input.path)?, &mut zip)?;
+            }
+        }
+    }
+    Ok(zip.finish()?.into_inner())
+}
+
+/// Writer passing an archive to `archive.read` in chunks.
+struct ChannelWriter {
+    tx: mpsc::Sender<StreamMessage>,
+    buf: Vec<u8>,
+    total: u64,
+}
+
+impl ChannelWriter {
+    fn send(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        let chunk = std::mem::replace(&mut self.buf, Vec::with_capacity(STREAM_CHUNK_SIZE));
+        self.tx
+            .blocking_send(StreamMessage::Data(chunk))
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+    }
+}
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        self.total += buf.len() as u64;
+        if self.buf.len() >= STREAM_CHUNK_SIZE {
+            self.send()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send()
+    }
+}
+
+enum StreamMessage {
+    Data(Vec<u8>),
+    /// The archive is complete, with this many bytes in total
+    Done(u64),
+    Failed(RpcError),
+}
+
+struct ArchiveStream {
+    rx: mpsc::Receiver<StreamMessage>,
+    last_activity: Instant,
+}
+
+static STREAM_MAP: OnceLock<Mutex<HashMap<u32, ArchiveStream>>> = OnceLock::new();
+static STREAM_ID_COUNTER: OnceLock<Mutex<u32>> = OnceLock::new();
+
+fn get_stream_map() -> &'static Mutex<HashMap<u32, ArchiveStream>> {
+    STREAM_MAP.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+async fn get_next_stream_id() -> u32 {
+    let counter = STREAM_ID_COUNTER.get_or_init(|| Mutex::new(1));
+    let mut id = counter.lock().await;
+    let current = *id;
+    *id = id.wrapping_add(1).max(1);
+    current
+}
+
+fn stream_not_found(id: u32) -> RpcError {
+    RpcError::invalid_params(format!("Archive stream not found: {}", id))
+}
+
+/// Create an archive from remote files.
+///
+/// The members are `paths` (relative to `root` if given, and named
+/// relative to it), or everything under `root`; directories are added
+/// recursively.  `include` and `exclude` globs filter members by archive
+/// name, an excluded directory dropping its whole subtree.  `format` is
+/// "tar", "tar.gz", "tar.zst" or "zip".  Symlinks are stored as links
+/// unless `follow_symlinks` is set.
+///
+/// The archive is written to `output` (via a temporary sibling), returning
+/// `{path, size, members, skipped}`, where `skipped` lists `{path, reason}`
+/// for files left out.  With `stream` set there is no output file:
+/// `{id, members, skipped}` is returned and the archive bytes are fetched
+/// with `archive.read`.
+pub async fn create(params: Value) -> HandlerResult {
+    #[derive(Deserialize)]
+    struct Params {
+        #[serde(default)]
+        paths: Option<Vec<PathBytes>>,
+        #[serde(default)]
+        root: Option<PathBytes>,
+        #[serde(default)]
+        include: Option<Vec<String>>,
+        #[serde(default)]
+        exclude: Option<Vec<String>>,
+        #[serde(default)]
+        output: Option<PathBytes>,
+        format: String,
+        #[serde(default)]
+        follow_symlinks: bool,
+        #[serde(default)]
+        stream: bool,
+    }
+
+    let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
+    let format = output_format(&params.format)?;
+
+    let root = params.root.as_ref().map(|root| bytes_to_path(&root.0));
+    let mut tops = Vec::new();
+    match (&params.paths, &root) {
+        (Some(paths), _) => {
+            for PathBytes(path) in paths {
+                let path = bytes_to_path(path);
+                let (full, name) = match &root {
+                    Some(root) => {
+                        let full = root.join(&path);
+                        let name = full
+                            .strip_prefix(root)
+                            .map_err(|_| {
+                                RpcError::invalid_params(format!(
+                                    "Path is not under root: {}",
+                                    path.display()
+                                ))
+                            })?
+                            .to_path_buf();
+                        (full, name)
+                    }
+                    None => {
+                        let name = path.strip_prefix("/").unwrap_or(&path).to_path_buf();
+                        (path, name)
+                    }
+                };
+                jail::check(&full)?;
+                tops.push((full, name));
+            }
+        }
+        (None, Some(root)) => {
+            jail::check(root)?;
+            tops.push((root.clone(), PathBuf::new()));
+        }
+        (None, None) => return Err(RpcError::invalid_params("Either paths or root is required")),
+    }
+
+    let output = match (params.output.as_ref(), params.stream) {
+        (Some(output), false) => Some(bytes_to_path(&output.0)),
+        (None, true) => None,
+        (Some(_), true) => {
+            return Err(RpcError::invalid_params(
+                "output and stream are mutually exclusive",
+            ));
+        }
+        (None, false) => return Err(RpcError::invalid_params("output is required")),
+    };
+    if let Some(output) = &output {
+        jail::check(output)?;
+    }
+
+    let mut stream_slot = None;
+    if params.stream {
+        let mut streams = get_stream_map().lock().await;
+        streams.retain(|_, stream| stream.last_activity.elapsed() < STREAM_IDLE_TIMEOUT);
+        if streams.len() >= MAX_OPEN_STREAMS {
+            return Err(RpcError::invalid_request(format!(
+                "Too many open archive streams (max {})",
+                MAX_OPEN_STREAMS
+            )));
+        }
+        stream_slot = Some(mpsc::channel(4));
+    }
+
+    let mut collector = Collector {
+        follow_symlinks: params.follow_symlinks,
+        include: params.include.as_deref().map(Selection::new).transpose()?,
+        exclude: params.exclude.as_deref().map(Selection::new).transpose()?,
+        skip: Vec::new(),
+        inputs: Vec::new(),
+        skipped: Vec::new(),
+        ancestors: Vec::new(),
+    };
+
+    let Some(output) = output else {
+        // Streaming: collect now, so that missing paths fail this call, and
+        // leave the writing to a thread feeding `archive.read`.
+        let collector = crate::blocking::run(
+            root.clone().unwrap_or_default(),
+            move || -> Result<Collector, RpcError> {
+                for (path, name) in tops {
+                    collector
+                        .add(&path, name)
+                        .map_err(|e| map_io_error(e, &path))?;
+                }
+                Ok(collector)
+            },
+        )
+        .await??;
+
+        let (tx, rx) = stream_slot.expect("stream channel");
+        let members = collector.inputs.len();
+        let follow_symlinks = params.follow_symlinks;
+        let inputs = collector.inputs;
+        std::thread::spawn(move || {
+            let writer = ChannelWriter {
+                tx: tx.clone(),
+                buf: Vec::with_capacity(STREAM_CHUNK_SIZE),
+                total: 0,
+            };
+            let message = match write_archive(format, &inputs, follow_symlinks, writer)
+                .and_then(|mut writer| writer.flush().map(|()| writer))
+            {
+                Ok(writer) => StreamMessage::Done(writer.total),
+                Err(e) => StreamMessage::Failed(RpcError::io_error(e)),
+            };
+            let _ = tx.blocking_send(message);
+        });
+
+        let id = get_next_stream_id().await;
+        get_stream_map().lock().await.insert(
+            id,
+            ArchiveStream {
+                rx,
+                last_activity: Instant::now(),
+            },
+        );
+        return Ok(msgpack_map! {
+            "id" => id,
+            "members" => members,
+            "skipped" => Value::Array(collector.skipped)
+        });
+    };
+
+    let (size, collector) = {
+        let output = output.clone();
+        crate::blocking::run(
+            output.clone(),
+            move || -> Result<(u64, Collector), RpcError> {
+                let name = output.file_name().unwrap_or_default().to_string_lossy();
+                let temp = output.with_file_name(format!(
+                    ".{}.tramp-rpc-archive-{}",
+                    name,
+                    std::process::id()
+                ));
+                let file = std::fs::OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .mode(0o644)
+                    .open(&temp)
+                    .map_err(|e| map_io_error(e, &output))?;
+                let built = (|| {
+                    let meta = file.metadata()?;
+                    collector.skip.push((meta.dev(), meta.ino()));
+                    if let Ok(meta) = std::fs::metadata(&output) {
+                        collector.skip.push((meta.dev(), meta.ino()));
+                    }
+                    for (path, name) in tops {
+                        collector.add(&path, name)?;
+                    }
+                    let file = write_archive(
+                        format,
+                        &collector.inputs,
+                        collector.follow_symlinks,
+                        io::BufWriter::new(file),
+                    )?
+                    .into_inner()
+                    .map_err(|e| e.into_error())?;
+                    file.sync_all()?;
+                    std::fs::rename(&temp, &output)?;
+                    Ok(file.metadata()?.len())
+                })();
+                match built {
+                    Ok(size) => Ok((size, collector)),
+                    Err(e) => {
+                        let _ = std::fs::remove_file(&temp);
+                        Err(map_io_error(e, &output))
+                    }
+                }
+            },
+        )
+        .await??
+    };
+    stat_cache::invalidate(&output).await;
+
+    Ok(msgpack_map! {
+        "path" => Value::Binary(output.as_os_str().as_bytes().to_vec()),
+        "size" => size,
+        "members" => collector.inputs.len(),
+        "skipped" => Value::Array(collector.skipped)
+    })
+}
+
+/// Read the next bytes of an `archive.create` stream.
+///
+/// Waits for at least one chunk, then returns what is ready, up to about
+/// `max_bytes`, as `{content, eof}`.  The last response has `eof` set and
+/// the archive's total `size`; the stream is closed after it.
+pub async fn read(params: Value) -> HandlerResult {
+    #[derive(Deserialize)]
+    struct Params {
+        id: u32,
+        #[serde(default = "default_max_bytes")]
+        max_bytes: usize,
+    }
+
+    fn default_max_bytes() -> usize {
+        1024 * 1024
+    }
+
+    let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
+
+    // Take the stream out while waiting, so the map is not locked meanwhile.
+    let mut stream = get_stream_map()
+        .lock()
+        .await
+        .remove(&params.id)
+        .ok_or_else(|| stream_not_found(params.id))?;
+
+    let mut content = Vec::new();
+    let mut size = None;
+    while content.len() < params.max_bytes {
+        let message = if content.is_empty() {
+            stream.rx.recv().await
+        } else {
+            match stream.rx.try_recv() {
+                Ok(message) => Some(message),
+                Err(mpsc::error::TryRecvError::Empty) => break,
+                Err(mpsc::error::TryRecvError::Disconnected) => None,
+            }
+        };
+        match message {
+            Some(StreamMessage::Data(chunk)) => content.extend_from_slice(&chunk),
+            Some(StreamMessage::Done(total)) => {
+                size = Some(total);
+                break;
+            }
+            Some(StreamMessage::Failed(e)) => return Err(e),
+            None => return Err(RpcError::internal_error("Archive writer exited")),
+        }
+    }
+
+    if size.is_none() {
+        stream.last_activity = Instant::now();
+        get_stream_map().lock().await.insert(params.id, stream);
+    }
+    Ok(msgpack_map! {
+        "content" => Value::Binary(content),
+        "eof" => size.is_some(),
+        "size" => size.into_value()
+    })
+}
+
+/// Abandon an `archive.create` stream.  Returns whether it was open.
+pub async fn close(params: Value) -> HandlerResult {
+    #[derive(Deserialize)]
+    struct Params {
+        id: u32,
+    }
+
+    let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
+    let removed = get_stream_map().lock().await.remove(&params.id);
+    Ok(Value::Boolean(removed.is_some()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::os::unix::ffi::OsStrExt;
+
+    fn path_value(path: &Path) -> Value {
+        Value::Binary(path.as_os_str().as_bytes().to_vec())
+    }
+
+    fn tar_bytes() -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (name, content) in [("a.txt", &b"alpha"[..]), ("dir/b.txt", &b"beta!"[..])] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o640);
+            header.set_mtime(1_700_000_000);
+            header.set_cksum();
+            builder.append_data(&mut header, name, content).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    #[test]
+    fn dos_times_convert_to_unix_seconds() {
+        let time = zip::DateTime::from_date_and_time(2023, 11, 14, 22, 13, 20).unwrap();
+        assert_eq!(dos_time_to_unix(time), 1_700_000_000);
+    }
+
+    #[tokio::test]
+    async fn lists_compressed_tars_and_zips_with_paging() {
+        let tmp = tempfile::tempdir().unwrap();
+
+        let tgz = tmp.path().join("a.tar.gz");
+        let mut encoder =
+            flate2::write::GzEncoder::new(File::create(&tgz).unwrap(), flate2::Compression::fast());
+        encoder.write_all(&tar_bytes()).unwrap();
+        encoder.finish().unwrap();
+
+        let txz = tmp.path().join("a.tar.xz");
+        lzma_rs::xz_compress(&mut &tar_bytes()[..], &mut File::create(&txz).unwrap()).unwrap();
+
+        for (path, format) in [(&tgz, "tar.gz"), (&txz, "tar.xz")] {
+            let listing = list(msgpack_map! { "path" => path_value(path) })
+                .await
+                .unwrap();
+            assert_eq!(listing["format"].as_str(), Some(format));
+            let members = listing["members"].as_array().unwrap();
+            assert_eq!(members.len(), 2);
+            assert_eq!(members[1]["name"].as_slice(), Some(&b"dir/b.txt"[..]));
+            assert_eq!(members[1]["size"].as_u64(), Some(5));
+            assert_eq!(members[1]["mode"].as_u64(), Some(0o640));
+            assert_eq!(members[1]["mtime"].as_u64(), Some(1_700_000_000));
+        }
+
+        let zip_path = tmp.path().join("a.zip");
+        let mut writer = zip::ZipWriter::new(File::create(&zip_path).unwrap());
+        let options = zip::write::SimpleFileOptions::default()
+            .compression_method(zip::CompressionMethod::Stored);
+        for name in ["one", "two", "three"] {
+            writer.start_file(name, options).unwrap();
+            writer.write_all(name.as_bytes()).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let listing = list(msgpack_map! {
+            "path" => path_value(&zip_path),
+            "offset" => 1,
+            "max_entries" => 1,
+        })
+        .await
+        .unwrap();
+        assert_eq!(listing["format"].as_str(), Some("zip"));
+        assert_eq!(listing["truncated"].as_bool(), Some(true));
+        let members = listing["members"].as_array().unwrap();
+        assert_eq!(members.len(), 1);
+        assert_eq!(members[0]["name"].as_slice(), Some(&b"two"[..]));
+        assert_eq!(members[0]["compressed_size"].as_u64(), Some(3));
+    }
+
+    #[tokio::test]
+    async fn truncated_xz_is_reported_as_corrupt() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("bad.tar.xz");
+        let mut bytes = Vec::new();
+        lzma_rs::xz_compress(&mut &tar_bytes()[..], &mut bytes).unwrap();
+        bytes.truncate(bytes.len() / 2);
+        std::fs::write(&path, &bytes).unwrap();
+
+        let err = list(msgpack_map! { "path" => path_value(&path) })
+            .await
+            .expect_err("truncated stream");
+        assert_eq!(err.code, RpcError::INVALID_ARCHIVE);
+        assert_eq!(err.data.unwrap()["format"].as_str(), Some("tar.xz"));
+    }
+
+    fn append(
+        builder: &mut tar::Builder<Vec<u8>>,
+        name: &[u8],
+        entry_type: tar::EntryType,
+        link: Option<&str>,
+        content: &[u8],
+    ) {
+        let mut header = tar::Header::new_gnu();
+        // Bypass the builder's own path checks to craft hostile names
+        header.as_old_mut().name[..name.len()].copy_from_slice(name);
+        header.set_entry_type(entry_type);
+        header.set_size(content.len() as u64);
+        header.set_mode(0o750);
+        header.set_mtime(1_600_000_000);
+        if let Some(link) = link {
+            header.set_link_name(link).unwrap();
+        }
+        header.set_cksum();
+        builder.append(&header, content).unwrap();
+    }
+
+    #[tokio::test]
+    async fn extract_selects_strips_and_rejects_escapes() {
+        use std::os::unix::fs::MetadataExt;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let mut builder = tar::Builder::new(Vec::new());
+        let regular = tar::EntryType::Regular;
+        append(
+            &mut builder,
+            b"top/src/main.rs",
+            regular,
+            None,
+            b"fn main() {}",
+        );
+        append(&mut builder, b"top/src/lib.rs", regular, None, b"lib");
+        append(&mut builder, b"top/README", regular, None, b"readme");
+        append(&mut builder, b"top/../../evil", regular, None, b"x");
+        append(
+            &mut builder,
+            b"top/link",
+            tar::EntryType::Symlink,
+            Some("/tmp"),
+            b"",
+        );
+        append(&mut builder, b"top/link/evil", regular, None, b"x");
+        let archive = tmp.path().join("a.tar");
+        std::fs::write(&archive, builder.into_inner().unwrap()).unwrap();
+        let dest = tmp.path().join("out");
+
+        let params = |dry_run: bool| {
+            msgpack_map! {
+                "path" => path_value(&archive),
+                "destination" => path_value(&dest),
+                "members" => Value::Array(vec!["top/src/*.rs".into(), "top/link".into(), "top/../../evil".into()]),
+                "strip_components" => 1,
+                "dry_run" => dry_run,
+            }
+        };
+
+        let planned = extract(params(true)).await.unwrap();
+        assert_eq!(planned["created"].as_array().unwrap().len(), 3);
+        assert_eq!(planned["bytes"].as_u64(), Some(15));
+        assert!(!dest.exists());
+
+        let result = extract(params(false)).await.unwrap();
+        assert_eq!(result["bytes"].as_u64(), Some(15));
+        let main = dest.join("src/main.rs");
+        assert_eq!(std::fs::read(&main).unwrap(), b"fn main() {}");
+        let meta = std::fs::metadata(&main).unwrap();
+        assert_eq!(meta.mode() & 0o777, 0o750);
+        assert_eq!(meta.mtime(), 1_600_000_000);
+        assert!(!dest.join("README").exists());
+        assert!(!tmp.path().join("evil").exists());
+
+        let rejected: Vec<_> = result["rejected"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|r| r["name"].as_slice().unwrap().to_vec())
+            .collect();
+        assert_eq!(
+            rejected,
+            vec![b"top/../../evil".to_vec(), b"top/link/evil".to_vec()]
+        );
+    }
+
+    #[test]
+    fn dos_time_conversion_round_trips() {
+        let time = unix_to_dos_time(1_700_000_000).unwrap();
+        assert_eq!(dos_time_to_unix(time), 1_700_000_000);
+        assert!(unix_to_dos_time(0).is_none());
+    }
+
+    fn sample_tree(root: &Path) {
+        std::fs::create_dir_all(root.join("src")).unwrap();
+        std::fs::create_dir_all(root.join("target")).unwrap();
+        std::fs::write(root.join("src/main.rs"), b"fn main() {}").unwrap();
+        std::fs::write(root.join("target/big.o"), b"object").unwrap();
+        std::os::unix::fs::symlink("src/main.rs", root.join("link")).unwrap();
+    }
+
+    fn member_names(listing: &Value) -> Vec<String> {
+        listing["members"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|m| String::from_utf8(m["name"].as_slice().unwrap().to_vec()).unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn create_writes_archives_that_list_back() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().join("project");
+        sample_tree(&root);
+
+        for format in ["tar", "tar.gz", "tar.zst", "zip"] {
+            // Written inside the tree, to check it does not archive itself
+            let output = root.join(format!("snapshot.{}", format));
+            let created = create(msgpack_map! {
+                "root" => path_value(&root),
+                "exclude" => Value::Array(vec!["target".into()]),
+                "output" => path_value(&output),
+                "format" => format,
+            })
+            .await
+            .unwrap();
+            assert_eq!(created["members"].as_u64(), Some(3), "{}", format);
+            assert_eq!(
+                created["size"].as_u64(),
+                Some(std::fs::metadata(&output).unwrap().len())
+            );
+
+            let listing = list(msgpack_map! { "path" => path_value(&output) })
+                .await
+                .unwrap();
+            let names = member_names(&listing);
+            let names: Vec<_> = names.iter().map(|n| n.trim_end_matches('/')).collect();
+            assert_eq!(names, ["link", "src", "src/main.rs"], "{}", format);
+            let link = &listing["members"][0];
+            assert_eq!(link["type"].as_str(), Some("symlink"), "{}", format);
+            std::fs::remove_file(&output).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn create_streams_archive_bytes() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().join("project");
+        sample_tree(&root);
+
+        let created = create(msgpack_map! {
+            "root" => path_value(&root),
+            "paths" => Value::Array(vec!["src".into(), "link".into()]),
+            "format" => "tar.gz",
+            "follow_symlinks" => true,
+            "stream" => true,
+        })
+        .await
+        .unwrap();
+        let id = created["id"].as_u64().unwrap();
+
+        let mut bytes = Vec::new();
+        loop {
+            let chunk = read(msgpack_map! { "id" => id, "max_bytes" => 64 })
+                .await
+                .unwrap();
+            bytes.extend_from_slice(chunk["content"].as_slice().unwrap());
+            if chunk["eof"].as_bool() == Some(true) {
+                assert_eq!(chunk["size"].as_u64(), Some(bytes.len() as u64));
+                break;
+            }
+        }
+        let err = read(msgpack_map! { "id" => id }).await.unwrap_err();
+        assert_eq!(err.code, RpcError::INVALID_PARAMS);
+
+        let archive = tmp.path().join("streamed.tar.gz");
+        std::fs::write(&archive, &bytes).unwrap();
+        let listing = list(msgpack_map! { "path" => path_value(&archive) })
+            .await
+            .unwrap();
+        assert_eq!(member_names(&listing), ["src", "src/main.rs", "link"]);
+        // Followed, the link is archived as the file it points to
+        assert_eq!(listing["members"][2]["type"].as_str(), Some("file"));
+    }
+
+    #[tokio::test]
+    async fn corrupt_tar_reports_the_failing_offset() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("bad.tar");
+        let mut bytes = tar_bytes();
+        // Break the checksum of the second header
+        bytes[1024 + 148] ^= 0x01;
+        std::fs::write(&path, &bytes).unwrap();
+
+        let err = list(msgpack_map! { "path" => path_value(&path) })
+            .await
+            .expect_err("corrupt header");
+        assert_eq!(err.code, RpcError::INVALID_ARCHIVE);
+        let data = err.data.unwrap();
+        assert_eq!(data["format"].as_str(), Some("tar"));
+        assert_eq!(data["offset"].as_u64(), Some(1024));
+    }
+}
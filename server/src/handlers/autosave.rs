@@ -0,0 +1,306 @@
+//! Auto-save files for TRAMP-RPC
+//!
+//! This module provides:
+//! - `file.write_autosave`: Write a buffer's auto-save file next to the
+//!   visited file, named `#NAME#` like Emacs does locally
+//! - `file.list_autosaves`: Find the auto-save files for a directory, for
+//!   `recover-session` and `recover-file`
+//!
+//! When the visited file's directory is not writable, auto-saves go to a
+//! per-user directory, `$XDG_STATE_HOME/tramp-rpc/auto-save`, under the
+//! visited file's full path with `/` written as `!` (and `!` doubled), the
+//! same mangling `auto-save-file-name-transforms` uses.
+
+use crate::jail;
+use crate::msgpack_map;
+use crate::protocol::{IntoValue, RpcError, from_value, path_or_bytes};
+use crate::stat_cache;
+use rmpv::Value;
+use serde::Deserialize;
+use std::ffi::{OsStr, OsString};
+use std::io::Write;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use super::HandlerResult;
+use super::file::{bytes_to_path, get_file_attributes, map_io_error};
+
+/// `#NAME#` next to `path`.
+fn sibling_autosave_path(path: &Path) -> Option<PathBuf> {
+    let name = path.file_name()?;
+    let mut autosave = b"#".to_vec();
+    autosave.extend_from_slice(name.as_bytes());
+    autosave.push(b'#');
+    Some(path.with_file_name(OsStr::from_bytes(&autosave)))
+}
+
+/// Per-user directory for auto-saves that cannot go next to their file
+/// (`auto-save` in the server's state dir).
+fn fallback_dir() -> PathBuf {
+    crate::server_dirs::path(crate::server_dirs::Kind::State).join("auto-save")
+}
+
+/// `#!dir!NAME#` for `path` in the fallback directory.
+fn mangle(path: &Path) -> OsString {
+    let mut mangled = b"#".to_vec();
+    for &byte in path.as_os_str().as_bytes() {
+        match byte {
+            b'!' => mangled.extend_from_slice(b"!!"),
+            b'/' => mangled.push(b'!'),
+            byte => mangled.push(byte),
+        }
+    }
+    mangled.push(b'#');
+    OsString::from_vec(mangled)
+}
+
+/// Inverse of [`mangle`]; `None` for names it cannot have produced.
+fn demangle(name: &[u8]) -> Option<PathBuf> {
+    let inner = name.strip_prefix(b"#")?.strip_suffix(b"#")?;
+    let mut path = Vec::with_capacity(inner.len());
+    let mut bytes = inner.iter().copied().peekable();
+    while let Some(byte) = bytes.next() {
+        if byte != b'!' {
+            path.push(byte);
+        } else if bytes.next_if_eq(&b'!').is_some() {
+            path.push(b'!');
+        } else {
+            path.push(b'/');
+        }
+    }
+    (path.first() == Some(&b'/')).then(|| PathBuf::from(OsString::from_vec(path)))
+}
+
+fn fallback_autosave_path(path: &Path) -> PathBuf {
+    fallback_dir().join(mangle(path))
+}
+
+/// Write `content` to `dest` with mode 0600, atomically: a reader (or a
+/// crash) sees either the previous auto-save or the new one, in full.
+fn write_atomically(dest: &Path, content: &[u8]) -> std::io::Result<()> {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let mut temp_name = dest.file_name().unwrap_or_default().to_os_string();
+    temp_name.push(format!(
+        ".tmp{}.{}",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let temp = dest.with_file_name(temp_name);
+
+    let written = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&temp)
+        .and_then(|mut file| {
+            file.write_all(content)?;
+            file.sync_data()
+        })
+        .and_then(|()| std::fs::rename(&temp, dest));
+    if written.is_err() {
+        let _ = std::fs::remove_file(&temp);
+    }
+    written
+}
+
+/// Whether a failed write means "this directory is not for us".
+fn is_unwritable(err: &std::io::Error) -> bool {
+    err.kind() == std::io::ErrorKind::PermissionDenied
+        || matches!(err.raw_os_error(), Some(libc::EROFS | libc::EPERM))
+}
+
+/// Write a buffer's auto-save file.
+///
+/// `path` is the visited file.  The auto-save is written with mode 0600
+/// to `#NAME#` next to it, or to the per-user fallback directory when that
+/// directory is not writable.  Returns the `path` written and its `attrs`.
+pub async fn write_autosave(params: Value) -> HandlerResult {
+    #[derive(Deserialize)]
+    struct Params {
+        #[serde(with = "path_or_bytes")]
+        path: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        content: Vec<u8>,
+    }
+
+    let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
+
+    let path = bytes_to_path(&params.path);
+    jail::check(&path)?;
+    let sibling = sibling_autosave_path(&path)
+        .ok_or_else(|| RpcError::invalid_params("path has no file name"))?;
+
+    let fallback = fallback_autosave_path(&path);
+    jail::check(&fallback)?;
+
+    // Auto-saves of one file are serialized by the lock of the sibling,
+    // which is tried first
+    let guard = crate::write_lock::lock(&sibling).await;
+    let written = crate::blocking::run(path.clone(), move || {
+        let _guard = guard;
+        match write_atomically(&sibling, &params.content) {
+            Ok(()) => Ok(sibling),
+            Err(e) if is_unwritable(&e) => {
+                crate::server_dirs::subdir(crate::server_dirs::Kind::State, "auto-save")?;
+                write_atomically(&fallback, &params.content)?;
+                Ok(fallback)
+            }
+            Err(e) => Err(e),
+        }
+    })
+    .await?
+    .map_err(|e| map_io_error(e, &path))?;
+
+    stat_cache::invalidate(&written).await;
+    let attrs = get_file_attributes(&written, true).await?;
+    Ok(msgpack_map! {
+        "path" => Value::Binary(written.as_os_str().as_bytes().to_vec()),
+        "attrs" => attrs.to_value()
+    })
+}
+
+/// Remove the auto-save files of `path`, wherever they were written.
+/// Missing files are not an error.
+pub(crate) fn remove_autosaves(path: &Path) -> std::io::Result<()> {
+    let candidates = [
+        sibling_autosave_path(path),
+        Some(fallback_autosave_path(path)),
+    ];
+    for autosave in candidates.into_iter().flatten() {
+        if jail::check(&autosave).is_err() {
+            continue;
+        }
+        match std::fs::remove_file(&autosave) {
+            Ok(()) => stat_cache::invalidate_blocking(&autosave),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// List the auto-save files for files in `directory`.
+///
+/// Returns `[{path, visited, attrs}]`: the auto-save file, the file it
+/// belongs to, and the auto-save's attributes.  Covers both `#NAME#`
+/// siblings and files in the per-user fallback directory.
+pub async fn list_autosaves(params: Value) -> HandlerResult {
+    #[derive(Deserialize)]
+    struct Params {
+        #[serde(with = "path_or_bytes")]
+        directory: Vec<u8>,
+    }
+
+    let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
+
+    let directory = bytes_to_path(&params.directory);
+    jail::check(&directory)?;
+    let dir_str = directory.to_string_lossy().into_owned();
+
+    let found = {
+        let directory = directory.clone();
+        crate::blocking::run(
+            directory.clone(),
+            move || -> std::io::Result<Vec<(PathBuf, PathBuf)>> {
+                let mut found = Vec::new();
+                for entry in std::fs::read_dir(&directory)? {
+                    let entry = entry?;
+                    let name = entry.file_name();
+                    let Some(visited) = name
+                        .as_bytes()
+                        .strip_prefix(b"#")
+                        .and_then(|rest| rest.strip_suffix(b"#"))
+                        .filter(|visited| !visited.is_empty())
+                    else {
+                        continue;
+                    };
+                    found.push((entry.path(), directory.join(OsStr::from_bytes(visited))));
+                }
+
+                // Auto-saves of this directory's files in the fallback directory
+                let canonical = std::fs::canonicalize(&directory).unwrap_or(directory);
+                if let Ok(entries) = std::fs::read_dir(fallback_dir()) {
+                    for entry in entries.flatten() {
+                        if let Some(visited) = demangle(entry.file_name().as_bytes())
+                            && visited.parent() == Some(canonical.as_path())
+                        {
+                            found.push((entry.path(), visited));
+                        }
+                    }
+                }
+                Ok(found)
+            },
+        )
+        .await?
+        .map_err(|e| map_io_error(e, &dir_str))?
+    };
+
+    let mut autosaves = Vec::with_capacity(found.len());
+    for (autosave, visited) in found {
+        let attrs = get_file_attributes(&autosave, true).await.ok();
+        autosaves.push(msgpack_map! {
+            "path" => Value::Binary(autosave.as_os_str().as_bytes().to_vec()),
+            "visited" => Value::Binary(visited.as_os_str().as_bytes().to_vec()),
+            "attrs" => attrs.map(|attrs| attrs.to_value()).into_value()
+        });
+    }
+    Ok(Value::Array(autosaves))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::map_get;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn mangled_names_round_trip() {
+        let path = Path::new("/home/u/we!rd/file.txt");
+        let mangled = mangle(path);
+        assert_eq!(mangled.as_bytes(), b"#!home!u!we!!rd!file.txt#");
+        assert_eq!(demangle(mangled.as_bytes()).as_deref(), Some(path));
+        assert_eq!(demangle(b"#file.txt#"), None);
+    }
+
+    #[tokio::test]
+    async fn autosave_is_written_private_listed_and_removed() {
+        let tmp = tempfile::tempdir().unwrap();
+        let file = tmp.path().join("notes.txt");
+        let path = Value::Binary(file.as_os_str().as_bytes().to_vec());
+
+        let result = write_autosave(msgpack_map! {
+            "path" => path,
+            "content" => Value::Binary(b"draft".to_vec()),
+        })
+        .await
+        .unwrap();
+        let autosave = tmp.path().join("#notes.txt#");
+        assert_eq!(
+            map_get(&result, "path").and_then(Value::as_slice),
+            Some(autosave.as_os_str().as_bytes())
+        );
+        assert_eq!(std::fs::read(&autosave).unwrap(), b"draft");
+        let mode = std::fs::metadata(&autosave).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let listed = list_autosaves(msgpack_map! {
+            "directory" => Value::Binary(tmp.path().as_os_str().as_bytes().to_vec()),
+        })
+        .await
+        .unwrap();
+        let listed = listed.as_array().unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(
+            map_get(&listed[0], "visited").and_then(Value::as_slice),
+            Some(file.as_os_str().as_bytes())
+        );
+
+        remove_autosaves(&file).unwrap();
+        assert!(!autosave.exists());
+        // Nothing left to remove is fine too
+        remove_autosaves(&file).unwrap();
+    }
+}
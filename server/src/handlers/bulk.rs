@@ -0,0 +1,272 @@
+//! Batched renames, copies and deletes, for dired operations on many marked
+//! files.
+//!
+//! `file.rename_batch {items}` and `file.copy_batch {items}` take `{src,
+//! dest, ...}` maps and `file.delete_batch {paths}` takes paths (or `{path,
+//! ...}` maps); each item accepts the options of `file.rename`, `file.copy`
+//! or `file.delete`, and `options` gives defaults for all of them.  Every
+//! item is dispatched as a request of its own, so it is audited, limited
+//! and reported exactly like one.
+//!
+//! At most 16 items run at once, or one at a time in order with
+//! `sequential: true`, as rename chains (a to b, then b to c) need.  A
+//! failed item does not stop the others unless `stop_on_error` is set; then
+//! items not yet started are skipped.  The result is `{results,
+//! error_count, stopped}`, with `{index, result}`, `{index, error}` or
+//! `{index, skipped: true}` per item, in order.  Batches of more than 100
+//! items report `file.batch_progress` notifications of `{batch_id, method,
+//! done, total, error_count}` along the way.
+
+use crate::msgpack_map;
+use crate::protocol::{Notification, Request, RequestId, RpcError, from_value};
+use rmpv::Value;
+use serde::Deserialize;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use super::HandlerResult;
+
+/// Items run concurrently unless the batch is sequential.
+const CONCURRENCY: usize = 16;
+
+/// Batches with more items than this report progress.
+const PROGRESS_THRESHOLD: usize = 100;
+
+/// Minimum time between two `file.batch_progress` notifications.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How a batch runs, common to all three methods.
+#[derive(Deserialize)]
+struct Options {
+    /// Defaults for every item
+    #[serde(default)]
+    options: Option<Value>,
+    #[serde(default)]
+    sequential: bool,
+    #[serde(default)]
+    stop_on_error: bool,
+    /// Names the batch in progress notifications
+    #[serde(default)]
+    batch_id: Option<Value>,
+}
+
+/// Rename `items` of `{src, dest, overwrite?, exchange?}`.
+pub async fn rename_batch(params: Value) -> HandlerResult {
+    #[derive(Deserialize)]
+    struct Params {
+        items: Vec<Value>,
+        #[serde(flatten)]
+        options: Options,
+    }
+
+    let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
+    run("file.rename", params.items, params.options).await
+}
+
+/// Copy `items` of `{src, dest, ...}` with the options of `file.copy`.
+pub async fn copy_batch(params: Value) -> HandlerResult {
+    #[derive(Deserialize)]
+    struct Params {
+        items: Vec<Value>,
+        #[serde(flatten)]
+        options: Options,
+    }
+
+    let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
+    run("file.copy", params.items, params.options).await
+}
+
+/// Delete `paths`, each a path or a `{path, force?}` map.
+pub async fn delete_batch(params: Value) -> HandlerResult {
+    #[derive(Deserialize)]
+    struct Params {
+        paths: Vec<Value>,
+        #[serde(flatten)]
+        options: Options,
+    }
+
+    let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
+    let items = params
+        .paths
+        .into_iter()
+        .map(|path| match path {
+            Value::Map(_) => path,
+            path => msgpack_map! { "path" => path },
+        })
+        .collect();
+    run("file.delete", items, params.options).await
+}
+
+/// `item` with the fields of `defaults` it does not set itself.
+fn with_defaults(item: Value, defaults: Option<&Value>) -> Result<Value, RpcError> {
+    let Value::Map(mut fields) = item else {
+        return Err(RpcError::invalid_params("batch items must be maps"));
+    };
+    if let Some(Value::Map(defaults)) = defaults {
+        for (key, value) in defaults {
+            if !fields.iter().any(|(k, _)| k == key) {
+                fields.push((key.clone(), value.clone()));
+            }
+        }
+    }
+    Ok(Value::Map(fields))
+}
+
+async fn run(method: &'static str, items: Vec<Value>, options: Options) -> HandlerResult {
+    use futures::StreamExt;
+
+    let items = items
+        .into_iter()
+        .map(|item| with_defaults(item, options.options.as_ref()))
+        .collect::<Result<Vec<_>, _>>()?;
+    let total = items.len();
+    let concurrency = if options.sequential { 1 } else { CONCURRENCY };
+    let stop_on_error = options.stop_on_error;
+    let stopped = AtomicBool::new(false);
+    let trace = crate::trace::current();
+
+    let stopped_ref = &stopped;
+    let mut results = futures::stream::iter(items.into_iter().enumerate())
+        .map(|(index, params)| {
+            let trace = trace.clone();
+            async move {
+                if stopped_ref.load(Ordering::SeqCst) {
+                    return msgpack_map! { "index" => index as u64, "skipped" => true };
+                }
+                let request = Request {
+                    version: "2.0".to_string(),
+                    id: RequestId::Number(0),
+                    method: method.to_string(),
+                    params,
+                    trace,
+                };
+                let response = super::dispatch_inner(request).await;
+                match response.error {
+                    Some(error) => {
+                        if stop_on_error {
+                            stopped_ref.store(true, Ordering::SeqCst);
+                        }
+                        msgpack_map! {
+                            "index" => index as u64,
+                            "error" => super::file::error_value(error)
+                        }
+                    }
+                    None => msgpack_map! {
+                        "index" => index as u64,
+                        "result" => response.result.unwrap_or(Value::Nil)
+                    },
+                }
+            }
+        })
+        .buffered(concurrency);
+
+    let batch_id = options.batch_id.unwrap_or(Value::Nil);
+    let context = crate::trace::context(trace.clone(), "batch", batch_id.clone());
+    let mut last_progress = Instant::now();
+    let mut values = Vec::with_capacity(total);
+    let mut error_count = 0u64;
+    while let Some(value) = results.next().await {
+        if value["error"] != Value::Nil {
+            error_count += 1;
+        }
+        values.push(value);
+        if total > PROGRESS_THRESHOLD && last_progress.elapsed() >= PROGRESS_INTERVAL {
+            last_progress = Instant::now();
+            let params = msgpack_map! {
+                "batch_id" => batch_id.clone(),
+                "method" => method,
+                "done" => values.len() as u64,
+                "total" => total as u64,
+                "error_count" => error_count
+            };
+            let notification = Notification::new("file.batch_progress", params);
+            crate::send_notification(notification.with_context(context.clone())).await;
+        }
+    }
+    drop(results);
+
+    Ok(msgpack_map! {
+        "results" => Value::Array(values),
+        "error_count" => error_count,
+        "stopped" => stopped.load(Ordering::SeqCst)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path(dir: &std::path::Path, name: &str) -> Value {
+        Value::from(dir.join(name).to_string_lossy().as_ref())
+    }
+
+    #[tokio::test]
+    async fn sequential_renames_follow_chains() {
+        let tmp = tempfile::tempdir().unwrap();
+        std::fs::write(tmp.path().join("a"), b"a").unwrap();
+        std::fs::write(tmp.path().join("b"), b"b").unwrap();
+        let item = |src: &str, dest: &str| {
+            msgpack_map! { "src" => path(tmp.path(), src), "dest" => path(tmp.path(), dest) }
+        };
+
+        // b moves out of the way before a takes its name
+        let result = rename_batch(msgpack_map! {
+            "items" => Value::Array(vec![item("b", "c"), item("a", "b"), item("gone", "x")]),
+            "sequential" => true
+        })
+        .await
+        .unwrap();
+        assert_eq!(std::fs::read(tmp.path().join("b")).unwrap(), b"a");
+        assert_eq!(std::fs::read(tmp.path().join("c")).unwrap(), b"b");
+        let results = result["results"].as_array().unwrap();
+        assert_eq!(results[0]["result"], Value::Boolean(true));
+        assert_eq!(results[1]["index"].as_u64(), Some(1));
+        assert_eq!(
+            results[2]["error"]["code"].as_i64(),
+            Some(i64::from(RpcError::FILE_NOT_FOUND))
+        );
+        assert_eq!(
+            results[2]["error"]["data"]["operation"].as_str(),
+            Some("file.rename")
+        );
+        assert_eq!(result["error_count"].as_u64(), Some(1));
+        assert_eq!(result["stopped"], Value::Boolean(false));
+    }
+
+    #[tokio::test]
+    async fn deletes_stop_on_the_first_error_when_asked() {
+        let tmp = tempfile::tempdir().unwrap();
+        for name in ["one", "three"] {
+            std::fs::write(tmp.path().join(name), b"").unwrap();
+        }
+        let paths = Value::Array(vec![
+            path(tmp.path(), "one"),
+            path(tmp.path(), "two"),
+            path(tmp.path(), "three"),
+        ]);
+
+        let result = delete_batch(msgpack_map! {
+            "paths" => paths.clone(),
+            "sequential" => true,
+            "stop_on_error" => true
+        })
+        .await
+        .unwrap();
+        let results = result["results"].as_array().unwrap();
+        assert_eq!(results[0]["result"], Value::Boolean(true));
+        assert!(results[1]["error"].is_map());
+        assert_eq!(results[2]["skipped"], Value::Boolean(true));
+        assert_eq!(result["stopped"], Value::Boolean(true));
+        assert!(tmp.path().join("three").exists());
+
+        // Defaults apply to every item
+        let result = delete_batch(msgpack_map! {
+            "paths" => paths,
+            "options" => msgpack_map! { "force" => true }
+        })
+        .await
+        .unwrap();
+        assert_eq!(result["error_count"].as_u64(), Some(0));
+        assert!(!tmp.path().join("three").exists());
+    }
+}
@@ -4,12 +4,18 @@
 //! - `commands.run_parallel`: Run multiple commands in parallel using OS threads
 //! - `ancestors.scan`: Scan ancestor directories for marker files
 
+use crate::jail;
 use crate::msgpack_map;
-use crate::protocol::{IntoValue, RpcError, from_value};
+use crate::protocol::{IntoValue, PathBytes, RpcError, from_value};
 use rmpv::Value;
 use serde::Deserialize;
 use std::collections::HashMap;
-use std::fs::File;
+use std::ffi::OsStr;
+use std::fs::{File, OpenOptions};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io::Read;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
 use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::thread;
@@ -39,6 +45,14 @@ const MAX_PARALLEL_COMMANDS: usize = 256;
 /// channel does not grant any capabilities beyond what SSH already provides.
 /// If the transport model ever changes (e.g., TCP socket), this handler
 /// would need a command whitelist.
+///
+/// # Conditional requests
+///
+/// When `repository` names a git worktree, the result is wrapped as
+/// `{etag, results}`, where `etag` fingerprints the repository state (see
+/// [`repo_fingerprint`]).  If `if_none_match` equals the current fingerprint
+/// the commands are not run and `{not_modified: true, etag}` is returned, so
+/// refreshing an unchanged Magit buffer costs a single cheap round-trip.
 pub async fn run_parallel(params: Value) -> HandlerResult {
     #[derive(Deserialize)]
     struct CommandEntry {
@@ -46,22 +60,60 @@ pub async fn run_parallel(params: Value) -> HandlerResult {
         key: String,
         /// Command to run
         cmd: String,
-        /// Arguments (default: empty)
+        /// Arguments, strings or binary (default: empty)
         #[serde(default)]
-        args: Vec<String>,
-        /// Working directory (optional)
-        cwd: Option<String>,
+        args: Vec<PathBytes>,
+        /// Working directory, a string or binary (optional)
+        cwd: Option<PathBytes>,
     }
 
     #[derive(Deserialize)]
     struct Params {
         commands: Vec<CommandEntry>,
+        /// Git worktree to fingerprint for conditional requests
+        #[serde(default)]
+        repository: Option<String>,
+        /// Skip the commands if the fingerprint still matches this etag
+        #[serde(default)]
+        if_none_match: Option<String>,
     }
 
     let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
 
+    for cwd in params
+        .commands
+        .iter()
+        .filter_map(|entry| entry.cwd.as_ref())
+    {
+        jail::check(&super::working_dir(cwd))?;
+    }
+
+    let etag = match &params.repository {
+        Some(repository) => {
+            let worktree = PathBuf::from(super::expand_tilde(repository));
+            jail::check(&worktree)?;
+            let etag = crate::blocking::run(worktree.clone(), move || repo_fingerprint(&worktree))
+                .await??;
+            if params.if_none_match.as_deref() == Some(etag.as_str()) {
+                return Ok(msgpack_map! {
+                    "not_modified" => true,
+                    "etag" => etag
+                });
+            }
+            Some(etag)
+        }
+        None => None,
+    };
+    let wrap = move |results: Value| match &etag {
+        Some(etag) => msgpack_map! {
+            "etag" => etag.clone(),
+            "results" => results
+        },
+        None => results,
+    };
+
     if params.commands.is_empty() {
-        return Ok(Value::Map(vec![]));
+        return Ok(wrap(Value::Map(vec![])));
     }
 
     // Enforce command count limit to prevent resource exhaustion
@@ -84,8 +136,12 @@ pub async fn run_parallel(params: Value) -> HandlerResult {
                     s.spawn(move || {
                         let mut cmd = Command::new(&entry.cmd);
                         cmd.args(&entry.args);
+                        if let Some(base) = crate::env_policy::base(false) {
+                            cmd.env_clear();
+                            cmd.envs(base);
+                        }
                         if let Some(ref cwd) = entry.cwd {
-                            cmd.current_dir(super::expand_tilde(cwd));
+                            cmd.current_dir(super::working_dir(cwd));
                         }
                         let value = match cmd.output() {
                             Ok(output) => {
@@ -120,26 +176,229 @@ pub async fn run_parallel(params: Value) -> HandlerResult {
             .map(|(k, v)| (Value::String(k.into()), v))
             .collect();
 
-        Ok(Value::Map(pairs))
+        Ok(wrap(Value::Map(pairs)))
     })
     .await
-    .map_err(|e| RpcError::internal_error(format!("Task join error: {}", e)))?
+    .map_err(crate::blocking::join_error)?
+}
+
+/// Resolve the git directory and common directory of a worktree.
+///
+/// Handles both a `.git` directory and the `gitdir:` file used by linked
+/// worktrees and submodules.
+fn resolve_git_dirs(worktree: &Path) -> Option<(PathBuf, PathBuf)> {
+    let dot_git = worktree.join(".git");
+    let git_dir = if dot_git.is_dir() {
+        dot_git
+    } else {
+        let contents = std::fs::read_to_string(&dot_git).ok()?;
+        let target = contents.trim().strip_prefix("gitdir:")?.trim();
+        worktree.join(target)
+    };
+    let common_dir = match std::fs::read_to_string(git_dir.join("commondir")) {
+        Ok(common) => git_dir.join(common.trim()),
+        Err(_) => git_dir.clone(),
+    };
+    Some((git_dir, common_dir))
+}
+
+fn hash_metadata(hasher: &mut impl Hasher, path: &Path) {
+    path.hash(hasher);
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) => {
+            meta.len().hash(hasher);
+            meta.mtime().hash(hasher);
+            meta.mtime_nsec().hash(hasher);
+        }
+        Err(_) => 0u8.hash(hasher),
+    }
+}
+
+fn hash_tree_metadata(hasher: &mut impl Hasher, dir: &Path) {
+    hash_metadata(hasher, dir);
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    let mut entries: Vec<_> = entries.filter_map(Result::ok).collect();
+    entries.sort_by_key(|e| e.file_name());
+    for entry in entries {
+        let path = entry.path();
+        if entry.file_type().is_ok_and(|t| t.is_dir()) {
+            hash_tree_metadata(hasher, &path);
+        } else {
+            hash_metadata(hasher, &path);
+        }
+    }
+}
+
+/// Fingerprint the state of a git worktree.
+///
+/// Covers `HEAD`, the index, loose and packed refs, the top-level worktree
+/// entries, the output of `git status --porcelain` and the size and mtime
+/// of every path it lists.  The status catches the first edit to a tracked
+/// file, which leaves the other metadata untouched; the listed paths catch
+/// further edits to a file that already shows as modified.  The value is
+/// only meaningful for comparison against the same server binary.
+fn repo_fingerprint(worktree: &Path) -> Result<String, RpcError> {
+    let (git_dir, common_dir) = resolve_git_dirs(worktree)
+        .ok_or_else(|| RpcError::invalid_params("repository is not a git worktree"))?;
+
+    // --no-optional-locks keeps status from refreshing the index, which
+    // would otherwise change the index mtime on every fingerprint.
+    let status = Command::new("git")
+        .args([
+            "--no-optional-locks",
+            "status",
+            "--porcelain=v1",
+            "-z",
+            "--untracked-files=normal",
+        ])
+        .current_dir(worktree)
+        .stdin(std::process::Stdio::null())
+        .output()
+        .map_err(|e| RpcError::process_error(format!("Failed to run git: {}", e)))?;
+    if !status.status.success() {
+        return Err(RpcError::process_error(format!(
+            "git status failed: {}",
+            String::from_utf8_lossy(&status.stderr).trim()
+        )));
+    }
+
+    let mut hasher = DefaultHasher::new();
+    status.stdout.hash(&mut hasher);
+    for path in porcelain_paths(&status.stdout) {
+        hash_metadata(&mut hasher, &worktree.join(OsStr::from_bytes(path)));
+    }
+    hash_metadata(&mut hasher, &git_dir.join("HEAD"));
+    hash_metadata(&mut hasher, &git_dir.join("index"));
+    hash_metadata(&mut hasher, &common_dir.join("packed-refs"));
+    hash_tree_metadata(&mut hasher, &common_dir.join("refs"));
+
+    hash_metadata(&mut hasher, worktree);
+    if let Ok(entries) = std::fs::read_dir(worktree) {
+        let mut entries: Vec<_> = entries.filter_map(Result::ok).map(|e| e.path()).collect();
+        entries.sort();
+        for entry in entries {
+            hash_metadata(&mut hasher, &entry);
+        }
+    }
+
+    Ok(format!("{:016x}", hasher.finish()))
+}
+
+/// The paths in `git status --porcelain=v1 -z` output, relative to the
+/// worktree: each entry's path, and the source of renames and copies.
+fn porcelain_paths(status: &[u8]) -> Vec<&[u8]> {
+    let mut paths = Vec::new();
+    let mut fields = status.split(|&b| b == 0).filter(|f| !f.is_empty());
+    while let Some(entry) = fields.next() {
+        let Some(path) = entry.get(3..) else {
+            continue;
+        };
+        paths.push(path);
+        if matches!(entry[0], b'R' | b'C') || matches!(entry[1], b'R' | b'C') {
+            paths.extend(fields.next());
+        }
+    }
+    paths
+}
+
+/// Default number of leading bytes inspected by a content probe.
+const DEFAULT_PROBE_BYTES: usize = 64 * 1024;
+
+/// An `ancestors.scan` marker: a bare name, or a name whose file must also
+/// contain a substring within its first `max_bytes` bytes.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum MarkerSpec {
+    Name(String),
+    Probe {
+        name: String,
+        contains: String,
+        /// Result key (default: the marker name)
+        #[serde(default)]
+        key: Option<String>,
+        #[serde(default = "default_probe_bytes")]
+        max_bytes: usize,
+    },
+}
+
+fn default_probe_bytes() -> usize {
+    DEFAULT_PROBE_BYTES
+}
+
+impl MarkerSpec {
+    fn key(&self) -> &str {
+        match self {
+            MarkerSpec::Name(name) => name,
+            MarkerSpec::Probe { name, key, .. } => key.as_deref().unwrap_or(name),
+        }
+    }
+
+    /// Whether this marker is present in `dir`.
+    ///
+    /// Unreadable and binary files never satisfy a content probe, nor does
+    /// anything but a regular file (or a symlink to one): opening a FIFO
+    /// named like a marker would block.
+    fn matches(&self, dir: &Path) -> bool {
+        match self {
+            MarkerSpec::Name(name) => dir.join(name).exists(),
+            MarkerSpec::Probe {
+                name,
+                contains,
+                max_bytes,
+                ..
+            } => {
+                let path = dir.join(name);
+                if !std::fs::metadata(&path).is_ok_and(|meta| meta.is_file()) {
+                    return false;
+                }
+                // In case it was swapped for a FIFO since
+                let Ok(file) = OpenOptions::new()
+                    .read(true)
+                    .custom_flags(libc::O_NONBLOCK)
+                    .open(&path)
+                else {
+                    return false;
+                };
+                if !file.metadata().is_ok_and(|meta| meta.is_file()) {
+                    return false;
+                }
+                let mut head = Vec::new();
+                if file.take(*max_bytes as u64).read_to_end(&mut head).is_err() || head.contains(&0)
+                {
+                    return false;
+                }
+                let needle = contains.as_bytes();
+                needle.is_empty() || head.windows(needle.len()).any(|w| w == needle)
+            }
+        }
+    }
 }
 
 /// Scan ancestor directories for marker files
 ///
 /// This is useful for project detection, VCS detection, etc.
-/// Returns a map of marker -> directory where it was found (or null if not found)
+/// Returns a map of marker -> directory where it was found (or null if not found).
+/// With `all_matches`, each marker maps to the list of every directory where
+/// it was found instead, nearest first.  A marker given as
+/// `{name, contains}` only matches when the file contains that substring.
 pub async fn ancestors_scan(params: Value) -> HandlerResult {
     #[derive(Deserialize)]
     struct Params {
         /// Starting directory
         directory: String,
         /// Marker files/directories to look for
-        markers: Vec<String>,
+        markers: Vec<MarkerSpec>,
         /// Maximum depth to search (default: 10)
         #[serde(default = "default_max_depth")]
         max_depth: usize,
+        /// Record every ancestor containing a marker, not just the nearest
+        #[serde(default)]
+        all_matches: bool,
+        /// Last directory to scan; ancestors above it are not visited
+        #[serde(default)]
+        stop_at: Option<String>,
     }
 
     fn default_max_depth() -> usize {
@@ -148,42 +407,52 @@ pub async fn ancestors_scan(params: Value) -> HandlerResult {
 
     let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
 
-    // Wrap in spawn_blocking since this does blocking filesystem I/O
+    // Run on the blocking pool since this does blocking filesystem I/O
     let expanded_directory = super::expand_tilde(&params.directory);
-    tokio::task::spawn_blocking(move || {
+    let stop_at = params
+        .stop_at
+        .as_deref()
+        .map(|p| PathBuf::from(super::expand_tilde(p)));
+    jail::check(Path::new(&expanded_directory))?;
+    crate::blocking::run(expanded_directory.clone(), move || {
         let dir = Path::new(&expanded_directory);
         if !dir.exists() {
             return Err(RpcError::file_not_found(&expanded_directory));
         }
 
-        // Initialize results with None for each marker
-        let mut results: HashMap<String, Option<String>> =
-            params.markers.iter().map(|m| (m.clone(), None)).collect();
+        // Initialize results with an empty match list for each marker
+        let mut results: HashMap<String, Vec<String>> = params
+            .markers
+            .iter()
+            .map(|m| (m.key().to_string(), Vec::new()))
+            .collect();
 
         // Walk up the directory tree
         let mut current = dir.to_path_buf();
         let mut depth = 0;
 
         while depth < params.max_depth {
-            // Check each marker that hasn't been found yet
+            // Check each marker that still needs matches
             for marker in &params.markers {
-                if results.get(marker).unwrap().is_none() {
-                    let marker_path = current.join(marker);
-                    if marker_path.exists() {
-                        results
-                            .insert(marker.clone(), Some(current.to_string_lossy().into_owned()));
-                    }
+                let matches = results.get_mut(marker.key()).unwrap();
+                if (params.all_matches || matches.is_empty()) && marker.matches(&current) {
+                    matches.push(current.to_string_lossy().into_owned());
                 }
             }
 
             // Check if all markers found
-            if results.values().all(|v| v.is_some()) {
+            if !params.all_matches && results.values().all(|v| !v.is_empty()) {
+                break;
+            }
+
+            // Do not escape the caller's boundary
+            if stop_at.as_deref() == Some(current.as_path()) {
                 break;
             }
 
             // Move to parent
             match current.parent() {
-                Some(parent) if parent != current => {
+                Some(parent) if parent != current && jail::contains(parent) => {
                     current = parent.to_path_buf();
                     depth += 1;
                 }
@@ -194,20 +463,26 @@ pub async fn ancestors_scan(params: Value) -> HandlerResult {
         // Convert to Value
         let pairs: Vec<(Value, Value)> = results
             .into_iter()
-            .map(|(k, v)| (k.into_value(), v.into_value()))
+            .map(|(k, v)| {
+                let value = if params.all_matches {
+                    v.into_value()
+                } else {
+                    v.into_iter().next().into_value()
+                };
+                (k.into_value(), value)
+            })
             .collect();
 
         Ok(Value::Map(pairs))
     })
-    .await
-    .map_err(|e| RpcError::internal_error(format!("Task join error: {}", e)))?
+    .await?
 }
 
 fn canonical_or_original(path: &Path) -> PathBuf {
     std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
 }
 
-fn find_existing_start(path: &Path) -> Option<&Path> {
+pub(super) fn find_existing_start(path: &Path) -> Option<&Path> {
     if path.exists() {
         return Some(path);
     }
@@ -219,7 +494,7 @@ fn find_existing_start(path: &Path) -> Option<&Path> {
     Some(current)
 }
 
-fn as_search_dir(path: &Path) -> Option<PathBuf> {
+pub(super) fn as_search_dir(path: &Path) -> Option<PathBuf> {
     if path.is_dir() {
         Some(path.to_path_buf())
     } else {
@@ -229,7 +504,7 @@ fn as_search_dir(path: &Path) -> Option<PathBuf> {
 
 const MAX_DOMINATING_DEPTH: usize = 100;
 
-fn find_dominating_dir(
+pub(super) fn find_dominating_dir(
     start_dir: &Path,
     names: &[String],
 ) -> Result<Option<(PathBuf, Vec<String>)>, RpcError> {
@@ -247,7 +522,7 @@ fn find_dominating_dir(
         }
 
         match current.parent() {
-            Some(parent) if parent != current => {
+            Some(parent) if parent != current && jail::contains(parent) => {
                 if depth >= MAX_DOMINATING_DEPTH {
                     return Err(RpcError::invalid_params(format!(
                         "Maximum ancestor traversal depth ({}) exceeded",
@@ -276,7 +551,7 @@ fn remap_to_lexical_ancestor(start_dir: &Path, found_dir: &Path) -> PathBuf {
     }
 }
 
-fn mtime_seconds(path: &Path) -> Option<i64> {
+pub(super) fn mtime_seconds(path: &Path) -> Option<i64> {
     let modified = std::fs::metadata(path).ok()?.modified().ok()?;
     let duration = modified.duration_since(UNIX_EPOCH).ok()?;
     Some(duration.as_secs() as i64)
@@ -292,7 +567,8 @@ pub async fn highlevel_test_files_in_dir(params: Value) -> HandlerResult {
 
     let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
 
-    tokio::task::spawn_blocking(move || {
+    jail::check(Path::new(&params.directory))?;
+    crate::blocking::run(params.directory.clone(), move || {
         let dir = canonical_or_original(Path::new(&params.directory));
         if !dir.is_dir() {
             return Ok(Value::Array(vec![]));
@@ -307,8 +583,7 @@ pub async fn highlevel_test_files_in_dir(params: Value) -> HandlerResult {
         }
         Ok(Value::Array(found))
     })
-    .await
-    .map_err(|e| RpcError::internal_error(format!("Task join error: {}", e)))?
+    .await?
 }
 
 /// Locate marker files in ancestor directories.
@@ -323,7 +598,8 @@ pub async fn highlevel_locate_dominating_file_multi(params: Value) -> HandlerRes
 
     let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
 
-    tokio::task::spawn_blocking(move || {
+    jail::check(Path::new(&params.file))?;
+    crate::blocking::run(params.file.clone(), move || {
         let path = PathBuf::from(&params.file);
         // Preserve lexical path shape instead of canonicalizing symlinks.
         // TRAMP clients rely on this to compute repo-relative paths correctly.
@@ -344,8 +620,7 @@ pub async fn highlevel_locate_dominating_file_multi(params: Value) -> HandlerRes
             .collect();
         Ok(Value::Array(marker_paths))
     })
-    .await
-    .map_err(|e| RpcError::internal_error(format!("Task join error: {}", e)))?
+    .await?
 }
 
 /// Prepare dir-locals data in one RPC call.
@@ -360,7 +635,8 @@ pub async fn highlevel_dir_locals_find_file_cache_update(params: Value) -> Handl
 
     let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
 
-    tokio::task::spawn_blocking(move || {
+    jail::check(Path::new(&params.file))?;
+    crate::blocking::run(params.file.clone(), move || {
         let file_path = PathBuf::from(&params.file);
         // Keep lexical (non-canonical) path shape to match locate-dominating behavior.
         let lexical_file = file_path.clone();
@@ -455,6 +731,257 @@ pub async fn highlevel_dir_locals_find_file_cache_update(params: Value) -> Handl
             "cache" => cache_value
         })
     })
-    .await
-    .map_err(|e| RpcError::internal_error(format!("Task join error: {}", e)))?
+    .await?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{git, map_get};
+
+    async fn run_with_etag(repo: &Path, if_none_match: Option<&str>) -> Value {
+        let mut params = msgpack_map! {
+            "commands" => Value::Array(vec![msgpack_map! {
+                "key" => "head",
+                "cmd" => "git",
+                "args" => vec!["rev-parse".to_string(), "HEAD".to_string()].into_value(),
+                "cwd" => repo.to_string_lossy().into_owned()
+            }]),
+            "repository" => repo.to_string_lossy().into_owned()
+        };
+        if let (Some(etag), Value::Map(pairs)) = (if_none_match, &mut params) {
+            pairs.push(("if_none_match".into(), etag.into()));
+        }
+        run_parallel(params).await.expect("run_parallel")
+    }
+
+    #[tokio::test]
+    async fn run_parallel_etag_short_circuits_until_worktree_changes() {
+        let tmp = tempfile::tempdir().unwrap();
+        let repo = tmp.path();
+        git(repo, &["init", "-q"]);
+        std::fs::write(repo.join("file.txt"), "one\n").unwrap();
+        git(repo, &["add", "file.txt"]);
+        git(repo, &["commit", "-q", "-m", "initial"]);
+
+        let full = run_with_etag(repo, None).await;
+        assert!(map_get(&full, "results").is_some_and(|r| map_get(r, "head").is_some()));
+        let etag = map_get(&full, "etag")
+            .and_then(Value::as_str)
+            .expect("etag")
+            .to_string();
+
+        let cached = run_with_etag(repo, Some(&etag)).await;
+        assert_eq!(
+            map_get(&cached, "not_modified").and_then(Value::as_bool),
+            Some(true)
+        );
+        assert!(map_get(&cached, "results").is_none());
+
+        // Same size, so only the porcelain status reveals the edit.
+        std::fs::write(repo.join("file.txt"), "two\n").unwrap();
+        let changed = run_with_etag(repo, Some(&etag)).await;
+        assert!(map_get(&changed, "not_modified").is_none());
+        assert_ne!(
+            map_get(&changed, "etag").and_then(Value::as_str),
+            Some(etag.as_str())
+        );
+    }
+
+    #[tokio::test]
+    async fn run_parallel_etag_changes_with_each_edit_of_a_modified_file() {
+        let tmp = tempfile::tempdir().unwrap();
+        let repo = tmp.path();
+        std::fs::create_dir(repo.join("sub")).unwrap();
+        git(repo, &["init", "-q"]);
+        std::fs::write(repo.join("sub/file.txt"), "one\n").unwrap();
+        git(repo, &["add", "sub/file.txt"]);
+        git(repo, &["commit", "-q", "-m", "initial"]);
+
+        let etag = |value: &Value| {
+            map_get(value, "etag")
+                .and_then(Value::as_str)
+                .expect("etag")
+                .to_string()
+        };
+        std::fs::write(repo.join("sub/file.txt"), "two\n").unwrap();
+        let first = etag(&run_with_etag(repo, None).await);
+
+        // The status line stays " M sub/file.txt" and nothing at the top
+        // level changes; only the file itself does.
+        std::fs::write(repo.join("sub/file.txt"), "three\n").unwrap();
+        let second = run_with_etag(repo, Some(&first)).await;
+        assert!(map_get(&second, "not_modified").is_none());
+        assert_ne!(etag(&second), first);
+    }
+
+    #[test]
+    fn porcelain_paths_include_rename_sources() {
+        let status = b" M sub/file\0R  new\0old\0?? dir/\0";
+        assert_eq!(
+            porcelain_paths(status),
+            vec![&b"sub/file"[..], b"new", b"old", b"dir/"]
+        );
+    }
+
+    async fn scan(params: Value) -> Value {
+        ancestors_scan(params).await.expect("ancestors.scan")
+    }
+
+    fn nested_projects() -> (tempfile::TempDir, PathBuf) {
+        let tmp = tempfile::tempdir().unwrap();
+        let inner = tmp.path().join("outer").join("inner");
+        let leaf = inner.join("src");
+        std::fs::create_dir_all(&leaf).unwrap();
+        std::fs::write(tmp.path().join("outer").join("Cargo.toml"), "").unwrap();
+        std::fs::write(inner.join("Cargo.toml"), "").unwrap();
+        (tmp, leaf)
+    }
+
+    #[tokio::test]
+    async fn ancestors_scan_all_matches_lists_nearest_first() {
+        let (tmp, leaf) = nested_projects();
+        let outer = tmp.path().join("outer");
+
+        let single = scan(msgpack_map! {
+            "directory" => leaf.to_string_lossy().into_owned(),
+            "markers" => vec!["Cargo.toml".to_string()].into_value()
+        })
+        .await;
+        assert_eq!(
+            map_get(&single, "Cargo.toml").and_then(Value::as_str),
+            Some(outer.join("inner").to_string_lossy().as_ref())
+        );
+
+        let all = scan(msgpack_map! {
+            "directory" => leaf.to_string_lossy().into_owned(),
+            "markers" => vec!["Cargo.toml".to_string(), "missing".to_string()].into_value(),
+            "all_matches" => true
+        })
+        .await;
+        let dirs: Vec<&str> = map_get(&all, "Cargo.toml")
+            .and_then(Value::as_array)
+            .expect("match list")
+            .iter()
+            .filter_map(Value::as_str)
+            .collect();
+        assert_eq!(
+            dirs,
+            vec![
+                outer.join("inner").to_string_lossy().as_ref(),
+                outer.to_string_lossy().as_ref()
+            ]
+        );
+        assert_eq!(map_get(&all, "missing"), Some(&Value::Array(vec![])));
+    }
+
+    #[tokio::test]
+    async fn ancestors_scan_content_probe_skips_non_matching_files() {
+        let (tmp, leaf) = nested_projects();
+        let outer = tmp.path().join("outer");
+        std::fs::write(outer.join("Cargo.toml"), "[workspace]\nmembers = []\n").unwrap();
+        std::fs::write(leaf.join("Cargo.toml"), b"[work\0space]").unwrap();
+
+        let result = scan(msgpack_map! {
+            "directory" => leaf.to_string_lossy().into_owned(),
+            "markers" => Value::Array(vec![
+                msgpack_map! {
+                    "name" => "Cargo.toml",
+                    "contains" => "[workspace]",
+                    "key" => "workspace"
+                },
+                "Cargo.toml".into()
+            ])
+        })
+        .await;
+        assert_eq!(
+            map_get(&result, "workspace").and_then(Value::as_str),
+            Some(outer.to_string_lossy().as_ref())
+        );
+        assert_eq!(
+            map_get(&result, "Cargo.toml").and_then(Value::as_str),
+            Some(leaf.to_string_lossy().as_ref())
+        );
+
+        let truncated = scan(msgpack_map! {
+            "directory" => leaf.to_string_lossy().into_owned(),
+            "markers" => Value::Array(vec![msgpack_map! {
+                "name" => "Cargo.toml",
+                "contains" => "members",
+                "max_bytes" => 4
+            }])
+        })
+        .await;
+        assert_eq!(map_get(&truncated, "Cargo.toml"), Some(&Value::Nil));
+    }
+
+    #[tokio::test]
+    async fn ancestors_scan_content_probe_skips_fifos() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        nix::unistd::mkfifo(&dir.join("flake.nix"), nix::sys::stat::Mode::S_IRWXU).unwrap();
+        std::fs::write(dir.join("real.nix"), b"outputs").unwrap();
+        std::os::unix::fs::symlink("real.nix", dir.join("default.nix")).unwrap();
+
+        let result = tokio::time::timeout(
+            std::time::Duration::from_secs(10),
+            scan(msgpack_map! {
+                "directory" => dir.to_string_lossy().into_owned(),
+                "markers" => Value::Array(vec![
+                    msgpack_map! { "name" => "flake.nix", "contains" => "outputs" },
+                    msgpack_map! { "name" => "default.nix", "contains" => "outputs" },
+                ]),
+                "max_depth" => 1
+            }),
+        )
+        .await
+        .expect("a FIFO marker must not block the scan");
+        assert_eq!(map_get(&result, "flake.nix"), Some(&Value::Nil));
+        assert_eq!(
+            map_get(&result, "default.nix").and_then(Value::as_str),
+            Some(dir.to_string_lossy().as_ref())
+        );
+    }
+
+    #[tokio::test]
+    async fn ancestors_scan_stop_at_bounds_the_walk() {
+        let (tmp, leaf) = nested_projects();
+        let inner = tmp.path().join("outer").join("inner");
+
+        let bounded = scan(msgpack_map! {
+            "directory" => leaf.to_string_lossy().into_owned(),
+            "markers" => vec!["Cargo.toml".to_string()].into_value(),
+            "all_matches" => true,
+            "stop_at" => inner.to_string_lossy().into_owned()
+        })
+        .await;
+        assert_eq!(
+            map_get(&bounded, "Cargo.toml")
+                .and_then(Value::as_array)
+                .map(Vec::len),
+            Some(1)
+        );
+
+        let src_only = scan(msgpack_map! {
+            "directory" => leaf.to_string_lossy().into_owned(),
+            "markers" => vec!["Cargo.toml".to_string()].into_value(),
+            "stop_at" => leaf.to_string_lossy().into_owned()
+        })
+        .await;
+        assert_eq!(map_get(&src_only, "Cargo.toml"), Some(&Value::Nil));
+    }
+
+    #[tokio::test]
+    async fn run_parallel_without_repository_keeps_flat_results() {
+        let result = run_parallel(msgpack_map! {
+            "commands" => Value::Array(vec![msgpack_map! {
+                "key" => "true",
+                "cmd" => "true"
+            }])
+        })
+        .await
+        .unwrap();
+        assert!(map_get(&result, "true").is_some());
+        assert!(map_get(&result, "etag").is_none());
+    }
 }
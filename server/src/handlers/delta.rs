@@ -0,0 +1,497 @@
+//! Delta transfer for TRAMP-RPC
+//!
+//! This module provides:
+//! - `file.signature`: Per-block checksums of an existing remote file
+//! - `file.write_delta`: Rebuild a file from copy/insert instructions
+//!
+//! The scheme follows rsync: the client rolls an adler32 window over its new
+//! content, confirms candidate blocks with md5, and only sends the bytes the
+//! remote file does not already have.
+
+use crate::jail;
+use crate::msgpack_map;
+use crate::protocol::{RpcError, from_value};
+use crate::stat_cache;
+use md5::{Digest, Md5};
+use rmpv::Value;
+use serde::Deserialize;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use super::HandlerResult;
+use super::file::{bytes_to_path, map_io_error};
+
+use crate::protocol::path_or_bytes;
+
+const DEFAULT_BLOCK_SIZE: usize = 4096;
+const MIN_BLOCK_SIZE: usize = 512;
+const MAX_BLOCK_SIZE: usize = 1 << 20;
+
+/// Modulus of the adler32 checksum.
+const ADLER_MOD: u32 = 65521;
+
+/// adler32 of a block, as computed by zlib.
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    // 5552 is the largest n for which the sums cannot overflow a u32.
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += byte as u32;
+            b += a;
+        }
+        a %= ADLER_MOD;
+        b %= ADLER_MOD;
+    }
+    (b << 16) | a
+}
+
+/// Identify the exact version of a file a signature was computed from.
+pub(crate) fn base_token(meta: &std::fs::Metadata) -> String {
+    format!(
+        "{}:{}:{}:{}",
+        meta.size(),
+        meta.mtime(),
+        meta.mtime_nsec(),
+        meta.ino()
+    )
+}
+
+fn validate_block_size(block_size: usize) -> Result<(), RpcError> {
+    if !(MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&block_size) {
+        return Err(RpcError::invalid_params(format!(
+            "block_size must be between {} and {}",
+            MIN_BLOCK_SIZE, MAX_BLOCK_SIZE
+        )));
+    }
+    Ok(())
+}
+
+/// Error telling the client to fall back to a full `file.write`.
+///
+/// `reason` is "missing" (the base vanished), "stale" (it changed since the
+/// signature), "checksum" (the rebuilt content did not verify) or "owner"
+/// (the rebuilt file could not be given the base's owner).
+fn delta_conflict(reason: &str, message: String) -> RpcError {
+    let mut error = RpcError::conflict(message);
+    error.data = Some(msgpack_map! {
+        "reason" => reason
+    });
+    error
+}
+
+/// Compute block signatures of a file.
+///
+/// Returns `weak` (adler32) and `strong` (md5) checksums for each
+/// `block_size` block, plus a `token` identifying this version of the file;
+/// `file.write_delta` rejects deltas whose token no longer matches.
+pub async fn signature(params: Value) -> HandlerResult {
+    #[derive(Deserialize)]
+    struct Params {
+        #[serde(with = "path_or_bytes")]
+        path: Vec<u8>,
+        #[serde(default = "default_block_size")]
+        block_size: usize,
+    }
+
+    fn default_block_size() -> usize {
+        DEFAULT_BLOCK_SIZE
+    }
+
+    let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
+    validate_block_size(params.block_size)?;
+
+    let path = bytes_to_path(&params.path).to_path_buf();
+    jail::check(&path)?;
+    let path_str = path.to_string_lossy().into_owned();
+
+    crate::blocking::run(path.clone(), move || {
+        let mut file = File::open(&path).map_err(|e| map_io_error(e, &path))?;
+        let meta = file.metadata().map_err(|e| map_io_error(e, &path))?;
+        if !meta.is_file() {
+            return Err(RpcError::invalid_params(format!(
+                "Not a regular file: {}",
+                path_str
+            )));
+        }
+
+        let mut weak = Vec::new();
+        let mut strong = Vec::new();
+        let mut block = vec![0u8; params.block_size];
+        loop {
+            let len = read_full(&mut file, &mut block).map_err(|e| map_io_error(e, &path))?;
+            if len == 0 {
+                break;
+            }
+            weak.push(Value::from(adler32(&block[..len])));
+            strong.push(Value::Binary(Md5::digest(&block[..len]).to_vec()));
+            if len < block.len() {
+                break;
+            }
+        }
+
+        Ok(msgpack_map! {
+            "token" => base_token(&meta),
+            "size" => meta.size(),
+            "block_size" => params.block_size,
+            "weak" => Value::Array(weak),
+            "strong" => Value::Array(strong)
+        })
+    })
+    .await?
+}
+
+/// Read until `buf` is full or EOF, returning the number of bytes read.
+fn read_full(file: &mut File, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match file.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// One reconstruction instruction.
+#[derive(Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum DeltaOp {
+    /// Copy `count` consecutive base blocks starting at `block`
+    Copy {
+        block: u64,
+        #[serde(default = "default_count")]
+        count: u64,
+    },
+    /// Insert literal bytes
+    Insert {
+        #[serde(with = "serde_bytes")]
+        data: Vec<u8>,
+    },
+}
+
+fn default_count() -> u64 {
+    1
+}
+
+/// Temporary sibling used while rebuilding `path`.
+fn temp_sibling(path: &Path) -> PathBuf {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    path.with_file_name(format!(
+        ".{}.tramp-rpc-delta.{}.{}",
+        name,
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ))
+}
+
+/// Give the rebuilt file `out` the owner of the base `meta`, then `mode`
+/// again, as chown clears the set-user-ID and set-group-ID bits.
+fn keep_owner(out: &File, meta: &std::fs::Metadata, mode: u32) -> std::io::Result<()> {
+    let made = out.metadata()?;
+    if (made.uid(), made.gid()) == (meta.uid(), meta.gid()) {
+        return Ok(());
+    }
+    let uid = (made.uid() != meta.uid()).then_some(meta.uid());
+    let gid = (made.gid() != meta.gid()).then_some(meta.gid());
+    std::os::unix::fs::fchown(out, uid, gid)?;
+    out.set_permissions(std::fs::Permissions::from_mode(mode))
+}
+
+/// Rebuild a file from a delta against its current content.
+///
+/// The new content is written to a temporary sibling, verified against the
+/// client's whole-file `md5` (hex) and renamed over `path`, so readers never
+/// see a partially applied delta.  When `path` is a symlink, the file it
+/// leads to is replaced and the link kept.  The file's permission bits and
+/// owner are kept.
+/// Fails with [`RpcError::CONFLICT`] if the base no longer matches `token`.
+pub async fn write_delta(params: Value) -> HandlerResult {
+    #[derive(Deserialize)]
+    struct Params {
+        #[serde(with = "path_or_bytes")]
+        path: Vec<u8>,
+        /// Token returned by `file.signature`
+        token: String,
+        /// Block size used for the signature
+        block_size: usize,
+        ops: Vec<DeltaOp>,
+        /// Expected md5 of the rebuilt file, lowercase hex
+        md5: String,
+    }
+
+    let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
+    validate_block_size(params.block_size)?;
+
+    let path = bytes_to_path(&params.path).to_path_buf();
+    jail::check(&path)?;
+    let path_str = path.to_string_lossy().into_owned();
+    let guard = crate::write_lock::lock(&path).await;
+
+    crate::blocking::run(path.clone(), move || {
+        let _guard = guard;
+        let check_base = || -> Result<std::fs::Metadata, RpcError> {
+            let meta = match std::fs::metadata(&path) {
+                Ok(meta) => meta,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    return Err(delta_conflict(
+                        "missing",
+                        format!("Delta base vanished: {}", path_str),
+                    ));
+                }
+                Err(e) => return Err(map_io_error(e, &path)),
+            };
+            if base_token(&meta) != params.token {
+                return Err(delta_conflict(
+                    "stale",
+                    format!("Delta base changed since signature: {}", path_str),
+                ));
+            }
+            Ok(meta)
+        };
+
+        let meta = check_base()?;
+        let mut base = File::open(&path).map_err(|e| map_io_error(e, &path))?;
+        // Replace the file a symlink leads to, not the link
+        let target = std::fs::canonicalize(&path).map_err(|e| map_io_error(e, &path))?;
+
+        let tmp = temp_sibling(&target);
+        let mode = meta.permissions().mode() & 0o7777;
+        let mut out = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(mode)
+            .open(&tmp)
+            .map_err(|e| map_io_error(e, &path))?;
+
+        let result = (|| {
+            keep_owner(&out, &meta, mode).map_err(|e| {
+                delta_conflict(
+                    "owner",
+                    format!("Cannot keep the owner of {}: {}", path_str, e),
+                )
+            })?;
+            let mut hasher = Md5::new();
+            let mut written = 0u64;
+            let mut buf = vec![0u8; params.block_size];
+            for op in &params.ops {
+                match op {
+                    DeltaOp::Copy { block, count } => {
+                        let offset = block
+                            .checked_mul(params.block_size as u64)
+                            .filter(|&offset| offset < meta.size())
+                            .ok_or_else(|| {
+                                RpcError::invalid_params(format!(
+                                    "Copy of block {} is past the end of the base",
+                                    block
+                                ))
+                            })?;
+                        base.seek(SeekFrom::Start(offset))
+                            .map_err(|e| map_io_error(e, &path))?;
+                        for _ in 0..*count {
+                            let len = read_full(&mut base, &mut buf)
+                                .map_err(|e| map_io_error(e, &path))?;
+                            if len == 0 {
+                                break;
+                            }
+                            out.write_all(&buf[..len])
+                                .map_err(|e| map_io_error(e, &path))?;
+                            hasher.update(&buf[..len]);
+                            written += len as u64;
+                        }
+                    }
+                    DeltaOp::Insert { data } => {
+                        out.write_all(data).map_err(|e| map_io_error(e, &path))?;
+                        hasher.update(data);
+                        written += data.len() as u64;
+                    }
+                }
+            }
+
+            let digest = format!("{:x}", hasher.finalize());
+            if !digest.eq_ignore_ascii_case(&params.md5) {
+                return Err(delta_conflict(
+                    "checksum",
+                    format!("Rebuilt content does not match md5 {}", params.md5),
+                ));
+            }
+            out.flush().map_err(|e| map_io_error(e, &path))?;
+            check_base()?;
+            std::fs::rename(&tmp, &target).map_err(|e| map_io_error(e, &path))?;
+            stat_cache::invalidate_blocking(&path);
+            Ok(written)
+        })();
+
+        let written = result.inspect_err(|_| {
+            let _ = std::fs::remove_file(&tmp);
+        })?;
+
+        Ok(msgpack_map! {
+            "size" => written
+        })
+    })
+    .await?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::map_get;
+
+    fn copy_op(block: u64, count: u64) -> Value {
+        msgpack_map! { "op" => "copy", "block" => block, "count" => count }
+    }
+
+    fn insert_op(data: &[u8]) -> Value {
+        msgpack_map! { "op" => "insert", "data" => Value::Binary(data.to_vec()) }
+    }
+
+    async fn signature_of(path: &Path) -> Value {
+        signature(msgpack_map! {
+            "path" => path.to_string_lossy().into_owned(),
+            "block_size" => MIN_BLOCK_SIZE
+        })
+        .await
+        .expect("signature")
+    }
+
+    fn delta_params(path: &Path, token: &Value, ops: Vec<Value>, content: &[u8]) -> Value {
+        msgpack_map! {
+            "path" => path.to_string_lossy().into_owned(),
+            "token" => token.clone(),
+            "block_size" => MIN_BLOCK_SIZE,
+            "ops" => Value::Array(ops),
+            "md5" => format!("{:x}", Md5::digest(content))
+        }
+    }
+
+    #[test]
+    fn adler32_matches_zlib() {
+        assert_eq!(adler32(b""), 1);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+        // Long enough to exercise the deferred modulo reduction.
+        assert_eq!(adler32(&[0xff; 100_000]), 0x149A_302C);
+    }
+
+    #[tokio::test]
+    async fn write_delta_rebuilds_file_from_blocks_and_literals() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("big.txt");
+        let base: Vec<u8> = (0..MIN_BLOCK_SIZE * 3).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&path, &base).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o640)).unwrap();
+
+        let sig = signature_of(&path).await;
+        assert_eq!(
+            map_get(&sig, "weak")
+                .and_then(Value::as_array)
+                .map(Vec::len),
+            Some(3)
+        );
+        let token = map_get(&sig, "token").unwrap();
+
+        // Replace the middle block, keep the other two.
+        let mut expected = base[..MIN_BLOCK_SIZE].to_vec();
+        expected.extend_from_slice(b"edited middle");
+        expected.extend_from_slice(&base[MIN_BLOCK_SIZE * 2..]);
+        let ops = vec![copy_op(0, 1), insert_op(b"edited middle"), copy_op(2, 1)];
+
+        let result = write_delta(delta_params(&path, token, ops, &expected))
+            .await
+            .expect("write_delta");
+        assert_eq!(
+            map_get(&result, "size").and_then(Value::as_u64),
+            Some(expected.len() as u64)
+        );
+        assert_eq!(std::fs::read(&path).unwrap(), expected);
+        assert_eq!(
+            std::fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o640
+        );
+        assert_eq!(std::fs::read_dir(tmp.path()).unwrap().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn write_delta_through_a_symlink_keeps_the_link_and_owner() {
+        let tmp = tempfile::tempdir().unwrap();
+        let real = tmp.path().join("real.txt");
+        let link = tmp.path().join("link.txt");
+        std::fs::write(&real, b"before").unwrap();
+        std::os::unix::fs::symlink("real.txt", &link).unwrap();
+        // Only root can give the file away; others keep their own group
+        let owner = if unsafe { libc::geteuid() } == 0 {
+            (1234, 4321)
+        } else {
+            let meta = std::fs::metadata(&real).unwrap();
+            (meta.uid(), meta.gid())
+        };
+        std::os::unix::fs::chown(&real, Some(owner.0), Some(owner.1)).unwrap();
+
+        let sig = signature_of(&link).await;
+        let token = map_get(&sig, "token").unwrap();
+        write_delta(delta_params(
+            &link,
+            token,
+            vec![insert_op(b"after")],
+            b"after",
+        ))
+        .await
+        .expect("write_delta");
+
+        assert!(
+            std::fs::symlink_metadata(&link)
+                .unwrap()
+                .file_type()
+                .is_symlink()
+        );
+        assert_eq!(std::fs::read(&real).unwrap(), b"after");
+        let meta = std::fs::metadata(&real).unwrap();
+        assert_eq!((meta.uid(), meta.gid()), owner);
+        assert_eq!(std::fs::read_dir(tmp.path()).unwrap().count(), 2);
+    }
+
+    #[tokio::test]
+    async fn write_delta_reports_stale_missing_and_bad_checksum() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("file.txt");
+        std::fs::write(&path, b"original").unwrap();
+        let sig = signature_of(&path).await;
+        let token = map_get(&sig, "token").unwrap().clone();
+        let reason = |e: &RpcError| {
+            e.data
+                .as_ref()
+                .and_then(|d| map_get(d, "reason"))
+                .and_then(Value::as_str)
+                .map(str::to_string)
+        };
+
+        let bad = write_delta(delta_params(&path, &token, vec![copy_op(0, 1)], b"other"))
+            .await
+            .expect_err("checksum mismatch");
+        assert_eq!(bad.code, RpcError::CONFLICT);
+        assert_eq!(reason(&bad).as_deref(), Some("checksum"));
+        assert_eq!(std::fs::read(&path).unwrap(), b"original");
+        assert_eq!(std::fs::read_dir(tmp.path()).unwrap().count(), 1);
+
+        std::fs::write(&path, b"changed!!").unwrap();
+        let stale = write_delta(delta_params(&path, &token, vec![], b""))
+            .await
+            .expect_err("stale base");
+        assert_eq!(reason(&stale).as_deref(), Some("stale"));
+
+        std::fs::remove_file(&path).unwrap();
+        let missing = write_delta(delta_params(&path, &token, vec![], b""))
+            .await
+            .expect_err("missing base");
+        assert_eq!(missing.code, RpcError::CONFLICT);
+        assert_eq!(reason(&missing).as_deref(), Some("missing"));
+    }
+}
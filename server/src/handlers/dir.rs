@@ -5,9 +5,17 @@
 //! - `fstatat` with directory fd for efficient attribute collection
 //! - Synchronous blocking task to avoid per-entry async overhead
 
-use crate::protocol::{DirEntry, FileAttributes, FileType, RpcError, from_value};
+use crate::handshake::Feature;
+use crate::jail;
+use crate::msgpack_map;
+use crate::protocol::{
+    DirEntry, FileAttributes, FileType, IntoValue, Listing, NamesAs, RpcError, TargetType,
+    Truncation, from_value,
+};
+use crate::stat_cache;
 use rmpv::Value;
 use serde::Deserialize;
+use std::ffi::OsStr;
 use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
 use tokio::fs;
@@ -15,12 +23,13 @@ use tokio::fs;
 use super::HandlerResult;
 use super::file::{bytes_to_path, map_io_error};
 
-use crate::protocol::path_or_bytes;
+use crate::protocol::{PathBytes, path_or_bytes};
 
 /// Extract time and mode fields from libc::stat in a cross-platform way
 /// Returns (atime, mtime, ctime, mode)
 /// - On Linux: st_mode is u32; time fields are i32 on 32-bit, i64 on 64-bit
-/// - On macOS: st_mode is u16, time fields are i64
+/// - On macOS and FreeBSD: st_mode is u16, time fields are i64
+/// - On OpenBSD: st_mode is u32, time fields are i64
 #[inline]
 fn stat_time_to_i64<T: Into<i64>>(time: T) -> i64 {
     time.into()
@@ -28,9 +37,9 @@ fn stat_time_to_i64<T: Into<i64>>(time: T) -> i64 {
 
 #[inline]
 fn extract_stat_fields(stat_buf: &libc::stat) -> (i64, i64, i64, u32) {
-    #[cfg(target_os = "macos")]
-    let mode = stat_buf.st_mode as u32;
-    #[cfg(not(target_os = "macos"))]
+    #[cfg(any(target_os = "macos", target_os = "freebsd"))]
+    let mode = u32::from(stat_buf.st_mode);
+    #[cfg(not(any(target_os = "macos", target_os = "freebsd")))]
     let mode = stat_buf.st_mode;
 
     (
@@ -41,7 +50,75 @@ fn extract_stat_fields(stat_buf: &libc::stat) -> (i64, i64, i64, u32) {
     )
 }
 
-/// Get FileAttributes using fstatat relative to directory fd
+/// Determine the file type from a stat mode
+fn file_type_from_mode(mode: libc::mode_t) -> FileType {
+    match mode & libc::S_IFMT {
+        libc::S_IFREG => FileType::File,
+        libc::S_IFDIR => FileType::Directory,
+        libc::S_IFLNK => FileType::Symlink,
+        libc::S_IFCHR => FileType::CharDevice,
+        libc::S_IFBLK => FileType::BlockDevice,
+        libc::S_IFIFO => FileType::Fifo,
+        libc::S_IFSOCK => FileType::Socket,
+        _ => FileType::Unknown,
+    }
+}
+
+/// Follow-stat the symlink `name` in `dir_fd` and return what it points to.
+///
+/// Dangling links, loops and links through non-directories are `Missing`;
+/// other failures (e.g. EACCES on the target's parent) resolve to `Unknown`.
+fn target_type_at(dir_fd: libc::c_int, name: &[u8]) -> TargetType {
+    let mut stat_buf: libc::stat = unsafe { std::mem::zeroed() };
+    let mut name_cstr = name.to_vec();
+    name_cstr.push(0);
+
+    let result = unsafe {
+        libc::fstatat(
+            dir_fd,
+            name_cstr.as_ptr() as *const libc::c_char,
+            &mut stat_buf,
+            0,
+        )
+    };
+
+    if result == 0 {
+        return TargetType::Resolved(file_type_from_mode(stat_buf.st_mode));
+    }
+    match std::io::Error::last_os_error().raw_os_error() {
+        Some(libc::ENOENT | libc::ELOOP | libc::ENOTDIR) => TargetType::Missing,
+        _ => TargetType::Resolved(FileType::Unknown),
+    }
+}
+
+/// How much `dir.list` resolves symlink entries
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ResolveSymlinks {
+    /// Report symlinks as-is
+    None,
+    /// Add `target_type` to symlink entries
+    TypeOnly,
+    /// Add `target_type` and the followed `target_attrs`
+    Full,
+}
+
+impl ResolveSymlinks {
+    fn parse(value: Option<&str>) -> Result<Self, RpcError> {
+        match value {
+            Option::None | Some("type_only") => Ok(Self::TypeOnly),
+            Some("none") => Ok(Self::None),
+            Some("full") => Ok(Self::Full),
+            Some(other) => Err(RpcError::invalid_params(format!(
+                "resolve_symlinks must be \"none\", \"type_only\" or \"full\", got \"{}\"",
+                other
+            ))),
+        }
+    }
+}
+
+/// Get FileAttributes using fstatat relative to directory fd.  `uname` and
+/// `gname` are left out, for [`super::file::fill_owner_names`] to fill in
+/// for a whole listing at once.
 fn get_file_attributes_at(
     dir_fd: libc::c_int,
     name: &[u8],
@@ -72,17 +149,7 @@ fn get_file_attributes_at(
         return None;
     }
 
-    // Determine file type from stat mode
-    let file_type = match stat_buf.st_mode & libc::S_IFMT {
-        libc::S_IFREG => FileType::File,
-        libc::S_IFDIR => FileType::Directory,
-        libc::S_IFLNK => FileType::Symlink,
-        libc::S_IFCHR => FileType::CharDevice,
-        libc::S_IFBLK => FileType::BlockDevice,
-        libc::S_IFIFO => FileType::Fifo,
-        libc::S_IFSOCK => FileType::Socket,
-        _ => FileType::Unknown,
-    };
+    let file_type = file_type_from_mode(stat_buf.st_mode);
 
     // Get link target if symlink
     let link_target = if file_type == FileType::Symlink {
@@ -115,13 +182,34 @@ fn get_file_attributes_at(
     let gid = stat_buf.st_gid;
     let (atime, mtime, ctime, mode) = extract_stat_fields(&stat_buf);
 
+    #[cfg(target_os = "macos")]
+    let (birth_time, flags) = (
+        Some((stat_buf.st_birthtime, stat_buf.st_birthtime_nsec as u32)),
+        Some(stat_buf.st_flags),
+    );
+    #[cfg(target_os = "freebsd")]
+    let (birth_time, flags) = (
+        (stat_buf.st_birthtime >= 0)
+            .then_some((stat_buf.st_birthtime, stat_buf.st_birthtime_nsec as u32)),
+        Some(stat_buf.st_flags),
+    );
+    #[cfg(target_os = "linux")]
+    let (birth_time, flags) = std::ffi::CStr::from_bytes_with_nul(&name_cstr)
+        .map(|name| {
+            let extras = super::file::statx_at(dir_fd, name, follow_symlinks);
+            (extras.birth_time, extras.flags)
+        })
+        .unwrap_or_default();
+    #[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "freebsd")))]
+    let (birth_time, flags) = (None, None);
+
     Some(FileAttributes {
         file_type,
         nlinks: stat_buf.st_nlink as u64,
         uid,
         gid,
-        uname: super::file::get_user_name(uid),
-        gname: super::file::get_group_name(gid),
+        uname: None,
+        gname: None,
         atime,
         mtime,
         ctime,
@@ -130,10 +218,57 @@ fn get_file_attributes_at(
         inode: stat_buf.st_ino as u64,
         dev: stat_buf.st_dev as u64,
         link_target,
+        btime: birth_time.map(|(secs, _)| secs),
+        btime_nsec: birth_time.map(|(_, nsecs)| nsecs),
+        flags,
+    })
+}
+
+/// Handle `dir.generation {path}`: `{generation, watched}`.
+///
+/// The generation changes whenever this server or the watcher invalidates
+/// an entry of the directory, so a client holding the generation of an
+/// earlier `dir.list {generation: true}` can skip re-listing while it is
+/// unchanged.  Only changes the server sees count: `watched` says whether
+/// a watch covers the directory, without which changes made by other
+/// processes do not.
+pub async fn generation(params: Value) -> HandlerResult {
+    #[derive(Deserialize)]
+    struct Params {
+        #[serde(with = "path_or_bytes")]
+        path: Vec<u8>,
+    }
+
+    let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
+    let path = bytes_to_path(&params.path);
+    jail::check(&path)?;
+    Ok(msgpack_map! {
+        "generation" => stat_cache::generation(&path),
+        "watched" => crate::watcher::get().is_some_and(|manager| manager.covers(&path))
     })
 }
 
 /// List directory contents using optimized synchronous I/O with d_type and fstatat
+///
+/// With `fields` each entry is a fresh lstat reduced to the named fields.
+/// With `fingerprint_only` the reply is `{fingerprint, count}`, a digest of
+/// the entries' names, mtimes and sizes that clients can compare against a
+/// stored value to revalidate a listing cheaply.  With `names_as: "auto"`
+/// names that are valid UTF-8 are sent as strings rather than binary.
+///
+/// `include_attrs` stats every entry with lstat, so symlinks show as
+/// symlinks, as `file.stat` does with `lstat: true`.  With `lstat: false`
+/// a symlink's `attrs` are those of what it leads to, as from a plain
+/// `file.stat`, or its own when it dangles; `type` always names the entry
+/// itself.  `both` asks for the link's own `attrs` and, for symlinks, the
+/// followed `target_attrs` (as `resolve_symlinks: "full"`), the same split
+/// as `file.stat` with `both`.
+///
+/// `limit` returns only the first entries by name.  With the
+/// "list_envelope" feature, a listing that is limited or leaves out hidden
+/// files comes as `{entries, total, truncated, reason}`.  With `generation`
+/// it always does, with the directory's generation added; see
+/// [`generation`].
 pub async fn list(params: Value) -> HandlerResult {
     #[derive(Deserialize)]
     struct Params {
@@ -142,9 +277,41 @@ pub async fn list(params: Value) -> HandlerResult {
         /// Include file attributes for each entry
         #[serde(default)]
         include_attrs: bool,
+        /// Whether `attrs` leave symlinks unfollowed (the default)
+        #[serde(default = "default_true")]
+        lstat: bool,
+        /// Include `attrs` and, for symlinks, `target_attrs`
+        #[serde(default)]
+        both: bool,
         /// Include hidden files (starting with .)
         #[serde(default = "default_true")]
         include_hidden: bool,
+        /// Bypass the server-side stat cache for entry attributes
+        #[serde(default)]
+        no_cache: bool,
+        /// "none", "type_only" (default) or "full"
+        #[serde(default)]
+        resolve_symlinks: Option<String>,
+        /// Return only these fields per entry, skipping owner name lookups
+        /// unless "uname" or "gname" is asked for
+        #[serde(default)]
+        fields: Option<Vec<String>>,
+        /// Return only `{fingerprint, count}` for the listing
+        #[serde(default)]
+        fingerprint_only: bool,
+        /// "binary" (default) or "auto"
+        #[serde(default)]
+        names_as: Option<String>,
+        /// Return at most this many entries, the first by name
+        #[serde(default)]
+        limit: Option<usize>,
+        /// Threads that stat a large listing, 0 (the default) for 4 to 8
+        #[serde(default)]
+        stat_threads: usize,
+        /// Reply `{entries, total, truncated, reason, generation}` with the
+        /// directory's generation, for `dir.generation` to compare with
+        #[serde(default)]
+        generation: bool,
     }
 
     fn default_true() -> bool {
@@ -154,30 +321,418 @@ pub async fn list(params: Value) -> HandlerResult {
     let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
 
     let path = bytes_to_path(&params.path);
+    jail::check(&path)?;
     let path_str = path.to_string_lossy().into_owned();
-    let include_attrs = params.include_attrs;
+    let names = NamesAs::parse(params.names_as.as_deref())?;
+    let limit = params.limit;
+    // Taken before listing, so a change made meanwhile shows up as a new
+    // generation on the next `dir.generation`.
+    let generation = params.generation.then(|| stat_cache::generation(&path));
+    let envelope = generation.is_some()
+        || crate::handshake::enabled(Feature::ListEnvelope)
+            && (limit.is_some() || !params.include_hidden);
+    let finish = |entries: Vec<Value>, total: usize| {
+        if envelope {
+            let truncated = total > entries.len();
+            let mut value = Listing {
+                entries,
+                total: Some(total as u64),
+                truncation: truncated.then_some(Truncation::Limit),
+            }
+            .into_value();
+            if let (Some(generation), Value::Map(fields)) = (generation, &mut value) {
+                fields.push(("generation".into(), generation.into()));
+            }
+            value
+        } else {
+            Value::Array(entries)
+        }
+    };
+
+    if params.fingerprint_only || params.fields.is_some() {
+        let fields = params
+            .fields
+            .unwrap_or_default()
+            .iter()
+            .map(|name| ListField::parse(name))
+            .collect::<Result<Vec<_>, _>>()?;
+        let include_hidden = params.include_hidden;
+        let mut entries = crate::blocking::run(path.clone(), move || {
+            lstat_entries_sync(&path, include_hidden)
+        })
+        .await?
+        .map_err(|e| map_io_error(e, &path_str))?;
+
+        if params.fingerprint_only {
+            return Ok(msgpack_map! {
+                "fingerprint" => listing_fingerprint(&entries),
+                "count" => entries.len()
+            });
+        }
+        let total = apply_limit(&mut entries, limit, |(name, _)| name);
+        return Ok(finish(
+            entries
+                .iter()
+                .map(|(name, stat_buf)| select_fields(name, stat_buf.as_ref(), &fields, names))
+                .collect(),
+            total,
+        ));
+    }
+
+    let include_attrs = params.include_attrs || params.both;
+    let lstat = params.lstat || params.both;
     let include_hidden = params.include_hidden;
+    let use_cache = !params.no_cache;
+    let resolve = match params.both {
+        true => ResolveSymlinks::Full,
+        false => ResolveSymlinks::parse(params.resolve_symlinks.as_deref())?,
+    };
+    let threads = params.stat_threads;
 
     // Do all I/O in a single blocking task for efficiency
-    let results =
-        tokio::task::spawn_blocking(move || list_dir_sync(&path, include_attrs, include_hidden))
-            .await
-            .map_err(|e| RpcError::internal_error(format!("Task join error: {}", e)))?
-            .map_err(|e| map_io_error(e, &path_str))?;
+    let mut results = crate::blocking::run(path.clone(), move || {
+        list_dir_sync(
+            &path,
+            include_attrs,
+            lstat,
+            include_hidden,
+            use_cache,
+            resolve,
+            threads,
+        )
+    })
+    .await?
+    .map_err(|e| map_io_error(e, &path_str))?;
 
     // Convert to array of map values with named fields
-    let values: Vec<Value> = results.iter().map(|e| e.to_value()).collect();
-    Ok(Value::Array(values))
+    let total = apply_limit(&mut results, limit, |entry| &entry.name);
+    let values: Vec<Value> = results.iter().map(|e| e.to_value(names)).collect();
+    Ok(finish(values, total))
+}
+
+/// Keep the first `limit` of `entries` by name, returning how many there
+/// were in all.  Without a limit the entries are left in directory order.
+fn apply_limit<T>(entries: &mut Vec<T>, limit: Option<usize>, name: impl Fn(&T) -> &[u8]) -> usize {
+    let total = entries.len();
+    if let Some(limit) = limit {
+        entries.sort_by(|a, b| name(a).cmp(name(b)));
+        entries.truncate(limit);
+    }
+    total
+}
+
+/// Most paths one `dir.list_multi` takes
+const LIST_MULTI_MAX_PATHS: usize = 1024;
+
+/// Directories `dir.list_multi` lists at a time
+const LIST_MULTI_CONCURRENCY: usize = 8;
+
+/// List several directories concurrently with shared options.
+///
+/// Returns `{listings, truncated}` where `listings` maps each requested path
+/// to either its entries array or `{error: {code, message}}`.  At most 1024
+/// paths are taken, and 8 of them listed at a time.  `filters` are globs
+/// matched against entry names; when given, only the entries matching one
+/// of them are listed.  `max_entries` caps the total number of entries
+/// across all directories: once that many have been read, listing stops,
+/// and the paths that were cut short (or not read at all) are reported in
+/// `truncated`.  Which of the directories listed at that moment lose
+/// entries is not fixed.  `names_as`, `lstat` and `both` apply as in
+/// `dir.list`.
+pub async fn list_multi(params: Value) -> HandlerResult {
+    use futures::StreamExt;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[derive(Deserialize)]
+    struct Params {
+        paths: Vec<PathBytes>,
+        #[serde(default)]
+        include_attrs: bool,
+        #[serde(default = "default_true")]
+        lstat: bool,
+        #[serde(default)]
+        both: bool,
+        #[serde(default = "default_true")]
+        include_hidden: bool,
+        #[serde(default)]
+        no_cache: bool,
+        #[serde(default)]
+        resolve_symlinks: Option<String>,
+        #[serde(default)]
+        filters: Vec<String>,
+        #[serde(default = "default_max_entries")]
+        max_entries: usize,
+        #[serde(default)]
+        names_as: Option<String>,
+    }
+
+    fn default_true() -> bool {
+        true
+    }
+
+    fn default_max_entries() -> usize {
+        100_000
+    }
+
+    let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
+    if params.paths.len() > LIST_MULTI_MAX_PATHS {
+        return Err(RpcError::invalid_params(format!(
+            "At most {} paths can be listed at once",
+            LIST_MULTI_MAX_PATHS
+        )));
+    }
+
+    let mut paths: Vec<Vec<u8>> = Vec::with_capacity(params.paths.len());
+    for PathBytes(path) in params.paths {
+        if !paths.contains(&path) {
+            paths.push(path);
+        }
+    }
+
+    let include_attrs = params.include_attrs || params.both;
+    let lstat = params.lstat || params.both;
+    let include_hidden = params.include_hidden;
+    let use_cache = !params.no_cache;
+    let resolve = match params.both {
+        true => ResolveSymlinks::Full,
+        false => ResolveSymlinks::parse(params.resolve_symlinks.as_deref())?,
+    };
+    let names = NamesAs::parse(params.names_as.as_deref())?;
+    let filters = if params.filters.is_empty() {
+        None
+    } else {
+        let mut builder = globset::GlobSetBuilder::new();
+        for filter in &params.filters {
+            builder.add(globset::Glob::new(filter).map_err(|e| {
+                RpcError::invalid_params(format!("Invalid filter {:?}: {}", filter, e))
+            })?);
+        }
+        Some(Arc::new(builder.build().map_err(|e| {
+            RpcError::invalid_params(format!("Invalid filters: {}", e))
+        })?))
+    };
+    // Entries that may still be read, taken one at a time as they are
+    let remaining = Arc::new(AtomicUsize::new(params.max_entries));
+
+    let dirs: Vec<PathBuf> = paths.iter().map(|raw| bytes_to_path(raw)).collect();
+    let listings: Vec<_> = futures::stream::iter(dirs.into_iter().map(|path| {
+        let filters = filters.clone();
+        let remaining = remaining.clone();
+        async move {
+            jail::check(&path)?;
+            let path_str = path.to_string_lossy().into_owned();
+            crate::blocking::run(path.clone(), move || {
+                let mut cut = false;
+                let admit = |name: &[u8]| {
+                    if filters
+                        .as_ref()
+                        .is_some_and(|filters| !filters.is_match(OsStr::from_bytes(name)))
+                    {
+                        return Admit::Skip;
+                    }
+                    match remaining
+                        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1))
+                    {
+                        Ok(_) => Admit::Keep,
+                        Err(_) => {
+                            cut = true;
+                            Admit::Stop
+                        }
+                    }
+                };
+                let entries = list_dir_sync_admitting(
+                    &path,
+                    include_attrs,
+                    lstat,
+                    include_hidden,
+                    use_cache,
+                    resolve,
+                    0,
+                    admit,
+                )?;
+                Ok((entries, cut))
+            })
+            .await?
+            .map_err(|e: std::io::Error| map_io_error(e, &path_str))
+        }
+    }))
+    .buffered(LIST_MULTI_CONCURRENCY)
+    .collect()
+    .await;
+
+    let mut truncated = Vec::new();
+    let mut pairs = Vec::with_capacity(paths.len());
+    for (raw, listing) in paths.into_iter().zip(listings) {
+        let value = match listing {
+            Ok((entries, cut)) => {
+                if cut {
+                    truncated.push(Value::Binary(raw.clone()));
+                }
+                Value::Array(entries.iter().map(|e| e.to_value(names)).collect())
+            }
+            Err(e) => msgpack_map! {
+                "error" => msgpack_map! {
+                    "code" => e.code,
+                    "message" => e.message
+                }
+            },
+        };
+        pairs.push((Value::Binary(raw), value));
+    }
+
+    Ok(msgpack_map! {
+        "listings" => Value::Map(pairs),
+        "truncated" => Value::Array(truncated)
+    })
+}
+
+/// Handle `dir.completions {path, prefix, dirs_only, names_as}`.
+///
+/// Returns the entries of `path` whose names start with `prefix`, sorted,
+/// as `[{name, type, is_dir_like}]`.  `is_dir_like` is true for
+/// directories and for symlinks that lead to one, so the client can append
+/// a slash and descend; each symlink candidate costs one follow-stat.  With
+/// `dirs_only` only those entries are returned, for `cd`-style completion.
+pub async fn completions(params: Value) -> HandlerResult {
+    #[derive(Deserialize)]
+    struct Params {
+        #[serde(with = "path_or_bytes")]
+        path: Vec<u8>,
+        #[serde(default, with = "path_or_bytes")]
+        prefix: Vec<u8>,
+        #[serde(default)]
+        dirs_only: bool,
+        #[serde(default)]
+        names_as: Option<String>,
+    }
+
+    let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
+    let path = bytes_to_path(&params.path);
+    jail::check(&path)?;
+    let path_str = path.to_string_lossy().into_owned();
+    let names = NamesAs::parse(params.names_as.as_deref())?;
+    let (prefix, dirs_only) = (params.prefix, params.dirs_only);
+
+    let candidates = crate::blocking::run(path.clone(), move || {
+        completions_sync(&path, &prefix, dirs_only)
+    })
+    .await?
+    .map_err(|e| map_io_error(e, &path_str))?;
+
+    Ok(Value::Array(
+        candidates
+            .into_iter()
+            .map(|(name, file_type, is_dir_like)| {
+                msgpack_map! {
+                    "name" => names.encode(&name),
+                    "type" => file_type.as_str(),
+                    "is_dir_like" => is_dir_like
+                }
+            })
+            .collect(),
+    ))
+}
+
+/// Entries of `path` starting with `prefix` as `(name, type, is_dir_like)`,
+/// sorted by name.  `.` and `..` are included when they match.
+fn completions_sync(
+    path: &Path,
+    prefix: &[u8],
+    dirs_only: bool,
+) -> Result<Vec<(Vec<u8>, FileType, bool)>, std::io::Error> {
+    let dir = std::fs::File::open(path)?;
+    let dir_fd = std::os::unix::io::AsRawFd::as_raw_fd(&dir);
+
+    let mut candidates: Vec<(Vec<u8>, FileType, bool)> = [b".".as_slice(), b".."]
+        .into_iter()
+        .filter(|name| name.starts_with(prefix))
+        .map(|name| (name.to_vec(), FileType::Directory, true))
+        .collect();
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        let name = entry.file_name().as_bytes().to_vec();
+        // Filter first, so only candidates cost a stat
+        if !name.starts_with(prefix) {
+            continue;
+        }
+        let file_type = match entry.file_type() {
+            Ok(ft) => file_type_from_metadata_ft(&ft),
+            Err(_) => FileType::Unknown,
+        };
+        let is_dir_like = match file_type {
+            FileType::Directory => true,
+            FileType::Symlink => {
+                target_type_at(dir_fd, &name) == TargetType::Resolved(FileType::Directory)
+            }
+            _ => false,
+        };
+        if dirs_only && !is_dir_like {
+            continue;
+        }
+        candidates.push((name, file_type, is_dir_like));
+    }
+    candidates.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(candidates)
+}
+
+/// Listings with at least this many entries are stat'ed on several threads
+const PARALLEL_STAT_MIN_ENTRIES: usize = 256;
+
+/// Threads that stat the entries of a large listing: one per CPU, but at
+/// least 4 as they mostly wait on the filesystem, and at most 8.
+fn stat_threads() -> usize {
+    std::thread::available_parallelism().map_or(4, |n| n.get().clamp(4, 8))
 }
 
-/// Synchronous directory listing with d_type and fstatat optimizations
+/// Synchronous directory listing with d_type and fstatat optimizations.
+///
+/// `threads` is how many threads stat the entries of a large listing, 0
+/// for [`stat_threads`].  `lstat` is as in `dir.list`.
 fn list_dir_sync(
     path: &Path,
     include_attrs: bool,
+    lstat: bool,
+    include_hidden: bool,
+    use_cache: bool,
+    resolve: ResolveSymlinks,
+    threads: usize,
+) -> Result<Vec<DirEntry>, std::io::Error> {
+    list_dir_sync_admitting(
+        path,
+        include_attrs,
+        lstat,
+        include_hidden,
+        use_cache,
+        resolve,
+        threads,
+        |_| Admit::Keep,
+    )
+}
+
+/// What to do with an entry name read from a directory
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Admit {
+    Keep,
+    Skip,
+    /// Leave it out and read no further
+    Stop,
+}
+
+/// [`list_dir_sync`], asking `admit` about each name before it is stat'ed.
+#[allow(clippy::too_many_arguments)]
+fn list_dir_sync_admitting(
+    path: &Path,
+    include_attrs: bool,
+    lstat: bool,
     include_hidden: bool,
+    use_cache: bool,
+    resolve: ResolveSymlinks,
+    threads: usize,
+    mut admit: impl FnMut(&[u8]) -> Admit,
 ) -> Result<Vec<DirEntry>, std::io::Error> {
     // Open directory fd for fstatat
-    let dir_fd = if include_attrs {
+    let dir_fd = if include_attrs || resolve != ResolveSymlinks::None {
         let mut path_cstr = path.as_os_str().as_bytes().to_vec();
         path_cstr.push(0);
         let fd = unsafe {
@@ -210,24 +765,35 @@ fn list_dir_sync(
     let mut results: Vec<DirEntry> = Vec::new();
 
     // Add . and .. entries
+    let mut stopped = false;
     if include_hidden {
-        results.push(DirEntry {
-            name: b".".to_vec(),
-            file_type: FileType::Directory,
-            attrs: dir_fd.and_then(|fd| get_file_attributes_at(fd, b".", true)),
-        });
-
-        results.push(DirEntry {
-            name: b"..".to_vec(),
-            file_type: FileType::Directory,
-            attrs: dir_fd.and_then(|fd| get_file_attributes_at(fd, b"..", true)),
-        });
+        for dot in [&b"."[..], b".."] {
+            match admit(dot) {
+                Admit::Keep => results.push(DirEntry {
+                    name: dot.to_vec(),
+                    file_type: FileType::Directory,
+                    attrs: dir_fd.and_then(|fd| get_file_attributes_at(fd, dot, true)),
+                    target_type: None,
+                    target_attrs: None,
+                }),
+                Admit::Skip => {}
+                Admit::Stop => {
+                    stopped = true;
+                    break;
+                }
+            }
+        }
     }
 
-    // Use std::fs::read_dir which exposes d_type on Linux via DirEntry::file_type()
-    let read_dir = std::fs::read_dir(path)?;
-
-    for entry_result in read_dir {
+    // Read the names on this thread; only the stats are spread out.
+    // std::fs::read_dir exposes d_type on Linux via DirEntry::file_type()
+    let mut listed = Vec::new();
+    let entries = if stopped {
+        None
+    } else {
+        Some(std::fs::read_dir(path)?)
+    };
+    for entry_result in entries.into_iter().flatten() {
         let entry = entry_result?;
         let name_bytes = entry.file_name().as_bytes().to_vec();
 
@@ -236,36 +802,162 @@ fn list_dir_sync(
         if !include_hidden && is_hidden {
             continue;
         }
+        match admit(&name_bytes) {
+            Admit::Keep => {}
+            Admit::Skip => continue,
+            Admit::Stop => break,
+        }
 
         // Get file type - std::fs::DirEntry::file_type() uses d_type on Linux
         // (no extra syscall needed unless d_type is DT_UNKNOWN)
-        let mut file_type = match entry.file_type() {
+        let file_type = match entry.file_type() {
             Ok(ft) => file_type_from_metadata_ft(&ft),
             Err(_) => FileType::Unknown,
         };
+        listed.push((name_bytes, file_type));
+    }
 
-        // For completion paths (include_attrs=false), treat symlinks to
-        // directories as directories
-        if !include_attrs
-            && file_type == FileType::Symlink
-            && std::fs::metadata(entry.path()).is_ok_and(|m| m.is_dir())
-        {
-            file_type = FileType::Directory;
-        }
+    /// An entry, and whether its `attrs` and `target_attrs` were stat'ed
+    /// now rather than taken from the cache
+    struct Stated {
+        entry: DirEntry,
+        fresh: bool,
+        fresh_target: bool,
+    }
+
+    let stat = |(name_bytes, file_type): &(Vec<u8>, FileType)| {
+        let file_type = *file_type;
+        let entry_path = path.join(OsStr::from_bytes(name_bytes));
+        let mut fresh_target = false;
+        // Nothing is followed out of the jail
+        let followable = file_type != FileType::Symlink || jail::contains(&entry_path);
+
+        // Only symlink entries pay for a follow-stat
+        let (target_type, target_attrs) = match dir_fd {
+            Some(_) if !followable && resolve != ResolveSymlinks::None => {
+                (Some(TargetType::Resolved(FileType::Unknown)), None)
+            }
+            Some(fd) if file_type == FileType::Symlink && resolve != ResolveSymlinks::None => {
+                let cached = (use_cache && resolve == ResolveSymlinks::Full)
+                    .then(|| stat_cache::get(&entry_path, false))
+                    .flatten();
+                match cached {
+                    Some(attrs) => (Some(TargetType::Resolved(attrs.file_type)), Some(attrs)),
+                    None => {
+                        let target_type = target_type_at(fd, name_bytes);
+                        let target_attrs = (resolve == ResolveSymlinks::Full
+                            && target_type != TargetType::Missing)
+                            .then(|| get_file_attributes_at(fd, name_bytes, true))
+                            .flatten();
+                        fresh_target = true;
+                        (Some(target_type), target_attrs)
+                    }
+                }
+            }
+            _ => (None, None),
+        };
 
+        let mut fresh = false;
         let attrs = if include_attrs {
-            // Use lstat (follow_symlinks=false) so symlinks show as symlinks
-            // with their link_target resolved, matching Emacs expectations
-            dir_fd.and_then(|fd| get_file_attributes_at(fd, &name_bytes, false))
+            // By default use lstat (follow_symlinks=false) so symlinks show
+            // as symlinks with their link_target resolved, matching Emacs
+            // expectations
+            let lstat = lstat || !followable;
+            match use_cache
+                .then(|| stat_cache::get(&entry_path, lstat))
+                .flatten()
+            {
+                Some(attrs) => Some(attrs),
+                None => {
+                    fresh = true;
+                    dir_fd.and_then(|fd| {
+                        let followed = (!lstat && file_type == FileType::Symlink)
+                            .then(|| get_file_attributes_at(fd, name_bytes, true))
+                            .flatten();
+                        followed.or_else(|| get_file_attributes_at(fd, name_bytes, false))
+                    })
+                }
+            }
         } else {
             None
         };
 
-        results.push(DirEntry {
-            name: name_bytes,
-            file_type,
+        Stated {
+            entry: DirEntry {
+                name: name_bytes.clone(),
+                file_type,
+                attrs,
+                target_type,
+                target_attrs,
+            },
+            fresh,
+            fresh_target,
+        }
+    };
+
+    // On a network filesystem each stat is a round trip, so a large
+    // listing is stat'ed by several threads, each taking a run of entries.
+    let threads = match threads {
+        0 => stat_threads(),
+        n => n,
+    };
+    let mut stated: Vec<Stated> =
+        if dir_fd.is_some() && threads > 1 && listed.len() >= PARALLEL_STAT_MIN_ENTRIES {
+            let run = listed.len().div_ceil(threads);
+            let stat = &stat;
+            std::thread::scope(|s| {
+                let workers: Vec<_> = listed
+                    .chunks(run)
+                    .map(|part| s.spawn(move || part.iter().map(stat).collect::<Vec<_>>()))
+                    .collect();
+                workers
+                    .into_iter()
+                    .flat_map(|worker| {
+                        worker
+                            .join()
+                            .unwrap_or_else(|e| std::panic::resume_unwind(e))
+                    })
+                    .collect()
+            })
+        } else {
+            listed.iter().map(stat).collect()
+        };
+
+    // Owner names for everything stat'ed here, in one batch
+    let fresh = stated.iter_mut().flat_map(|stated| {
+        let DirEntry {
             attrs,
-        });
+            target_attrs,
+            ..
+        } = &mut stated.entry;
+        [
+            attrs.as_mut().filter(|_| stated.fresh),
+            target_attrs.as_mut().filter(|_| stated.fresh_target),
+        ]
+        .into_iter()
+        .flatten()
+    });
+    super::file::fill_owner_names(
+        results
+            .iter_mut()
+            .filter_map(|dot| dot.attrs.as_mut())
+            .chain(fresh),
+    );
+
+    for stated in stated {
+        let entry = &stated.entry;
+        if stated.fresh || stated.fresh_target {
+            let entry_path = path.join(OsStr::from_bytes(&entry.name));
+            if let Some(attrs) = entry.attrs.as_ref().filter(|_| stated.fresh) {
+                // A dangling symlink has only its own attributes
+                let own = lstat || attrs.file_type == FileType::Symlink;
+                stat_cache::insert(&entry_path, own, attrs);
+            }
+            if let Some(attrs) = entry.target_attrs.as_ref().filter(|_| stated.fresh_target) {
+                stat_cache::insert(&entry_path, false, attrs);
+            }
+        }
+        results.push(stated.entry);
     }
 
     // Sort by name
@@ -274,8 +966,172 @@ fn list_dir_sync(
     Ok(results)
 }
 
+/// A per-entry field `dir.list` can be restricted to
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ListField {
+    Name,
+    Type,
+    Size,
+    Mtime,
+    Atime,
+    Ctime,
+    Mode,
+    Nlinks,
+    Uid,
+    Gid,
+    Uname,
+    Gname,
+    Inode,
+    Dev,
+}
+
+impl ListField {
+    fn parse(name: &str) -> Result<Self, RpcError> {
+        Ok(match name {
+            "name" => Self::Name,
+            "type" => Self::Type,
+            "size" => Self::Size,
+            "mtime" => Self::Mtime,
+            "atime" => Self::Atime,
+            "ctime" => Self::Ctime,
+            "mode" => Self::Mode,
+            "nlinks" => Self::Nlinks,
+            "uid" => Self::Uid,
+            "gid" => Self::Gid,
+            "uname" => Self::Uname,
+            "gname" => Self::Gname,
+            "inode" => Self::Inode,
+            "dev" => Self::Dev,
+            other => {
+                return Err(RpcError::invalid_params(format!(
+                    "Unknown dir.list field \"{}\"",
+                    other
+                )));
+            }
+        })
+    }
+}
+
+/// An entry name and its stat, if it could be taken
+type StatEntry = (Vec<u8>, Option<libc::stat>);
+
+/// Names and lstat results of the entries of `path`, sorted by name.
+///
+/// `.` and `..` are followed, as in `list_dir_sync`.  Entries that vanish
+/// or cannot be stat'ed between readdir and fstatat have no stat.
+fn lstat_entries_sync(path: &Path, include_hidden: bool) -> Result<Vec<StatEntry>, std::io::Error> {
+    let dir = std::fs::File::open(path)?;
+    let dir_fd = std::os::unix::io::AsRawFd::as_raw_fd(&dir);
+
+    let stat_at = |name: &[u8], flags: libc::c_int| {
+        let mut stat_buf: libc::stat = unsafe { std::mem::zeroed() };
+        let mut name_cstr = name.to_vec();
+        name_cstr.push(0);
+        let result = unsafe {
+            libc::fstatat(
+                dir_fd,
+                name_cstr.as_ptr() as *const libc::c_char,
+                &mut stat_buf,
+                flags,
+            )
+        };
+        (result == 0).then_some(stat_buf)
+    };
+
+    let mut results = Vec::new();
+    if include_hidden {
+        results.push((b".".to_vec(), stat_at(b".", 0)));
+        results.push((b"..".to_vec(), stat_at(b"..", 0)));
+    }
+    for entry_result in std::fs::read_dir(path)? {
+        let name = entry_result?.file_name().as_bytes().to_vec();
+        if !include_hidden && name.first() == Some(&b'.') {
+            continue;
+        }
+        let stat_buf = stat_at(&name, libc::AT_SYMLINK_NOFOLLOW);
+        results.push((name, stat_buf));
+    }
+    results.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(results)
+}
+
+/// Build an entry map holding only `fields` (plus `name`, always).
+#[allow(clippy::unnecessary_cast)] // stat field widths vary across platforms
+fn select_fields(
+    name: &[u8],
+    stat_buf: Option<&libc::stat>,
+    fields: &[ListField],
+    names: NamesAs,
+) -> Value {
+    let mut pairs: Vec<(Value, Value)> = vec![("name".into(), names.encode(name))];
+    let Some(stat_buf) = stat_buf else {
+        return Value::Map(pairs);
+    };
+    let (atime, mtime, ctime, mode) = extract_stat_fields(stat_buf);
+    for field in fields {
+        let (key, value): (&str, Value) = match field {
+            ListField::Name => continue,
+            ListField::Type => (
+                "type",
+                file_type_from_mode(stat_buf.st_mode).as_str().into(),
+            ),
+            ListField::Size => ("size", (stat_buf.st_size as u64).into()),
+            ListField::Mtime => ("mtime", mtime.into()),
+            ListField::Atime => ("atime", atime.into()),
+            ListField::Ctime => ("ctime", ctime.into()),
+            ListField::Mode => ("mode", mode.into()),
+            ListField::Nlinks => ("nlinks", (stat_buf.st_nlink as u64).into()),
+            ListField::Uid => ("uid", stat_buf.st_uid.into()),
+            ListField::Gid => ("gid", stat_buf.st_gid.into()),
+            ListField::Uname => match super::file::get_user_name(stat_buf.st_uid) {
+                Some(uname) => ("uname", uname.into()),
+                None => continue,
+            },
+            ListField::Gname => match super::file::get_group_name(stat_buf.st_gid) {
+                Some(gname) => ("gname", gname.into()),
+                None => continue,
+            },
+            ListField::Inode => ("inode", (stat_buf.st_ino as u64).into()),
+            ListField::Dev => ("dev", (stat_buf.st_dev as u64).into()),
+        };
+        pairs.push((key.into(), value));
+    }
+    Value::Map(pairs)
+}
+
+/// Hex sha256 over the sorted `(name, mtime, size)` tuples of a listing.
+///
+/// `..` is left out so changes to sibling directories don't change it.
+/// Entries that could not be stat'ed contribute their name only.
+#[allow(clippy::unnecessary_cast)] // st_mtime_nsec is not i64 everywhere
+fn listing_fingerprint(entries: &[StatEntry]) -> String {
+    use sha2::{Digest, Sha256};
+    let mut hasher = Sha256::new();
+    for (name, stat_buf) in entries {
+        if name == b".." {
+            continue;
+        }
+        hasher.update((name.len() as u64).to_le_bytes());
+        hasher.update(name);
+        match stat_buf {
+            Some(stat_buf) => {
+                hasher.update([1]);
+                hasher.update(stat_time_to_i64(stat_buf.st_mtime).to_le_bytes());
+                hasher.update((stat_buf.st_mtime_nsec as i64).to_le_bytes());
+                hasher.update((stat_buf.st_size as u64).to_le_bytes());
+            }
+            None => hasher.update([0]),
+        }
+    }
+    hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
 /// Convert std::fs::FileType to our FileType
-fn file_type_from_metadata_ft(ft: &std::fs::FileType) -> FileType {
+pub(super) fn file_type_from_metadata_ft(ft: &std::fs::FileType) -> FileType {
     use std::os::unix::fs::FileTypeExt;
     if ft.is_file() {
         FileType::File
@@ -313,18 +1169,103 @@ fn missing_directory_chain(path: &Path) -> Vec<PathBuf> {
     missing
 }
 
-/// Create a directory
-pub async fn create(params: Value) -> HandlerResult {
-    #[derive(Deserialize)]
-    struct Params {
-        #[serde(with = "path_or_bytes")]
-        path: Vec<u8>,
-        /// Create parent directories if they don't exist
-        #[serde(default)]
-        parents: bool,
-        /// Directory mode (permissions)
-        #[serde(default = "default_mode")]
-        mode: u32,
+/// Create `path` (and with `parents`, its missing ancestors) with `mode`.
+///
+/// Each directory is created by mkdir(2) with `mode` itself, so group and
+/// other never get more than requested, not even briefly.  Only when the
+/// umask stripped requested bits is it chmodded afterwards, keeping a setgid
+/// bit it inherited.  Missing ancestors also get u+wx until their children
+/// exist, so a `mode` such as 0o555 does not lock the call out of its own
+/// tree; they are chmodded to `mode`, deepest first, at the end.  Already
+/// existing directories are left alone, so `make-directory DIR t' on an
+/// existing DIR has no chmod side effects.
+///
+/// Returns the directories this call created, also when it failed halfway.
+fn create_dirs_with_mode(
+    path: &Path,
+    parents: bool,
+    mode: u32,
+) -> (Vec<PathBuf>, std::io::Result<()>) {
+    let chain = if parents {
+        missing_directory_chain(path)
+    } else {
+        vec![path.to_path_buf()]
+    };
+    if chain.is_empty() && !path.is_dir() {
+        return (Vec::new(), Err(std::io::ErrorKind::AlreadyExists.into()));
+    }
+
+    let last = chain.len().saturating_sub(1);
+    let mut created = Vec::new();
+    let mut result = Ok(());
+    for (i, dir) in chain.into_iter().enumerate() {
+        let wanted = if i == last { mode } else { mode | 0o300 };
+        match create_dir_with_mode(&dir, wanted) {
+            Ok(()) => created.push(dir),
+            // Lost a race against another creator; that is fine for parents.
+            Err((e, false))
+                if parents && e.kind() == std::io::ErrorKind::AlreadyExists && dir.is_dir() => {}
+            Err((e, made)) => {
+                if made {
+                    created.push(dir);
+                }
+                result = Err(e);
+                break;
+            }
+        }
+    }
+    // The ancestors this call created, now that nothing below needs them
+    // writable
+    if mode & 0o300 != 0o300 {
+        for dir in created.iter().rev() {
+            if let Err(e) = set_dir_mode(dir, mode) {
+                result = result.and(Err(e));
+            }
+        }
+    }
+    (created, result)
+}
+
+/// mkdir(2) `dir` with `mode`, then chmod it if the umask stripped bits.
+/// On failure, says whether the directory was made.
+fn create_dir_with_mode(dir: &Path, mode: u32) -> Result<(), (std::io::Error, bool)> {
+    use std::os::unix::fs::DirBuilderExt;
+
+    std::fs::DirBuilder::new()
+        .mode(mode)
+        .create(dir)
+        .map_err(|e| (e, false))?;
+    set_dir_mode(dir, mode).map_err(|e| (e, true))
+}
+
+/// chmod `dir` to `mode` unless its permission bits already match, keeping
+/// any setgid or sticky bit it has.
+fn set_dir_mode(dir: &Path, mode: u32) -> std::io::Result<()> {
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    let current = std::fs::symlink_metadata(dir)?.mode();
+    if current & 0o777 == mode & 0o777 && mode & 0o7000 & !current == 0 {
+        return Ok(());
+    }
+    let mode = (current & 0o7000) | (mode & 0o7777);
+    std::fs::set_permissions(dir, std::fs::Permissions::from_mode(mode))
+}
+
+/// Create a directory
+pub async fn create(params: Value) -> HandlerResult {
+    #[derive(Deserialize)]
+    struct Params {
+        #[serde(with = "path_or_bytes")]
+        path: Vec<u8>,
+        /// Create parent directories if they don't exist
+        #[serde(default)]
+        parents: bool,
+        /// Directory mode (permissions)
+        #[serde(default = "default_mode")]
+        mode: u32,
+        /// Include the directory's attributes as `attrs`
+        #[serde(default)]
+        return_attrs: bool,
     }
 
     fn default_mode() -> u32 {
@@ -334,40 +1275,33 @@ pub async fn create(params: Value) -> HandlerResult {
     let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
 
     let path = bytes_to_path(&params.path);
-    let path_str = path.to_string_lossy().into_owned();
-
-    let created_paths = if params.parents {
-        missing_directory_chain(&path)
-    } else {
-        vec![path.clone()]
-    };
+    jail::check(&path)?;
 
-    let result = if params.parents {
-        fs::create_dir_all(&path).await
-    } else {
-        fs::create_dir(&path).await
+    let (parents, mode) = (params.parents, params.mode);
+    let (created_paths, result) = {
+        let path = path.clone();
+        crate::blocking::run(path.clone(), move || {
+            create_dirs_with_mode(&path, parents, mode)
+        })
+        .await?
     };
 
-    result.map_err(|e| map_io_error(e, &path_str))?;
-
-    // Set permissions only on directories created by this request.  This keeps
-    // the previous RPC behavior for new parent components while avoiding chmod
-    // side effects when `make-directory DIR t' is called for an existing dir.
-    #[cfg(unix)]
-    {
-        use std::os::unix::fs::PermissionsExt;
-        let perms = std::fs::Permissions::from_mode(params.mode);
-        for created_path in &created_paths {
-            fs::set_permissions(created_path, perms.clone())
-                .await
-                .map_err(|e| map_io_error(e, &created_path.to_string_lossy()))?;
-        }
+    for created_path in &created_paths {
+        stat_cache::invalidate(created_path).await;
     }
+    result.map_err(|e| map_io_error(e, &path))?;
 
     // Return whether this call created PATH.  Existing clients ignored the old
     // unconditional `true'; the Lisp handler now uses false to preserve the
     // `make-directory DIR t' return value when DIR already exists.
-    Ok(Value::Boolean(!created_paths.is_empty()))
+    let created = Value::Boolean(!created_paths.is_empty());
+    if params.return_attrs {
+        return Ok(super::file::with_attrs(
+            created,
+            vec![("attrs", super::file::attrs_after(&path, false).await)],
+        ));
+    }
+    Ok(created)
 }
 
 /// Remove a directory
@@ -379,20 +1313,2139 @@ pub async fn remove(params: Value) -> HandlerResult {
         /// Remove recursively
         #[serde(default)]
         recursive: bool,
+        /// Go on past entries that cannot be removed and report them, as
+        /// `rm -rf` does
+        #[serde(default)]
+        ignore_errors: bool,
     }
 
     let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
 
     let path = bytes_to_path(&params.path);
-    let path_str = path.to_string_lossy().into_owned();
+    jail::check(&path)?;
+
+    if params.recursive {
+        let result = remove_recursive(&path, params.ignore_errors).await;
+        stat_cache::invalidate_tree(&path).await;
+        return result;
+    }
+
+    let result = fs::remove_dir(&path).await;
+    stat_cache::invalidate_tree(&path).await;
+    result.map_err(|e| map_io_error(e, &path))?;
+
+    Ok(Value::Boolean(true))
+}
+
+/// Minimum time between two `dir.remove_progress` notifications.
+const REMOVE_PROGRESS_INTERVAL: std::time::Duration = std::time::Duration::from_millis(500);
+
+/// At most this many remaining paths and errors are reported.
+const REMOVE_REPORT_LIMIT: usize = 100;
+
+/// How far a recursive removal got, for its progress notifications
+#[derive(Default)]
+struct RemoveProgress {
+    removed: std::sync::atomic::AtomicU64,
+    current: std::sync::Mutex<PathBuf>,
+}
+
+#[derive(Default)]
+struct RemoveOutcome {
+    removed: u64,
+    /// The first entries that could not be removed
+    errors: Vec<(PathBuf, std::io::Error)>,
+    error_count: u64,
+    cancelled: bool,
+}
+
+impl RemoveOutcome {
+    /// Record that `path` failed with `err`; returns whether to go on.
+    fn fail(&mut self, path: PathBuf, err: std::io::Error, ignore_errors: bool) -> bool {
+        self.error_count += 1;
+        if self.errors.len() < REMOVE_REPORT_LIMIT {
+            self.errors.push((path, err));
+        }
+        ignore_errors
+    }
+
+    fn removed(&mut self, path: &Path, progress: &RemoveProgress) {
+        self.removed += 1;
+        progress
+            .removed
+            .store(self.removed, std::sync::atomic::Ordering::Relaxed);
+        *progress.current.lock().unwrap_or_else(|e| e.into_inner()) = path.to_path_buf();
+    }
+}
+
+/// Remove the tree at `path` for `dir.remove` with `recursive`.
+///
+/// The walk runs in one blocking task, removing the entries of each
+/// directory before the directory itself.  It stops at the first error,
+/// unless `ignore_errors`, and between two entries once the request is
+/// cancelled.  Either way the error carries `removed` and the first
+/// `remaining` paths.  Trees that take a while report `dir.remove_progress`
+/// notifications of `{path, removed, current}`.
+async fn remove_recursive(path: &Path, ignore_errors: bool) -> HandlerResult {
+    let progress = std::sync::Arc::new(RemoveProgress::default());
+    let work = {
+        let (root, progress) = (path.to_path_buf(), progress.clone());
+        let token = crate::cancel::current();
+        crate::blocking::run(path, move || {
+            remove_tree(&root, ignore_errors, &token, &progress)
+        })
+    };
+    tokio::pin!(work);
+
+    let path_value = crate::protocol::path_value(path);
+    let context = crate::trace::context(crate::trace::current(), "remove", path_value.clone());
+    let mut ticker = tokio::time::interval_at(
+        tokio::time::Instant::now() + REMOVE_PROGRESS_INTERVAL,
+        REMOVE_PROGRESS_INTERVAL,
+    );
+    let outcome = loop {
+        tokio::select! {
+            outcome = &mut work => break outcome?,
+            _ = ticker.tick() => {
+                let current = progress.current.lock().unwrap_or_else(|e| e.into_inner()).clone();
+                let params = msgpack_map! {
+                    "path" => path_value.clone(),
+                    "removed" => progress.removed.load(std::sync::atomic::Ordering::Relaxed),
+                    "current" => crate::protocol::path_value(&current)
+                };
+                let notification = crate::protocol::Notification::new("dir.remove_progress", params);
+                crate::send_notification(notification.with_context(context.clone())).await;
+            }
+        }
+    };
 
-    let result = if params.recursive {
-        fs::remove_dir_all(&path).await
+    if !outcome.cancelled && outcome.error_count == 0 && !ignore_errors {
+        return Ok(Value::Boolean(true));
+    }
+    let remaining = if outcome.cancelled || outcome.error_count > 0 {
+        let root = path.to_path_buf();
+        crate::blocking::run(path, move || remaining_paths(&root, REMOVE_REPORT_LIMIT)).await?
     } else {
-        fs::remove_dir(&path).await
+        Vec::new()
     };
+    let remaining = Value::Array(
+        remaining
+            .iter()
+            .map(|path| crate::protocol::path_value(path))
+            .collect(),
+    );
 
-    result.map_err(|e| map_io_error(e, &path_str))?;
+    if outcome.cancelled {
+        return Err(RpcError::cancelled("dir.remove")
+            .with_data("path", path_value)
+            .with_data("removed", outcome.removed.into())
+            .with_data("remaining", remaining));
+    }
+    if !ignore_errors {
+        let (failed, err) = outcome.errors.into_iter().next().expect("an error");
+        return Err(map_io_error(err, &failed)
+            .with_data("removed", outcome.removed.into())
+            .with_data("remaining", remaining));
+    }
+    let errors = outcome
+        .errors
+        .into_iter()
+        .map(|(path, err)| {
+            msgpack_map! {
+                "path" => crate::protocol::path_value(&path),
+                "error" => super::file::error_value(map_io_error(err, &path))
+            }
+        })
+        .collect();
+    Ok(msgpack_map! {
+        "removed" => outcome.removed,
+        "errors" => Value::Array(errors),
+        "error_count" => outcome.error_count,
+        "remaining" => remaining
+    })
+}
 
-    Ok(Value::Boolean(true))
+/// Entries read from a directory before removing them.
+const REMOVE_BATCH: usize = 1024;
+
+/// A directory on the way down from the root of a removal
+struct RemoveLevel {
+    path: PathBuf,
+    /// The name in its parent, for `unlinkat` once it is empty
+    name: std::ffi::CString,
+    /// Device and inode, to recognize it on the way back up
+    id: (u64, u64),
+    /// Entries that failed with `ignore_errors`, skipped when reread
+    failed: std::collections::HashSet<Vec<u8>>,
+}
+
+/// Remove `root` and everything below it, depth first, without following
+/// symlinks.  Entries that vanish meanwhile are not errors.
+///
+/// Every entry is opened or unlinked relative to its parent's descriptor,
+/// with `O_NOFOLLOW`, so a directory swapped for a symlink mid-walk is
+/// removed as a link and its target is never entered.  Only the directory
+/// being emptied is open: the walk climbs back through `..` and checks that
+/// it reached the parent it came from, so deep trees need no descriptor per
+/// level.
+fn remove_tree(
+    root: &Path,
+    ignore_errors: bool,
+    token: &crate::cancel::Token,
+    progress: &RemoveProgress,
+) -> RemoveOutcome {
+    use nix::dir::{Dir, Type};
+    use nix::errno::Errno;
+    use nix::fcntl::{AtFlags, OFlag};
+    use nix::sys::stat::{Mode, SFlag, fstat, fstatat};
+    use nix::unistd::{UnlinkatFlags, unlinkat};
+    use std::io::ErrorKind;
+
+    let open_flags = OFlag::O_RDONLY | OFlag::O_DIRECTORY | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC;
+    let mut outcome = RemoveOutcome::default();
+    let is_dir = match std::fs::symlink_metadata(root) {
+        Ok(meta) => meta.is_dir(),
+        Err(e) if ignore_errors && e.kind() == ErrorKind::NotFound => return outcome,
+        Err(e) => {
+            outcome.fail(root.to_path_buf(), e, ignore_errors);
+            return outcome;
+        }
+    };
+    if !is_dir {
+        // As with remove_dir_all, a symlink is removed, not its target
+        match std::fs::remove_file(root) {
+            Ok(()) => outcome.removed(root, progress),
+            Err(e) => {
+                outcome.fail(root.to_path_buf(), e, ignore_errors);
+            }
+        }
+        return outcome;
+    }
+
+    let opened = Dir::open(root, open_flags, Mode::empty()).and_then(|dir| {
+        let stat = fstat(&dir)?;
+        Ok((dir, stat))
+    });
+    let (mut dir, stat) = match opened {
+        Ok(opened) => opened,
+        Err(e) => {
+            outcome.fail(root.to_path_buf(), e.into(), ignore_errors);
+            return outcome;
+        }
+    };
+    let mut stack = vec![RemoveLevel {
+        path: root.to_path_buf(),
+        name: std::ffi::CString::default(),
+        id: file_id(&stat),
+        failed: Default::default(),
+    }];
+    'walk: loop {
+        if token.is_cancelled() {
+            outcome.cancelled = true;
+            break;
+        }
+        let level = stack.last_mut().expect("a level");
+        // The next entries, from the start: those removed are gone by now
+        let mut batch = Vec::new();
+        let mut listed = true;
+        for entry in dir.iter() {
+            match entry {
+                Ok(entry) => {
+                    let name = entry.file_name();
+                    let bytes = name.to_bytes();
+                    if bytes == b"." || bytes == b".." || level.failed.contains(bytes) {
+                        continue;
+                    }
+                    batch.push((name.to_owned(), entry.file_type()));
+                    if batch.len() == REMOVE_BATCH {
+                        break;
+                    }
+                }
+                // A listing that fails cannot be resumed; the directory stays
+                Err(e) => {
+                    if !outcome.fail(level.path.clone(), e.into(), ignore_errors) {
+                        break 'walk;
+                    }
+                    listed = false;
+                    break;
+                }
+            }
+        }
+
+        let exhausted = batch.is_empty();
+        let mut descend = None;
+        for (name, file_type) in batch {
+            if token.is_cancelled() {
+                outcome.cancelled = true;
+                break 'walk;
+            }
+            let is_dir = match file_type {
+                Some(file_type) => file_type == Type::Directory,
+                None => {
+                    fstatat(&dir, name.as_c_str(), AtFlags::AT_SYMLINK_NOFOLLOW).is_ok_and(|st| {
+                        SFlag::from_bits_truncate(st.st_mode) & SFlag::S_IFMT == SFlag::S_IFDIR
+                    })
+                }
+            };
+            if is_dir {
+                descend = Some(name);
+                break;
+            }
+            let path = level.path.join(OsStr::from_bytes(name.to_bytes()));
+            match unlinkat(&dir, name.as_c_str(), UnlinkatFlags::NoRemoveDir) {
+                Ok(()) => outcome.removed(&path, progress),
+                Err(Errno::ENOENT) => {}
+                Err(e) => {
+                    if !outcome.fail(path, e.into(), ignore_errors) {
+                        break 'walk;
+                    }
+                    level.failed.insert(name.into_bytes());
+                }
+            }
+        }
+
+        if let Some(name) = descend {
+            let path = level.path.join(OsStr::from_bytes(name.to_bytes()));
+            let opened =
+                Dir::openat(&dir, name.as_c_str(), open_flags, Mode::empty()).and_then(|child| {
+                    let stat = fstat(&child)?;
+                    Ok((child, stat))
+                });
+            match opened {
+                Ok((child, stat)) => {
+                    stack.push(RemoveLevel {
+                        path,
+                        name,
+                        id: file_id(&stat),
+                        failed: Default::default(),
+                    });
+                    dir = child;
+                }
+                Err(Errno::ENOENT) => {}
+                // Replaced by a symlink or a file since it was listed
+                Err(Errno::ELOOP | Errno::ENOTDIR) => {
+                    match unlinkat(&dir, name.as_c_str(), UnlinkatFlags::NoRemoveDir) {
+                        Ok(()) => outcome.removed(&path, progress),
+                        Err(Errno::ENOENT) => {}
+                        Err(e) => {
+                            if !outcome.fail(path, e.into(), ignore_errors) {
+                                break;
+                            }
+                            level.failed.insert(name.into_bytes());
+                        }
+                    }
+                }
+                Err(e) => {
+                    if !outcome.fail(path, e.into(), ignore_errors) {
+                        break;
+                    }
+                    level.failed.insert(name.into_bytes());
+                }
+            }
+            continue;
+        }
+        if listed && !exhausted {
+            continue;
+        }
+
+        // Empty, as far as it can be: remove it from its parent
+        let done = stack.pop().expect("a level");
+        let Some(parent) = stack.last_mut() else {
+            drop(dir);
+            match std::fs::remove_dir(root) {
+                Ok(()) => outcome.removed(root, progress),
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                // Left non-empty by the failures below it
+                Err(e) if e.kind() == ErrorKind::DirectoryNotEmpty && outcome.error_count > 0 => {}
+                Err(e) => {
+                    outcome.fail(root.to_path_buf(), e, ignore_errors);
+                }
+            }
+            return outcome;
+        };
+        let opened = Dir::openat(&dir, c"..", open_flags, Mode::empty()).and_then(|up| {
+            let stat = fstat(&up)?;
+            Ok((up, stat))
+        });
+        let up = match opened {
+            Ok((up, stat)) if file_id(&stat) == parent.id => up,
+            Ok(_) => {
+                let moved = std::io::Error::other("moved during the removal");
+                outcome.fail(done.path, moved, false);
+                break;
+            }
+            Err(e) => {
+                outcome.fail(done.path, e.into(), false);
+                break;
+            }
+        };
+        dir = up;
+        match unlinkat(&dir, done.name.as_c_str(), UnlinkatFlags::RemoveDir) {
+            Ok(()) => outcome.removed(&done.path, progress),
+            Err(Errno::ENOENT) => {}
+            Err(Errno::ENOTEMPTY | Errno::EEXIST) if outcome.error_count > 0 => {
+                parent.failed.insert(done.name.into_bytes());
+            }
+            Err(e) => {
+                if !outcome.fail(done.path, e.into(), ignore_errors) {
+                    break;
+                }
+                parent.failed.insert(done.name.into_bytes());
+            }
+        }
+    }
+    outcome
+}
+
+/// The device and inode of `stat`
+// Allow unnecessary casts for cross-platform compatibility (types differ between Linux/macOS)
+#[allow(clippy::unnecessary_cast)]
+fn file_id(stat: &nix::sys::stat::FileStat) -> (u64, u64) {
+    (stat.st_dev as u64, stat.st_ino as u64)
+}
+
+/// Up to `limit` paths of what is left of the tree at `root`, parents
+/// before their entries.
+fn remaining_paths(root: &Path, limit: usize) -> Vec<PathBuf> {
+    let mut found = Vec::new();
+    let mut pending = vec![root.to_path_buf()];
+    while let Some(path) = pending.pop() {
+        if found.len() >= limit {
+            break;
+        }
+        let Ok(meta) = std::fs::symlink_metadata(&path) else {
+            continue;
+        };
+        if meta.is_dir()
+            && let Ok(entries) = std::fs::read_dir(&path)
+        {
+            pending.extend(entries.flatten().take(limit).map(|entry| entry.path()));
+        }
+        found.push(path);
+    }
+    found
+}
+
+/// Default and maximum number of paths returned by `file.expand_wildcards`.
+const DEFAULT_WILDCARD_LIMIT: usize = 10_000;
+const MAX_WILDCARD_LIMIT: usize = 100_000;
+
+/// Expand a shell glob on the server, for `file-expand-wildcards`.
+///
+/// `pattern` may span several components (`src/**/*.rs`).  Each component
+/// supports `*`, `?`, character classes and braces, and `**` matches any
+/// number of directories (or, as the last component, everything below).  As
+/// in the shell, wildcards only match hidden names when the component starts
+/// with a dot, and a trailing slash only matches directories.  Relative
+/// patterns are expanded from `directory`.
+///
+/// Returns `{paths, truncated}` with absolute paths sorted by name, or
+/// `{path, attrs}` maps with `attrs`.  No match is an empty list.  With the
+/// "list_envelope" feature the paths come as `{entries, total, truncated,
+/// reason}`.
+pub async fn expand_wildcards(params: Value) -> HandlerResult {
+    #[derive(Deserialize)]
+    struct Params {
+        #[serde(with = "path_or_bytes")]
+        pattern: Vec<u8>,
+        /// Base of relative patterns
+        #[serde(default)]
+        directory: Option<PathBytes>,
+        #[serde(default = "default_limit")]
+        limit: usize,
+        /// Include lstat attributes for each match
+        #[serde(default)]
+        attrs: bool,
+    }
+
+    fn default_limit() -> usize {
+        DEFAULT_WILDCARD_LIMIT
+    }
+
+    let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
+
+    if params.limit == 0 || params.limit > MAX_WILDCARD_LIMIT {
+        return Err(RpcError::invalid_params(format!(
+            "limit must be between 1 and {}",
+            MAX_WILDCARD_LIMIT
+        )));
+    }
+
+    let mut pattern = bytes_to_path(&params.pattern);
+    if pattern.is_relative() {
+        let Some(PathBytes(directory)) = params.directory else {
+            return Err(RpcError::invalid_params(
+                "A relative pattern needs a directory",
+            ));
+        };
+        pattern = bytes_to_path(&directory).join(pattern);
+    }
+
+    let raw = pattern.as_os_str().as_bytes();
+    let dirs_only = raw.ends_with(b"/");
+    let mut parts: Vec<&[u8]> = raw
+        .split(|&b| b == b'/')
+        .filter(|p| !p.is_empty())
+        .collect();
+
+    // The literal leading components name the directory to start from
+    let literal = parts.iter().take_while(|p| !is_wildcard(p)).count();
+    let mut base = PathBuf::from("/");
+    for part in parts.drain(..literal) {
+        base.push(OsStr::from_bytes(part));
+    }
+    jail::check(&base)?;
+
+    let matchers = parts
+        .iter()
+        .map(|part| WildcardPart::new(part))
+        .collect::<Result<Vec<_>, _>>()?;
+    let limit = params.limit;
+
+    let (paths, total) = crate::blocking::run(base.clone(), move || {
+        let mut candidates = vec![base];
+        let last = matchers.len().saturating_sub(1);
+        for (i, part) in matchers.iter().enumerate() {
+            let mut next = Vec::new();
+            for dir in &candidates {
+                part.expand(dir, i == last, &mut next);
+            }
+            candidates = next;
+        }
+
+        let mut paths: Vec<PathBuf> = candidates
+            .into_iter()
+            .filter(|path| {
+                let exists = if dirs_only {
+                    path.is_dir()
+                } else {
+                    std::fs::symlink_metadata(path).is_ok()
+                };
+                exists && jail::check(path).is_ok()
+            })
+            .collect();
+        paths.sort();
+        paths.dedup();
+        let total = paths.len();
+        paths.truncate(limit);
+        (paths, total)
+    })
+    .await?;
+
+    let mut values = Vec::with_capacity(paths.len());
+    for path in paths {
+        let mut bytes = path.as_os_str().as_bytes().to_vec();
+        if dirs_only {
+            bytes.push(b'/');
+        }
+        if params.attrs {
+            let attrs = super::file::get_file_attributes(&path, true)
+                .await
+                .map(|attrs| attrs.to_value())
+                .unwrap_or(Value::Nil);
+            values.push(msgpack_map! {
+                "path" => Value::Binary(bytes),
+                "attrs" => attrs
+            });
+        } else {
+            values.push(Value::Binary(bytes));
+        }
+    }
+
+    let truncated = total > values.len();
+    if crate::handshake::enabled(Feature::ListEnvelope) {
+        return Ok(Listing {
+            entries: values,
+            total: Some(total as u64),
+            truncation: truncated.then_some(Truncation::Limit),
+        }
+        .into_value());
+    }
+    Ok(msgpack_map! {
+        "paths" => Value::Array(values),
+        "truncated" => truncated
+    })
+}
+
+fn is_wildcard(part: &[u8]) -> bool {
+    part.iter()
+        .any(|b| matches!(b, b'*' | b'?' | b'[' | b'{' | b'\\'))
+}
+
+/// One component of a `file.expand_wildcards` pattern
+enum WildcardPart {
+    Literal(PathBuf),
+    /// `**`
+    Recursive,
+    Glob {
+        matcher: globset::GlobMatcher,
+        hidden: bool,
+    },
+}
+
+impl WildcardPart {
+    fn new(part: &[u8]) -> Result<Self, RpcError> {
+        if part == b"**" {
+            return Ok(Self::Recursive);
+        }
+        if !is_wildcard(part) {
+            return Ok(Self::Literal(PathBuf::from(OsStr::from_bytes(part))));
+        }
+        let glob = String::from_utf8_lossy(part);
+        let matcher = globset::GlobBuilder::new(&glob)
+            .literal_separator(true)
+            .backslash_escape(true)
+            .build()
+            .map_err(|e| RpcError::invalid_params(format!("Invalid pattern {:?}: {}", glob, e)))?
+            .compile_matcher();
+        Ok(Self::Glob {
+            matcher,
+            hidden: part.starts_with(b"."),
+        })
+    }
+
+    /// Push the matches of this component below `dir` onto `out`.
+    fn expand(&self, dir: &Path, last: bool, out: &mut Vec<PathBuf>) {
+        match self {
+            Self::Literal(name) => out.push(dir.join(name)),
+            Self::Glob { matcher, hidden } => {
+                if let Ok(entries) = std::fs::read_dir(dir) {
+                    for entry in entries.filter_map(Result::ok) {
+                        let name = entry.file_name();
+                        if (*hidden || !name.as_bytes().starts_with(b"."))
+                            && matcher.is_match(Path::new(&name))
+                        {
+                            out.push(dir.join(name));
+                        }
+                    }
+                }
+            }
+            Self::Recursive => {
+                // Like bash's globstar: skip hidden entries and do not
+                // follow symlinked directories
+                if !last {
+                    out.push(dir.to_path_buf());
+                }
+                let mut stack = vec![dir.to_path_buf()];
+                while let Some(dir) = stack.pop() {
+                    let Ok(entries) = std::fs::read_dir(&dir) else {
+                        continue;
+                    };
+                    for entry in entries.filter_map(Result::ok) {
+                        if entry.file_name().as_bytes().starts_with(b".") {
+                            continue;
+                        }
+                        let is_dir = entry.file_type().is_ok_and(|ft| ft.is_dir());
+                        if is_dir {
+                            stack.push(entry.path());
+                            out.push(entry.path());
+                        } else if last {
+                            out.push(entry.path());
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Usage of one child of a `dir.disk_usage` directory
+struct Usage {
+    name: Vec<u8>,
+    file_type: FileType,
+    size: u64,
+    blocks: u64,
+    incomplete: bool,
+}
+
+/// Inodes already counted, so hard links are only counted once
+type Seen = std::collections::HashSet<(u64, u64)>;
+
+/// Add the usage of `meta` to `usage` unless it is a hard link already
+/// counted.
+fn count_usage(usage: &mut Usage, meta: &std::fs::Metadata, seen: &mut Seen) {
+    use std::os::unix::fs::MetadataExt;
+    if !meta.is_dir() && meta.nlink() > 1 && !seen.insert((meta.dev(), meta.ino())) {
+        return;
+    }
+    usage.size += meta.len();
+    usage.blocks += meta.blocks();
+}
+
+/// Walk `root`, summing each immediate child's recursive usage.  Symlinks
+/// are not followed and unreadable directories only count themselves.
+fn disk_usage_sync(
+    root: &Path,
+    exclude: &ignore::gitignore::Gitignore,
+    deadline: Option<std::time::Instant>,
+) -> std::io::Result<(Vec<Usage>, Usage)> {
+    let expired = || deadline.is_some_and(|d| std::time::Instant::now() >= d);
+    let skips = |path: &Path, is_dir: bool| {
+        let rel = path.strip_prefix(root).unwrap_or(path);
+        exclude.matched_path_or_any_parents(rel, is_dir).is_ignore()
+    };
+
+    let mut seen = Seen::new();
+    let mut total = Usage {
+        name: Vec::new(),
+        file_type: FileType::Directory,
+        size: 0,
+        blocks: 0,
+        incomplete: false,
+    };
+    count_usage(&mut total, &std::fs::symlink_metadata(root)?, &mut seen);
+
+    // In name order, so the child a shared hard link is counted under
+    // does not depend on readdir order
+    let mut paths: Vec<PathBuf> = std::fs::read_dir(root)?
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .collect();
+    paths.sort();
+
+    let mut children = Vec::new();
+    for path in paths {
+        let Ok(meta) = std::fs::symlink_metadata(&path) else {
+            continue;
+        };
+        if skips(&path, meta.is_dir()) {
+            continue;
+        }
+        let mut usage = Usage {
+            name: path.file_name().unwrap_or_default().as_bytes().to_vec(),
+            file_type: file_type_from_metadata_ft(&meta.file_type()),
+            size: 0,
+            blocks: 0,
+            incomplete: false,
+        };
+        count_usage(&mut usage, &meta, &mut seen);
+
+        let mut stack = if meta.is_dir() {
+            vec![path]
+        } else {
+            Vec::new()
+        };
+        while let Some(dir) = stack.pop() {
+            if expired() {
+                usage.incomplete = true;
+                break;
+            }
+            let Ok(entries) = std::fs::read_dir(&dir) else {
+                continue;
+            };
+            for entry in entries.filter_map(Result::ok) {
+                let path = entry.path();
+                let Ok(meta) = std::fs::symlink_metadata(&path) else {
+                    continue;
+                };
+                if skips(&path, meta.is_dir()) {
+                    continue;
+                }
+                count_usage(&mut usage, &meta, &mut seen);
+                if meta.is_dir() {
+                    stack.push(path);
+                }
+            }
+        }
+
+        total.size += usage.size;
+        total.blocks += usage.blocks;
+        total.incomplete |= usage.incomplete;
+        children.push(usage);
+    }
+    Ok((children, total))
+}
+
+/// Report the recursive usage of each entry of a directory, for showing
+/// sizes in dired without running `du` per subdirectory.
+///
+/// One walk computes, for every immediate child, its apparent `size` in
+/// bytes and its `blocks` in 512-byte units, counting each hard-linked
+/// inode once across the whole walk.  `exclude` are gitignore-style globs
+/// relative to `path`; excluded children are left out.  Symlinks are not
+/// followed.
+///
+/// Returns `{entries: [{name, type, size, blocks, incomplete}], total:
+/// {size, blocks}, incomplete}`, where `total` also counts `path` itself.
+/// Once `max_seconds` have passed the walk stops: the directories not
+/// fully walked by then are marked `incomplete` and hold what was counted
+/// so far.  `order` is "size" (default, largest first), "blocks" (largest
+/// first) or "name".  With the "list_envelope" feature the result also has
+/// `truncated` and `reason` ("timeout"), as other listings do.
+pub async fn disk_usage(params: Value) -> HandlerResult {
+    #[derive(Deserialize)]
+    struct Params {
+        #[serde(with = "path_or_bytes")]
+        path: Vec<u8>,
+        #[serde(default)]
+        exclude: Vec<String>,
+        /// Time budget for the walk
+        #[serde(default)]
+        max_seconds: Option<f64>,
+        /// "size" (default), "blocks" or "name"
+        #[serde(default)]
+        order: Option<String>,
+        /// "binary" (default) or "auto"
+        #[serde(default)]
+        names_as: Option<String>,
+    }
+
+    enum Order {
+        Size,
+        Blocks,
+        Name,
+    }
+
+    let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
+
+    let path = bytes_to_path(&params.path);
+    jail::check(&path)?;
+    let path_str = path.to_string_lossy().into_owned();
+    let names = NamesAs::parse(params.names_as.as_deref())?;
+    let order = match params.order.as_deref() {
+        None | Some("size") => Order::Size,
+        Some("blocks") => Order::Blocks,
+        Some("name") => Order::Name,
+        Some(other) => {
+            return Err(RpcError::invalid_params(format!(
+                "order must be \"size\", \"blocks\" or \"name\", got \"{}\"",
+                other
+            )));
+        }
+    };
+    let deadline = match params.max_seconds {
+        Some(secs) if !(secs >= 0.0 && secs.is_finite()) => {
+            return Err(RpcError::invalid_params(
+                "max_seconds must be a non-negative number",
+            ));
+        }
+        Some(secs) => Some(std::time::Instant::now() + std::time::Duration::from_secs_f64(secs)),
+        None => None,
+    };
+    let exclude = super::project::build_globs(&path, &params.exclude)?;
+
+    let (mut entries, total) = crate::blocking::run(path.clone(), move || {
+        disk_usage_sync(&path, &exclude, deadline)
+    })
+    .await?
+    .map_err(|e| map_io_error(e, &path_str))?;
+
+    match order {
+        Order::Size => entries.sort_by_key(|usage| std::cmp::Reverse(usage.size)),
+        Order::Blocks => entries.sort_by_key(|usage| std::cmp::Reverse(usage.blocks)),
+        Order::Name => {}
+    }
+
+    let mut result = msgpack_map! {
+        "entries" => Value::Array(
+            entries
+                .iter()
+                .map(|usage| msgpack_map! {
+                    "name" => names.encode(&usage.name),
+                    "type" => usage.file_type.as_str(),
+                    "size" => usage.size,
+                    "blocks" => usage.blocks,
+                    "incomplete" => usage.incomplete
+                })
+                .collect()
+        ),
+        "total" => msgpack_map! {
+            "size" => total.size,
+            "blocks" => total.blocks
+        },
+        "incomplete" => total.incomplete
+    };
+    // `total` already holds the sizes, so only the truncation is added
+    if crate::handshake::enabled(Feature::ListEnvelope)
+        && let Value::Map(ref mut pairs) = result
+    {
+        let reason = total.incomplete.then_some(Truncation::Timeout);
+        pairs.push(("truncated".into(), total.incomplete.into()));
+        pairs.push((
+            "reason".into(),
+            reason.map_or(Value::Nil, |reason| reason.as_str().into()),
+        ));
+    }
+    Ok(result)
+}
+
+/// Entries reported by `dir.audit_perms` unless `max_results` says otherwise
+const DEFAULT_AUDIT_RESULTS: usize = 1000;
+
+/// Time budget of `dir.audit_perms` unless `max_seconds` says otherwise
+const DEFAULT_AUDIT_SECONDS: f64 = 30.0;
+
+/// An expected owner or group, by id or by name
+#[derive(Deserialize, Clone)]
+#[serde(untagged)]
+enum AuditOwner {
+    Id(u32),
+    Name(String),
+}
+
+impl AuditOwner {
+    fn differs(&self, id: u32, name_of: fn(u32) -> Option<String>) -> bool {
+        match self {
+            AuditOwner::Id(expected) => id != *expected,
+            AuditOwner::Name(expected) => name_of(id).as_deref() != Some(expected.as_str()),
+        }
+    }
+}
+
+/// What `dir.audit_perms` looks for; an entry is reported when any of
+/// these match it
+#[derive(Deserialize, Clone, Default)]
+struct AuditPredicates {
+    /// Any of these permission bits set, such as 0o002 for world-writable
+    #[serde(default)]
+    mode_set: Option<u32>,
+    /// Any of these permission bits clear
+    #[serde(default)]
+    mode_clear: Option<u32>,
+    #[serde(default)]
+    owner: Option<AuditOwner>,
+    #[serde(default)]
+    group: Option<AuditOwner>,
+    #[serde(default)]
+    setuid: bool,
+    #[serde(default)]
+    setgid: bool,
+    #[serde(default)]
+    sticky: bool,
+}
+
+impl AuditPredicates {
+    fn is_empty(&self) -> bool {
+        self.mode_set.is_none()
+            && self.mode_clear.is_none()
+            && self.owner.is_none()
+            && self.group.is_none()
+            && !(self.setuid || self.setgid || self.sticky)
+    }
+
+    /// The names of the predicates `stat_buf` matches.  The mode of a
+    /// symlink means nothing, so only its owner and group are checked.
+    fn matches(&self, stat_buf: &libc::stat) -> Vec<&'static str> {
+        let (_, _, _, mode) = extract_stat_fields(stat_buf);
+        let checks_mode = stat_buf.st_mode & libc::S_IFMT != libc::S_IFLNK;
+        let perms = mode & 0o7777;
+        let mut matched = Vec::new();
+        if checks_mode {
+            let bits = [
+                ("mode_set", self.mode_set.is_some_and(|m| perms & m != 0)),
+                (
+                    "mode_clear",
+                    self.mode_clear.is_some_and(|m| !perms & m & 0o7777 != 0),
+                ),
+                ("setuid", self.setuid && perms & 0o4000 != 0),
+                ("setgid", self.setgid && perms & 0o2000 != 0),
+                ("sticky", self.sticky && perms & 0o1000 != 0),
+            ];
+            matched.extend(
+                bits.into_iter()
+                    .filter(|(_, hit)| *hit)
+                    .map(|(name, _)| name),
+            );
+        }
+        if let Some(owner) = &self.owner
+            && owner.differs(stat_buf.st_uid, super::file::get_user_name)
+        {
+            matched.push("owner");
+        }
+        if let Some(group) = &self.group
+            && group.differs(stat_buf.st_gid, super::file::get_group_name)
+        {
+            matched.push("group");
+        }
+        matched
+    }
+}
+
+/// One entry reported by `dir.audit_perms`
+struct AuditHit {
+    path: PathBuf,
+    stat: libc::stat,
+    matched: Vec<&'static str>,
+}
+
+/// What [`audit_perms_sync`] found
+#[derive(Default)]
+struct AuditOutcome {
+    hits: Vec<AuditHit>,
+    scanned: u64,
+    unreadable: u64,
+    truncation: Option<Truncation>,
+    cancelled: bool,
+}
+
+/// Walk `root` depth first, checking the entries of each directory in
+/// name order against `predicates` before entering its subdirectories.  Symlinks are not followed; directories that cannot be
+/// read are counted in `unreadable` and skipped.
+fn audit_perms_sync(
+    root: &Path,
+    predicates: &AuditPredicates,
+    exclude: &ignore::gitignore::Gitignore,
+    max_depth: Option<usize>,
+    max_results: usize,
+    deadline: std::time::Instant,
+    token: &crate::cancel::Token,
+) -> std::io::Result<AuditOutcome> {
+    let mut outcome = AuditOutcome::default();
+    let root_stat = {
+        let name = std::ffi::CString::new(root.as_os_str().as_bytes())
+            .map_err(|_| std::io::Error::from(std::io::ErrorKind::InvalidInput))?;
+        let mut stat_buf: libc::stat = unsafe { std::mem::zeroed() };
+        if unsafe { libc::lstat(name.as_ptr(), &mut stat_buf) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        stat_buf
+    };
+    let check = |outcome: &mut AuditOutcome, path: &Path, stat: &libc::stat| {
+        outcome.scanned += 1;
+        let matched = predicates.matches(stat);
+        if matched.is_empty() {
+            return true;
+        }
+        if outcome.hits.len() == max_results {
+            outcome.truncation = Some(Truncation::Limit);
+            return false;
+        }
+        outcome.hits.push(AuditHit {
+            path: path.to_path_buf(),
+            stat: *stat,
+            matched,
+        });
+        true
+    };
+    if !check(&mut outcome, root, &root_stat) || root_stat.st_mode & libc::S_IFMT != libc::S_IFDIR {
+        return Ok(outcome);
+    }
+
+    let mut stack = vec![(root.to_path_buf(), 0usize)];
+    while let Some((dir, depth)) = stack.pop() {
+        if token.is_cancelled() {
+            outcome.cancelled = true;
+            return Ok(outcome);
+        }
+        if std::time::Instant::now() >= deadline {
+            outcome.truncation = Some(Truncation::Timeout);
+            return Ok(outcome);
+        }
+        let Ok(entries) = lstat_entries_sync(&dir, true) else {
+            outcome.unreadable += 1;
+            continue;
+        };
+        let mut subdirs = Vec::new();
+        for (name, stat) in entries {
+            if name == b"." || name == b".." {
+                continue;
+            }
+            let path = dir.join(OsStr::from_bytes(&name));
+            let Some(stat) = stat else {
+                continue;
+            };
+            let is_dir = stat.st_mode & libc::S_IFMT == libc::S_IFDIR;
+            let rel = path.strip_prefix(root).unwrap_or(&path);
+            if exclude.matched_path_or_any_parents(rel, is_dir).is_ignore() {
+                continue;
+            }
+            if !check(&mut outcome, &path, &stat) {
+                return Ok(outcome);
+            }
+            if is_dir && max_depth.is_none_or(|max| depth + 1 < max) {
+                subdirs.push((path, depth + 1));
+            }
+        }
+        // Popped in name order
+        stack.extend(subdirs.into_iter().rev());
+    }
+    Ok(outcome)
+}
+
+/// Find the entries of a tree with suspicious permissions or ownership:
+/// `dir.audit_perms {path, mode_set?, mode_clear?, owner?, group?, setuid?,
+/// setgid?, sticky?, exclude?, max_depth?, max_results?, max_seconds?}`.
+///
+/// An entry is reported when any predicate matches: `mode_set` when it has
+/// any of those permission bits (0o002 finds world-writable files),
+/// `mode_clear` when it lacks any of them, `owner` and `group` (an id or a
+/// name) when it belongs to someone else, and `setuid`, `setgid` and
+/// `sticky` when it has that bit.  Only the owner and group of symlinks are
+/// checked.  `path` itself is checked too; `max_depth` 1 stops at its
+/// children.  `exclude` are gitignore-style globs relative to `path`, and
+/// excluded directories are not entered.
+///
+/// Returns `{entries, total, truncated, reason, scanned, unreadable}` with
+/// `{path, type, mode, uid, gid, uname, gname, matched}` entries in walk
+/// order, `matched` naming the predicates that matched.  At most
+/// `max_results` (default 1000) are returned and the walk stops after
+/// `max_seconds` (default 30); `reason` then says which ended it.
+pub async fn audit_perms(params: Value) -> HandlerResult {
+    #[derive(Deserialize)]
+    struct Params {
+        #[serde(with = "path_or_bytes")]
+        path: Vec<u8>,
+        #[serde(flatten)]
+        predicates: AuditPredicates,
+        #[serde(default)]
+        exclude: Vec<String>,
+        #[serde(default)]
+        max_depth: Option<usize>,
+        #[serde(default)]
+        max_results: Option<usize>,
+        #[serde(default)]
+        max_seconds: Option<f64>,
+    }
+
+    let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
+    if params.predicates.is_empty() {
+        return Err(RpcError::invalid_params(
+            "Give at least one of mode_set, mode_clear, owner, group, setuid, setgid or sticky",
+        ));
+    }
+    let max_results = params.max_results.unwrap_or(DEFAULT_AUDIT_RESULTS);
+    let secs = params.max_seconds.unwrap_or(DEFAULT_AUDIT_SECONDS);
+    if !(secs >= 0.0 && secs.is_finite()) {
+        return Err(RpcError::invalid_params(
+            "max_seconds must be a non-negative number",
+        ));
+    }
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs_f64(secs);
+
+    let path = bytes_to_path(&params.path);
+    jail::check(&path)?;
+    let path_str = path.to_string_lossy().into_owned();
+    let exclude = super::project::build_globs(&path, &params.exclude)?;
+
+    let outcome = {
+        let (root, predicates) = (path.clone(), params.predicates);
+        let token = crate::cancel::current();
+        crate::blocking::run(path.clone(), move || {
+            audit_perms_sync(
+                &root,
+                &predicates,
+                &exclude,
+                params.max_depth,
+                max_results,
+                deadline,
+                &token,
+            )
+        })
+        .await?
+        .map_err(|e| map_io_error(e, &path_str))?
+    };
+    if outcome.cancelled {
+        return Err(RpcError::cancelled("dir.audit_perms")
+            .with_data("path", crate::protocol::path_value(&path))
+            .with_data("scanned", outcome.scanned.into()));
+    }
+
+    let entries = outcome
+        .hits
+        .iter()
+        .map(|hit| {
+            let (_, _, _, mode) = extract_stat_fields(&hit.stat);
+            msgpack_map! {
+                "path" => crate::protocol::path_value(&hit.path),
+                "type" => file_type_from_mode(hit.stat.st_mode).as_str(),
+                "mode" => mode & 0o7777,
+                "uid" => hit.stat.st_uid,
+                "gid" => hit.stat.st_gid,
+                "uname" => super::file::get_user_name(hit.stat.st_uid).into_value(),
+                "gname" => super::file::get_group_name(hit.stat.st_gid).into_value(),
+                "matched" => Value::Array(hit.matched.iter().map(|&name| name.into()).collect())
+            }
+        })
+        .collect();
+    let mut result = Listing {
+        entries,
+        total: None,
+        truncation: outcome.truncation,
+    }
+    .into_value();
+    if let Value::Map(ref mut pairs) = result {
+        pairs.push(("scanned".into(), outcome.scanned.into()));
+        pairs.push(("unreadable".into(), outcome.unreadable.into()));
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::map_get;
+
+    #[tokio::test]
+    async fn list_multi_reports_errors_and_truncation() {
+        let tmp = tempfile::tempdir().unwrap();
+        let a = tmp.path().join("a");
+        let b = tmp.path().join("b");
+        std::fs::create_dir(&a).unwrap();
+        std::fs::create_dir(&b).unwrap();
+        for name in ["1", "2", "3"] {
+            std::fs::write(a.join(name), b"").unwrap();
+            std::fs::write(b.join(name), b"").unwrap();
+        }
+        let missing = tmp.path().join("missing");
+        let path_value = |p: &Path| Value::String(p.to_string_lossy().into_owned().into());
+
+        let result = list_multi(msgpack_map! {
+            "paths" => Value::Array(vec![path_value(&a), path_value(&missing), path_value(&b)]),
+            "include_hidden" => false,
+            "max_entries" => 4
+        })
+        .await
+        .unwrap();
+
+        let listings = map_get(&result, "listings").unwrap().as_map().unwrap();
+        assert_eq!(listings.len(), 3);
+        let listing = |p: &Path| {
+            let key = p.as_os_str().as_bytes();
+            listings
+                .iter()
+                .find(|(k, _)| k.as_slice() == Some(key))
+                .map(|(_, v)| v)
+                .unwrap()
+        };
+        // a and b are listed at the same time, so either may be cut short
+        let (a_len, b_len) = (
+            listing(&a).as_array().unwrap().len(),
+            listing(&b).as_array().unwrap().len(),
+        );
+        assert_eq!(a_len + b_len, 4);
+        let error = map_get(listing(&missing), "error").unwrap();
+        assert_eq!(
+            map_get(error, "code").and_then(Value::as_i64),
+            Some(RpcError::FILE_NOT_FOUND as i64)
+        );
+
+        let truncated = map_get(&result, "truncated").unwrap().as_array().unwrap();
+        let short: Vec<Value> = [(&a, a_len), (&b, b_len)]
+            .into_iter()
+            .filter(|(_, len)| *len < 3)
+            .map(|(p, _)| Value::Binary(p.as_os_str().as_bytes().to_vec()))
+            .collect();
+        assert_eq!(truncated, &short);
+    }
+
+    #[tokio::test]
+    async fn list_multi_stops_reading_once_the_budget_is_spent() {
+        let tmp = tempfile::tempdir().unwrap();
+        let big = tmp.path().join("big");
+        let small = tmp.path().join("small");
+        std::fs::create_dir(&big).unwrap();
+        std::fs::create_dir(&small).unwrap();
+        for i in 0..200 {
+            std::fs::write(big.join(format!("{i}.txt")), b"").unwrap();
+        }
+        for name in ["a.rs", "b.rs", "c.txt", ".d.rs"] {
+            std::fs::write(small.join(name), b"").unwrap();
+        }
+        let path_value = |p: &Path| Value::String(p.to_string_lossy().into_owned().into());
+        let names = |listing: &Value| -> Vec<Vec<u8>> {
+            listing
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|e| map_get(e, "name").unwrap().as_slice().unwrap().to_vec())
+                .collect()
+        };
+
+        let result = list_multi(msgpack_map! {
+            "paths" => Value::Array(vec![path_value(&big)]),
+            "max_entries" => 10
+        })
+        .await
+        .unwrap();
+        let listings = map_get(&result, "listings").unwrap().as_map().unwrap();
+        assert_eq!(names(&listings[0].1).len(), 10);
+        assert_eq!(
+            map_get(&result, "truncated")
+                .unwrap()
+                .as_array()
+                .unwrap()
+                .len(),
+            1
+        );
+
+        // Filters pick names before they count against the budget
+        let result = list_multi(msgpack_map! {
+            "paths" => Value::Array(vec![path_value(&small)]),
+            "filters" => Value::Array(vec!["*.rs".into()]),
+            "max_entries" => 3
+        })
+        .await
+        .unwrap();
+        let listings = map_get(&result, "listings").unwrap().as_map().unwrap();
+        assert_eq!(
+            names(&listings[0].1),
+            vec![b".d.rs".to_vec(), b"a.rs".to_vec(), b"b.rs".to_vec()]
+        );
+        assert!(
+            map_get(&result, "truncated")
+                .unwrap()
+                .as_array()
+                .unwrap()
+                .is_empty()
+        );
+
+        let too_many = vec![path_value(&small); LIST_MULTI_MAX_PATHS + 1];
+        let err = list_multi(msgpack_map! { "paths" => Value::Array(too_many) })
+            .await
+            .unwrap_err();
+        assert_eq!(err.code, RpcError::INVALID_PARAMS);
+    }
+
+    #[tokio::test]
+    async fn list_selects_fields_and_fingerprints() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        std::fs::write(dir.join("a"), b"abc").unwrap();
+        std::fs::create_dir(dir.join("sub")).unwrap();
+        let path = Value::String(dir.to_string_lossy().into_owned().into());
+
+        let entries = list(msgpack_map! {
+            "path" => path.clone(),
+            "include_hidden" => false,
+            "fields" => Value::Array(vec!["mtime".into(), "size".into()])
+        })
+        .await
+        .unwrap();
+        let entries = entries.as_array().unwrap();
+        assert_eq!(entries.len(), 2);
+        let keys: Vec<_> = entries[0]
+            .as_map()
+            .unwrap()
+            .iter()
+            .map(|(k, _)| k.as_str().unwrap())
+            .collect();
+        assert_eq!(keys, ["name", "mtime", "size"]);
+        assert_eq!(entries[0]["name"], Value::Binary(b"a".to_vec()));
+        assert_eq!(entries[0]["size"].as_u64(), Some(3));
+
+        let fingerprint = || async {
+            list(msgpack_map! {
+                "path" => path.clone(),
+                "fingerprint_only" => true
+            })
+            .await
+            .unwrap()
+        };
+        let before = fingerprint().await;
+        assert_eq!(before["count"].as_u64(), Some(4));
+        assert_eq!(fingerprint().await, before);
+
+        std::fs::write(dir.join("a"), b"abcd").unwrap();
+        assert_ne!(fingerprint().await["fingerprint"], before["fingerprint"]);
+
+        let err = list(msgpack_map! {
+            "path" => path,
+            "fields" => Value::Array(vec!["colour".into()])
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(err.code, RpcError::INVALID_PARAMS);
+    }
+
+    #[tokio::test]
+    async fn list_limit_keeps_the_first_entries_by_name() {
+        let tmp = tempfile::tempdir().unwrap();
+        for name in ["c", "a", "d", "b"] {
+            std::fs::write(tmp.path().join(name), b"").unwrap();
+        }
+        let path = Value::String(tmp.path().to_string_lossy().into_owned().into());
+
+        // Without the handshake the listing stays a bare array
+        for fields in [Value::Nil, Value::Array(vec!["size".into()])] {
+            let entries = list(msgpack_map! {
+                "path" => path.clone(),
+                "include_hidden" => false,
+                "limit" => 2,
+                "fields" => fields
+            })
+            .await
+            .unwrap();
+            let names: Vec<_> = entries
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|entry| entry["name"].clone())
+                .collect();
+            assert_eq!(
+                names,
+                [Value::Binary(b"a".to_vec()), Value::Binary(b"b".to_vec())]
+            );
+        }
+
+        let mut entries = vec![b"b".to_vec(), b"a".to_vec(), b"c".to_vec()];
+        assert_eq!(apply_limit(&mut entries, Some(1), |name| name), 3);
+        let listing = Listing {
+            entries: entries.into_iter().map(Value::Binary).collect(),
+            total: Some(3),
+            truncation: Some(Truncation::Limit),
+        }
+        .into_value();
+        assert_eq!(
+            listing["entries"],
+            Value::Array(vec![Value::Binary(b"a".to_vec())])
+        );
+        assert_eq!(listing["total"].as_u64(), Some(3));
+        assert_eq!(listing["truncated"].as_bool(), Some(true));
+        assert_eq!(listing["reason"].as_str(), Some("limit"));
+    }
+
+    #[tokio::test]
+    async fn list_sends_utf8_names_as_strings_when_asked() {
+        use std::ffi::OsStr;
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        std::fs::write(dir.join("a"), b"").unwrap();
+        std::fs::write(dir.join(OsStr::from_bytes(b"\xff")), b"").unwrap();
+        let path = Value::String(dir.to_string_lossy().into_owned().into());
+
+        let entries = list(msgpack_map! {
+            "path" => path.clone(),
+            "include_hidden" => false,
+            "names_as" => "auto"
+        })
+        .await
+        .unwrap();
+        let mut names: Vec<_> = entries
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|e| e["name"].clone())
+            .collect();
+        names.sort_by_key(|n| n.is_str());
+        assert_eq!(
+            names,
+            [Value::Binary(b"\xff".to_vec()), Value::String("a".into())]
+        );
+
+        let err = list(msgpack_map! {
+            "path" => path,
+            "names_as" => "utf16"
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(err.code, RpcError::INVALID_PARAMS);
+    }
+
+    #[tokio::test]
+    async fn create_applies_mode_to_each_new_directory() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let base = tmp.path();
+        std::fs::set_permissions(base, std::fs::Permissions::from_mode(0o751)).unwrap();
+        let mode_of = |p: &Path| std::fs::metadata(p).unwrap().permissions().mode() & 0o7777;
+        let path_value = |p: &Path| Value::String(p.to_string_lossy().into_owned().into());
+
+        let leaf = base.join("a/b/c");
+        let created = create(msgpack_map! {
+            "path" => path_value(&leaf),
+            "parents" => true,
+            "mode" => 0o700,
+        })
+        .await
+        .unwrap();
+        assert_eq!(created, Value::Boolean(true));
+        for dir in ["a", "a/b", "a/b/c"] {
+            assert_eq!(mode_of(&base.join(dir)), 0o700, "{dir}");
+        }
+        assert_eq!(mode_of(base), 0o751);
+
+        // Existing directories are not chmodded again
+        let again = create(msgpack_map! {
+            "path" => path_value(&leaf),
+            "parents" => true,
+            "mode" => 0o755,
+        })
+        .await
+        .unwrap();
+        assert_eq!(again, Value::Boolean(false));
+        assert_eq!(mode_of(&leaf), 0o700);
+
+        // Bits the umask strips are restored afterwards
+        let open = base.join("open");
+        create(msgpack_map! { "path" => path_value(&open), "mode" => 0o777 })
+            .await
+            .unwrap();
+        assert_eq!(mode_of(&open), 0o777);
+
+        std::fs::write(base.join("file"), b"").unwrap();
+        let err = create(msgpack_map! {
+            "path" => path_value(&base.join("file")),
+            "parents" => true,
+        })
+        .await
+        .expect_err("a file is in the way");
+        assert!(err.message.contains("exists"));
+    }
+
+    #[tokio::test]
+    async fn create_parents_with_a_mode_that_is_not_writable() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let base = tmp.path();
+        let mode_of = |p: &Path| std::fs::metadata(p).unwrap().permissions().mode() & 0o7777;
+        let path_value = |p: &Path| Value::String(p.to_string_lossy().into_owned().into());
+
+        for mode in [0o555, 0o500] {
+            let top = base.join(format!("m{mode:o}"));
+            let created = create(msgpack_map! {
+                "path" => path_value(&top.join("b/c")),
+                "parents" => true,
+                "mode" => mode,
+            })
+            .await
+            .unwrap();
+            assert_eq!(created, Value::Boolean(true));
+            for dir in [top.clone(), top.join("b"), top.join("b/c")] {
+                assert_eq!(mode_of(&dir), mode, "{}", dir.display());
+            }
+        }
+
+        // A setgid bit inherited from the parent survives the chmod
+        let shared = base.join("shared");
+        std::fs::create_dir(&shared).unwrap();
+        std::fs::set_permissions(&shared, std::fs::Permissions::from_mode(0o2775)).unwrap();
+        if mode_of(&shared) == 0o2775 {
+            let sub = shared.join("sub");
+            create(msgpack_map! { "path" => path_value(&sub), "mode" => 0o777 })
+                .await
+                .unwrap();
+            assert_eq!(mode_of(&sub), 0o2777);
+        }
+
+        for dir in ["m555", "m555/b", "m500", "m500/b"] {
+            std::fs::set_permissions(base.join(dir), std::fs::Permissions::from_mode(0o700))
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn parallel_listing_matches_the_serial_one() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        for i in 0..PARALLEL_STAT_MIN_ENTRIES * 2 {
+            match i % 5 {
+                0 => std::fs::create_dir(dir.join(format!("d{i}"))).unwrap(),
+                1 => std::os::unix::fs::symlink(format!("f{}", i + 1), dir.join(format!("l{i}")))
+                    .unwrap(),
+                2 => std::os::unix::fs::symlink("gone", dir.join(format!(".l{i}"))).unwrap(),
+                _ => std::fs::write(dir.join(format!("f{i}")), vec![b'x'; i]).unwrap(),
+            }
+        }
+        let listing = |threads| {
+            let entries =
+                list_dir_sync(dir, true, true, true, false, ResolveSymlinks::Full, threads)
+                    .unwrap();
+            Value::Array(
+                entries
+                    .iter()
+                    .map(|e| e.to_value(NamesAs::Binary))
+                    .collect(),
+            )
+        };
+        // The first listing may update the atime of "."
+        listing(1);
+
+        let serial = listing(1);
+        assert_eq!(listing(8), serial);
+        assert_eq!(listing(0), serial);
+        let entries = serial.as_array().unwrap();
+        assert_eq!(entries.len(), PARALLEL_STAT_MIN_ENTRIES * 2 + 2);
+        let uname = super::super::file::get_user_name(unsafe { libc::getuid() });
+        assert!(uname.is_some());
+        for entry in entries {
+            assert_eq!(entry["attrs"]["uname"].as_str(), uname.as_deref());
+        }
+    }
+
+    #[tokio::test]
+    async fn completions_mark_symlinks_to_directories() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        std::fs::create_dir(dir.join("alt-dir")).unwrap();
+        std::fs::write(dir.join("alt-file"), b"").unwrap();
+        std::os::unix::fs::symlink("alt-dir", dir.join("alt-link")).unwrap();
+        std::os::unix::fs::symlink("alt-file", dir.join("alt-file-link")).unwrap();
+        std::os::unix::fs::symlink("gone", dir.join("alt-dangling")).unwrap();
+        std::fs::write(dir.join("other"), b"").unwrap();
+
+        let complete = |dirs_only: bool| {
+            completions(msgpack_map! {
+                "path" => dir.to_string_lossy().into_owned(),
+                "prefix" => "alt",
+                "dirs_only" => dirs_only,
+                "names_as" => "auto"
+            })
+        };
+        let candidates = |value: Value| -> Vec<(String, bool)> {
+            value
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|c| {
+                    let name = c["name"].as_str().unwrap().to_string();
+                    (name, c["is_dir_like"].as_bool().unwrap())
+                })
+                .collect()
+        };
+
+        let all = candidates(complete(false).await.unwrap());
+        let expected = [
+            ("alt-dangling", false),
+            ("alt-dir", true),
+            ("alt-file", false),
+            ("alt-file-link", false),
+            ("alt-link", true),
+        ];
+        assert_eq!(
+            all,
+            expected.map(|(name, dir_like)| (name.to_string(), dir_like))
+        );
+
+        let dirs = candidates(complete(true).await.unwrap());
+        assert_eq!(
+            dirs,
+            [
+                ("alt-dir".to_string(), true),
+                ("alt-link".to_string(), true)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn listing_attrs_agree_with_stat_on_birth_time_and_flags() {
+        let tmp = tempfile::tempdir().unwrap();
+        std::fs::write(tmp.path().join("file"), b"").unwrap();
+
+        let stat = super::super::file::get_file_attributes(&tmp.path().join("file"), true)
+            .await
+            .unwrap();
+        let entries = list_dir_sync(
+            tmp.path(),
+            true,
+            true,
+            true,
+            false,
+            ResolveSymlinks::None,
+            0,
+        )
+        .unwrap();
+        let entry = entries.iter().find(|e| e.name == b"file").unwrap();
+        let listed = entry.attrs.as_ref().unwrap();
+
+        assert_eq!(listed.btime, stat.btime);
+        assert_eq!(listed.btime_nsec, stat.btime_nsec);
+        assert_eq!(listed.flags, stat.flags);
+        if let Some(btime) = stat.btime {
+            // Created just now, so no later than its last status change
+            assert!(btime <= stat.ctime);
+            assert!(
+                stat.to_value()
+                    .as_map()
+                    .unwrap()
+                    .iter()
+                    .any(|(k, _)| k.as_str() == Some("btime"))
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn list_resolves_symlink_targets() {
+        let tmp = tempfile::tempdir().unwrap();
+        std::fs::create_dir(tmp.path().join("dir")).unwrap();
+        std::fs::write(tmp.path().join("file"), b"abc").unwrap();
+        std::os::unix::fs::symlink("dir", tmp.path().join("to-dir")).unwrap();
+        std::os::unix::fs::symlink("file", tmp.path().join("to-file")).unwrap();
+        std::os::unix::fs::symlink("gone", tmp.path().join("dangling")).unwrap();
+        let path = Value::String(tmp.path().to_string_lossy().into_owned().into());
+
+        let listing = |resolve: &str| {
+            list(msgpack_map! {
+                "path" => path.clone(),
+                "include_hidden" => false,
+                "resolve_symlinks" => resolve
+            })
+        };
+        let entry = |entries: &Value, name: &str| {
+            entries
+                .as_array()
+                .unwrap()
+                .iter()
+                .find(|e| e["name"].as_slice() == Some(name.as_bytes()))
+                .cloned()
+                .unwrap()
+        };
+
+        let entries = listing("type_only").await.unwrap();
+        assert_eq!(entry(&entries, "to-dir")["type"].as_str(), Some("symlink"));
+        assert_eq!(
+            entry(&entries, "to-dir")["target_type"].as_str(),
+            Some("directory")
+        );
+        assert_eq!(
+            entry(&entries, "to-file")["target_type"].as_str(),
+            Some("file")
+        );
+        assert_eq!(
+            entry(&entries, "dangling")["target_type"].as_str(),
+            Some("missing")
+        );
+        assert!(map_get(&entry(&entries, "file"), "target_type").is_none());
+        assert!(map_get(&entry(&entries, "to-file"), "target_attrs").is_none());
+
+        let entries = listing("full").await.unwrap();
+        assert_eq!(
+            entry(&entries, "to-file")["target_attrs"]["size"].as_u64(),
+            Some(3)
+        );
+        assert!(map_get(&entry(&entries, "dangling"), "target_attrs").is_none());
+
+        let entries = listing("none").await.unwrap();
+        assert!(map_get(&entry(&entries, "to-dir"), "target_type").is_none());
+
+        assert!(listing("deep").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn list_and_stat_agree_on_symlink_attributes() {
+        let tmp = tempfile::tempdir().unwrap();
+        std::fs::create_dir(tmp.path().join("dir")).unwrap();
+        std::os::unix::fs::symlink("dir", tmp.path().join("to-dir")).unwrap();
+        std::os::unix::fs::symlink("gone", tmp.path().join("dangling")).unwrap();
+        let path = Value::String(tmp.path().to_string_lossy().into_owned().into());
+        let link = Value::String(
+            tmp.path()
+                .join("to-dir")
+                .to_string_lossy()
+                .into_owned()
+                .into(),
+        );
+
+        let listing = |options: Value| {
+            let mut params = msgpack_map! {
+                "path" => path.clone(),
+                "include_attrs" => true,
+                "include_hidden" => false,
+                "no_cache" => true
+            };
+            if let (Value::Map(fields), Value::Map(options)) = (&mut params, options) {
+                fields.extend(options);
+            }
+            list(params)
+        };
+        let entry = |entries: &Value, name: &str| {
+            entries
+                .as_array()
+                .unwrap()
+                .iter()
+                .find(|e| e["name"].as_slice() == Some(name.as_bytes()))
+                .cloned()
+                .unwrap()
+        };
+
+        // lstat by default, like file.stat with lstat
+        let entries = listing(Value::Map(vec![])).await.unwrap();
+        let stat =
+            super::super::file::stat(msgpack_map! { "path" => link.clone(), "lstat" => true })
+                .await
+                .unwrap();
+        assert_eq!(
+            entry(&entries, "to-dir")["attrs"]["type"].as_str(),
+            Some("symlink")
+        );
+        assert_eq!(entry(&entries, "to-dir")["attrs"], stat);
+
+        // Followed, like a plain file.stat; dangling links keep their own
+        let entries = listing(msgpack_map! { "lstat" => false }).await.unwrap();
+        let stat = super::super::file::stat(msgpack_map! { "path" => link.clone() })
+            .await
+            .unwrap();
+        assert_eq!(entry(&entries, "to-dir")["type"].as_str(), Some("symlink"));
+        assert_eq!(
+            entry(&entries, "to-dir")["attrs"]["type"].as_str(),
+            Some("directory")
+        );
+        assert_eq!(entry(&entries, "to-dir")["attrs"], stat);
+        assert_eq!(
+            entry(&entries, "dangling")["attrs"]["type"].as_str(),
+            Some("symlink")
+        );
+
+        // Both at once
+        let entries = listing(msgpack_map! { "both" => true }).await.unwrap();
+        let stat =
+            super::super::file::stat(msgpack_map! { "path" => link.clone(), "both" => true })
+                .await
+                .unwrap();
+        let listed = entry(&entries, "to-dir");
+        assert_eq!(listed["attrs"]["type"].as_str(), Some("symlink"));
+        assert_eq!(listed["target_attrs"]["type"].as_str(), Some("directory"));
+        assert_eq!(stat["type"].as_str(), Some("symlink"));
+        assert_eq!(stat["target_attrs"], listed["target_attrs"]);
+        assert_eq!(
+            entry(&entries, "dangling")["attrs"]["type"].as_str(),
+            Some("symlink")
+        );
+
+        let dangling = Value::String(
+            tmp.path()
+                .join("dangling")
+                .to_string_lossy()
+                .into_owned()
+                .into(),
+        );
+        let batch = super::super::file::stat_batch(msgpack_map! {
+            "paths" => Value::Array(vec![link, dangling]),
+            "both" => true
+        })
+        .await
+        .unwrap();
+        assert_eq!(
+            batch[0]["result"]["target_attrs"]["type"].as_str(),
+            Some("directory")
+        );
+        assert_eq!(batch[1]["result"]["type"].as_str(), Some("symlink"));
+        assert_eq!(batch[1]["result"]["target_attrs"], Value::Nil);
+    }
+
+    #[tokio::test]
+    async fn expand_wildcards_matches_like_the_shell() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        for dir in ["src/a", "src/b/deep", "src/.hidden", "test"] {
+            std::fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        for file in [
+            "src/main.rs",
+            "src/a/lib.rs",
+            "src/b/deep/x.rs",
+            "src/.hidden/h.rs",
+            "src/.dot.rs",
+            "src/notes.txt",
+            "test/t1.rs",
+            "test/t2.rs",
+        ] {
+            std::fs::write(root.join(file), b"").unwrap();
+        }
+
+        let expand = |pattern: &str| {
+            expand_wildcards(msgpack_map! {
+                "pattern" => pattern,
+                "directory" => root.to_string_lossy().into_owned()
+            })
+        };
+        let relative = |result: Value| -> Vec<String> {
+            result["paths"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|p| {
+                    let path = Path::new(OsStr::from_bytes(p.as_slice().unwrap()));
+                    let mut rel = path
+                        .strip_prefix(root)
+                        .unwrap()
+                        .to_string_lossy()
+                        .into_owned();
+                    if p.as_slice().unwrap().ends_with(b"/") {
+                        rel.push('/');
+                    }
+                    rel
+                })
+                .collect()
+        };
+
+        assert_eq!(relative(expand("src/*.rs").await.unwrap()), ["src/main.rs"]);
+        assert_eq!(
+            relative(expand("src/.*.rs").await.unwrap()),
+            ["src/.dot.rs"]
+        );
+        assert_eq!(
+            relative(expand("src/**/*.rs").await.unwrap()),
+            ["src/a/lib.rs", "src/b/deep/x.rs", "src/main.rs"]
+        );
+        assert_eq!(
+            relative(expand("{src,test}/[mnt]*.{rs,txt}").await.unwrap()),
+            ["src/main.rs", "src/notes.txt", "test/t1.rs", "test/t2.rs"]
+        );
+        assert_eq!(
+            relative(expand("src/*/").await.unwrap()),
+            ["src/a/", "src/b/"]
+        );
+        assert!(relative(expand("nothing/*.c").await.unwrap()).is_empty());
+
+        let absolute = format!("{}/test/t?.rs", root.display());
+        let limited = expand_wildcards(msgpack_map! {
+            "pattern" => absolute,
+            "limit" => 1,
+            "attrs" => true
+        })
+        .await
+        .unwrap();
+        assert_eq!(limited["truncated"].as_bool(), Some(true));
+        assert_eq!(limited["paths"][0]["attrs"]["type"].as_str(), Some("file"));
+
+        assert!(
+            expand_wildcards(msgpack_map! { "pattern" => "*.rs" })
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn disk_usage_sums_children_once_per_inode() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        std::fs::create_dir_all(root.join("big/sub")).unwrap();
+        std::fs::create_dir(root.join("small")).unwrap();
+        std::fs::create_dir(root.join("target")).unwrap();
+        std::fs::write(root.join("big/sub/data"), vec![0u8; 10_000]).unwrap();
+        std::fs::write(root.join("small/a"), vec![0u8; 100]).unwrap();
+        std::fs::write(root.join("target/junk"), vec![0u8; 50_000]).unwrap();
+        std::fs::write(root.join("file"), vec![0u8; 9_000]).unwrap();
+        // A second link to big's data is counted once
+        std::fs::hard_link(root.join("big/sub/data"), root.join("small/link")).unwrap();
+
+        let usage = |extra: Vec<(&str, Value)>| {
+            let mut params = vec![(Value::from("path"), Value::from(root.to_str().unwrap()))];
+            params.extend(extra.into_iter().map(|(k, v)| (Value::from(k), v)));
+            disk_usage(Value::Map(params))
+        };
+        let names = |result: &Value| -> Vec<String> {
+            result["entries"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|e| String::from_utf8(e["name"].as_slice().unwrap().to_vec()).unwrap())
+                .collect()
+        };
+        let dir_size = |dir: &str| std::fs::symlink_metadata(root.join(dir)).unwrap().len();
+
+        let result = usage(vec![("exclude", Value::Array(vec!["target".into()]))])
+            .await
+            .unwrap();
+        // The shared inode is counted under the first child walked
+        assert_eq!(names(&result), ["big", "file", "small"]);
+        let big = &result["entries"][0];
+        let big_size = dir_size("big") + dir_size("big/sub") + 10_000;
+        assert_eq!(big["size"].as_u64(), Some(big_size));
+        assert_eq!(big["type"].as_str(), Some("directory"));
+        assert_eq!(big["incomplete"].as_bool(), Some(false));
+        assert!(big["blocks"].as_u64().unwrap() > 0);
+        let small_size = dir_size("small") + 100;
+        assert_eq!(result["entries"][2]["size"].as_u64(), Some(small_size));
+        assert_eq!(
+            result["total"]["size"].as_u64(),
+            Some(dir_size(".") + big_size + 9_000 + small_size)
+        );
+        assert_eq!(result["incomplete"].as_bool(), Some(false));
+
+        let by_name = usage(vec![("order", "name".into())]).await.unwrap();
+        assert_eq!(names(&by_name), ["big", "file", "small", "target"]);
+
+        let expired = usage(vec![("max_seconds", 0.into())]).await.unwrap();
+        assert_eq!(expired["incomplete"].as_bool(), Some(true));
+        let big = &expired["entries"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|e| e["name"].as_slice() == Some(b"big"))
+            .unwrap()
+            .clone();
+        assert_eq!(big["incomplete"].as_bool(), Some(true));
+        assert_eq!(big["size"].as_u64(), Some(dir_size("big")));
+
+        assert!(usage(vec![("order", "mtime".into())]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn recursive_removes_report_what_is_left() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().join("tree");
+        std::fs::create_dir_all(root.join("a/b")).unwrap();
+        std::fs::write(root.join("a/b/file"), b"").unwrap();
+        std::fs::write(root.join("top"), b"").unwrap();
+        std::os::unix::fs::symlink(tmp.path(), root.join("link")).unwrap();
+        let params = |ignore_errors: bool| {
+            msgpack_map! {
+                "path" => crate::protocol::path_value(&root),
+                "recursive" => true,
+                "ignore_errors" => ignore_errors
+            }
+        };
+
+        // Cancelled before the first entry, nothing is removed
+        let id = crate::protocol::RequestId::String("remove-test".into());
+        let err = crate::cancel::scope(&id, async {
+            crate::cancel::handle_cancel(msgpack_map! { "id" => "remove-test" }).unwrap();
+            remove(params(false)).await
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(err.code, RpcError::CANCELLED);
+        let data = err.data.unwrap();
+        assert_eq!(data["removed"].as_u64(), Some(0));
+        let remaining = data["remaining"].as_array().unwrap();
+        assert_eq!(remaining.len(), 6);
+        assert_eq!(remaining[0].as_slice(), Some(root.as_os_str().as_bytes()));
+
+        // Symlinks are removed, not followed
+        let result = remove(params(true)).await.unwrap();
+        assert_eq!(result["removed"].as_u64(), Some(6));
+        assert_eq!(result["error_count"].as_u64(), Some(0));
+        assert!(!root.exists());
+        assert!(tmp.path().exists());
+
+        let err = remove(params(false)).await.unwrap_err();
+        assert_eq!(err.code, RpcError::FILE_NOT_FOUND);
+        assert_eq!(
+            remove(params(true)).await.unwrap()["removed"].as_u64(),
+            Some(0)
+        );
+    }
+
+    #[test]
+    fn removal_holds_one_directory_open_at_a_time() {
+        // Deeper than the default limit of 1024 descriptors
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().join("tree");
+        let mut deepest = root.clone();
+        for _ in 0..1500 {
+            deepest.push("d");
+        }
+        std::fs::create_dir_all(&deepest).unwrap();
+        std::fs::write(deepest.join("file"), b"").unwrap();
+        std::fs::write(root.join("top"), b"").unwrap();
+
+        let token = crate::cancel::Token::default();
+        let outcome = remove_tree(&root, false, &token, &RemoveProgress::default());
+        assert_eq!(outcome.error_count, 0, "{:?}", outcome.errors);
+        assert_eq!(outcome.removed, 1503);
+        assert!(!root.exists());
+    }
+
+    #[test]
+    fn removal_failures_stop_the_walk_unless_ignored() {
+        use std::os::unix::fs::PermissionsExt;
+
+        if unsafe { libc::geteuid() } == 0 {
+            // Permissions do not stop root
+            return;
+        }
+        let tmp = tempfile::tempdir().unwrap();
+        let locked = tmp.path().join("locked");
+        std::fs::create_dir(&locked).unwrap();
+        std::fs::write(locked.join("kept"), b"").unwrap();
+        std::fs::write(tmp.path().join("gone"), b"").unwrap();
+        std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o500)).unwrap();
+
+        let token = crate::cancel::Token::default();
+        let outcome = remove_tree(tmp.path(), false, &token, &RemoveProgress::default());
+        assert_eq!(outcome.error_count, 1);
+        assert!(locked.join("kept").exists());
+        assert!(tmp.path().exists());
+
+        let outcome = remove_tree(tmp.path(), true, &token, &RemoveProgress::default());
+        std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o700)).unwrap();
+        assert_eq!(outcome.error_count, 1);
+        assert_eq!(outcome.errors[0].0, locked.join("kept"));
+        assert!(!tmp.path().join("gone").exists());
+        assert!(locked.join("kept").exists());
+    }
+
+    #[tokio::test]
+    async fn audit_perms_reports_matching_entries() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        let mode = |path: &Path, mode: u32| {
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)).unwrap()
+        };
+        mode(root, 0o755);
+        std::fs::create_dir_all(root.join("a/deep")).unwrap();
+        std::fs::create_dir_all(root.join("cache")).unwrap();
+        for (name, bits) in [
+            ("a/open", 0o666),
+            ("a/tool", 0o4755),
+            ("a/deep/also_open", 0o646),
+            ("cache/open", 0o666),
+            ("fine", 0o644),
+        ] {
+            std::fs::write(root.join(name), b"").unwrap();
+            mode(&root.join(name), bits);
+        }
+        std::os::unix::fs::symlink("fine", root.join("link")).unwrap();
+        let audit = |extra: Vec<(&str, Value)>| {
+            let mut pairs = vec![("path".into(), root.to_string_lossy().as_ref().into())];
+            pairs.extend(extra.into_iter().map(|(k, v)| (k.into(), v)));
+            audit_perms(Value::Map(pairs))
+        };
+        let paths = |result: &Value| -> Vec<String> {
+            result["entries"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|entry| {
+                    let path = Path::new(OsStr::from_bytes(entry["path"].as_slice().unwrap()));
+                    path.strip_prefix(root)
+                        .unwrap()
+                        .to_string_lossy()
+                        .into_owned()
+                })
+                .collect()
+        };
+
+        // World-writable or setuid, in walk order; the symlink's 0777 is ignored
+        let result = audit(vec![
+            ("mode_set", 0o002.into()),
+            ("setuid", true.into()),
+            ("exclude", Value::Array(vec!["cache".into()])),
+        ])
+        .await
+        .unwrap();
+        assert_eq!(paths(&result), ["a/open", "a/tool", "a/deep/also_open"]);
+        assert_eq!(result["truncated"].as_bool(), Some(false));
+        let tool = &result["entries"][1];
+        assert_eq!(tool["mode"].as_u64(), Some(0o4755));
+        assert_eq!(tool["matched"], Value::Array(vec!["setuid".into()]));
+
+        // Depth 2 reaches a/open but not a/deep/also_open
+        let shallow = audit(vec![("mode_set", 0o002.into()), ("max_depth", 2.into())])
+            .await
+            .unwrap();
+        assert_eq!(paths(&shallow), ["a/open", "cache/open"]);
+        let capped = audit(vec![("mode_set", 0o002.into()), ("max_results", 1.into())])
+            .await
+            .unwrap();
+        assert_eq!(paths(&capped).len(), 1);
+        assert_eq!(capped["reason"].as_str(), Some("limit"));
+
+        // Everything here is ours, so an owner check against our name finds
+        // nothing and one against another uid finds every entry
+        let uid = unsafe { libc::getuid() };
+        let name = super::super::file::get_user_name(uid).unwrap();
+        let mine = audit(vec![("owner", name.as_str().into())]).await.unwrap();
+        assert!(paths(&mine).is_empty());
+        assert_eq!(mine["scanned"].as_u64(), Some(10));
+        let theirs = audit(vec![("owner", (uid + 1).into())]).await.unwrap();
+        assert_eq!(paths(&theirs).len(), 10);
+        assert_eq!(
+            theirs["entries"][0]["matched"],
+            Value::Array(vec!["owner".into()])
+        );
+
+        for bad in [
+            vec![],
+            vec![("mode_set", 2.into()), ("max_seconds", (-1.0).into())],
+        ] {
+            let err = audit(bad).await.unwrap_err();
+            assert_eq!(err.code, RpcError::INVALID_PARAMS);
+        }
+    }
 }
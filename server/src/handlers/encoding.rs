@@ -0,0 +1,358 @@
+//! Character set conversion for TRAMP-RPC
+//!
+//! This module provides:
+//! - `file.convert_encoding`: Convert a file (or inline content) between
+//!   character encodings, or guess which encoding it is in
+//!
+//! Encodings are named by their WHATWG labels ("shift_jis", "latin1",
+//! "utf-16le", ...), as handled by `encoding_rs`.
+
+use crate::jail;
+use crate::msgpack_map;
+use crate::protocol::{IntoValue, PathBytes, RpcError, from_value};
+use crate::stat_cache;
+use encoding_rs::{DecoderResult, EncoderResult, Encoding, UTF_8, UTF_16BE, UTF_16LE};
+use rmpv::Value;
+use serde::Deserialize;
+use std::os::unix::ffi::OsStrExt;
+
+use super::HandlerResult;
+use super::file::{bytes_to_path, map_io_error};
+use super::io::max_read_size;
+
+/// How to handle input that cannot be decoded or encoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OnError {
+    /// Substitute U+FFFD when decoding and `?` when encoding
+    Replace,
+    /// Fail with [`RpcError::ENCODING_ERROR`]
+    Fail,
+}
+
+impl OnError {
+    fn parse(value: Option<&str>) -> Result<Self, RpcError> {
+        match value {
+            Some("replace") => Ok(OnError::Replace),
+            Some("fail") | None => Ok(OnError::Fail),
+            Some(other) => Err(RpcError::invalid_params(format!(
+                "on_error must be \"replace\" or \"fail\", not {:?}",
+                other
+            ))),
+        }
+    }
+}
+
+fn lookup(label: &str) -> Result<&'static Encoding, RpcError> {
+    Encoding::for_label(label.trim().as_bytes())
+        .ok_or_else(|| RpcError::invalid_params(format!("Unknown encoding: {}", label)))
+}
+
+/// First conversion failure, and how many were replaced in total.
+#[derive(Default)]
+struct Failures {
+    first: Option<(&'static str, usize)>,
+    count: usize,
+}
+
+impl Failures {
+    fn record(&mut self, stage: &'static str, offset: usize) {
+        self.first.get_or_insert((stage, offset));
+        self.count += 1;
+    }
+}
+
+/// Decode `input`, dropping a BOM for `encoding`.  Malformed sequences are
+/// recorded at their byte offset in `input` and replaced with U+FFFD.
+fn decode(encoding: &'static Encoding, input: &[u8], failures: &mut Failures) -> String {
+    let mut decoder = encoding.new_decoder_with_bom_removal();
+    let mut output = String::with_capacity(
+        decoder
+            .max_utf8_buffer_length_without_replacement(input.len())
+            .unwrap_or(input.len()),
+    );
+    let mut consumed = 0;
+    loop {
+        let (result, read) =
+            decoder.decode_to_string_without_replacement(&input[consumed..], &mut output, true);
+        consumed += read;
+        match result {
+            DecoderResult::InputEmpty => return output,
+            DecoderResult::OutputFull => output.reserve(input.len() - consumed + 16),
+            DecoderResult::Malformed(bad, after) => {
+                failures.record("decode", consumed - after as usize - bad as usize);
+                output.push('\u{FFFD}');
+            }
+        }
+    }
+}
+
+/// Encode `text`.  Unmappable characters are recorded at their character
+/// offset in `text` and replaced with `?`.
+fn encode(encoding: &'static Encoding, text: &str, failures: &mut Failures) -> Vec<u8> {
+    // encoding_rs only decodes UTF-16; its encoders for it produce UTF-8.
+    if encoding == UTF_16LE || encoding == UTF_16BE {
+        let units = text.encode_utf16();
+        return if encoding == UTF_16LE {
+            units.flat_map(u16::to_le_bytes).collect()
+        } else {
+            units.flat_map(u16::to_be_bytes).collect()
+        };
+    }
+
+    let mut encoder = encoding.new_encoder();
+    let mut output = Vec::with_capacity(text.len());
+    let mut consumed = 0;
+    loop {
+        let (result, read) = encoder.encode_from_utf8_to_vec_without_replacement(
+            &text[consumed..],
+            &mut output,
+            true,
+        );
+        consumed += read;
+        match result {
+            EncoderResult::InputEmpty => return output,
+            EncoderResult::OutputFull => output.reserve(text.len() - consumed + 16),
+            EncoderResult::Unmappable(c) => {
+                let offset = text[..consumed - c.len_utf8()].chars().count();
+                failures.record("encode", offset);
+                output.push(b'?');
+            }
+        }
+    }
+}
+
+/// Best guess at the encoding of `input`, with a confidence between 0 and 1.
+///
+/// A BOM is trusted fully and valid UTF-8 with non-ASCII content nearly so.
+/// Otherwise the guess comes from `chardetng`, and its confidence grows
+/// with the amount of non-ASCII text the guess was based on; it is low if
+/// the input does not even decode cleanly in the guessed encoding.
+fn detect(input: &[u8]) -> (&'static Encoding, f64, bool) {
+    if let Some((encoding, _)) = Encoding::for_bom(input) {
+        return (encoding, 1.0, true);
+    }
+    let ascii = Encoding::ascii_valid_up_to(input) == input.len();
+    if ascii {
+        return (UTF_8, 1.0, false);
+    }
+    if std::str::from_utf8(input).is_ok() {
+        return (UTF_8, 0.99, false);
+    }
+
+    let mut detector = chardetng::EncodingDetector::new(chardetng::Iso2022JpDetection::Allow);
+    detector.feed(input, true);
+    let encoding = detector.guess(None, chardetng::Utf8Detection::Deny);
+    let non_ascii = input.iter().filter(|b| !b.is_ascii()).count();
+    let mut confidence = 0.5 + 0.4 * (non_ascii.min(256) as f64 / 256.0);
+    if encoding
+        .decode_without_bom_handling_and_without_replacement(input)
+        .is_none()
+    {
+        confidence = 0.1;
+    }
+    (encoding, (confidence * 100.0).round() / 100.0, false)
+}
+
+fn encoding_error(stage: &str, offset: usize) -> RpcError {
+    let unit = if stage == "decode" {
+        "byte"
+    } else {
+        "character"
+    };
+    let mut error =
+        RpcError::encoding_error(format!("Cannot {} at {} offset {}", stage, unit, offset));
+    error.data = Some(msgpack_map! {
+        "stage" => stage,
+        "offset" => offset
+    });
+    error
+}
+
+/// Convert between character encodings.
+///
+/// The input is the file at `path` or the inline `content`.  It is decoded
+/// from `from` (dropping a BOM) and encoded to `to` (default "utf-8"); the
+/// result is returned as `content`, or written to `destination`.  Failures are
+/// handled per `on_error`: "fail" (the default) fails with
+/// [`RpcError::ENCODING_ERROR`], whose data has the `stage` ("decode" or
+/// "encode") and `offset` (in bytes of the input for decoding, characters
+/// of the text for encoding); "replace" substitutes them and reports their
+/// number as `replaced` and the first as `first_error`.
+///
+/// With `detect` set nothing is converted; the result is the guessed
+/// `{encoding, confidence, bom}` of the input.
+pub async fn convert(params: Value) -> HandlerResult {
+    #[derive(Deserialize)]
+    struct Params {
+        #[serde(default)]
+        path: Option<PathBytes>,
+        #[serde(default, with = "serde_bytes")]
+        content: Option<Vec<u8>>,
+        #[serde(default)]
+        from: Option<String>,
+        #[serde(default)]
+        to: Option<String>,
+        #[serde(default)]
+        destination: Option<PathBytes>,
+        #[serde(default)]
+        detect: bool,
+        #[serde(default)]
+        on_error: Option<String>,
+    }
+
+    let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
+    let on_error = OnError::parse(params.on_error.as_deref())?;
+
+    let input = match (params.path, params.content) {
+        (Some(PathBytes(path)), None) => {
+            let path = bytes_to_path(&path);
+            jail::check(&path)?;
+            let path_str = path.to_string_lossy().into_owned();
+            let limit = max_read_size();
+            let meta = tokio::fs::metadata(&path)
+                .await
+                .map_err(|e| map_io_error(e, &path))?;
+            if meta.len() > limit {
+                return Err(RpcError::file_too_large(&path_str, Some(meta.len()), limit));
+            }
+            tokio::fs::read(&path)
+                .await
+                .map_err(|e| map_io_error(e, &path))?
+        }
+        (None, Some(content)) => content,
+        _ => {
+            return Err(RpcError::invalid_params(
+                "Exactly one of path and content is required",
+            ));
+        }
+    };
+
+    if params.detect {
+        let (encoding, confidence, bom) = detect(&input);
+        return Ok(msgpack_map! {
+            "encoding" => encoding.name(),
+            "confidence" => confidence,
+            "bom" => bom
+        });
+    }
+
+    let from = lookup(
+        params
+            .from
+            .as_deref()
+            .ok_or_else(|| RpcError::invalid_params("from is required unless detect is set"))?,
+    )?;
+    let to = lookup(params.to.as_deref().unwrap_or("utf-8"))?;
+
+    let mut failures = Failures::default();
+    let text = decode(from, &input, &mut failures);
+    let output = encode(to, &text, &mut failures);
+    if let (OnError::Fail, Some((stage, offset))) = (on_error, failures.first) {
+        return Err(encoding_error(stage, offset));
+    }
+    let first_error = failures.first.map(|(stage, offset)| {
+        msgpack_map! {
+            "stage" => stage,
+            "offset" => offset
+        }
+    });
+
+    let mut result = msgpack_map! {
+        "from" => from.name(),
+        "to" => to.name(),
+        "size" => output.len(),
+        "replaced" => failures.count,
+        "first_error" => first_error.into_value()
+    };
+    let entry = match params.destination {
+        Some(PathBytes(destination)) => {
+            let dest = bytes_to_path(&destination);
+            jail::check(&dest)?;
+            tokio::fs::write(&dest, &output)
+                .await
+                .map_err(|e| map_io_error(e, &dest))?;
+            stat_cache::invalidate(&dest).await;
+            ("path", Value::Binary(dest.as_os_str().as_bytes().to_vec()))
+        }
+        None => ("content", Value::Binary(output)),
+    };
+    if let Value::Map(entries) = &mut result {
+        entries.push((entry.0.into(), entry.1));
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn converts_latin1_and_reports_failures() {
+        let converted = convert(msgpack_map! {
+            "content" => Value::Binary(b"caf\xe9".to_vec()),
+            "from" => "latin1",
+        })
+        .await
+        .unwrap();
+        assert_eq!(converted["content"].as_slice(), Some("café".as_bytes()));
+
+        // windows-1252 has the euro sign but no CJK characters
+        let params = |on_error: &str| {
+            msgpack_map! {
+                "content" => Value::Binary("a€漢b".as_bytes().to_vec()),
+                "from" => "utf-8",
+                "to" => "windows-1252",
+                "on_error" => on_error,
+            }
+        };
+        let err = convert(params("fail")).await.unwrap_err();
+        assert_eq!(err.code, RpcError::ENCODING_ERROR);
+        assert_eq!(err.data.unwrap()["offset"].as_u64(), Some(2));
+        let replaced = convert(params("replace")).await.unwrap();
+        assert_eq!(replaced["content"].as_slice(), Some(&b"a\x80?b"[..]));
+        assert_eq!(replaced["replaced"].as_u64(), Some(1));
+
+        let err = convert(msgpack_map! {
+            "content" => Value::Binary(b"ok\xff".to_vec()),
+            "from" => "utf-8",
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(err.data.unwrap()["offset"].as_u64(), Some(2));
+
+        let utf16 = convert(msgpack_map! {
+            "content" => Value::Binary(b"hi".to_vec()),
+            "from" => "utf-8",
+            "to" => "utf-16be",
+        })
+        .await
+        .unwrap();
+        assert_eq!(utf16["content"].as_slice(), Some(&b"\0h\0i"[..]));
+    }
+
+    #[tokio::test]
+    async fn detects_encodings() {
+        let sjis = encode(
+            encoding_rs::SHIFT_JIS,
+            "日本語のテキストです。これはテストです。",
+            &mut Failures::default(),
+        );
+        let detected = convert(msgpack_map! {
+            "content" => Value::Binary(sjis),
+            "detect" => true,
+        })
+        .await
+        .unwrap();
+        assert_eq!(detected["encoding"].as_str(), Some("Shift_JIS"));
+        assert!(detected["confidence"].as_f64().unwrap() > 0.5);
+
+        let bom = convert(msgpack_map! {
+            "content" => Value::Binary(b"\xff\xfeh\0".to_vec()),
+            "detect" => true,
+        })
+        .await
+        .unwrap();
+        assert_eq!(bom["encoding"].as_str(), Some("UTF-16LE"));
+        assert_eq!(bom["bom"].as_bool(), Some(true));
+    }
+}
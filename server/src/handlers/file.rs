@@ -1,6 +1,11 @@
 //! File metadata operations
 
-use crate::protocol::{FileAttributes, FileType, RpcError, from_value};
+use crate::jail;
+use crate::msgpack_map;
+use crate::protocol::{
+    FileAttributes, FileType, IntoValue, Notification, PathBytes, RpcError, from_value, path_value,
+};
+use crate::stat_cache;
 use rmpv::Value;
 use serde::Deserialize;
 use std::collections::HashMap;
@@ -12,6 +17,13 @@ use tokio::fs;
 use super::HandlerResult;
 
 /// Get file attributes
+///
+/// Symlinks are followed unless `lstat` is set, the same flag `dir.list`
+/// takes (where it defaults to true instead).  A dangling symlink fails
+/// with BROKEN_SYMLINK, carrying the link's own attributes.  With `both`
+/// the attributes are the link's own, as with `lstat`, and a symlink also
+/// gets `target_attrs`: those of what it leads to, nil if that cannot be
+/// stat'ed.
 pub async fn stat(params: Value) -> HandlerResult {
     #[derive(Deserialize)]
     struct Params {
@@ -21,18 +33,613 @@ pub async fn stat(params: Value) -> HandlerResult {
         /// If true, don't follow symlinks
         #[serde(default)]
         lstat: bool,
+        /// Stat symlinks both ways
+        #[serde(default)]
+        both: bool,
+        /// Bypass the server-side stat cache
+        #[serde(default)]
+        no_cache: bool,
     }
 
     let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
 
-    let path = bytes_to_path(&params.path);
-    match get_file_attributes(path.as_path(), params.lstat).await {
-        Ok(attrs) => Ok(attrs.to_value()),
-        Err(e) if e.code == RpcError::FILE_NOT_FOUND => Ok(Value::Nil),
+    if params.both {
+        return stat_both(&params.path, params.no_cache).await;
+    }
+    stat_path(&params.path, params.lstat, params.no_cache).await
+}
+
+/// Stat one raw path as `file.stat` with `both` does.
+async fn stat_both(raw: &[u8], no_cache: bool) -> HandlerResult {
+    let attrs = stat_path(raw, true, no_cache).await?;
+    if attrs["type"].as_str() != Some(FileType::Symlink.as_str()) {
+        return Ok(attrs);
+    }
+    let target = stat_path(raw, false, no_cache).await.unwrap_or(Value::Nil);
+    Ok(with_attrs(attrs, vec![("target_attrs", target)]))
+}
+
+/// Stat one raw path as `file.stat` does.
+async fn stat_path(raw: &[u8], lstat: bool, no_cache: bool) -> HandlerResult {
+    let path = bytes_to_path(raw);
+    jail::check(&path)?;
+    match (!no_cache)
+        .then(|| stat_cache::lookup(&path, lstat))
+        .flatten()
+    {
+        Some(stat_cache::Lookup::Found(attrs)) => return Ok(attrs.to_value()),
+        Some(stat_cache::Lookup::Missing) => return Ok(Value::Nil),
+        None => {}
+    }
+    let generation = stat_cache::parent_generation(&path);
+    match get_file_attributes(path.as_path(), lstat).await {
+        Ok(attrs) => {
+            stat_cache::insert(&path, lstat, &attrs);
+            Ok(attrs.to_value())
+        }
+        Err(e) if e.code == RpcError::FILE_NOT_FOUND => {
+            if !lstat && let Ok(attrs) = get_file_attributes(path.as_path(), true).await {
+                // The path exists but is a dangling symlink
+                stat_cache::insert(&path, true, &attrs);
+                return Err(RpcError::broken_symlink(&path.to_string_lossy(), &attrs));
+            }
+            stat_cache::insert_missing(&path, lstat, generation);
+            Ok(Value::Nil)
+        }
         Err(e) => Err(e),
     }
 }
 
+/// Stats run concurrently by `file.stat_batch`.
+const STAT_BATCH_CONCURRENCY: usize = 64;
+
+/// Default number of results per `stat.results` notification.
+const STAT_BATCH_GROUP_SIZE: usize = 256;
+
+/// Errors reported in the final response of a streamed `file.stat_batch`.
+const STAT_BATCH_MAX_ERRORS: usize = 16;
+
+/// A path of `file.stat_batch`, alone or with its own `lstat`
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StatBatchPath {
+    Path(PathBytes),
+    Entry {
+        #[serde(with = "path_or_bytes")]
+        path: Vec<u8>,
+        #[serde(default)]
+        lstat: Option<bool>,
+    },
+}
+
+/// Stat many paths: `{paths, lstat?, both?, no_cache?, stream?, group_size?,
+/// stream_id?}`.
+///
+/// `lstat` and `both` work as in `file.stat`.  Paths may be strings or
+/// binary, or `{path, lstat}` to stat that one path with its own `lstat`,
+/// which then also wins over `both`.  Each result is `{index, result}`,
+/// with `result` as from `file.stat` (nil for a missing path), or `{index,
+/// error: {code, message, os_errno, data?}}`, where `index` is the position
+/// of the path in `paths`.
+///
+/// Without `stream`, returns the results in order.  With `stream`, sends
+/// them in order as `stat.results` notifications of `{stream_id, results}`
+/// with up to `group_size` results each, and returns `{count, error_count,
+/// errors}` once all are sent, where `errors` holds the first few failed
+/// results.  At most 64 stats are in flight either way.
+pub async fn stat_batch(params: Value) -> HandlerResult {
+    use futures::StreamExt;
+
+    #[derive(Deserialize)]
+    struct Params {
+        paths: Vec<StatBatchPath>,
+        #[serde(default)]
+        lstat: bool,
+        #[serde(default)]
+        both: bool,
+        #[serde(default)]
+        no_cache: bool,
+        #[serde(default)]
+        stream: bool,
+        #[serde(default)]
+        group_size: Option<usize>,
+        #[serde(default)]
+        stream_id: Option<Value>,
+    }
+
+    let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
+    let group_size = params.group_size.unwrap_or(STAT_BATCH_GROUP_SIZE);
+    if group_size == 0 {
+        return Err(RpcError::invalid_params("group_size must be positive"));
+    }
+    let (lstat, both, no_cache) = (params.lstat, params.both, params.no_cache);
+    let stream_id = params.stream_id.unwrap_or(Value::Nil);
+
+    let mut results = futures::stream::iter(params.paths.into_iter().enumerate())
+        .map(|(index, entry)| async move {
+            let result = match entry {
+                StatBatchPath::Entry {
+                    path,
+                    lstat: Some(lstat),
+                } => stat_path(&path, lstat, no_cache).await,
+                StatBatchPath::Path(PathBytes(path)) | StatBatchPath::Entry { path, .. }
+                    if both =>
+                {
+                    stat_both(&path, no_cache).await
+                }
+                StatBatchPath::Path(PathBytes(path)) | StatBatchPath::Entry { path, .. } => {
+                    stat_path(&path, lstat, no_cache).await
+                }
+            };
+            (index, result)
+        })
+        .buffered(STAT_BATCH_CONCURRENCY);
+
+    if !params.stream {
+        let mut values = Vec::new();
+        while let Some((index, result)) = results.next().await {
+            values.push(stat_result_value(index, result));
+        }
+        return Ok(Value::Array(values));
+    }
+
+    let context = crate::trace::context(crate::trace::current(), "stream", stream_id.clone());
+    let mut count = 0u64;
+    let mut error_count = 0u64;
+    let mut errors = Vec::new();
+    let mut group = Vec::with_capacity(group_size);
+    loop {
+        let next = results.next().await;
+        let done = next.is_none();
+        if let Some((index, result)) = next {
+            count += 1;
+            let failed = result.is_err();
+            let value = stat_result_value(index, result);
+            if failed {
+                error_count += 1;
+                if errors.len() < STAT_BATCH_MAX_ERRORS {
+                    errors.push(value.clone());
+                }
+            }
+            group.push(value);
+        }
+        if group.len() == group_size || (done && !group.is_empty()) {
+            let params = msgpack_map! {
+                "stream_id" => stream_id.clone(),
+                "results" => Value::Array(std::mem::take(&mut group))
+            };
+            // Dropping results would leave the client waiting for them.
+            let notification = Notification::new("stat.results", params);
+            crate::send_notification_forced(notification.with_context(context.clone())).await;
+        }
+        if done {
+            break;
+        }
+    }
+    Ok(msgpack_map! {
+        "count" => count,
+        "error_count" => error_count,
+        "errors" => Value::Array(errors)
+    })
+}
+
+fn stat_result_value(index: usize, result: HandlerResult) -> Value {
+    match result {
+        Ok(result) => msgpack_map! { "index" => index as u64, "result" => result },
+        Err(e) => msgpack_map! { "index" => index as u64, "error" => error_value(e) },
+    }
+}
+
+/// An error inline in a batch result, as `{code, message, os_errno,
+/// data?}`.  `os_errno` is copied from the data, nil without one, so that
+/// clients can tell ENOENT from EACCES without looking further.
+pub(super) fn error_value(e: RpcError) -> Value {
+    let os_errno = e
+        .data
+        .as_ref()
+        .and_then(Value::as_map)
+        .and_then(|data| {
+            data.iter()
+                .find(|(key, _)| key.as_str() == Some("os_errno"))
+                .map(|(_, errno)| errno.clone())
+        })
+        .unwrap_or(Value::Nil);
+    let mut error = vec![
+        (Value::from("code"), Value::from(e.code)),
+        (Value::from("message"), Value::from(e.message)),
+        (Value::from("os_errno"), os_errno),
+    ];
+    if let Some(data) = e.data {
+        error.push((Value::from("data"), data));
+    }
+    Value::Map(error)
+}
+
+/// Checks `file.access_batch` makes unless told otherwise.
+const ACCESS_CHECKS: &[&str] = &["exists", "r", "w", "x"];
+
+/// Check access to many paths: `{paths, checks?}`.
+///
+/// `checks` lists any of "exists", "r", "w" and "x" (default all four),
+/// checked with the effective ids as `file-readable-p` and friends do.
+/// Returns one map per path, in order, with a boolean for each check, or
+/// `{error: {code, message, os_errno, data?}}` for a path whose parent
+/// cannot be reached or that lies outside the jail.  Missing paths fail
+/// every check.
+/// Paths may be strings or binary.  All checks run in one blocking task,
+/// relative to one directory fd when the paths share a parent.
+pub async fn access_batch(params: Value) -> HandlerResult {
+    #[derive(Deserialize)]
+    struct Params {
+        paths: Vec<PathBytes>,
+        #[serde(default)]
+        checks: Option<Vec<String>>,
+    }
+
+    let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
+    let checks = match params.checks {
+        Some(checks) => checks,
+        None => ACCESS_CHECKS.iter().map(|c| c.to_string()).collect(),
+    };
+    let modes = checks
+        .iter()
+        .map(|check| match check.as_str() {
+            "exists" => Ok(libc::F_OK),
+            "r" => Ok(libc::R_OK),
+            "w" => Ok(libc::W_OK),
+            "x" => Ok(libc::X_OK),
+            other => Err(RpcError::invalid_params(format!(
+                "checks must be \"exists\", \"r\", \"w\" or \"x\", got \"{}\"",
+                other
+            ))),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let paths: Vec<_> = params
+        .paths
+        .iter()
+        .map(|PathBytes(raw)| {
+            let path = bytes_to_path(raw);
+            jail::check(&path).map(|()| path)
+        })
+        .collect();
+    let label = paths
+        .iter()
+        .flatten()
+        .next()
+        .map(|path| path.parent().unwrap_or(path).to_path_buf())
+        .unwrap_or_default();
+
+    let results = crate::blocking::run(label, move || {
+        let dir = shared_parent(&paths);
+        paths
+            .into_iter()
+            .map(|path| {
+                let path = path?;
+                let (dir_fd, name) = match &dir {
+                    Some(dir) => (
+                        std::os::unix::io::AsRawFd::as_raw_fd(dir),
+                        path.file_name().unwrap().as_bytes().to_vec(),
+                    ),
+                    None => (libc::AT_FDCWD, path.as_os_str().as_bytes().to_vec()),
+                };
+                let name = std::ffi::CString::new(name)
+                    .map_err(|_| RpcError::invalid_params("path contains a NUL byte"))?;
+                let access = |mode| unsafe {
+                    libc::faccessat(dir_fd, name.as_ptr(), mode, libc::AT_EACCESS) == 0
+                };
+                let exists = if access(libc::F_OK) {
+                    true
+                } else {
+                    let err = std::io::Error::last_os_error();
+                    if err.raw_os_error() != Some(libc::ENOENT) {
+                        return Err(map_io_error(err, &path));
+                    }
+                    false
+                };
+                Ok(checks
+                    .iter()
+                    .zip(&modes)
+                    .map(|(check, &mode)| {
+                        let passed = exists && (mode == libc::F_OK || access(mode));
+                        (Value::from(check.as_str()), Value::Boolean(passed))
+                    })
+                    .collect::<Vec<_>>())
+            })
+            .collect::<Vec<Result<_, RpcError>>>()
+    })
+    .await?;
+
+    Ok(Value::Array(
+        results
+            .into_iter()
+            .map(|result| match result {
+                Ok(fields) => Value::Map(fields),
+                Err(e) => msgpack_map! { "error" => error_value(e) },
+            })
+            .collect(),
+    ))
+}
+
+/// The parent directory shared by all of `paths`, opened, if they all
+/// name an entry in it and it can be opened.
+fn shared_parent(paths: &[Result<std::path::PathBuf, RpcError>]) -> Option<std::fs::File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::path::Component;
+
+    let mut parent = None;
+    for path in paths {
+        let path = path.as_ref().ok()?;
+        if !matches!(path.components().next_back(), Some(Component::Normal(_))) {
+            return None;
+        }
+        let this = path.parent()?;
+        match parent {
+            None => parent = Some(this),
+            Some(parent) if parent == this => {}
+            Some(_) => return None,
+        }
+    }
+    let parent = parent?;
+    let parent = if parent.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        parent
+    };
+    std::fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_DIRECTORY)
+        .open(parent)
+        .ok()
+}
+
+/// Tell missing paths from dangling symlinks.
+///
+/// Returns `{state, link_target}` where `state` is "absent", "present" or
+/// "broken-symlink" and `link_target` is set for symlinks.  Symlink loops
+/// count as broken.
+pub async fn exists_ex(params: Value) -> HandlerResult {
+    #[derive(Deserialize)]
+    struct Params {
+        #[serde(with = "path_or_bytes")]
+        path: Vec<u8>,
+    }
+
+    let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
+
+    let path = bytes_to_path(&params.path);
+    jail::check(&path)?;
+    let path_str = path.to_string_lossy().into_owned();
+
+    let (state, link_target) = crate::blocking::run(path.clone(), move || {
+        let unresolvable = |e: &std::io::Error| {
+            matches!(
+                e.raw_os_error(),
+                Some(libc::ENOENT | libc::ENOTDIR | libc::ELOOP)
+            )
+        };
+        let metadata = match std::fs::symlink_metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(e) if unresolvable(&e) => return Ok(("absent", None)),
+            Err(e) => return Err(e),
+        };
+        if !metadata.file_type().is_symlink() {
+            return Ok(("present", None));
+        }
+        let link_target = std::fs::read_link(&path)
+            .ok()
+            .map(|target| target.as_os_str().as_bytes().to_vec());
+        match std::fs::metadata(&path) {
+            Ok(_) => Ok(("present", link_target)),
+            Err(e) if unresolvable(&e) => Ok(("broken-symlink", link_target)),
+            Err(e) => Err(e),
+        }
+    })
+    .await?
+    .map_err(|e| map_io_error(e, &path_str))?;
+
+    let mut result = vec![(Value::String("state".into()), Value::String(state.into()))];
+    if let Some(target) = link_target {
+        result.push((Value::String("link_target".into()), Value::Binary(target)));
+    }
+    Ok(Value::Map(result))
+}
+
+/// Read the chattr-style flags of a file: `{available, immutable, append,
+/// nodump, hidden}`.
+///
+/// Uses `FS_IOC_GETFLAGS` on Linux and `st_flags` on macOS and FreeBSD,
+/// where both the user and system variants count.  `hidden` exists on the
+/// BSDs only.  Symlinks, devices and filesystems without flags give
+/// `{available: false}`.
+pub async fn get_flags(params: Value) -> HandlerResult {
+    #[derive(Deserialize)]
+    struct Params {
+        #[serde(with = "path_or_bytes")]
+        path: Vec<u8>,
+    }
+
+    let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
+
+    let path = bytes_to_path(&params.path);
+    jail::check(&path)?;
+    let path_str = path.to_string_lossy().into_owned();
+
+    let flags = crate::blocking::run(path.clone(), move || read_file_flags(&path))
+        .await?
+        .map_err(|e| map_io_error(e, &path_str))?;
+
+    Ok(match flags {
+        Some(flags) => flags.to_value(),
+        None => msgpack_map! { "available" => false },
+    })
+}
+
+/// Extents returned by `file.hole_map` unless `max_extents` says otherwise
+const DEFAULT_MAX_EXTENTS: usize = 4096;
+
+/// Map the data extents of a sparse file: `file.hole_map {path, offset?,
+/// max_extents?}`.
+///
+/// Returns `{size, blocks, allocated, extents, truncated, next_offset,
+/// supported}`.  `extents` are the `{offset, length}` ranges holding data
+/// from `offset` on, found with `SEEK_DATA`/`SEEK_HOLE`; the gaps between
+/// them read as zeros.  `blocks` counts 512-byte units and `allocated` is
+/// the bytes they take up.  At most `max_extents` are returned; `truncated`
+/// is then set and `next_offset` is where to continue.  Where holes cannot
+/// be found (`supported: false`) the whole file is one extent.
+pub async fn hole_map(params: Value) -> HandlerResult {
+    #[derive(Deserialize)]
+    struct Params {
+        #[serde(with = "path_or_bytes")]
+        path: Vec<u8>,
+        #[serde(default)]
+        offset: u64,
+        #[serde(default)]
+        max_extents: Option<usize>,
+    }
+
+    let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
+    let max_extents = params.max_extents.unwrap_or(DEFAULT_MAX_EXTENTS);
+    if max_extents == 0 {
+        return Err(RpcError::invalid_params("max_extents must be at least 1"));
+    }
+
+    let path = bytes_to_path(&params.path);
+    jail::check(&path)?;
+    let path_str = path.to_string_lossy().into_owned();
+
+    let map = crate::blocking::run(path.clone(), move || {
+        read_hole_map(&path, params.offset, max_extents)
+    })
+    .await?
+    .map_err(|e| map_io_error(e, &path_str))?;
+    Ok(map.to_value())
+}
+
+/// The data extents of a file, from [`read_hole_map`]
+struct HoleMap {
+    size: u64,
+    blocks: u64,
+    extents: Vec<(u64, u64)>,
+    next_offset: Option<u64>,
+    supported: bool,
+}
+
+impl HoleMap {
+    fn to_value(&self) -> Value {
+        msgpack_map! {
+            "size" => self.size,
+            "blocks" => self.blocks,
+            "allocated" => self.blocks.saturating_mul(512),
+            "extents" => Value::Array(
+                self.extents
+                    .iter()
+                    .map(|&(offset, length)| msgpack_map! {
+                        "offset" => offset,
+                        "length" => length
+                    })
+                    .collect()
+            ),
+            "truncated" => self.next_offset.is_some(),
+            "next_offset" => self.next_offset.into_value(),
+            "supported" => self.supported
+        }
+    }
+}
+
+fn read_hole_map(path: &Path, offset: u64, max_extents: usize) -> std::io::Result<HoleMap> {
+    let file = std::fs::File::open(path)?;
+    let metadata = file.metadata()?;
+    if metadata.is_dir() {
+        return Err(std::io::Error::from_raw_os_error(libc::EISDIR));
+    }
+    let size = metadata.len();
+    let mut map = HoleMap {
+        size,
+        blocks: metadata.blocks(),
+        extents: Vec::new(),
+        next_offset: None,
+        supported: true,
+    };
+    let mut pos = offset;
+    while pos < size {
+        let data = match seek(&file, pos, true)? {
+            Seek::Found(data) => data,
+            Seek::End => break,
+            Seek::Unsupported => {
+                map.supported = false;
+                map.extents = vec![(offset, size - offset)];
+                break;
+            }
+        };
+        let hole = match seek(&file, data, false)? {
+            Seek::Found(hole) => hole.min(size),
+            Seek::End | Seek::Unsupported => size,
+        };
+        if map.extents.len() == max_extents {
+            map.next_offset = Some(data);
+            break;
+        }
+        if hole > data {
+            map.extents.push((data, hole - data));
+        }
+        pos = hole.max(data + 1);
+    }
+    Ok(map)
+}
+
+/// Where [`seek`] landed
+enum Seek {
+    Found(u64),
+    /// ENXIO: no data, or no hole, after the position
+    End,
+    /// The filesystem or platform cannot tell
+    Unsupported,
+}
+
+/// `lseek(SEEK_DATA)`, or `SEEK_HOLE` when `!data`, from `pos`.
+fn seek(file: &std::fs::File, pos: u64, data: bool) -> std::io::Result<Seek> {
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "macos",
+        target_os = "freebsd"
+    ))]
+    {
+        use std::os::fd::AsRawFd;
+        let whence = if data {
+            libc::SEEK_DATA
+        } else {
+            libc::SEEK_HOLE
+        };
+        let Ok(pos) = libc::off_t::try_from(pos) else {
+            return Ok(Seek::End);
+        };
+        // SAFETY: the descriptor is open for as long as `file` lives
+        let found = unsafe { libc::lseek(file.as_raw_fd(), pos, whence) };
+        if found >= 0 {
+            return Ok(Seek::Found(found as u64));
+        }
+        let error = std::io::Error::last_os_error();
+        match error.raw_os_error() {
+            Some(libc::ENXIO) => Ok(Seek::End),
+            Some(libc::EINVAL) | Some(libc::ENOTSUP) => Ok(Seek::Unsupported),
+            _ => Err(error),
+        }
+    }
+    #[cfg(not(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "macos",
+        target_os = "freebsd"
+    )))]
+    {
+        let _ = (file, pos, data);
+        Ok(Seek::Unsupported)
+    }
+}
+
 /// Get the true name of a file (resolve symlinks)
 pub async fn truename(params: Value) -> HandlerResult {
     #[derive(Deserialize)]
@@ -44,12 +651,12 @@ pub async fn truename(params: Value) -> HandlerResult {
     let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
 
     let path = bytes_to_path(&params.path);
-    let path_str = path.to_string_lossy().into_owned();
+    jail::check(&path)?;
 
     // Use tokio's async canonicalize
     let canonical = fs::canonicalize(&path)
         .await
-        .map_err(|e| map_io_error(e, &path_str))?;
+        .map_err(|e| map_io_error(e, &path))?;
 
     // Return path as binary (MessagePack handles encoding)
     use std::os::unix::ffi::OsStrExt;
@@ -62,16 +669,22 @@ pub async fn truename(params: Value) -> HandlerResult {
 // ============================================================================
 
 pub async fn get_file_attributes(path: &Path, lstat: bool) -> Result<FileAttributes, RpcError> {
-    let metadata = if lstat {
-        fs::symlink_metadata(path).await
-    } else {
-        fs::metadata(path).await
+    let (metadata, (birth_time, flags)) = {
+        let path = path.to_path_buf();
+        crate::blocking::run(path.clone(), move || {
+            let metadata = if lstat {
+                std::fs::symlink_metadata(&path)
+            } else {
+                std::fs::metadata(&path)
+            }?;
+            let extras = extra_attributes(&path, &metadata, !lstat);
+            Ok::<_, std::io::Error>((metadata, extras))
+        })
+        .await?
     }
-    .map_err(|e| map_io_error(e, &path.to_string_lossy()))?;
-
-    let file_type = get_file_type(&metadata);
+    .map_err(|e| map_io_error(e, path))?;
 
-    let link_target = if file_type == FileType::Symlink {
+    let link_target = if metadata.file_type().is_symlink() {
         fs::read_link(path)
             .await
             .ok()
@@ -80,11 +693,57 @@ pub async fn get_file_attributes(path: &Path, lstat: bool) -> Result<FileAttribu
         None
     };
 
+    Ok(build_attributes(&metadata, birth_time, flags, link_target))
+}
+
+/// Attributes of the open `file`, from fstat, so they are those of the
+/// file written even if its path has been replaced since.
+pub(super) async fn open_file_attributes(file: &fs::File) -> std::io::Result<FileAttributes> {
+    let metadata = file.metadata().await?;
+    #[cfg(target_os = "linux")]
+    let (birth_time, flags) = {
+        let extras = statx_at(std::os::unix::io::AsRawFd::as_raw_fd(file), c"", true);
+        (extras.birth_time, extras.flags)
+    };
+    #[cfg(not(target_os = "linux"))]
+    let (birth_time, flags) = extra_attributes(Path::new(""), &metadata, true);
+    Ok(build_attributes(&metadata, birth_time, flags, None))
+}
+
+/// Attributes of `path` after a mutating operation, for `return_attrs`:
+/// nil if it cannot be stat'ed, as the operation itself succeeded.
+pub(super) async fn attrs_after(path: &Path, lstat: bool) -> Value {
+    get_file_attributes(path, lstat)
+        .await
+        .map_or(Value::Nil, |attrs| attrs.to_value())
+}
+
+/// `result` with the `attrs` fields added: a map result gets them as
+/// extra keys, anything else becomes `{result, ...}`.
+pub(super) fn with_attrs(result: Value, attrs: Vec<(&str, Value)>) -> Value {
+    let mut fields = match result {
+        Value::Map(fields) => fields,
+        result => vec![(Value::from("result"), result)],
+    };
+    fields.extend(
+        attrs
+            .into_iter()
+            .map(|(key, value)| (Value::from(key), value)),
+    );
+    Value::Map(fields)
+}
+
+fn build_attributes(
+    metadata: &std::fs::Metadata,
+    birth_time: Option<(i64, u32)>,
+    flags: Option<u32>,
+    link_target: Option<Vec<u8>>,
+) -> FileAttributes {
     let uid = metadata.uid();
     let gid = metadata.gid();
 
-    Ok(FileAttributes {
-        file_type,
+    FileAttributes {
+        file_type: get_file_type(metadata),
         nlinks: metadata.nlink(),
         uid,
         gid,
@@ -98,10 +757,267 @@ pub async fn get_file_attributes(path: &Path, lstat: bool) -> Result<FileAttribu
         inode: metadata.ino(),
         dev: metadata.dev(),
         link_target,
-    })
+        btime: birth_time.map(|(secs, _)| secs),
+        btime_nsec: birth_time.map(|(_, nsecs)| nsecs),
+        flags,
+    }
+}
+
+/// Creation time as (seconds, nanoseconds) and file flags, where the
+/// platform records them.
+#[cfg(target_os = "macos")]
+fn extra_attributes(
+    _path: &Path,
+    metadata: &std::fs::Metadata,
+    _follow_symlinks: bool,
+) -> (Option<(i64, u32)>, Option<u32>) {
+    use std::os::macos::fs::MetadataExt;
+    (
+        Some((metadata.st_birthtime(), metadata.st_birthtime_nsec() as u32)),
+        Some(metadata.st_flags()),
+    )
+}
+
+#[cfg(target_os = "freebsd")]
+fn extra_attributes(
+    _path: &Path,
+    metadata: &std::fs::Metadata,
+    _follow_symlinks: bool,
+) -> (Option<(i64, u32)>, Option<u32>) {
+    use std::os::freebsd::fs::MetadataExt;
+    // UFS reports -1 for files created before birth times were recorded
+    (
+        (metadata.st_birthtime() >= 0)
+            .then(|| (metadata.st_birthtime(), metadata.st_birthtime_nsec() as u32)),
+        Some(metadata.st_flags()),
+    )
+}
+
+/// On Linux both come from statx, the flags being the `FS_*_FL` bits of
+/// [`STATX_FLAGS`].
+#[cfg(target_os = "linux")]
+fn extra_attributes(
+    path: &Path,
+    _metadata: &std::fs::Metadata,
+    follow_symlinks: bool,
+) -> (Option<(i64, u32)>, Option<u32>) {
+    let Ok(path) = std::ffi::CString::new(path.as_os_str().as_bytes()) else {
+        return (None, None);
+    };
+    let extras = statx_at(libc::AT_FDCWD, &path, follow_symlinks);
+    (extras.birth_time, extras.flags)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "freebsd")))]
+fn extra_attributes(
+    _path: &Path,
+    _metadata: &std::fs::Metadata,
+    _follow_symlinks: bool,
+) -> (Option<(i64, u32)>, Option<u32>) {
+    (None, None)
+}
+
+/// What statx(2) adds to a stat: the creation time as (seconds,
+/// nanoseconds) and the chattr flags among [`STATX_FLAGS`], each `None`
+/// when the kernel or filesystem does not report it.
+#[cfg(target_os = "linux")]
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct StatxExtras {
+    pub birth_time: Option<(i64, u32)>,
+    pub flags: Option<u32>,
+}
+
+/// The `FS_*_FL` flags reported in `FileAttributes`: immutable, append-only
+/// and no-dump, whose `STATX_ATTR_*` bits have the same values.
+#[cfg(target_os = "linux")]
+const STATX_FLAGS: u64 = 0x10 | 0x20 | 0x40;
+
+/// Creation time and chattr flags of `name` relative to `dir_fd` via
+/// statx(2).
+///
+/// The syscall is issued directly because the musl versions the release
+/// binaries are built against do not wrap it.
+#[cfg(target_os = "linux")]
+pub(crate) fn statx_at(
+    dir_fd: libc::c_int,
+    name: &std::ffi::CStr,
+    follow_symlinks: bool,
+) -> StatxExtras {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    #[repr(C)]
+    struct StatxTimestamp {
+        tv_sec: i64,
+        tv_nsec: u32,
+        _pad: i32,
+    }
+
+    /// Leading part of `struct statx`, padded to the kernel's 256 bytes
+    #[repr(C)]
+    struct Statx {
+        stx_mask: u32,
+        _blksize: u32,
+        stx_attributes: u64,
+        _before_attributes_mask: [u32; 10],
+        stx_attributes_mask: u64,
+        _atime: StatxTimestamp,
+        stx_btime: StatxTimestamp,
+        _after_btime: [u64; 20],
+    }
+    const _: () = assert!(std::mem::size_of::<Statx>() == 256);
+
+    const STATX_BTIME: libc::c_uint = 0x800;
+
+    // Kernels before 4.11 lack statx; stop asking after the first ENOSYS.
+    static UNSUPPORTED: AtomicBool = AtomicBool::new(false);
+    if UNSUPPORTED.load(Ordering::Relaxed) {
+        return StatxExtras::default();
+    }
+
+    let mut flags = if follow_symlinks {
+        0
+    } else {
+        libc::AT_SYMLINK_NOFOLLOW
+    };
+    // An empty name means `dir_fd` itself
+    if name.is_empty() {
+        flags |= libc::AT_EMPTY_PATH;
+    }
+    let mut buf: Statx = unsafe { std::mem::zeroed() };
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_statx,
+            dir_fd,
+            name.as_ptr(),
+            flags,
+            STATX_BTIME,
+            &mut buf as *mut Statx,
+        )
+    };
+    if result != 0 {
+        if std::io::Error::last_os_error().raw_os_error() == Some(libc::ENOSYS) {
+            UNSUPPORTED.store(true, Ordering::Relaxed);
+        }
+        return StatxExtras::default();
+    }
+    StatxExtras {
+        birth_time: (buf.stx_mask & STATX_BTIME != 0)
+            .then_some((buf.stx_btime.tv_sec, buf.stx_btime.tv_nsec)),
+        // Only filesystems that support all three report them
+        flags: (buf.stx_attributes_mask & STATX_FLAGS == STATX_FLAGS)
+            .then_some((buf.stx_attributes & STATX_FLAGS) as u32),
+    }
 }
 
-fn get_file_type(metadata: &std::fs::Metadata) -> FileType {
+/// The chattr-style flags `file.get_flags` and `file.set_flags` handle
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub(crate) struct FileFlags {
+    pub immutable: bool,
+    pub append: bool,
+    pub nodump: bool,
+    pub hidden: bool,
+}
+
+impl FileFlags {
+    fn to_value(self) -> Value {
+        msgpack_map! {
+            "available" => true,
+            "immutable" => self.immutable,
+            "append" => self.append,
+            "nodump" => self.nodump,
+            "hidden" => self.hidden
+        }
+    }
+}
+
+/// `FS_IOC_GETFLAGS` and `FS_IOC_SETFLAGS`.  They are declared with a
+/// `long` argument, but the kernel reads and writes an `int`.
+#[cfg(target_os = "linux")]
+pub(crate) const FS_IOC_GETFLAGS: nix::sys::ioctl::ioctl_num_type =
+    nix::request_code_read!(b'f', 1, std::mem::size_of::<libc::c_long>());
+#[cfg(target_os = "linux")]
+pub(crate) const FS_IOC_SETFLAGS: nix::sys::ioctl::ioctl_num_type =
+    nix::request_code_write!(b'f', 2, std::mem::size_of::<libc::c_long>());
+
+/// `FS_IMMUTABLE_FL`, `FS_APPEND_FL` and `FS_NODUMP_FL`
+#[cfg(target_os = "linux")]
+pub(crate) const FS_IMMUTABLE_FL: libc::c_int = 0x10;
+#[cfg(target_os = "linux")]
+pub(crate) const FS_APPEND_FL: libc::c_int = 0x20;
+#[cfg(target_os = "linux")]
+pub(crate) const FS_NODUMP_FL: libc::c_int = 0x40;
+
+/// Open `path` for the flags ioctls, or `None` if it is not a regular file
+/// or directory, which the ioctls do not apply to.
+#[cfg(target_os = "linux")]
+pub(crate) fn open_for_flags(path: &Path) -> std::io::Result<Option<std::fs::File>> {
+    use std::os::unix::fs::OpenOptionsExt;
+    let file_type = std::fs::symlink_metadata(path)?.file_type();
+    if !(file_type.is_file() || file_type.is_dir()) {
+        return Ok(None);
+    }
+    std::fs::File::options()
+        .read(true)
+        .custom_flags(libc::O_NONBLOCK | libc::O_NOFOLLOW)
+        .open(path)
+        .map(Some)
+}
+
+/// Whether an ioctl error means the filesystem has no flags.
+#[cfg(target_os = "linux")]
+pub(crate) fn flags_unsupported(err: &std::io::Error) -> bool {
+    matches!(
+        err.raw_os_error(),
+        Some(libc::ENOTTY | libc::ENOTSUP | libc::EINVAL | libc::ENOSYS)
+    )
+}
+
+/// The flags of `path`, or `None` where it has none.
+#[cfg(target_os = "linux")]
+pub(crate) fn read_file_flags(path: &Path) -> std::io::Result<Option<FileFlags>> {
+    use std::os::fd::AsRawFd;
+    let Some(file) = open_for_flags(path)? else {
+        return Ok(None);
+    };
+    let mut flags: libc::c_int = 0;
+    if unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_GETFLAGS as _, &mut flags) } != 0 {
+        let err = std::io::Error::last_os_error();
+        return if flags_unsupported(&err) {
+            Ok(None)
+        } else {
+            Err(err)
+        };
+    }
+    Ok(Some(FileFlags {
+        immutable: flags & FS_IMMUTABLE_FL != 0,
+        append: flags & FS_APPEND_FL != 0,
+        nodump: flags & FS_NODUMP_FL != 0,
+        hidden: false,
+    }))
+}
+
+#[cfg(any(target_os = "macos", target_os = "freebsd"))]
+pub(crate) fn read_file_flags(path: &Path) -> std::io::Result<Option<FileFlags>> {
+    #[cfg(target_os = "freebsd")]
+    use std::os::freebsd::fs::MetadataExt;
+    #[cfg(target_os = "macos")]
+    use std::os::macos::fs::MetadataExt;
+    let flags = std::fs::symlink_metadata(path)?.st_flags() as libc::c_ulong;
+    let has = |bits: libc::c_ulong| flags & bits != 0;
+    Ok(Some(FileFlags {
+        immutable: has((libc::UF_IMMUTABLE | libc::SF_IMMUTABLE) as libc::c_ulong),
+        append: has((libc::UF_APPEND | libc::SF_APPEND) as libc::c_ulong),
+        nodump: has(libc::UF_NODUMP as libc::c_ulong),
+        hidden: has(libc::UF_HIDDEN as libc::c_ulong),
+    }))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "freebsd")))]
+pub(crate) fn read_file_flags(_path: &Path) -> std::io::Result<Option<FileFlags>> {
+    Ok(None)
+}
+
+pub(crate) fn get_file_type(metadata: &std::fs::Metadata) -> FileType {
     let ft = metadata.file_type();
 
     if ft.is_file() {
@@ -147,19 +1063,15 @@ const MAX_NSS_BUFSIZE: usize = 1024 * 1024;
 /// (potentially slow) NSS syscall, to avoid blocking other threads
 /// when the directory backend is slow.
 pub fn get_user_name(uid: u32) -> Option<String> {
-    // Fast path: check cache under lock, release immediately.
-    {
-        let cache = USER_NAMES.lock().unwrap_or_else(|e| e.into_inner());
-        if let Some(result) = cache.get(&uid) {
-            return Some(result.clone());
-        }
-    }
+    cached_name(&USER_NAMES, uid, lookup_user_name)
+}
 
-    // Slow path: perform the syscall without holding the lock.
+/// Look up the name of `uid` with getpwuid_r, bypassing the cache.
+fn lookup_user_name(uid: u32) -> Option<String> {
     let init_size = sysconf_bufsize(libc::_SC_GETPW_R_SIZE_MAX, 1024);
     let mut bufsize = init_size;
 
-    let name = loop {
+    loop {
         let mut buf = vec![0u8; bufsize];
         let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
         let mut result_ptr: *mut libc::passwd = std::ptr::null_mut();
@@ -185,15 +1097,7 @@ pub fn get_user_name(uid: u32) -> Option<String> {
 
         let cname = unsafe { std::ffi::CStr::from_ptr(pwd.pw_name) };
         break cname.to_str().ok().map(|s| s.to_string());
-    };
-
-    // Re-acquire lock to insert into cache.
-    if let Some(ref n) = name {
-        let mut cache = USER_NAMES.lock().unwrap_or_else(|e| e.into_inner());
-        cache.insert(uid, n.clone());
     }
-
-    name
 }
 
 static GROUP_NAMES: std::sync::LazyLock<Mutex<HashMap<u32, String>>> =
@@ -209,19 +1113,15 @@ static GROUP_NAMES: std::sync::LazyLock<Mutex<HashMap<u32, String>>> =
 /// The mutex is only held for cache lookups/inserts, not during the
 /// (potentially slow) NSS syscall.
 pub fn get_group_name(gid: u32) -> Option<String> {
-    // Fast path: check cache under lock, release immediately.
-    {
-        let cache = GROUP_NAMES.lock().unwrap_or_else(|e| e.into_inner());
-        if let Some(result) = cache.get(&gid) {
-            return Some(result.clone());
-        }
-    }
+    cached_name(&GROUP_NAMES, gid, lookup_group_name)
+}
 
-    // Slow path: perform the syscall without holding the lock.
+/// Look up the name of `gid` with getgrgid_r, bypassing the cache.
+fn lookup_group_name(gid: u32) -> Option<String> {
     let init_size = sysconf_bufsize(libc::_SC_GETGR_R_SIZE_MAX, 1024);
     let mut bufsize = init_size;
 
-    let name = loop {
+    loop {
         let mut buf = vec![0u8; bufsize];
         let mut grp: libc::group = unsafe { std::mem::zeroed() };
         let mut result_ptr: *mut libc::group = std::ptr::null_mut();
@@ -247,22 +1147,90 @@ pub fn get_group_name(gid: u32) -> Option<String> {
 
         let cname = unsafe { std::ffi::CStr::from_ptr(grp.gr_name) };
         break cname.to_str().ok().map(|s| s.to_string());
-    };
+    }
+}
+
+/// Name of `id` from `cache`, else from `lookup`, whose answer is cached.
+fn cached_name(
+    cache: &Mutex<HashMap<u32, String>>,
+    id: u32,
+    lookup: fn(u32) -> Option<String>,
+) -> Option<String> {
+    // Fast path: check cache under lock, release immediately.
+    if let Some(name) = cache.lock().unwrap_or_else(|e| e.into_inner()).get(&id) {
+        return Some(name.clone());
+    }
 
-    // Re-acquire lock to insert into cache.
-    if let Some(ref n) = name {
-        let mut cache = GROUP_NAMES.lock().unwrap_or_else(|e| e.into_inner());
-        cache.insert(gid, n.clone());
+    // Slow path: perform the syscall without holding the lock.
+    let name = lookup(id)?;
+    cache
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .insert(id, name.clone());
+    Some(name)
+}
+
+/// Names of all of `ids` that have one, taking the lock of `cache` once
+/// for the hits and once more for the misses.
+fn cached_names(
+    cache: &Mutex<HashMap<u32, String>>,
+    ids: impl IntoIterator<Item = u32>,
+    lookup: fn(u32) -> Option<String>,
+) -> HashMap<u32, String> {
+    let ids: std::collections::BTreeSet<u32> = ids.into_iter().collect();
+    let mut names = HashMap::new();
+    let missing: Vec<u32> = {
+        let cache = cache.lock().unwrap_or_else(|e| e.into_inner());
+        ids.into_iter()
+            .filter(|id| match cache.get(id) {
+                Some(name) => {
+                    names.insert(*id, name.clone());
+                    false
+                }
+                None => true,
+            })
+            .collect()
+    };
+    if missing.is_empty() {
+        return names;
     }
 
-    name
+    let found: Vec<(u32, String)> = missing
+        .into_iter()
+        .filter_map(|id| lookup(id).map(|name| (id, name)))
+        .collect();
+    cache
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .extend(found.iter().cloned());
+    names.extend(found);
+    names
 }
 
-pub fn map_io_error(err: std::io::Error, path: &str) -> RpcError {
+/// Fill in `uname` and `gname` of all of `attrs` from one lookup of their
+/// distinct owners, rather than one cache lookup per entry.
+pub fn fill_owner_names<'a>(attrs: impl IntoIterator<Item = &'a mut FileAttributes>) {
+    let mut attrs: Vec<_> = attrs.into_iter().collect();
+    if attrs.is_empty() {
+        return;
+    }
+    let users = cached_names(&USER_NAMES, attrs.iter().map(|a| a.uid), lookup_user_name);
+    let groups = cached_names(&GROUP_NAMES, attrs.iter().map(|a| a.gid), lookup_group_name);
+    for attrs in attrs.iter_mut() {
+        attrs.uname = users.get(&attrs.uid).cloned();
+        attrs.gname = groups.get(&attrs.gid).cloned();
+    }
+}
+
+/// Turn an I/O error on `path` into an RPC error.  The data always has the
+/// path as binary under `path`, next to `os_errno` when there is one.
+pub fn map_io_error(err: std::io::Error, path: impl AsRef<Path>) -> RpcError {
     use std::io::ErrorKind;
 
-    match err.kind() {
+    let path = path.as_ref();
+    let rpc_error = match err.kind() {
         ErrorKind::NotFound => RpcError::file_not_found(path),
+        ErrorKind::PermissionDenied if err.raw_os_error() == Some(libc::EPERM) => flags_error(path),
         ErrorKind::PermissionDenied => RpcError::permission_denied(path),
         ErrorKind::AlreadyExists => {
             let mut rpc_error = RpcError::io_error(err);
@@ -274,8 +1242,82 @@ pub fn map_io_error(err: std::io::Error, path: &str) -> RpcError {
             }
             rpc_error
         }
+        _ if matches!(err.raw_os_error(), Some(libc::EDQUOT | libc::ENOSPC)) => {
+            quota_error(err, path)
+        }
         _ => RpcError::io_error(err),
+    };
+    rpc_error.with_data("path", path_value(path))
+}
+
+/// Add the two paths of a copy, rename or link to an error on either, as
+/// binary under `src` and `dest`.
+pub fn with_src_dest(err: RpcError, src: &Path, dest: &Path) -> RpcError {
+    err.with_data("src", path_value(src))
+        .with_data("dest", path_value(dest))
+}
+
+/// An EPERM error, saying so when `path` or its directory is immutable or
+/// append-only, as writes and deletes then fail even for root.
+fn flags_error(path: &Path) -> RpcError {
+    let mut rpc_error = RpcError::permission_denied(path).with_data("os_errno", libc::EPERM.into());
+    let flagged = [Some(path), path.parent()]
+        .into_iter()
+        .flatten()
+        .filter(|p| !p.as_os_str().is_empty())
+        .find_map(|p| {
+            let flags = read_file_flags(p).ok().flatten()?;
+            let flag = if flags.immutable {
+                "immutable"
+            } else if flags.append {
+                "append"
+            } else {
+                return None;
+            };
+            Some((p, flag))
+        });
+    if let Some((flagged, flag)) = flagged {
+        let what = if flagged == path {
+            ""
+        } else {
+            "in a directory that is "
+        };
+        let flag_name = if flag == "immutable" {
+            "immutable"
+        } else {
+            "append-only"
+        };
+        rpc_error.message = format!(
+            "Operation not permitted: {} is {}{}",
+            path.display(),
+            what,
+            flag_name
+        );
+        rpc_error.data = Some(msgpack_map! {
+            "os_errno" => libc::EPERM,
+            "flag" => flag,
+            "flagged_path" => Value::Binary(flagged.as_os_str().as_bytes().to_vec())
+        });
+    }
+    rpc_error
+}
+
+/// An out-of-space error, pointing at the quota when one of ours is
+/// exceeded on the filesystem holding `path`.
+fn quota_error(err: std::io::Error, path: &Path) -> RpcError {
+    let mut rpc_error = RpcError::io_error(err);
+    if let Some(quota) = crate::quota::exceeded(path) {
+        rpc_error.message = format!(
+            "Disk quota exceeded ({} quota on {}): {}",
+            quota["kind"].as_str().unwrap_or_default(),
+            quota["mount"].as_str().unwrap_or_default(),
+            path.display()
+        );
+        if let Some(Value::Map(ref mut pairs)) = rpc_error.data {
+            pairs.push((Value::String("quota".into()), quota));
+        }
     }
+    rpc_error
 }
 
 use std::ffi::OsStr;
@@ -447,4 +1489,305 @@ mod tests {
             get_user_name(expected_uid).as_deref()
         );
     }
+
+    /// exists_ex tells dangling symlinks apart, and a follow-stat of one
+    /// carries the link's lstat attributes in the error.
+    #[tokio::test]
+    async fn test_broken_symlink_detail() {
+        let tmp = tempfile::tempdir().unwrap();
+        std::fs::write(tmp.path().join("file"), b"").unwrap();
+        std::os::unix::fs::symlink("file", tmp.path().join("good")).unwrap();
+        std::os::unix::fs::symlink("gone", tmp.path().join("broken")).unwrap();
+        let path =
+            |name: &str| Value::String(tmp.path().join(name).to_string_lossy().into_owned().into());
+        let state = |result: Value| result["state"].as_str().map(str::to_owned);
+
+        let params = |name: &str| Value::Map(vec![("path".into(), path(name))]);
+        let exists = |name: &str| exists_ex(params(name));
+        assert_eq!(
+            state(exists("file").await.unwrap()).as_deref(),
+            Some("present")
+        );
+        assert_eq!(
+            state(exists("missing").await.unwrap()).as_deref(),
+            Some("absent")
+        );
+        let good = exists("good").await.unwrap();
+        assert_eq!(state(good.clone()).as_deref(), Some("present"));
+        assert_eq!(good["link_target"].as_slice(), Some(&b"file"[..]));
+        let broken = exists("broken").await.unwrap();
+        assert_eq!(state(broken.clone()).as_deref(), Some("broken-symlink"));
+        assert_eq!(broken["link_target"].as_slice(), Some(&b"gone"[..]));
+
+        let err = stat(Value::Map(vec![
+            ("path".into(), path("broken")),
+            ("no_cache".into(), true.into()),
+        ]))
+        .await
+        .expect_err("dangling follow-stat");
+        assert_eq!(err.code, RpcError::FILE_NOT_FOUND);
+        let data = err.data.unwrap();
+        assert_eq!(data["lstat"]["type"].as_str(), Some("symlink"));
+        assert_eq!(data["lstat"]["link_target"].as_slice(), Some(&b"gone"[..]));
+
+        let missing = stat(Value::Map(vec![
+            ("path".into(), path("missing")),
+            ("no_cache".into(), true.into()),
+        ]))
+        .await
+        .unwrap();
+        assert!(missing.is_nil());
+    }
+
+    /// access_batch answers in input order, with or without a shared
+    /// parent, and reports unreachable parents inline.
+    #[tokio::test]
+    async fn test_access_batch_checks_in_order() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let script = tmp.path().join("script");
+        std::fs::write(&script, b"").unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+        std::fs::write(tmp.path().join("data"), b"").unwrap();
+        let mut binary = tmp.path().as_os_str().as_bytes().to_vec();
+        binary.extend_from_slice(b"/caf\xe9");
+        std::fs::write(std::ffi::OsStr::from_bytes(&binary), b"").unwrap();
+        let path = |name: &str| Value::from(tmp.path().join(name).to_string_lossy().as_ref());
+        let check = |result: &Value, key: &str| result[key].as_bool();
+
+        let batch = |paths: Vec<Value>| async move {
+            let results = access_batch(msgpack_map! { "paths" => Value::Array(paths) })
+                .await
+                .unwrap();
+            results.as_array().unwrap().clone()
+        };
+
+        // One parent, opened once
+        let results = batch(vec![
+            path("script"),
+            path("missing"),
+            Value::Binary(binary.clone()),
+        ])
+        .await;
+        assert_eq!(check(&results[0], "exists"), Some(true));
+        assert_eq!(check(&results[0], "x"), Some(true));
+        assert_eq!(check(&results[1], "exists"), Some(false));
+        assert_eq!(check(&results[1], "r"), Some(false));
+        assert_eq!(check(&results[2], "exists"), Some(true));
+
+        // Several parents, one of them a file
+        let results = batch(vec![path("script"), path("data/child"), path("missing")]).await;
+        assert_eq!(check(&results[0], "x"), Some(true));
+        assert_eq!(
+            results[1]["error"]["code"].as_i64(),
+            Some(i64::from(RpcError::IO_ERROR))
+        );
+        assert_eq!(check(&results[2], "exists"), Some(false));
+
+        let results = access_batch(msgpack_map! {
+            "paths" => Value::Array(vec![path("data"), path("missing")]),
+            "checks" => Value::Array(vec!["exists".into(), "x".into()])
+        })
+        .await
+        .unwrap();
+        assert_eq!(
+            results,
+            Value::Array(vec![
+                msgpack_map! { "exists" => true, "x" => false },
+                msgpack_map! { "exists" => false, "x" => false }
+            ])
+        );
+        let err = access_batch(msgpack_map! {
+            "paths" => Value::Array(vec![path("data")]),
+            "checks" => Value::Array(vec!["rw".into()])
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(err.code, RpcError::INVALID_PARAMS);
+    }
+
+    /// stat_batch keys results by index, in order, for string and binary
+    /// paths alike, and a stream reports counts and the first errors.
+    #[tokio::test]
+    async fn test_stat_batch_results_keyed_by_index() {
+        let tmp = tempfile::tempdir().unwrap();
+        std::fs::write(tmp.path().join("file"), b"abc").unwrap();
+        std::os::unix::fs::symlink("gone", tmp.path().join("broken")).unwrap();
+        let mut paths = Vec::new();
+        for i in 0..100 {
+            let name = if i % 10 == 0 { "broken" } else { "file" };
+            let path = tmp.path().join(name);
+            paths.push(if i % 2 == 0 {
+                Value::String(path.to_string_lossy().into_owned().into())
+            } else {
+                Value::Binary(path.as_os_str().as_bytes().to_vec())
+            });
+        }
+        paths.push(Value::String(
+            tmp.path()
+                .join("missing")
+                .to_string_lossy()
+                .into_owned()
+                .into(),
+        ));
+
+        let results = stat_batch(msgpack_map! {
+            "paths" => Value::Array(paths.clone()),
+            "no_cache" => true
+        })
+        .await
+        .unwrap();
+        let results = results.as_array().unwrap();
+        assert_eq!(results.len(), 101);
+        for (i, result) in results.iter().enumerate() {
+            assert_eq!(result["index"].as_u64(), Some(i as u64));
+        }
+        assert_eq!(results[1]["result"]["size"].as_u64(), Some(3));
+        assert_eq!(
+            results[10]["error"]["code"].as_i64(),
+            Some(RpcError::FILE_NOT_FOUND as i64)
+        );
+        assert!(results[100]["result"].is_nil());
+
+        let summary = stat_batch(msgpack_map! {
+            "paths" => Value::Array(paths),
+            "stream" => true,
+            "group_size" => 8
+        })
+        .await
+        .unwrap();
+        assert_eq!(summary["count"].as_u64(), Some(101));
+        assert_eq!(summary["error_count"].as_u64(), Some(10));
+        let errors = summary["errors"].as_array().unwrap();
+        assert_eq!(errors.len(), 10);
+        assert_eq!(errors[1]["index"].as_u64(), Some(10));
+
+        let err = stat_batch(msgpack_map! {
+            "paths" => Value::Array(vec![]),
+            "group_size" => 0
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(err.code, RpcError::INVALID_PARAMS);
+    }
+
+    #[tokio::test]
+    async fn test_stat_batch_entries_take_their_own_lstat() {
+        let tmp = tempfile::tempdir().unwrap();
+        std::fs::write(tmp.path().join("file"), b"abc").unwrap();
+        std::os::unix::fs::symlink("gone", tmp.path().join("broken")).unwrap();
+        let mut latin1 = tmp.path().as_os_str().as_bytes().to_vec();
+        latin1.extend_from_slice(b"/caf\xe9");
+        std::fs::write(OsStr::from_bytes(&latin1), b"12345").unwrap();
+        let path = |name: &str| Value::from(tmp.path().join(name).to_string_lossy().as_ref());
+
+        let results = stat_batch(msgpack_map! {
+            "paths" => Value::Array(vec![
+                path("broken"),
+                msgpack_map! { "path" => path("broken"), "lstat" => true },
+                msgpack_map! { "path" => Value::Binary(latin1.clone()) },
+                path("file/child"),
+                path("missing")
+            ]),
+            "no_cache" => true
+        })
+        .await
+        .unwrap();
+        let results = results.as_array().unwrap();
+        assert_eq!(
+            results[0]["error"]["code"].as_i64(),
+            Some(RpcError::FILE_NOT_FOUND as i64)
+        );
+        assert_eq!(results[1]["result"]["type"].as_str(), Some("symlink"));
+        assert_eq!(results[2]["result"]["size"].as_u64(), Some(5));
+        // A file in the way is an error, with its errno, not a missing path
+        let error = &results[3]["error"];
+        assert_eq!(error["os_errno"].as_i64(), Some(libc::ENOTDIR as i64));
+        assert!(error["message"].as_str().is_some());
+        assert!(results[4]["result"].is_nil());
+
+        // An entry's lstat wins over both
+        let results = stat_batch(msgpack_map! {
+            "paths" => Value::Array(vec![
+                path("broken"),
+                msgpack_map! { "path" => path("broken"), "lstat" => false }
+            ]),
+            "both" => true,
+            "no_cache" => true
+        })
+        .await
+        .unwrap();
+        assert_eq!(results[0]["result"]["type"].as_str(), Some("symlink"));
+        assert_eq!(results[0]["result"]["target_attrs"], Value::Nil);
+        assert_eq!(
+            results[1]["error"]["code"].as_i64(),
+            Some(RpcError::FILE_NOT_FOUND as i64)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_hole_map_lists_data_extents() {
+        use std::os::unix::fs::FileExt;
+        const MIB: u64 = 1024 * 1024;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("sparse");
+        let file = std::fs::File::create(&path).unwrap();
+        file.set_len(8 * MIB).unwrap();
+        file.write_all_at(&[1; 4096], 0).unwrap();
+        file.write_all_at(&[2; 4096], 4 * MIB).unwrap();
+        file.sync_all().unwrap();
+        let params = |extra: Vec<(&str, Value)>| {
+            let mut pairs = vec![("path".into(), path.to_string_lossy().as_ref().into())];
+            pairs.extend(extra.into_iter().map(|(k, v)| (k.into(), v)));
+            Value::Map(pairs)
+        };
+        let extents = |map: &Value| -> Vec<(u64, u64)> {
+            map["extents"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|e| (e["offset"].as_u64().unwrap(), e["length"].as_u64().unwrap()))
+                .collect()
+        };
+
+        let map = hole_map(params(vec![])).await.unwrap();
+        assert_eq!(map["size"].as_u64(), Some(8 * MIB));
+        assert_eq!(map["truncated"].as_bool(), Some(false));
+        let all = extents(&map);
+        // Every byte written lies in an extent, whatever the filesystem
+        for written in [0, 4 * MIB] {
+            assert!(
+                all.iter()
+                    .any(|&(offset, length)| offset <= written && written + 4096 <= offset + length)
+            );
+        }
+        if map["supported"].as_bool() == Some(true) && all.len() > 1 {
+            assert!(map["allocated"].as_u64().unwrap() < 8 * MIB);
+
+            let first = hole_map(params(vec![("max_extents", 1.into())]))
+                .await
+                .unwrap();
+            assert_eq!(extents(&first), all[..1]);
+            assert_eq!(first["truncated"].as_bool(), Some(true));
+            let next = first["next_offset"].as_u64().unwrap();
+            let rest = hole_map(params(vec![("offset", next.into())]))
+                .await
+                .unwrap();
+            assert_eq!(extents(&rest), all[1..]);
+        }
+
+        let err = hole_map(Value::Map(vec![(
+            "path".into(),
+            tmp.path().to_string_lossy().as_ref().into(),
+        )]))
+        .await
+        .unwrap_err();
+        assert_ne!(err.code, RpcError::INVALID_PARAMS);
+        let err = hole_map(params(vec![("max_extents", 0.into())]))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code, RpcError::INVALID_PARAMS);
+    }
 }
@@ -0,0 +1,383 @@
+//! Text search for TRAMP-RPC
+//!
+//! This module provides:
+//! - `grep.search`: Regex search below a root, skipping what ripgrep skips
+
+use crate::ignore_rules::IgnoreRules;
+use crate::jail;
+use crate::msgpack_map;
+use crate::protocol::{NamesAs, RpcError, from_value, path_or_bytes};
+use regex_automata::meta::Regex;
+use regex_automata::util::syntax;
+use rmpv::Value;
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::io::Read;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use super::HandlerResult;
+use super::file::{bytes_to_path, map_io_error};
+use super::project::build_globs;
+
+/// Default number of matches returned by `grep.search`.
+const DEFAULT_MATCH_LIMIT: usize = 10_000;
+
+/// Default depth of the walk.
+const DEFAULT_WALK_DEPTH: usize = 32;
+
+/// Bytes at the start of a file checked for a NUL to call it binary, as
+/// git and ripgrep do.
+const BINARY_PROBE_LEN: usize = 8192;
+
+/// Search the files below `root` for lines matching `pattern`.
+///
+/// By default the search skips what ripgrep does: files excluded by
+/// `.gitignore`/`.ignore` files (see [`IgnoreRules`]) and `.git` itself,
+/// hidden files, binary files (a NUL in their first 8 KB) and symlinks.
+/// `respect_gitignore: false` or `no_ignore: true` turn the ignore rules
+/// off, `skip_binary: false`, `include_hidden` and `follow_symlinks` the
+/// rest.  `include` limits the search to files matching gitignore-style
+/// globs, relative to `root`.
+///
+/// Returns `{matches, truncated, files_scanned, skipped_ignored,
+/// skipped_binary}`, where each match is `{path, line, column, text}` with
+/// `path` relative to `root`, 1-based `line` and `column` (in bytes), and
+/// the line's `text` without its newline.  An ignored directory counts as
+/// one skipped entry.  `names_as: "auto"` sends paths and text as strings
+/// when valid UTF-8.
+pub async fn search(params: Value) -> HandlerResult {
+    #[derive(Deserialize)]
+    struct Params {
+        /// Directory to search
+        #[serde(with = "path_or_bytes")]
+        root: Vec<u8>,
+        /// Regular expression, in Rust regex syntax
+        pattern: String,
+        #[serde(default)]
+        ignore_case: bool,
+        /// Skip files excluded by gitignore-style ignore files
+        #[serde(default = "default_true")]
+        respect_gitignore: bool,
+        /// Search ignored files whatever `respect_gitignore` says
+        #[serde(default)]
+        no_ignore: bool,
+        /// Skip files with a NUL in their first 8 KB
+        #[serde(default = "default_true")]
+        skip_binary: bool,
+        /// Search files and directories starting with "."
+        #[serde(default)]
+        include_hidden: bool,
+        /// Search the targets of symlinks
+        #[serde(default)]
+        follow_symlinks: bool,
+        /// Only search files matching one of these globs
+        #[serde(default)]
+        include: Vec<String>,
+        #[serde(default = "default_max_depth")]
+        max_depth: usize,
+        /// Maximum number of matches to return
+        #[serde(default = "default_limit")]
+        limit: usize,
+        /// "binary" (default) or "auto"
+        #[serde(default)]
+        names_as: Option<String>,
+    }
+
+    fn default_true() -> bool {
+        true
+    }
+
+    fn default_max_depth() -> usize {
+        DEFAULT_WALK_DEPTH
+    }
+
+    fn default_limit() -> usize {
+        DEFAULT_MATCH_LIMIT
+    }
+
+    let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
+
+    let names = NamesAs::parse(params.names_as.as_deref())?;
+    let regex = Regex::builder()
+        .syntax(syntax::Config::new().case_insensitive(params.ignore_case))
+        .build(&params.pattern)
+        .map_err(|e| RpcError::invalid_params(format!("Invalid pattern: {}", e)))?;
+    let root = bytes_to_path(&params.root).to_path_buf();
+    jail::check(&root)?;
+    let root_str = root.to_string_lossy().into_owned();
+
+    crate::blocking::run(root.clone(), move || {
+        if !root.is_dir() {
+            return Err(RpcError::file_not_found(&root_str));
+        }
+        let include = build_globs(&root, &params.include)?;
+        let options = WalkOptions {
+            rules: (params.respect_gitignore && !params.no_ignore).then(|| IgnoreRules::new(&root)),
+            include_hidden: params.include_hidden,
+            follow_symlinks: params.follow_symlinks,
+            max_depth: params.max_depth,
+        };
+        let mut search = Search {
+            regex,
+            skip_binary: params.skip_binary,
+            limit: params.limit,
+            names,
+            matches: Vec::new(),
+            truncated: false,
+            scanned: 0,
+            binary: 0,
+        };
+
+        let ignored = walk(&root, options, |rel| {
+            if !params.include.is_empty()
+                && !include.matched_path_or_any_parents(rel, false).is_ignore()
+            {
+                return true;
+            }
+            search.file(&root, rel);
+            !search.truncated
+        })
+        .map_err(|e| map_io_error(e, &root_str))?;
+
+        Ok(msgpack_map! {
+            "matches" => Value::Array(search.matches),
+            "truncated" => search.truncated,
+            "files_scanned" => search.scanned,
+            "skipped_ignored" => ignored,
+            "skipped_binary" => search.binary
+        })
+    })
+    .await?
+}
+
+/// What the walk skips
+struct WalkOptions {
+    rules: Option<IgnoreRules>,
+    include_hidden: bool,
+    follow_symlinks: bool,
+    max_depth: usize,
+}
+
+/// Walk `root`, calling `visit` with the path of each file relative to
+/// `root` until it returns false.  Returns how many entries the ignore
+/// rules skipped.
+fn walk(
+    root: &Path,
+    mut options: WalkOptions,
+    mut visit: impl FnMut(&Path) -> bool,
+) -> std::io::Result<u64> {
+    let mut ignored = 0;
+    let mut stack = vec![(PathBuf::new(), 0usize)];
+    // Directories entered, so followed symlinks cannot loop
+    let mut seen = HashSet::new();
+    if let Ok(meta) = std::fs::metadata(root) {
+        seen.insert((meta.dev(), meta.ino()));
+    }
+
+    while let Some((rel_dir, depth)) = stack.pop() {
+        let entries = match std::fs::read_dir(root.join(&rel_dir)) {
+            Ok(entries) => entries,
+            Err(_) if depth > 0 => continue,
+            Err(e) => return Err(e),
+        };
+        let mut entries: Vec<_> = entries.filter_map(Result::ok).collect();
+        entries.sort_by_key(|entry| entry.file_name());
+        let mut subdirs = Vec::new();
+        for entry in entries {
+            let name = entry.file_name();
+            if !options.include_hidden && name.as_bytes().first() == Some(&b'.') {
+                continue;
+            }
+            let rel = rel_dir.join(&name);
+            let Ok(mut file_type) = entry.file_type() else {
+                continue;
+            };
+            if file_type.is_symlink() {
+                if !options.follow_symlinks {
+                    continue;
+                }
+                match std::fs::metadata(entry.path()) {
+                    Ok(meta) => {
+                        if meta.is_dir() && !seen.insert((meta.dev(), meta.ino())) {
+                            continue;
+                        }
+                        file_type = meta.file_type();
+                    }
+                    Err(_) => continue,
+                }
+            } else if file_type.is_dir()
+                && let Ok(meta) = entry.metadata()
+            {
+                seen.insert((meta.dev(), meta.ino()));
+            }
+            let is_dir = file_type.is_dir();
+            if let Some(rules) = options.rules.as_mut()
+                && (is_dir && name == ".git" || rules.check(&root.join(&rel), is_dir).is_some())
+            {
+                ignored += 1;
+                continue;
+            }
+            if is_dir {
+                if depth + 1 < options.max_depth {
+                    subdirs.push((rel, depth + 1));
+                }
+            } else if file_type.is_file() && !visit(&rel) {
+                return Ok(ignored);
+            }
+        }
+        // Popped in name order
+        stack.extend(subdirs.into_iter().rev());
+    }
+    Ok(ignored)
+}
+
+/// Matches found so far
+struct Search {
+    regex: Regex,
+    skip_binary: bool,
+    limit: usize,
+    names: NamesAs,
+    matches: Vec<Value>,
+    truncated: bool,
+    scanned: u64,
+    binary: u64,
+}
+
+impl Search {
+    /// Search the file `rel`; unreadable files are left out.
+    fn file(&mut self, root: &Path, rel: &Path) {
+        let Ok(mut file) = std::fs::File::open(root.join(rel)) else {
+            return;
+        };
+        let mut contents = Vec::new();
+        // The probe is taken before anything else is read
+        if file
+            .by_ref()
+            .take(BINARY_PROBE_LEN as u64)
+            .read_to_end(&mut contents)
+            .is_err()
+        {
+            return;
+        }
+        if self.skip_binary && contents.contains(&0) {
+            self.binary += 1;
+            return;
+        }
+        if file.read_to_end(&mut contents).is_err() {
+            return;
+        }
+        self.scanned += 1;
+
+        for (index, line) in contents.split(|&b| b == b'\n').enumerate() {
+            let Some(found) = self.regex.find(line) else {
+                continue;
+            };
+            if self.matches.len() == self.limit {
+                self.truncated = true;
+                return;
+            }
+            let text = line.strip_suffix(b"\r").unwrap_or(line);
+            self.matches.push(msgpack_map! {
+                "path" => self.names.encode(rel.as_os_str().as_bytes()),
+                "line" => index as u64 + 1,
+                "column" => found.start() as u64 + 1,
+                "text" => self.names.encode(text)
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::git;
+
+    fn paths(result: &Value) -> Vec<String> {
+        result["matches"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|m| m["path"].as_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn search_skips_ignored_binary_and_hidden_files_by_default() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        git(root, &["init", "-q"]);
+        std::fs::create_dir_all(root.join("src")).unwrap();
+        std::fs::create_dir_all(root.join("target")).unwrap();
+        std::fs::write(root.join(".gitignore"), "target/\n").unwrap();
+        std::fs::write(
+            root.join("src").join("main.rs"),
+            "fn main() {}\n// needle\n",
+        )
+        .unwrap();
+        std::fs::write(root.join("target").join("out.rs"), "needle\n").unwrap();
+        std::fs::write(root.join(".hidden"), "needle\n").unwrap();
+        std::fs::write(root.join("blob"), b"needle\0\xff").unwrap();
+        std::os::unix::fs::symlink("src/main.rs", root.join("link.rs")).unwrap();
+
+        let search_with = |extra: &[(&str, bool)]| {
+            let mut params = vec![
+                ("root".into(), root.to_string_lossy().into_owned().into()),
+                ("pattern".into(), "NEEDLE".into()),
+                ("ignore_case".into(), true.into()),
+                ("names_as".into(), "auto".into()),
+            ];
+            params.extend(extra.iter().map(|&(k, v)| (k.into(), v.into())));
+            search(Value::Map(params))
+        };
+
+        let result = search_with(&[]).await.unwrap();
+        assert_eq!(paths(&result), ["src/main.rs"]);
+        let found = &result["matches"][0];
+        assert_eq!(found["line"].as_u64(), Some(2));
+        assert_eq!(found["column"].as_u64(), Some(4));
+        assert_eq!(found["text"].as_str(), Some("// needle"));
+        assert_eq!(result["files_scanned"].as_u64(), Some(1));
+        assert_eq!(result["skipped_ignored"].as_u64(), Some(1));
+        assert_eq!(result["skipped_binary"].as_u64(), Some(1));
+
+        let everything = search_with(&[
+            ("no_ignore", true),
+            ("skip_binary", false),
+            ("include_hidden", true),
+            ("follow_symlinks", true),
+        ])
+        .await
+        .unwrap();
+        assert_eq!(
+            paths(&everything),
+            [".hidden", "blob", "link.rs", "src/main.rs", "target/out.rs"]
+        );
+        assert_eq!(everything["skipped_ignored"].as_u64(), Some(0));
+        assert_eq!(everything["skipped_binary"].as_u64(), Some(0));
+    }
+
+    #[tokio::test]
+    async fn search_stops_at_the_limit() {
+        let tmp = tempfile::tempdir().unwrap();
+        std::fs::write(tmp.path().join("a"), "x\nx\nx\n").unwrap();
+        let result = search(msgpack_map! {
+            "root" => tmp.path().to_string_lossy().into_owned(),
+            "pattern" => "x",
+            "limit" => 2
+        })
+        .await
+        .unwrap();
+        assert_eq!(result["matches"].as_array().unwrap().len(), 2);
+        assert_eq!(result["truncated"].as_bool(), Some(true));
+
+        let err = search(msgpack_map! {
+            "root" => tmp.path().to_string_lossy().into_owned(),
+            "pattern" => "("
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(err.code, RpcError::INVALID_PARAMS);
+    }
+}
@@ -1,23 +1,63 @@
 //! File I/O operations
 
+use crate::jail;
 use crate::msgpack_map;
 use crate::protocol::{RpcError, from_value};
+use crate::stat_cache;
+use crate::write_lock;
 use flate2::Compression;
 use flate2::write::ZlibEncoder;
+use ignore::gitignore::Gitignore;
 use rmpv::Value;
 use serde::Deserialize;
+use std::collections::HashSet;
+use std::ffi::{OsStr, OsString};
 use std::io::{SeekFrom, Write};
 use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
-use tokio::fs::{self, File, OpenOptions};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::fs::{self, OpenOptions};
 use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
 
 use super::HandlerResult;
-use super::file::{bytes_to_path, map_io_error};
+use super::delta::base_token;
+use super::file::{
+    attrs_after, bytes_to_path, map_io_error, open_file_attributes, with_attrs, with_src_dest,
+};
+use super::project::build_globs;
+use super::sudo::{self, SudoCommand};
 
 use crate::protocol::path_or_bytes;
 
+/// Default for [`max_read_size`]
+pub const DEFAULT_MAX_READ_SIZE: u64 = 64 * 1024 * 1024;
+
+static MAX_READ_SIZE: AtomicU64 = AtomicU64::new(DEFAULT_MAX_READ_SIZE);
+
+/// Largest file `file.read` buffers without an explicit `length` or
+/// `allow_large`.
+pub fn max_read_size() -> u64 {
+    MAX_READ_SIZE.load(Ordering::Relaxed)
+}
+
+pub fn set_max_read_size(limit: u64) {
+    MAX_READ_SIZE.store(limit, Ordering::Relaxed);
+}
+
 /// Read file contents
+///
+/// Ranged reads (with `offset` or `length`) also return the file's
+/// `fingerprint` and the `crc32c` of the bytes read, so a client assembling
+/// a file from several chunks can verify each one.  With
+/// `expect_fingerprint` set to the fingerprint of an earlier chunk, the read
+/// fails with [`RpcError::STALE_FILE`] if the file has changed since.
+///
+/// Every read returns `eof`, whether it reached the end of the file, and
+/// the `file_size`.  A range past the end is empty with `eof` set.
+///
+/// With `consistent_read` the read waits for writes to the path in
+/// progress and holds off new ones (see [`crate::write_lock`]), so it never
+/// sees a half-written file.
 pub async fn read(params: Value) -> HandlerResult {
     #[derive(Deserialize)]
     struct Params {
@@ -32,53 +72,372 @@ pub async fn read(params: Value) -> HandlerResult {
         /// When true, zlib-compress payload bytes before sending.
         #[serde(default)]
         compress: bool,
+        /// Read the whole file even if it exceeds the maximum read size
+        #[serde(default)]
+        allow_large: bool,
+        /// Fail unless the file still has this fingerprint
+        #[serde(default)]
+        expect_fingerprint: Option<String>,
+        /// Read the file as `sudo_user` (default root), through sudo
+        #[serde(default)]
+        sudo: bool,
+        #[serde(default)]
+        sudo_user: Option<String>,
+        #[serde(default)]
+        password_timeout: Option<u64>,
+        /// Read a FIFO, socket or device; needs `length` and `timeout_ms`
+        #[serde(default)]
+        allow_special: bool,
+        #[serde(default)]
+        timeout_ms: Option<u64>,
+        /// Wait for writes to the path in progress, and hold off new ones
+        #[serde(default)]
+        consistent_read: bool,
     }
 
     let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
 
     let path = bytes_to_path(&params.path);
+    jail::check(&path)?;
     let path_str = path.to_string_lossy().into_owned();
+    let _guard = match params.consistent_read {
+        true => Some(write_lock::lock(&path).await),
+        false => None,
+    };
+
+    let ranged =
+        params.offset.is_some() || params.length.is_some() || params.expect_fingerprint.is_some();
+    if params.sudo {
+        if ranged {
+            return Err(RpcError::invalid_params("sudo reads are whole-file reads"));
+        }
+        let result = sudo::run_as(SudoCommand {
+            user: params.sudo_user.as_deref().unwrap_or("root"),
+            program: OsStr::new("cat"),
+            args: vec!["--".into(), path.clone().into_os_string()],
+            cwd: None,
+            stdin: None,
+            password_timeout: sudo::password_timeout(params.password_timeout)?,
+        })
+        .await?;
+        if result.exit_code != 0 {
+            return Err(sudo::file_error(&result, &path_str));
+        }
+        let limit = max_read_size();
+        if !params.allow_large && result.stdout.len() as u64 > limit {
+            return Err(RpcError::file_too_large(
+                &path_str,
+                Some(result.stdout.len() as u64),
+                limit,
+            ));
+        }
+        let file_size = result.stdout.len() as u64;
+        return read_payload(result.stdout, params.compress, true, Some(file_size));
+    }
 
-    let mut file = File::open(&path)
+    // Non-blocking, so that opening a FIFO does not wait for a writer
+    let mut file = OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(&path)
         .await
-        .map_err(|e| map_io_error(e, &path_str))?;
+        .map_err(|e| map_io_error(e, &path))?;
+    let metadata = file.metadata().await.map_err(|e| map_io_error(e, &path))?;
+
+    // Reading a FIFO or device may never end, so it must be asked for and
+    // bounded.  The check is on the type: procfs files are regular files
+    // that report size 0, and are read like any other.
+    let file_type = metadata.file_type();
+    if !file_type.is_file() && !file_type.is_dir() {
+        let file_type = super::file::get_file_type(&metadata);
+        if !params.allow_special {
+            return Err(RpcError::not_regular_file(&path_str, file_type));
+        }
+        let (Some(length), Some(timeout_ms)) = (params.length, params.timeout_ms) else {
+            return Err(RpcError::invalid_params(
+                "allow_special reads need length and timeout_ms",
+            ));
+        };
+        if params.offset.is_some() || params.expect_fingerprint.is_some() {
+            return Err(RpcError::invalid_params(
+                "offset and expect_fingerprint do not apply to special files",
+            ));
+        }
+        let file = file.into_std().await;
+        let timeout = std::time::Duration::from_millis(timeout_ms);
+        let (content, timed_out) =
+            crate::blocking::run(&path, move || read_special(file, length, timeout))
+                .await?
+                .map_err(|e| map_io_error(e, &path))?;
+        let checksum = crc32c(&content);
+        let eof = !timed_out && content.len() < length;
+        let mut response = read_payload(content, params.compress, eof, None)?;
+        if let Value::Map(entries) = &mut response {
+            entries.push(("crc32c".into(), checksum.into()));
+            entries.push(("timed_out".into(), timed_out.into()));
+        }
+        return Ok(response);
+    }
+
+    let fingerprint = base_token(&metadata);
+    if let Some(expected) = &params.expect_fingerprint
+        && *expected != fingerprint
+    {
+        return Err(RpcError::stale_file(&path_str, &fingerprint));
+    }
+
+    // Refuse to buffer huge files unless a length bounds the read.
+    let limit = (params.length.is_none() && !params.allow_large).then(max_read_size);
+    if let Some(limit) = limit
+        && metadata.is_file()
+    {
+        let size = metadata.len().saturating_sub(params.offset.unwrap_or(0));
+        if size > limit {
+            return Err(RpcError::file_too_large(&path_str, Some(size), limit));
+        }
+    }
 
     // Seek to offset if specified
     if let Some(offset) = params.offset {
         file.seek(SeekFrom::Start(offset))
             .await
-            .map_err(|e| map_io_error(e, &path_str))?;
+            .map_err(|e| map_io_error(e, &path))?;
     }
 
-    // Read the content
-    let content = if let Some(length) = params.length {
-        // Read up to LENGTH bytes in a single pass. `take` keeps reads bounded.
-        let mut buf = Vec::with_capacity(length);
-        let mut reader = file.take(length as u64);
+    // Read the content, and whether that reached the end of the file
+    let (content, eof) = if let Some(length) = params.length {
+        // `read_to_end` keeps reading until the buffer is full or the file
+        // ends, so a short result is always the end of the file.  One byte
+        // more than asked for tells whether a full one is too.
+        let mut buf = Vec::with_capacity(length.saturating_add(1));
+        let mut reader = (&mut file).take((length as u64).saturating_add(1));
         reader
             .read_to_end(&mut buf)
             .await
-            .map_err(|e| map_io_error(e, &path_str))?;
-        buf
+            .map_err(|e| map_io_error(e, &path))?;
+        let eof = buf.len() <= length;
+        buf.truncate(length);
+        (buf, eof)
     } else {
         // Pre-size from metadata to avoid repeated reallocations on large reads.
         let mut buf = Vec::new();
-        if let Ok(metadata) = file.metadata().await {
-            let mut expected_len = metadata.len() as usize;
-            if let Some(offset) = params.offset {
-                expected_len = expected_len.saturating_sub(offset as usize);
-            }
-            buf.reserve(expected_len);
+        let mut expected_len = metadata.len() as usize;
+        if let Some(offset) = params.offset {
+            expected_len = expected_len.saturating_sub(offset as usize);
         }
-        file.read_to_end(&mut buf)
+        buf.reserve(expected_len);
+        // Files that grew since the check, or report no size at all (pipes,
+        // procfs), are still cut off one byte past the limit.
+        let mut reader = (&mut file).take(limit.map_or(u64::MAX, |limit| limit.saturating_add(1)));
+        reader
+            .read_to_end(&mut buf)
             .await
-            .map_err(|e| map_io_error(e, &path_str))?;
-        buf
+            .map_err(|e| map_io_error(e, &path))?;
+        if let Some(limit) = limit
+            && buf.len() as u64 > limit
+        {
+            return Err(RpcError::file_too_large(&path_str, None, limit));
+        }
+        (buf, true)
+    };
+
+    // Files such as procfs ones report a size of 0, but have the bytes read
+    let file_size = match content.is_empty() {
+        true => metadata.len(),
+        false => metadata
+            .len()
+            .max(params.offset.unwrap_or(0) + content.len() as u64),
+    };
+    if !ranged {
+        return read_payload(content, params.compress, eof, Some(file_size));
+    }
+
+    // A chunk read while the file was being modified in place may mix old
+    // and new bytes; report it as stale rather than let it verify.
+    let after = file.metadata().await.map_err(|e| map_io_error(e, &path))?;
+    let after = base_token(&after);
+    if after != fingerprint {
+        return Err(RpcError::stale_file(&path_str, &after));
+    }
+
+    let checksum = crc32c(&content);
+    let mut response = read_payload(content, params.compress, eof, Some(file_size))?;
+    if let Value::Map(entries) = &mut response {
+        entries.push(("crc32c".into(), checksum.into()));
+        entries.push(("fingerprint".into(), fingerprint.into()));
+    }
+    Ok(response)
+}
+
+/// Largest number of ranges accepted by one `file.read_multi_ranges`.
+const MAX_READ_RANGES: usize = 1024;
+
+/// Read several ranges of a file in one round trip.
+///
+/// `ranges` is a list of `[offset, length]` pairs, whose lengths may add up
+/// to at most the maximum read size.  Returns `{fingerprint, ranges}`, with
+/// one `{offset, content, size, crc32c, ...}` entry per requested range, all
+/// read from the same version of the file.  `expect_fingerprint` and
+/// `compress` work as for `file.read`.
+pub async fn read_multi_ranges(params: Value) -> HandlerResult {
+    #[derive(Deserialize)]
+    struct Params {
+        #[serde(with = "path_or_bytes")]
+        path: Vec<u8>,
+        ranges: Vec<(u64, u64)>,
+        #[serde(default)]
+        compress: bool,
+        #[serde(default)]
+        expect_fingerprint: Option<String>,
+    }
+
+    let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
+    if params.ranges.len() > MAX_READ_RANGES {
+        return Err(RpcError::invalid_params(format!(
+            "At most {} ranges per request",
+            MAX_READ_RANGES
+        )));
+    }
+
+    let path = bytes_to_path(&params.path);
+    jail::check(&path)?;
+    let path_str = path.to_string_lossy().into_owned();
+
+    let total = params
+        .ranges
+        .iter()
+        .fold(0u64, |total, (_, length)| total.saturating_add(*length));
+    if total > max_read_size() {
+        return Err(RpcError::file_too_large(
+            &path_str,
+            Some(total),
+            max_read_size(),
+        ));
+    }
+
+    let chunks = {
+        let path_str = path_str.clone();
+        crate::blocking::run(path.clone(), move || -> Result<_, RpcError> {
+            use std::os::unix::fs::FileExt;
+
+            let file = std::fs::File::open(&path).map_err(|e| map_io_error(e, &path))?;
+            let metadata = file.metadata().map_err(|e| map_io_error(e, &path))?;
+            let fingerprint = base_token(&metadata);
+            if let Some(expected) = &params.expect_fingerprint
+                && *expected != fingerprint
+            {
+                return Err(RpcError::stale_file(&path_str, &fingerprint));
+            }
+
+            let mut chunks = Vec::with_capacity(params.ranges.len());
+            for (offset, length) in params.ranges {
+                let mut buf = vec![0; length as usize];
+                let mut filled = 0;
+                while filled < buf.len() {
+                    match file.read_at(&mut buf[filled..], offset + filled as u64) {
+                        Ok(0) => break,
+                        Ok(n) => filled += n,
+                        Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                        Err(e) => return Err(map_io_error(e, &path)),
+                    }
+                }
+                buf.truncate(filled);
+                let eof = filled < length as usize || offset + length >= metadata.len();
+                chunks.push((offset, buf, eof));
+            }
+
+            let after = file.metadata().map_err(|e| map_io_error(e, &path))?;
+            let after = base_token(&after);
+            if after != fingerprint {
+                return Err(RpcError::stale_file(&path_str, &after));
+            }
+            Ok((fingerprint, metadata.len(), chunks))
+        })
+        .await??
     };
 
-    // Return binary content directly (no base64!). Compression is opt-in.
+    let (fingerprint, file_size, chunks) = chunks;
+    let mut ranges = Vec::with_capacity(chunks.len());
+    for (offset, content, eof) in chunks {
+        let checksum = crc32c(&content);
+        let mut range = read_payload(content, params.compress, eof, Some(file_size))?;
+        if let Value::Map(entries) = &mut range {
+            entries.insert(0, ("offset".into(), offset.into()));
+            entries.push(("crc32c".into(), checksum.into()));
+        }
+        ranges.push(range);
+    }
+
+    Ok(msgpack_map! {
+        "fingerprint" => fingerprint,
+        "ranges" => Value::Array(ranges)
+    })
+}
+
+/// Read up to `length` bytes from `file`, a FIFO, socket or device opened
+/// non-blocking, until end of file or `timeout` runs out.  Returns the
+/// bytes read and whether the timeout cut the read short.
+fn read_special(
+    mut file: std::fs::File,
+    length: usize,
+    timeout: std::time::Duration,
+) -> std::io::Result<(Vec<u8>, bool)> {
+    use std::io::Read;
+    use std::os::fd::AsRawFd;
+
+    let deadline = std::time::Instant::now() + timeout;
+    let mut content = Vec::with_capacity(length.min(1024 * 1024));
+    let mut chunk = vec![0u8; length.clamp(1, 64 * 1024)];
+    while content.len() < length {
+        let remaining = deadline.saturating_duration_since(std::time::Instant::now());
+        if remaining.is_zero() {
+            return Ok((content, true));
+        }
+        let mut pollfd = libc::pollfd {
+            fd: file.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let timeout_ms = remaining.as_millis().clamp(1, i32::MAX as u128) as i32;
+        match unsafe { libc::poll(&mut pollfd, 1, timeout_ms) } {
+            0 => return Ok((content, true)),
+            ready if ready < 0 => {
+                let err = std::io::Error::last_os_error();
+                if err.kind() == std::io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(err);
+            }
+            _ => {}
+        }
+        let want = (length - content.len()).min(chunk.len());
+        match file.read(&mut chunk[..want]) {
+            Ok(0) => break,
+            Ok(n) => content.extend_from_slice(&chunk[..n]),
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::Interrupted
+                ) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok((content, false))
+}
+
+/// `{content, size, compressed, compression, eof, file_size}` for bytes read
+/// from a file.  `eof` tells whether the read reached the end of the file,
+/// and `file_size` is nil for files without one, such as FIFOs.
+/// Compression is opt-in; content is sent as binary (no base64!).
+fn read_payload(
+    content: Vec<u8>,
+    compress: bool,
+    eof: bool,
+    file_size: Option<u64>,
+) -> HandlerResult {
     let size = content.len();
-    if params.compress {
+    let file_size = file_size.map_or(Value::Nil, Value::from);
+    if compress {
         let mut encoder = ZlibEncoder::new(Vec::new(), Compression::fast());
         encoder
             .write_all(&content)
@@ -90,19 +449,53 @@ pub async fn read(params: Value) -> HandlerResult {
             "content" => Value::Binary(compressed),
             "size" => size,
             "compressed" => true,
-            "compression" => "zlib"
+            "compression" => "zlib",
+            "eof" => eof,
+            "file_size" => file_size
         })
     } else {
         Ok(msgpack_map! {
             "content" => Value::Binary(content),
             "size" => size,
             "compressed" => false,
-            "compression" => Value::Nil
+            "compression" => Value::Nil,
+            "eof" => eof,
+            "file_size" => file_size
         })
     }
 }
 
+/// CRC-32C (Castagnoli) lookup table, reflected polynomial 0x82F63B78.
+const CRC32C_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0x82F6_3B78
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// CRC-32C of `data`, as used by iSCSI, ext4 and `crc32c` libraries.
+fn crc32c(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, &byte| {
+        CRC32C_TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
 /// Write file contents
+///
+/// Writes to one path are serialized (see [`crate::write_lock`]).
 pub async fn write(params: Value) -> HandlerResult {
     #[derive(Deserialize)]
     struct Params {
@@ -111,63 +504,203 @@ pub async fn write(params: Value) -> HandlerResult {
         /// Content to write as binary
         #[serde(with = "serde_bytes")]
         content: Vec<u8>,
-        /// File mode (permissions) - only applied to new files
+        /// File mode (permissions), applied after writing
         #[serde(default)]
         mode: Option<u32>,
         /// Append to file instead of overwriting
         #[serde(default)]
         append: bool,
-        /// Byte offset to start writing at (only if not appending)
+        /// Byte offset to start writing at; the rest of the file is kept
         #[serde(default)]
         offset: Option<u64>,
+        /// Create the file if it is missing.  Defaults to true, except for
+        /// offset writes, which expect an existing file unless asked.
+        #[serde(default)]
+        create: Option<bool>,
+        /// Fail if the file already exists
+        #[serde(default)]
+        create_new: bool,
+        /// Remove the file's auto-save files once the write succeeded
+        #[serde(default)]
+        delete_autosave: bool,
+        /// Write the file as `sudo_user` (default root), through sudo
+        #[serde(default)]
+        sudo: bool,
+        #[serde(default)]
+        sudo_user: Option<String>,
+        #[serde(default)]
+        password_timeout: Option<u64>,
+        /// Fail up front unless the filesystem has this many bytes available
+        #[serde(default)]
+        require_free_bytes: Option<u64>,
+        /// Include the file's attributes after the write as `attrs`
+        #[serde(default)]
+        return_attrs: bool,
     }
 
     let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
 
+    if params.append && params.offset.is_some() {
+        return Err(RpcError::invalid_params("append and offset are exclusive"));
+    }
+    if params.create_new && params.append {
+        return Err(RpcError::invalid_params(
+            "create_new and append are exclusive",
+        ));
+    }
+    if params.create_new && params.create == Some(false) {
+        return Err(RpcError::invalid_params(
+            "create_new conflicts with create: false",
+        ));
+    }
+
     let path = bytes_to_path(&params.path);
+    jail::check(&path)?;
     let path_str = path.to_string_lossy().into_owned();
+    let _guard = write_lock::lock(&path).await;
+    if let Some(required) = params.require_free_bytes {
+        super::check_free_space(&path, required)?;
+    }
 
     // Content is already binary, no decoding needed!
     let content = params.content;
 
-    // Open the file with appropriate options
+    if params.sudo {
+        if params.offset.is_some() || params.create == Some(false) {
+            return Err(RpcError::invalid_params(
+                "sudo writes do not support offset or create: false",
+            ));
+        }
+        // The path and mode are passed as arguments, never in the script
+        let mut script = String::new();
+        if params.create_new {
+            script.push_str("set -C; ");
+        }
+        script.push_str(if params.append {
+            "cat >> \"$1\""
+        } else {
+            "cat > \"$1\""
+        });
+        if params.mode.is_some() {
+            script.push_str(" && chmod \"$2\" \"$1\"");
+        }
+        let mut args: Vec<OsString> = vec!["-c".into(), script.into(), "sh".into()];
+        args.push(path.clone().into_os_string());
+        if let Some(mode) = params.mode {
+            args.push(format!("{:o}", mode).into());
+        }
+        let written = content.len();
+        let result = sudo::run_as(SudoCommand {
+            user: params.sudo_user.as_deref().unwrap_or("root"),
+            program: OsStr::new("sh"),
+            args,
+            cwd: None,
+            stdin: Some(content),
+            password_timeout: sudo::password_timeout(params.password_timeout)?,
+        })
+        .await?;
+        if result.exit_code != 0 {
+            return Err(sudo::file_error(&result, &path_str));
+        }
+        stat_cache::invalidate(&path).await;
+        if params.delete_autosave {
+            let autosave_for = path.clone();
+            crate::blocking::run(&path, move || {
+                super::autosave::remove_autosaves(&autosave_for)
+            })
+            .await?
+            .map_err(|e| map_io_error(e, &path))?;
+        }
+        let result = msgpack_map! {
+            "written" => written
+        };
+        if params.return_attrs {
+            return Ok(with_attrs(
+                result,
+                vec![("attrs", attrs_after(&path, false).await)],
+            ));
+        }
+        return Ok(result);
+    }
+
+    // Open the file with appropriate options:
+    // - append: write at the end, keeping existing content
+    // - offset: write in place at `offset`, keeping the rest
+    // - neither: replace the whole content (truncate)
     let mut options = OpenOptions::new();
 
     if params.append {
-        options.append(true).create(true);
+        options.append(true);
     } else if params.offset.is_some() {
         options.write(true);
     } else {
-        options.write(true).create(true).truncate(true);
+        options.write(true).truncate(true);
+    }
+
+    if params.create_new {
+        options.create_new(true);
+    } else {
+        options.create(params.create.unwrap_or(params.offset.is_none()));
+    }
+    if let Some(mode) = params.mode {
+        // Created files never exist with looser permissions than requested
+        options.mode(mode);
     }
 
     let mut file = options
         .open(&path)
         .await
-        .map_err(|e| map_io_error(e, &path_str))?;
+        .map_err(|e| map_io_error(e, &path))?;
 
     // Seek to offset if specified
     if let Some(offset) = params.offset {
         file.seek(SeekFrom::Start(offset))
             .await
-            .map_err(|e| map_io_error(e, &path_str))?;
+            .map_err(|e| map_io_error(e, &path))?;
     }
 
     // Write the content
     file.write_all(&content)
         .await
-        .map_err(|e| map_io_error(e, &path_str))?;
+        .map_err(|e| map_io_error(e, &path))?;
 
     // Set permissions if specified
     if let Some(mode) = params.mode {
         let perms = std::fs::Permissions::from_mode(mode);
         fs::set_permissions(&path, perms)
             .await
-            .map_err(|e| map_io_error(e, &path_str))?;
+            .map_err(|e| map_io_error(e, &path))?;
     }
 
-    Ok(msgpack_map! {
+    stat_cache::invalidate(&path).await;
+
+    // Stat the open file, which is the one written even if the path has
+    // been replaced meanwhile
+    let attrs = if params.return_attrs {
+        Some(
+            open_file_attributes(&file)
+                .await
+                .map_or(Value::Nil, |attrs| attrs.to_value()),
+        )
+    } else {
+        None
+    };
+
+    if params.delete_autosave {
+        let autosave_for = path.clone();
+        crate::blocking::run(&path, move || {
+            super::autosave::remove_autosaves(&autosave_for)
+        })
+        .await?
+        .map_err(|e| map_io_error(e, &path))?;
+    }
+
+    let result = msgpack_map! {
         "written" => content.len()
+    };
+    Ok(match attrs {
+        Some(attrs) => with_attrs(result, vec![("attrs", attrs)]),
+        None => result,
     })
 }
 
@@ -188,9 +721,21 @@ pub async fn copy(params: Value) -> HandlerResult {
         /// Preserve access and modification times.
         #[serde(default)]
         preserve_times: bool,
-        /// Overwrite existing destination entries where possible.
+        /// Preserve the owner and group; needs root.
+        #[serde(default)]
+        preserve_ownership: bool,
+        /// Preserve extended attributes: user.* ones, and security.*,
+        /// system.* and trusted.* ones when running as root.
+        #[serde(default)]
+        preserve_xattrs: bool,
+        /// Overwrite existing destination entries where possible.  Without
+        /// it, files are created with O_EXCL and an existing one is EEXIST.
         #[serde(default)]
         overwrite: bool,
+        /// Leave destination files that are not older than their source
+        /// alone and replace older ones, whatever `overwrite` says.
+        #[serde(default)]
+        keep_newer: bool,
         /// Treat `dest` as the exact destination path, even when it names an
         /// existing directory.  The default keeps the historical `file.copy`
         /// behavior of copying into an existing destination directory.
@@ -202,14 +747,100 @@ pub async fn copy(params: Value) -> HandlerResult {
         /// PARENTS set it to false.
         #[serde(default = "default_true")]
         merge_existing_directories: bool,
+        /// Copy what symlinks inside a copied directory point to instead
+        /// of recreating the links.
+        #[serde(default)]
+        dereference: bool,
+        /// Copy what `src` points to when it is itself a symlink.  When
+        /// false, the link is recreated at `dest` with the same target.
+        #[serde(default = "default_true")]
+        follow_symlink: bool,
+        /// Maximum directory nesting below `src` for recursive copies.
+        #[serde(default = "default_max_copy_depth")]
+        max_depth: usize,
+        /// Leave out entries of a copied directory matching these
+        /// gitignore-style globs (relative to `src`, as in `project.files`)
+        #[serde(default)]
+        exclude: Vec<String>,
+        /// Only copy the files of a copied directory matching these globs
+        #[serde(default)]
+        include_only: Vec<String>,
+        /// Leave out files of a copied directory larger than this
+        #[serde(default)]
+        max_file_size: Option<u64>,
+        /// Check each copied file afterwards: "size" or "checksum" (sha256)
+        #[serde(default)]
+        verify: Option<String>,
+        /// Remove a destination file that fails verification
+        #[serde(default)]
+        cleanup_on_mismatch: bool,
+        /// Fail up front unless the destination filesystem has this many
+        /// bytes available
+        #[serde(default)]
+        require_free_bytes: Option<u64>,
+    }
+
+    fn default_max_copy_depth() -> usize {
+        256
     }
 
     let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
+    let verify = match params.verify.as_deref() {
+        None => Verify::None,
+        Some("size") => Verify::Size,
+        Some("checksum") => Verify::Checksum,
+        Some(other) => {
+            return Err(RpcError::invalid_params(format!(
+                "verify must be \"size\" or \"checksum\", got \"{}\"",
+                other
+            )));
+        }
+    };
+    let mut state = CopyState::default();
+    let privileged = unsafe { libc::geteuid() } == 0;
+    let mut preserve_ownership = params.preserve || params.preserve_ownership;
+    let mut preserve_xattrs = params.preserve || params.preserve_xattrs;
+    let mut requested = Vec::new();
+    if preserve_ownership {
+        requested.push("ownership");
+        if !privileged {
+            state.not_preserved("ownership", "the server is not running as root");
+            preserve_ownership = false;
+        }
+    }
+    if params.preserve || params.preserve_permissions {
+        requested.push("permissions");
+    }
+    if params.preserve || params.preserve_times {
+        requested.push("times");
+    }
+    if preserve_xattrs {
+        requested.extend(["xattrs", "system_xattrs"]);
+        if cfg!(not(target_os = "linux")) {
+            for class in ["xattrs", "system_xattrs"] {
+                state.not_preserved(class, "xattrs are only copied on Linux");
+            }
+            preserve_xattrs = false;
+        } else if !privileged {
+            state.not_preserved(
+                "system_xattrs",
+                "security.*, system.* and trusted.* xattrs need root",
+            );
+        }
+    }
     let options = CopyOptions {
+        verify,
+        cleanup_on_mismatch: params.cleanup_on_mismatch,
         preserve_permissions: params.preserve || params.preserve_permissions,
         preserve_times: params.preserve || params.preserve_times,
+        preserve_ownership,
+        preserve_xattrs,
+        system_xattrs: preserve_xattrs && privileged,
         overwrite: params.overwrite,
+        keep_newer: params.keep_newer,
         merge_existing_directories: params.merge_existing_directories,
+        dereference: params.dereference,
+        max_depth: params.max_depth,
     };
 
     let src_path = bytes_to_path(&params.src);
@@ -226,46 +857,258 @@ pub async fn copy(params: Value) -> HandlerResult {
         dest_path.push(filename);
     }
 
-    let src_str = src_path.to_string_lossy().into_owned();
+    jail::check(&src_path)?;
+    jail::check(&dest_path)?;
+    if let Some(required) = params.require_free_bytes {
+        super::check_free_space(&dest_path, required)?;
+    }
 
-    let src_metadata = fs::metadata(&src_path)
-        .await
-        .map_err(|e| map_io_error(e, &src_str))?;
+    let result: HandlerResult = async {
+        if !params.follow_symlink
+            && fs::symlink_metadata(&src_path)
+                .await
+                .map_err(|e| map_io_error(e, &src_path))?
+                .file_type()
+                .is_symlink()
+        {
+            // Reproduce the link itself, keeping a relative target relative
+            let target = fs::read_link(&src_path)
+                .await
+                .map_err(|e| map_io_error(e, &src_path))?;
+            jail::check_link_target(&dest_path, &target)?;
+            prepare_symlink_destination(&dest_path, options.overwrite)
+                .await
+                .map_err(|e| map_io_error(e, &dest_path))?;
+            fs::symlink(&target, &dest_path)
+                .await
+                .map_err(|e| map_io_error(e, &dest_path))?;
+            let result =
+                preserve_ownership_and_xattrs(&src_path, &dest_path, true, options, &mut state)
+                    .await;
+            result.map_err(|e| state.copy_error(e, &src_path))?;
+            stat_cache::invalidate(&dest_path).await;
+            return Ok(with_preserve_report(
+                msgpack_map! {
+                    "copied" => 0,
+                    "entries" => 1,
+                    "skipped" => 0,
+                    "filtered" => 0
+                },
+                &requested,
+                &state,
+            ));
+        }
 
-    let bytes_copied = if src_metadata.is_dir() {
-        reject_recursive_self_copy(&src_path, &dest_path)
-            .await
-            .map_err(|e| map_io_error(e, &src_str))?;
-        // Recursive directory copy
-        copy_dir_recursive(&src_path, &dest_path, options, true)
-            .await
-            .map_err(|e| map_io_error(e, &src_str))?
-    } else {
-        // Copy regular file (or symlink target)
-        prepare_regular_destination(&dest_path, options.overwrite)
-            .await
-            .map_err(|e| map_io_error(e, &src_str))?;
-        let n = fs::copy(&src_path, &dest_path)
+        let src_metadata = fs::metadata(&src_path)
             .await
-            .map_err(|e| map_io_error(e, &src_str))?;
+            .map_err(|e| map_io_error(e, &src_path))?;
 
-        apply_copied_metadata(&src_metadata, &dest_path, options)
-            .await
-            .map_err(|e| map_io_error(e, &src_str))?;
-        n
-    };
+        let is_dir = src_metadata.is_dir();
+        let bytes_copied = if is_dir {
+            reject_recursive_self_copy(&src_path, &dest_path)
+                .await
+                .map_err(|e| map_io_error(e, &src_path))?;
+            if !(params.exclude.is_empty()
+                && params.include_only.is_empty()
+                && params.max_file_size.is_none())
+            {
+                state.filter = Some(CopyFilter {
+                    exclude: build_globs(&src_path, &params.exclude)?,
+                    include_only: (!params.include_only.is_empty())
+                        .then(|| build_globs(&src_path, &params.include_only))
+                        .transpose()?,
+                    max_file_size: params.max_file_size,
+                    root: src_path.clone(),
+                });
+            }
+            // Recursive directory copy
+            let result =
+                copy_dir_recursive(&src_path, &dest_path, options, true, 0, &mut state).await;
+            result.map_err(|e| state.copy_error(e, &src_path))?
+        } else {
+            // Copy regular file (or symlink target), like any other write
+            // of `dest`
+            let _guard = write_lock::lock(&dest_path).await;
+            let result =
+                copy_regular_file(&src_path, &dest_path, &src_metadata, options, &mut state).await;
+            result.map_err(|e| state.copy_error(e, &src_path))?
+        };
 
-    Ok(msgpack_map! {
-        "copied" => bytes_copied
-    })
+        if is_dir {
+            stat_cache::invalidate_tree(&dest_path).await;
+        } else {
+            stat_cache::invalidate(&dest_path).await;
+        }
+
+        let mut result = msgpack_map! {
+            "copied" => bytes_copied,
+            "entries" => state.entries,
+            "skipped" => state.skipped,
+            "filtered" => state.filtered
+        };
+        if verify != Verify::None
+            && let Value::Map(ref mut pairs) = result
+        {
+            let millis = (state.verify_time.as_secs_f64() * 1_000_000.0).round() / 1000.0;
+            pairs.push(("verify_ms".into(), millis.into()));
+        }
+        Ok(with_preserve_report(result, &requested, &state))
+    }
+    .await;
+    result.map_err(|e| with_src_dest(e, &src_path, &dest_path))
+}
+
+/// How `file.copy` checks copied files
+#[derive(Clone, Copy, PartialEq)]
+enum Verify {
+    None,
+    /// Compare st_size
+    Size,
+    /// Compare sha256 digests
+    Checksum,
 }
 
 #[derive(Clone, Copy)]
 struct CopyOptions {
+    verify: Verify,
+    cleanup_on_mismatch: bool,
     preserve_permissions: bool,
     preserve_times: bool,
+    preserve_ownership: bool,
+    /// Copy user.* xattrs, and the others too with `system_xattrs`
+    preserve_xattrs: bool,
+    system_xattrs: bool,
     overwrite: bool,
+    keep_newer: bool,
     merge_existing_directories: bool,
+    dereference: bool,
+    max_depth: usize,
+}
+
+/// Directories seen during one recursive copy, as (dev, inode) pairs.
+#[derive(Default)]
+struct CopyState {
+    /// Source directories currently being copied (the recursion stack)
+    active: HashSet<(u64, u64)>,
+    /// Directories created or merged into on the destination side
+    dest_dirs: HashSet<(u64, u64)>,
+    /// Files left alone by `keep_newer`
+    skipped: u64,
+    /// Files and symlinks copied
+    entries: u64,
+    /// Entries left out by `filter`
+    filtered: u64,
+    filter: Option<CopyFilter>,
+    /// Time spent verifying copies
+    verify_time: std::time::Duration,
+    /// Set when a copy failed verification or a blocking step timed out
+    mismatch: Option<RpcError>,
+    /// Metadata classes that were asked for but not (fully) preserved, with
+    /// the first reason why
+    not_preserved: Vec<(&'static str, String)>,
+}
+
+impl CopyState {
+    /// Turn the error that aborted a copy into an RpcError.
+    fn copy_error(&mut self, err: std::io::Error, path: &Path) -> RpcError {
+        self.mismatch
+            .take()
+            .unwrap_or_else(|| map_io_error(err, path))
+    }
+
+    /// The result of a step run through [`crate::blocking::run`], keeping
+    /// a timeout to report in place of the I/O error that aborts the copy.
+    fn blocking<T>(&mut self, result: Result<std::io::Result<T>, RpcError>) -> std::io::Result<T> {
+        result.unwrap_or_else(|e| {
+            self.mismatch = Some(e);
+            Err(std::io::Error::other("blocking operation failed"))
+        })
+    }
+
+    /// Record that the metadata of `class` could not be preserved.
+    fn not_preserved(&mut self, class: &'static str, reason: impl Into<String>) {
+        if !self.not_preserved.iter().any(|(c, _)| *c == class) {
+            self.not_preserved.push((class, reason.into()));
+        }
+    }
+}
+
+/// `result` with `preserved`, the `requested` metadata classes that were
+/// preserved for every entry, and `not_preserved`, `{class, reason}` for
+/// the others.  Copies that preserve nothing are left as they are.
+fn with_preserve_report(result: Value, requested: &[&str], state: &CopyState) -> Value {
+    if requested.is_empty() {
+        return result;
+    }
+    let preserved = requested
+        .iter()
+        .filter(|class| !state.not_preserved.iter().any(|(c, _)| c == *class))
+        .map(|class| Value::from(*class))
+        .collect();
+    let not_preserved = state
+        .not_preserved
+        .iter()
+        .map(|(class, reason)| {
+            msgpack_map! {
+                "class" => *class,
+                "reason" => reason.as_str()
+            }
+        })
+        .collect();
+    super::file::with_attrs(
+        result,
+        vec![
+            ("preserved", Value::Array(preserved)),
+            ("not_preserved", Value::Array(not_preserved)),
+        ],
+    )
+}
+
+/// Which entries of a recursive copy to leave out
+struct CopyFilter {
+    /// The copied directory, which the patterns are relative to
+    root: PathBuf,
+    exclude: Gitignore,
+    include_only: Option<Gitignore>,
+    max_file_size: Option<u64>,
+}
+
+impl CopyFilter {
+    /// Whether to leave out `path`; `size` is given for regular files.
+    /// `include_only` does not apply to directories, so they are still
+    /// searched for matching files.
+    fn skips(&self, path: &Path, is_dir: bool, size: Option<u64>) -> bool {
+        let rel = path.strip_prefix(&self.root).unwrap_or(path);
+        if self
+            .exclude
+            .matched_path_or_any_parents(rel, is_dir)
+            .is_ignore()
+        {
+            return true;
+        }
+        if is_dir {
+            return false;
+        }
+        if let Some(ref include) = self.include_only
+            && !include.matched_path_or_any_parents(rel, false).is_ignore()
+        {
+            return true;
+        }
+        size.zip(self.max_file_size)
+            .is_some_and(|(size, max)| size > max)
+    }
+}
+
+fn dir_id(meta: &std::fs::Metadata) -> (u64, u64) {
+    use std::os::unix::fs::MetadataExt;
+    (meta.dev(), meta.ino())
+}
+
+fn copy_loop_error(path: &Path, reason: &str) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidInput,
+        format!("Cannot copy {}: {}", path.display(), reason),
+    )
 }
 
 fn default_true() -> bool {
@@ -273,13 +1116,37 @@ fn default_true() -> bool {
 }
 
 /// Recursively copy a directory and its contents.
+///
+/// Symlinks are recreated as links unless `options.dereference` is set.
+/// Either way, re-entering a directory that is still being copied or one
+/// that belongs to the destination is refused, so neither symlink loops nor
+/// links back into the destination can recurse without bound.
 async fn copy_dir_recursive(
     src: &Path,
     dest: &Path,
     options: CopyOptions,
     allow_existing_dest_dir: bool,
+    depth: usize,
+    state: &mut CopyState,
 ) -> std::io::Result<u64> {
+    if depth > options.max_depth {
+        return Err(copy_loop_error(
+            src,
+            &format!("maximum depth ({}) exceeded", options.max_depth),
+        ));
+    }
+
+    let src_meta = fs::metadata(src).await?;
+    let src_id = dir_id(&src_meta);
+    if state.dest_dirs.contains(&src_id) {
+        return Err(copy_loop_error(src, "it is inside the destination"));
+    }
+    if !state.active.insert(src_id) {
+        return Err(copy_loop_error(src, "directory cycle detected"));
+    }
+
     ensure_directory_destination(dest, allow_existing_dest_dir).await?;
+    state.dest_dirs.insert(dir_id(&fs::metadata(dest).await?));
 
     let mut total: u64 = 0;
     let mut entries = fs::read_dir(src).await?;
@@ -287,7 +1154,26 @@ async fn copy_dir_recursive(
     while let Some(entry) = entries.next_entry().await? {
         let entry_path = entry.path();
         let dest_child = dest.join(entry.file_name());
-        let file_type = entry.file_type().await?;
+        let mut file_type = entry.file_type().await?;
+        if options.dereference && file_type.is_symlink() {
+            file_type = fs::metadata(&entry_path).await?.file_type();
+        }
+
+        let meta = if file_type.is_file() {
+            Some(fs::metadata(&entry_path).await?)
+        } else {
+            None
+        };
+        if let Some(ref filter) = state.filter
+            && filter.skips(
+                &entry_path,
+                file_type.is_dir(),
+                meta.as_ref().map(|meta| meta.len()),
+            )
+        {
+            state.filtered += 1;
+            continue;
+        }
 
         if file_type.is_dir() {
             total += Box::pin(copy_dir_recursive(
@@ -295,6 +1181,8 @@ async fn copy_dir_recursive(
                 &dest_child,
                 options,
                 options.merge_existing_directories,
+                depth + 1,
+                state,
             ))
             .await?;
         } else if file_type.is_symlink() {
@@ -302,18 +1190,19 @@ async fn copy_dir_recursive(
             let link_target = fs::read_link(&entry_path).await?;
             prepare_symlink_destination(&dest_child, options.overwrite).await?;
             tokio::fs::symlink(&link_target, &dest_child).await?;
+            preserve_ownership_and_xattrs(&entry_path, &dest_child, true, options, state).await?;
+            state.entries += 1;
         } else {
-            prepare_regular_destination(&dest_child, options.overwrite).await?;
-            let n = fs::copy(&entry_path, &dest_child).await?;
-            total += n;
-
-            let meta = fs::metadata(&entry_path).await?;
-            apply_copied_metadata(&meta, &dest_child, options).await?;
+            let meta = match meta {
+                Some(meta) => meta,
+                None => fs::metadata(&entry_path).await?,
+            };
+            total += copy_regular_file(&entry_path, &dest_child, &meta, options, state).await?;
         }
     }
 
-    let src_meta = fs::metadata(src).await?;
-    apply_copied_metadata(&src_meta, dest, options).await?;
+    state.active.remove(&src_id);
+    apply_copied_metadata(src, &src_meta, dest, options, state).await?;
 
     Ok(total)
 }
@@ -350,6 +1239,15 @@ async fn reject_recursive_self_copy(src: &Path, dest: &Path) -> std::io::Result<
                 dest.display()
             ),
         ))
+    } else if src.starts_with(&dest) {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "Cannot copy directory {} into its ancestor {}",
+                src.display(),
+                dest.display()
+            ),
+        ))
     } else {
         Ok(())
     }
@@ -377,16 +1275,133 @@ async fn canonicalize_existing_ancestor(path: &Path) -> std::io::Result<PathBuf>
     }
 }
 
-async fn prepare_regular_destination(path: &Path, overwrite: bool) -> std::io::Result<()> {
-    if overwrite {
-        return Ok(());
-    }
-
-    match fs::symlink_metadata(path).await {
-        Ok(_) => Err(already_exists(path)),
-        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
-        Err(e) => Err(e),
-    }
+/// Copy the regular file (or symlink target) `src` to `dest` and apply the
+/// preserved metadata, returning the number of bytes copied.
+///
+/// Without `overwrite`, `dest` is created with O_EXCL so that a destination
+/// appearing after the caller's checks fails with EEXIST instead of being
+/// clobbered.  `keep_newer` counts an up-to-date destination as skipped.
+async fn copy_regular_file(
+    src: &Path,
+    dest: &Path,
+    src_meta: &std::fs::Metadata,
+    options: CopyOptions,
+    state: &mut CopyState,
+) -> std::io::Result<u64> {
+    let n = if options.keep_newer {
+        if let Ok(dest_meta) = fs::metadata(dest).await
+            && !is_older(&dest_meta, src_meta)
+        {
+            state.skipped += 1;
+            return Ok(0);
+        }
+        fs::copy(src, dest).await?
+    } else if options.overwrite {
+        fs::copy(src, dest).await?
+    } else {
+        let (src, dest) = (src.to_path_buf(), dest.to_path_buf());
+        state.blocking(
+            crate::blocking::run(src.clone(), move || copy_file_exclusive(&src, &dest)).await,
+        )?
+    };
+    apply_copied_metadata(src, src_meta, dest, options, state).await?;
+    state.entries += 1;
+
+    if options.verify != Verify::None {
+        let started = std::time::Instant::now();
+        let (src, dest) = (src.to_path_buf(), dest.to_path_buf());
+        let verify = options.verify;
+        let cleanup = options.cleanup_on_mismatch;
+        let mismatch = state.blocking(
+            crate::blocking::run(src.clone(), move || {
+                verify_copy(&src, &dest, verify, cleanup)
+            })
+            .await,
+        )?;
+        state.verify_time += started.elapsed();
+        if let Some(error) = mismatch {
+            state.mismatch = Some(error);
+            return Err(std::io::Error::other("copy verification failed"));
+        }
+    }
+    Ok(n)
+}
+
+/// Compare `dest` with `src`, returning the error to report on a mismatch.
+fn verify_copy(
+    src: &Path,
+    dest: &Path,
+    verify: Verify,
+    cleanup: bool,
+) -> std::io::Result<Option<RpcError>> {
+    let mut data = match verify {
+        Verify::None => return Ok(None),
+        Verify::Size => {
+            let src_size = std::fs::metadata(src)?.len();
+            let dest_size = std::fs::metadata(dest)?.len();
+            if src_size == dest_size {
+                return Ok(None);
+            }
+            vec![
+                ("src_size".into(), src_size.into()),
+                ("dest_size".into(), dest_size.into()),
+            ]
+        }
+        Verify::Checksum => {
+            let src_sha256 = sha256_file(src)?;
+            let dest_sha256 = sha256_file(dest)?;
+            if src_sha256 == dest_sha256 {
+                return Ok(None);
+            }
+            vec![
+                ("src_sha256".into(), src_sha256.into()),
+                ("dest_sha256".into(), dest_sha256.into()),
+            ]
+        }
+    };
+    let removed = cleanup && std::fs::remove_file(dest).is_ok();
+    data.push(("removed".into(), removed.into()));
+    Ok(Some(RpcError::verify_failed(
+        &dest.to_string_lossy(),
+        Value::Map(data),
+    )))
+}
+
+/// Hex sha256 digest of the file at `path`.
+pub(crate) fn sha256_file(path: &Path) -> std::io::Result<String> {
+    use sha2::{Digest, Sha256};
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut std::fs::File::open(path)?, &mut hasher)?;
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect())
+}
+
+/// Whether `dest` was last modified before `src`.
+fn is_older(dest: &std::fs::Metadata, src: &std::fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    (dest.mtime(), dest.mtime_nsec()) < (src.mtime(), src.mtime_nsec())
+}
+
+/// Like `std::fs::copy`, but fail with EEXIST if `dest` exists.
+fn copy_file_exclusive(src: &Path, dest: &Path) -> std::io::Result<u64> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let mut reader = std::fs::File::open(src)?;
+    let permissions = reader.metadata()?.permissions();
+    let mut writer = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(dest)?;
+    let copied = std::io::copy(&mut reader, &mut writer)
+        .and_then(|n| writer.set_permissions(permissions).map(|()| n));
+    if copied.is_err() {
+        let _ = std::fs::remove_file(dest);
+    }
+    copied
 }
 
 async fn prepare_symlink_destination(path: &Path, overwrite: bool) -> std::io::Result<()> {
@@ -413,10 +1428,15 @@ fn already_exists(path: &Path) -> std::io::Error {
 }
 
 async fn apply_copied_metadata(
+    src: &Path,
     src_meta: &std::fs::Metadata,
     dest: &Path,
     options: CopyOptions,
+    state: &mut CopyState,
 ) -> std::io::Result<()> {
+    // Ownership first, as chown clears the set-user-ID and set-group-ID bits
+    preserve_ownership_and_xattrs(src, dest, false, options, state).await?;
+
     if options.preserve_permissions {
         fs::set_permissions(dest, src_meta.permissions()).await?;
     }
@@ -431,17 +1451,177 @@ async fn apply_copied_metadata(
             let mtime = src_meta.mtime();
             let mtime_nsec = src_meta.mtime_nsec();
             let dest = dest.to_path_buf();
-            tokio::task::spawn_blocking(move || {
+            crate::blocking::run(dest.clone(), move || {
                 set_file_times_sync_path_io(&dest, atime, atime_nsec, mtime, mtime_nsec, false)
             })
             .await
-            .map_err(std::io::Error::other)??;
+            .map_err(|e| std::io::Error::other(e.message))??;
         }
     }
 
     Ok(())
 }
 
+/// Give `dest` the owner, group and xattrs of `src` as far as `options`
+/// asks, recording what could not be preserved in `state`.  With `link`,
+/// both are symlinks and the links themselves are changed.
+async fn preserve_ownership_and_xattrs(
+    src: &Path,
+    dest: &Path,
+    link: bool,
+    options: CopyOptions,
+    state: &mut CopyState,
+) -> std::io::Result<()> {
+    if !options.preserve_ownership && !options.preserve_xattrs {
+        return Ok(());
+    }
+    let (from, to) = (src.to_path_buf(), dest.to_path_buf());
+    let failures = state.blocking(
+        crate::blocking::run(src.to_path_buf(), move || {
+            copy_ownership_and_xattrs(&from, &to, link, options)
+        })
+        .await,
+    )?;
+    for (class, reason) in failures {
+        state.not_preserved(class, reason);
+    }
+    Ok(())
+}
+
+/// The blocking part of [`preserve_ownership_and_xattrs`], returning the
+/// classes it failed to preserve and why.  Only failing to stat `src` is an
+/// error.
+fn copy_ownership_and_xattrs(
+    src: &Path,
+    dest: &Path,
+    link: bool,
+    options: CopyOptions,
+) -> std::io::Result<Vec<(&'static str, String)>> {
+    use std::os::unix::fs::MetadataExt;
+
+    let mut failures = Vec::new();
+    if options.preserve_ownership {
+        let meta = std::fs::symlink_metadata(src)?;
+        let changed = if link {
+            std::os::unix::fs::lchown(dest, Some(meta.uid()), Some(meta.gid()))
+        } else {
+            std::os::unix::fs::chown(dest, Some(meta.uid()), Some(meta.gid()))
+        };
+        if let Err(e) = changed {
+            failures.push(("ownership", format!("{}: {}", dest.display(), e)));
+        }
+    }
+    #[cfg(target_os = "linux")]
+    if options.preserve_xattrs {
+        copy_xattrs(src, dest, link, options.system_xattrs, &mut failures);
+    }
+    Ok(failures)
+}
+
+/// Copy the user.* xattrs of `src` to `dest`, and with `system` all the
+/// others too, pushing the failures to `failures`.
+#[cfg(target_os = "linux")]
+fn copy_xattrs(
+    src: &Path,
+    dest: &Path,
+    link: bool,
+    system: bool,
+    failures: &mut Vec<(&'static str, String)>,
+) {
+    let (Ok(src_c), Ok(dest_c)) = (path_cstring(src), path_cstring(dest)) else {
+        return;
+    };
+    let names = xattr_buffer(|buf, size| unsafe {
+        if link {
+            libc::llistxattr(src_c.as_ptr(), buf.cast(), size)
+        } else {
+            libc::listxattr(src_c.as_ptr(), buf.cast(), size)
+        }
+    });
+    let names = match names {
+        Ok(names) => names,
+        // Nothing to copy from a filesystem without xattrs
+        Err(e) if e.raw_os_error() == Some(libc::ENOTSUP) => return,
+        Err(e) => {
+            failures.push(("xattrs", format!("{}: {}", src.display(), e)));
+            return;
+        }
+    };
+    for name in names.split(|b| *b == 0).filter(|name| !name.is_empty()) {
+        let class = if name.starts_with(b"user.") {
+            "xattrs"
+        } else if system {
+            "system_xattrs"
+        } else {
+            continue;
+        };
+        let Ok(name) = std::ffi::CString::new(name) else {
+            continue;
+        };
+        let value = xattr_buffer(|buf, size| unsafe {
+            if link {
+                libc::lgetxattr(src_c.as_ptr(), name.as_ptr(), buf, size)
+            } else {
+                libc::getxattr(src_c.as_ptr(), name.as_ptr(), buf, size)
+            }
+        });
+        let set = value.and_then(|value| {
+            let rc = unsafe {
+                if link {
+                    libc::lsetxattr(
+                        dest_c.as_ptr(),
+                        name.as_ptr(),
+                        value.as_ptr().cast(),
+                        value.len(),
+                        0,
+                    )
+                } else {
+                    libc::setxattr(
+                        dest_c.as_ptr(),
+                        name.as_ptr(),
+                        value.as_ptr().cast(),
+                        value.len(),
+                        0,
+                    )
+                }
+            };
+            if rc == 0 {
+                Ok(())
+            } else {
+                Err(std::io::Error::last_os_error())
+            }
+        });
+        if let Err(e) = set {
+            failures.push((
+                class,
+                format!("{} on {}: {}", name.to_string_lossy(), dest.display(), e),
+            ));
+        }
+    }
+}
+
+/// The contents of an xattr list or value, fetched with `call(buf, size)`:
+/// first sized with an empty buffer, then again if it grew meanwhile.
+#[cfg(target_os = "linux")]
+fn xattr_buffer(call: impl Fn(*mut libc::c_void, usize) -> isize) -> std::io::Result<Vec<u8>> {
+    loop {
+        let size = call(std::ptr::null_mut(), 0);
+        if size < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let mut buf = vec![0u8; size as usize];
+        let n = call(buf.as_mut_ptr().cast(), buf.len());
+        if n >= 0 {
+            buf.truncate(n as usize);
+            return Ok(buf);
+        }
+        let e = std::io::Error::last_os_error();
+        if e.raw_os_error() != Some(libc::ERANGE) {
+            return Err(e);
+        }
+    }
+}
+
 async fn remove_path_for_overwrite(path: &Path) -> std::io::Result<()> {
     let meta = fs::symlink_metadata(path).await?;
     if meta.is_dir() && !meta.file_type().is_symlink() {
@@ -462,29 +1642,67 @@ pub async fn rename(params: Value) -> HandlerResult {
         /// Overwrite destination if it exists
         #[serde(default)]
         overwrite: bool,
+        /// Atomically swap `src` and `dest`, which must both exist
+        #[serde(default)]
+        exchange: bool,
+        /// Include the attributes of `dest` after the rename as `attrs` and
+        /// those `src` had before it as `old_attrs`
+        #[serde(default)]
+        return_attrs: bool,
     }
 
     let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
 
     let src = bytes_to_path(&params.src);
     let dest = bytes_to_path(&params.dest);
-    let dest_str = dest.to_string_lossy().into_owned();
-    let src_str = src.to_string_lossy().into_owned();
-
-    // Check if destination exists and overwrite is false
-    if !params.overwrite && dest.exists() {
-        return Err(RpcError {
-            code: RpcError::IO_ERROR,
-            message: format!("Destination already exists: {}", dest_str),
-            data: None,
-        });
-    }
+    jail::check(&src)?;
+    jail::check(&dest)?;
 
-    fs::rename(&src, &dest)
-        .await
-        .map_err(|e| map_io_error(e, &src_str))?;
+    let old_attrs = if params.return_attrs {
+        Some(attrs_after(&src, true).await)
+    } else {
+        None
+    };
 
-    Ok(Value::Boolean(true))
+    let result = if params.exchange {
+        let (from, to) = (src.clone(), dest.clone());
+        crate::blocking::run(&src, move || rename_exchange(&from, &to))
+            .await?
+            .map_err(|e| map_io_error(e, &src))
+    } else if params.overwrite {
+        fs::rename(&src, &dest)
+            .await
+            .map_err(|e| map_io_error(e, &src))
+    } else {
+        let (from, to) = (src.clone(), dest.clone());
+        crate::blocking::run(&src, move || rename_noreplace(&from, &to))
+            .await?
+            .map_err(|e| {
+                if e.kind() == std::io::ErrorKind::AlreadyExists {
+                    RpcError {
+                        code: RpcError::IO_ERROR,
+                        message: format!("Destination already exists: {}", dest.display()),
+                        data: None,
+                    }
+                } else {
+                    map_io_error(e, &src)
+                }
+            })
+    };
+    result.map_err(|e| with_src_dest(e, &src, &dest))?;
+    stat_cache::invalidate_tree(&src).await;
+    stat_cache::invalidate_tree(&dest).await;
+
+    Ok(match old_attrs {
+        Some(old_attrs) => with_attrs(
+            Value::Boolean(true),
+            vec![
+                ("attrs", attrs_after(&dest, true).await),
+                ("old_attrs", old_attrs),
+            ],
+        ),
+        None => Value::Boolean(true),
+    })
 }
 
 /// Delete a file
@@ -501,14 +1719,16 @@ pub async fn delete(params: Value) -> HandlerResult {
     let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
 
     let path = bytes_to_path(&params.path);
-    let path_str = path.to_string_lossy().into_owned();
+    jail::check(&path)?;
 
-    match fs::remove_file(&path).await {
+    let result = fs::remove_file(&path).await;
+    stat_cache::invalidate(&path).await;
+    match result {
         Ok(()) => Ok(Value::Boolean(true)),
         Err(e) if params.force && e.kind() == std::io::ErrorKind::NotFound => {
             Ok(Value::Boolean(false))
         }
-        Err(e) => Err(map_io_error(e, &path_str)),
+        Err(e) => Err(map_io_error(e, &path)),
     }
 }
 
@@ -519,21 +1739,183 @@ pub async fn set_modes(params: Value) -> HandlerResult {
         #[serde(with = "path_or_bytes")]
         path: Vec<u8>,
         mode: u32,
+        /// Include the attributes after the change as `attrs`
+        #[serde(default)]
+        return_attrs: bool,
     }
 
     let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
 
     let path = bytes_to_path(&params.path);
-    let path_str = path.to_string_lossy().into_owned();
+    jail::check(&path)?;
 
     let perms = std::fs::Permissions::from_mode(params.mode);
     fs::set_permissions(&path, perms)
         .await
-        .map_err(|e| map_io_error(e, &path_str))?;
+        .map_err(|e| map_io_error(e, &path))?;
+    stat_cache::invalidate(&path).await;
+
+    if params.return_attrs {
+        return Ok(with_attrs(
+            Value::Boolean(true),
+            vec![("attrs", attrs_after(&path, false).await)],
+        ));
+    }
+    Ok(Value::Boolean(true))
+}
+
+/// Set or clear the immutable, append-only, no-dump and hidden file flags.
+/// Flags that are not mentioned are left as they are.
+///
+/// On macOS and FreeBSD these are the user flags (`uchg`, `uappnd`,
+/// `nodump`, `hidden`); on Linux the chattr `i`, `a` and `d` flags set with
+/// `FS_IOC_SETFLAGS`, where `hidden` does not exist.  Setting immutable or
+/// append-only on Linux takes `CAP_LINUX_IMMUTABLE`.
+pub async fn set_flags(params: Value) -> HandlerResult {
+    #[derive(Deserialize)]
+    struct Params {
+        #[serde(with = "path_or_bytes")]
+        path: Vec<u8>,
+        #[serde(default)]
+        immutable: Option<bool>,
+        #[serde(default)]
+        append: Option<bool>,
+        #[serde(default)]
+        nodump: Option<bool>,
+        #[serde(default)]
+        hidden: Option<bool>,
+    }
+
+    let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
+
+    let path = bytes_to_path(&params.path);
+    jail::check(&path)?;
+
+    let result = {
+        let path = path.clone();
+        let changes = FlagChanges {
+            immutable: params.immutable,
+            append: params.append,
+            nodump: params.nodump,
+            hidden: params.hidden,
+        };
+        crate::blocking::run(path.clone(), move || set_file_flags(&path, changes)).await?
+    };
+    result.map_err(|e| map_io_error(e, &path))?;
+    stat_cache::invalidate(&path).await;
 
     Ok(Value::Boolean(true))
 }
 
+/// What `file.fallocate` does to its range
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum AllocateMode {
+    /// Reserve blocks, growing the file if the range ends past it
+    Allocate,
+    /// Free the blocks, which then read as zeros; the size stays
+    PunchHole,
+    /// Make the range read as zeros, growing the file if need be
+    ZeroRange,
+}
+
+impl AllocateMode {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "allocate" => Some(Self::Allocate),
+            "punch_hole" => Some(Self::PunchHole),
+            "zero_range" => Some(Self::ZeroRange),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Allocate => "allocate",
+            Self::PunchHole => "punch_hole",
+            Self::ZeroRange => "zero_range",
+        }
+    }
+}
+
+/// Preallocate or deallocate part of a file: `file.fallocate {path, mode?,
+/// offset?, length}`, with `mode` one of `allocate` (the default),
+/// `punch_hole` or `zero_range`.
+///
+/// Returns `{size, blocks, allocated}` afterwards, as in `file.hole_map`.
+/// A mode the filesystem or platform lacks fails with UNSUPPORTED, so the
+/// client can write zeros instead; macOS only allocates.
+pub async fn fallocate(params: Value) -> HandlerResult {
+    #[derive(Deserialize)]
+    struct Params {
+        #[serde(with = "path_or_bytes")]
+        path: Vec<u8>,
+        #[serde(default)]
+        mode: Option<String>,
+        #[serde(default)]
+        offset: u64,
+        length: u64,
+    }
+
+    let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
+    let mode = match params.mode.as_deref() {
+        None => AllocateMode::Allocate,
+        Some(name) => AllocateMode::parse(name).ok_or_else(|| {
+            RpcError::invalid_params(format!(
+                "Unknown mode {:?}: expected allocate, punch_hole or zero_range",
+                name
+            ))
+        })?,
+    };
+    if params.length == 0 {
+        return Err(RpcError::invalid_params("length must be positive"));
+    }
+    if params
+        .offset
+        .checked_add(params.length)
+        .is_none_or(|end| end > i64::MAX as u64)
+    {
+        return Err(RpcError::invalid_params("offset + length is too large"));
+    }
+
+    let path = bytes_to_path(&params.path);
+    jail::check(&path)?;
+    let path_str = path.to_string_lossy().into_owned();
+
+    let guard = write_lock::lock(&path).await;
+    let (offset, length) = (params.offset, params.length);
+    let result = {
+        let path = path.clone();
+        crate::blocking::run(path.clone(), move || {
+            let _guard = guard;
+            let file = std::fs::OpenOptions::new().write(true).open(&path)?;
+            allocate_range(&file, mode, offset, length)?;
+            file.metadata()
+        })
+        .await?
+    };
+    stat_cache::invalidate(&path).await;
+    let metadata = result.map_err(|e| {
+        // The same errno on Linux, but not on macOS
+        let unsupported = e
+            .raw_os_error()
+            .is_some_and(|errno| errno == libc::EOPNOTSUPP || errno == libc::ENOTSUP);
+        if unsupported {
+            RpcError::unsupported(&format!("fallocate {}", mode.name()), &path_str)
+                .with_data("mode", mode.name().into())
+                .with_data("path", crate::protocol::path_value(&path))
+        } else {
+            map_io_error(e, &path)
+        }
+    })?;
+
+    use std::os::unix::fs::MetadataExt;
+    Ok(msgpack_map! {
+        "size" => metadata.len(),
+        "blocks" => metadata.blocks(),
+        "allocated" => metadata.blocks().saturating_mul(512)
+    })
+}
+
 /// Set file timestamps
 pub async fn set_times(params: Value) -> HandlerResult {
     #[derive(Deserialize)]
@@ -548,24 +1930,36 @@ pub async fn set_times(params: Value) -> HandlerResult {
         /// If true, update a symlink itself instead of following it.
         #[serde(default)]
         nofollow: bool,
+        /// Include the attributes after the change as `attrs`
+        #[serde(default)]
+        return_attrs: bool,
     }
 
     let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
 
     let path = bytes_to_path(&params.path);
+    jail::check(&path)?;
     let path_str = path.to_string_lossy().into_owned();
     let atime = params.atime.unwrap_or(params.mtime);
     let mtime = params.mtime;
     let nofollow = params.nofollow;
 
-    // Use spawn_blocking for the libc syscall
-    tokio::task::spawn_blocking(move || {
+    let cache_path = path.clone();
+
+    // Run the libc syscall on the blocking pool
+    crate::blocking::run(path.clone(), move || {
         set_file_times_sync_path_io(&path, atime, 0, mtime, 0, nofollow)
     })
-    .await
-    .map_err(|e| RpcError::internal_error(e.to_string()))?
+    .await?
     .map_err(|e| map_io_error(e, &path_str))?;
+    stat_cache::invalidate(&cache_path).await;
 
+    if params.return_attrs {
+        return Ok(with_attrs(
+            Value::Boolean(true),
+            vec![("attrs", attrs_after(&cache_path, nofollow).await)],
+        ));
+    }
     Ok(Value::Boolean(true))
 }
 
@@ -583,24 +1977,24 @@ pub async fn make_symlink(params: Value) -> HandlerResult {
 
     let target = bytes_to_path(&params.target);
     let link_path = bytes_to_path(&params.link_path);
-    let link_path_str = link_path.to_string_lossy().into_owned();
+    jail::check(&link_path)?;
+    jail::check_link_target(&link_path, &target)?;
 
     #[cfg(unix)]
     {
         // Try creating the symlink; if it already exists, remove it and retry
         // (matching `ln -sf` behavior needed by tramp lock files).
-        match fs::symlink(&target, &link_path).await {
-            Ok(()) => {}
+        let result = match fs::symlink(&target, &link_path).await {
             Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
-                fs::remove_file(&link_path)
-                    .await
-                    .map_err(|e| map_io_error(e, &link_path_str))?;
-                fs::symlink(&target, &link_path)
-                    .await
-                    .map_err(|e| map_io_error(e, &link_path_str))?;
+                match fs::remove_file(&link_path).await {
+                    Ok(()) => fs::symlink(&target, &link_path).await,
+                    Err(e) => Err(e),
+                }
             }
-            Err(e) => return Err(map_io_error(e, &link_path_str)),
-        }
+            result => result,
+        };
+        stat_cache::invalidate(&link_path).await;
+        result.map_err(|e| with_src_dest(map_io_error(e, &link_path), &target, &link_path))?;
     }
 
     Ok(Value::Boolean(true))
@@ -622,11 +2016,15 @@ pub async fn make_hardlink(params: Value) -> HandlerResult {
 
     let src = bytes_to_path(&params.src);
     let dest = bytes_to_path(&params.dest);
-    let dest_str = dest.to_string_lossy().into_owned();
+    jail::check(&src)?;
+    jail::check(&dest)?;
 
     fs::hard_link(&src, &dest)
         .await
-        .map_err(|e| map_io_error(e, &dest_str))?;
+        .map_err(|e| with_src_dest(map_io_error(e, &dest), &src, &dest))?;
+    // The link count of the source changes too.
+    stat_cache::invalidate(&src).await;
+    stat_cache::invalidate(&dest).await;
 
     Ok(Value::Boolean(true))
 }
@@ -646,11 +2044,13 @@ pub async fn chown(params: Value) -> HandlerResult {
     let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
 
     let path = bytes_to_path(&params.path);
+    jail::check(&path)?;
     let uid = params.uid;
     let gid = params.gid;
+    let cache_path = path.clone();
 
-    // Use spawn_blocking for the libc syscall
-    tokio::task::spawn_blocking(move || {
+    // Run the libc syscall on the blocking pool
+    crate::blocking::run(path.clone(), move || {
         use std::os::unix::ffi::OsStrExt;
         let path_bytes = path.as_os_str().as_bytes();
         let mut path_cstr = path_bytes.to_vec();
@@ -669,8 +2069,8 @@ pub async fn chown(params: Value) -> HandlerResult {
         }
         Ok(())
     })
-    .await
-    .map_err(|e| RpcError::internal_error(e.to_string()))??;
+    .await??;
+    stat_cache::invalidate(&cache_path).await;
 
     Ok(Value::Boolean(true))
 }
@@ -679,8 +2079,286 @@ pub async fn chown(params: Value) -> HandlerResult {
 // Helper functions
 // ============================================================================
 
+fn path_cstring(path: &Path) -> std::io::Result<std::ffi::CString> {
+    use std::os::unix::ffi::OsStrExt;
+    std::ffi::CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))
+}
+
+/// `renameat2(2)` via syscall, since older glibc and musl lack the wrapper.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn renameat2(src: &Path, dest: &Path, flags: libc::c_uint) -> std::io::Result<()> {
+    let (src, dest) = (path_cstring(src)?, path_cstring(dest)?);
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_renameat2,
+            libc::AT_FDCWD,
+            src.as_ptr(),
+            libc::AT_FDCWD,
+            dest.as_ptr(),
+            flags,
+        )
+    };
+    if result != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "macos")]
+fn renamex_np(src: &Path, dest: &Path, flags: libc::c_uint) -> std::io::Result<()> {
+    let (src, dest) = (path_cstring(src)?, path_cstring(dest)?);
+    if unsafe { libc::renamex_np(src.as_ptr(), dest.as_ptr(), flags) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Rename `src` to `dest`, failing with `AlreadyExists` instead of replacing
+/// an existing `dest`.  The check and the rename are one atomic operation,
+/// so concurrent renames to the same destination cannot clobber each other.
+fn rename_noreplace(src: &Path, dest: &Path) -> std::io::Result<()> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    let native = renameat2(src, dest, libc::RENAME_NOREPLACE);
+    #[cfg(target_os = "macos")]
+    let native = renamex_np(src, dest, libc::RENAME_EXCL);
+    #[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos")))]
+    let native: std::io::Result<()> = Err(std::io::Error::from_raw_os_error(libc::ENOTSUP));
+
+    match native {
+        // Kernel or filesystem without support: fall back to link + unlink,
+        // which is just as atomic for everything except directories.
+        Err(e)
+            if matches!(
+                e.raw_os_error(),
+                Some(libc::EINVAL | libc::ENOSYS | libc::ENOTSUP)
+            ) =>
+        {
+            if std::fs::symlink_metadata(src)?.is_dir() {
+                // Directories cannot be hard-linked; this is the best we can do.
+                if std::fs::symlink_metadata(dest).is_ok() {
+                    return Err(std::io::Error::from_raw_os_error(libc::EEXIST));
+                }
+                return std::fs::rename(src, dest);
+            }
+            std::fs::hard_link(src, dest)?;
+            std::fs::remove_file(src)
+        }
+        result => result,
+    }
+}
+
+/// The flags `file.set_flags` changes: `Some(true)` sets, `Some(false)`
+/// clears and `None` leaves a flag alone
+#[derive(Debug, Clone, Copy)]
+struct FlagChanges {
+    immutable: Option<bool>,
+    append: Option<bool>,
+    nodump: Option<bool>,
+    hidden: Option<bool>,
+}
+
+impl FlagChanges {
+    /// Apply the changes in `bits`, pairs of a change and its flag bit, to
+    /// `flags`.
+    fn apply(flags: u64, bits: &[(Option<bool>, u64)]) -> u64 {
+        bits.iter()
+            .fold(flags, |flags, &(enable, bit)| match enable {
+                Some(true) => flags | bit,
+                Some(false) => flags & !bit,
+                None => flags,
+            })
+    }
+}
+
+#[cfg(any(target_os = "macos", target_os = "freebsd"))]
+fn set_file_flags(path: &Path, changes: FlagChanges) -> std::io::Result<()> {
+    #[cfg(target_os = "freebsd")]
+    use std::os::freebsd::fs::MetadataExt;
+    #[cfg(target_os = "macos")]
+    use std::os::macos::fs::MetadataExt;
+
+    // chflags takes a c_uint on macOS and a c_ulong on FreeBSD
+    let flags = FlagChanges::apply(
+        std::fs::metadata(path)?.st_flags() as u64,
+        &[
+            (changes.immutable, libc::UF_IMMUTABLE as u64),
+            (changes.append, libc::UF_APPEND as u64),
+            (changes.nodump, libc::UF_NODUMP as u64),
+            (changes.hidden, libc::UF_HIDDEN as u64),
+        ],
+    );
+    let path = path_cstring(path)?;
+    if unsafe { libc::chflags(path.as_ptr(), flags as _) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn set_file_flags(path: &Path, changes: FlagChanges) -> std::io::Result<()> {
+    use super::file::{
+        FS_APPEND_FL, FS_IMMUTABLE_FL, FS_IOC_GETFLAGS, FS_IOC_SETFLAGS, FS_NODUMP_FL,
+    };
+    use std::os::fd::AsRawFd;
+
+    let unsupported = || std::io::Error::from_raw_os_error(libc::ENOTSUP);
+    if changes.hidden.is_some() {
+        return Err(unsupported());
+    }
+    let file = super::file::open_for_flags(path)?.ok_or_else(unsupported)?;
+    let ioctl_error = || {
+        let err = std::io::Error::last_os_error();
+        if super::file::flags_unsupported(&err) {
+            unsupported()
+        } else {
+            err
+        }
+    };
+    let mut flags: libc::c_int = 0;
+    if unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_GETFLAGS as _, &mut flags) } != 0 {
+        return Err(ioctl_error());
+    }
+    let flags = FlagChanges::apply(
+        flags as u64,
+        &[
+            (changes.immutable, FS_IMMUTABLE_FL as u64),
+            (changes.append, FS_APPEND_FL as u64),
+            (changes.nodump, FS_NODUMP_FL as u64),
+        ],
+    ) as libc::c_int;
+    if unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_SETFLAGS as _, &flags) } != 0 {
+        return Err(ioctl_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "freebsd")))]
+fn set_file_flags(_path: &Path, _changes: FlagChanges) -> std::io::Result<()> {
+    Err(std::io::Error::from_raw_os_error(libc::ENOTSUP))
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn allocate_range(
+    file: &std::fs::File,
+    mode: AllocateMode,
+    offset: u64,
+    length: u64,
+) -> std::io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let flags = match mode {
+        AllocateMode::Allocate => 0,
+        AllocateMode::PunchHole => libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+        AllocateMode::ZeroRange => libc::FALLOC_FL_ZERO_RANGE,
+    };
+    // The caller checked that both fit in an off_t
+    let ret = unsafe {
+        libc::fallocate(
+            file.as_raw_fd(),
+            flags,
+            offset as libc::off_t,
+            length as libc::off_t,
+        )
+    };
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "macos")]
+fn allocate_range(
+    file: &std::fs::File,
+    mode: AllocateMode,
+    offset: u64,
+    length: u64,
+) -> std::io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    if mode != AllocateMode::Allocate {
+        return Err(std::io::Error::from_raw_os_error(libc::ENOTSUP));
+    }
+    let end = offset + length;
+    // Allocated from the physical end of the file, so a little may be
+    // reserved past the range; contiguous if possible
+    let mut store = libc::fstore_t {
+        fst_flags: libc::F_ALLOCATECONTIG,
+        fst_posmode: libc::F_PEOFPOSMODE,
+        fst_offset: 0,
+        fst_length: end as libc::off_t,
+        fst_bytesalloc: 0,
+    };
+    let fd = file.as_raw_fd();
+    if unsafe { libc::fcntl(fd, libc::F_PREALLOCATE, &mut store) } == -1 {
+        store.fst_flags = libc::F_ALLOCATEALL;
+        if unsafe { libc::fcntl(fd, libc::F_PREALLOCATE, &mut store) } == -1 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    // Like fallocate(2), allocating past the end grows the file
+    if file.metadata()?.len() < end {
+        file.set_len(end)?;
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "freebsd")]
+fn allocate_range(
+    file: &std::fs::File,
+    mode: AllocateMode,
+    offset: u64,
+    length: u64,
+) -> std::io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    if mode != AllocateMode::Allocate {
+        return Err(std::io::Error::from_raw_os_error(libc::ENOTSUP));
+    }
+    // posix_fallocate returns the error instead of setting errno
+    match unsafe {
+        libc::posix_fallocate(
+            file.as_raw_fd(),
+            offset as libc::off_t,
+            length as libc::off_t,
+        )
+    } {
+        0 => Ok(()),
+        libc::EINVAL => Err(std::io::Error::from_raw_os_error(libc::ENOTSUP)),
+        errno => Err(std::io::Error::from_raw_os_error(errno)),
+    }
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "freebsd"
+)))]
+fn allocate_range(
+    _file: &std::fs::File,
+    _mode: AllocateMode,
+    _offset: u64,
+    _length: u64,
+) -> std::io::Result<()> {
+    Err(std::io::Error::from_raw_os_error(libc::ENOTSUP))
+}
+
+/// Atomically exchange `src` and `dest`; both must exist.
+fn rename_exchange(src: &Path, dest: &Path) -> std::io::Result<()> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    return renameat2(src, dest, libc::RENAME_EXCHANGE);
+    #[cfg(target_os = "macos")]
+    return renamex_np(src, dest, libc::RENAME_SWAP);
+    #[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos")))]
+    {
+        let _ = (src, dest);
+        Err(std::io::Error::from_raw_os_error(libc::ENOTSUP))
+    }
+}
+
 #[cfg(unix)]
-fn set_file_times_sync_path_io(
+pub(crate) fn set_file_times_sync_path_io(
     path: &Path,
     atime: i64,
     atime_nsec: i64,
@@ -843,20 +2521,96 @@ mod tests {
         );
     }
 
+    #[cfg(target_os = "linux")]
     #[tokio::test]
-    async fn copy_directory_preserves_file_and_directory_times() {
+    async fn preserving_copies_report_ownership_and_xattrs() {
+        use std::os::unix::fs::MetadataExt;
+
         let tmp = tempfile::tempdir().expect("create tempdir");
         let src = tmp.path().join("src");
-        let child = src.join("child");
-        let file = child.join("file.txt");
         let dest = tmp.path().join("dest");
+        fs::create_dir(&src).await.unwrap();
+        fs::write(src.join("file"), b"payload").await.unwrap();
+        std::os::unix::fs::symlink("file", src.join("link")).unwrap();
+        let root = unsafe { libc::geteuid() } == 0;
+        if root {
+            std::os::unix::fs::chown(src.join("file"), Some(1234), Some(4321)).unwrap();
+            std::os::unix::fs::lchown(src.join("link"), Some(1234), Some(4321)).unwrap();
+        }
+        let file_c = path_cstring(&src.join("file")).unwrap();
+        let xattrs = unsafe {
+            libc::setxattr(
+                file_c.as_ptr(),
+                c"user.origin".as_ptr(),
+                b"here".as_ptr().cast(),
+                4,
+                0,
+            )
+        } == 0;
 
-        fs::create_dir_all(&child).await.unwrap();
-        fs::write(&file, b"payload").await.unwrap();
-
-        let src_time = 1_600_000_001;
-        let child_time = 1_600_000_002;
-        let file_time = 1_600_000_003;
+        let result = copy(msgpack_map! {
+            "src" => path_value(&src),
+            "dest" => path_value(&dest),
+            "exact_dest" => true,
+            "preserve" => true,
+        })
+        .await
+        .unwrap();
+        let preserved: Vec<&str> = result["preserved"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter_map(Value::as_str)
+            .collect();
+        assert!(preserved.contains(&"permissions"));
+        assert!(preserved.contains(&"times"));
+        if root {
+            assert!(preserved.contains(&"ownership"));
+            let meta = fs::metadata(dest.join("file")).await.unwrap();
+            assert_eq!((meta.uid(), meta.gid()), (1234, 4321));
+            let meta = fs::symlink_metadata(dest.join("link")).await.unwrap();
+            assert_eq!((meta.uid(), meta.gid()), (1234, 4321));
+        } else {
+            assert!(!preserved.contains(&"ownership"));
+            assert_eq!(
+                result["not_preserved"][0]["reason"].as_str(),
+                Some("the server is not running as root")
+            );
+        }
+        if xattrs {
+            assert!(preserved.contains(&"xattrs"));
+            let dest_c = path_cstring(&dest.join("file")).unwrap();
+            let value = xattr_buffer(|buf, size| unsafe {
+                libc::getxattr(dest_c.as_ptr(), c"user.origin".as_ptr(), buf, size)
+            })
+            .unwrap();
+            assert_eq!(value, b"here");
+        }
+
+        // Plain copies report nothing
+        let result = copy(msgpack_map! {
+            "src" => path_value(&src.join("file")),
+            "dest" => path_value(&tmp.path().join("plain")),
+        })
+        .await
+        .unwrap();
+        assert_eq!(result["preserved"], Value::Nil);
+    }
+
+    #[tokio::test]
+    async fn copy_directory_preserves_file_and_directory_times() {
+        let tmp = tempfile::tempdir().expect("create tempdir");
+        let src = tmp.path().join("src");
+        let child = src.join("child");
+        let file = child.join("file.txt");
+        let dest = tmp.path().join("dest");
+
+        fs::create_dir_all(&child).await.unwrap();
+        fs::write(&file, b"payload").await.unwrap();
+
+        let src_time = 1_600_000_001;
+        let child_time = 1_600_000_002;
+        let file_time = 1_600_000_003;
         let src_nsec = 101_000_001;
         let child_nsec = 102_000_002;
         let file_nsec = 103_000_003;
@@ -943,6 +2697,96 @@ mod tests {
         assert!(err.message.contains("Cannot copy directory"));
     }
 
+    #[tokio::test]
+    async fn copy_directory_rejects_copy_into_ancestor() {
+        let tmp = tempfile::tempdir().expect("create tempdir");
+        let dest = tmp.path().join("dest");
+        let src = dest.join("src");
+        fs::create_dir_all(&src).await.unwrap();
+
+        let err = copy(msgpack_map! {
+            "src" => path_value(&src),
+            "dest" => path_value(&dest),
+            "overwrite" => true,
+            "exact_dest" => true,
+        })
+        .await
+        .expect_err("merging a directory into its ancestor must be rejected");
+
+        assert!(err.message.contains("into its ancestor"));
+    }
+
+    #[tokio::test]
+    async fn copy_directory_dereference_follows_links_and_detects_loops() {
+        let tmp = tempfile::tempdir().expect("create tempdir");
+        let src = tmp.path().join("src");
+        let outside = tmp.path().join("outside");
+        fs::create_dir_all(src.join("sub")).await.unwrap();
+        fs::create_dir_all(&outside).await.unwrap();
+        fs::write(outside.join("file.txt"), b"linked")
+            .await
+            .unwrap();
+        tokio::fs::symlink(&outside, src.join("linked-dir"))
+            .await
+            .unwrap();
+
+        let dest = tmp.path().join("copy");
+        copy(msgpack_map! {
+            "src" => path_value(&src),
+            "dest" => path_value(&dest),
+            "exact_dest" => true,
+            "dereference" => true,
+        })
+        .await
+        .expect("dereferencing copy should succeed");
+        let copied = dest.join("linked-dir");
+        assert!(!fs::symlink_metadata(&copied).await.unwrap().is_symlink());
+        assert_eq!(fs::read(copied.join("file.txt")).await.unwrap(), b"linked");
+
+        // A link back up to the source root is a cycle once followed...
+        tokio::fs::symlink("..", src.join("sub/up")).await.unwrap();
+        let err = copy(msgpack_map! {
+            "src" => path_value(&src),
+            "dest" => path_value(&tmp.path().join("loop")),
+            "exact_dest" => true,
+            "dereference" => true,
+        })
+        .await
+        .expect_err("following a symlink loop must fail");
+        assert!(err.message.contains("cycle"));
+
+        // ...but is copied as a plain link by default.
+        let dest = tmp.path().join("links");
+        copy(msgpack_map! {
+            "src" => path_value(&src),
+            "dest" => path_value(&dest),
+            "exact_dest" => true,
+        })
+        .await
+        .expect("copying links as links should succeed");
+        assert_eq!(
+            fs::read_link(dest.join("sub/up")).await.unwrap(),
+            Path::new("..")
+        );
+    }
+
+    #[tokio::test]
+    async fn copy_directory_enforces_max_depth() {
+        let tmp = tempfile::tempdir().expect("create tempdir");
+        let src = tmp.path().join("src");
+        fs::create_dir_all(src.join("a/b/c")).await.unwrap();
+
+        let err = copy(msgpack_map! {
+            "src" => path_value(&src),
+            "dest" => path_value(&tmp.path().join("dest")),
+            "exact_dest" => true,
+            "max_depth" => 2,
+        })
+        .await
+        .expect_err("copy deeper than max_depth must fail");
+        assert!(err.message.contains("maximum depth"));
+    }
+
     #[tokio::test]
     async fn copy_regular_file_without_overwrite_rejects_existing_file() {
         let tmp = tempfile::tempdir().expect("create tempdir");
@@ -963,6 +2807,213 @@ mod tests {
         assert_eq!(fs::read(&dest).await.unwrap(), b"old");
     }
 
+    #[tokio::test]
+    async fn copy_without_overwrite_reports_eexist() {
+        let tmp = tempfile::tempdir().expect("create tempdir");
+        let src = tmp.path().join("src.txt");
+        let dir = tmp.path().join("dir");
+        fs::write(&src, b"new").await.unwrap();
+        fs::create_dir(&dir).await.unwrap();
+        std::os::unix::fs::symlink("missing", dir.join("src.txt")).unwrap();
+
+        // The file name is appended first, so the dangling link is in the way
+        let err = copy(msgpack_map! {
+            "src" => path_value(&src),
+            "dest" => path_value(&dir),
+        })
+        .await
+        .expect_err("copy should fail without overwrite");
+        assert_eq!(
+            err.data.unwrap()["os_errno"].as_i64(),
+            Some(libc::EEXIST.into())
+        );
+        assert!(!tmp.path().join("missing").exists());
+    }
+
+    #[tokio::test]
+    async fn copy_without_following_recreates_top_level_symlinks() {
+        let tmp = tempfile::tempdir().expect("create tempdir");
+        let target = tmp.path().join("target.txt");
+        fs::write(&target, b"contents").await.unwrap();
+        let relative = tmp.path().join("relative");
+        let absolute = tmp.path().join("absolute");
+        std::os::unix::fs::symlink("target.txt", &relative).unwrap();
+        std::os::unix::fs::symlink(&target, &absolute).unwrap();
+        let dest_dir = tmp.path().join("out");
+        fs::create_dir(&dest_dir).await.unwrap();
+
+        for link in [&relative, &absolute] {
+            copy(msgpack_map! {
+                "src" => path_value(link),
+                "dest" => path_value(&dest_dir),
+                "follow_symlink" => false,
+            })
+            .await
+            .expect("copy symlink");
+            let copied = dest_dir.join(link.file_name().unwrap());
+            assert!(
+                fs::symlink_metadata(&copied)
+                    .await
+                    .unwrap()
+                    .file_type()
+                    .is_symlink()
+            );
+            assert_eq!(
+                fs::read_link(&copied).await.unwrap(),
+                fs::read_link(link).await.unwrap()
+            );
+        }
+
+        // The default still copies what the link points to
+        let followed = tmp.path().join("followed");
+        copy(msgpack_map! {
+            "src" => path_value(&relative),
+            "dest" => path_value(&followed),
+        })
+        .await
+        .expect("copy through symlink");
+        assert!(fs::symlink_metadata(&followed).await.unwrap().is_file());
+        assert_eq!(fs::read(&followed).await.unwrap(), b"contents");
+
+        let err = copy(msgpack_map! {
+            "src" => path_value(&relative),
+            "dest" => path_value(&dest_dir),
+            "follow_symlink" => false,
+        })
+        .await
+        .expect_err("existing link without overwrite");
+        assert!(err.message.contains("exists"));
+    }
+
+    #[tokio::test]
+    async fn copy_directory_applies_filters() {
+        let tmp = tempfile::tempdir().expect("create tempdir");
+        let src = tmp.path().join("project");
+        let dest = tmp.path().join("copy");
+        for dir in [".git/objects", "target/debug", "src/nested"] {
+            fs::create_dir_all(src.join(dir)).await.unwrap();
+        }
+        for (file, size) in [
+            (".git/HEAD", 4),
+            ("target/debug/app", 4),
+            ("src/main.rs", 4),
+            ("src/nested/lib.rs", 4),
+            ("src/main.rs~", 4),
+            ("src/big.rs", 4096),
+            ("README", 4),
+        ] {
+            fs::write(src.join(file), vec![b'x'; size]).await.unwrap();
+        }
+
+        let result = copy(msgpack_map! {
+            "src" => path_value(&src),
+            "dest" => path_value(&dest),
+            "exclude" => Value::Array(vec![".git".into(), "target/".into(), "*~".into()]),
+            "include_only" => Value::Array(vec!["*.rs".into()]),
+            "max_file_size" => 1024,
+        })
+        .await
+        .expect("filtered copy");
+
+        assert!(dest.join("src/main.rs").exists());
+        assert!(dest.join("src/nested/lib.rs").exists());
+        for skipped in [".git", "target", "src/main.rs~", "src/big.rs", "README"] {
+            assert!(!dest.join(skipped).exists(), "{skipped} was copied");
+        }
+        assert_eq!(result["entries"].as_u64(), Some(2));
+        // .git, target, main.rs~, big.rs and README
+        assert_eq!(result["filtered"].as_u64(), Some(5));
+        assert_eq!(result["copied"].as_u64(), Some(8));
+    }
+
+    #[tokio::test]
+    async fn copy_verifies_and_reports_mismatches() {
+        let tmp = tempfile::tempdir().expect("create tempdir");
+        let src = tmp.path().join("src.bin");
+        let dest = tmp.path().join("dest.bin");
+        fs::write(&src, vec![7u8; 100_000]).await.unwrap();
+
+        let result = copy(msgpack_map! {
+            "src" => path_value(&src),
+            "dest" => path_value(&dest),
+            "verify" => "checksum",
+        })
+        .await
+        .expect("verified copy");
+        assert!(result["verify_ms"].as_f64().is_some());
+
+        // A corrupted destination, as a flaky network filesystem might leave
+        fs::write(&dest, vec![7u8; 99_999]).await.unwrap();
+        let size = verify_copy(&src, &dest, Verify::Size, false)
+            .unwrap()
+            .expect("size mismatch");
+        assert_eq!(size.code, RpcError::VERIFY_FAILED);
+        assert_eq!(size.data.unwrap()["dest_size"].as_u64(), Some(99_999));
+
+        fs::write(&dest, vec![8u8; 100_000]).await.unwrap();
+        assert!(
+            verify_copy(&src, &dest, Verify::Size, false)
+                .unwrap()
+                .is_none()
+        );
+        let checksum = verify_copy(&src, &dest, Verify::Checksum, true)
+            .unwrap()
+            .expect("checksum mismatch");
+        let data = checksum.data.unwrap();
+        assert_ne!(data["src_sha256"], data["dest_sha256"]);
+        assert_eq!(data["removed"].as_bool(), Some(true));
+        assert!(!dest.exists());
+
+        assert!(
+            copy(msgpack_map! {
+                "src" => path_value(&src),
+                "dest" => path_value(&dest),
+                "verify" => "crc",
+            })
+            .await
+            .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn copy_keep_newer_skips_up_to_date_files() {
+        let tmp = tempfile::tempdir().expect("create tempdir");
+        let src = tmp.path().join("src");
+        let dest = tmp.path().join("dest");
+        fs::create_dir_all(&src).await.unwrap();
+        fs::create_dir_all(&dest).await.unwrap();
+        for name in ["newer", "older"] {
+            fs::write(src.join(name), b"source").await.unwrap();
+            fs::write(dest.join(name), b"dest").await.unwrap();
+        }
+        let hour = std::time::Duration::from_secs(3600);
+        let now = std::time::SystemTime::now();
+        let set_mtime = |path: PathBuf, time| {
+            std::fs::File::options()
+                .write(true)
+                .open(path)
+                .unwrap()
+                .set_modified(time)
+                .unwrap()
+        };
+        set_mtime(dest.join("newer"), now + hour);
+        set_mtime(dest.join("older"), now - hour);
+
+        let result = copy(msgpack_map! {
+            "src" => path_value(&src),
+            "dest" => path_value(&dest),
+            "exact_dest" => true,
+            "keep_newer" => true,
+        })
+        .await
+        .expect("copy with keep_newer");
+
+        assert_eq!(result["skipped"].as_u64(), Some(1));
+        assert_eq!(result["copied"].as_u64(), Some(6));
+        assert_eq!(fs::read(dest.join("newer")).await.unwrap(), b"dest");
+        assert_eq!(fs::read(dest.join("older")).await.unwrap(), b"source");
+    }
+
     #[tokio::test]
     async fn copy_directory_sets_permissions_after_children() {
         let tmp = tempfile::tempdir().expect("create tempdir");
@@ -1019,4 +3070,569 @@ mod tests {
             .await
             .unwrap();
     }
+
+    #[tokio::test]
+    async fn rename_without_overwrite_keeps_existing_destination() {
+        let tmp = tempfile::tempdir().expect("create tempdir");
+        let src = tmp.path().join("src.txt");
+        let dest = tmp.path().join("dest.txt");
+
+        fs::write(&src, b"new").await.unwrap();
+        fs::write(&dest, b"old").await.unwrap();
+
+        let err = rename(msgpack_map! {
+            "src" => path_value(&src),
+            "dest" => path_value(&dest),
+        })
+        .await
+        .expect_err("rename should fail without overwrite");
+        assert!(err.message.starts_with("Destination already exists"));
+        assert_eq!(fs::read(&src).await.unwrap(), b"new");
+        assert_eq!(fs::read(&dest).await.unwrap(), b"old");
+
+        let moved = tmp.path().join("moved.txt");
+        rename(msgpack_map! {
+            "src" => path_value(&src),
+            "dest" => path_value(&moved),
+        })
+        .await
+        .unwrap();
+        assert!(!src.exists());
+        assert_eq!(fs::read(&moved).await.unwrap(), b"new");
+    }
+
+    #[tokio::test]
+    async fn rename_exchange_swaps_paths() {
+        let tmp = tempfile::tempdir().expect("create tempdir");
+        let file = tmp.path().join("file");
+        let dir = tmp.path().join("dir");
+
+        fs::write(&file, b"contents").await.unwrap();
+        fs::create_dir(&dir).await.unwrap();
+
+        rename(msgpack_map! {
+            "src" => path_value(&file),
+            "dest" => path_value(&dir),
+            "exchange" => true,
+        })
+        .await
+        .unwrap();
+        assert!(file.is_dir());
+        assert_eq!(fs::read(&dir).await.unwrap(), b"contents");
+
+        let err = rename(msgpack_map! {
+            "src" => path_value(&file),
+            "dest" => path_value(&tmp.path().join("missing")),
+            "exchange" => true,
+        })
+        .await
+        .expect_err("exchange needs both paths to exist");
+        assert_eq!(err.code, RpcError::FILE_NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn mutations_return_attributes_on_request() {
+        let tmp = tempfile::tempdir().expect("create tempdir");
+        let file = tmp.path().join("file");
+        let moved = tmp.path().join("moved");
+
+        let result = write(msgpack_map! {
+            "path" => path_value(&file),
+            "content" => Value::Binary(b"abcde".to_vec()),
+            "mode" => 0o600,
+            "return_attrs" => true,
+        })
+        .await
+        .unwrap();
+        assert_eq!(result["written"].as_u64(), Some(5));
+        assert_eq!(result["attrs"]["size"].as_u64(), Some(5));
+        assert_eq!(result["attrs"]["mode"].as_u64().unwrap() & 0o7777, 0o600);
+
+        let result = set_modes(msgpack_map! {
+            "path" => path_value(&file),
+            "mode" => 0o640,
+            "return_attrs" => true,
+        })
+        .await
+        .unwrap();
+        assert_eq!(result["result"], Value::Boolean(true));
+        assert_eq!(result["attrs"]["mode"].as_u64().unwrap() & 0o7777, 0o640);
+
+        let result = set_times(msgpack_map! {
+            "path" => path_value(&file),
+            "mtime" => 1_000_000_000,
+            "return_attrs" => true,
+        })
+        .await
+        .unwrap();
+        assert_eq!(result["attrs"]["mtime"].as_i64(), Some(1_000_000_000));
+
+        let result = rename(msgpack_map! {
+            "src" => path_value(&file),
+            "dest" => path_value(&moved),
+            "return_attrs" => true,
+        })
+        .await
+        .unwrap();
+        assert_eq!(result["result"], Value::Boolean(true));
+        assert_eq!(result["old_attrs"]["size"].as_u64(), Some(5));
+        assert_eq!(result["attrs"]["inode"], result["old_attrs"]["inode"]);
+
+        // Without the flag the results keep their old shape
+        let result = set_modes(msgpack_map! {
+            "path" => path_value(&moved),
+            "mode" => 0o644,
+        })
+        .await
+        .unwrap();
+        assert_eq!(result, Value::Boolean(true));
+    }
+
+    #[tokio::test]
+    async fn write_create_options_compose_with_offset() {
+        let tmp = tempfile::tempdir().expect("create tempdir");
+        let path = tmp.path().join("sparse");
+
+        let err = write(msgpack_map! {
+            "path" => path_value(&path),
+            "content" => Value::Binary(b"abc".to_vec()),
+            "offset" => 4,
+        })
+        .await
+        .expect_err("offset writes do not create by default");
+        assert_eq!(err.code, RpcError::FILE_NOT_FOUND);
+
+        write(msgpack_map! {
+            "path" => path_value(&path),
+            "content" => Value::Binary(b"abc".to_vec()),
+            "offset" => 4,
+            "create" => true,
+            "mode" => 0o600,
+        })
+        .await
+        .unwrap();
+        assert_eq!(fs::read(&path).await.unwrap(), b"\0\0\0\0abc");
+        let mode = fs::metadata(&path).await.unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let err = write(msgpack_map! {
+            "path" => path_value(&path),
+            "content" => Value::Binary(b"x".to_vec()),
+            "create_new" => true,
+        })
+        .await
+        .expect_err("create_new must not reuse an existing file");
+        assert!(err.message.contains("exists"));
+        assert_eq!(fs::read(&path).await.unwrap(), b"\0\0\0\0abc");
+    }
+
+    #[tokio::test]
+    async fn write_rejects_conflicting_modes() {
+        let tmp = tempfile::tempdir().expect("create tempdir");
+        let path = path_value(&tmp.path().join("file"));
+
+        for params in [
+            msgpack_map! { "path" => path.clone(), "content" => "", "append" => true, "offset" => 0 },
+            msgpack_map! { "path" => path.clone(), "content" => "", "append" => true, "create_new" => true },
+            msgpack_map! { "path" => path.clone(), "content" => "", "create" => false, "create_new" => true },
+        ] {
+            let err = write(params).await.expect_err("conflicting options");
+            assert_eq!(err.code, RpcError::INVALID_PARAMS);
+        }
+        assert!(!tmp.path().join("file").exists());
+    }
+
+    #[tokio::test]
+    async fn writes_requiring_more_space_than_available_fail_up_front() {
+        let tmp = tempfile::tempdir().expect("create tempdir");
+        let dest = tmp.path().join("new/dir/file");
+        let too_much = u64::MAX / 2;
+
+        // The missing file is checked on its nearest existing ancestor
+        let err = write(msgpack_map! {
+            "path" => path_value(&tmp.path().join("file")),
+            "content" => "data",
+            "require_free_bytes" => too_much,
+        })
+        .await
+        .expect_err("not enough space");
+        assert_eq!(err.code, RpcError::NO_SPACE);
+        let data = err.data.expect("error data");
+        let get = |key: &str| {
+            data.as_map()
+                .and_then(|m| m.iter().find(|(k, _)| k.as_str() == Some(key)))
+                .and_then(|(_, v)| v.as_u64())
+                .unwrap()
+        };
+        assert_eq!(get("required"), too_much);
+        assert_eq!(get("shortfall"), too_much - get("available"));
+        assert!(!tmp.path().join("file").exists());
+        assert!(super::super::check_free_space(&dest, too_much).is_err());
+        super::super::check_free_space(&dest, 4).unwrap();
+
+        let src = tmp.path().join("src");
+        fs::write(&src, b"data").await.unwrap();
+        let err = copy(msgpack_map! {
+            "src" => path_value(&src),
+            "dest" => path_value(&tmp.path().join("copy")),
+            "require_free_bytes" => too_much,
+        })
+        .await
+        .expect_err("not enough space");
+        assert_eq!(err.code, RpcError::NO_SPACE);
+        assert!(!tmp.path().join("copy").exists());
+
+        write(msgpack_map! {
+            "path" => path_value(&tmp.path().join("file")),
+            "content" => "data",
+            "require_free_bytes" => 4,
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn read_refuses_files_over_the_limit_without_length() {
+        let tmp = tempfile::tempdir().expect("create tempdir");
+        let path = tmp.path().join("huge");
+        let size = max_read_size() + 1;
+        std::fs::File::create(&path).unwrap().set_len(size).unwrap();
+
+        let err = read(msgpack_map! { "path" => path_value(&path) })
+            .await
+            .expect_err("whole-file read over the limit");
+        assert_eq!(err.code, RpcError::FILE_TOO_LARGE);
+        let data = err.data.expect("error data");
+        let size_field = data
+            .as_map()
+            .unwrap()
+            .iter()
+            .find(|(k, _)| k.as_str() == Some("size"))
+            .map(|(_, v)| v.as_u64());
+        assert_eq!(size_field, Some(Some(size)));
+
+        // Ranged reads and reads of a small enough tail are still served
+        let ranged = read(msgpack_map! { "path" => path_value(&path), "length" => 4 })
+            .await
+            .unwrap();
+        assert_eq!(ranged["size"].as_u64(), Some(4));
+        let tail = read(msgpack_map! { "path" => path_value(&path), "offset" => size - 8 })
+            .await
+            .unwrap();
+        assert_eq!(tail["size"].as_u64(), Some(8));
+    }
+
+    #[tokio::test]
+    async fn read_guards_special_files() {
+        let tmp = tempfile::tempdir().expect("create tempdir");
+        let fifo = tmp.path().join("fifo");
+        nix::unistd::mkfifo(&fifo, nix::sys::stat::Mode::from_bits_truncate(0o600)).unwrap();
+
+        let err = read(msgpack_map! { "path" => path_value(&fifo) })
+            .await
+            .expect_err("FIFO without allow_special");
+        assert_eq!(err.code, RpcError::NOT_REGULAR_FILE);
+        assert_eq!(err.data.unwrap()["type"].as_str(), Some("fifo"));
+        let err = read(msgpack_map! { "path" => path_value(&fifo), "allow_special" => true })
+            .await
+            .expect_err("allow_special without bounds");
+        assert_eq!(err.code, RpcError::INVALID_PARAMS);
+
+        // No data within the timeout
+        let special = |length: u64, timeout_ms: u64| {
+            read(msgpack_map! {
+                "path" => path_value(&fifo),
+                "allow_special" => true,
+                "length" => length,
+                "timeout_ms" => timeout_ms
+            })
+        };
+        let writer = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&fifo)
+            .unwrap();
+        let idle = special(16, 20).await.unwrap();
+        assert_eq!(idle["size"].as_u64(), Some(0));
+        assert_eq!(idle["timed_out"].as_bool(), Some(true));
+
+        // Bounded by length
+        (&writer).write_all(b"hello world").unwrap();
+        let partial = special(5, 1000).await.unwrap();
+        assert_eq!(partial["content"].as_slice(), Some(&b"hello"[..]));
+        assert_eq!(partial["timed_out"].as_bool(), Some(false));
+
+        if Path::new("/dev/zero").exists() {
+            let zero = read(msgpack_map! {
+                "path" => "/dev/zero",
+                "allow_special" => true,
+                "length" => 100_000,
+                "timeout_ms" => 1000
+            })
+            .await
+            .unwrap();
+            assert_eq!(zero["size"].as_u64(), Some(100_000));
+        }
+    }
+
+    #[test]
+    fn crc32c_matches_the_check_value() {
+        assert_eq!(crc32c(b"123456789"), 0xE306_9283);
+        assert_eq!(crc32c(b""), 0);
+    }
+
+    #[tokio::test]
+    async fn ranged_reads_are_checksummed_and_detect_changes() {
+        let tmp = tempfile::tempdir().expect("create tempdir");
+        let path = tmp.path().join("file");
+        fs::write(&path, b"0123456789").await.unwrap();
+
+        let first = read(msgpack_map! { "path" => path_value(&path), "length" => 4 })
+            .await
+            .unwrap();
+        assert_eq!(first["crc32c"].as_u64(), Some(u64::from(crc32c(b"0123"))));
+        let fingerprint = first["fingerprint"].as_str().unwrap().to_string();
+
+        let ranges = read_multi_ranges(msgpack_map! {
+            "path" => path_value(&path),
+            "ranges" => Value::Array(vec![
+                Value::Array(vec![4.into(), 2.into()]),
+                Value::Array(vec![8.into(), 10.into()]),
+            ]),
+            "expect_fingerprint" => fingerprint.clone(),
+        })
+        .await
+        .unwrap();
+        let ranges = ranges["ranges"].as_array().unwrap();
+        assert_eq!(ranges[0]["content"].as_slice(), Some(&b"45"[..]));
+        assert_eq!(ranges[1]["offset"].as_u64(), Some(8));
+        assert_eq!(ranges[1]["content"].as_slice(), Some(&b"89"[..]));
+        assert_eq!(ranges[1]["crc32c"].as_u64(), Some(u64::from(crc32c(b"89"))));
+
+        fs::write(&path, b"0123456789abc").await.unwrap();
+        let err = read(msgpack_map! {
+            "path" => path_value(&path),
+            "offset" => 4,
+            "length" => 4,
+            "expect_fingerprint" => fingerprint,
+        })
+        .await
+        .expect_err("file changed since the first chunk");
+        assert_eq!(err.code, RpcError::STALE_FILE);
+        assert_ne!(
+            err.data.unwrap()["fingerprint"].as_str(),
+            first["fingerprint"].as_str()
+        );
+    }
+
+    #[tokio::test]
+    async fn reads_report_whether_they_reached_the_end() {
+        let tmp = tempfile::tempdir().expect("create tempdir");
+        let path = tmp.path().join("file");
+        fs::write(&path, b"0123456789").await.unwrap();
+        let ranged = |offset: u64, length: u64| {
+            read(msgpack_map! {
+                "path" => path_value(&path),
+                "offset" => offset,
+                "length" => length,
+            })
+        };
+
+        let middle = ranged(2, 4).await.unwrap();
+        assert_eq!(middle["content"].as_slice(), Some(&b"2345"[..]));
+        assert_eq!(middle["eof"].as_bool(), Some(false));
+        assert_eq!(middle["file_size"].as_u64(), Some(10));
+        // A range ending exactly at the end is known to be the last one
+        let last = ranged(6, 4).await.unwrap();
+        assert_eq!(last["eof"].as_bool(), Some(true));
+        let short = ranged(8, 4).await.unwrap();
+        assert_eq!(short["content"].as_slice(), Some(&b"89"[..]));
+        assert_eq!(short["eof"].as_bool(), Some(true));
+        let past = ranged(20, 4).await.unwrap();
+        assert_eq!(past["size"].as_u64(), Some(0));
+        assert_eq!(past["eof"].as_bool(), Some(true));
+        assert_eq!(past["file_size"].as_u64(), Some(10));
+
+        let whole = read(msgpack_map! { "path" => path_value(&path) })
+            .await
+            .unwrap();
+        assert_eq!(whole["eof"].as_bool(), Some(true));
+        assert_eq!(whole["file_size"].as_u64(), Some(10));
+
+        let ranges = read_multi_ranges(msgpack_map! {
+            "path" => path_value(&path),
+            "ranges" => Value::Array(vec![
+                Value::Array(vec![0.into(), 2.into()]),
+                Value::Array(vec![8.into(), 2.into()]),
+            ]),
+        })
+        .await
+        .unwrap();
+        let ranges = ranges["ranges"].as_array().unwrap();
+        assert_eq!(ranges[0]["eof"].as_bool(), Some(false));
+        assert_eq!(ranges[1]["eof"].as_bool(), Some(true));
+        assert_eq!(ranges[1]["file_size"].as_u64(), Some(10));
+    }
+
+    #[cfg(not(any(target_os = "macos", target_os = "freebsd")))]
+    #[tokio::test]
+    async fn set_flags_is_unsupported_without_bsd_flags() {
+        let tmp = tempfile::tempdir().expect("create tempdir");
+        let path = tmp.path().join("file");
+        fs::write(&path, b"").await.unwrap();
+
+        let err = set_flags(msgpack_map! { "path" => path_value(&path), "hidden" => true })
+            .await
+            .expect_err("chflags is BSD only");
+        assert_eq!(err.code, RpcError::IO_ERROR);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn chattr_flags_are_read_set_and_explain_eperm() {
+        use crate::handlers::file::{get_file_attributes, get_flags};
+
+        let tmp = tempfile::tempdir().expect("create tempdir");
+        let path = tmp.path().join("file");
+        fs::write(&path, b"").await.unwrap();
+        let get = || get_flags(msgpack_map! { "path" => path_value(&path) });
+
+        let flags = get().await.unwrap();
+        if flags["available"].as_bool() != Some(true) {
+            // The temp dir is on a filesystem without flags: nothing to test
+            return;
+        }
+        assert_eq!(flags["nodump"].as_bool(), Some(false));
+
+        set_flags(msgpack_map! { "path" => path_value(&path), "nodump" => true })
+            .await
+            .unwrap();
+        assert_eq!(get().await.unwrap()["nodump"].as_bool(), Some(true));
+        let attrs = get_file_attributes(&path, true).await.unwrap();
+        if let Some(flags) = attrs.flags {
+            assert_eq!(flags, 0x40);
+        }
+
+        // Immutable takes CAP_LINUX_IMMUTABLE, which sandboxes may lack
+        let immutable =
+            set_flags(msgpack_map! { "path" => path_value(&path), "immutable" => true }).await;
+        if immutable.is_ok() {
+            let err = fs::remove_file(&path).await.unwrap_err();
+            let err = map_io_error(err, &path);
+            set_flags(msgpack_map! { "path" => path_value(&path), "immutable" => false })
+                .await
+                .unwrap();
+            assert_eq!(err.code, RpcError::PERMISSION_DENIED);
+            assert!(err.message.contains("immutable"), "{}", err.message);
+            assert_eq!(err.data.unwrap()["flag"].as_str(), Some("immutable"));
+        }
+
+        // Devices and symlinks have no flags
+        let link = tmp.path().join("link");
+        std::os::unix::fs::symlink(&path, &link).unwrap();
+        let flags = get_flags(msgpack_map! { "path" => path_value(&link) })
+            .await
+            .unwrap();
+        assert_eq!(flags["available"].as_bool(), Some(false));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn fallocate_allocates_and_punches_holes() {
+        const MIB: u64 = 1024 * 1024;
+
+        let tmp = tempfile::tempdir().expect("create tempdir");
+        let path = tmp.path().join("image");
+        fs::write(&path, b"").await.unwrap();
+        let call = |mode: &str, offset: u64, length: u64| {
+            fallocate(msgpack_map! {
+                "path" => path_value(&path),
+                "mode" => mode,
+                "offset" => offset,
+                "length" => length
+            })
+        };
+
+        let allocated = match call("allocate", 0, 2 * MIB).await {
+            Ok(result) => result,
+            Err(err) => {
+                // The temp dir is on a filesystem without fallocate
+                assert_eq!(err.code, RpcError::UNSUPPORTED);
+                return;
+            }
+        };
+        assert_eq!(allocated["size"].as_u64(), Some(2 * MIB));
+        assert!(allocated["allocated"].as_u64().unwrap() >= 2 * MIB);
+
+        std::fs::write(&path, vec![7u8; 2 * MIB as usize]).unwrap();
+        match call("punch_hole", 0, MIB).await {
+            Ok(punched) => {
+                assert_eq!(punched["size"].as_u64(), Some(2 * MIB));
+                assert!(punched["allocated"].as_u64().unwrap() < 2 * MIB);
+                let content = std::fs::read(&path).unwrap();
+                assert!(content[..MIB as usize].iter().all(|&b| b == 0));
+                assert!(content[MIB as usize..].iter().all(|&b| b == 7));
+            }
+            Err(err) => assert_eq!(err.code, RpcError::UNSUPPORTED),
+        }
+        if let Ok(zeroed) = call("zero_range", MIB, 2 * MIB).await {
+            assert_eq!(zeroed["size"].as_u64(), Some(3 * MIB));
+            assert!(std::fs::read(&path).unwrap().iter().all(|&b| b == 0));
+        }
+
+        for (mode, length) in [("shrink", MIB), ("allocate", 0)] {
+            let err = call(mode, 0, length).await.unwrap_err();
+            assert_eq!(err.code, RpcError::INVALID_PARAMS);
+        }
+        let missing = tmp.path().join("missing");
+        let err = fallocate(msgpack_map! { "path" => path_value(&missing), "length" => 1 })
+            .await
+            .unwrap_err();
+        assert_eq!(err.code, RpcError::FILE_NOT_FOUND);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+    async fn concurrent_writes_to_one_path_do_not_interleave() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("contended.txt");
+        // Large enough that an unserialized write takes several syscalls
+        let content = |i: usize| vec![b'a' + (i % 26) as u8; 256 * 1024 + i];
+
+        let writes: Vec<_> = (0..50)
+            .map(|i| {
+                let params = msgpack_map! {
+                    "path" => path_value(&path),
+                    "content" => Value::Binary(content(i))
+                };
+                tokio::spawn(write(params))
+            })
+            .collect();
+        let reads: Vec<_> = (0..10)
+            .map(|_| {
+                let params = msgpack_map! {
+                    "path" => path_value(&path),
+                    "consistent_read" => true
+                };
+                tokio::spawn(read(params))
+            })
+            .collect();
+        for write in writes {
+            write.await.unwrap().unwrap();
+        }
+
+        let written = fs::read(&path).await.unwrap();
+        assert!(
+            (0..50).any(|i| written == content(i)),
+            "final content ({} bytes) is not that of a single write",
+            written.len()
+        );
+        // Consistent reads see no write or a whole one
+        for read in reads {
+            let Ok(result) = read.await.unwrap() else {
+                continue;
+            };
+            let seen = result["content"].as_slice().unwrap();
+            assert!((0..50).any(|i| seen == content(i)));
+        }
+    }
 }
@@ -0,0 +1,397 @@
+//! Emacs lock files for TRAMP-RPC
+//!
+//! Emacs marks a file being edited with a symlink `.#NAME` next to it whose
+//! target is `USER@HOST.PID:BOOT_TIME` (`:BOOT_TIME` is optional).  This
+//! module provides:
+//! - `file.lockinfo`: Derive, read and parse the lock of a file, and judge
+//!   whether its owner is still alive
+//! - `file.lock_claim`: Create the lock, failing if someone else holds it
+//! - `file.lock_release`: Remove the lock if it is still ours
+
+use crate::jail;
+use crate::msgpack_map;
+use crate::protocol::{IntoValue, PathBytes, RpcError, from_value, path_or_bytes};
+use crate::stat_cache;
+use rmpv::Value;
+use serde::Deserialize;
+use std::ffi::OsStr;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use super::HandlerResult;
+use super::file::{bytes_to_path, map_io_error};
+
+/// Lock owner as recorded in the lock target.
+#[derive(Debug, PartialEq)]
+struct LockOwner {
+    user: Vec<u8>,
+    host: Vec<u8>,
+    pid: i32,
+    boot_time: Option<u64>,
+}
+
+/// Parse `USER@HOST.PID[:BOOT_TIME]`.  Host names may contain dots, so the
+/// pid is whatever follows the last one.
+fn parse_owner(target: &[u8]) -> Option<LockOwner> {
+    let at = target.iter().position(|&b| b == b'@')?;
+    let (user, rest) = (&target[..at], &target[at + 1..]);
+
+    let (rest, boot_time) = match rest.iter().rposition(|&b| b == b':') {
+        Some(colon) => (
+            &rest[..colon],
+            Some(std::str::from_utf8(&rest[colon + 1..]).ok()?.parse().ok()?),
+        ),
+        None => (rest, None),
+    };
+    let dot = rest.iter().rposition(|&b| b == b'.')?;
+    let pid = std::str::from_utf8(&rest[dot + 1..]).ok()?.parse().ok()?;
+
+    Some(LockOwner {
+        user: user.to_vec(),
+        host: rest[..dot].to_vec(),
+        pid,
+        boot_time,
+    })
+}
+
+/// Default lock file name for `path`: `.#NAME` in the same directory.
+fn default_lock_path(path: &Path) -> Result<PathBuf, RpcError> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| RpcError::invalid_params("path has no file name"))?;
+    let mut lock_name = b".#".to_vec();
+    lock_name.extend_from_slice(name.as_bytes());
+    Ok(path.with_file_name(OsStr::from_bytes(&lock_name)))
+}
+
+/// The lock path to use: the explicit `lock_path`, or the default one.
+fn lock_path_for(path: &[u8], lock_path: Option<&[u8]>) -> Result<PathBuf, RpcError> {
+    let lock_path = match lock_path {
+        Some(lock_path) => bytes_to_path(lock_path),
+        None => default_lock_path(&bytes_to_path(path))?,
+    };
+    jail::check(&lock_path)?;
+    Ok(lock_path)
+}
+
+/// Read the lock target.  Emacs falls back to a regular file holding the
+/// same text on filesystems without symlinks.
+fn read_lock(lock_path: &Path) -> std::io::Result<Option<Vec<u8>>> {
+    let metadata = match std::fs::symlink_metadata(lock_path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let target = if metadata.file_type().is_symlink() {
+        std::fs::read_link(lock_path)?.into_os_string().into_vec()
+    } else {
+        std::fs::read(lock_path)?
+    };
+    Ok(Some(target))
+}
+
+/// Boot time of this host in seconds since the epoch.
+#[cfg(target_os = "linux")]
+fn boot_time() -> Option<u64> {
+    let stat = std::fs::read_to_string("/proc/stat").ok()?;
+    stat.lines()
+        .find_map(|line| line.strip_prefix("btime "))
+        .and_then(|secs| secs.trim().parse().ok())
+}
+
+#[cfg(any(target_os = "macos", target_os = "freebsd"))]
+fn boot_time() -> Option<u64> {
+    let mut tv: libc::timeval = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::timeval>();
+    let result = unsafe {
+        libc::sysctlbyname(
+            c"kern.boottime".as_ptr(),
+            &mut tv as *mut libc::timeval as *mut libc::c_void,
+            &mut len,
+            std::ptr::null_mut(),
+            0,
+        )
+    };
+    (result == 0).then_some(tv.tv_sec as u64)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "freebsd")))]
+fn boot_time() -> Option<u64> {
+    None
+}
+
+fn pid_alive(pid: i32) -> bool {
+    if pid <= 0 {
+        return false;
+    }
+    if unsafe { libc::kill(pid, 0) } == 0 {
+        return true;
+    }
+    // EPERM means the process exists but belongs to someone else
+    std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// Describe the lock at `lock_path` as returned by `file.lockinfo`.
+fn lock_info(lock_path: &Path) -> std::io::Result<Value> {
+    let lock_path_value = Value::Binary(lock_path.as_os_str().as_bytes().to_vec());
+    let Some(target) = read_lock(lock_path)? else {
+        return Ok(msgpack_map! {
+            "lock_path" => lock_path_value,
+            "locked" => false
+        });
+    };
+
+    let Some(owner) = parse_owner(&target) else {
+        // Not a lock Emacs would write; report it without judging it.
+        return Ok(msgpack_map! {
+            "lock_path" => lock_path_value,
+            "locked" => true,
+            "target" => Value::Binary(target),
+            "owner" => Value::Nil,
+            "local" => false,
+            "alive" => Value::Nil,
+            "stale" => false
+        });
+    };
+
+    // Liveness can only be judged for owners on this host.  A boot time
+    // that differs from ours (Emacs allows one second of jitter) means the
+    // pid belongs to an earlier boot.
+    let local = owner.host == super::hostname().as_bytes();
+    let alive = local.then(|| {
+        let same_boot = match (owner.boot_time, boot_time()) {
+            (Some(theirs), Some(ours)) => theirs.abs_diff(ours) <= 1,
+            _ => true,
+        };
+        same_boot && pid_alive(owner.pid)
+    });
+
+    Ok(msgpack_map! {
+        "lock_path" => lock_path_value,
+        "locked" => true,
+        "target" => Value::Binary(target),
+        "owner" => msgpack_map! {
+            "user" => Value::Binary(owner.user),
+            "host" => Value::Binary(owner.host),
+            "pid" => owner.pid,
+            "boot_time" => owner.boot_time.into_value()
+        },
+        "local" => local,
+        "alive" => alive.into_value(),
+        "stale" => alive == Some(false)
+    })
+}
+
+/// Inspect the Emacs lock of a file.
+///
+/// `path` is the locked file itself; the lock is looked up at `.#NAME`
+/// next to it unless `lock_path` names it explicitly (for
+/// `lock-file-name-transforms`).  Returns `locked`, the raw `target`, the
+/// parsed `owner` {user, host, pid, boot_time}, whether the owner is on
+/// this host (`local`), whether its process is `alive` (nil when not
+/// local), and whether the lock is `stale`.
+pub async fn lockinfo(params: Value) -> HandlerResult {
+    #[derive(Deserialize)]
+    struct Params {
+        #[serde(with = "path_or_bytes")]
+        path: Vec<u8>,
+        #[serde(default)]
+        lock_path: Option<PathBytes>,
+    }
+
+    let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
+    let lock_path = lock_path_for(
+        &params.path,
+        params.lock_path.as_ref().map(|p| p.0.as_slice()),
+    )?;
+    let lock_str = lock_path.to_string_lossy().into_owned();
+
+    crate::blocking::run(lock_path.clone(), move || lock_info(&lock_path))
+        .await?
+        .map_err(|e| map_io_error(e, &lock_str))
+}
+
+/// Create the lock of a file, pointing at `owner`.
+///
+/// The symlink is created exclusively, so of two clients racing for the
+/// lock exactly one gets `claimed: true`.  The other gets `claimed: false`
+/// and the current `lockinfo`.  With `force`, an existing lock is replaced
+/// atomically, as `lock-file` does once the user chose to steal it.
+pub async fn lock_claim(params: Value) -> HandlerResult {
+    #[derive(Deserialize)]
+    struct Params {
+        #[serde(with = "path_or_bytes")]
+        path: Vec<u8>,
+        #[serde(default)]
+        lock_path: Option<PathBytes>,
+        /// Lock target, `USER@HOST.PID:BOOT_TIME`
+        #[serde(with = "path_or_bytes")]
+        owner: Vec<u8>,
+        #[serde(default)]
+        force: bool,
+    }
+
+    let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
+    let lock_path = lock_path_for(
+        &params.path,
+        params.lock_path.as_ref().map(|p| p.0.as_slice()),
+    )?;
+    let lock_str = lock_path.to_string_lossy().into_owned();
+
+    let result = {
+        let lock_path = lock_path.clone();
+        crate::blocking::run(lock_path.clone(), move || {
+            let owner = OsStr::from_bytes(&params.owner);
+            if params.force {
+                // Build the new lock aside and rename it over the old one.
+                static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+                let temp = lock_path.with_file_name(format!(
+                    ".#tramp-rpc-lock.{}.{}",
+                    std::process::id(),
+                    TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+                ));
+                let _ = std::fs::remove_file(&temp);
+                std::os::unix::fs::symlink(owner, &temp)?;
+                if let Err(e) = std::fs::rename(&temp, &lock_path) {
+                    let _ = std::fs::remove_file(&temp);
+                    return Err(e);
+                }
+                return Ok(msgpack_map! { "claimed" => true });
+            }
+            match std::os::unix::fs::symlink(owner, &lock_path) {
+                Ok(()) => Ok(msgpack_map! { "claimed" => true }),
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => Ok(msgpack_map! {
+                    "claimed" => false,
+                    "lockinfo" => lock_info(&lock_path)?
+                }),
+                Err(e) => Err(e),
+            }
+        })
+        .await?
+    };
+    stat_cache::invalidate(&lock_path).await;
+    result.map_err(|e| map_io_error(e, &lock_str))
+}
+
+/// Remove the lock of a file.
+///
+/// With `owner`, the lock is only removed while it still points there, so
+/// a lock stolen by someone else in the meantime survives.  Returns whether
+/// a lock was removed.
+pub async fn lock_release(params: Value) -> HandlerResult {
+    #[derive(Deserialize)]
+    struct Params {
+        #[serde(with = "path_or_bytes")]
+        path: Vec<u8>,
+        #[serde(default)]
+        lock_path: Option<PathBytes>,
+        #[serde(default)]
+        owner: Option<PathBytes>,
+    }
+
+    let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
+    let lock_path = lock_path_for(
+        &params.path,
+        params.lock_path.as_ref().map(|p| p.0.as_slice()),
+    )?;
+    let lock_str = lock_path.to_string_lossy().into_owned();
+
+    let result = {
+        let lock_path = lock_path.clone();
+        crate::blocking::run(lock_path.clone(), move || {
+            if let Some(owner) = &params.owner
+                && read_lock(&lock_path)?.is_some_and(|target| target != owner.0)
+            {
+                return Ok(false);
+            }
+            match std::fs::remove_file(&lock_path) {
+                Ok(()) => Ok(true),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+                Err(e) => Err(e),
+            }
+        })
+        .await?
+    };
+    stat_cache::invalidate(&lock_path).await;
+    result
+        .map(Value::Boolean)
+        .map_err(|e| map_io_error(e, &lock_str))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::map_get;
+
+    #[test]
+    fn parse_owner_handles_dotted_hosts_and_optional_boot_time() {
+        assert_eq!(
+            parse_owner(b"alice@build.example.com.4242:1700000000"),
+            Some(LockOwner {
+                user: b"alice".to_vec(),
+                host: b"build.example.com".to_vec(),
+                pid: 4242,
+                boot_time: Some(1_700_000_000),
+            })
+        );
+        assert_eq!(
+            parse_owner(b"bob@host.17").map(|o| (o.pid, o.boot_time)),
+            Some((17, None))
+        );
+        assert_eq!(parse_owner(b"no-at-sign.1"), None);
+        assert_eq!(parse_owner(b"u@host.notapid"), None);
+    }
+
+    #[tokio::test]
+    async fn lock_claim_release_and_staleness() {
+        let tmp = tempfile::tempdir().unwrap();
+        let file = tmp.path().join("notes.txt");
+        let path = Value::Binary(file.as_os_str().as_bytes().to_vec());
+        let host = super::super::hostname();
+        let ours = format!("me@{}.{}", host, std::process::id());
+
+        let info = lockinfo(msgpack_map! { "path" => path.clone() })
+            .await
+            .unwrap();
+        assert_eq!(map_get(&info, "locked"), Some(&Value::Boolean(false)));
+
+        let claim = msgpack_map! { "path" => path.clone(), "owner" => ours.clone() };
+        let claimed = lock_claim(claim.clone()).await.unwrap();
+        assert_eq!(map_get(&claimed, "claimed"), Some(&Value::Boolean(true)));
+        assert_eq!(
+            std::fs::read_link(tmp.path().join(".#notes.txt")).unwrap(),
+            Path::new(&ours)
+        );
+
+        // A second claim loses and sees a live local owner
+        let again = lock_claim(claim).await.unwrap();
+        assert_eq!(map_get(&again, "claimed"), Some(&Value::Boolean(false)));
+        let info = map_get(&again, "lockinfo").unwrap();
+        assert_eq!(map_get(info, "local"), Some(&Value::Boolean(true)));
+        assert_eq!(map_get(info, "alive"), Some(&Value::Boolean(true)));
+        assert_eq!(map_get(info, "stale"), Some(&Value::Boolean(false)));
+
+        // Releasing on behalf of another owner keeps the lock
+        let foreign = msgpack_map! { "path" => path.clone(), "owner" => "other@elsewhere.1" };
+        assert_eq!(lock_release(foreign).await.unwrap(), Value::Boolean(false));
+
+        // Stealing replaces it; a dead local pid makes it stale
+        let dead = format!("me@{}.{}", host, i32::MAX);
+        lock_claim(msgpack_map! { "path" => path.clone(), "owner" => dead, "force" => true })
+            .await
+            .unwrap();
+        let info = lockinfo(msgpack_map! { "path" => path.clone() })
+            .await
+            .unwrap();
+        assert_eq!(map_get(&info, "stale"), Some(&Value::Boolean(true)));
+
+        assert_eq!(
+            lock_release(msgpack_map! { "path" => path.clone() })
+                .await
+                .unwrap(),
+            Value::Boolean(true)
+        );
+        assert!(std::fs::symlink_metadata(tmp.path().join(".#notes.txt")).is_err());
+    }
+}
@@ -1,34 +1,74 @@
 //! Request handlers for TRAMP-RPC operations
 
+pub mod archive;
+pub mod autosave;
+pub mod bulk;
 pub mod commands;
+pub mod delta;
 pub mod dir;
+pub mod encoding;
 pub mod file;
+pub mod grep;
 pub mod io;
+pub mod lock;
+pub mod network;
 pub mod process;
+pub mod project;
+pub mod shell;
+pub mod sudo;
+pub mod tags;
+pub mod upload;
+pub mod vc;
+pub mod wait;
 
 use crate::msgpack_map;
 use crate::protocol::{Request, RequestId, Response, RpcError, from_value};
 use rmpv::Value;
+use std::sync::atomic::{AtomicU64, Ordering};
 
 /// Dispatch a request to the appropriate handler
 pub async fn dispatch(request: Request) -> Response {
+    let id = request.id.clone();
+    crate::cancel::scope(&id, dispatch_cancellable(request)).await
+}
+
+async fn dispatch_cancellable(request: Request) -> Response {
     // Handle batch separately (it needs special handling and can't recurse)
     if request.method == "batch" {
-        let result = batch_execute(request.params.clone()).await;
-        return match result {
+        let stopwatch = crate::timing::start();
+        let recent = crate::recent::begin(&request.method, &request.params);
+        let trace = request.trace.clone();
+        let result = crate::trace::scope(trace, batch_execute(request.params.clone())).await;
+        if let Some(recent) = recent {
+            recent.finish(&result);
+        }
+        let mut response = match result {
             Ok(value) => Response::success(request.id.clone(), value),
             Err(error) => Response::error(Some(request.id.clone()), error),
         };
+        stopwatch.finish(&request.method, &mut response);
+        return response;
     }
 
     // All other methods go through dispatch_inner
     dispatch_inner(request).await
 }
 
+/// Dispatch a request that `handler` serves instead of the routing table,
+/// with the same auditing, limits and bookkeeping as any other method.
+pub async fn dispatch_with<F, Fut>(request: Request, handler: F) -> Response
+where
+    F: FnOnce(Value) -> Fut,
+    Fut: std::future::Future<Output = HandlerResult>,
+{
+    let id = request.id.clone();
+    crate::cancel::scope(&id, serve(request, handler)).await
+}
+
 pub type HandlerResult = Result<Value, RpcError>;
 
 /// Get system information
-fn system_info() -> HandlerResult {
+async fn system_info() -> HandlerResult {
     use std::env;
 
     Ok(msgpack_map! {
@@ -36,15 +76,140 @@ fn system_info() -> HandlerResult {
         "os" => std::env::consts::OS,
         "arch" => std::env::consts::ARCH,
         "watcher" => watcher_kind(),
+        "jail" => crate::jail::root()
+            .map(|root| root.to_string_lossy().into_owned())
+            .into_value(),
+        "max_read_size" => io::max_read_size(),
+        "env_policy" => crate::env_policy::info(),
+        "http_client" => network::http_client(),
+        "dirs" => crate::server_dirs::info(),
         "hostname" => hostname(),
         "uid" => unsafe { libc::getuid() },
         "gid" => unsafe { libc::getgid() },
         "home" => env::var("HOME").ok().into_value(),
         "user" => env::var("USER").ok().into_value(),
-        "shell" => login_shell().into_value()
+        "shell" => login_shell().into_value(),
+        "build" => build_info().await,
+        "capabilities" => probe_capabilities().await,
+        "settings" => crate::config::info()
+    })
+}
+
+/// What the running binary was built from, so a deployed build can be told
+/// apart from the one the client has cached: `{commit, timestamp, rustc,
+/// target, panic, exe, exe_sha256}`.  The hash is computed on first request.
+async fn build_info() -> Value {
+    static EXE_SHA256: tokio::sync::OnceCell<Option<String>> = tokio::sync::OnceCell::const_new();
+
+    let sha256 = EXE_SHA256
+        .get_or_init(|| async { tokio::task::spawn_blocking(exe_sha256).await.ok().flatten() })
+        .await;
+    msgpack_map! {
+        "commit" => env!("TRAMP_RPC_GIT_COMMIT"),
+        "timestamp" => env!("TRAMP_RPC_BUILD_TIMESTAMP").parse::<u64>().unwrap_or(0),
+        "rustc" => env!("TRAMP_RPC_RUSTC_VERSION"),
+        "target" => env!("TRAMP_RPC_TARGET"),
+        "panic" => if cfg!(panic = "unwind") { "unwind" } else { "abort" },
+        "exe" => std::env::current_exe()
+            .ok()
+            .map(|exe| exe.to_string_lossy().into_owned())
+            .into_value(),
+        "exe_sha256" => sha256.clone().into_value()
+    }
+}
+
+/// The sha256 of the running executable.  On Linux `/proc/self/exe` still
+/// reads the running image after a redeploy has replaced the file.
+fn exe_sha256() -> Option<String> {
+    let exe = if cfg!(target_os = "linux") {
+        std::path::PathBuf::from("/proc/self/exe")
+    } else {
+        std::env::current_exe().ok()?
+    };
+    io::sha256_file(&exe).ok()
+}
+
+/// Time limit for each capability probe in `system.info`
+const PROBE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(2);
+
+/// Probe optional features so the client can disable integrations that
+/// would fail mid-session.  Each probe reports `{available, ...}`; those
+/// that fail, panic or time out report `{available: false, error}` without
+/// affecting the others.
+async fn probe_capabilities() -> Value {
+    let (watcher, pty, git, tmp) = tokio::join!(
+        probe(async {
+            Ok(msgpack_map! {
+                "available" => crate::watcher::get().is_some(),
+                "backend" => watcher_kind()
+            })
+        }),
+        probe(blocking_probe(|| {
+            nix::pty::openpty(None, None).map_err(|e| e.to_string())?;
+            Ok(msgpack_map! { "available" => true })
+        })),
+        probe(async {
+            let output = tokio::process::Command::new("git")
+                .arg("--version")
+                .stdin(std::process::Stdio::null())
+                .kill_on_drop(true)
+                .output()
+                .await
+                .map_err(|e| e.to_string())?;
+            if !output.status.success() {
+                return Err(format!("git --version failed: {}", output.status));
+            }
+            let version = String::from_utf8_lossy(&output.stdout);
+            let version = version.trim();
+            Ok(msgpack_map! {
+                "available" => true,
+                "version" => version.strip_prefix("git version ").unwrap_or(version)
+            })
+        }),
+        probe(blocking_probe(|| {
+            let tmp = std::env::temp_dir();
+            crate::jail::check(&tmp).map_err(|e| e.message)?;
+            let path = std::ffi::CString::new(tmp.as_os_str().as_encoded_bytes())
+                .map_err(|e| e.to_string())?;
+            let statvfs = statvfs_value(&path).map_err(|e| e.to_string())?;
+            Ok(msgpack_map! {
+                "available" => true,
+                "path" => tmp.to_string_lossy().into_owned(),
+                "statvfs" => statvfs
+            })
+        })),
+    );
+    msgpack_map! {
+        "watcher" => watcher,
+        "pty" => pty,
+        "git" => git,
+        "tmp" => tmp
+    }
+}
+
+/// Run one capability probe with [`PROBE_TIMEOUT`].
+async fn probe(probe: impl std::future::Future<Output = Result<Value, String>>) -> Value {
+    let result = tokio::time::timeout(PROBE_TIMEOUT, probe)
+        .await
+        .unwrap_or_else(|_| Err("probe timed out".to_string()));
+    result.unwrap_or_else(|error| {
+        msgpack_map! {
+            "available" => false,
+            "error" => error
+        }
     })
 }
 
+/// Run a blocking probe on the blocking pool, turning a panic into an error.
+async fn blocking_probe<F>(f: F) -> Result<Value, String>
+where
+    F: FnOnce() -> Result<Value, String> + Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .unwrap_or_else(|_| Err("probe panicked".to_string()))
+}
+
 fn watcher_kind() -> &'static str {
     use notify::{RecommendedWatcher, Watcher, WatcherKind};
 
@@ -88,7 +253,7 @@ fn login_shell() -> Option<String> {
 
 use crate::protocol::IntoValue;
 
-fn hostname() -> String {
+pub(crate) fn hostname() -> String {
     let mut buf = [0u8; 256];
     unsafe {
         if libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) == 0 {
@@ -109,7 +274,37 @@ fn system_getenv(params: Value) -> HandlerResult {
 
     let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
 
-    Ok(std::env::var(&params.name).ok().into_value())
+    Ok(std::env::var_os(&params.name).map(env_value).into_value())
+}
+
+/// Get all environment variables as a map from name to value
+fn system_getenv_all() -> HandlerResult {
+    use std::os::unix::ffi::OsStringExt;
+
+    let vars = std::env::vars_os()
+        .map(|(name, value)| {
+            let name = match name.into_string() {
+                Ok(name) => Value::String(name.into()),
+                Err(name) => Value::Binary(name.into_vec()),
+            };
+            (name, env_value(value))
+        })
+        .collect();
+    Ok(Value::Map(vars))
+}
+
+/// Encode an environment variable value: a string when it is valid UTF-8,
+/// otherwise `{value: <binary>, binary: true}` carrying the raw bytes.
+fn env_value(value: std::ffi::OsString) -> Value {
+    use std::os::unix::ffi::OsStringExt;
+
+    match value.into_string() {
+        Ok(value) => Value::String(value.into()),
+        Err(value) => msgpack_map! {
+            "value" => Value::Binary(value.into_vec()),
+            "binary" => true
+        },
+    }
 }
 
 /// Expand path with tilde and environment variables
@@ -136,14 +331,30 @@ fn system_statvfs(params: Value) -> HandlerResult {
 
     use std::ffi::CString;
     let expanded = expand_tilde(&params.path);
+    crate::jail::check(std::path::Path::new(&expanded))?;
     let path_cstr =
         CString::new(expanded.as_str()).map_err(|_| RpcError::invalid_params("Invalid path"))?;
 
+    statvfs_value(&path_cstr).map_err(RpcError::io_error)
+}
+
+/// Space on a filesystem, in bytes and inodes
+struct FsSpace {
+    block_size: u64,
+    total: u64,
+    free: u64,
+    available: u64,
+    inodes_total: u64,
+    inodes_free: u64,
+    inodes_available: u64,
+}
+
+fn statvfs(path: &std::ffi::CStr) -> std::io::Result<FsSpace> {
     let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
-    let result = unsafe { libc::statvfs(path_cstr.as_ptr(), &mut stat) };
+    let result = unsafe { libc::statvfs(path.as_ptr(), &mut stat) };
 
     if result != 0 {
-        return Err(RpcError::io_error(std::io::Error::last_os_error()));
+        return Err(std::io::Error::last_os_error());
     }
 
     // Return values in bytes (multiply by block size)
@@ -151,39 +362,90 @@ fn system_statvfs(params: Value) -> HandlerResult {
     #[allow(clippy::unnecessary_cast)]
     let block_size = stat.f_frsize as u64;
     #[allow(clippy::unnecessary_cast)]
-    let total = stat.f_blocks as u64 * block_size;
-    #[allow(clippy::unnecessary_cast)]
-    let free = stat.f_bfree as u64 * block_size;
-    #[allow(clippy::unnecessary_cast)]
-    let available = stat.f_bavail as u64 * block_size;
+    let space = FsSpace {
+        block_size,
+        total: stat.f_blocks as u64 * block_size,
+        free: stat.f_bfree as u64 * block_size,
+        available: stat.f_bavail as u64 * block_size,
+        inodes_total: stat.f_files as u64,
+        inodes_free: stat.f_ffree as u64,
+        inodes_available: stat.f_favail as u64,
+    };
+    Ok(space)
+}
 
+/// statvfs of `path` as `{total, free, available, block_size}` in bytes and
+/// `{inodes_total, inodes_free, inodes_available}`
+fn statvfs_value(path: &std::ffi::CStr) -> std::io::Result<Value> {
+    let space = statvfs(path)?;
     Ok(msgpack_map! {
-        "total" => total,
-        "free" => free,
-        "available" => available,
-        "block_size" => block_size
+        "total" => space.total,
+        "free" => space.free,
+        "available" => space.available,
+        "block_size" => space.block_size,
+        "inodes_total" => space.inodes_total,
+        "inodes_free" => space.inodes_free,
+        "inodes_available" => space.inodes_available
     })
 }
 
+/// Fail with NO_SPACE unless the filesystem `path` will be on has
+/// `required` bytes available, and an inode to spare if `path` does not
+/// exist yet.  A missing `path` is checked on its nearest existing
+/// ancestor.
+///
+/// Filesystems that report no inodes at all (as btrfs does) allocate them
+/// as needed and are not checked for them.
+pub(crate) fn check_free_space(path: &std::path::Path, required: u64) -> Result<(), RpcError> {
+    let path_str = path.to_string_lossy();
+    let exists = std::fs::symlink_metadata(path).is_ok();
+    let existing = path
+        .ancestors()
+        .find(|dir| !dir.as_os_str().is_empty() && std::fs::metadata(dir).is_ok())
+        .unwrap_or(std::path::Path::new("."));
+    let cstr = std::ffi::CString::new(existing.as_os_str().as_encoded_bytes())
+        .map_err(|_| RpcError::invalid_params("Invalid path"))?;
+    let space = statvfs(&cstr).map_err(|e| file::map_io_error(e, path))?;
+
+    let no_inodes = !exists && space.inodes_total > 0 && space.inodes_available == 0;
+    if space.available < required || no_inodes {
+        return Err(RpcError::no_space(
+            &path_str,
+            required,
+            space.available,
+            space.inodes_available,
+        ));
+    }
+    Ok(())
+}
+
 /// Get groups for the current user
 fn system_groups() -> HandlerResult {
-    // Query how many groups we need.  On Linux/macOS, getgroups(0, NULL)
-    // returns the count without writing.
-    let needed = unsafe { libc::getgroups(0, std::ptr::null_mut()) };
-    if needed < 0 {
-        return Err(RpcError::io_error(std::io::Error::last_os_error()));
-    }
+    let groups = loop {
+        // Query how many groups we need.  POSIX getgroups(0, NULL) returns
+        // the count without writing.
+        let needed = unsafe { libc::getgroups(0, std::ptr::null_mut()) };
+        if needed < 0 {
+            return Err(RpcError::io_error(std::io::Error::last_os_error()));
+        }
 
-    // Allocate at least 1 so a zero-length result still has a valid pointer.
-    let capacity = (needed as usize).max(1);
-    let mut groups: Vec<libc::gid_t> = vec![0; capacity];
+        // Allocate at least 1 so a zero-length result still has a valid pointer.
+        let capacity = (needed as usize).max(1);
+        let mut groups: Vec<libc::gid_t> = vec![0; capacity];
 
-    let actual_count = unsafe { libc::getgroups(capacity as libc::c_int, groups.as_mut_ptr()) };
-    if actual_count < 0 {
-        return Err(RpcError::io_error(std::io::Error::last_os_error()));
-    }
+        let actual_count = unsafe { libc::getgroups(capacity as libc::c_int, groups.as_mut_ptr()) };
+        if actual_count < 0 {
+            let err = std::io::Error::last_os_error();
+            // The group list grew in between (setgroups from another thread)
+            if err.raw_os_error() == Some(libc::EINVAL) {
+                continue;
+            }
+            return Err(RpcError::io_error(err));
+        }
 
-    groups.truncate(actual_count as usize);
+        groups.truncate(actual_count as usize);
+        break groups;
+    };
 
     // Convert to group info with names
     let group_info: Vec<Value> = groups
@@ -200,6 +462,20 @@ fn system_groups() -> HandlerResult {
     Ok(Value::Array(group_info))
 }
 
+/// Report server-internal counters
+fn system_stats() -> HandlerResult {
+    Ok(msgpack_map! {
+        "stat_cache" => crate::stat_cache::stats(),
+        "audit_log" => crate::audit::stats(),
+        "notifications" => crate::notifications::stats(),
+        "blocking" => crate::blocking::stats(),
+        "limits" => crate::limits::stats(),
+        "latency" => crate::timing::stats(),
+        "watched_directories" => crate::watcher::get().map_or(0, |watcher| watcher.directory_count()) as u64,
+        "panics" => PANICS.load(Ordering::Relaxed)
+    })
+}
+
 /// Get group name from gid (delegates to file.rs's mutex-protected, cached version)
 fn get_group_name(gid: libc::gid_t) -> Option<String> {
     file::get_group_name(gid)
@@ -219,7 +495,20 @@ pub(crate) fn expand_tilde(path: &str) -> String {
     path.to_string()
 }
 
+/// A working directory from params, with a leading `~` expanded.  Names
+/// that are not UTF-8 cannot start with one and are used as given.
+pub(crate) fn working_dir(cwd: &crate::protocol::PathBytes) -> std::path::PathBuf {
+    match std::str::from_utf8(&cwd.0) {
+        Ok(utf8) => expand_tilde(utf8).into(),
+        Err(_) => std::path::Path::new(cwd).to_path_buf(),
+    }
+}
+
 /// Execute multiple RPC requests in a single batch
+///
+/// Every method but `batch` itself is dispatched exactly as it would be on
+/// its own.  Each result carries the sub-request's `key` if one was given,
+/// or its index in `requests` otherwise.
 async fn batch_execute(params: Value) -> HandlerResult {
     #[derive(serde::Deserialize)]
     struct BatchParams {
@@ -235,6 +524,11 @@ async fn batch_execute(params: Value) -> HandlerResult {
         method: String,
         #[serde(default = "default_params")]
         params: Value,
+        #[serde(default)]
+        key: Option<Value>,
+        /// Defaults to the trace of the batch
+        #[serde(default)]
+        trace: Option<Value>,
     }
 
     let batch_params: BatchParams =
@@ -244,21 +538,32 @@ async fn batch_execute(params: Value) -> HandlerResult {
     let futures: Vec<_> = batch_params
         .requests
         .into_iter()
-        .map(|req| async move {
+        .enumerate()
+        .map(|(index, req)| async move {
+            let key = req.key.unwrap_or(Value::from(index));
+
             // Create a fake Request to reuse dispatch logic
             let fake_request = Request {
                 version: "2.0".to_string(),
                 id: RequestId::Number(0), // Dummy ID, not used in batch
                 method: req.method,
                 params: req.params,
+                trace: req.trace.or_else(crate::trace::current),
             };
 
             // Get the result by calling the handler directly (not full dispatch)
-            let response = dispatch_inner(fake_request).await;
+            let response = if fake_request.method == "batch" {
+                Response::error(
+                    None,
+                    RpcError::invalid_request("batch cannot be nested in a batch"),
+                )
+            } else {
+                dispatch_inner(fake_request).await
+            };
 
             // Convert Response to a result object
             match (response.result, response.error) {
-                (Some(result), None) => msgpack_map! { "result" => result },
+                (Some(result), None) => msgpack_map! { "key" => key, "result" => result },
                 (None, Some(error)) => {
                     let mut error_fields = vec![
                         (
@@ -274,10 +579,11 @@ async fn batch_execute(params: Value) -> HandlerResult {
                         error_fields.push((Value::String("data".into()), data));
                     }
                     msgpack_map! {
+                        "key" => key,
                         "error" => Value::Map(error_fields)
                     }
                 }
-                _ => msgpack_map! { "result" => Value::Nil },
+                _ => msgpack_map! { "key" => key, "result" => Value::Nil },
             }
         })
         .collect();
@@ -291,27 +597,172 @@ async fn batch_execute(params: Value) -> HandlerResult {
 /// Inner dispatch that handles the actual method routing
 /// Used by both single requests and batch requests
 async fn dispatch_inner(request: Request) -> Response {
+    let method = request.method.clone();
+    serve(
+        request,
+        |params| async move { route(&method, params).await },
+    )
+    .await
+}
+
+/// Serve `request` with `handler`: audit, record, check and limit it.
+async fn serve<F, Fut>(request: Request, handler: F) -> Response
+where
+    F: FnOnce(Value) -> Fut,
+    Fut: std::future::Future<Output = HandlerResult>,
+{
     let Request {
-        id, method, params, ..
+        id,
+        method,
+        params,
+        trace,
+        ..
     } = request;
 
-    let result = match method.as_str() {
+    let audit = crate::audit::begin(&method, &params);
+    let recent = crate::recent::begin(&method, &params);
+
+    let mut stopwatch = None;
+    let permit = match crate::config::check_writable(&method) {
+        Ok(()) => crate::limits::acquire(&method, crate::limits::no_wait(&params)).await,
+        Err(e) => Err(e),
+    };
+    let result = match permit {
+        Ok(_permit) => {
+            stopwatch = Some(crate::timing::start());
+            let handler =
+                crate::trace::scope(trace, crate::blocking::scope(&method, handler(params)));
+            futures::FutureExt::catch_unwind(std::panic::AssertUnwindSafe(handler))
+                .await
+                .unwrap_or_else(|payload| Err(panicked(&method, payload)))
+        }
+        Err(e) => Err(e),
+    };
+    // A program the client ran may have changed any path
+    if crate::mutating::effect(&method) == Some(crate::mutating::Effect::Spawns) {
+        crate::stat_cache::clear();
+    }
+    // Errors about a path say which operation failed on it.
+    let result = result.map_err(|e| {
+        if e.has_data("path") || e.has_data("src") {
+            e.with_data("operation", method.as_str().into())
+        } else {
+            e
+        }
+    });
+
+    if let Some(audit) = audit {
+        audit.finish(&result);
+    }
+    if let Some(recent) = recent {
+        recent.finish(&result);
+    }
+
+    let mut response = match result {
+        Ok(value) => Response::success(id, value),
+        Err(error) => Response::error(Some(id), error),
+    };
+    stopwatch
+        .unwrap_or_else(crate::timing::start)
+        .finish(&method, &mut response);
+    response
+}
+
+/// Handler panics caught since startup
+static PANICS: AtomicU64 = AtomicU64::new(0);
+
+/// The reply for a handler of `method` that panicked with `payload`: an
+/// INTERNAL_ERROR whose data holds the `method` and the `panic` message.
+/// Panics on the blocking pool come here too; see [`crate::blocking::run`].
+fn panicked(method: &str, payload: Box<dyn std::any::Any + Send>) -> RpcError {
+    PANICS.fetch_add(1, Ordering::Relaxed);
+    let message = payload
+        .downcast_ref::<&str>()
+        .map(|s| s.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown panic".to_string());
+    RpcError {
+        code: RpcError::INTERNAL_ERROR,
+        message: format!("Handler for {} panicked: {}", method, message),
+        data: Some(msgpack_map! {
+            "method" => method,
+            "panic" => message
+        }),
+    }
+}
+
+/// Handle `debug.panic {blocking}`: panic in the handler, or on the
+/// blocking pool with `blocking`.  Debug builds only, for testing.
+#[cfg(debug_assertions)]
+async fn debug_panic(params: Value) -> HandlerResult {
+    #[derive(serde::Deserialize)]
+    struct Params {
+        #[serde(default)]
+        blocking: bool,
+    }
+
+    let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
+    if params.blocking {
+        crate::blocking::run("", || panic!("debug.panic on the blocking pool")).await
+    } else {
+        panic!("debug.panic")
+    }
+}
+
+/// Run the handler for `method`.
+async fn route(method: &str, params: Value) -> HandlerResult {
+    match method {
         // File metadata operations
         "file.stat" => file::stat(params).await,
+        "file.stat_batch" => file::stat_batch(params).await,
+        "file.wait_changed" => wait::wait_changed(params).await,
+        "file.exists_ex" => file::exists_ex(params).await,
+        "file.access_batch" => file::access_batch(params).await,
+        "file.expand_wildcards" => dir::expand_wildcards(params).await,
         "file.truename" => file::truename(params).await,
+        "file.get_flags" => file::get_flags(params).await,
+        "file.hole_map" => file::hole_map(params).await,
 
         // Directory operations
         "dir.list" => dir::list(params).await,
+        "dir.list_multi" => dir::list_multi(params).await,
+        "dir.disk_usage" => dir::disk_usage(params).await,
+        "dir.audit_perms" => dir::audit_perms(params).await,
+        "dir.generation" => dir::generation(params).await,
+        "dir.completions" => dir::completions(params).await,
         "dir.create" => dir::create(params).await,
         "dir.remove" => dir::remove(params).await,
 
         // File I/O operations
         "file.read" => io::read(params).await,
+        "file.read_multi_ranges" => io::read_multi_ranges(params).await,
+        "file.convert_encoding" => encoding::convert(params).await,
         "file.write" => io::write(params).await,
+        "file.signature" => delta::signature(params).await,
+        "file.write_delta" => delta::write_delta(params).await,
+        "file.write_begin" => upload::write_begin(params).await,
+        "file.write_chunk" => upload::write_chunk(params).await,
+        "file.write_commit" => upload::write_commit(params).await,
+        "file.write_abort" => upload::write_abort(params).await,
         "file.copy" => io::copy(params).await,
         "file.rename" => io::rename(params).await,
         "file.delete" => io::delete(params).await,
+        "file.rename_batch" => bulk::rename_batch(params).await,
+        "file.copy_batch" => bulk::copy_batch(params).await,
+        "file.delete_batch" => bulk::delete_batch(params).await,
         "file.set_modes" => io::set_modes(params).await,
+        "file.set_flags" => io::set_flags(params).await,
+        "file.fallocate" => io::fallocate(params).await,
+        "file.write_autosave" => autosave::write_autosave(params).await,
+        "file.list_autosaves" => autosave::list_autosaves(params).await,
+        "file.lockinfo" => lock::lockinfo(params).await,
+        "archive.list" => archive::list(params).await,
+        "archive.extract" => archive::extract(params).await,
+        "archive.create" => archive::create(params).await,
+        "archive.read" => archive::read(params).await,
+        "archive.close" => archive::close(params).await,
+        "file.lock_claim" => lock::lock_claim(params).await,
+        "file.lock_release" => lock::lock_release(params).await,
         "file.set_times" => io::set_times(params).await,
         "file.make_symlink" => io::make_symlink(params).await,
         "file.make_hardlink" => io::make_hardlink(params).await,
@@ -326,22 +777,41 @@ async fn dispatch_inner(request: Request) -> Response {
         "process.close_stdin" => process::close_stdin(params).await,
         "process.kill" => process::kill(params).await,
         "process.list" => process::list(params).await,
+        "process.run_sudo" => sudo::run_sudo(params).await,
+        "auth.password_reply" => sudo::password_reply(params).await,
 
         // PTY (pseudo-terminal) process operations
         "process.start_pty" => process::start_pty(params).await,
         "process.read_pty" => process::read_pty(params).await,
         "process.write_pty" => process::write_pty(params).await,
         "process.resize_pty" => process::resize_pty(params).await,
+        "process.get_winsize" => process::get_winsize(params).await,
         "process.kill_pty" => process::kill_pty(params).await,
+        "process.interrupt_pty" => process::interrupt_pty(params).await,
         "process.close_pty" => process::close_pty(params).await,
         "process.list_pty" => process::list_pty(params).await,
 
+        // Shell sessions
+        "shell.session_open" => shell::session_open(params).await,
+        "shell.session_run" => shell::session_run(params).await,
+        "shell.session_close" => shell::session_close(params).await,
+
         // System info
-        "system.info" => system_info(),
+        "system.hello" => crate::handshake::handle_hello(params),
+        "system.info" => system_info().await,
         "system.getenv" => system_getenv(params),
+        "system.getenv_all" => system_getenv_all(),
         "system.expand_path" => system_expand_path(params),
         "system.statvfs" => system_statvfs(params),
+        "system.quota" => crate::quota::handle_quota(params).await,
         "system.groups" => system_groups(),
+        "system.stats" => system_stats(),
+        "system.set_audit_log" => crate::audit::handle_set_log(params),
+        "system.set_env_policy" => crate::env_policy::handle_set(params),
+        "system.recent_requests" => crate::recent::handle_recent_requests(params),
+        "system.gc" => crate::server_dirs::handle_gc(params).await,
+        "system.install_binary" => upload::install_binary(params).await,
+        "rpc.cancel" => crate::cancel::handle_cancel(params),
 
         // Parallel command execution and ancestor scanning
         "commands.run_parallel" => commands::run_parallel(params).await,
@@ -354,18 +824,30 @@ async fn dispatch_inner(request: Request) -> Response {
             commands::highlevel_dir_locals_find_file_cache_update(params).await
         }
 
+        // Network
+        "network.fetch" => network::fetch(params).await,
+        "network.check" => network::check(params).await,
+
+        // Project files
+        "project.files" => project::files(params).await,
+        "grep.search" => grep::search(params).await,
+        "tags.generate" => tags::generate(params).await,
+        "tags.query" => tags::query(params).await,
+
+        // Version control
+        "git.log" => vc::git_log(params).await,
+        "vc.status" => vc::vc_status(params).await,
+
         // Filesystem watch operations (for cache invalidation)
-        "watch.add" => crate::watcher::handle_add(params),
+        "watch.add" => crate::watcher::handle_add(params).await,
         "watch.remove" => crate::watcher::handle_remove(params),
         "watch.list" => crate::watcher::handle_list(params),
 
-        // Note: "batch" is NOT allowed in batch (no recursion)
-        _ => Err(RpcError::method_not_found(&method)),
-    };
+        #[cfg(debug_assertions)]
+        "debug.panic" => debug_panic(params).await,
 
-    match result {
-        Ok(value) => Response::success(id, value),
-        Err(error) => Response::error(Some(id), error),
+        // Note: "batch" is NOT allowed in batch (no recursion)
+        _ => Err(RpcError::method_not_found(method)),
     }
 }
 
@@ -375,6 +857,101 @@ mod tests {
     use crate::msgpack_map;
     use std::os::unix::ffi::OsStrExt;
 
+    #[test]
+    fn statvfs_reports_bytes_and_inodes() {
+        let root = std::ffi::CString::new("/").unwrap();
+        let space = statvfs(&root).unwrap();
+        assert!(space.available <= space.free && space.free <= space.total);
+        assert!(space.inodes_available <= space.inodes_free);
+        assert!(space.inodes_free <= space.inodes_total);
+        let value = statvfs_value(&root).unwrap();
+        let keys: Vec<_> = value
+            .as_map()
+            .unwrap()
+            .iter()
+            .filter_map(|(k, _)| k.as_str())
+            .collect();
+        assert!(keys.contains(&"inodes_total") && keys.contains(&"inodes_available"));
+    }
+
+    #[tokio::test]
+    async fn build_info_identifies_the_binary() {
+        let info = build_info().await;
+        assert!(!info["commit"].as_str().unwrap().is_empty());
+        assert!(info["timestamp"].as_u64().unwrap() > 0);
+        assert!(info["rustc"].as_str().unwrap().starts_with("rustc "));
+        assert!(!info["target"].as_str().unwrap().is_empty());
+        assert!(info["exe"].is_str());
+        let sha256 = info["exe_sha256"].as_str().expect("exe hash");
+        assert!(sha256.len() == 64 && sha256.bytes().all(|b| b.is_ascii_hexdigit()));
+        // Computed once
+        assert_eq!(build_info().await["exe_sha256"], info["exe_sha256"]);
+    }
+
+    #[tokio::test]
+    async fn handler_panics_become_internal_errors() {
+        let request = |params: Value| Request {
+            version: "2.0".to_string(),
+            id: RequestId::Number(7),
+            method: "debug.panic".to_string(),
+            params,
+            trace: None,
+        };
+        let panics = || system_stats().unwrap()["panics"].as_u64().unwrap();
+        let before = panics();
+
+        let response = dispatch(request(Value::Map(vec![]))).await;
+        let error = response.error.expect("error response");
+        assert_eq!(error.code, RpcError::INTERNAL_ERROR);
+        let data = error.data.unwrap();
+        assert_eq!(data["method"].as_str(), Some("debug.panic"));
+        assert_eq!(data["panic"].as_str(), Some("debug.panic"));
+
+        let response = dispatch(request(msgpack_map! { "blocking" => true })).await;
+        let data = response.error.expect("error response").data.unwrap();
+        assert_eq!(
+            data["panic"].as_str(),
+            Some("debug.panic on the blocking pool")
+        );
+        assert!(panics() >= before + 2);
+    }
+
+    #[tokio::test]
+    async fn programs_that_ran_drop_cached_attributes() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("file");
+        std::fs::write(&path, b"").unwrap();
+        let attrs = crate::handlers::file::get_file_attributes(&path, true)
+            .await
+            .unwrap();
+        crate::stat_cache::insert(&path, true, &attrs);
+
+        let request = Request {
+            version: "2.0".to_string(),
+            id: RequestId::Number(9),
+            method: "process.run".to_string(),
+            params: msgpack_map! { "cmd" => "true" },
+            trace: None,
+        };
+        assert!(dispatch(request).await.error.is_none());
+        assert!(crate::stat_cache::get(&path, true).is_none());
+    }
+
+    #[tokio::test]
+    async fn panics_are_caught_without_debug_methods() {
+        let request = Request {
+            version: "2.0".to_string(),
+            id: RequestId::Number(8),
+            method: "file.stat".to_string(),
+            params: Value::Nil,
+            trace: None,
+        };
+        let response = serve(request, |_| async { panic!("in a handler") }).await;
+        let data = response.error.expect("error response").data.unwrap();
+        assert_eq!(data["panic"].as_str(), Some("in a handler"));
+        assert_eq!(build_info().await["panic"].as_str(), Some("unwind"));
+    }
+
     #[tokio::test]
     async fn batch_errors_preserve_data() {
         let tmp = tempfile::tempdir().expect("create tempdir");
@@ -416,4 +993,306 @@ mod tests {
 
         assert_eq!(errno, i64::from(libc::ENOTDIR));
     }
+
+    #[tokio::test]
+    async fn io_errors_name_the_paths_and_operation() {
+        let tmp = tempfile::tempdir().expect("create tempdir");
+        let missing = tmp.path().join(std::ffi::OsStr::from_bytes(b"gone-\xff"));
+        let dest = tmp.path().join("dest");
+        let bytes = |path: &std::path::Path| Value::Binary(path.as_os_str().as_bytes().to_vec());
+        let call = |method: &str, params: Value| {
+            dispatch_inner(Request {
+                version: "2.0".to_string(),
+                id: RequestId::Number(1),
+                method: method.to_string(),
+                params,
+                trace: None,
+            })
+        };
+
+        let read = call("file.read", msgpack_map! { "path" => bytes(&missing) }).await;
+        let error = read.error.expect("reading a missing file fails");
+        assert_eq!(error.code, RpcError::FILE_NOT_FOUND);
+        let data = error.data.expect("error data");
+        assert_eq!(data["path"], bytes(&missing));
+        assert_eq!(data["os_errno"].as_i64(), Some(i64::from(libc::ENOENT)));
+        assert_eq!(data["operation"].as_str(), Some("file.read"));
+
+        let renamed = call(
+            "file.rename",
+            msgpack_map! { "src" => bytes(&missing), "dest" => bytes(&dest) },
+        )
+        .await;
+        let data = renamed.error.and_then(|e| e.data).expect("error data");
+        assert_eq!(data["src"], bytes(&missing));
+        assert_eq!(data["dest"], bytes(&dest));
+        assert_eq!(data["operation"].as_str(), Some("file.rename"));
+
+        // Errors without a path are left alone
+        let unknown = call("file.no_such_method", Value::Nil).await;
+        assert!(unknown.error.expect("unknown method").data.is_none());
+    }
+
+    #[tokio::test]
+    async fn batch_runs_watch_and_process_methods_with_keys() {
+        use std::sync::Arc;
+        use tokio::io::BufWriter;
+        use tokio::sync::Mutex;
+
+        if crate::watcher::get().is_none()
+            && let Ok(manager) = crate::watcher::WatchManager::new(Arc::new(Mutex::new(
+                BufWriter::new(tokio::io::stdout()),
+            )))
+        {
+            crate::watcher::init(manager);
+        }
+        let tmp = tempfile::tempdir().expect("create tempdir");
+        let dir = Value::Binary(tmp.path().as_os_str().as_bytes().to_vec());
+
+        let result = batch_execute(msgpack_map! {
+            "requests" => Value::Array(vec![
+                msgpack_map! {
+                    "method" => "watch.add",
+                    "params" => msgpack_map! { "path" => dir.clone() },
+                    "key" => "watch",
+                },
+                msgpack_map! {
+                    "method" => "process.start",
+                    "params" => msgpack_map! { "cmd" => "cat", "cwd" => "/tmp" },
+                },
+                msgpack_map! { "method" => "batch", "key" => 7 },
+            ]),
+        })
+        .await
+        .expect("batch should run");
+        let results = result["results"].as_array().expect("results array");
+
+        assert_eq!(results[0]["key"].as_str(), Some("watch"));
+        let direct = dispatch_inner(Request {
+            version: "2.0".to_string(),
+            id: RequestId::Number(1),
+            method: "watch.add".to_string(),
+            params: msgpack_map! { "path" => dir.clone() },
+            trace: None,
+        })
+        .await;
+        assert_eq!(results[0]["result"], direct.result.unwrap_or(Value::Nil));
+        assert!(results[0]["result"]["path"].is_bin() || results[0]["result"]["path"].is_str());
+        crate::watcher::handle_remove(msgpack_map! { "path" => dir }).unwrap();
+
+        assert_eq!(results[1]["key"].as_u64(), Some(1));
+        let pid = results[1]["result"]["pid"]
+            .as_u64()
+            .expect("process.start pid");
+        let listed = process::list(Value::Nil).await.unwrap();
+        assert!(
+            listed
+                .as_array()
+                .unwrap()
+                .iter()
+                .any(|p| p["pid"].as_u64() == Some(pid))
+        );
+        process::kill(msgpack_map! { "pid" => pid, "signal" => 9 })
+            .await
+            .unwrap();
+
+        assert_eq!(results[2]["key"].as_u64(), Some(7));
+        assert_eq!(
+            results[2]["error"]["code"].as_i64(),
+            Some(i64::from(RpcError::INVALID_REQUEST))
+        );
+    }
+
+    #[tokio::test]
+    async fn capability_probes_are_isolated() {
+        let failed = probe(async { Err("no luck".to_string()) }).await;
+        assert_eq!(failed["available"], Value::Boolean(false));
+        assert_eq!(failed["error"].as_str(), Some("no luck"));
+
+        let panicked = probe(blocking_probe(|| panic!("probe bug"))).await;
+        assert_eq!(panicked["error"].as_str(), Some("probe panicked"));
+
+        let info = system_info().await.unwrap();
+        for name in ["watcher", "pty", "git", "tmp"] {
+            assert!(
+                info["capabilities"][name]["available"].is_bool(),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn non_utf8_environment_values_round_trip() {
+        use std::ffi::OsString;
+        use std::os::unix::ffi::OsStringExt;
+
+        let raw = b"/opt/caf\xe9/bin".to_vec();
+        let encoded = env_value(OsString::from_vec(raw.clone()));
+        assert_eq!(
+            encoded,
+            msgpack_map! {
+                "value" => Value::Binary(raw.clone()),
+                "binary" => true
+            }
+        );
+        assert_eq!(
+            env_value(OsString::from("/usr/bin")),
+            Value::String("/usr/bin".into())
+        );
+
+        // Back into a process environment, as getenv returned it or as
+        // plain binary, and out of the child's through getenv_all
+        for value in [encoded.clone(), Value::Binary(raw.clone())] {
+            let result = process::run(msgpack_map! {
+                "cmd" => std::env::current_exe().unwrap().to_string_lossy().into_owned(),
+                "args" => Value::Array(vec![
+                    "--exact".into(),
+                    "handlers::tests::print_environment_values".into(),
+                    "--ignored".into(),
+                    "--nocapture".into(),
+                ]),
+                "env" => msgpack_map! {
+                    "TRAMP_RPC_TEST_VALUE" => value,
+                    "TRAMP_RPC_TEST_PRINT" => "1",
+                },
+            })
+            .await
+            .unwrap();
+            let stdout = result
+                .as_map()
+                .unwrap()
+                .iter()
+                .find(|(k, _)| k.as_str() == Some("stdout"))
+                .and_then(|(_, v)| v.as_slice().map(<[u8]>::to_vec))
+                .unwrap();
+            let line = stdout
+                .split(|&b| b == b'\n')
+                .find_map(|line| line.strip_prefix(b"value="))
+                .expect("the child printed the value");
+            let mut expected = Vec::new();
+            rmpv::encode::write_value(&mut expected, &encoded).unwrap();
+            assert_eq!(line, hex(&expected).as_bytes());
+        }
+    }
+
+    /// Run by `non_utf8_environment_values_round_trip` in a child process:
+    /// prints how `system.getenv` and `system.getenv_all` encode
+    /// TRAMP_RPC_TEST_VALUE, as hex msgpack.
+    #[test]
+    #[ignore]
+    fn print_environment_values() {
+        if std::env::var_os("TRAMP_RPC_TEST_PRINT").is_none() {
+            return;
+        }
+        let name = "TRAMP_RPC_TEST_VALUE";
+        let value = system_getenv(msgpack_map! { "name" => name }).unwrap();
+        let all = system_getenv_all().unwrap();
+        let listed = all
+            .as_map()
+            .unwrap()
+            .iter()
+            .find(|(k, _)| k.as_str() == Some(name))
+            .map(|(_, v)| v.clone());
+        assert_eq!(listed.as_ref(), Some(&value));
+        let mut bytes = Vec::new();
+        rmpv::encode::write_value(&mut bytes, &value).unwrap();
+        // On a line of its own, after the harness's "test ... "
+        println!("\nvalue={}", hex(&bytes));
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    #[tokio::test]
+    async fn binary_params_and_results_survive_dispatch() {
+        let call = |method: &str, params: Value| {
+            dispatch(Request {
+                version: "2.0".to_string(),
+                id: RequestId::Number(1),
+                method: method.to_string(),
+                params,
+                trace: None,
+            })
+        };
+        let result = |response: Response| match response.error {
+            Some(error) => panic!("{}", error.message),
+            None => response.result.unwrap(),
+        };
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().join(std::ffi::OsStr::from_bytes(b"d\xe9"));
+        std::fs::create_dir(&dir).unwrap();
+        let file = dir.join(std::ffi::OsStr::from_bytes(b"f\xff"));
+        let file_path = Value::Binary(file.as_os_str().as_bytes().to_vec());
+        let dir_path = Value::Binary(dir.as_os_str().as_bytes().to_vec());
+        let content = b"\x00\xff\xfe not text".to_vec();
+
+        // io
+        let written = call(
+            "file.write",
+            msgpack_map! { "path" => file_path.clone(), "content" => Value::Binary(content.clone()) },
+        )
+        .await;
+        assert!(result(written)["written"].as_u64().is_some());
+        let read = result(call("file.read", msgpack_map! { "path" => file_path.clone() }).await);
+        assert_eq!(read["content"].as_slice(), Some(content.as_slice()));
+
+        // file
+        let stat = result(call("file.stat", msgpack_map! { "path" => file_path.clone() }).await);
+        assert_eq!(stat["size"].as_u64(), Some(content.len() as u64));
+
+        // dir
+        let listing = result(call("dir.list", msgpack_map! { "path" => dir_path.clone() }).await);
+        let names: Vec<_> = listing
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|entry| entry["name"].clone())
+            .collect();
+        assert!(names.contains(&Value::Binary(b"f\xff".to_vec())));
+
+        // process
+        let run = result(
+            call(
+                "process.run",
+                msgpack_map! {
+                    "cmd" => "cat",
+                    "args" => Value::Array(vec![Value::Binary(b"f\xff".to_vec())]),
+                    "cwd" => dir_path.clone()
+                },
+            )
+            .await,
+        );
+        assert_eq!(run["exit_code"].as_i64(), Some(0));
+        assert_eq!(run["stdout"].as_slice(), Some(content.as_slice()));
+        let run = result(
+            call(
+                "process.run",
+                msgpack_map! { "cmd" => "cat", "stdin" => Value::Binary(content.clone()) },
+            )
+            .await,
+        );
+        assert_eq!(run["stdout"].as_slice(), Some(content.as_slice()));
+
+        // commands
+        let commands = result(
+            call(
+                "commands.run_parallel",
+                msgpack_map! {
+                    "commands" => Value::Array(vec![msgpack_map! {
+                        "key" => "cat",
+                        "cmd" => "cat",
+                        "args" => Value::Array(vec![Value::Binary(b"f\xff".to_vec())]),
+                        "cwd" => dir_path
+                    }])
+                },
+            )
+            .await,
+        );
+        assert_eq!(
+            commands["cat"]["stdout"].as_slice(),
+            Some(content.as_slice())
+        );
+    }
 }
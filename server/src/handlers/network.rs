@@ -0,0 +1,1012 @@
+//! Downloads for TRAMP-RPC
+//!
+//! This module provides:
+//! - `network.fetch`: Download an http(s) URL straight onto this host, so
+//!   bootstrapping tools on it does not route the bytes through Emacs
+//! - `network.check`: Probe whether this host can resolve and connect to
+//!   a list of `host:port` targets
+//!
+//! With the `http-client` feature the download runs in-process (reqwest
+//! with rustls); otherwise it runs `curl`, or `wget` when curl is missing.
+//! Either way, proxies come from the usual environment variables.
+
+use crate::jail;
+use crate::msgpack_map;
+use crate::protocol::{IntoValue, Notification, RpcError, from_value, path_or_bytes};
+use crate::stat_cache;
+use rmpv::Value;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::ffi::OsString;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+use super::HandlerResult;
+use super::file::{bytes_to_path, map_io_error};
+
+/// Default and maximum overall time for a download, in seconds.
+const DEFAULT_TIMEOUT_SECS: u64 = 300;
+const MAX_TIMEOUT_SECS: u64 = 6 * 3600;
+
+/// Time allowed to connect, within the overall timeout.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Default and maximum number of redirects followed.
+const DEFAULT_MAX_REDIRECTS: u32 = 10;
+const MAX_REDIRECTS: u32 = 20;
+
+/// Default download size limit (1 GiB).
+const DEFAULT_MAX_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// Minimum time between `network.progress` notifications for a download.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Default and maximum time for one `network.check` probe, in milliseconds.
+const DEFAULT_PROBE_TIMEOUT_MS: u64 = 3000;
+const MAX_PROBE_TIMEOUT_MS: u64 = 30_000;
+
+/// Maximum number of targets in one `network.check`.
+const MAX_PROBE_TARGETS: usize = 64;
+
+/// Probes running at once, across all `network.check` requests.
+static PROBE_SLOTS: tokio::sync::Semaphore = tokio::sync::Semaphore::const_new(8);
+
+const USER_AGENT: &str = concat!("tramp-rpc/", env!("CARGO_PKG_VERSION"));
+
+/// Transport used for downloads, as reported by `system.info`.
+pub(crate) fn http_client() -> &'static str {
+    if cfg!(feature = "http-client") {
+        "reqwest"
+    } else {
+        "command"
+    }
+}
+
+/// `PATH.part`, where a download is written until it is complete.
+fn part_path(path: &Path) -> PathBuf {
+    let mut part = OsString::from(path.as_os_str());
+    part.push(".part");
+    PathBuf::from(part)
+}
+
+fn parse_sha256(hex: &str) -> Option<[u8; 32]> {
+    let hex = hex.trim().as_bytes();
+    if hex.len() != 64 {
+        return None;
+    }
+    let mut digest = [0u8; 32];
+    for (byte, pair) in digest.iter_mut().zip(hex.chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some(digest)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Start offset of a `Content-Range: bytes START-END/TOTAL` header.
+fn content_range_start(value: &str) -> Option<u64> {
+    let range = value.trim().strip_prefix("bytes")?.trim_start();
+    range.split('-').next()?.trim().parse().ok()
+}
+
+struct Options {
+    timeout: Duration,
+    max_redirects: u32,
+}
+
+/// The final response to a request, after redirects.
+struct Response {
+    status: u16,
+    /// Length of the body, when the server said
+    length: Option<u64>,
+    /// Where a 206 response's body starts
+    range_start: Option<u64>,
+    body: Body,
+}
+
+// ============================================================================
+// In-process client
+// ============================================================================
+
+#[cfg(feature = "http-client")]
+enum Body {
+    Native(reqwest::Response),
+}
+
+#[cfg(feature = "http-client")]
+impl Body {
+    async fn chunk(&mut self) -> Result<Option<Vec<u8>>, RpcError> {
+        let Body::Native(response) = self;
+        let chunk = response
+            .chunk()
+            .await
+            .map_err(|e| RpcError::network_error(e.to_string(), None))?;
+        Ok(chunk.map(|chunk| chunk.to_vec()))
+    }
+
+    async fn finish(self) -> Result<(), RpcError> {
+        Ok(())
+    }
+}
+
+/// GET `url`, from `offset` on if it is not 0.
+#[cfg(feature = "http-client")]
+async fn request(url: &str, offset: u64, options: &Options) -> Result<Response, RpcError> {
+    let client = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::limited(
+            options.max_redirects as usize,
+        ))
+        .connect_timeout(CONNECT_TIMEOUT)
+        .timeout(options.timeout)
+        .user_agent(USER_AGENT)
+        .build()
+        .map_err(|e| RpcError::network_error(e.to_string(), None))?;
+    let mut request = client.get(url);
+    if offset > 0 {
+        request = request.header(reqwest::header::RANGE, format!("bytes={}-", offset));
+    }
+    let response = request
+        .send()
+        .await
+        .map_err(|e| RpcError::network_error(e.to_string(), None))?;
+    Ok(Response {
+        status: response.status().as_u16(),
+        length: response.content_length(),
+        range_start: response
+            .headers()
+            .get(reqwest::header::CONTENT_RANGE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(content_range_start),
+        body: Body::Native(response),
+    })
+}
+
+// ============================================================================
+// curl / wget
+// ============================================================================
+
+#[cfg(not(feature = "http-client"))]
+enum Body {
+    Command {
+        program: &'static str,
+        child: tokio::process::Child,
+        stdout: tokio::io::BufReader<tokio::process::ChildStdout>,
+    },
+}
+
+#[cfg(not(feature = "http-client"))]
+impl Body {
+    async fn chunk(&mut self) -> Result<Option<Vec<u8>>, RpcError> {
+        let Body::Command { stdout, .. } = self;
+        let mut chunk = vec![0u8; 64 * 1024];
+        let n = stdout
+            .read(&mut chunk)
+            .await
+            .map_err(|e| RpcError::network_error(e.to_string(), None))?;
+        chunk.truncate(n);
+        Ok((n > 0).then_some(chunk))
+    }
+
+    /// Wait for the program, failing if it did.
+    async fn finish(self) -> Result<(), RpcError> {
+        let Body::Command {
+            program, mut child, ..
+        } = self;
+        let status = child
+            .wait()
+            .await
+            .map_err(|e| RpcError::network_error(e.to_string(), None))?;
+        if status.success() {
+            return Ok(());
+        }
+        let mut stderr = String::new();
+        if let Some(mut pipe) = child.stderr.take() {
+            let _ = pipe.read_to_string(&mut stderr).await;
+        }
+        let reason = match stderr.lines().find(|line| !line.trim().is_empty()) {
+            Some(line) => line.trim().to_string(),
+            None => format!(
+                "exit status {}",
+                crate::protocol::exit_code_from_status(status)
+            ),
+        };
+        Err(RpcError::network_error(
+            format!("{} failed: {}", program, reason),
+            None,
+        ))
+    }
+}
+
+/// GET `url`, from `offset` on if it is not 0, with curl.
+///
+/// curl writes the headers of each response before the body (`-D -`), so
+/// the final status and length can be read off its output; wget gives no
+/// such access, so it is assumed to have sent the whole file.
+#[cfg(not(feature = "http-client"))]
+async fn request(url: &str, offset: u64, options: &Options) -> Result<Response, RpcError> {
+    use std::process::Stdio;
+    use tokio::io::AsyncBufReadExt;
+    use tokio::process::Command;
+
+    let mut curl = Command::new("curl");
+    curl.args(["-sS", "-L", "-D", "-", "--suppress-connect-headers"])
+        .args(["--proto", "=http,https", "--proto-redir", "=http,https"])
+        .arg("--max-redirs")
+        .arg(options.max_redirects.to_string())
+        .arg("--connect-timeout")
+        .arg(CONNECT_TIMEOUT.as_secs().to_string())
+        .arg("--max-time")
+        .arg(options.timeout.as_secs().to_string())
+        .args(["-A", USER_AGENT]);
+    if offset > 0 {
+        curl.arg("-r").arg(format!("{}-", offset));
+    }
+    curl.arg(url);
+
+    let (program, mut child) = match spawn(&mut curl) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let mut wget = Command::new("wget");
+            wget.args(["-q", "-O", "-", "--tries", "1"])
+                .arg(format!("--max-redirect={}", options.max_redirects))
+                .arg(format!("--timeout={}", CONNECT_TIMEOUT.as_secs()))
+                .args(["-U", USER_AGENT])
+                .arg(url);
+            match spawn(&mut wget) {
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    return Err(RpcError::network_error(
+                        "Neither curl nor wget is installed",
+                        None,
+                    ));
+                }
+                spawned => ("wget", spawned.map_err(RpcError::io_error)?),
+            }
+        }
+        spawned => ("curl", spawned.map_err(RpcError::io_error)?),
+    };
+
+    fn spawn(command: &mut Command) -> std::io::Result<tokio::process::Child> {
+        command
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+    }
+
+    let stdout = child
+        .stdout
+        .take()
+        .ok_or_else(|| RpcError::internal_error("Missing stdout pipe"))?;
+    let mut body = Body::Command {
+        program,
+        child,
+        stdout: tokio::io::BufReader::new(stdout),
+    };
+    if program == "wget" {
+        return Ok(Response {
+            status: 200,
+            length: None,
+            range_start: None,
+            body,
+        });
+    }
+
+    // Header blocks, one per response; interim (1xx) responses and followed
+    // redirects are skipped.
+    let Body::Command { stdout, .. } = &mut body;
+    loop {
+        let mut status = None;
+        let mut length = None;
+        let mut range_start = None;
+        let mut redirected = false;
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            let n = stdout
+                .read_until(b'\n', &mut line)
+                .await
+                .map_err(|e| RpcError::network_error(e.to_string(), None))?;
+            let text = String::from_utf8_lossy(&line);
+            let text = text.trim();
+            if n == 0 || (text.is_empty() && status.is_some()) {
+                break;
+            }
+            if status.is_none() {
+                status = text
+                    .strip_prefix("HTTP/")
+                    .and_then(|rest| rest.split_whitespace().nth(1))
+                    .and_then(|code| code.parse::<u16>().ok());
+                continue;
+            }
+            let Some((name, value)) = text.split_once(':') else {
+                continue;
+            };
+            match name.trim().to_ascii_lowercase().as_str() {
+                "content-length" => length = value.trim().parse().ok(),
+                "content-range" => range_start = content_range_start(value),
+                "location" => redirected = true,
+                _ => {}
+            }
+        }
+        let Some(status) = status else {
+            // No response at all: curl failed, and says why
+            body.finish().await?;
+            return Err(RpcError::network_error("curl sent no response", None));
+        };
+        if (100..200).contains(&status) || ((300..400).contains(&status) && redirected) {
+            continue;
+        }
+        return Ok(Response {
+            status,
+            length,
+            range_start,
+            body,
+        });
+    }
+}
+
+// ============================================================================
+// Writing the download
+// ============================================================================
+
+/// A download in progress into its `.part` file.
+struct Download {
+    file: tokio::fs::File,
+    part: PathBuf,
+    hasher: Sha256,
+    /// Bytes in the part file, including any a previous attempt left
+    received: u64,
+    total: Option<u64>,
+    max_size: u64,
+    progress: Option<Progress>,
+}
+
+struct Progress {
+    url: String,
+    path: Vec<u8>,
+    last: Instant,
+    /// Context of the notifications, from the request's trace
+    context: Option<Value>,
+}
+
+impl Download {
+    /// Open `part`, keeping (and hashing) what is already there if `resume`.
+    async fn open(
+        part: PathBuf,
+        resume: bool,
+        max_size: u64,
+        progress: Option<Progress>,
+    ) -> std::io::Result<Self> {
+        let mut file = tokio::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(!resume)
+            .open(&part)
+            .await?;
+        let mut hasher = Sha256::new();
+        let mut received = 0;
+        let mut buf = vec![0u8; 64 * 1024];
+        loop {
+            let n = file.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+            received += n as u64;
+        }
+        Ok(Self {
+            file,
+            part,
+            hasher,
+            received,
+            total: None,
+            max_size,
+            progress,
+        })
+    }
+
+    /// Throw away what has been received, to start from the beginning.
+    async fn restart(&mut self) -> std::io::Result<()> {
+        self.file.set_len(0).await?;
+        self.file.rewind().await?;
+        self.hasher = Sha256::new();
+        self.received = 0;
+        Ok(())
+    }
+
+    fn too_large(&self, size: u64) -> RpcError {
+        RpcError::file_too_large(&self.part.to_string_lossy(), Some(size), self.max_size)
+    }
+
+    async fn write(&mut self, chunk: &[u8]) -> Result<(), RpcError> {
+        let received = self.received + chunk.len() as u64;
+        if received > self.max_size {
+            return Err(self.too_large(received));
+        }
+        self.file
+            .write_all(chunk)
+            .await
+            .map_err(|e| map_io_error(e, &self.part))?;
+        self.hasher.update(chunk);
+        self.received = received;
+        if self
+            .progress
+            .as_ref()
+            .is_some_and(|progress| progress.last.elapsed() >= PROGRESS_INTERVAL)
+        {
+            self.report_progress().await;
+        }
+        Ok(())
+    }
+
+    async fn report_progress(&mut self) {
+        let Some(progress) = &mut self.progress else {
+            return;
+        };
+        progress.last = Instant::now();
+        let params = msgpack_map! {
+            "url" => progress.url.clone(),
+            "path" => Value::Binary(progress.path.clone()),
+            "received" => self.received,
+            "total" => self.total.into_value()
+        };
+        let notification = Notification::new("network.progress", params);
+        crate::send_notification(notification.with_context(progress.context.clone())).await;
+    }
+}
+
+/// Fetch `url` into `download`, resuming from what it already holds.
+/// Returns the final status and the number of bytes resumed from.
+async fn transfer(
+    url: &str,
+    options: &Options,
+    download: &mut Download,
+) -> Result<(u16, u64), RpcError> {
+    let io_error = RpcError::io_error;
+    let mut response = request(url, download.received, options).await?;
+    if response.status == 416 && download.received > 0 {
+        // The part file is no prefix of what the server has (any more)
+        response.body.finish().await.ok();
+        download.restart().await.map_err(io_error)?;
+        response = request(url, 0, options).await?;
+    }
+    match response.status {
+        206 if download.received > 0 => {
+            if response.range_start != Some(download.received) {
+                return Err(RpcError::network_error(
+                    format!("Server resumed at the wrong offset: {}", url),
+                    Some(206),
+                ));
+            }
+        }
+        200..=299 if download.received > 0 => download.restart().await.map_err(io_error)?,
+        200..=299 => {}
+        status => {
+            return Err(RpcError::network_error(
+                format!("HTTP status {}: {}", status, url),
+                Some(status),
+            ));
+        }
+    }
+    let resumed = download.received;
+    if let Some(length) = response.length {
+        let total = download.received + length;
+        if total > download.max_size {
+            return Err(download.too_large(total));
+        }
+        download.total = Some(total);
+    }
+
+    while let Some(chunk) = response.body.chunk().await? {
+        download.write(&chunk).await?;
+    }
+    response.body.finish().await?;
+    if let Some(total) = download.total
+        && download.received < total
+    {
+        return Err(RpcError::network_error(
+            format!(
+                "Download ended after {} of {} bytes: {}",
+                download.received, total, url
+            ),
+            None,
+        ));
+    }
+    Ok((response.status, resumed))
+}
+
+/// Download an http(s) URL to `path`.
+///
+/// The body is written to `PATH.part` and renamed to `path` once it is
+/// complete and, if `sha256` (hex) is given, matches it.  With `resume`
+/// (the default) an existing part file is continued with a `Range` request;
+/// a server that sends the whole file again is fine too.  A download over
+/// `max_size` bytes (default 1 GiB) fails with FILE_TOO_LARGE; transport
+/// errors, HTTP errors, a `timeout` (seconds, default 300) and checksum
+/// mismatches fail with NETWORK_ERROR.  At most `max_redirects` (default
+/// 10) redirects are followed.
+///
+/// With `progress` set, `network.progress` notifications with `{url, path,
+/// received, total}` are sent while the download runs.
+///
+/// Returns `{path, size, sha256, resumed, status}`, where `resumed` is the
+/// number of bytes kept from an earlier attempt.
+pub async fn fetch(params: Value) -> HandlerResult {
+    #[derive(Deserialize)]
+    struct Params {
+        url: String,
+        #[serde(with = "path_or_bytes")]
+        path: Vec<u8>,
+        #[serde(default)]
+        sha256: Option<String>,
+        #[serde(default = "default_max_size")]
+        max_size: u64,
+        #[serde(default = "default_timeout")]
+        timeout: u64,
+        #[serde(default = "default_max_redirects")]
+        max_redirects: u32,
+        #[serde(default = "default_resume")]
+        resume: bool,
+        #[serde(default)]
+        progress: bool,
+    }
+
+    fn default_max_size() -> u64 {
+        DEFAULT_MAX_SIZE
+    }
+
+    fn default_timeout() -> u64 {
+        DEFAULT_TIMEOUT_SECS
+    }
+
+    fn default_max_redirects() -> u32 {
+        DEFAULT_MAX_REDIRECTS
+    }
+
+    fn default_resume() -> bool {
+        true
+    }
+
+    let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
+
+    let url = params.url.trim();
+    let lower = url.to_ascii_lowercase();
+    if !lower.starts_with("http://") && !lower.starts_with("https://") {
+        return Err(RpcError::invalid_params("url must be http:// or https://"));
+    }
+    let expected = params
+        .sha256
+        .as_deref()
+        .map(|hex| {
+            parse_sha256(hex)
+                .ok_or_else(|| RpcError::invalid_params("sha256 must be 64 hex digits"))
+        })
+        .transpose()?;
+    if params.timeout == 0 || params.timeout > MAX_TIMEOUT_SECS {
+        return Err(RpcError::invalid_params(format!(
+            "timeout must be between 1 and {} seconds",
+            MAX_TIMEOUT_SECS
+        )));
+    }
+    if params.max_redirects > MAX_REDIRECTS {
+        return Err(RpcError::invalid_params(format!(
+            "max_redirects must be at most {}",
+            MAX_REDIRECTS
+        )));
+    }
+
+    let path = bytes_to_path(&params.path);
+    jail::check(&path)?;
+    let part = part_path(&path);
+    jail::check(&part)?;
+
+    let progress = params.progress.then(|| Progress {
+        url: url.to_string(),
+        path: params.path.clone(),
+        last: Instant::now(),
+        context: crate::trace::context(crate::trace::current(), "fetch", url.to_string()),
+    });
+    let mut download = Download::open(part.clone(), params.resume, params.max_size, progress)
+        .await
+        .map_err(|e| map_io_error(e, &part))?;
+    let options = Options {
+        timeout: Duration::from_secs(params.timeout),
+        max_redirects: params.max_redirects,
+    };
+
+    let transferred =
+        match tokio::time::timeout(options.timeout, transfer(url, &options, &mut download)).await {
+            Ok(transferred) => transferred,
+            Err(_) => Err(RpcError::network_error(
+                format!("Timed out after {} seconds: {}", params.timeout, url),
+                None,
+            )),
+        };
+    let (status, resumed) = match transferred {
+        Ok(transferred) => transferred,
+        Err(e) => {
+            // Keep the part file to resume from, unless it will never do
+            if e.code == RpcError::FILE_TOO_LARGE {
+                let _ = tokio::fs::remove_file(&part).await;
+            }
+            return Err(e);
+        }
+    };
+    download.report_progress().await;
+
+    let Download {
+        file,
+        hasher,
+        received,
+        ..
+    } = download;
+    file.sync_all().await.map_err(|e| map_io_error(e, &part))?;
+    drop(file);
+    let digest: [u8; 32] = hasher.finalize().into();
+    if let Some(expected) = expected
+        && digest != expected
+    {
+        let _ = tokio::fs::remove_file(&part).await;
+        let mut error = RpcError::network_error(format!("Checksum mismatch: {}", url), None);
+        error.data = Some(msgpack_map! {
+            "expected_sha256" => to_hex(&expected),
+            "sha256" => to_hex(&digest)
+        });
+        return Err(error);
+    }
+
+    tokio::fs::rename(&part, &path)
+        .await
+        .map_err(|e| map_io_error(e, &path))?;
+    stat_cache::invalidate(&part).await;
+    stat_cache::invalidate(&path).await;
+
+    Ok(msgpack_map! {
+        "path" => Value::Binary(path.as_os_str().as_bytes().to_vec()),
+        "size" => received,
+        "sha256" => to_hex(&digest),
+        "resumed" => resumed,
+        "status" => status
+    })
+}
+
+// ============================================================================
+// Connectivity probes
+// ============================================================================
+
+/// Split `host:port` (or `[v6]:port`); the port is optional if `dns_only`.
+fn parse_target(target: &str, dns_only: bool) -> Result<(String, u16), String> {
+    let (host, port) = match target.rsplit_once(':') {
+        Some((host, port)) if !host.contains(':') || host.ends_with(']') => (host, Some(port)),
+        _ => (target, None),
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if host.is_empty() {
+        return Err("missing host".to_string());
+    }
+    let port = match port {
+        Some(port) => port
+            .parse()
+            .map_err(|_| format!("invalid port: {}", port))?,
+        None if dns_only => 0,
+        None => return Err("missing port".to_string()),
+    };
+    Ok((host.to_string(), port))
+}
+
+fn millis(elapsed: Duration) -> f64 {
+    (elapsed.as_secs_f64() * 1_000_000.0).round() / 1000.0
+}
+
+/// Resolve `target` and, unless `dns_only`, connect to its addresses in
+/// turn until one accepts, all within `timeout`.
+async fn probe(target: String, dns_only: bool, timeout: Duration) -> Value {
+    let _slot = PROBE_SLOTS.acquire().await;
+    let failed = |error: String| {
+        msgpack_map! {
+            "target" => target.clone(),
+            "ok" => false,
+            "error" => error
+        }
+    };
+    let (host, port) = match parse_target(&target, dns_only) {
+        Ok(parsed) => parsed,
+        Err(error) => return failed(error),
+    };
+
+    let deadline = tokio::time::Instant::now() + timeout;
+    let started = Instant::now();
+    let resolved =
+        tokio::time::timeout_at(deadline, tokio::net::lookup_host((host.as_str(), port))).await;
+    let addresses: Vec<std::net::SocketAddr> = match resolved {
+        Ok(Ok(addresses)) => addresses.collect(),
+        Ok(Err(e)) => return failed(format!("resolving {}: {}", host, e)),
+        Err(_) => return failed(format!("resolving {}: timed out", host)),
+    };
+    let dns_ms = millis(started.elapsed());
+    if addresses.is_empty() {
+        return failed(format!("resolving {}: no addresses", host));
+    }
+    let address_values = || {
+        Value::Array(
+            addresses
+                .iter()
+                .map(|address| Value::from(address.ip().to_string()))
+                .collect(),
+        )
+    };
+    if dns_only {
+        return msgpack_map! {
+            "target" => target.clone(),
+            "ok" => true,
+            "addresses" => address_values(),
+            "dns_ms" => dns_ms
+        };
+    }
+
+    let mut last_error = String::new();
+    for address in &addresses {
+        let started = Instant::now();
+        match tokio::time::timeout_at(deadline, tokio::net::TcpStream::connect(address)).await {
+            Ok(Ok(_stream)) => {
+                return msgpack_map! {
+                    "target" => target.clone(),
+                    "ok" => true,
+                    "address" => address.to_string(),
+                    "addresses" => address_values(),
+                    "dns_ms" => dns_ms,
+                    "latency_ms" => millis(started.elapsed())
+                };
+            }
+            Ok(Err(e)) => last_error = format!("connecting to {}: {}", address, e),
+            Err(_) => {
+                last_error = format!("connecting to {}: timed out", address);
+                break;
+            }
+        }
+    }
+    let mut result = failed(last_error);
+    if let Value::Map(entries) = &mut result {
+        entries.push(("addresses".into(), address_values()));
+        entries.push(("dns_ms".into(), dns_ms.into()));
+    }
+    result
+}
+
+/// Check whether this host can reach `targets` (`"host:port"` strings).
+///
+/// Each target is resolved and connected to over TCP, trying its
+/// addresses in turn, within `timeout_ms` (default 3000).  With `dns_only`
+/// the targets are only resolved, and need no port.  Probes run as
+/// separate tasks, a few at a time across the whole server.
+///
+/// Returns one result per target, in order: `{target, ok, address,
+/// addresses, dns_ms, latency_ms}` on success, where `address` is the one
+/// connected to and `latency_ms` the connect time; `{target, ok: false,
+/// error}` (with whatever was resolved) on failure.
+pub async fn check(params: Value) -> HandlerResult {
+    #[derive(Deserialize)]
+    struct Params {
+        targets: Vec<String>,
+        #[serde(default)]
+        dns_only: bool,
+        #[serde(default = "default_timeout_ms")]
+        timeout_ms: u64,
+    }
+
+    fn default_timeout_ms() -> u64 {
+        DEFAULT_PROBE_TIMEOUT_MS
+    }
+
+    let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
+
+    if params.targets.len() > MAX_PROBE_TARGETS {
+        return Err(RpcError::invalid_params(format!(
+            "Too many targets: {} (max {})",
+            params.targets.len(),
+            MAX_PROBE_TARGETS
+        )));
+    }
+    if params.timeout_ms == 0 || params.timeout_ms > MAX_PROBE_TIMEOUT_MS {
+        return Err(RpcError::invalid_params(format!(
+            "timeout_ms must be between 1 and {}",
+            MAX_PROBE_TIMEOUT_MS
+        )));
+    }
+
+    let timeout = Duration::from_millis(params.timeout_ms);
+    let probes: Vec<_> = params
+        .targets
+        .into_iter()
+        .map(|target| tokio::spawn(probe(target, params.dns_only, timeout)))
+        .collect();
+    let mut results = Vec::with_capacity(probes.len());
+    for probe in probes {
+        results.push(probe.await.map_err(crate::blocking::join_error)?);
+    }
+    Ok(Value::Array(results))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncBufReadExt;
+
+    /// Serve `body` at `/file` over HTTP/1.0, honouring `Range: bytes=N-`,
+    /// and 404 for anything else.  Returns the base URL.
+    async fn serve(body: Vec<u8>) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let body = body.clone();
+                tokio::spawn(async move {
+                    let mut stream = tokio::io::BufReader::new(stream);
+                    let mut request_line = String::new();
+                    stream.read_line(&mut request_line).await.unwrap();
+                    let mut start = None;
+                    loop {
+                        let mut line = String::new();
+                        stream.read_line(&mut line).await.unwrap();
+                        if line.trim().is_empty() {
+                            break;
+                        }
+                        if let Some(range) = line.to_ascii_lowercase().strip_prefix("range: bytes=")
+                        {
+                            start = range.trim().trim_end_matches('-').parse::<usize>().ok();
+                        }
+                    }
+                    let response = if !request_line.starts_with("GET /file ") {
+                        b"HTTP/1.0 404 Not Found\r\nContent-Length: 0\r\n\r\n".to_vec()
+                    } else if let Some(start) = start {
+                        let mut response = format!(
+                            "HTTP/1.0 206 Partial Content\r\nContent-Length: {}\r\n\
+                             Content-Range: bytes {}-{}/{}\r\n\r\n",
+                            body.len() - start,
+                            start,
+                            body.len() - 1,
+                            body.len()
+                        )
+                        .into_bytes();
+                        response.extend_from_slice(&body[start..]);
+                        response
+                    } else {
+                        let mut response =
+                            format!("HTTP/1.0 200 OK\r\nContent-Length: {}\r\n\r\n", body.len())
+                                .into_bytes();
+                        response.extend_from_slice(&body);
+                        response
+                    };
+                    let _ = stream.get_mut().write_all(&response).await;
+                });
+            }
+        });
+        format!("http://{}", addr)
+    }
+
+    fn sha256_hex(data: &[u8]) -> String {
+        to_hex(&Sha256::digest(data))
+    }
+
+    #[tokio::test]
+    async fn fetch_downloads_resumes_and_verifies() {
+        let body: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        let base = serve(body.clone()).await;
+        let tmp = tempfile::tempdir().unwrap();
+        let dest = tmp.path().join("tool.bin");
+        let path = Value::Binary(dest.as_os_str().as_bytes().to_vec());
+
+        let result = fetch(msgpack_map! {
+            "url" => format!("{}/file", base),
+            "path" => path.clone(),
+            "sha256" => sha256_hex(&body),
+        })
+        .await
+        .unwrap();
+        assert_eq!(std::fs::read(&dest).unwrap(), body);
+        assert_eq!(result["resumed"].as_u64(), Some(0));
+        assert!(!part_path(&dest).exists());
+
+        // A partial download left behind is continued
+        std::fs::remove_file(&dest).unwrap();
+        std::fs::write(part_path(&dest), &body[..50_000]).unwrap();
+        let result = fetch(msgpack_map! {
+            "url" => format!("{}/file", base),
+            "path" => path.clone(),
+            "sha256" => sha256_hex(&body),
+        })
+        .await
+        .unwrap();
+        assert_eq!(result["resumed"].as_u64(), Some(50_000));
+        assert_eq!(result["status"].as_u64(), Some(206));
+        assert_eq!(std::fs::read(&dest).unwrap(), body);
+
+        let err = fetch(msgpack_map! {
+            "url" => format!("{}/file", base),
+            "path" => path.clone(),
+            "sha256" => sha256_hex(b"something else"),
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(err.code, RpcError::NETWORK_ERROR);
+        assert_eq!(
+            err.data.unwrap()["sha256"].as_str(),
+            Some(sha256_hex(&body).as_str())
+        );
+        assert!(!part_path(&dest).exists());
+
+        let err = fetch(msgpack_map! {
+            "url" => format!("{}/file", base),
+            "path" => path.clone(),
+            "max_size" => 1000,
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(err.code, RpcError::FILE_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn check_probes_targets() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let open = listener.local_addr().unwrap();
+        let closed = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap()
+        };
+
+        let results = check(msgpack_map! {
+            "targets" => Value::Array(vec![
+                open.to_string().into(),
+                closed.to_string().into(),
+                "no-port".into(),
+            ]),
+        })
+        .await
+        .unwrap();
+        let results = results.as_array().unwrap();
+        assert_eq!(results[0]["ok"].as_bool(), Some(true));
+        assert_eq!(
+            results[0]["address"].as_str(),
+            Some(open.to_string().as_str())
+        );
+        assert!(results[0]["latency_ms"].as_f64().is_some());
+        assert_eq!(results[1]["ok"].as_bool(), Some(false));
+        assert_eq!(results[2]["error"].as_str(), Some("missing port"));
+
+        let results = check(msgpack_map! {
+            "targets" => Value::Array(vec!["127.0.0.1".into(), "[::1]:22".into()]),
+            "dns_only" => true,
+        })
+        .await
+        .unwrap();
+        assert_eq!(results[0]["addresses"][0].as_str(), Some("127.0.0.1"));
+        assert_eq!(results[1]["addresses"][0].as_str(), Some("::1"));
+    }
+
+    #[tokio::test]
+    async fn fetch_reports_http_errors() {
+        let base = serve(Vec::new()).await;
+        let tmp = tempfile::tempdir().unwrap();
+        let err = fetch(msgpack_map! {
+            "url" => format!("{}/missing", base),
+            "path" => Value::Binary(tmp.path().join("x").as_os_str().as_bytes().to_vec()),
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(err.code, RpcError::NETWORK_ERROR);
+        assert_eq!(err.data.unwrap()["status"].as_u64(), Some(404));
+
+        let err = fetch(msgpack_map! {
+            "url" => "file:///etc/passwd",
+            "path" => Value::Binary(tmp.path().join("x").as_os_str().as_bytes().to_vec()),
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(err.code, RpcError::INVALID_PARAMS);
+    }
+}
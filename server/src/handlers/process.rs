@@ -1,7 +1,11 @@
 //! Process execution operations
 
+use crate::jail;
 use crate::msgpack_map;
-use crate::protocol::{ProcessResult, RpcError, from_value};
+use crate::protocol::{
+    EnvValue, ExitSignal, OutputAs, PathBytes, ProcessResult, RpcError, from_value, path_or_bytes,
+    with_signal,
+};
 use nix::pty::{OpenptyResult, openpty};
 use nix::sys::signal::Signal;
 use nix::sys::wait::{WaitPidFlag, WaitStatus, waitpid};
@@ -9,33 +13,129 @@ use nix::unistd::{Pid, tcgetpgrp};
 use rmpv::Value;
 use serde::Deserialize;
 use std::collections::HashMap;
+use std::ffi::OsString;
 use std::io::ErrorKind;
 use std::os::fd::{AsRawFd, BorrowedFd, RawFd};
+use std::os::unix::ffi::OsStringExt;
+use std::path::{Path, PathBuf};
 use std::process::{Command as StdCommand, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::{Arc, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
 use tokio::process::{Child, ChildStderr, ChildStdin, ChildStdout, Command};
 use tokio::sync::Mutex;
 
 use super::HandlerResult;
 
+/// Environment variables passed in `env`.  Values are strings, or for
+/// values that are not valid UTF-8, binary or `{value, binary: true}` as
+/// from `system.getenv`.
+type EnvVars = HashMap<String, EnvValue>;
+
 // ============================================================================
 // Process management for async processes
 // ============================================================================
 
 static PROCESS_MAP: OnceLock<Mutex<HashMap<u32, ManagedProcess>>> = OnceLock::new();
-static PID_COUNTER: OnceLock<Mutex<u32>> = OnceLock::new();
+
+/// Ids of managed processes and PTYs, from one counter so that an id names
+/// at most one of them
+static NEXT_ID: AtomicU32 = AtomicU32::new(1);
 
 fn get_process_map() -> &'static Mutex<HashMap<u32, ManagedProcess>> {
     PROCESS_MAP.get_or_init(|| Mutex::new(HashMap::new()))
 }
 
-async fn get_next_pid() -> u32 {
-    let counter = PID_COUNTER.get_or_init(|| Mutex::new(1));
-    let mut pid = counter.lock().await;
-    let current = *pid;
-    *pid += 1;
-    current
+/// How many managed processes and PTYs are open, as `system.reexec` would
+/// lose them
+pub(crate) async fn open_count() -> usize {
+    get_process_map().lock().await.len() + get_pty_process_map().lock().await.len()
+}
+
+/// Kill every managed process and PTY, as the server does when it shuts
+/// down.
+pub(crate) async fn kill_all() {
+    for (_, mut managed) in get_process_map().lock().await.drain() {
+        let _ = managed.child.start_kill();
+    }
+    for (_, managed) in get_pty_process_map().lock().await.drain() {
+        let _ = nix::sys::signal::kill(managed.child_pid, Signal::SIGKILL);
+    }
+}
+
+fn next_id() -> u32 {
+    NEXT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// The two families of managed processes, kept in separate maps
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    Process,
+    Pty,
+}
+
+impl Kind {
+    fn name(self) -> &'static str {
+        match self {
+            Kind::Process => "process",
+            Kind::Pty => "pty",
+        }
+    }
+
+    fn description(self) -> &'static str {
+        match self {
+            Kind::Process => "a pipe process",
+            Kind::Pty => "a PTY",
+        }
+    }
+
+    /// The methods that take ids of this kind
+    fn methods(self) -> &'static str {
+        match self {
+            Kind::Process => "process.*",
+            Kind::Pty => "process.*_pty",
+        }
+    }
+}
+
+/// The error for `pid` missing from the map of `expected`, naming the other
+/// kind if the id belongs to it.  Callers release their map first, as both
+/// are locked here.
+async fn not_found(pid: u32, expected: Kind) -> RpcError {
+    let actual = if get_process_map().lock().await.contains_key(&pid) {
+        Kind::Process
+    } else if get_pty_process_map().lock().await.contains_key(&pid) {
+        Kind::Pty
+    } else {
+        expected
+    };
+    if actual == expected {
+        return RpcError::process_error(match expected {
+            Kind::Process => format!("Process not found: {}", pid),
+            Kind::Pty => format!("PTY process not found: {}", pid),
+        });
+    }
+    let mut error = RpcError::process_error(format!(
+        "Process {} is {}, not {}; use the {} methods",
+        pid,
+        actual.description(),
+        expected.description(),
+        actual.methods()
+    ));
+    error.data = Some(msgpack_map! {
+        "reason" => "wrong_kind",
+        "kind" => actual.name()
+    });
+    error
+}
+
+async fn process_not_found(pid: u32) -> RpcError {
+    not_found(pid, Kind::Process).await
+}
+
+async fn pty_not_found(pid: u32) -> RpcError {
+    not_found(pid, Kind::Pty).await
 }
 
 struct ManagedProcess {
@@ -45,6 +145,20 @@ struct ManagedProcess {
     stdout: Arc<Mutex<Option<ChildStdout>>>,
     stderr: Arc<Mutex<Option<ChildStderr>>>,
     cmd: String,
+    /// Where stdout and stderr go instead of pipes
+    output_files: (Option<PathBuf>, Option<PathBuf>),
+    /// Where stdin comes from instead of `process.write`
+    stdin_file: Option<Arc<StdinFile>>,
+    /// From the trace of the starting request; see `crate::trace`
+    context: Option<Value>,
+}
+
+/// Add the `context` a process was started with, if it had one.
+fn with_context(mut value: Value, context: Option<&Value>) -> Value {
+    if let (Value::Map(fields), Some(context)) = (&mut value, context) {
+        fields.push(("context".into(), context.clone()));
+    }
+    value
 }
 
 // ============================================================================
@@ -52,39 +166,155 @@ struct ManagedProcess {
 // ============================================================================
 
 /// Run a command and wait for it to complete
+/// Who to run a `process.run` command as
+#[derive(Deserialize)]
+struct Become {
+    /// "su" or "sudo"
+    method: String,
+    /// Target user, root by default
+    #[serde(default)]
+    user: Option<String>,
+    /// Seconds to wait for the password, as for `process.run_sudo`
+    #[serde(default)]
+    password_timeout: Option<u64>,
+}
+
 pub async fn run(params: Value) -> HandlerResult {
     #[derive(Deserialize)]
     struct Params {
         /// Command to run
         cmd: String,
-        /// Arguments
+        /// Arguments, strings or binary
         #[serde(default)]
-        args: Vec<String>,
-        /// Working directory
+        args: Vec<PathBytes>,
+        /// Working directory, a string or binary
         #[serde(default)]
-        cwd: Option<String>,
+        cwd: Option<PathBytes>,
         /// Environment variables to set
         #[serde(default)]
-        env: Option<HashMap<String, String>>,
+        env: Option<EnvVars>,
         /// Stdin input as binary
         #[serde(default, with = "serde_bytes")]
         stdin: Option<Vec<u8>>,
         /// Clear environment before setting env vars
         #[serde(default)]
         clear_env: bool,
+        /// Run as another user: `{method: "su" | "sudo", user, password_timeout}`
+        #[serde(default, rename = "become")]
+        become_user: Option<Become>,
+        /// Send stdout to a file instead of returning it
+        #[serde(default)]
+        stdout_file: Option<OutputFile>,
+        /// Send stderr to a file instead of returning it
+        #[serde(default)]
+        stderr_file: Option<OutputFile>,
+        /// Read stdin from a file instead of `stdin`
+        #[serde(default)]
+        stdin_file: Option<PathBytes>,
+        /// How to send stdout and stderr: "auto", "text_lossy" or "binary"
+        #[serde(default)]
+        output_as: Option<String>,
     }
 
     let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
+    let output_as = OutputAs::parse(params.output_as.as_deref())?;
+
+    if params.become_user.is_some()
+        && (params.stdout_file.is_some()
+            || params.stderr_file.is_some()
+            || params.stdin_file.is_some())
+    {
+        return Err(RpcError::invalid_params(
+            "stdout_file, stderr_file and stdin_file are not supported with become",
+        ));
+    }
+    if params.stdin.is_some() && params.stdin_file.is_some() {
+        return Err(RpcError::invalid_params(
+            "stdin and stdin_file are mutually exclusive",
+        ));
+    }
+
+    if let Some(become_user) = params.become_user {
+        let cwd = match &params.cwd {
+            Some(cwd) => {
+                let cwd = super::working_dir(cwd);
+                jail::check(&cwd)?;
+                Some(cwd)
+            }
+            None => None,
+        };
+        // sudo and su reset the environment, so it is set by `env` instead
+        let mut argv: Vec<OsString> = Vec::new();
+        if params.clear_env || params.env.as_ref().is_some_and(|env| !env.is_empty()) {
+            argv.push("env".into());
+            if params.clear_env {
+                argv.push("-i".into());
+            }
+            for (key, value) in params.env.iter().flatten() {
+                let mut assignment = format!("{}=", key).into_bytes();
+                assignment.extend_from_slice(value.as_bytes());
+                argv.push(OsString::from_vec(assignment));
+            }
+        }
+        argv.push(params.cmd.into());
+        argv.extend(
+            params
+                .args
+                .into_iter()
+                .map(|PathBytes(arg)| OsString::from_vec(arg)),
+        );
+
+        let user = become_user.user.as_deref().unwrap_or("root");
+        let password_timeout = super::sudo::password_timeout(become_user.password_timeout)?;
+        let result = match become_user.method.as_str() {
+            "sudo" => {
+                let program = argv.remove(0);
+                super::sudo::run_as(super::sudo::SudoCommand {
+                    user,
+                    program: &program,
+                    args: argv,
+                    cwd,
+                    stdin: params.stdin,
+                    password_timeout,
+                })
+                .await?
+            }
+            "su" => {
+                if params.stdin.is_some() {
+                    return Err(RpcError::invalid_params(
+                        "stdin is not supported with become method \"su\"",
+                    ));
+                }
+                super::sudo::run_su(super::sudo::SuCommand {
+                    user,
+                    argv,
+                    cwd,
+                    password_timeout,
+                })
+                .await?
+            }
+            other => {
+                return Err(RpcError::invalid_params(format!(
+                    "become method must be \"su\" or \"sudo\", got \"{}\"",
+                    other
+                )));
+            }
+        };
+        return Ok(result.to_value_as(output_as));
+    }
 
     let mut cmd = Command::new(&params.cmd);
     cmd.args(&params.args);
 
     if let Some(cwd) = &params.cwd {
-        cmd.current_dir(super::expand_tilde(cwd));
+        let cwd = super::working_dir(cwd);
+        jail::check(&cwd)?;
+        cmd.current_dir(cwd);
     }
 
-    if params.clear_env {
+    if let Some(base) = crate::env_policy::base(params.clear_env) {
         cmd.env_clear();
+        cmd.envs(base);
     }
 
     if let Some(env) = &params.env {
@@ -97,9 +327,13 @@ pub async fn run(params: Value) -> HandlerResult {
     if params.stdin.is_some() {
         cmd.stdin(Stdio::piped());
     }
+    let stdin_file = params.stdin_file.map(StdinFile::open).transpose()?;
+    if let Some(stdin_file) = &stdin_file {
+        cmd.stdin(stdin_file.stdio()?);
+    }
 
-    cmd.stdout(Stdio::piped());
-    cmd.stderr(Stdio::piped());
+    let mut outputs = OutputFiles::open(params.stdout_file, params.stderr_file)?;
+    outputs.apply(&mut cmd);
 
     let mut child = cmd
         .spawn()
@@ -122,18 +356,203 @@ pub async fn run(params: Value) -> HandlerResult {
     let exit_code = crate::protocol::exit_code_from_status(output.status);
     let result = ProcessResult {
         exit_code,
+        signal: ExitSignal::from_status(output.status),
         stdout: output.stdout,
         stderr: output.stderr,
     };
 
-    Ok(result.to_value())
+    let result = with_output_files(result.to_value_as(output_as), &outputs.paths());
+    Ok(with_stdin_file(result, stdin_file.as_ref()))
 }
 
 // ============================================================================
 // Asynchronous process management
 // ============================================================================
 
+/// A file a process's stdout or stderr goes to: `{path, append?, mode?}`.
+/// Without `append` the file is truncated; `mode` applies when it is
+/// created (0644 by default, less the umask).
+#[derive(Deserialize, Clone)]
+struct OutputFile {
+    #[serde(with = "path_or_bytes")]
+    path: Vec<u8>,
+    #[serde(default)]
+    append: bool,
+    #[serde(default)]
+    mode: Option<u32>,
+}
+
+/// The output files of one process, opened before it is spawned so that a
+/// file that cannot be opened fails the request up front
+struct OutputFiles {
+    stdout: Option<(PathBuf, std::fs::File)>,
+    stderr: Option<(PathBuf, std::fs::File)>,
+}
+
+impl OutputFiles {
+    fn open(stdout: Option<OutputFile>, stderr: Option<OutputFile>) -> Result<Self, RpcError> {
+        use std::os::unix::fs::OpenOptionsExt;
+        let open = |spec: &OutputFile| -> Result<(PathBuf, std::fs::File), RpcError> {
+            let path = super::file::bytes_to_path(&spec.path);
+            jail::check(&path)?;
+            let file = std::fs::File::options()
+                .write(true)
+                .create(true)
+                .append(spec.append)
+                .truncate(!spec.append)
+                .mode(spec.mode.unwrap_or(0o644))
+                .open(&path)
+                .map_err(|e| super::file::map_io_error(e, &path))?;
+            Ok((path, file))
+        };
+        let stdout = stdout.as_ref().map(open).transpose()?;
+        let stderr = match (&stdout, &stderr) {
+            // One file description for both, so the output interleaves in
+            // order rather than one stream overwriting the other
+            (Some((out_path, out)), Some(spec))
+                if super::file::bytes_to_path(&spec.path) == *out_path =>
+            {
+                let file = out
+                    .try_clone()
+                    .map_err(|e| super::file::map_io_error(e, out_path))?;
+                Some((out_path.clone(), file))
+            }
+            _ => stderr.as_ref().map(open).transpose()?,
+        };
+        Ok(Self { stdout, stderr })
+    }
+
+    /// Redirect `cmd`'s stdout and stderr to the files, or to pipes where
+    /// there are none.
+    fn apply(&mut self, cmd: &mut Command) {
+        let stdio = |file: Option<&(PathBuf, std::fs::File)>| match file.map(|(_, f)| f.try_clone())
+        {
+            Some(Ok(file)) => Stdio::from(file),
+            _ => Stdio::piped(),
+        };
+        cmd.stdout(stdio(self.stdout.as_ref()));
+        cmd.stderr(stdio(self.stderr.as_ref()));
+    }
+
+    /// The paths of the files, for reporting sizes later
+    fn paths(&self) -> (Option<PathBuf>, Option<PathBuf>) {
+        (
+            self.stdout.as_ref().map(|(path, _)| path.clone()),
+            self.stderr.as_ref().map(|(path, _)| path.clone()),
+        )
+    }
+}
+
+/// Add `stdout_file` / `stderr_file` entries of `{path, size}` to `result`
+/// for the streams that went to files.
+fn with_output_files(result: Value, paths: &(Option<PathBuf>, Option<PathBuf>)) -> Value {
+    let Value::Map(mut pairs) = result else {
+        return result;
+    };
+    for (key, path) in [("stdout_file", &paths.0), ("stderr_file", &paths.1)] {
+        if let Some(path) = path {
+            let size = std::fs::metadata(path).map_or(Value::Nil, |m| m.len().into());
+            pairs.push((
+                key.into(),
+                msgpack_map! {
+                    "path" => Value::Binary(path.as_os_str().as_encoded_bytes().to_vec()),
+                    "size" => size
+                },
+            ));
+        }
+    }
+    Value::Map(pairs)
+}
+
+/// A file a process reads its stdin from, opened before it is spawned so
+/// that a missing or unreadable file fails the request up front
+struct StdinFile {
+    path: PathBuf,
+    file: std::fs::File,
+    /// The size of a regular file; FIFOs and devices have none
+    size: Option<u64>,
+}
+
+impl StdinFile {
+    fn open(PathBytes(path): PathBytes) -> Result<Self, RpcError> {
+        use std::os::unix::fs::OpenOptionsExt;
+        let path = super::file::bytes_to_path(&path);
+        jail::check(&path)?;
+        let path_str = path.to_string_lossy().into_owned();
+        // Non-blocking, so that opening a FIFO does not wait for a writer;
+        // the child reads it blocking as usual.
+        let file = std::fs::File::options()
+            .read(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(&path)
+            .map_err(|e| super::file::map_io_error(e, &path))?;
+        let fd = file.as_raw_fd();
+        checked_fcntl(unsafe { libc::fcntl(fd, libc::F_GETFL) })
+            .and_then(|flags| {
+                checked_fcntl(unsafe { libc::fcntl(fd, libc::F_SETFL, flags & !libc::O_NONBLOCK) })
+            })
+            .map_err(|e| super::file::map_io_error(e, &path))?;
+        let metadata = file
+            .metadata()
+            .map_err(|e| super::file::map_io_error(e, &path))?;
+        if metadata.is_dir() {
+            return Err(super::file::map_io_error(
+                std::io::Error::from_raw_os_error(libc::EISDIR),
+                &path_str,
+            ));
+        }
+        let size = metadata.is_file().then_some(metadata.len());
+        Ok(Self { path, file, size })
+    }
+
+    /// The file as the child's stdin.  The child shares its offset, which
+    /// is how much it has consumed.
+    fn stdio(&self) -> Result<Stdio, RpcError> {
+        let file = self
+            .file
+            .try_clone()
+            .map_err(|e| super::file::map_io_error(e, &self.path))?;
+        Ok(file.into())
+    }
+
+    /// `{path, size, consumed}`, where `consumed` is the offset the child
+    /// has read up to, for regular files
+    fn to_value(&self) -> Value {
+        use std::io::Seek;
+        let consumed = self
+            .size
+            .and((&self.file).stream_position().ok())
+            .map_or(Value::Nil, Value::from);
+        msgpack_map! {
+            "path" => Value::Binary(self.path.as_os_str().as_encoded_bytes().to_vec()),
+            "size" => self.size.map_or(Value::Nil, Value::from),
+            "consumed" => consumed
+        }
+    }
+}
+
+/// Add a `stdin_file` entry of `{path, size, consumed}` to `result` if
+/// stdin came from a file.
+fn with_stdin_file(result: Value, stdin_file: Option<&StdinFile>) -> Value {
+    match (result, stdin_file) {
+        (Value::Map(mut pairs), Some(stdin_file)) => {
+            pairs.push(("stdin_file".into(), stdin_file.to_value()));
+            Value::Map(pairs)
+        }
+        (result, _) => result,
+    }
+}
+
+// ============================================================================
+
 /// Start an async process
+///
+/// `stdout_file` and `stderr_file` send a stream to a file, as for
+/// `process.run`, and the result then carries `stdout_file` /
+/// `stderr_file` as `{path, size}`.  `stdin_file` feeds stdin from a file
+/// instead of `process.write`; `process.status` reports how much of it the
+/// child has consumed.  With `detach` the process outlives the server; see
+/// [`start_detached`].
 pub async fn start(params: Value) -> HandlerResult {
     #[derive(Deserialize)]
     struct Params {
@@ -143,22 +562,53 @@ pub async fn start(params: Value) -> HandlerResult {
         #[serde(default)]
         cwd: Option<String>,
         #[serde(default)]
-        env: Option<HashMap<String, String>>,
+        env: Option<EnvVars>,
         #[serde(default)]
         clear_env: bool,
+        /// Start the process detached; see `start_detached`
+        #[serde(default)]
+        detach: bool,
+        /// Send stdout to a file instead of `process.read`
+        #[serde(default)]
+        stdout_file: Option<OutputFile>,
+        /// Send stderr to a file instead of `process.read`
+        #[serde(default)]
+        stderr_file: Option<OutputFile>,
+        /// Read stdin from a file instead of `process.write`
+        #[serde(default)]
+        stdin_file: Option<PathBytes>,
     }
 
     let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
 
+    let stdin_file = params.stdin_file.map(StdinFile::open).transpose()?;
+    let mut outputs = OutputFiles::open(params.stdout_file, params.stderr_file)?;
+
+    if params.detach {
+        return start_detached(
+            &params.cmd,
+            &params.args,
+            params.cwd.as_deref(),
+            params.env.as_ref(),
+            params.clear_env,
+            stdin_file,
+            outputs,
+        )
+        .await;
+    }
+
     let mut cmd = Command::new(&params.cmd);
     cmd.args(&params.args);
 
     if let Some(cwd) = &params.cwd {
-        cmd.current_dir(super::expand_tilde(cwd));
+        let cwd = super::expand_tilde(cwd);
+        jail::check(Path::new(&cwd))?;
+        cmd.current_dir(cwd);
     }
 
-    if params.clear_env {
+    if let Some(base) = crate::env_policy::base(params.clear_env) {
         cmd.env_clear();
+        cmd.envs(base);
     }
 
     if let Some(env) = &params.env {
@@ -167,15 +617,17 @@ pub async fn start(params: Value) -> HandlerResult {
         }
     }
 
-    cmd.stdin(Stdio::piped());
-    cmd.stdout(Stdio::piped());
-    cmd.stderr(Stdio::piped());
+    match &stdin_file {
+        Some(stdin_file) => cmd.stdin(stdin_file.stdio()?),
+        None => cmd.stdin(Stdio::piped()),
+    };
+    outputs.apply(&mut cmd);
 
     let mut child = cmd
         .spawn()
         .map_err(|e| RpcError::process_error(format!("Failed to spawn process: {}", e)))?;
 
-    let pid = get_next_pid().await;
+    let pid = next_id();
 
     let managed = ManagedProcess {
         exit_status: None,
@@ -184,13 +636,96 @@ pub async fn start(params: Value) -> HandlerResult {
         stderr: Arc::new(Mutex::new(child.stderr.take())),
         child,
         cmd: params.cmd.clone(),
+        output_files: outputs.paths(),
+        stdin_file: stdin_file.map(Arc::new),
+        context: crate::trace::context(crate::trace::current(), "process", pid),
     };
 
+    let result = with_output_files(msgpack_map! { "pid" => pid }, &outputs.paths());
+    let result = with_stdin_file(result, managed.stdin_file.as_deref());
     get_process_map().lock().await.insert(pid, managed);
+    Ok(result)
+}
 
-    Ok(msgpack_map! {
-        "pid" => pid
-    })
+/// Start a process that outlives the server.
+///
+/// The child runs in a session of its own, with stdin from `stdin_file` or
+/// /dev/null and stdout and stderr sent to `stdout_file` and `stderr_file`
+/// or to /dev/null.  It is not managed: it is not in the process map, so
+/// `process.read`, `process.kill` and the like do not know it and nothing
+/// kills it when the server exits.  The server only reaps it
+/// while it runs.
+///
+/// Returns `{os_pid, detached, stdout_path, stderr_path}`; there is no
+/// managed `pid`.
+async fn start_detached(
+    program: &str,
+    args: &[String],
+    cwd: Option<&str>,
+    env: Option<&EnvVars>,
+    clear_env: bool,
+    stdin_file: Option<StdinFile>,
+    outputs: OutputFiles,
+) -> HandlerResult {
+    let (stdout_path, stderr_path) = outputs.paths();
+    let dev_null = || PathBuf::from("/dev/null");
+    let (stdout_path, stderr_path) = (
+        stdout_path.unwrap_or_else(dev_null),
+        stderr_path.unwrap_or_else(dev_null),
+    );
+    let stdio =
+        |file: Option<(PathBuf, std::fs::File)>| file.map_or_else(Stdio::null, |(_, f)| f.into());
+    let (stdout, stderr) = (stdio(outputs.stdout), stdio(outputs.stderr));
+
+    let mut cmd = Command::new(program);
+    cmd.args(args);
+    if let Some(cwd) = cwd {
+        let cwd = super::expand_tilde(cwd);
+        jail::check(Path::new(&cwd))?;
+        cmd.current_dir(cwd);
+    }
+    if let Some(base) = crate::env_policy::base(clear_env) {
+        cmd.env_clear();
+        cmd.envs(base);
+    }
+    if let Some(env) = env {
+        for (key, value) in env {
+            cmd.env(key, value);
+        }
+    }
+    match &stdin_file {
+        Some(stdin_file) => cmd.stdin(stdin_file.stdio()?),
+        None => cmd.stdin(Stdio::null()),
+    };
+    cmd.stdout(stdout);
+    cmd.stderr(stderr);
+
+    // SAFETY: setsid is async-signal-safe.
+    unsafe {
+        cmd.pre_exec(|| {
+            if libc::setsid() < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+
+    let mut child = cmd
+        .spawn()
+        .map_err(|e| RpcError::process_error(format!("Failed to spawn process: {}", e)))?;
+    let os_pid = child.id();
+    tokio::spawn(async move {
+        let _ = child.wait().await;
+    });
+
+    let path_value = |path: &Path| Value::Binary(path.as_os_str().as_encoded_bytes().to_vec());
+    let result = msgpack_map! {
+        "os_pid" => os_pid.map_or(Value::Nil, Value::from),
+        "detached" => true,
+        "stdout_path" => path_value(&stdout_path),
+        "stderr_path" => path_value(&stderr_path)
+    };
+    Ok(with_stdin_file(result, stdin_file.as_ref()))
 }
 
 /// Write to an async process's stdin
@@ -210,11 +745,11 @@ pub async fn write(params: Value) -> HandlerResult {
 
     let stdin = {
         let processes = get_process_map().lock().await;
-        processes
-            .get(&params.pid)
-            .ok_or_else(|| RpcError::process_error(format!("Process not found: {}", params.pid)))?
-            .stdin
-            .clone()
+        let Some(managed) = processes.get(&params.pid) else {
+            drop(processes);
+            return Err(process_not_found(params.pid).await);
+        };
+        managed.stdin.clone()
     };
 
     let mut stdin_guard = stdin.lock().await;
@@ -231,16 +766,29 @@ pub async fn write(params: Value) -> HandlerResult {
 }
 
 /// Read from an async process's stdout/stderr
+///
+/// Returns `{stdout, stderr, exited, exit_code, stdout_pending,
+/// stderr_pending}`.  `max_bytes` bounds stdout and stderr together: the
+/// stream that has data first gets up to all of it and the other the rest.
+/// `stdout_pending` / `stderr_pending` say that a stream has more to read
+/// (data or its EOF) right now, so the client can read again at once
+/// instead of waiting out a poll.  `exited` is only set once both streams
+/// are at EOF, so it never comes with pending output.  For streams sent
+/// to files by `stdout_file` / `stderr_file`, the exited result carries
+/// `stdout_file` / `stderr_file` as `{path, size}`.
 pub async fn read(params: Value) -> HandlerResult {
     #[derive(Deserialize)]
     struct Params {
         pid: u32,
-        /// Maximum bytes to read
+        /// Maximum bytes to read from both streams together
         #[serde(default = "default_max_read")]
         max_bytes: usize,
         /// Timeout in milliseconds to wait for data. If 0 or not specified, returns immediately.
         #[serde(default)]
         timeout_ms: Option<u64>,
+        /// How to send stdout and stderr: "auto", "text_lossy" or "binary"
+        #[serde(default)]
+        output_as: Option<String>,
     }
 
     fn default_max_read() -> usize {
@@ -248,6 +796,7 @@ pub async fn read(params: Value) -> HandlerResult {
     }
 
     let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
+    let output_as = OutputAs::parse(params.output_as.as_deref())?;
 
     if params.max_bytes == 0 {
         return Err(RpcError::invalid_params(
@@ -256,13 +805,21 @@ pub async fn read(params: Value) -> HandlerResult {
     }
 
     let timeout = params.timeout_ms.unwrap_or(0);
+    // Reading and waiting for the exit below share the caller's timeout
+    let deadline = std::time::Instant::now() + std::time::Duration::from_millis(timeout);
 
-    let (stdout, stderr) = {
+    let (stdout, stderr, output_files, stdin_file) = {
         let processes = get_process_map().lock().await;
-        let managed = processes
-            .get(&params.pid)
-            .ok_or_else(|| RpcError::process_error(format!("Process not found: {}", params.pid)))?;
-        (managed.stdout.clone(), managed.stderr.clone())
+        let Some(managed) = processes.get(&params.pid) else {
+            drop(processes);
+            return Err(process_not_found(params.pid).await);
+        };
+        (
+            managed.stdout.clone(),
+            managed.stderr.clone(),
+            managed.output_files.clone(),
+            managed.stdin_file.clone(),
+        )
     };
 
     // Try to read stdout/stderr (with optional blocking timeout) without
@@ -271,22 +828,40 @@ pub async fn read(params: Value) -> HandlerResult {
     // `process.write` calls wait behind the read timeout, which turns LSP
     // typing into a synchronous round-trip bottleneck.
     let (stdout_result, stderr_result) =
-        try_read_streams(stdout, stderr, params.max_bytes, timeout).await?;
+        try_read_streams(stdout.clone(), stderr.clone(), params.max_bytes, timeout).await?;
 
     let stdout_eof = matches!(stdout_result, ReadResult::Eof);
     let stderr_eof = matches!(stderr_result, ReadResult::Eof);
+    let stdout_pending = !stdout_eof && stream_readable(&stdout).await;
+    let stderr_pending = !stderr_eof && stream_readable(&stderr).await;
     let stdout_data = stdout_result.into_data();
     let stderr_data = stderr_result.into_data();
 
     // Check if process has exited.  Reacquire the map briefly; do not hold it
     // across any await points above.
-    let exit_status = {
-        let mut processes = get_process_map().lock().await;
-        let managed = processes
-            .get_mut(&params.pid)
-            .ok_or_else(|| RpcError::process_error(format!("Process not found: {}", params.pid)))?;
-        poll_exit_status(managed)
-            .map_err(|e| RpcError::process_error(format!("Failed to query process status: {e}")))?
+    let exit_status = loop {
+        let exit_status = {
+            let mut processes = get_process_map().lock().await;
+            let Some(managed) = processes.get_mut(&params.pid) else {
+                drop(processes);
+                return Err(process_not_found(params.pid).await);
+            };
+            poll_exit_status(managed).map_err(|e| {
+                RpcError::process_error(format!("Failed to query process status: {e}"))
+            })?
+        };
+
+        // Both pipes can reach EOF slightly before the child becomes
+        // reapable.  Keep polling for what is left of the caller's timeout
+        // instead of returning an empty, non-terminal result the client
+        // would spin on.
+        if exit_status.is_some()
+            || !(stdout_eof && stderr_eof)
+            || std::time::Instant::now() >= deadline
+        {
+            break exit_status;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(5)).await;
     };
 
     // Child exit and pipe EOF are separate events.  A child can exit after a
@@ -296,33 +871,39 @@ pub async fn read(params: Value) -> HandlerResult {
     // output has been delivered.
     let exited = exit_status.is_some() && stdout_eof && stderr_eof;
 
-    // Return binary data directly (no encoding!)
     let stdout_val = if stdout_data.is_empty() {
         Value::Nil
     } else {
-        Value::Binary(stdout_data)
+        output_as.encode(stdout_data)
     };
 
     let stderr_val = if stderr_data.is_empty() {
         Value::Nil
     } else {
-        Value::Binary(stderr_data)
+        output_as.encode(stderr_data)
     };
 
-    let exit_code = if exited {
-        exit_status
-            .map(crate::protocol::exit_code_from_status)
-            .map(|code| Value::Integer(code.into()))
-            .unwrap_or(Value::Nil)
-    } else {
-        Value::Nil
-    };
+    let exit_status = exit_status.filter(|_| exited);
+    let exit_code = exit_status
+        .map(crate::protocol::exit_code_from_status)
+        .map(|code| Value::Integer(code.into()))
+        .unwrap_or(Value::Nil);
 
-    Ok(msgpack_map! {
+    let result = msgpack_map! {
         "stdout" => stdout_val,
         "stderr" => stderr_val,
         "exited" => exited,
-        "exit_code" => exit_code
+        "exit_code" => exit_code,
+        "stdout_pending" => stdout_pending,
+        "stderr_pending" => stderr_pending
+    };
+    let result = with_signal(result, exit_status.and_then(ExitSignal::from_status));
+    // The sizes of output files are final once the process has exited
+    Ok(if exited {
+        let result = with_output_files(result, &output_files);
+        with_stdin_file(result, stdin_file.as_deref())
+    } else {
+        result
     })
 }
 
@@ -344,11 +925,17 @@ impl ReadResult {
 fn poll_exit_status(managed: &mut ManagedProcess) -> std::io::Result<Option<ExitStatus>> {
     if managed.exit_status.is_none() {
         managed.exit_status = managed.child.try_wait()?;
+        if managed.exit_status.is_some() {
+            // It may have changed any path while it ran
+            crate::stat_cache::clear();
+        }
     }
     Ok(managed.exit_status)
 }
 
-/// Read both output streams until either produces data or the shared timeout expires.
+/// Read both output streams until either produces data or the shared timeout
+/// expires.  The first stream with data gets up to `max_bytes`; the other is
+/// then read without waiting for what is left of the budget.
 async fn try_read_streams<ROut, RErr>(
     stdout: Arc<Mutex<Option<ROut>>>,
     stderr: Arc<Mutex<Option<RErr>>>,
@@ -359,55 +946,108 @@ where
     ROut: AsyncRead + Unpin,
     RErr: AsyncRead + Unpin,
 {
-    let stdout_read = async {
-        try_read_optional_stream(stdout, max_bytes)
-            .await
-            .map_err(|e| RpcError::process_error(format!("Failed to read stdout: {e}")))
-    };
-    let stderr_read = async {
-        try_read_optional_stream(stderr, max_bytes)
-            .await
-            .map_err(|e| RpcError::process_error(format!("Failed to read stderr: {e}")))
-    };
-    let deadline = tokio::time::sleep(std::time::Duration::from_millis(if timeout_ms == 0 {
-        1
-    } else {
-        timeout_ms
-    }));
-
-    tokio::pin!(stdout_read, stderr_read, deadline);
-
-    // AsyncReadExt::read is cancellation-safe: when one stream produces data,
-    // dropping the other branch cannot consume bytes from the idle stream.
-    tokio::select! {
-        stdout_result = &mut stdout_read => {
-            let stdout_result = stdout_result?;
-            if matches!(stdout_result, ReadResult::Data(_)) {
-                return Ok((stdout_result, ReadResult::Pending));
+    use futures::FutureExt;
+
+    /// What the waiting read got: data from one stream, or both results
+    enum First {
+        Stdout(ReadResult),
+        Stderr(ReadResult),
+        Both(ReadResult, ReadResult),
+    }
+
+    let stdout_error = |e| RpcError::process_error(format!("Failed to read stdout: {e}"));
+    let stderr_error = |e| RpcError::process_error(format!("Failed to read stderr: {e}"));
+    let first = {
+        let stdout_read = try_read_optional_stream(stdout.clone(), max_bytes);
+        let stderr_read = try_read_optional_stream(stderr.clone(), max_bytes);
+        let deadline = tokio::time::sleep(std::time::Duration::from_millis(if timeout_ms == 0 {
+            1
+        } else {
+            timeout_ms
+        }));
+
+        tokio::pin!(stdout_read, stderr_read, deadline);
+
+        // AsyncReadExt::read is cancellation-safe: when one stream produces
+        // data, dropping the other branch cannot consume bytes from the idle
+        // stream.
+        tokio::select! {
+            stdout_result = &mut stdout_read => {
+                let stdout_result = stdout_result.map_err(stdout_error)?;
+                if matches!(stdout_result, ReadResult::Data(_)) {
+                    First::Stdout(stdout_result)
+                } else {
+                    let stderr_result = tokio::select! {
+                        stderr_result = &mut stderr_read => stderr_result.map_err(stderr_error)?,
+                        _ = &mut deadline => ReadResult::Pending,
+                    };
+                    First::Both(stdout_result, stderr_result)
+                }
+            }
+            stderr_result = &mut stderr_read => {
+                let stderr_result = stderr_result.map_err(stderr_error)?;
+                if matches!(stderr_result, ReadResult::Data(_)) {
+                    First::Stderr(stderr_result)
+                } else {
+                    let stdout_result = tokio::select! {
+                        stdout_result = &mut stdout_read => stdout_result.map_err(stdout_error)?,
+                        _ = &mut deadline => ReadResult::Pending,
+                    };
+                    First::Both(stdout_result, stderr_result)
+                }
             }
+            _ = &mut deadline => First::Both(ReadResult::Pending, ReadResult::Pending),
+        }
+    };
 
-            let stderr_result = tokio::select! {
-                stderr_result = &mut stderr_read => stderr_result?,
-                _ = &mut deadline => ReadResult::Pending,
+    // With one stream's data in hand (and the pending reads above dropped),
+    // take whatever the other already has, within the remaining budget.
+    let remaining = |data: &ReadResult| match data {
+        ReadResult::Data(data) => max_bytes - data.len(),
+        _ => max_bytes,
+    };
+    match first {
+        First::Both(stdout_result, stderr_result) => Ok((stdout_result, stderr_result)),
+        First::Stdout(stdout_result) => {
+            let stderr_result = match remaining(&stdout_result) {
+                0 => ReadResult::Pending,
+                left => try_read_optional_stream(stderr, left)
+                    .now_or_never()
+                    .transpose()
+                    .map_err(stderr_error)?
+                    .unwrap_or(ReadResult::Pending),
             };
             Ok((stdout_result, stderr_result))
         }
-        stderr_result = &mut stderr_read => {
-            let stderr_result = stderr_result?;
-            if matches!(stderr_result, ReadResult::Data(_)) {
-                return Ok((ReadResult::Pending, stderr_result));
-            }
-
-            let stdout_result = tokio::select! {
-                stdout_result = &mut stdout_read => stdout_result?,
-                _ = &mut deadline => ReadResult::Pending,
+        First::Stderr(stderr_result) => {
+            let stdout_result = match remaining(&stderr_result) {
+                0 => ReadResult::Pending,
+                left => try_read_optional_stream(stdout, left)
+                    .now_or_never()
+                    .transpose()
+                    .map_err(stdout_error)?
+                    .unwrap_or(ReadResult::Pending),
             };
             Ok((stdout_result, stderr_result))
         }
-        _ = &mut deadline => Ok((ReadResult::Pending, ReadResult::Pending)),
     }
 }
 
+/// Whether `stream` has data or EOF waiting, without reading it.
+async fn stream_readable<R: AsRawFd>(stream: &Mutex<Option<R>>) -> bool {
+    let guard = stream.lock().await;
+    let Some(reader) = guard.as_ref() else {
+        return false;
+    };
+    let mut pollfd = libc::pollfd {
+        fd: reader.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    let ret = unsafe { libc::poll(&mut pollfd, 1, 0) };
+    ret > 0 && (pollfd.revents & (libc::POLLIN | libc::POLLHUP)) != 0
+}
+
 /// Try to read from an optional async reader.
 async fn try_read_optional_stream<R>(
     stream: Arc<Mutex<Option<R>>>,
@@ -457,11 +1097,11 @@ pub async fn close_stdin(params: Value) -> HandlerResult {
 
     let stdin = {
         let processes = get_process_map().lock().await;
-        processes
-            .get(&params.pid)
-            .ok_or_else(|| RpcError::process_error(format!("Process not found: {}", params.pid)))?
-            .stdin
-            .clone()
+        let Some(managed) = processes.get(&params.pid) else {
+            drop(processes);
+            return Err(process_not_found(params.pid).await);
+        };
+        managed.stdin.clone()
     };
 
     // Flush any buffered data before closing stdin, then drop to close the pipe.
@@ -494,9 +1134,10 @@ pub async fn kill(params: Value) -> HandlerResult {
     let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
 
     let mut processes = get_process_map().lock().await;
-    let managed = processes
-        .get_mut(&params.pid)
-        .ok_or_else(|| RpcError::process_error(format!("Process not found: {}", params.pid)))?;
+    let Some(managed) = processes.get_mut(&params.pid) else {
+        drop(processes);
+        return Err(process_not_found(params.pid).await);
+    };
 
     // Get the actual OS PID
     let os_pid = managed
@@ -532,17 +1173,21 @@ pub async fn status(params: Value) -> HandlerResult {
     let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
 
     let mut processes = get_process_map().lock().await;
-    let managed = processes
-        .get_mut(&params.pid)
-        .ok_or_else(|| RpcError::process_error(format!("Process not found: {}", params.pid)))?;
+    let Some(managed) = processes.get_mut(&params.pid) else {
+        drop(processes);
+        return Err(process_not_found(params.pid).await);
+    };
 
     let exit_status = poll_exit_status(managed)
         .map_err(|e| RpcError::process_error(format!("Failed to query process status: {e}")))?;
 
-    Ok(msgpack_map! {
+    let result = msgpack_map! {
         "exited" => exit_status.is_some(),
         "exit_code" => exit_status.map(crate::protocol::exit_code_from_status).map(|c| Value::Integer(c.into())).unwrap_or(Value::Nil)
-    })
+    };
+    let result = with_signal(result, exit_status.and_then(ExitSignal::from_status));
+    let result = with_output_files(result, &managed.output_files);
+    Ok(with_stdin_file(result, managed.stdin_file.as_deref()))
 }
 
 /// List all managed async processes
@@ -553,13 +1198,16 @@ pub async fn list(_params: Value) -> HandlerResult {
         .iter_mut()
         .map(|(pid, managed)| {
             let exited = poll_exit_status(managed).ok().flatten();
-            msgpack_map! {
+            let entry = msgpack_map! {
                 "pid" => *pid,
+                "kind" => Kind::Process.name(),
                 "os_pid" => managed.child.id().map(|id| Value::Integer((id as i64).into())).unwrap_or(Value::Nil),
                 "cmd" => managed.cmd.clone(),
                 "exited" => exited.is_some(),
                 "exit_code" => exited.map(crate::protocol::exit_code_from_status).map(|c| Value::Integer(c.into())).unwrap_or(Value::Nil)
-            }
+            };
+            let entry = with_signal(entry, exited.and_then(ExitSignal::from_status));
+            with_context(entry, managed.context.as_ref())
         })
         .collect();
 
@@ -576,25 +1224,29 @@ use tokio::io::Interest;
 use tokio::io::unix::AsyncFd;
 
 static PTY_PROCESS_MAP: OnceLock<Mutex<HashMap<u32, ManagedPtyProcess>>> = OnceLock::new();
-static PTY_PID_COUNTER: OnceLock<Mutex<u32>> = OnceLock::new();
 
 fn get_pty_process_map() -> &'static Mutex<HashMap<u32, ManagedPtyProcess>> {
     PTY_PROCESS_MAP.get_or_init(|| Mutex::new(HashMap::new()))
 }
 
-async fn get_next_pty_pid() -> u32 {
-    let counter = PTY_PID_COUNTER.get_or_init(|| Mutex::new(10000));
-    let mut pid = counter.lock().await;
-    let current = *pid;
-    *pid += 1;
-    current
-}
-
 struct ManagedPtyProcess {
     async_fd: AsyncFd<OwnedFd>,
     child_pid: Pid,
     cmd: String,
     exit_status: Option<i32>,
+    exit_signal: Option<ExitSignal>,
+    /// The client's label for the session, as in "shell:~/project"
+    name: Option<String>,
+    tty_name: String,
+    started: SystemTime,
+    /// The directory the process started in
+    cwd: Option<PathBuf>,
+    /// The size the PTY was started with
+    initial_size: WindowSize,
+    /// From the trace of the starting request; see `crate::trace`
+    context: Option<Value>,
+    /// The session's login record, dropped once it is over
+    login: Option<crate::utmp::Login>,
 }
 
 fn checked_fcntl(result: libc::c_int) -> Result<libc::c_int, std::io::Error> {
@@ -621,12 +1273,34 @@ fn dup_cloexec(fd: RawFd) -> Result<RawFd, std::io::Error> {
     checked_fcntl(unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) })
 }
 
-fn set_window_size(fd: RawFd, rows: u16, cols: u16) -> Result<(), std::io::Error> {
+/// The size of a PTY, in cells and in pixels.  Pixels are 0 when unknown.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+struct WindowSize {
+    rows: u16,
+    cols: u16,
+    #[serde(default)]
+    xpixel: u16,
+    #[serde(default)]
+    ypixel: u16,
+}
+
+impl WindowSize {
+    fn to_value(self) -> Value {
+        msgpack_map! {
+            "rows" => self.rows,
+            "cols" => self.cols,
+            "xpixel" => self.xpixel,
+            "ypixel" => self.ypixel
+        }
+    }
+}
+
+fn set_window_size(fd: RawFd, size: WindowSize) -> Result<(), std::io::Error> {
     let ws = libc::winsize {
-        ws_row: rows,
-        ws_col: cols,
-        ws_xpixel: 0,
-        ws_ypixel: 0,
+        ws_row: size.rows,
+        ws_col: size.cols,
+        ws_xpixel: size.xpixel,
+        ws_ypixel: size.ypixel,
     };
     let result = unsafe { libc::ioctl(fd, libc::TIOCSWINSZ as _, &ws) };
     if result < 0 {
@@ -636,15 +1310,34 @@ fn set_window_size(fd: RawFd, rows: u16, cols: u16) -> Result<(), std::io::Error
     }
 }
 
+fn get_window_size(fd: RawFd) -> Result<WindowSize, std::io::Error> {
+    let mut ws = libc::winsize {
+        ws_row: 0,
+        ws_col: 0,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+    let result = unsafe { libc::ioctl(fd, libc::TIOCGWINSZ as _, &mut ws) };
+    if result < 0 {
+        Err(std::io::Error::last_os_error())
+    } else {
+        Ok(WindowSize {
+            rows: ws.ws_row,
+            cols: ws.ws_col,
+            xpixel: ws.ws_xpixel,
+            ypixel: ws.ws_ypixel,
+        })
+    }
+}
+
 #[derive(Clone)]
 struct PtyStartParams {
     cmd: String,
     args: Vec<String>,
     cwd: Option<String>,
-    env: Option<HashMap<String, String>>,
+    env: Option<EnvVars>,
     clear_env: bool,
-    rows: u16,
-    cols: u16,
+    size: WindowSize,
 }
 
 struct ForkResult2 {
@@ -681,18 +1374,21 @@ fn do_fork_exec(params: PtyStartParams) -> Result<ForkResult2, RpcError> {
         String::from_utf8_lossy(&buf[..nul_pos]).into_owned()
     };
 
-    set_window_size(master.as_raw_fd(), params.rows, params.cols)
+    set_window_size(master.as_raw_fd(), params.size)
         .map_err(|e| RpcError::process_error(format!("Failed to set window size: {}", e)))?;
 
     let mut cmd = StdCommand::new(&params.cmd);
     cmd.args(&params.args);
 
     if let Some(cwd) = &params.cwd {
-        cmd.current_dir(super::expand_tilde(cwd));
+        let cwd = super::expand_tilde(cwd);
+        jail::check(Path::new(&cwd))?;
+        cmd.current_dir(cwd);
     }
 
-    if params.clear_env {
+    if let Some(base) = crate::env_policy::base(params.clear_env) {
         cmd.env_clear();
+        cmd.envs(base);
     }
 
     if let Some(env) = &params.env {
@@ -743,6 +1439,12 @@ fn do_fork_exec(params: PtyStartParams) -> Result<ForkResult2, RpcError> {
 }
 
 /// Start a process with a PTY (pseudo-terminal)
+///
+/// The PTY is `rows` x `cols` cells (24 x 80 by default) and `xpixel` x
+/// `ypixel` pixels (0, unknown, by default) for programs that draw images.
+/// With `detach` the process is started as by `process.start` with
+/// `detach`, without a PTY: one held by the server would hang up when the
+/// server exits.
 pub async fn start_pty(params: Value) -> HandlerResult {
     #[derive(Deserialize)]
     struct Params {
@@ -752,13 +1454,32 @@ pub async fn start_pty(params: Value) -> HandlerResult {
         #[serde(default)]
         cwd: Option<String>,
         #[serde(default)]
-        env: Option<HashMap<String, String>>,
+        env: Option<EnvVars>,
         #[serde(default)]
         clear_env: bool,
         #[serde(default = "default_rows")]
         rows: u16,
         #[serde(default = "default_cols")]
         cols: u16,
+        #[serde(default)]
+        xpixel: u16,
+        #[serde(default)]
+        ypixel: u16,
+        /// A label echoed by `process.list_pty`
+        #[serde(default)]
+        name: Option<String>,
+        /// Start the process detached; see `start_detached`
+        #[serde(default)]
+        detach: bool,
+        /// With `detach`, send stdout to a file as for `process.start`
+        #[serde(default)]
+        stdout_file: Option<OutputFile>,
+        /// With `detach`, send stderr to a file as for `process.start`
+        #[serde(default)]
+        stderr_file: Option<OutputFile>,
+        /// Write utmp and wtmp records for the session; see `crate::utmp`
+        #[serde(default)]
+        register_utmp: bool,
     }
 
     fn default_rows() -> u16 {
@@ -770,19 +1491,47 @@ pub async fn start_pty(params: Value) -> HandlerResult {
 
     let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
 
+    if params.detach {
+        if params.register_utmp {
+            return Err(RpcError::invalid_params(
+                "register_utmp needs a PTY, not a detached process",
+            ));
+        }
+        return start_detached(
+            &params.cmd,
+            &params.args,
+            params.cwd.as_deref(),
+            params.env.as_ref(),
+            params.clear_env,
+            None,
+            OutputFiles::open(params.stdout_file, params.stderr_file)?,
+        )
+        .await;
+    }
+
+    let cwd = match &params.cwd {
+        Some(cwd) => Some(PathBuf::from(super::expand_tilde(cwd))),
+        None => std::env::current_dir().ok(),
+    };
+    let size = WindowSize {
+        rows: params.rows,
+        cols: params.cols,
+        xpixel: params.xpixel,
+        ypixel: params.ypixel,
+    };
     let start_params = PtyStartParams {
         cmd: params.cmd.clone(),
         args: params.args,
         cwd: params.cwd,
         env: params.env,
         clear_env: params.clear_env,
-        rows: params.rows,
-        cols: params.cols,
+        size,
     };
 
-    let fork_result = tokio::task::spawn_blocking(move || do_fork_exec(start_params))
-        .await
-        .map_err(|e| RpcError::process_error(format!("Task join error: {}", e)))??;
+    let fork_result = crate::blocking::run(PathBuf::from(&params.cmd), move || {
+        do_fork_exec(start_params)
+    })
+    .await??;
 
     set_fd_nonblocking(fork_result.master_fd)
         .map_err(|e| RpcError::process_error(format!("Failed to set non-blocking: {}", e)))?;
@@ -791,43 +1540,81 @@ pub async fn start_pty(params: Value) -> HandlerResult {
     let async_fd = AsyncFd::new(owned_fd)
         .map_err(|e| RpcError::process_error(format!("Failed to create AsyncFd: {}", e)))?;
 
-    let our_pid = get_next_pty_pid().await;
+    let our_pid = next_id();
+
+    // A session that cannot be registered still starts
+    let login = params.register_utmp.then(|| {
+        crate::utmp::Login::register(&fork_result.tty_name, fork_result.child_pid.as_raw())
+    });
+    let utmp_error = match &login {
+        Some(Err(e)) => Some(e.clone()),
+        _ => None,
+    };
 
     let managed = ManagedPtyProcess {
         async_fd,
         child_pid: fork_result.child_pid,
         cmd: params.cmd.clone(),
         exit_status: None,
+        exit_signal: None,
+        name: params.name,
+        tty_name: fork_result.tty_name.clone(),
+        started: SystemTime::now(),
+        cwd,
+        initial_size: size,
+        context: crate::trace::context(crate::trace::current(), "pty", our_pid),
+        login: login.and_then(Result::ok),
     };
+    let registered = managed.login.is_some();
 
     get_pty_process_map().lock().await.insert(our_pid, managed);
 
-    Ok(msgpack_map! {
+    let mut result = msgpack_map! {
         "pid" => our_pid,
         "os_pid" => fork_result.child_pid.as_raw(),
         "tty_name" => fork_result.tty_name
-    })
+    };
+    if params.register_utmp
+        && let Value::Map(fields) = &mut result
+    {
+        fields.push(("utmp".into(), registered.into()));
+        if let Some(error) = utmp_error {
+            fields.push(("utmp_error".into(), error.into()));
+        }
+    }
+    Ok(result)
 }
 
-/// Resize a PTY terminal
+/// Resize a PTY terminal to `{rows, cols, xpixel?, ypixel?}`
 pub async fn resize_pty(params: Value) -> HandlerResult {
     #[derive(Deserialize)]
     struct Params {
         pid: u32,
         rows: u16,
         cols: u16,
+        #[serde(default)]
+        xpixel: u16,
+        #[serde(default)]
+        ypixel: u16,
     }
 
     let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
 
     let processes = get_pty_process_map().lock().await;
-    let managed = processes
-        .get(&params.pid)
-        .ok_or_else(|| RpcError::process_error(format!("PTY process not found: {}", params.pid)))?;
+    let Some(managed) = processes.get(&params.pid) else {
+        drop(processes);
+        return Err(pty_not_found(params.pid).await);
+    };
 
     let fd = managed.async_fd.get_ref().as_raw_fd();
 
-    set_window_size(fd, params.rows, params.cols)
+    let size = WindowSize {
+        rows: params.rows,
+        cols: params.cols,
+        xpixel: params.xpixel,
+        ypixel: params.ypixel,
+    };
+    set_window_size(fd, size)
         .map_err(|e| RpcError::process_error(format!("Failed to resize PTY: {}", e)))?;
 
     match tcgetpgrp(unsafe { BorrowedFd::borrow_raw(fd) }) {
@@ -845,6 +1632,27 @@ pub async fn resize_pty(params: Value) -> HandlerResult {
     Ok(Value::Boolean(true))
 }
 
+/// Return the size of a PTY as `{rows, cols, xpixel, ypixel}`, for clients
+/// that reattach to a session and need to resynchronize it.
+pub async fn get_winsize(params: Value) -> HandlerResult {
+    #[derive(Deserialize)]
+    struct Params {
+        pid: u32,
+    }
+
+    let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
+
+    let processes = get_pty_process_map().lock().await;
+    let Some(managed) = processes.get(&params.pid) else {
+        drop(processes);
+        return Err(pty_not_found(params.pid).await);
+    };
+
+    let size = get_window_size(managed.async_fd.get_ref().as_raw_fd())
+        .map_err(|e| RpcError::process_error(format!("Failed to get PTY size: {}", e)))?;
+    Ok(size.to_value())
+}
+
 /// Read from a PTY process with optional blocking
 pub async fn read_pty(params: Value) -> HandlerResult {
     #[derive(Deserialize)]
@@ -854,6 +1662,9 @@ pub async fn read_pty(params: Value) -> HandlerResult {
         max_bytes: usize,
         #[serde(default)]
         timeout_ms: Option<u64>,
+        /// How to send the output: "auto", "text_lossy" or "binary"
+        #[serde(default)]
+        output_as: Option<String>,
     }
 
     fn default_max_read() -> usize {
@@ -861,6 +1672,7 @@ pub async fn read_pty(params: Value) -> HandlerResult {
     }
 
     let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
+    let output_as = OutputAs::parse(params.output_as.as_deref())?;
 
     let timeout = params.timeout_ms.unwrap_or(0);
     let mut buf = vec![0u8; params.max_bytes];
@@ -870,6 +1682,11 @@ pub async fn read_pty(params: Value) -> HandlerResult {
         let managed = match processes.get_mut(&params.pid) {
             Some(m) => m,
             None => {
+                drop(processes);
+                // Gone PTYs read as exited, but other ids are a mix-up
+                if get_process_map().lock().await.contains_key(&params.pid) {
+                    return Err(pty_not_found(params.pid).await);
+                }
                 return Ok(msgpack_map! {
                     "output" => Value::Nil,
                     "exited" => true,
@@ -883,42 +1700,39 @@ pub async fn read_pty(params: Value) -> HandlerResult {
 
         if n > 0 {
             buf.truncate(n as usize);
-            Some((buf.clone(), false, None))
+            Some((buf.clone(), (false, None, None)))
         } else if timeout == 0 {
-            Some((vec![], false, None))
+            Some((vec![], (false, None, None)))
         } else {
-            let (exited, exit_code) = check_exit_status(managed);
-            if exited {
-                Some((vec![], true, exit_code))
-            } else {
-                None
-            }
+            let exit = check_exit_status(managed);
+            if exit.0 { Some((vec![], exit)) } else { None }
         }
     };
 
-    if let Some((output, exited, exit_code)) = read_result {
-        let (exited, exit_code) = if exited {
-            (exited, exit_code)
+    if let Some((output, exit)) = read_result {
+        let (exited, exit_code, signal) = if exit.0 {
+            exit
         } else {
             let mut processes = get_pty_process_map().lock().await;
             if let Some(managed) = processes.get_mut(&params.pid) {
                 check_exit_status(managed)
             } else {
-                (true, None)
+                (true, None, None)
             }
         };
 
         let output_val = if output.is_empty() {
             Value::Nil
         } else {
-            Value::Binary(output)
+            output_as.encode(output)
         };
 
-        return Ok(msgpack_map! {
+        let result = msgpack_map! {
             "output" => output_val,
             "exited" => exited,
             "exit_code" => exit_code.map(|c| Value::Integer(c.into())).unwrap_or(Value::Nil)
-        });
+        };
+        return Ok(with_signal(result, signal));
     }
 
     let wait_result = tokio::time::timeout(
@@ -952,37 +1766,55 @@ pub async fn read_pty(params: Value) -> HandlerResult {
         vec![]
     };
 
-    let (exited, exit_code) = check_exit_status(managed);
+    let (exited, exit_code, signal) = check_exit_status(managed);
 
     let output_val = if output.is_empty() {
         Value::Nil
     } else {
-        Value::Binary(output)
+        output_as.encode(output)
     };
 
-    Ok(msgpack_map! {
+    let result = msgpack_map! {
         "output" => output_val,
         "exited" => exited,
         "exit_code" => exit_code.map(|c| Value::Integer(c.into())).unwrap_or(Value::Nil)
-    })
+    };
+    Ok(with_signal(result, signal))
 }
 
-fn check_exit_status(managed: &mut ManagedPtyProcess) -> (bool, Option<i32>) {
+/// Whether a PTY process has exited, its exit code and the signal that
+/// ended it.  A process ended by a signal keeps the shell's 128 + signal
+/// exit code; the signal itself is returned too.
+fn check_exit_status(managed: &mut ManagedPtyProcess) -> (bool, Option<i32>, Option<ExitSignal>) {
     if managed.exit_status.is_some() {
-        (true, managed.exit_status)
+        (true, managed.exit_status, managed.exit_signal)
     } else {
-        match waitpid(managed.child_pid, Some(WaitPidFlag::WNOHANG)) {
+        let status = waitpid(managed.child_pid, Some(WaitPidFlag::WNOHANG));
+        if matches!(
+            status,
+            Ok(WaitStatus::Exited(..) | WaitStatus::Signaled(..))
+        ) {
+            // It may have changed any path while it ran
+            crate::stat_cache::clear();
+        }
+        match status {
             Ok(WaitStatus::Exited(_, code)) => {
                 managed.exit_status = Some(code);
-                (true, Some(code))
+                managed.login.take();
+                (true, Some(code), None)
             }
-            Ok(WaitStatus::Signaled(_, signal, _)) => {
+            Ok(WaitStatus::Signaled(_, signal, core_dumped)) => {
                 let code = 128 + signal as i32;
                 managed.exit_status = Some(code);
-                (true, Some(code))
+                managed.login.take();
+                managed.exit_signal = Some(ExitSignal {
+                    number: signal as i32,
+                    core_dumped,
+                });
+                (true, Some(code), managed.exit_signal)
             }
-            Ok(WaitStatus::StillAlive) => (false, None),
-            _ => (false, None),
+            Ok(WaitStatus::StillAlive) => (false, None, None),
+            _ => (false, None, None),
         }
     }
 }
@@ -1037,9 +1869,10 @@ pub async fn write_pty(params: Value) -> HandlerResult {
     let data = params.data;
 
     let processes = get_pty_process_map().lock().await;
-    let managed = processes
-        .get(&params.pid)
-        .ok_or_else(|| RpcError::process_error(format!("PTY process not found: {}", params.pid)))?;
+    let Some(managed) = processes.get(&params.pid) else {
+        drop(processes);
+        return Err(pty_not_found(params.pid).await);
+    };
 
     let mut guard = managed
         .async_fd
@@ -1092,9 +1925,10 @@ pub async fn kill_pty(params: Value) -> HandlerResult {
     let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
 
     let mut processes = get_pty_process_map().lock().await;
-    let managed = processes
-        .get(&params.pid)
-        .ok_or_else(|| RpcError::process_error(format!("PTY process not found: {}", params.pid)))?;
+    let Some(managed) = processes.get(&params.pid) else {
+        drop(processes);
+        return Err(pty_not_found(params.pid).await);
+    };
 
     let signal = Signal::try_from(params.signal).map_err(|_| RpcError {
         code: RpcError::INVALID_PARAMS,
@@ -1112,6 +1946,55 @@ pub async fn kill_pty(params: Value) -> HandlerResult {
     Ok(Value::Boolean(true))
 }
 
+/// Interrupt what runs in a PTY: `{pid, signal?}`.
+///
+/// Sends `signal` ("SIGINT" by default, or "SIGTSTP" or "SIGQUIT") to the
+/// terminal's foreground process group, as the line discipline does for
+/// `C-c`, `C-z` and `C-\` unless a program has put the terminal in raw
+/// mode.  If the group cannot be read, the session leader's group gets it.
+/// Returns `{pgid, foreground}`: the group signaled and whether it was the
+/// foreground group.
+pub async fn interrupt_pty(params: Value) -> HandlerResult {
+    #[derive(Deserialize)]
+    struct Params {
+        pid: u32,
+        #[serde(default)]
+        signal: Option<String>,
+    }
+
+    let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
+    let signal = match params.signal.as_deref().unwrap_or("SIGINT") {
+        "SIGINT" => Signal::SIGINT,
+        "SIGTSTP" => Signal::SIGTSTP,
+        "SIGQUIT" => Signal::SIGQUIT,
+        other => {
+            return Err(RpcError::invalid_params(format!(
+                "signal must be \"SIGINT\", \"SIGTSTP\" or \"SIGQUIT\", got \"{}\"",
+                other
+            )));
+        }
+    };
+
+    let processes = get_pty_process_map().lock().await;
+    let Some(managed) = processes.get(&params.pid) else {
+        drop(processes);
+        return Err(pty_not_found(params.pid).await);
+    };
+
+    let fd = managed.async_fd.get_ref().as_raw_fd();
+    let (pgid, foreground) = match tcgetpgrp(unsafe { BorrowedFd::borrow_raw(fd) }) {
+        Ok(fg_pgrp) => (fg_pgrp, true),
+        Err(_) => (managed.child_pid, false),
+    };
+    nix::sys::signal::kill(Pid::from_raw(-pgid.as_raw()), signal)
+        .map_err(|e| RpcError::process_error(format!("Failed to send signal: {}", e)))?;
+
+    Ok(msgpack_map! {
+        "pgid" => pgid.as_raw(),
+        "foreground" => foreground
+    })
+}
+
 /// Close a PTY process and clean up
 pub async fn close_pty(params: Value) -> HandlerResult {
     #[derive(Deserialize)]
@@ -1121,36 +2004,70 @@ pub async fn close_pty(params: Value) -> HandlerResult {
 
     let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
 
-    let mut processes = get_pty_process_map().lock().await;
-
-    match processes.remove(&params.pid) {
+    let removed = get_pty_process_map().lock().await.remove(&params.pid);
+    match removed {
         Some(managed) => {
             let _ = nix::sys::signal::kill(managed.child_pid, Signal::SIGKILL);
             Ok(Value::Boolean(true))
         }
-        _ => Err(RpcError::process_error(format!(
-            "PTY process not found: {}",
-            params.pid
-        ))),
+        _ => Err(pty_not_found(params.pid).await),
     }
 }
 
+/// The current directory of the process `pid`, where /proc shows it
+fn current_dir_of(pid: Pid) -> Option<PathBuf> {
+    std::fs::read_link(format!("/proc/{}/cwd", pid.as_raw())).ok()
+}
+
 /// List all PTY processes
+///
+/// Each entry carries what a client reattaching after a restart needs to
+/// tell sessions apart: the `name` given to `process.start_pty`, the
+/// `tty_name`, `start_time` (seconds since the epoch), the `initial_cwd`
+/// and `initial_size` it was started with, and the `size` and `cwd` it has
+/// now.  `cwd` is that of the session leader, and nil where /proc does not
+/// show it.
 pub async fn list_pty(_params: Value) -> HandlerResult {
     let mut processes = get_pty_process_map().lock().await;
 
     let list: Vec<Value> = processes
         .iter_mut()
         .map(|(pid, managed)| {
-            let (exited, exit_code) = check_exit_status(managed);
+            let (exited, exit_code, signal) = check_exit_status(managed);
+            let size = get_window_size(managed.async_fd.get_ref().as_raw_fd())
+                .map_or(Value::Nil, WindowSize::to_value);
+            let path_value = |path: Option<&Path>| {
+                path.map_or(Value::Nil, |path| {
+                    Value::Binary(path.as_os_str().as_encoded_bytes().to_vec())
+                })
+            };
+            let start_time = managed
+                .started
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs_f64();
+            let cwd = if exited {
+                None
+            } else {
+                current_dir_of(managed.child_pid)
+            };
 
-            msgpack_map! {
+            let entry = msgpack_map! {
                 "pid" => *pid,
+                "kind" => Kind::Pty.name(),
                 "os_pid" => managed.child_pid.as_raw(),
                 "cmd" => managed.cmd.clone(),
+                "name" => managed.name.clone().map_or(Value::Nil, Value::from),
+                "tty_name" => managed.tty_name.clone(),
+                "start_time" => start_time,
                 "exited" => exited,
-                "exit_code" => exit_code.map(|c| Value::Integer(c.into())).unwrap_or(Value::Nil)
-            }
+                "exit_code" => exit_code.map(|c| Value::Integer(c.into())).unwrap_or(Value::Nil),
+                "initial_cwd" => path_value(managed.cwd.as_deref()),
+                "cwd" => path_value(cwd.as_deref()),
+                "initial_size" => managed.initial_size.to_value(),
+                "size" => size
+            };
+            with_context(with_signal(entry, signal), managed.context.as_ref())
         })
         .collect();
 
@@ -1160,14 +2077,7 @@ pub async fn list_pty(_params: Value) -> HandlerResult {
 #[cfg(test)]
 mod tests {
     use super::*;
-
-    fn map_get<'a>(value: &'a Value, key: &str) -> Option<&'a Value> {
-        value.as_map().and_then(|m| {
-            m.iter()
-                .find(|(k, _)| k.as_str() == Some(key))
-                .map(|(_, v)| v)
-        })
-    }
+    use crate::test_util::map_get;
 
     async fn start_pipe_process(script: &str) -> u32 {
         let result = start(Value::Map(vec![
@@ -1284,6 +2194,27 @@ mod tests {
         assert_eq!(exit_code, 0);
     }
 
+    #[tokio::test]
+    async fn process_read_waits_for_exit_within_its_timeout() {
+        // Both pipes close well into the read, long before the child exits
+        let pid = start_pipe_process("sleep 0.6; exec >&- 2>&-; sleep 5").await;
+
+        let started = std::time::Instant::now();
+        let result = read_pipe_process(pid, 65_536, 1_000).await;
+        let elapsed = started.elapsed();
+        let _ = kill(msgpack_map! { "pid" => pid, "signal" => libc::SIGKILL }).await;
+        get_process_map().lock().await.remove(&pid);
+
+        assert_eq!(
+            map_get(&result, "exited").and_then(Value::as_bool),
+            Some(false)
+        );
+        assert!(
+            elapsed < std::time::Duration::from_millis(1_400),
+            "read took {elapsed:?} with a 1s timeout"
+        );
+    }
+
     #[tokio::test]
     async fn process_read_drains_output_larger_than_max_bytes() {
         let pid = start_pipe_process("printf 0123456789abcdef").await;
@@ -1341,6 +2272,123 @@ mod tests {
         assert_eq!(exit_code, 0);
     }
 
+    #[tokio::test]
+    async fn process_read_shares_max_bytes_and_reports_pending() {
+        let pid = start_pipe_process("printf abc; printf XYZ >&2; sleep 0.2").await;
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+
+        let output = |result: &Value, key| match map_get(result, key) {
+            Some(Value::Binary(bytes)) => bytes.clone(),
+            _ => Vec::new(),
+        };
+        let pending = |result: &Value, key| map_get(result, key) == Some(&Value::from(true));
+
+        let first = read_pipe_process(pid, 4, 500).await;
+        let (mut stdout, mut stderr) = (output(&first, "stdout"), output(&first, "stderr"));
+        assert!(stdout.len() + stderr.len() <= 4, "{first:?}");
+        assert!(
+            pending(&first, "stdout_pending") || pending(&first, "stderr_pending"),
+            "two bytes are still waiting: {first:?}"
+        );
+
+        // Reading again without waiting while anything is pending gets the
+        // rest, after which the idle streams are not pending
+        let mut result = first;
+        for _ in 0..8 {
+            if !pending(&result, "stdout_pending") && !pending(&result, "stderr_pending") {
+                break;
+            }
+            result = read_pipe_process(pid, 4, 0).await;
+            stdout.extend(output(&result, "stdout"));
+            stderr.extend(output(&result, "stderr"));
+        }
+        assert_eq!(stdout, b"abc");
+        assert_eq!(stderr, b"XYZ");
+        assert!(!pending(&result, "stdout_pending") && !pending(&result, "stderr_pending"));
+
+        let (_, _, exit_code) = collect_pipe_output(pid, 65_536).await;
+        assert_eq!(exit_code, 0);
+    }
+
+    #[tokio::test]
+    async fn process_read_exits_only_without_pending_output() {
+        let pid = start_pipe_process("printf 0123456789; printf abcdefghij >&2").await;
+        wait_for_child_exit(pid).await;
+
+        let mut stdout = Vec::new();
+        let mut stderr = Vec::new();
+        for _ in 0..64 {
+            let result = read_pipe_process(pid, 3, 500).await;
+            if let Some(Value::Binary(bytes)) = map_get(&result, "stdout") {
+                stdout.extend_from_slice(bytes);
+            }
+            if let Some(Value::Binary(bytes)) = map_get(&result, "stderr") {
+                stderr.extend_from_slice(bytes);
+            }
+            if map_get(&result, "exited").and_then(Value::as_bool) == Some(true) {
+                assert_eq!(
+                    map_get(&result, "stdout_pending"),
+                    Some(&Value::from(false))
+                );
+                assert_eq!(
+                    map_get(&result, "stderr_pending"),
+                    Some(&Value::from(false))
+                );
+                break;
+            }
+        }
+        get_process_map().lock().await.remove(&pid);
+        assert_eq!(stdout, b"0123456789");
+        assert_eq!(stderr, b"abcdefghij");
+    }
+
+    #[tokio::test]
+    async fn process_results_report_the_terminating_signal() {
+        let run_sh = |script: &str| {
+            run(Value::Map(vec![
+                (Value::String("cmd".into()), Value::String("/bin/sh".into())),
+                (
+                    Value::String("args".into()),
+                    Value::Array(vec!["-c".into(), script.into()]),
+                ),
+            ]))
+        };
+        let killed = run_sh("kill -KILL $$").await.unwrap();
+        assert_eq!(
+            map_get(&killed, "exit_code").and_then(Value::as_i64),
+            Some(137)
+        );
+        assert_eq!(map_get(&killed, "signal").and_then(Value::as_i64), Some(9));
+        assert_eq!(
+            map_get(&killed, "signal_name").and_then(Value::as_str),
+            Some("SIGKILL")
+        );
+        assert_eq!(map_get(&killed, "core_dumped"), Some(&Value::from(false)));
+
+        let exited = run_sh("exit 3").await.unwrap();
+        assert_eq!(
+            map_get(&exited, "exit_code").and_then(Value::as_i64),
+            Some(3)
+        );
+        assert_eq!(map_get(&exited, "signal"), Some(&Value::Nil));
+        assert_eq!(map_get(&exited, "signal_name"), Some(&Value::Nil));
+
+        let pid = start_pipe_process("kill -TERM $$").await;
+        wait_for_child_exit(pid).await;
+        let status = status(Value::Map(vec![(
+            Value::String("pid".into()),
+            Value::Integer(pid.into()),
+        )]))
+        .await
+        .unwrap();
+        assert_eq!(
+            map_get(&status, "signal_name").and_then(Value::as_str),
+            Some("SIGTERM")
+        );
+        let (.., exit_code) = collect_pipe_output(pid, 65_536).await;
+        assert_eq!(exit_code, 143);
+    }
+
     #[tokio::test]
     async fn start_pty_applies_env_without_mutating_process_env() {
         let parent_value = std::env::var("TRAMP_RPC_PTY_TEST").ok();
@@ -1396,4 +2444,599 @@ mod tests {
         assert_eq!(String::from_utf8_lossy(&output), "ok");
         assert_eq!(std::env::var("TRAMP_RPC_PTY_TEST").ok(), parent_value);
     }
+
+    #[tokio::test]
+    async fn list_pty_identifies_sessions() {
+        let tmp = tempfile::tempdir().unwrap();
+        let project = tmp.path().canonicalize().unwrap();
+        let subdir = project.join("src");
+        std::fs::create_dir(&subdir).unwrap();
+        let before = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs_f64();
+        let started = start_pty(msgpack_map! {
+            "cmd" => "/bin/sh",
+            "args" => Value::Array(vec!["-c".into(), "cd src && echo moved && read _".into()]),
+            "cwd" => project.to_str().unwrap(),
+            "name" => "shell:~/project"
+        })
+        .await
+        .unwrap();
+        let pid = map_get(&started, "pid").and_then(Value::as_u64).unwrap();
+
+        // Wait until the shell has changed directory
+        for _ in 0..50 {
+            let read = read_pty(msgpack_map! { "pid" => pid, "timeout_ms" => 100 })
+                .await
+                .unwrap();
+            if let Some(Value::Binary(bytes)) = map_get(&read, "output")
+                && bytes.windows(5).any(|w| w == b"moved")
+            {
+                break;
+            }
+        }
+
+        let listed = list_pty(Value::Nil).await.unwrap();
+        let entry = listed
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|entry| map_get(entry, "pid").and_then(Value::as_u64) == Some(pid))
+            .unwrap()
+            .clone();
+        let _ = close_pty(msgpack_map! { "pid" => pid }).await;
+
+        assert_eq!(
+            map_get(&entry, "name").and_then(Value::as_str),
+            Some("shell:~/project")
+        );
+        assert_eq!(map_get(&entry, "tty_name"), map_get(&started, "tty_name"));
+        let start_time = map_get(&entry, "start_time")
+            .and_then(Value::as_f64)
+            .unwrap();
+        assert!(start_time >= before.floor(), "{} < {}", start_time, before);
+        assert_eq!(
+            map_get(&entry, "initial_cwd").and_then(Value::as_slice),
+            Some(project.as_os_str().as_encoded_bytes())
+        );
+        assert_eq!(
+            map_get(&entry, "cwd").and_then(Value::as_slice),
+            Some(subdir.as_os_str().as_encoded_bytes())
+        );
+    }
+
+    #[tokio::test]
+    async fn process_and_pty_ids_do_not_mix() {
+        let piped = start(msgpack_map! { "cmd" => "sleep", "args" => vec![Value::from("5")] })
+            .await
+            .unwrap();
+        let piped = map_get(&piped, "pid").and_then(Value::as_u64).unwrap();
+        let pty = start_pty(msgpack_map! { "cmd" => "/bin/cat" })
+            .await
+            .unwrap();
+        let pty = map_get(&pty, "pid").and_then(Value::as_u64).unwrap();
+        assert_ne!(piped, pty);
+
+        // Each side of the mix-up is told what the id is
+        let err = read_pty(msgpack_map! { "pid" => piped }).await.unwrap_err();
+        assert_eq!(err.code, RpcError::PROCESS_ERROR);
+        let data = err.data.unwrap();
+        assert_eq!(
+            map_get(&data, "reason").and_then(Value::as_str),
+            Some("wrong_kind")
+        );
+        assert_eq!(
+            map_get(&data, "kind").and_then(Value::as_str),
+            Some("process")
+        );
+        let err = close_pty(msgpack_map! { "pid" => piped })
+            .await
+            .unwrap_err();
+        assert!(err.message.contains("not a PTY"), "{}", err.message);
+        let err = kill(msgpack_map! { "pid" => pty }).await.unwrap_err();
+        assert_eq!(
+            map_get(&err.data.unwrap(), "kind").and_then(Value::as_str),
+            Some("pty")
+        );
+        let err = write(msgpack_map! { "pid" => pty, "data" => Value::Binary(b"x".to_vec()) })
+            .await
+            .unwrap_err();
+        assert!(err.message.contains("process.*_pty"), "{}", err.message);
+
+        // Unknown ids are still plainly not found
+        let err = status(msgpack_map! { "pid" => u32::MAX })
+            .await
+            .unwrap_err();
+        assert_eq!(err.message, format!("Process not found: {}", u32::MAX));
+        assert!(err.data.is_none());
+
+        let listed = list(Value::Nil).await.unwrap();
+        let entry = listed
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|entry| map_get(entry, "pid").and_then(Value::as_u64) == Some(piped))
+            .unwrap();
+        assert_eq!(
+            map_get(entry, "kind").and_then(Value::as_str),
+            Some("process")
+        );
+        let listed = list_pty(Value::Nil).await.unwrap();
+        let entry = listed
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|entry| map_get(entry, "pid").and_then(Value::as_u64) == Some(pty))
+            .unwrap();
+        assert_eq!(map_get(entry, "kind").and_then(Value::as_str), Some("pty"));
+
+        let _ = kill(msgpack_map! { "pid" => piped, "signal" => libc::SIGKILL }).await;
+        let _ = close_pty(msgpack_map! { "pid" => pty }).await;
+    }
+
+    #[tokio::test]
+    async fn pty_sessions_start_whether_or_not_utmp_can_be_written() {
+        let started = start_pty(msgpack_map! {
+            "cmd" => "/bin/sh",
+            "args" => Value::Array(vec!["-c".into(), "read _".into()]),
+            "register_utmp" => true
+        })
+        .await
+        .expect("start pty");
+        let pid = map_get(&started, "pid").and_then(Value::as_u64).unwrap();
+        let registered = map_get(&started, "utmp").and_then(Value::as_bool);
+        let error = map_get(&started, "utmp_error");
+        let _ = close_pty(msgpack_map! { "pid" => pid }).await;
+
+        match registered {
+            Some(true) => assert!(error.is_none()),
+            Some(false) => assert!(error.and_then(Value::as_str).is_some()),
+            None => panic!("no utmp in {:?}", started),
+        }
+
+        // Without the option the response is unchanged
+        let started = start_pty(msgpack_map! { "cmd" => "/bin/sh", "args" => Value::Array(vec!["-c".into(), "read _".into()]) })
+            .await
+            .expect("start pty");
+        let pid = map_get(&started, "pid").and_then(Value::as_u64).unwrap();
+        let _ = close_pty(msgpack_map! { "pid" => pid }).await;
+        assert!(map_get(&started, "utmp").is_none());
+
+        let err =
+            start_pty(msgpack_map! { "cmd" => "true", "detach" => true, "register_utmp" => true })
+                .await
+                .unwrap_err();
+        assert_eq!(err.code, RpcError::INVALID_PARAMS);
+    }
+
+    #[tokio::test]
+    async fn interrupts_reach_ptys_in_raw_mode() {
+        let script = "stty raw -isig; trap 'echo caught; exit 7' INT; echo ready; \
+                      while :; do sleep 0.1; done";
+        let started = start_pty(msgpack_map! {
+            "cmd" => "/bin/sh",
+            "args" => Value::Array(vec!["-c".into(), script.into()])
+        })
+        .await
+        .expect("start pty");
+        let pid = map_get(&started, "pid").and_then(Value::as_u64).unwrap();
+        let os_pid = map_get(&started, "os_pid").and_then(Value::as_i64).unwrap();
+        let read_until = |needle: &'static str| async move {
+            let mut output = Vec::new();
+            for _ in 0..50 {
+                let read = read_pty(msgpack_map! { "pid" => pid, "timeout_ms" => 100 })
+                    .await
+                    .expect("read pty");
+                if let Some(Value::Binary(bytes)) = map_get(&read, "output") {
+                    output.extend_from_slice(bytes);
+                }
+                if String::from_utf8_lossy(&output).contains(needle) {
+                    return true;
+                }
+            }
+            false
+        };
+        assert!(read_until("ready").await);
+
+        let err = interrupt_pty(msgpack_map! { "pid" => pid, "signal" => "SIGKILL" })
+            .await
+            .unwrap_err();
+        assert_eq!(err.code, RpcError::INVALID_PARAMS);
+
+        // 0x03 is plain input once the terminal is raw
+        let interrupted = interrupt_pty(msgpack_map! { "pid" => pid }).await.unwrap();
+        let caught = read_until("caught").await;
+        let _ = close_pty(msgpack_map! { "pid" => pid }).await;
+        assert!(caught);
+        assert_eq!(
+            map_get(&interrupted, "foreground"),
+            Some(&Value::Boolean(true))
+        );
+        assert_eq!(
+            map_get(&interrupted, "pgid").and_then(Value::as_i64),
+            Some(os_pid)
+        );
+    }
+
+    #[tokio::test]
+    async fn pty_window_size_round_trips_with_pixels() {
+        let started = start_pty(msgpack_map! {
+            "cmd" => "/bin/sh",
+            "args" => Value::Array(vec!["-c".into(), "read _".into()]),
+            "rows" => 40,
+            "cols" => 120,
+            "xpixel" => 960,
+            "ypixel" => 640
+        })
+        .await
+        .unwrap();
+        let pid = map_get(&started, "pid").and_then(Value::as_u64).unwrap();
+        let tty_name = map_get(&started, "tty_name").unwrap().clone();
+        let get = || get_winsize(msgpack_map! { "pid" => pid });
+
+        let expected = |rows: u16, cols: u16, xpixel: u16, ypixel: u16| {
+            WindowSize {
+                rows,
+                cols,
+                xpixel,
+                ypixel,
+            }
+            .to_value()
+        };
+        assert_eq!(get().await.unwrap(), expected(40, 120, 960, 640));
+
+        resize_pty(msgpack_map! {
+            "pid" => pid,
+            "rows" => 50,
+            "cols" => 132,
+            "xpixel" => 1056,
+            "ypixel" => 800
+        })
+        .await
+        .unwrap();
+        assert_eq!(get().await.unwrap(), expected(50, 132, 1056, 800));
+
+        let listed = list_pty(Value::Nil).await.unwrap();
+        let entry = listed
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|entry| map_get(entry, "pid").and_then(Value::as_u64) == Some(pid))
+            .unwrap();
+        assert_eq!(map_get(entry, "size"), Some(&expected(50, 132, 1056, 800)));
+        assert_eq!(
+            map_get(entry, "initial_size"),
+            Some(&expected(40, 120, 960, 640))
+        );
+        assert_eq!(map_get(entry, "tty_name"), Some(&tty_name));
+
+        let _ = close_pty(msgpack_map! { "pid" => pid }).await;
+        let err = get().await.unwrap_err();
+        assert_eq!(err.code, RpcError::PROCESS_ERROR);
+    }
+
+    #[tokio::test]
+    async fn detached_processes_get_their_own_session_and_output_files() {
+        let tmp = tempfile::tempdir().unwrap();
+        let log = tmp.path().join("service.log");
+        let log_value = msgpack_map! {
+            "path" => log.to_str().unwrap(),
+            "append" => true
+        };
+        let script = "echo out; echo err >&2; echo $$ > pidfile; exec sleep 30";
+        let started = start_pty(msgpack_map! {
+            "cmd" => "/bin/sh",
+            "args" => Value::Array(vec!["-c".into(), script.into()]),
+            "cwd" => tmp.path().to_str().unwrap(),
+            "detach" => true,
+            "stdout_file" => log_value.clone(),
+            "stderr_file" => log_value
+        })
+        .await
+        .unwrap();
+        assert_eq!(map_get(&started, "detached"), Some(&Value::from(true)));
+        assert!(map_get(&started, "pid").is_none());
+        assert_eq!(
+            map_get(&started, "stdout_path").and_then(Value::as_slice),
+            Some(log.as_os_str().as_encoded_bytes())
+        );
+        let os_pid = map_get(&started, "os_pid").and_then(Value::as_i64).unwrap() as i32;
+
+        let pidfile = tmp.path().join("pidfile");
+        for _ in 0..200 {
+            if std::fs::read_to_string(&pidfile).is_ok_and(|s| s.ends_with('\n')) {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert_eq!(
+            std::fs::read_to_string(&pidfile).unwrap().trim(),
+            os_pid.to_string()
+        );
+        assert_eq!(std::fs::read_to_string(&log).unwrap(), "out\nerr\n");
+        // A session leader of its own, unknown to the managed lists
+        assert_eq!(unsafe { libc::getsid(os_pid) }, os_pid);
+        let listed = list_pty(Value::Nil).await.unwrap();
+        assert!(
+            !listed
+                .as_array()
+                .unwrap()
+                .iter()
+                .any(|p| map_get(p, "os_pid").and_then(Value::as_i64) == Some(os_pid as i64))
+        );
+        unsafe { libc::kill(os_pid, libc::SIGKILL) };
+
+        let quiet = start(msgpack_map! { "cmd" => "/bin/true", "detach" => true })
+            .await
+            .unwrap();
+        assert_eq!(
+            map_get(&quiet, "stderr_path").and_then(Value::as_slice),
+            Some(&b"/dev/null"[..])
+        );
+    }
+
+    #[tokio::test]
+    async fn output_files_take_the_place_of_inline_output() {
+        let tmp = tempfile::tempdir().unwrap();
+        let out = tmp.path().join("build.log");
+        let file = |path: &Path, append: bool| {
+            msgpack_map! {
+                "path" => path.to_str().unwrap(),
+                "append" => append,
+                "mode" => 0o600
+            }
+        };
+        let run_sh = |script: &str, stdout_file: Value, stderr_file: Value| {
+            run(msgpack_map! {
+                "cmd" => "/bin/sh",
+                "args" => Value::Array(vec!["-c".into(), script.into()]),
+                "stdout_file" => stdout_file,
+                "stderr_file" => stderr_file
+            })
+        };
+
+        let result = run_sh("echo one; echo two >&2", file(&out, false), Value::Nil)
+            .await
+            .unwrap();
+        assert_eq!(
+            map_get(&result, "stdout").and_then(Value::as_slice),
+            Some(&b""[..])
+        );
+        assert_eq!(
+            map_get(&result, "stderr").and_then(Value::as_slice),
+            Some(&b"two\n"[..])
+        );
+        let stdout_file = map_get(&result, "stdout_file").unwrap();
+        assert_eq!(
+            map_get(stdout_file, "size").and_then(Value::as_u64),
+            Some(4)
+        );
+        assert!(map_get(&result, "stderr_file").is_none());
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(&out).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        // Both streams to one appended file keep their order
+        run_sh(
+            "echo three; echo four >&2",
+            file(&out, true),
+            file(&out, true),
+        )
+        .await
+        .unwrap();
+        assert_eq!(std::fs::read_to_string(&out).unwrap(), "one\nthree\nfour\n");
+        run_sh("echo five", file(&out, false), Value::Nil)
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read_to_string(&out).unwrap(), "five\n");
+
+        // A file that cannot be opened fails before anything runs
+        let marker = tmp.path().join("ran");
+        let script = format!("touch {}", marker.display());
+        let err = run_sh(
+            &script,
+            file(&tmp.path().join("no/such/dir"), false),
+            Value::Nil,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.code, RpcError::FILE_NOT_FOUND);
+        assert!(!marker.exists());
+
+        let started = start(msgpack_map! {
+            "cmd" => "/bin/sh",
+            "args" => Value::Array(vec!["-c".into(), "echo started".into()]),
+            "stdout_file" => file(&out, false)
+        })
+        .await
+        .unwrap();
+        let pid = map_get(&started, "pid").and_then(Value::as_u64).unwrap();
+        let finished = loop {
+            let result = read(msgpack_map! { "pid" => pid, "timeout_ms" => 1000 })
+                .await
+                .unwrap();
+            assert!(map_get(&result, "stdout").unwrap().is_nil());
+            if map_get(&result, "exited") == Some(&Value::from(true)) {
+                break result;
+            }
+        };
+        let stdout_file = map_get(&finished, "stdout_file").unwrap();
+        assert_eq!(
+            map_get(stdout_file, "size").and_then(Value::as_u64),
+            Some(8)
+        );
+    }
+
+    #[tokio::test]
+    async fn stdin_file_feeds_the_child_and_reports_what_it_read() {
+        let tmp = tempfile::tempdir().unwrap();
+        let input = tmp.path().join("dump.sql");
+        std::fs::write(&input, b"line one\nline two\n").unwrap();
+        let run_sh = |script: &str, extra: Vec<(Value, Value)>| {
+            let mut pairs: Vec<(Value, Value)> = vec![
+                ("cmd".into(), "/bin/sh".into()),
+                (
+                    "args".into(),
+                    Value::Array(vec!["-c".into(), script.into()]),
+                ),
+            ];
+            pairs.extend(extra);
+            run(Value::Map(pairs))
+        };
+        let stdin_file = |path: &Path| vec![("stdin_file".into(), path.to_str().unwrap().into())];
+
+        let result = run_sh("cat", stdin_file(&input)).await.unwrap();
+        assert_eq!(
+            map_get(&result, "stdout").and_then(Value::as_slice),
+            Some(&b"line one\nline two\n"[..])
+        );
+        let reported = map_get(&result, "stdin_file").unwrap();
+        assert_eq!(map_get(reported, "size").and_then(Value::as_u64), Some(18));
+        assert_eq!(
+            map_get(reported, "consumed").and_then(Value::as_u64),
+            Some(18)
+        );
+
+        // A child that stops early has consumed less
+        let result = run_sh("read line; echo \"$line\"", stdin_file(&input))
+            .await
+            .unwrap();
+        let consumed = map_get(map_get(&result, "stdin_file").unwrap(), "consumed")
+            .and_then(Value::as_u64)
+            .unwrap();
+        assert!(consumed < 18, "consumed {}", consumed);
+
+        // A missing file fails before anything runs
+        let marker = tmp.path().join("ran");
+        let script = format!("touch {}", marker.display());
+        let err = run_sh(&script, stdin_file(&tmp.path().join("missing")))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code, RpcError::FILE_NOT_FOUND);
+        let mut both = stdin_file(&input);
+        both.push(("stdin".into(), Value::Binary(b"data".to_vec())));
+        let err = run_sh(&script, both).await.unwrap_err();
+        assert_eq!(err.code, RpcError::INVALID_PARAMS);
+        assert!(!marker.exists());
+
+        let started = start(msgpack_map! {
+            "cmd" => "/bin/cat",
+            "stdin_file" => input.to_str().unwrap()
+        })
+        .await
+        .unwrap();
+        let pid = map_get(&started, "pid").and_then(Value::as_u64).unwrap();
+        let mut stdout = Vec::new();
+        let finished = loop {
+            let result = read(msgpack_map! { "pid" => pid, "timeout_ms" => 1000 })
+                .await
+                .unwrap();
+            if let Some(data) = map_get(&result, "stdout").and_then(Value::as_slice) {
+                stdout.extend_from_slice(data);
+            }
+            if map_get(&result, "exited") == Some(&Value::from(true)) {
+                break result;
+            }
+        };
+        assert_eq!(stdout, b"line one\nline two\n");
+        let reported = map_get(&finished, "stdin_file").unwrap();
+        assert_eq!(
+            map_get(reported, "consumed").and_then(Value::as_u64),
+            Some(18)
+        );
+    }
+
+    #[tokio::test]
+    async fn output_as_applies_to_both_streams() {
+        let script = "printf 'ok \\377'; printf 'err' >&2";
+        let run_as = |output_as: &'static str| {
+            run(Value::Map(vec![
+                ("cmd".into(), "/bin/sh".into()),
+                (
+                    "args".into(),
+                    Value::Array(vec!["-c".into(), script.into()]),
+                ),
+                ("output_as".into(), output_as.into()),
+            ]))
+        };
+
+        let result = run_as("text_lossy").await.unwrap();
+        assert_eq!(
+            map_get(&result, "stdout").and_then(Value::as_str),
+            Some("ok \u{fffd}")
+        );
+        assert_eq!(
+            map_get(&result, "stderr").and_then(Value::as_str),
+            Some("err")
+        );
+
+        let result = run_as("binary").await.unwrap();
+        assert_eq!(
+            map_get(&result, "stdout").and_then(Value::as_slice),
+            Some(&b"ok \xff"[..])
+        );
+        assert!(map_get(&result, "stderr").is_some_and(Value::is_bin));
+
+        let err = run_as("base64").await.expect_err("unknown mode");
+        assert_eq!(err.code, RpcError::INVALID_PARAMS);
+
+        let started = start(Value::Map(vec![
+            ("cmd".into(), "/bin/sh".into()),
+            (
+                "args".into(),
+                Value::Array(vec!["-c".into(), script.into()]),
+            ),
+        ]))
+        .await
+        .unwrap();
+        let pid = map_get(&started, "pid").and_then(Value::as_u64).unwrap();
+        let (mut stdout, mut stderr) = (String::new(), String::new());
+        loop {
+            let read = read(Value::Map(vec![
+                ("pid".into(), pid.into()),
+                ("timeout_ms".into(), 1000.into()),
+                ("output_as".into(), "text_lossy".into()),
+            ]))
+            .await
+            .unwrap();
+            for (key, text) in [("stdout", &mut stdout), ("stderr", &mut stderr)] {
+                match map_get(&read, key) {
+                    Some(Value::String(chunk)) => text.push_str(chunk.as_str().unwrap()),
+                    Some(Value::Nil) => {}
+                    other => panic!("{} is not text: {:?}", key, other),
+                }
+            }
+            if map_get(&read, "exited").and_then(Value::as_bool) == Some(true) {
+                break;
+            }
+        }
+        assert_eq!((stdout.as_str(), stderr.as_str()), ("ok \u{fffd}", "err"));
+    }
+
+    #[tokio::test]
+    async fn processes_keep_the_trace_of_their_start() {
+        let started = crate::trace::scope(
+            Some("session-1".into()),
+            start(msgpack_map! { "cmd" => "sleep", "args" => vec![Value::from("5")] }),
+        )
+        .await
+        .expect("start");
+        let pid = map_get(&started, "pid").and_then(Value::as_u64).unwrap();
+
+        let listed = list(Value::Nil).await.unwrap();
+        let entry = listed
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|entry| map_get(entry, "pid").and_then(Value::as_u64) == Some(pid))
+            .cloned()
+            .expect("listed");
+        let _ = kill(msgpack_map! { "pid" => pid }).await;
+
+        let context = map_get(&entry, "context").expect("context");
+        assert_eq!(map_get(context, "trace"), Some(&Value::from("session-1")));
+        assert_eq!(map_get(context, "resource"), Some(&Value::from("process")));
+        assert_eq!(map_get(context, "id"), Some(&Value::from(pid)));
+    }
 }
@@ -0,0 +1,537 @@
+//! Project file listing for TRAMP-RPC
+//!
+//! This module provides:
+//! - `project.files`: Binary-safe project file lists for project.el / consult
+
+use crate::handshake::Feature;
+use crate::ignore_rules::{Exclusion, IgnoreRules};
+use crate::jail;
+use crate::msgpack_map;
+use crate::protocol::{
+    IntoValue, Listing, NamesAs, PathBytes, RpcError, Truncation, from_value, path_or_bytes,
+};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use rmpv::Value;
+use serde::Deserialize;
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use super::HandlerResult;
+use super::file::{bytes_to_path, map_io_error};
+
+/// Default and maximum number of files returned by `project.files`.
+const DEFAULT_FILE_LIMIT: usize = 100_000;
+const MAX_FILE_LIMIT: usize = 1_000_000;
+
+/// Default depth of the fallback walk for non-git roots.
+const DEFAULT_WALK_DEPTH: usize = 32;
+
+/// List the files of a project.
+///
+/// Git worktrees are listed with `git ls-files -z` (optionally including
+/// untracked, non-ignored files); other roots fall back to a bounded walk
+/// that skips entries matching `ignore`.  Paths are relative to `root` and
+/// returned as binary so unusual file names survive intact, or with
+/// `names_as: "auto"` as strings when valid UTF-8.  `include` and
+/// `exclude` are gitignore-style globs applied to the relative paths.
+///
+/// With `respect_gitignore`, both backends also drop files excluded by
+/// `.gitignore`/`.ignore` files, `.git/info/exclude` and the global excludes
+/// file, like ripgrep does (so ignored files git tracks anyway are dropped
+/// too).  `explain` lists relative paths to report the verdict for, as
+/// `explanations: [{path, ignored, pattern, source, ancestor}]`.
+///
+/// Returns `{backend, files, truncated}`, or with the "list_envelope"
+/// feature `{backend, entries, total, truncated, reason}`.
+pub async fn files(params: Value) -> HandlerResult {
+    #[derive(Deserialize)]
+    struct Params {
+        /// Project root
+        #[serde(with = "path_or_bytes")]
+        root: Vec<u8>,
+        /// Also list untracked files that are not ignored (git only)
+        #[serde(default)]
+        untracked: bool,
+        /// Only return files matching one of these globs
+        #[serde(default)]
+        include: Vec<String>,
+        /// Drop files matching any of these globs
+        #[serde(default)]
+        exclude: Vec<String>,
+        /// Entries skipped by the fallback walk (e.g. "node_modules")
+        #[serde(default)]
+        ignore: Vec<String>,
+        /// Maximum depth of the fallback walk
+        #[serde(default = "default_max_depth")]
+        max_depth: usize,
+        /// Maximum number of files to return
+        #[serde(default = "default_limit")]
+        limit: usize,
+        /// Return {path, size, mtime} maps instead of bare paths
+        #[serde(default)]
+        attrs: bool,
+        /// Skip files excluded by gitignore-style ignore files
+        #[serde(default)]
+        respect_gitignore: bool,
+        /// Relative paths to explain the ignore verdict for
+        #[serde(default)]
+        explain: Vec<PathBytes>,
+        /// "binary" (default) or "auto"
+        #[serde(default)]
+        names_as: Option<String>,
+    }
+
+    fn default_max_depth() -> usize {
+        DEFAULT_WALK_DEPTH
+    }
+
+    fn default_limit() -> usize {
+        DEFAULT_FILE_LIMIT
+    }
+
+    let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
+
+    if params.limit == 0 || params.limit > MAX_FILE_LIMIT {
+        return Err(RpcError::invalid_params(format!(
+            "limit must be between 1 and {}",
+            MAX_FILE_LIMIT
+        )));
+    }
+
+    let names = NamesAs::parse(params.names_as.as_deref())?;
+    let root = bytes_to_path(&params.root).to_path_buf();
+    jail::check(&root)?;
+    let root_str = root.to_string_lossy().into_owned();
+
+    crate::blocking::run(root.clone(), move || {
+        if !root.is_dir() {
+            return Err(RpcError::file_not_found(&root_str));
+        }
+
+        let include = build_globs(&root, &params.include)?;
+        let exclude = build_globs(&root, &params.exclude)?;
+        let keep = |rel: &Path| {
+            (params.include.is_empty()
+                || include.matched_path_or_any_parents(rel, false).is_ignore())
+                && !exclude.matched_path_or_any_parents(rel, false).is_ignore()
+        };
+
+        let mut rules = params.respect_gitignore.then(|| IgnoreRules::new(&root));
+
+        let (backend, candidates) = match git_ls_files(&root, params.untracked) {
+            Some(paths) => ("git", paths),
+            None => {
+                let ignore = build_globs(&root, &params.ignore)?;
+                let paths = walk_files(&root, &ignore, rules.as_mut(), params.max_depth)
+                    .map_err(|e| map_io_error(e, &root))?;
+                ("walk", paths)
+            }
+        };
+
+        let mut files = Vec::new();
+        let mut truncated = false;
+        for rel in candidates.iter().filter(|rel| keep(rel)) {
+            // The walk already skipped ignored entries
+            if backend == "git"
+                && let Some(rules) = rules.as_mut()
+                && rules.check(&root.join(rel), false).is_some()
+            {
+                continue;
+            }
+            if files.len() == params.limit {
+                truncated = true;
+                break;
+            }
+            files.push(file_value(&root, rel, params.attrs, names));
+        }
+
+        let mut result = if crate::handshake::enabled(Feature::ListEnvelope) {
+            let total = (!truncated).then_some(files.len() as u64);
+            let mut listing = Listing {
+                entries: files,
+                total,
+                truncation: truncated.then_some(Truncation::Limit),
+            }
+            .into_value();
+            if let Value::Map(ref mut pairs) = listing {
+                pairs.push(("backend".into(), backend.into()));
+            }
+            listing
+        } else {
+            msgpack_map! {
+                "backend" => backend,
+                "files" => Value::Array(files),
+                "truncated" => truncated
+            }
+        };
+        if !params.explain.is_empty() {
+            let rules = rules.get_or_insert_with(|| IgnoreRules::new(&root));
+            let explanations = params
+                .explain
+                .iter()
+                .map(|PathBytes(raw)| {
+                    let path = root.join(OsStr::from_bytes(raw));
+                    explanation(raw, rules.check(&path, path.is_dir()), &root)
+                })
+                .collect();
+            if let Value::Map(ref mut pairs) = result {
+                pairs.push(("explanations".into(), Value::Array(explanations)));
+            }
+        }
+        Ok(result)
+    })
+    .await?
+}
+
+/// Compile gitignore-style `globs` matched against paths relative to `root`.
+pub(crate) fn build_globs(root: &Path, globs: &[String]) -> Result<Gitignore, RpcError> {
+    let mut builder = GitignoreBuilder::new(root);
+    for glob in globs {
+        builder
+            .add_line(None, glob)
+            .map_err(|e| RpcError::invalid_params(format!("Invalid glob {:?}: {}", glob, e)))?;
+    }
+    builder
+        .build()
+        .map_err(|e| RpcError::invalid_params(e.to_string()))
+}
+
+/// List tracked (and optionally untracked) files, or None if `root` is not
+/// inside a git worktree or git is unavailable.
+fn git_ls_files(root: &Path, untracked: bool) -> Option<Vec<PathBuf>> {
+    let mut cmd = Command::new("git");
+    cmd.args(["ls-files", "-z", "--cached"]);
+    if untracked {
+        cmd.args(["--others", "--exclude-standard"]);
+    }
+    let output = cmd
+        .current_dir(root)
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+
+    let mut paths: Vec<PathBuf> = output
+        .stdout
+        .split(|&b| b == 0)
+        .filter(|p| !p.is_empty())
+        .map(|p| PathBuf::from(OsStr::from_bytes(p)))
+        .collect();
+    // Unmerged paths are listed once per stage.
+    paths.sort();
+    paths.dedup();
+    Some(paths)
+}
+
+/// Describe the ignore verdict for the relative path `raw`.
+fn explanation(raw: &[u8], exclusion: Option<Exclusion>, root: &Path) -> Value {
+    let relative = |path: &Path| {
+        Value::Binary(
+            path.strip_prefix(root)
+                .unwrap_or(path)
+                .as_os_str()
+                .as_bytes()
+                .to_vec(),
+        )
+    };
+    match exclusion {
+        None => msgpack_map! {
+            "path" => Value::Binary(raw.to_vec()),
+            "ignored" => false
+        },
+        Some(exclusion) => msgpack_map! {
+            "path" => Value::Binary(raw.to_vec()),
+            "ignored" => true,
+            "pattern" => exclusion.pattern,
+            "source" => exclusion
+                .source
+                .map(|p| Value::Binary(p.as_os_str().as_bytes().to_vec()))
+                .unwrap_or(Value::Nil),
+            "ancestor" => exclusion.ancestor.as_deref().map(relative).unwrap_or(Value::Nil)
+        },
+    }
+}
+
+/// Walk `root` for regular files and symlinks, skipping `ignore` matches
+/// and, when given, entries excluded by `rules`.
+fn walk_files(
+    root: &Path,
+    ignore: &Gitignore,
+    mut rules: Option<&mut IgnoreRules>,
+    max_depth: usize,
+) -> std::io::Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    let mut stack = vec![(PathBuf::new(), 0usize)];
+
+    while let Some((rel_dir, depth)) = stack.pop() {
+        let entries = match std::fs::read_dir(root.join(&rel_dir)) {
+            Ok(entries) => entries,
+            // Unreadable subdirectories are skipped; the root must be readable.
+            Err(_) if depth > 0 => continue,
+            Err(e) => return Err(e),
+        };
+        for entry in entries.filter_map(Result::ok) {
+            let rel = rel_dir.join(entry.file_name());
+            let Ok(file_type) = entry.file_type() else {
+                continue;
+            };
+            let is_dir = file_type.is_dir();
+            if ignore.matched(&rel, is_dir).is_ignore() {
+                continue;
+            }
+            if let Some(rules) = rules.as_deref_mut()
+                && rules.check(&root.join(&rel), is_dir).is_some()
+            {
+                continue;
+            }
+            if is_dir {
+                if depth + 1 < max_depth {
+                    stack.push((rel, depth + 1));
+                }
+            } else if paths.len() < MAX_FILE_LIMIT {
+                paths.push(rel);
+            }
+        }
+    }
+
+    paths.sort();
+    Ok(paths)
+}
+
+fn file_value(root: &Path, rel: &Path, attrs: bool, names: NamesAs) -> Value {
+    let path = names.encode(rel.as_os_str().as_bytes());
+    if !attrs {
+        return path;
+    }
+    // Tracked files deleted from the worktree have no attributes.
+    match std::fs::symlink_metadata(root.join(rel)) {
+        Ok(meta) => msgpack_map! {
+            "path" => path,
+            "size" => meta.size(),
+            "mtime" => meta.mtime()
+        },
+        Err(_) => msgpack_map! {
+            "path" => path,
+            "size" => Value::Nil,
+            "mtime" => Value::Nil
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{git, map_get};
+
+    fn names(result: &Value) -> Vec<Vec<u8>> {
+        map_get(result, "files")
+            .and_then(Value::as_array)
+            .expect("files")
+            .iter()
+            .map(|f| match f {
+                Value::Binary(b) => b.clone(),
+                other => match map_get(other, "path") {
+                    Some(Value::Binary(b)) => b.clone(),
+                    _ => panic!("unexpected entry {:?}", other),
+                },
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn project_files_lists_tracked_and_untracked_git_files() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        git(root, &["init", "-q"]);
+        std::fs::write(root.join(".gitignore"), "*.log\n").unwrap();
+        std::fs::write(root.join("main.rs"), "").unwrap();
+        std::fs::write(root.join("odd\nname.rs"), "").unwrap();
+        std::fs::write(root.join("debug.log"), "").unwrap();
+        std::fs::write(root.join("notes.txt"), "").unwrap();
+        git(root, &["add", ".gitignore", "main.rs", "odd\nname.rs"]);
+
+        let tracked = files(msgpack_map! {
+            "root" => root.to_string_lossy().into_owned(),
+            "include" => Value::Array(vec!["*.rs".into()])
+        })
+        .await
+        .unwrap();
+        assert_eq!(
+            map_get(&tracked, "backend").and_then(Value::as_str),
+            Some("git")
+        );
+        assert_eq!(
+            names(&tracked),
+            vec![b"main.rs".to_vec(), b"odd\nname.rs".to_vec()]
+        );
+
+        let all = files(msgpack_map! {
+            "root" => root.to_string_lossy().into_owned(),
+            "untracked" => true,
+            "exclude" => Value::Array(vec!["odd*".into()]),
+            "attrs" => true
+        })
+        .await
+        .unwrap();
+        assert_eq!(
+            names(&all),
+            vec![
+                b".gitignore".to_vec(),
+                b"main.rs".to_vec(),
+                b"notes.txt".to_vec()
+            ]
+        );
+        let first = &map_get(&all, "files").and_then(Value::as_array).unwrap()[0];
+        assert!(map_get(first, "mtime").is_some_and(|m| m.as_i64().is_some()));
+    }
+
+    #[tokio::test]
+    async fn project_files_walks_non_git_roots_with_ignore_and_limit() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        std::fs::create_dir_all(root.join("src")).unwrap();
+        std::fs::create_dir_all(root.join("node_modules").join("dep")).unwrap();
+        std::fs::write(root.join("src").join("a.js"), "").unwrap();
+        std::fs::write(root.join("src").join("b.js"), "").unwrap();
+        std::fs::write(root.join("node_modules").join("dep").join("c.js"), "").unwrap();
+
+        // Skip if the temp dir happens to live inside a git checkout.
+        if git_ls_files(root, false).is_some() {
+            return;
+        }
+
+        let result = files(msgpack_map! {
+            "root" => root.to_string_lossy().into_owned(),
+            "ignore" => Value::Array(vec!["node_modules".into()])
+        })
+        .await
+        .unwrap();
+        assert_eq!(
+            map_get(&result, "backend").and_then(Value::as_str),
+            Some("walk")
+        );
+        assert_eq!(
+            names(&result),
+            vec![b"src/a.js".to_vec(), b"src/b.js".to_vec()]
+        );
+
+        let limited = files(msgpack_map! {
+            "root" => root.to_string_lossy().into_owned(),
+            "ignore" => Value::Array(vec!["node_modules".into()]),
+            "limit" => 1
+        })
+        .await
+        .unwrap();
+        assert_eq!(names(&limited).len(), 1);
+        assert_eq!(
+            map_get(&limited, "truncated").and_then(Value::as_bool),
+            Some(true)
+        );
+    }
+
+    #[tokio::test]
+    async fn project_files_respects_gitignore_and_explains() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        git(root, &["init", "-q"]);
+        std::fs::create_dir_all(root.join("gen")).unwrap();
+        std::fs::write(
+            root.join(".gitignore"),
+            "gen/
+*.bak
+!keep.bak
+",
+        )
+        .unwrap();
+        std::fs::write(root.join("main.rs"), "").unwrap();
+        std::fs::write(root.join("old.bak"), "").unwrap();
+        std::fs::write(root.join("keep.bak"), "").unwrap();
+        std::fs::write(root.join("gen").join("out.rs"), "").unwrap();
+        // Force-added files git tracks despite the ignore rules
+        git(root, &["add", "-f", "."]);
+
+        let result = files(msgpack_map! {
+            "root" => root.to_string_lossy().into_owned(),
+            "respect_gitignore" => true,
+            "explain" => Value::Array(vec!["gen/out.rs".into(), "main.rs".into()])
+        })
+        .await
+        .unwrap();
+        assert_eq!(
+            names(&result),
+            vec![
+                b".gitignore".to_vec(),
+                b"keep.bak".to_vec(),
+                b"main.rs".to_vec()
+            ]
+        );
+
+        let explanations = map_get(&result, "explanations")
+            .and_then(Value::as_array)
+            .unwrap();
+        assert_eq!(explanations[0]["ignored"].as_bool(), Some(true));
+        assert_eq!(explanations[0]["pattern"].as_str(), Some("gen/"));
+        assert_eq!(explanations[0]["ancestor"].as_slice(), Some(&b"gen"[..]));
+        assert_eq!(
+            explanations[0]["source"].as_slice(),
+            Some(root.join(".gitignore").as_os_str().as_bytes())
+        );
+        assert_eq!(explanations[1]["ignored"].as_bool(), Some(false));
+    }
+
+    /// Time the fallback walk over a large tree with nested ignore files.
+    /// Run with `cargo test --release -- --ignored --nocapture`.
+    #[tokio::test]
+    #[ignore = "benchmark"]
+    async fn bench_walk_with_ignore_rules() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        if git_ls_files(root, false).is_some() {
+            return;
+        }
+        std::fs::write(root.join(".ignore"), "*.o\ntarget/\n").unwrap();
+        for a in 0..50 {
+            let dir = root.join(format!("d{a}"));
+            std::fs::create_dir_all(dir.join("target")).unwrap();
+            std::fs::write(dir.join(".ignore"), "!keep.o\n*.tmp\n").unwrap();
+            std::fs::write(dir.join("target").join("x.rs"), "").unwrap();
+            for b in 0..20 {
+                let sub = dir.join(format!("s{b}"));
+                std::fs::create_dir(&sub).unwrap();
+                for c in 0..20 {
+                    let ext = ["rs", "o", "tmp", "txt"][c % 4];
+                    std::fs::write(sub.join(format!("f{c}.{ext}")), "").unwrap();
+                }
+            }
+        }
+
+        let root_value = root.to_string_lossy().into_owned();
+        let mut timings = Vec::new();
+        for respect_gitignore in [false, true] {
+            let start = std::time::Instant::now();
+            let result = files(msgpack_map! {
+                "root" => root_value.clone(),
+                "respect_gitignore" => respect_gitignore
+            })
+            .await
+            .unwrap();
+            timings.push(start.elapsed());
+            let count = names(&result).len();
+            // 20101 files; the rules drop the .o and .tmp ones (5000 each)
+            // and the 50 below target/
+            assert_eq!(count, if respect_gitignore { 10051 } else { 20101 });
+        }
+        assert!(
+            timings[1] < timings[0] * 10 + std::time::Duration::from_secs(1),
+            "with gitignore rules {:?}, without {:?}",
+            timings[1],
+            timings[0]
+        );
+    }
+}
@@ -0,0 +1,505 @@
+//! Persistent shell sessions for TRAMP-RPC
+//!
+//! This module provides:
+//! - `shell.session_open`: Start a long-lived shell
+//! - `shell.session_run`: Run one command in it
+//! - `shell.session_close`: End it
+//!
+//! A session is one POSIX shell reading commands from its stdin, so `cd`,
+//! `export` and shell variables persist from one command to the next, and
+//! repeated commands skip the shell's startup.  After each command the shell
+//! prints a random per-command marker with the exit status on stdout, and
+//! the marker again on stderr, which tells where the command's output ends.
+//! A command that outlives its timeout is killed with the shell, and the
+//! session restarts from the options it was opened with.
+
+use crate::jail;
+use crate::msgpack_map;
+use crate::protocol::{PathBytes, RpcError, from_value, path_or_bytes};
+use rmpv::Value;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::process::{Child, ChildStderr, ChildStdin, ChildStdout, Command};
+use tokio::sync::Mutex;
+
+use super::HandlerResult;
+
+/// Maximum number of sessions open at once.
+const MAX_SESSIONS: usize = 16;
+
+/// Default time a command may run before its session is reset.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Output a command may produce on each stream before its session is reset.
+const MAX_OUTPUT: usize = 64 * 1024 * 1024;
+
+/// Shells that read POSIX commands from stdin; any other `$SHELL` (such as
+/// fish or csh) is replaced by `/bin/sh`.
+const POSIX_SHELLS: &[&str] = &["sh", "bash", "zsh", "dash", "ksh", "mksh", "ash", "yash"];
+
+/// How a session's shell is started, kept to restart it after a reset.
+struct Options {
+    shell: PathBuf,
+    cwd: Option<PathBuf>,
+    env: HashMap<String, PathBytes>,
+}
+
+/// A running shell
+struct Shell {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: ChildStdout,
+    stderr: ChildStderr,
+}
+
+impl Shell {
+    fn spawn(options: &Options) -> Result<Self, RpcError> {
+        let mut cmd = Command::new(&options.shell);
+        if let Some(base) = crate::env_policy::base(false) {
+            cmd.env_clear();
+            cmd.envs(base);
+        }
+        cmd.stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .envs(&options.env)
+            // Its own process group, so a reset also kills what it started
+            .process_group(0)
+            .kill_on_drop(true);
+        if let Some(cwd) = &options.cwd {
+            cmd.current_dir(cwd);
+        }
+        let mut child = cmd
+            .spawn()
+            .map_err(|e| RpcError::process_error(format!("Failed to start shell: {}", e)))?;
+        let (Some(stdin), Some(stdout), Some(stderr)) =
+            (child.stdin.take(), child.stdout.take(), child.stderr.take())
+        else {
+            return Err(RpcError::internal_error("shell pipes missing"));
+        };
+        Ok(Self {
+            child,
+            stdin,
+            stdout,
+            stderr,
+        })
+    }
+
+    /// Kill the shell and everything in its process group.
+    async fn kill(mut self) -> Option<std::process::ExitStatus> {
+        if let Some(pid) = self.child.id() {
+            unsafe { libc::killpg(pid as libc::pid_t, libc::SIGKILL) };
+        }
+        let _ = self.child.start_kill();
+        self.child.wait().await.ok()
+    }
+}
+
+struct Session {
+    options: Options,
+    /// `None` if the shell could not be restarted; the next run retries.
+    shell: Option<Shell>,
+}
+
+static SESSIONS: OnceLock<Mutex<HashMap<u32, Arc<Mutex<Session>>>>> = OnceLock::new();
+static NEXT_SESSION_ID: AtomicU32 = AtomicU32::new(1);
+static NEXT_MARKER: AtomicU32 = AtomicU32::new(0);
+
+fn sessions() -> &'static Mutex<HashMap<u32, Arc<Mutex<Session>>>> {
+    SESSIONS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// How many shell sessions are open
+pub(crate) async fn open_count() -> usize {
+    sessions().lock().await.len()
+}
+
+/// End every session and its shell, as the server does when it shuts down.
+pub(crate) async fn close_all() {
+    let all: Vec<_> = sessions().lock().await.drain().map(|(_, s)| s).collect();
+    for session in all {
+        if let Some(shell) = session.lock().await.shell.take() {
+            shell.kill().await;
+        }
+    }
+}
+
+async fn session(id: u32) -> Result<Arc<Mutex<Session>>, RpcError> {
+    sessions()
+        .lock()
+        .await
+        .get(&id)
+        .cloned()
+        .ok_or_else(|| RpcError::process_error(format!("Shell session not found: {}", id)))
+}
+
+/// Whether `path` names a POSIX shell.
+fn is_posix_shell(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| POSIX_SHELLS.contains(&name))
+}
+
+/// `$SHELL` if it is a POSIX shell, else `/bin/sh`.
+fn default_shell() -> PathBuf {
+    std::env::var_os("SHELL")
+        .map(PathBuf::from)
+        .filter(|shell| shell.is_absolute() && is_posix_shell(shell))
+        .unwrap_or_else(|| PathBuf::from("/bin/sh"))
+}
+
+/// A marker no command output can predict: random bytes from the system,
+/// or a per-process counter if those are unavailable.
+fn new_marker() -> String {
+    let mut bytes = [0u8; 16];
+    let random = std::fs::File::open("/dev/urandom")
+        .and_then(|mut urandom| urandom.read_exact(&mut bytes))
+        .is_ok();
+    if !random {
+        let n = NEXT_MARKER.fetch_add(1, Ordering::Relaxed);
+        bytes[..4].copy_from_slice(&std::process::id().to_le_bytes());
+        bytes[4..8].copy_from_slice(&n.to_le_bytes());
+    }
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("=tramp-rpc-{}=", hex)
+}
+
+/// The input that runs `command` and then prints `marker` with its exit
+/// status on stdout and alone on stderr, each on a line of its own.  The
+/// command goes through `command eval` with stdin from /dev/null, so a
+/// syntax error is just a failed command rather than the end of the shell,
+/// and nothing it runs can read the session's input.
+fn command_script(command: &[u8], marker: &str) -> Vec<u8> {
+    let marker = super::sudo::shell_quote(marker.as_bytes());
+    let mut script = b"command eval ".to_vec();
+    script.extend(super::sudo::shell_quote(command));
+    script.extend_from_slice(b" </dev/null\nprintf '\\n%s %d\\n' ");
+    script.extend_from_slice(&marker);
+    script.extend_from_slice(b" \"$?\"\nprintf '\\n%s\\n' ");
+    script.extend_from_slice(&marker);
+    script.extend_from_slice(b" >&2\n");
+    script
+}
+
+/// Why reading a command's output stopped early
+enum Interrupted {
+    /// The shell closed the stream, as after `exit`
+    Ended,
+    TooLarge,
+    Io(std::io::Error),
+}
+
+/// Read from `reader` until the `\n<marker>...\n` line, returning the
+/// output before it and the rest of that line.
+async fn read_marked<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    marker: &str,
+    output: &mut Vec<u8>,
+) -> Result<(usize, Vec<u8>), Interrupted> {
+    let start = format!("\n{}", marker).into_bytes();
+    let mut buf = vec![0u8; 64 * 1024];
+    // Output before this offset is known not to hold the marker line
+    let mut searched = 0;
+    loop {
+        if let Some(at) = output[searched..]
+            .windows(start.len())
+            .position(|window| window == start.as_slice())
+            .map(|at| searched + at)
+        {
+            let rest = &output[at + start.len()..];
+            if let Some(end) = rest.iter().position(|&b| b == b'\n') {
+                return Ok((at, rest[..end].to_vec()));
+            }
+            searched = at;
+        } else {
+            searched = output.len().saturating_sub(start.len() - 1);
+        }
+        if output.len() > MAX_OUTPUT {
+            return Err(Interrupted::TooLarge);
+        }
+        match reader.read(&mut buf).await {
+            Ok(0) => return Err(Interrupted::Ended),
+            Ok(n) => output.extend_from_slice(&buf[..n]),
+            Err(e) => return Err(Interrupted::Io(e)),
+        }
+    }
+}
+
+/// Handle `shell.session_open {shell?, cwd?, env?}`.
+///
+/// Starts `shell` (default `$SHELL` if it is a POSIX shell, else `/bin/sh`)
+/// in `cwd` with `env` added to the server's environment.  Returns `{id,
+/// shell, os_pid}`.
+pub async fn session_open(params: Value) -> HandlerResult {
+    #[derive(Deserialize, Default)]
+    struct Params {
+        #[serde(default)]
+        shell: Option<String>,
+        #[serde(default)]
+        cwd: Option<String>,
+        #[serde(default)]
+        env: HashMap<String, PathBytes>,
+    }
+
+    let params: Params = if params.is_nil() {
+        Params::default()
+    } else {
+        from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?
+    };
+    let cwd = match params.cwd {
+        Some(cwd) => {
+            let cwd = PathBuf::from(super::expand_tilde(&cwd));
+            jail::check(&cwd)?;
+            Some(cwd)
+        }
+        None => None,
+    };
+    let options = Options {
+        shell: params.shell.map_or_else(default_shell, PathBuf::from),
+        cwd,
+        env: params.env,
+    };
+
+    let mut sessions = sessions().lock().await;
+    if sessions.len() >= MAX_SESSIONS {
+        return Err(RpcError::process_error(format!(
+            "Too many shell sessions (limit {})",
+            MAX_SESSIONS
+        )));
+    }
+    let shell = Shell::spawn(&options)?;
+    let os_pid = shell.child.id();
+    let id = NEXT_SESSION_ID.fetch_add(1, Ordering::Relaxed);
+    let shell_path = options.shell.to_string_lossy().into_owned();
+    sessions.insert(
+        id,
+        Arc::new(Mutex::new(Session {
+            options,
+            shell: Some(shell),
+        })),
+    );
+
+    Ok(msgpack_map! {
+        "id" => id,
+        "shell" => shell_path,
+        "os_pid" => os_pid.map_or(Value::Nil, Value::from)
+    })
+}
+
+/// Handle `shell.session_run {id, command, timeout_ms?}`.
+///
+/// Runs `command` (a string or binary shell command line) in the session
+/// and returns `{exit_code, stdout, stderr, timed_out, reset}` once it
+/// finishes.  Commands in one session run one at a time.
+///
+/// If the command runs longer than `timeout_ms` (default 30000), or ends
+/// the shell (as `exit` does), the session is reset: the shell is killed
+/// with everything it started and a fresh one takes its place, so `reset`
+/// is true and the cwd and environment are back to those the session was
+/// opened with.  A timed-out command has `timed_out` set, a nil
+/// `exit_code` and whatever output it produced so far.
+pub async fn session_run(params: Value) -> HandlerResult {
+    #[derive(Deserialize)]
+    struct Params {
+        id: u32,
+        #[serde(with = "path_or_bytes")]
+        command: Vec<u8>,
+        #[serde(default)]
+        timeout_ms: Option<u64>,
+    }
+
+    let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
+    let timeout = params
+        .timeout_ms
+        .map_or(DEFAULT_TIMEOUT, Duration::from_millis);
+
+    let session = session(params.id).await?;
+    let mut session = session.lock().await;
+    if session.shell.is_none() {
+        session.shell = Some(Shell::spawn(&session.options)?);
+    }
+    let Some(shell) = session.shell.as_mut() else {
+        return Err(RpcError::internal_error("shell missing"));
+    };
+
+    let marker = new_marker();
+    let script = command_script(&params.command, &marker);
+    let mut stdout = Vec::new();
+    let mut stderr = Vec::new();
+    let outcome = tokio::time::timeout(timeout, async {
+        shell
+            .stdin
+            .write_all(&script)
+            .await
+            .map_err(Interrupted::Io)?;
+        tokio::try_join!(
+            read_marked(&mut shell.stdout, &marker, &mut stdout),
+            read_marked(&mut shell.stderr, &marker, &mut stderr),
+        )
+    })
+    .await;
+
+    let (timed_out, interrupted) = match outcome {
+        Ok(Ok(((stdout_end, status), (stderr_end, _)))) => {
+            stdout.truncate(stdout_end);
+            stderr.truncate(stderr_end);
+            let exit_code = std::str::from_utf8(&status)
+                .ok()
+                .and_then(|status| status.trim().parse::<i32>().ok());
+            return Ok(msgpack_map! {
+                "exit_code" => exit_code.map_or(Value::Nil, Value::from),
+                "stdout" => Value::Binary(stdout),
+                "stderr" => Value::Binary(stderr),
+                "timed_out" => false,
+                "reset" => false
+            });
+        }
+        Ok(Err(interrupted)) => (false, Some(interrupted)),
+        Err(_) => (true, None),
+    };
+
+    // The shell is stuck, gone or flooding its output: start over
+    let exit_status = match session.shell.take() {
+        Some(shell) => shell.kill().await,
+        None => None,
+    };
+    session.shell = Shell::spawn(&session.options).ok();
+    let exit_code = match interrupted {
+        Some(Interrupted::TooLarge) => {
+            return Err(RpcError::process_error(format!(
+                "Command output exceeded {} bytes; the session was reset",
+                MAX_OUTPUT
+            )));
+        }
+        Some(Interrupted::Io(e)) if e.kind() != std::io::ErrorKind::BrokenPipe => {
+            return Err(RpcError::process_error(format!(
+                "Shell session I/O failed: {}; the session was reset",
+                e
+            )));
+        }
+        // The shell ended by itself, so its status is the command's
+        Some(_) => exit_status
+            .map(crate::protocol::exit_code_from_status)
+            .map_or(Value::Nil, Value::from),
+        None => Value::Nil,
+    };
+
+    Ok(msgpack_map! {
+        "exit_code" => exit_code,
+        "stdout" => Value::Binary(stdout),
+        "stderr" => Value::Binary(stderr),
+        "timed_out" => timed_out,
+        "reset" => true
+    })
+}
+
+/// Handle `shell.session_close {id}`: end the session and its shell.
+pub async fn session_close(params: Value) -> HandlerResult {
+    #[derive(Deserialize)]
+    struct Params {
+        id: u32,
+    }
+
+    let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
+    let session = sessions().lock().await.remove(&params.id).ok_or_else(|| {
+        RpcError::process_error(format!("Shell session not found: {}", params.id))
+    })?;
+    if let Some(shell) = session.lock().await.shell.take() {
+        shell.kill().await;
+    }
+    Ok(Value::Boolean(true))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn run(id: u32, command: &str, timeout_ms: u64) -> Value {
+        session_run(msgpack_map! {
+            "id" => id,
+            "command" => command,
+            "timeout_ms" => timeout_ms
+        })
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn sessions_keep_state_and_recover_from_hung_commands() {
+        let tmp = tempfile::tempdir().unwrap();
+        let opened = session_open(msgpack_map! {
+            "shell" => "/bin/sh",
+            "env" => msgpack_map! { "GREETING" => "hello" }
+        })
+        .await
+        .unwrap();
+        let id = opened["id"].as_u64().unwrap() as u32;
+        assert!(is_posix_shell(Path::new(opened["shell"].as_str().unwrap())));
+
+        let dir = tmp.path().to_string_lossy().into_owned();
+        let first = run(id, &format!("cd '{}' && export FOO=bar", dir), 5000).await;
+        assert_eq!(first["exit_code"].as_i64(), Some(0));
+        assert_eq!(first["reset"], Value::from(false));
+
+        // cwd and exports persist; output without a final newline is intact
+        let second = run(
+            id,
+            "pwd; echo $FOO $GREETING; echo oops >&2; cat; printf end",
+            5000,
+        )
+        .await;
+        assert_eq!(
+            second["stdout"].as_slice(),
+            Some(format!("{}\nbar hello\nend", dir).as_bytes())
+        );
+        assert_eq!(second["stderr"].as_slice(), Some(&b"oops\n"[..]));
+        assert_eq!(run(id, "false", 5000).await["exit_code"].as_i64(), Some(1));
+        // A syntax error is a failed command, not a broken session
+        let broken = run(id, "if then 'unclosed", 5000).await;
+        assert_ne!(broken["exit_code"].as_i64(), Some(0));
+        assert_eq!(broken["reset"], Value::from(false));
+
+        // A hung command resets the session
+        let hung = run(id, "echo started; sleep 10", 200).await;
+        assert_eq!(hung["timed_out"], Value::from(true));
+        assert_eq!(hung["reset"], Value::from(true));
+        assert_eq!(hung["stdout"].as_slice(), Some(&b"started\n"[..]));
+        let after = run(id, "echo ${FOO:-unset}", 5000).await;
+        assert_eq!(after["stdout"].as_slice(), Some(&b"unset\n"[..]));
+
+        // So does ending the shell, returning its status
+        let exited = run(id, "exit 7", 5000).await;
+        assert_eq!(exited["exit_code"].as_i64(), Some(7));
+        assert_eq!(exited["reset"], Value::from(true));
+        assert_eq!(
+            run(id, "echo ok", 5000).await["stdout"].as_slice(),
+            Some(&b"ok\n"[..])
+        );
+
+        session_close(msgpack_map! { "id" => id }).await.unwrap();
+        let err = session_run(msgpack_map! { "id" => id, "command" => "true" })
+            .await
+            .unwrap_err();
+        assert_eq!(err.code, RpcError::PROCESS_ERROR);
+    }
+
+    #[test]
+    fn command_markers_are_unique_and_quoted() {
+        let (a, b) = (new_marker(), new_marker());
+        assert_ne!(a, b);
+        let script = command_script(b"echo 'it''s'", &a);
+        assert!(script.starts_with(b"command eval 'echo '\\''it'\\'''\\''s'\\''' </dev/null\n"));
+        let mentions = script
+            .windows(a.len())
+            .filter(|window| *window == a.as_bytes())
+            .count();
+        assert_eq!(mentions, 2);
+    }
+}
@@ -0,0 +1,834 @@
+//! Running commands as another user through sudo or su
+//!
+//! This module provides:
+//! - `process.run_sudo`: Run a command under sudo and wait for it
+//! - `auth.password_reply`: Answer an `auth.password_request`
+//!
+//! It also backs the `sudo` flag of `file.read` and `file.write`, and the
+//! `become` option of `process.run`, which can use su on hosts without sudo.
+//!
+//! When sudo can run the command without a password it runs under
+//! `sudo -n`.  Otherwise it runs under `sudo -A`, with `SUDO_ASKPASS` set to
+//! this server binary: started as an askpass helper ([`askpass_main`]), it
+//! passes sudo's prompt to the server over a Unix socket in a private
+//! directory, the server forwards it to the client as an
+//! `auth.password_request {id, prompt, user}` notification, and the
+//! client's `auth.password_reply {id, password}` goes back the same way.
+//! The password is never logged, and is wiped once sudo has it.
+
+use crate::msgpack_map;
+use crate::protocol::{Notification, PathBytes, ProcessResult, RpcError, from_value};
+use crate::server_dirs::{self, Freed, Kind};
+use rmpv::Value;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::io::{Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Mutex, MutexGuard, OnceLock};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::process::Command;
+use tokio::sync::oneshot;
+
+use super::HandlerResult;
+
+/// Environment variable holding the socket of the server that started an
+/// askpass helper.
+pub(crate) const ASKPASS_SOCKET_ENV: &str = "TRAMP_RPC_ASKPASS_SOCKET";
+
+/// Default and maximum time to wait for the client to answer a password
+/// prompt, in seconds.
+const DEFAULT_PASSWORD_TIMEOUT_SECS: u64 = 120;
+const MAX_PASSWORD_TIMEOUT_SECS: u64 = 600;
+
+/// Longest prompt accepted from an askpass helper.
+const MAX_PROMPT_LEN: u64 = 4096;
+
+type Reply = Option<Vec<u8>>;
+
+/// Password prompts waiting for `auth.password_reply`, by id.
+static PENDING: OnceLock<Mutex<HashMap<u32, oneshot::Sender<Reply>>>> = OnceLock::new();
+static NEXT_PROMPT_ID: AtomicU32 = AtomicU32::new(1);
+
+fn pending() -> MutexGuard<'static, HashMap<u32, oneshot::Sender<Reply>>> {
+    PENDING
+        .get_or_init(|| Mutex::new(HashMap::new()))
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+}
+
+/// Overwrite `bytes` in a way the compiler will not optimize out.
+fn wipe(bytes: &mut [u8]) {
+    for byte in bytes.iter_mut() {
+        // SAFETY: `byte` is a valid, exclusive reference.
+        unsafe { std::ptr::write_volatile(byte, 0) };
+    }
+    std::sync::atomic::compiler_fence(Ordering::SeqCst);
+}
+
+// ============================================================================
+// The askpass helper
+// ============================================================================
+
+/// Run as sudo's askpass helper: ask the server listening on `socket` for
+/// the password, with sudo's prompt (our first argument), and print it.
+/// Returns the exit status, which is non-zero if no password was given.
+pub fn askpass_main(socket: &OsStr) -> i32 {
+    let prompt = std::env::args_os().nth(1).unwrap_or_default();
+    let Ok(Some(mut password)) = askpass(Path::new(socket), prompt.as_bytes()) else {
+        return 1;
+    };
+    password.push(b'\n');
+    let mut stdout = std::io::stdout();
+    let written = stdout.write_all(&password).and_then(|()| stdout.flush());
+    wipe(&mut password);
+    if written.is_ok() { 0 } else { 1 }
+}
+
+/// Send `prompt` to the server and read its answer: `+PASSWORD`, or `-`
+/// when the prompt was cancelled.
+fn askpass(socket: &Path, prompt: &[u8]) -> std::io::Result<Option<Vec<u8>>> {
+    let mut stream = std::os::unix::net::UnixStream::connect(socket)?;
+    stream.write_all(prompt)?;
+    stream.shutdown(std::net::Shutdown::Write)?;
+    let mut reply = Vec::with_capacity(1024);
+    let read = stream.read_to_end(&mut reply);
+    let password = match (&read, reply.split_first()) {
+        (Ok(_), Some((b'+', password))) => Some(password.to_vec()),
+        _ => None,
+    };
+    wipe(&mut reply);
+    read.map(|_| password)
+}
+
+// ============================================================================
+// The server's side
+// ============================================================================
+
+/// A private (0700) directory for the askpass socket, removed on drop.
+struct SocketDir(PathBuf);
+
+impl SocketDir {
+    fn create() -> std::io::Result<Self> {
+        static COUNTER: AtomicU32 = AtomicU32::new(0);
+
+        let base = server_dirs::dir(Kind::Runtime)?;
+        let dir = base.join(format!(
+            "askpass-{}-{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::DirBuilder::new().mode(0o700).create(&dir)?;
+        Ok(Self(dir))
+    }
+
+    fn socket(&self) -> PathBuf {
+        self.0.join("socket")
+    }
+}
+
+impl Drop for SocketDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Remove askpass directories left by server processes that have exited.
+pub(crate) fn gc() -> Freed {
+    let mut freed = Freed::default();
+    let Ok(entries) = std::fs::read_dir(server_dirs::path(Kind::Runtime)) else {
+        return freed;
+    };
+    for entry in entries.flatten() {
+        let name = entry.file_name();
+        let Some(pid) = name
+            .to_str()
+            .and_then(|n| n.strip_prefix("askpass-"))
+            .and_then(|rest| rest.split('-').next())
+            .and_then(|pid| pid.parse::<i32>().ok())
+        else {
+            continue;
+        };
+        let alive = !matches!(
+            nix::sys::signal::kill(nix::unistd::Pid::from_raw(pid), None),
+            Err(nix::errno::Errno::ESRCH)
+        );
+        if !alive && std::fs::remove_dir_all(entry.path()).is_ok() {
+            freed.removed += 1;
+        }
+    }
+    freed
+}
+
+/// Removes a pending prompt when it is answered, times out or is dropped.
+struct PendingPrompt(u32);
+
+impl Drop for PendingPrompt {
+    fn drop(&mut self) {
+        pending().remove(&self.0);
+    }
+}
+
+/// Forward the prompt an askpass helper sent on `stream` to the client and
+/// pass its answer back.  Fails if the client cancels or does not answer
+/// within `timeout`; the helper is told and exits without a password.
+async fn answer_prompt(
+    mut stream: tokio::net::UnixStream,
+    user: &str,
+    timeout: Duration,
+) -> Result<(), RpcError> {
+    let mut prompt = Vec::new();
+    (&mut stream)
+        .take(MAX_PROMPT_LEN)
+        .read_to_end(&mut prompt)
+        .await
+        .map_err(RpcError::io_error)?;
+
+    match request_password("sudo", &prompt, user, timeout).await {
+        Ok(mut password) => {
+            let mut message = Vec::with_capacity(password.len() + 1);
+            message.push(b'+');
+            message.extend_from_slice(&password);
+            wipe(&mut password);
+            let written = stream.write_all(&message).await;
+            wipe(&mut message);
+            written.map_err(RpcError::io_error)
+        }
+        Err(e) => {
+            let _ = stream.write_all(b"-").await;
+            Err(e)
+        }
+    }
+}
+
+/// Ask the client for `user`'s password with an `auth.password_request`
+/// carrying `prompt`.  Fails if the client cancels or does not answer
+/// within `timeout`; `tool` names the asking program in the error.
+async fn request_password(
+    tool: &str,
+    prompt: &[u8],
+    user: &str,
+    timeout: Duration,
+) -> Result<Vec<u8>, RpcError> {
+    let id = NEXT_PROMPT_ID.fetch_add(1, Ordering::Relaxed);
+    let (tx, rx) = oneshot::channel();
+    pending().insert(id, tx);
+    let _pending = PendingPrompt(id);
+    let context = crate::trace::context(crate::trace::current(), "password_request", id);
+    crate::send_notification_forced(
+        Notification::new(
+            "auth.password_request",
+            msgpack_map! {
+                "id" => id,
+                "prompt" => String::from_utf8_lossy(prompt).trim().to_string(),
+                "user" => user
+            },
+        )
+        .with_context(context),
+    )
+    .await;
+
+    let failure = match tokio::time::timeout(timeout, rx).await {
+        Ok(Ok(Some(password))) => return Ok(password),
+        Ok(_) => "password prompt was cancelled",
+        Err(_) => "password prompt timed out",
+    };
+    Err(RpcError::process_error(format!("{} {}", tool, failure)))
+}
+
+/// Whether sudo can run commands as `user` without a password.  Checked
+/// with a no-op, so a command that fails on its own is never run twice.
+async fn runs_without_password(user: &str) -> Result<bool, RpcError> {
+    let status = Command::new("sudo")
+        .args(["-n", "-u", user, "--", "true"])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .await
+        .map_err(|e| RpcError::process_error(format!("Failed to run sudo: {}", e)))?;
+    Ok(status.success())
+}
+
+/// How to run a command under sudo.
+pub(crate) struct SudoCommand<'a> {
+    pub user: &'a str,
+    pub program: &'a OsStr,
+    pub args: Vec<OsString>,
+    pub cwd: Option<PathBuf>,
+    pub stdin: Option<Vec<u8>>,
+    pub password_timeout: Duration,
+}
+
+/// Run `command` under sudo and wait for it.  A cancelled or unanswered
+/// password prompt kills sudo (before it ran the command) and fails.
+pub(crate) async fn run_as(command: SudoCommand<'_>) -> Result<ProcessResult, RpcError> {
+    if command.user.is_empty() || command.user.starts_with('-') {
+        return Err(RpcError::invalid_params("Invalid sudo user"));
+    }
+
+    let socket_dir = if runs_without_password(command.user).await? {
+        None
+    } else {
+        Some(SocketDir::create().map_err(RpcError::io_error)?)
+    };
+
+    let mut sudo = Command::new("sudo");
+    let listener = match &socket_dir {
+        Some(dir) => {
+            let helper = std::env::current_exe().map_err(RpcError::io_error)?;
+            let listener =
+                tokio::net::UnixListener::bind(dir.socket()).map_err(RpcError::io_error)?;
+            sudo.arg("-A")
+                .env("SUDO_ASKPASS", helper)
+                .env(ASKPASS_SOCKET_ENV, dir.socket());
+            Some(listener)
+        }
+        None => {
+            sudo.arg("-n");
+            None
+        }
+    };
+    sudo.args(["-u", command.user, "--"])
+        .arg(command.program)
+        .args(&command.args)
+        .stdin(if command.stdin.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+    if let Some(cwd) = &command.cwd {
+        sudo.current_dir(cwd);
+    }
+
+    let mut child = sudo
+        .spawn()
+        .map_err(|e| RpcError::process_error(format!("Failed to run sudo: {}", e)))?;
+
+    // sudo reads the password before the command reads its input, so
+    // input and output are handled alongside the prompts.
+    if let (Some(input), Some(mut stdin)) = (command.stdin, child.stdin.take()) {
+        tokio::spawn(async move {
+            let _ = stdin.write_all(&input).await;
+        });
+    }
+    let collect = |pipe: Option<Box<dyn tokio::io::AsyncRead + Send + Unpin>>| {
+        tokio::spawn(async move {
+            let mut output = Vec::new();
+            if let Some(mut pipe) = pipe {
+                let _ = pipe.read_to_end(&mut output).await;
+            }
+            output
+        })
+    };
+    let stdout = collect(child.stdout.take().map(|p| Box::new(p) as _));
+    let stderr = collect(child.stderr.take().map(|p| Box::new(p) as _));
+
+    let status = loop {
+        let prompt = async {
+            match &listener {
+                Some(listener) => listener.accept().await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            status = child.wait() => {
+                break status.map_err(|e| {
+                    RpcError::process_error(format!("Failed to wait for sudo: {}", e))
+                })?;
+            }
+            accepted = prompt => {
+                let (stream, _) = accepted.map_err(RpcError::io_error)?;
+                if let Err(e) = answer_prompt(stream, command.user, command.password_timeout).await
+                {
+                    let _ = child.kill().await;
+                    return Err(e);
+                }
+            }
+        }
+    };
+
+    let join = crate::blocking::join_error;
+    Ok(ProcessResult {
+        exit_code: crate::protocol::exit_code_from_status(status),
+        signal: crate::protocol::ExitSignal::from_status(status),
+        stdout: stdout.await.map_err(join)?,
+        stderr: stderr.await.map_err(join)?,
+    })
+}
+
+// ============================================================================
+// su
+// ============================================================================
+
+/// How to run a command under su.
+pub(crate) struct SuCommand<'a> {
+    pub user: &'a str,
+    /// The command and its arguments
+    pub argv: Vec<OsString>,
+    pub cwd: Option<PathBuf>,
+    pub password_timeout: Duration,
+}
+
+/// Longest output from su before its password prompt that is examined.
+const MAX_SU_PROMPT_LEN: usize = 4096;
+
+/// Run `command` under `su USER -c`, on a pty since su reads its password
+/// from the controlling terminal, and wait for it.
+///
+/// The command runs under `/bin/sh` with stdin from `/dev/null`, so it never
+/// blocks on the terminal.  Its stdout and stderr both go to the pty and
+/// come back merged as stdout, with su's password prompt removed.  The exit
+/// code comes from a marker the wrapper prints after the command, since not
+/// every su passes the command's status on; if the marker is missing, as
+/// when the command is killed, su's own exit code is reported.  A failed
+/// authentication is an error.
+pub(crate) async fn run_su(command: SuCommand<'_>) -> Result<ProcessResult, RpcError> {
+    use nix::pty::{OpenptyResult, openpty};
+    use nix::sys::termios::{LocalFlags, OutputFlags, SetArg, tcgetattr, tcsetattr};
+
+    if command.user.is_empty() || command.user.starts_with('-') {
+        return Err(RpcError::invalid_params("Invalid su user"));
+    }
+    if command.argv.is_empty() {
+        return Err(RpcError::invalid_params("No command to run"));
+    }
+
+    let OpenptyResult { master, slave } = openpty(None, None)
+        .map_err(|e| RpcError::process_error(format!("Failed to allocate a pty: {}", e)))?;
+    // Keep output bytes as written ("\n", not "\r\n") and don't echo input
+    let mut termios = tcgetattr(&slave).map_err(|e| RpcError::io_error(e.into()))?;
+    termios.output_flags.remove(OutputFlags::OPOST);
+    termios.local_flags.remove(LocalFlags::ECHO);
+    tcsetattr(&slave, SetArg::TCSANOW, &termios).map_err(|e| RpcError::io_error(e.into()))?;
+
+    let marker = format!(
+        "=tramp-rpc-exit-{}-{}=",
+        std::process::id(),
+        NEXT_PROMPT_ID.fetch_add(1, Ordering::Relaxed)
+    );
+    let slave_io = |slave: &std::os::fd::OwnedFd| {
+        slave
+            .try_clone()
+            .map(Stdio::from)
+            .map_err(RpcError::io_error)
+    };
+    let mut su = Command::new("su");
+    su.arg(command.user)
+        .arg("-c")
+        .arg(su_script(&command.argv, command.cwd.as_deref(), &marker))
+        .stdin(slave_io(&slave)?)
+        .stdout(slave_io(&slave)?)
+        .stderr(Stdio::from(slave))
+        .kill_on_drop(true);
+    if let Some(cwd) = &command.cwd {
+        su.current_dir(cwd);
+    }
+    // SAFETY: only async-signal-safe calls between fork and exec.
+    unsafe {
+        su.pre_exec(|| {
+            if libc::setsid() < 0 || libc::ioctl(0, libc::TIOCSCTTY as _, 0) < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let mut child = su
+        .spawn()
+        .map_err(|e| RpcError::process_error(format!("Failed to run su: {}", e)))?;
+    drop(su);
+
+    let mut pty = tokio::fs::File::from_std(std::fs::File::from(master));
+    // Root is never asked for a password
+    let mut awaiting_prompt = unsafe { libc::geteuid() } != 0;
+    let mut prompted = false;
+    let mut output = Vec::new();
+    let mut buf = vec![0u8; 8192];
+    loop {
+        // Reading fails with EIO once every process has closed the pty
+        let n = pty.read(&mut buf).await.unwrap_or(0);
+        if n == 0 {
+            break;
+        }
+        output.extend_from_slice(&buf[..n]);
+        if awaiting_prompt {
+            if is_password_prompt(&output) {
+                awaiting_prompt = false;
+                prompted = true;
+                let mut password =
+                    match request_password("su", &output, command.user, command.password_timeout)
+                        .await
+                    {
+                        Ok(password) => password,
+                        Err(e) => {
+                            let _ = child.kill().await;
+                            return Err(e);
+                        }
+                    };
+                password.push(b'\n');
+                let written = pty.write_all(&password).await;
+                wipe(&mut password);
+                written.map_err(RpcError::io_error)?;
+                pty.flush().await.map_err(RpcError::io_error)?;
+                output.clear();
+            } else if output.len() > MAX_SU_PROMPT_LEN || output.contains(&b'\n') {
+                awaiting_prompt = false;
+            }
+        }
+    }
+    let status = child
+        .wait()
+        .await
+        .map_err(|e| RpcError::process_error(format!("Failed to wait for su: {}", e)))?;
+
+    // su ends the prompt line itself once it has read the password
+    if prompted {
+        let newline = [&b"\r\n"[..], b"\n"]
+            .into_iter()
+            .find(|newline| output.starts_with(newline));
+        if let Some(newline) = newline {
+            output.drain(..newline.len());
+        }
+    }
+
+    match take_exit_marker(&mut output, &marker) {
+        Some(exit_code) => Ok(ProcessResult {
+            exit_code,
+            signal: None,
+            stdout: output,
+            stderr: Vec::new(),
+        }),
+        None if output.starts_with(b"su:") => Err(RpcError::process_error(format!(
+            "su failed: {}",
+            String::from_utf8_lossy(&output).trim()
+        ))),
+        None => Ok(ProcessResult {
+            exit_code: crate::protocol::exit_code_from_status(status),
+            signal: crate::protocol::ExitSignal::from_status(status),
+            stdout: output,
+            stderr: Vec::new(),
+        }),
+    }
+}
+
+/// Quote `word` for a POSIX shell.
+pub(crate) fn shell_quote(word: &[u8]) -> Vec<u8> {
+    let mut quoted = vec![b'\''];
+    for &b in word {
+        if b == b'\'' {
+            quoted.extend_from_slice(b"'\\''");
+        } else {
+            quoted.push(b);
+        }
+    }
+    quoted.push(b'\'');
+    quoted
+}
+
+/// The `su -c` argument running `argv` in `cwd` under `/bin/sh`, then
+/// printing `marker` and the exit status.  The outer command is a plain
+/// `exec` of one quoted word, so it means the same in sh, csh and fish,
+/// whichever is the target user's shell.
+fn su_script(argv: &[OsString], cwd: Option<&Path>, marker: &str) -> OsString {
+    let mut inner = Vec::new();
+    if let Some(cwd) = cwd {
+        inner.extend_from_slice(b"cd ");
+        inner.extend(shell_quote(cwd.as_os_str().as_bytes()));
+        inner.extend_from_slice(b" || exit 1; ");
+    }
+    for word in argv {
+        inner.extend(shell_quote(word.as_bytes()));
+        inner.push(b' ');
+    }
+    inner.extend_from_slice(b"</dev/null; printf '%s%d\\n' ");
+    inner.extend(shell_quote(marker.as_bytes()));
+    inner.extend_from_slice(b" \"$?\"");
+
+    let mut script = b"exec /bin/sh -c ".to_vec();
+    script.extend(shell_quote(&inner));
+    std::os::unix::ffi::OsStringExt::from_vec(script)
+}
+
+/// Whether su's output so far is a password prompt: a single unterminated
+/// line ending in a colon, as in "Password:" or "Passwort: ".
+fn is_password_prompt(output: &[u8]) -> bool {
+    !output.contains(&b'\n') && output.trim_ascii_end().ends_with(b":")
+}
+
+/// Remove the last `marker` line from `output` and return its exit status.
+fn take_exit_marker(output: &mut Vec<u8>, marker: &str) -> Option<i32> {
+    let marker = marker.as_bytes();
+    let at = output
+        .windows(marker.len())
+        .rposition(|window| window == marker)?;
+    let status = std::str::from_utf8(&output[at + marker.len()..])
+        .ok()?
+        .trim_end()
+        .parse()
+        .ok()?;
+    output.truncate(at);
+    Some(status)
+}
+
+/// Error for a failed sudo `cat`/`sh` on `path`, from its stderr.
+pub(crate) fn file_error(result: &ProcessResult, path: &str) -> RpcError {
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    if stderr.contains("No such file or directory") {
+        RpcError::file_not_found(path)
+    } else if stderr.contains("Permission denied") {
+        RpcError::permission_denied(path)
+    } else {
+        RpcError::process_error(format!(
+            "sudo failed with exit code {}: {}",
+            result.exit_code,
+            stderr.trim()
+        ))
+    }
+}
+
+/// The `password_timeout` parameter, in seconds, checked.
+pub(crate) fn password_timeout(secs: Option<u64>) -> Result<Duration, RpcError> {
+    match secs.unwrap_or(DEFAULT_PASSWORD_TIMEOUT_SECS) {
+        secs @ 1..=MAX_PASSWORD_TIMEOUT_SECS => Ok(Duration::from_secs(secs)),
+        _ => Err(RpcError::invalid_params(format!(
+            "password_timeout must be between 1 and {} seconds",
+            MAX_PASSWORD_TIMEOUT_SECS
+        ))),
+    }
+}
+
+/// Run a command as `user` (default root) under sudo.
+///
+/// Takes `cmd`, `args`, `cwd` and `stdin` like `process.run`, and returns
+/// the same `{exit_code, stdout, stderr}`.  When sudo needs a password it
+/// is asked for with `auth.password_request`; without an answer within
+/// `password_timeout` seconds (default 120), sudo is killed and the call
+/// fails.
+pub async fn run_sudo(params: Value) -> HandlerResult {
+    #[derive(Deserialize)]
+    struct Params {
+        cmd: String,
+        #[serde(default)]
+        args: Vec<String>,
+        #[serde(default)]
+        cwd: Option<String>,
+        #[serde(default, with = "serde_bytes")]
+        stdin: Option<Vec<u8>>,
+        #[serde(default)]
+        user: Option<String>,
+        #[serde(default)]
+        password_timeout: Option<u64>,
+    }
+
+    let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
+
+    let cwd = match &params.cwd {
+        Some(cwd) => {
+            let cwd = PathBuf::from(super::expand_tilde(cwd));
+            crate::jail::check(&cwd)?;
+            Some(cwd)
+        }
+        None => None,
+    };
+    let result = run_as(SudoCommand {
+        user: params.user.as_deref().unwrap_or("root"),
+        program: OsStr::new(&params.cmd),
+        args: params.args.into_iter().map(OsString::from).collect(),
+        cwd,
+        stdin: params.stdin,
+        password_timeout: password_timeout(params.password_timeout)?,
+    })
+    .await?;
+    Ok(result.to_value())
+}
+
+/// Answer the `auth.password_request` with `id`: with `password`, or
+/// cancelling it with `cancel: true`.  Returns whether a prompt with that
+/// id was still waiting.
+pub async fn password_reply(params: Value) -> HandlerResult {
+    #[derive(Deserialize)]
+    struct Params {
+        id: u32,
+        #[serde(default)]
+        password: Option<PathBytes>,
+        #[serde(default)]
+        cancel: bool,
+    }
+
+    let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
+
+    let reply = match (params.cancel, params.password) {
+        (true, Some(PathBytes(mut password))) => {
+            wipe(&mut password);
+            None
+        }
+        (true, None) => None,
+        (false, Some(PathBytes(password))) => Some(password),
+        (false, None) => {
+            return Err(RpcError::invalid_params(
+                "password is required unless cancel is set",
+            ));
+        }
+    };
+    let waiting = pending().remove(&params.id);
+    let delivered = match waiting {
+        Some(tx) => match tx.send(reply) {
+            Ok(()) => true,
+            Err(Some(mut password)) => {
+                wipe(&mut password);
+                false
+            }
+            Err(None) => false,
+        },
+        None => {
+            if let Some(mut password) = reply {
+                wipe(&mut password);
+            }
+            false
+        }
+    };
+    Ok(Value::Boolean(delivered))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Run the helper side of a prompt on a thread, as sudo would.
+    fn helper(socket: PathBuf) -> std::thread::JoinHandle<Option<Vec<u8>>> {
+        std::thread::spawn(move || askpass(&socket, b"[sudo] password for u: ").unwrap())
+    }
+
+    async fn waiting_prompt() -> u32 {
+        loop {
+            if let Some(&id) = pending().keys().next() {
+                return id;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn password_prompts_are_answered_cancelled_and_time_out() {
+        let dir = SocketDir::create().unwrap();
+        let listener = tokio::net::UnixListener::bind(dir.socket()).unwrap();
+        let mode = std::fs::metadata(&dir.0).unwrap();
+        assert_eq!(
+            std::os::unix::fs::PermissionsExt::mode(&mode.permissions()) & 0o777,
+            0o700
+        );
+
+        // Answered
+        let thread = helper(dir.socket());
+        let (stream, _) = listener.accept().await.unwrap();
+        let answer = tokio::spawn(answer_prompt(stream, "root", Duration::from_secs(10)));
+        let id = waiting_prompt().await;
+        let delivered = password_reply(msgpack_map! {
+            "id" => id,
+            "password" => "hunter2",
+        })
+        .await
+        .unwrap();
+        assert_eq!(delivered.as_bool(), Some(true));
+        answer.await.unwrap().unwrap();
+        assert_eq!(thread.join().unwrap().as_deref(), Some(&b"hunter2"[..]));
+        assert!(pending().is_empty());
+
+        // Cancelled
+        let thread = helper(dir.socket());
+        let (stream, _) = listener.accept().await.unwrap();
+        let answer = tokio::spawn(answer_prompt(stream, "root", Duration::from_secs(10)));
+        let id = waiting_prompt().await;
+        password_reply(msgpack_map! { "id" => id, "cancel" => true })
+            .await
+            .unwrap();
+        assert!(answer.await.unwrap().is_err());
+        assert_eq!(thread.join().unwrap(), None);
+
+        // Unanswered
+        let thread = helper(dir.socket());
+        let (stream, _) = listener.accept().await.unwrap();
+        let err = answer_prompt(stream, "root", Duration::from_millis(20))
+            .await
+            .unwrap_err();
+        assert!(err.message.contains("timed out"));
+        assert_eq!(thread.join().unwrap(), None);
+        assert!(pending().is_empty());
+
+        let late = password_reply(msgpack_map! { "id" => id, "password" => "x" })
+            .await
+            .unwrap();
+        assert_eq!(late.as_bool(), Some(false));
+    }
+
+    #[test]
+    fn su_script_quotes_and_reports_the_exit_code() {
+        let tmp = tempfile::tempdir().unwrap();
+        let cwd = tmp.path().join("it's here");
+        std::fs::create_dir(&cwd).unwrap();
+        let marker = "=marker=";
+        // Run the script the way su hands it to the user's shell
+        let run = |argv: &[&str]| {
+            let argv: Vec<OsString> = argv.iter().map(OsString::from).collect();
+            let output = std::process::Command::new("/bin/sh")
+                .arg("-c")
+                .arg(su_script(&argv, Some(&cwd), marker))
+                .output()
+                .unwrap();
+            let mut stdout = output.stdout;
+            let status = take_exit_marker(&mut stdout, marker);
+            (stdout, status)
+        };
+
+        let (stdout, status) = run(&["printf", "%s|", "it's", "a $b", "`c`"]);
+        assert_eq!(stdout, b"it's|a $b|`c`|");
+        assert_eq!(status, Some(0));
+
+        let (stdout, status) = run(&["sh", "-c", "pwd; exit 3"]);
+        assert_eq!(stdout, format!("{}\n", cwd.display()).as_bytes());
+        assert_eq!(status, Some(3));
+
+        let mut unmarked = b"killed".to_vec();
+        assert_eq!(take_exit_marker(&mut unmarked, marker), None);
+        assert_eq!(unmarked, b"killed");
+    }
+
+    #[test]
+    fn su_password_prompts_are_recognized() {
+        assert!(is_password_prompt(b"Password: "));
+        assert!(is_password_prompt(b"Mot de passe :"));
+        assert!(!is_password_prompt(b"Password"));
+        assert!(!is_password_prompt(b"motd\nPassword: "));
+    }
+
+    #[tokio::test]
+    async fn run_su_as_root_needs_no_password() {
+        // Only root can su without a password prompt
+        if unsafe { libc::geteuid() } != 0 || !Path::new("/bin/sh").exists() {
+            return;
+        }
+        let result = run_su(SuCommand {
+            user: "root",
+            argv: ["sh", "-c", "echo out; echo err >&2; exit 5"]
+                .iter()
+                .map(OsString::from)
+                .collect(),
+            cwd: None,
+            password_timeout: Duration::from_secs(5),
+        })
+        .await;
+        let Ok(result) = result else {
+            return; // no usable su here
+        };
+        assert_eq!(result.exit_code, 5);
+        assert_eq!(result.stdout, b"out\nerr\n");
+    }
+}
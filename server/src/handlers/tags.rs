@@ -0,0 +1,591 @@
+//! Tag generation and lookup for TRAMP-RPC
+//!
+//! This module provides:
+//! - `tags.generate`: Run universal-ctags into a per-root cache file
+//! - `tags.query`: Look up symbols in that cache without downloading it
+
+use crate::jail;
+use crate::msgpack_map;
+use crate::protocol::{RpcError, from_value};
+use crate::server_dirs::{self, Freed, Kind};
+use rmpv::Value;
+use serde::Deserialize;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{Duration, SystemTime};
+
+use super::HandlerResult;
+
+/// Default and maximum number of matches returned by `tags.query`.
+const DEFAULT_QUERY_LIMIT: usize = 100;
+const MAX_QUERY_LIMIT: usize = 10_000;
+
+/// Output formats supported by `tags.generate`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum TagFormat {
+    /// universal-ctags' extended `tags` format
+    Ctags,
+    /// Emacs `TAGS` format, usable by `visit-tags-table`
+    Etags,
+}
+
+impl TagFormat {
+    const ALL: [TagFormat; 2] = [TagFormat::Ctags, TagFormat::Etags];
+
+    fn as_str(self) -> &'static str {
+        match self {
+            TagFormat::Ctags => "ctags",
+            TagFormat::Etags => "etags",
+        }
+    }
+
+    fn ctags_args(self) -> &'static [&'static str] {
+        match self {
+            TagFormat::Ctags => &["--output-format=u-ctags", "--fields=+nK"],
+            TagFormat::Etags => &["--output-format=etags"],
+        }
+    }
+}
+
+/// Tag caches not regenerated for this long are removed by `system.gc`.
+const CACHE_MAX_AGE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// Directory holding tag caches: `dir`, below the directory `root` that
+/// the server owns (see [`server_dirs::locate`]).
+struct CacheDir {
+    root: PathBuf,
+    dir: PathBuf,
+}
+
+impl CacheDir {
+    /// `tags` in the server's cache dir
+    fn server() -> Self {
+        let (root, dir) = server_dirs::locate(Kind::Cache);
+        CacheDir {
+            root,
+            dir: dir.join("tags"),
+        }
+    }
+
+    /// Cache file for `root` in `format`.
+    fn file(&self, root: &Path, format: TagFormat) -> PathBuf {
+        let mut hasher = DefaultHasher::new();
+        root.hash(&mut hasher);
+        self.dir
+            .join(format!("{:016x}.{}", hasher.finish(), format.as_str()))
+    }
+
+    fn create(&self) -> std::io::Result<()> {
+        server_dirs::create_private(&self.root, &self.dir)
+    }
+}
+
+fn ctags_missing(program: &str, detail: impl std::fmt::Display) -> RpcError {
+    RpcError {
+        code: RpcError::PROCESS_ERROR,
+        message: format!("universal-ctags is not available ({}): {}", program, detail),
+        data: Some(msgpack_map! {
+            "missing_program" => program
+        }),
+    }
+}
+
+/// Newest mtime of the files and directories under `paths`.
+///
+/// Directories are included so that deletions and renames also invalidate
+/// the cache.  Git ignore rules are honored, matching what is worth tagging.
+fn newest_mtime(root: &Path, paths: &[String]) -> Option<SystemTime> {
+    let mut newest = None;
+    for path in paths {
+        let walker = ignore::WalkBuilder::new(root.join(path))
+            .hidden(false)
+            .ignore(false)
+            .parents(true)
+            .build();
+        for entry in walker.filter_map(Result::ok) {
+            if let Some(mtime) = entry.metadata().ok().and_then(|m| m.modified().ok()) {
+                newest = newest.max(Some(mtime));
+            }
+        }
+    }
+    newest
+}
+
+/// Generate tags for a root into a server-side cache file.
+///
+/// The cache is reused as long as it is newer than every file and directory
+/// under `paths`, unless `regenerate` is set.  When ctags is missing or is
+/// not universal-ctags, the error carries `missing_program` in its data so
+/// clients can fall back to other xref backends.
+pub async fn generate(params: Value) -> HandlerResult {
+    generate_in(CacheDir::server(), params).await
+}
+
+/// [`generate`] with the caches in `caches`.
+async fn generate_in(caches: CacheDir, params: Value) -> HandlerResult {
+    #[derive(Deserialize)]
+    struct Params {
+        /// Project root; tag file paths are relative to it
+        root: String,
+        /// Paths to index, relative to the root (default: the whole root)
+        #[serde(default = "default_paths")]
+        paths: Vec<String>,
+        /// Output format: "ctags" (default) or "etags"
+        #[serde(default = "default_format")]
+        format: TagFormat,
+        /// Ignore an up-to-date cache
+        #[serde(default)]
+        regenerate: bool,
+        /// ctags executable (default: "ctags")
+        #[serde(default = "default_program")]
+        program: String,
+    }
+
+    fn default_paths() -> Vec<String> {
+        vec![".".to_string()]
+    }
+
+    fn default_format() -> TagFormat {
+        TagFormat::Ctags
+    }
+
+    fn default_program() -> String {
+        "ctags".to_string()
+    }
+
+    let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
+
+    let root = PathBuf::from(super::expand_tilde(&params.root));
+    jail::check(&root)?;
+    for path in &params.paths {
+        jail::check(&root.join(path))?;
+    }
+    crate::blocking::run(root.clone(), move || {
+        if !root.is_dir() {
+            return Err(RpcError::file_not_found(&root));
+        }
+        let cache = caches.file(&root, params.format);
+
+        if !params.regenerate
+            && let Ok(cached) = std::fs::metadata(&cache).and_then(|m| m.modified())
+            && newest_mtime(&root, &params.paths).is_none_or(|newest| newest <= cached)
+        {
+            return Ok(generate_result(&cache, params.format, false));
+        }
+
+        let version = Command::new(&params.program)
+            .arg("--version")
+            .stdin(Stdio::null())
+            .output()
+            .map_err(|e| ctags_missing(&params.program, e))?;
+        if !String::from_utf8_lossy(&version.stdout).contains("Universal Ctags") {
+            return Err(ctags_missing(&params.program, "not universal-ctags"));
+        }
+
+        caches.create().map_err(RpcError::io_error)?;
+        // Write next to the cache and rename so queries never see a partial file.
+        let tmp = cache.with_extension(format!("{}.tmp", std::process::id()));
+        let output = Command::new(&params.program)
+            .arg("-R")
+            .args(params.format.ctags_args())
+            .arg("-f")
+            .arg(&tmp)
+            .arg("--")
+            .args(&params.paths)
+            .current_dir(&root)
+            .stdin(Stdio::null())
+            .output()
+            .map_err(|e| ctags_missing(&params.program, e))?;
+        if !output.status.success() {
+            let _ = std::fs::remove_file(&tmp);
+            return Err(RpcError::process_error(format!(
+                "ctags exited with {}: {}",
+                crate::protocol::exit_code_from_status(output.status),
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        std::fs::rename(&tmp, &cache).map_err(RpcError::io_error)?;
+
+        Ok(generate_result(&cache, params.format, true))
+    })
+    .await?
+}
+
+fn generate_result(cache: &Path, format: TagFormat, generated: bool) -> Value {
+    msgpack_map! {
+        "cache_file" => cache.to_string_lossy().into_owned(),
+        "format" => format.as_str(),
+        "generated" => generated
+    }
+}
+
+/// A single tag record.
+#[derive(Debug, PartialEq)]
+struct Tag {
+    name: String,
+    file: String,
+    line: Option<u64>,
+    pattern: Option<String>,
+    kind: Option<String>,
+}
+
+impl Tag {
+    fn to_value(&self) -> Value {
+        use crate::protocol::IntoValue;
+        msgpack_map! {
+            "name" => self.name.clone(),
+            "file" => self.file.clone(),
+            "line" => self.line.into_value(),
+            "pattern" => self.pattern.clone().into_value(),
+            "kind" => self.kind.clone().into_value()
+        }
+    }
+}
+
+/// Look up a symbol in the tags cache generated for a root.
+///
+/// Matches are exact unless `prefix` is set.  Uses whichever cache format
+/// exists, preferring ctags since it carries kinds.
+pub async fn query(params: Value) -> HandlerResult {
+    query_in(CacheDir::server(), params).await
+}
+
+/// [`query`] with the caches in `caches`.
+async fn query_in(caches: CacheDir, params: Value) -> HandlerResult {
+    #[derive(Deserialize)]
+    struct Params {
+        /// Project root passed to `tags.generate`
+        root: String,
+        /// Symbol to look up
+        symbol: String,
+        /// Match every tag starting with `symbol`
+        #[serde(default)]
+        prefix: bool,
+        /// Maximum number of matches to return
+        #[serde(default = "default_limit")]
+        limit: usize,
+    }
+
+    fn default_limit() -> usize {
+        DEFAULT_QUERY_LIMIT
+    }
+
+    let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
+
+    if params.limit == 0 || params.limit > MAX_QUERY_LIMIT {
+        return Err(RpcError::invalid_params(format!(
+            "limit must be between 1 and {}",
+            MAX_QUERY_LIMIT
+        )));
+    }
+
+    let root = PathBuf::from(super::expand_tilde(&params.root));
+    jail::check(&root)?;
+    crate::blocking::run(root.clone(), move || {
+        let Some((cache, format)) = TagFormat::ALL
+            .iter()
+            .map(|&format| (caches.file(&root, format), format))
+            .find(|(cache, _)| cache.is_file())
+        else {
+            return Err(RpcError {
+                message: format!(
+                    "File not found: tags cache for {} (run tags.generate)",
+                    root.display()
+                ),
+                ..RpcError::file_not_found(&root)
+            });
+        };
+
+        let contents = std::fs::read(&cache).map_err(RpcError::io_error)?;
+        let contents = String::from_utf8_lossy(&contents);
+        let wanted = |name: &str| {
+            if params.prefix {
+                name.starts_with(&params.symbol)
+            } else {
+                name == params.symbol
+            }
+        };
+        let tags = match format {
+            TagFormat::Ctags => parse_ctags(&contents, wanted, params.limit),
+            TagFormat::Etags => parse_etags(&contents, wanted, params.limit),
+        };
+
+        Ok(msgpack_map! {
+            "format" => format.as_str(),
+            "matches" => Value::Array(tags.iter().map(Tag::to_value).collect())
+        })
+    })
+    .await?
+}
+
+/// Parse universal-ctags `tags` lines, keeping those whose name is wanted.
+fn parse_ctags(contents: &str, wanted: impl Fn(&str) -> bool, limit: usize) -> Vec<Tag> {
+    let mut tags = Vec::new();
+    for line in contents.lines() {
+        if tags.len() == limit {
+            break;
+        }
+        // Pseudo-tags describe the file itself.
+        if line.starts_with("!_") {
+            continue;
+        }
+        let mut parts = line.splitn(3, '\t');
+        let (Some(name), Some(file), Some(rest)) = (parts.next(), parts.next(), parts.next())
+        else {
+            continue;
+        };
+        if !wanted(name) {
+            continue;
+        }
+
+        let (excmd, fields) = rest.split_once(";\"").unwrap_or((rest, ""));
+        let mut tag = Tag {
+            name: name.to_string(),
+            file: file.to_string(),
+            line: None,
+            pattern: None,
+            kind: None,
+        };
+        if let Ok(line) = excmd.parse() {
+            tag.line = Some(line);
+        } else {
+            tag.pattern = Some(unescape_pattern(excmd));
+        }
+        for field in fields.split('\t').filter(|f| !f.is_empty()) {
+            match field.split_once(':') {
+                Some(("line", value)) => tag.line = value.parse().ok().or(tag.line),
+                Some(("kind", value)) => tag.kind = Some(value.to_string()),
+                Some(_) => {}
+                None => tag.kind = Some(field.to_string()),
+            }
+        }
+        tags.push(tag);
+    }
+    tags
+}
+
+/// Strip the `/^...$/` delimiters and escapes from a ctags search pattern.
+fn unescape_pattern(excmd: &str) -> String {
+    let body = excmd
+        .strip_prefix('/')
+        .or_else(|| excmd.strip_prefix('?'))
+        .unwrap_or(excmd);
+    let body = body
+        .strip_suffix('/')
+        .or_else(|| body.strip_suffix('?'))
+        .unwrap_or(body);
+    let body = body.strip_prefix('^').unwrap_or(body);
+    let body = body.strip_suffix('$').unwrap_or(body);
+
+    let mut out = String::with_capacity(body.len());
+    let mut chars = body.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\'
+            && let Some(next) = chars.next()
+        {
+            out.push(next);
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+/// Parse an Emacs `TAGS` file, keeping tags whose name is wanted.
+fn parse_etags(contents: &str, wanted: impl Fn(&str) -> bool, limit: usize) -> Vec<Tag> {
+    let mut tags = Vec::new();
+    for section in contents.split('\x0c') {
+        let mut lines = section.trim_start_matches('\n').lines();
+        let Some(header) = lines.next() else {
+            continue;
+        };
+        let file = header.rsplit_once(',').map_or(header, |(file, _)| file);
+        for line in lines {
+            if tags.len() == limit {
+                return tags;
+            }
+            let Some((pattern, rest)) = line.split_once('\x7f') else {
+                continue;
+            };
+            let (name, position) = match rest.split_once('\x01') {
+                Some((name, position)) => (name.to_string(), position),
+                // Implicit tag name: the last identifier in the pattern.
+                None => (implicit_etags_name(pattern), rest),
+            };
+            if !wanted(&name) {
+                continue;
+            }
+            tags.push(Tag {
+                name,
+                file: file.to_string(),
+                line: position.split(',').next().and_then(|l| l.parse().ok()),
+                pattern: Some(pattern.to_string()),
+                kind: None,
+            });
+        }
+    }
+    tags
+}
+
+fn implicit_etags_name(pattern: &str) -> String {
+    let is_ident = |c: char| c.is_alphanumeric() || c == '_' || c == '$';
+    pattern
+        .trim_end_matches(|c: char| !is_ident(c))
+        .rsplit(|c: char| !is_ident(c))
+        .next()
+        .unwrap_or_default()
+        .to_string()
+}
+
+/// Remove tag caches older than `max_age` (default [`CACHE_MAX_AGE`]).
+pub(crate) fn gc(max_age: Option<Duration>) -> Freed {
+    let mut freed = Freed::default();
+    freed.remove_older_than(&CacheDir::server().dir, max_age.unwrap_or(CACHE_MAX_AGE));
+    freed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::map_get;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn parse_ctags_reads_patterns_kinds_and_lines() {
+        let contents = "!_TAG_FILE_FORMAT\t2\t/extended format/\n\
+            main\tsrc/main.rs\t/^fn main() {$/;\"\tfunction\tline:3\n\
+            main_loop\tsrc/main.rs\t/^async fn main_loop(a: \\/x) {$/;\"\tkind:function\tline:9\n\
+            MAX\tsrc/lib.rs\t12;\"\tconstant\n";
+
+        let exact = parse_ctags(contents, |n| n == "main", 10);
+        assert_eq!(
+            exact,
+            vec![Tag {
+                name: "main".into(),
+                file: "src/main.rs".into(),
+                line: Some(3),
+                pattern: Some("fn main() {".into()),
+                kind: Some("function".into()),
+            }]
+        );
+
+        let prefixed = parse_ctags(contents, |n| n.starts_with("main"), 10);
+        assert_eq!(prefixed.len(), 2);
+        assert_eq!(
+            prefixed[1].pattern.as_deref(),
+            Some("async fn main_loop(a: /x) {")
+        );
+
+        let numeric = parse_ctags(contents, |n| n == "MAX", 10);
+        assert_eq!(numeric[0].line, Some(12));
+        assert_eq!(numeric[0].pattern, None);
+        assert_eq!(parse_ctags(contents, |_| true, 1).len(), 1);
+    }
+
+    #[test]
+    fn parse_etags_reads_explicit_and_implicit_names() {
+        let contents =
+            "\x0c\nsrc/main.c,52\nint main(void)\x7fmain\x011,0\nstatic int helper(\x7f5,40\n";
+        let tags = parse_etags(contents, |_| true, 10);
+        assert_eq!(tags.len(), 2);
+        assert_eq!(tags[0].name, "main");
+        assert_eq!(tags[0].file, "src/main.c");
+        assert_eq!(tags[0].line, Some(1));
+        assert_eq!(tags[1].name, "helper");
+        assert_eq!(tags[1].line, Some(5));
+    }
+
+    #[tokio::test]
+    async fn tags_generate_reports_missing_ctags() {
+        let tmp = tempfile::tempdir().unwrap();
+        let error = generate(msgpack_map! {
+            "root" => tmp.path().to_string_lossy().into_owned(),
+            "program" => "/nonexistent/ctags",
+            "regenerate" => true
+        })
+        .await
+        .expect_err("missing ctags");
+        assert_eq!(error.code, RpcError::PROCESS_ERROR);
+        assert_eq!(
+            error
+                .data
+                .as_ref()
+                .and_then(|d| map_get(d, "missing_program"))
+                .and_then(Value::as_str),
+            Some("/nonexistent/ctags")
+        );
+    }
+
+    #[tokio::test]
+    async fn tags_generate_caches_until_sources_change() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().join("project");
+        std::fs::create_dir(&root).unwrap();
+        std::fs::write(root.join("lib.rs"), "fn answer() {}\n").unwrap();
+
+        // Stand-in for universal-ctags that writes a fixed tags file to `-f`.
+        let program = tmp.path().join("fake-ctags");
+        std::fs::write(
+            &program,
+            "#!/bin/sh\n\
+             [ \"$1\" = --version ] && { echo 'Universal Ctags 6.0.0'; exit 0; }\n\
+             while [ \"$1\" != -f ]; do shift; done\n\
+             printf 'answer\\tlib.rs\\t/^fn answer() {}$/;\"\\tfunction\\tline:1\\n' > \"$2\"\n",
+        )
+        .unwrap();
+        std::fs::set_permissions(&program, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let caches = || CacheDir {
+            root: tmp.path().join("cache"),
+            dir: tmp.path().join("cache/tags"),
+        };
+
+        let params = || {
+            msgpack_map! {
+                "root" => root.to_string_lossy().into_owned(),
+                "program" => program.to_string_lossy().into_owned()
+            }
+        };
+        let first = generate_in(caches(), params()).await.expect("generate");
+        assert_eq!(
+            map_get(&first, "generated").and_then(Value::as_bool),
+            Some(true)
+        );
+        let cache_file = map_get(&first, "cache_file")
+            .and_then(Value::as_str)
+            .unwrap();
+        assert!(Path::new(cache_file).starts_with(caches().dir));
+        let second = generate_in(caches(), params())
+            .await
+            .expect("cached generate");
+        assert_eq!(
+            map_get(&second, "generated").and_then(Value::as_bool),
+            Some(false)
+        );
+
+        let result = query_in(
+            caches(),
+            msgpack_map! {
+                "root" => root.to_string_lossy().into_owned(),
+                "symbol" => "ans",
+                "prefix" => true
+            },
+        )
+        .await
+        .expect("query");
+        let matches = map_get(&result, "matches")
+            .and_then(Value::as_array)
+            .unwrap();
+        assert_eq!(matches.len(), 1);
+        assert_eq!(
+            map_get(&matches[0], "kind").and_then(Value::as_str),
+            Some("function")
+        );
+        assert_eq!(
+            map_get(&matches[0], "line").and_then(Value::as_u64),
+            Some(1)
+        );
+    }
+}
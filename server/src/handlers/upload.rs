@@ -0,0 +1,922 @@
+//! Chunked, resumable uploads for TRAMP-RPC
+//!
+//! This module provides:
+//! - `file.write_begin`: Open (or resume) an upload into a temporary sibling
+//! - `file.write_chunk`: Write one chunk at an offset (idempotent)
+//! - `file.write_commit`: Verify and atomically rename into place
+//! - `file.write_abort`: Discard an upload
+//!
+//! The partial file is named after the target path and total size, so a
+//! client reconnecting to a fresh server process can resume it.  Each one is
+//! also recorded in the server's state dir, so that `system.gc`, and a sweep
+//! every few minutes, can remove those that were never resumed.
+
+use crate::jail;
+use crate::msgpack_map;
+use crate::protocol::{IntoValue, RpcError, from_value};
+use crate::server_dirs::{self, Freed, Kind};
+use crate::stat_cache;
+use md5::{Digest, Md5};
+use rmpv::Value;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io::Read;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::Mutex;
+
+use super::HandlerResult;
+use super::file::{bytes_to_path, map_io_error};
+
+use crate::protocol::path_or_bytes;
+
+/// Maximum number of uploads open at once.
+const MAX_OPEN_UPLOADS: usize = 64;
+
+/// Uploads without activity for this long are discarded.
+const UPLOAD_IDLE_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
+/// Partial files left by earlier server processes are resumed only if they
+/// were touched within this window; older ones are overwritten.
+const PARTIAL_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+struct Upload {
+    path: PathBuf,
+    temp: PathBuf,
+    total_size: u64,
+    mode: Option<u32>,
+    /// Sorted, non-overlapping byte ranges written so far
+    ranges: Vec<(u64, u64)>,
+    last_activity: Instant,
+}
+
+impl Upload {
+    /// Length of the contiguous prefix received so far.
+    fn received(&self) -> u64 {
+        match self.ranges.first() {
+            Some(&(0, end)) => end,
+            _ => 0,
+        }
+    }
+
+    fn record(&mut self, start: u64, end: u64) {
+        if start == end {
+            return;
+        }
+        self.ranges.push((start, end));
+        self.ranges.sort_unstable();
+        let mut merged: Vec<(u64, u64)> = Vec::with_capacity(self.ranges.len());
+        for &(s, e) in &self.ranges {
+            match merged.last_mut() {
+                Some(last) if s <= last.1 => last.1 = last.1.max(e),
+                _ => merged.push((s, e)),
+            }
+        }
+        self.ranges = merged;
+    }
+}
+
+static UPLOAD_MAP: OnceLock<Mutex<HashMap<u32, Upload>>> = OnceLock::new();
+static UPLOAD_ID_COUNTER: OnceLock<Mutex<u32>> = OnceLock::new();
+
+fn get_upload_map() -> &'static Mutex<HashMap<u32, Upload>> {
+    UPLOAD_MAP.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+async fn get_next_upload_id() -> u32 {
+    let counter = UPLOAD_ID_COUNTER.get_or_init(|| Mutex::new(1));
+    let mut id = counter.lock().await;
+    let current = *id;
+    *id = id.wrapping_add(1).max(1);
+    current
+}
+
+/// Deterministic partial-file path for `path` with `total_size` bytes.
+fn partial_path(path: &Path, total_size: u64) -> PathBuf {
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    path.with_file_name(format!(".{}.tramp-rpc-upload-{}", name, total_size))
+}
+
+/// Take out the uploads idle for longer than [`UPLOAD_IDLE_TIMEOUT`], for
+/// [`discard`] to remove once the map is unlocked.
+fn take_idle(uploads: &mut HashMap<u32, Upload>) -> Vec<Upload> {
+    let idle: Vec<u32> = uploads
+        .iter()
+        .filter(|(_, upload)| upload.last_activity.elapsed() >= UPLOAD_IDLE_TIMEOUT)
+        .map(|(&id, _)| id)
+        .collect();
+    idle.iter().filter_map(|id| uploads.remove(id)).collect()
+}
+
+/// Delete the partial files of `uploads` and their records.
+fn discard(uploads: Vec<Upload>) {
+    for upload in uploads {
+        let _ = std::fs::remove_file(&upload.temp);
+        forget(&upload.temp);
+    }
+}
+
+/// How often idle uploads and stale partial files are swept
+const SWEEP_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Run [`gc`] now and every [`SWEEP_INTERVAL`], so idle uploads and
+/// partial files nobody resumes go away without waiting for a `system.gc`.
+pub(crate) fn start_sweeper() {
+    tokio::spawn(async {
+        let mut ticks = tokio::time::interval(SWEEP_INTERVAL);
+        loop {
+            ticks.tick().await;
+            gc(None).await;
+        }
+    });
+}
+
+/// Directory of partial file records (`uploads` in the server's state dir).
+fn records_dir() -> PathBuf {
+    server_dirs::path(Kind::State).join("uploads")
+}
+
+fn record_name(temp: &Path) -> String {
+    let mut hasher = DefaultHasher::new();
+    temp.hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
+/// Record the partial file `temp`.  Uploads work without a record; it
+/// only lets `system.gc` find the file later.
+fn remember(temp: &Path) {
+    if let Ok(dir) = server_dirs::subdir(Kind::State, "uploads") {
+        let _ = std::fs::write(dir.join(record_name(temp)), temp.as_os_str().as_bytes());
+    }
+}
+
+/// Drop the record of the partial file `temp`.
+fn forget(temp: &Path) {
+    let _ = std::fs::remove_file(records_dir().join(record_name(temp)));
+}
+
+/// Remove recorded partial files older than `max_age` (default
+/// [`PARTIAL_MAX_AGE`]) that no open upload is using.
+pub(crate) async fn gc(max_age: Option<Duration>) -> Freed {
+    let (idle, active): (_, HashSet<PathBuf>) = {
+        let mut uploads = get_upload_map().lock().await;
+        let idle = take_idle(&mut uploads);
+        (idle, uploads.values().map(|u| u.temp.clone()).collect())
+    };
+    let max_age = max_age.unwrap_or(PARTIAL_MAX_AGE);
+    tokio::task::spawn_blocking(move || {
+        discard(idle);
+        gc_records(&records_dir(), &active, max_age)
+    })
+    .await
+    .unwrap_or_default()
+}
+
+fn gc_records(records: &Path, active: &HashSet<PathBuf>, max_age: Duration) -> Freed {
+    let mut freed = Freed::default();
+    let Ok(entries) = std::fs::read_dir(records) else {
+        return freed;
+    };
+    for entry in entries.flatten() {
+        let Ok(bytes) = std::fs::read(entry.path()) else {
+            continue;
+        };
+        let temp = PathBuf::from(OsStr::from_bytes(&bytes));
+        if active.contains(&temp) {
+            continue;
+        }
+        // Only ever delete files named like the ones `write_begin` creates.
+        let is_partial = temp.file_name().is_some_and(|name| {
+            name.as_bytes()
+                .windows(b".tramp-rpc-upload-".len())
+                .any(|w| w == b".tramp-rpc-upload-")
+        });
+        match std::fs::symlink_metadata(&temp) {
+            Ok(meta) if is_partial && meta.is_file() => {
+                let expired = meta
+                    .modified()
+                    .ok()
+                    .and_then(|m| SystemTime::now().duration_since(m).ok())
+                    .is_some_and(|age| age > max_age);
+                if !expired {
+                    continue;
+                }
+                freed.remove_file(&temp);
+            }
+            Ok(_) if is_partial => continue,
+            _ => {}
+        }
+        let _ = std::fs::remove_file(entry.path());
+    }
+    freed
+}
+
+fn upload_not_found(id: u32) -> RpcError {
+    RpcError::invalid_params(format!("Upload not found: {}", id))
+}
+
+#[derive(Deserialize)]
+struct BeginParams {
+    #[serde(with = "path_or_bytes")]
+    path: Vec<u8>,
+    /// Final size of the file in bytes
+    total_size: u64,
+    /// File mode applied on commit (default: keep the existing file's mode)
+    #[serde(default)]
+    mode: Option<u32>,
+    /// Resume an existing partial upload (default: true)
+    #[serde(default = "default_true")]
+    resume: bool,
+    /// Fail up front unless the filesystem has this many bytes available
+    #[serde(default)]
+    require_free_bytes: Option<u64>,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// Start or resume a chunked upload.
+///
+/// Returns `{id, temp_path, received}`; `received` is the offset to resume
+/// from.  An upload already open for the same path and size is returned as
+/// is; otherwise a partial file left by an earlier connection is adopted,
+/// assuming it was written sequentially.  Pass `resume: false` to start over.
+pub async fn write_begin(params: Value) -> HandlerResult {
+    let params: BeginParams =
+        from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
+
+    let path = bytes_to_path(&params.path).to_path_buf();
+    jail::check(&path)?;
+    let temp = partial_path(&path, params.total_size);
+    // The upload is written to `temp`, which needs the space and an inode
+    if let Some(required) = params.require_free_bytes {
+        super::check_free_space(&temp, required)?;
+    }
+
+    let idle = {
+        let mut uploads = get_upload_map().lock().await;
+        let idle = take_idle(&mut uploads);
+        if let Some(result) = reuse_open(&mut uploads, &path, &params) {
+            drop(uploads);
+            discard_in_background(idle);
+            return Ok(result);
+        }
+        if uploads.len() >= MAX_OPEN_UPLOADS {
+            drop(uploads);
+            discard_in_background(idle);
+            return Err(too_many_uploads());
+        }
+        idle
+    };
+
+    // The file work is done without holding the map
+    let resume = params.resume;
+    let (temp, resumable_len) = crate::blocking::run(temp.clone(), move || {
+        discard(idle);
+        let resumable_len = std::fs::metadata(&temp).ok().filter(|meta| {
+            resume
+                && meta.is_file()
+                && meta
+                    .modified()
+                    .ok()
+                    .and_then(|m| SystemTime::now().duration_since(m).ok())
+                    .is_some_and(|age| age < PARTIAL_MAX_AGE)
+        });
+        std::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(resumable_len.is_none())
+            .open(&temp)?;
+        remember(&temp);
+        Ok::<_, std::io::Error>((temp, resumable_len.map(|meta| meta.len())))
+    })
+    .await?
+    .map_err(|e| map_io_error(e, &path))?;
+
+    let mut uploads = get_upload_map().lock().await;
+    // Another `file.write_begin` for the same upload may have won the race
+    if let Some(result) = reuse_open(&mut uploads, &path, &params) {
+        return Ok(result);
+    }
+    if uploads.len() >= MAX_OPEN_UPLOADS {
+        return Err(too_many_uploads());
+    }
+    let mut upload = Upload {
+        path,
+        temp,
+        total_size: params.total_size,
+        mode: params.mode,
+        ranges: Vec::new(),
+        last_activity: Instant::now(),
+    };
+    if let Some(len) = resumable_len {
+        upload.record(0, len.min(params.total_size));
+    }
+
+    let id = get_next_upload_id().await;
+    let result = begin_result(id, &upload);
+    uploads.insert(id, upload);
+    Ok(result)
+}
+
+/// The reply for an upload already open for the same path and size, when
+/// it is to be resumed.  Without `resume` such an upload is dropped, as its
+/// partial file is about to be started over.
+fn reuse_open(
+    uploads: &mut HashMap<u32, Upload>,
+    path: &Path,
+    params: &BeginParams,
+) -> Option<Value> {
+    let (&id, upload) = uploads
+        .iter_mut()
+        .find(|(_, u)| u.path == path && u.total_size == params.total_size)?;
+    if params.resume {
+        upload.last_activity = Instant::now();
+        upload.mode = params.mode.or(upload.mode);
+        return Some(begin_result(id, upload));
+    }
+    uploads.remove(&id);
+    None
+}
+
+fn discard_in_background(idle: Vec<Upload>) {
+    if !idle.is_empty() {
+        tokio::task::spawn_blocking(move || discard(idle));
+    }
+}
+
+fn too_many_uploads() -> RpcError {
+    RpcError::invalid_request(format!("Too many open uploads (max {})", MAX_OPEN_UPLOADS))
+}
+
+fn begin_result(id: u32, upload: &Upload) -> Value {
+    msgpack_map! {
+        "id" => id,
+        "temp_path" => upload.temp.to_string_lossy().into_owned(),
+        "received" => upload.received()
+    }
+}
+
+/// Write one chunk of an upload at `offset`.
+///
+/// Rewriting a chunk that was already received is harmless, so clients may
+/// retry any chunk whose acknowledgement was lost.
+pub async fn write_chunk(params: Value) -> HandlerResult {
+    #[derive(Deserialize)]
+    struct Params {
+        id: u32,
+        offset: u64,
+        #[serde(with = "serde_bytes")]
+        data: Vec<u8>,
+    }
+
+    let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
+
+    let (temp, path_str) = {
+        let mut uploads = get_upload_map().lock().await;
+        let upload = uploads
+            .get_mut(&params.id)
+            .ok_or_else(|| upload_not_found(params.id))?;
+        let end = params.offset.checked_add(params.data.len() as u64);
+        if end.is_none_or(|end| end > upload.total_size) {
+            return Err(RpcError::invalid_params(format!(
+                "Chunk at offset {} exceeds total size {}",
+                params.offset, upload.total_size
+            )));
+        }
+        upload.last_activity = Instant::now();
+        (
+            upload.temp.clone(),
+            upload.path.to_string_lossy().into_owned(),
+        )
+    };
+
+    let len = params.data.len() as u64;
+    let offset = params.offset;
+    crate::blocking::run(temp.clone(), move || {
+        let file = std::fs::OpenOptions::new().write(true).open(&temp)?;
+        file.write_all_at(&params.data, offset)
+    })
+    .await?
+    .map_err(|e| map_io_error(e, &path_str))?;
+
+    let mut uploads = get_upload_map().lock().await;
+    let upload = uploads
+        .get_mut(&params.id)
+        .ok_or_else(|| upload_not_found(params.id))?;
+    upload.record(offset, offset + len);
+    Ok(msgpack_map! {
+        "received" => upload.received()
+    })
+}
+
+/// Remove upload `id` to finish it, which every byte must have been
+/// received for.
+async fn take_complete(id: u32) -> Result<Upload, RpcError> {
+    let mut uploads = get_upload_map().lock().await;
+    let upload = uploads.get(&id).ok_or_else(|| upload_not_found(id))?;
+    if upload.received() < upload.total_size {
+        return Err(RpcError::invalid_request(format!(
+            "Upload incomplete: received {} of {} bytes",
+            upload.received(),
+            upload.total_size
+        )));
+    }
+    Ok(uploads.remove(&id).unwrap())
+}
+
+/// Finish an upload: verify it and rename it over the target.
+///
+/// Every byte must have been received.  When `md5` (lowercase hex) is given
+/// and does not match, the upload is discarded and a [`RpcError::CONFLICT`]
+/// error with reason "checksum" is returned.
+pub async fn write_commit(params: Value) -> HandlerResult {
+    #[derive(Deserialize)]
+    struct Params {
+        id: u32,
+        #[serde(default)]
+        md5: Option<String>,
+    }
+
+    let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
+
+    let upload = take_complete(params.id).await?;
+    let guard = crate::write_lock::lock(&upload.path).await;
+
+    crate::blocking::run(upload.path.clone(), move || {
+        let _guard = guard;
+        let path_str = upload.path.to_string_lossy().into_owned();
+        let result = (|| {
+            let mut file = std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(&upload.temp)
+                .map_err(|e| map_io_error(e, &path_str))?;
+            // A resumed partial file may be longer than the declared size.
+            file.set_len(upload.total_size)
+                .map_err(|e| map_io_error(e, &path_str))?;
+
+            if let Some(expected) = &params.md5 {
+                let mut hasher = Md5::new();
+                let mut buf = vec![0u8; 64 * 1024];
+                loop {
+                    let n = file
+                        .read(&mut buf)
+                        .map_err(|e| map_io_error(e, &path_str))?;
+                    if n == 0 {
+                        break;
+                    }
+                    hasher.update(&buf[..n]);
+                }
+                let digest = format!("{:x}", hasher.finalize());
+                if !digest.eq_ignore_ascii_case(expected) {
+                    let mut error = RpcError::conflict(format!(
+                        "Uploaded content does not match md5 {}",
+                        expected
+                    ));
+                    error.data = Some(msgpack_map! { "reason" => "checksum" });
+                    return Err(error);
+                }
+            }
+
+            let mode = upload.mode.or_else(|| {
+                std::fs::metadata(&upload.path)
+                    .ok()
+                    .map(|m| m.permissions().mode() & 0o7777)
+            });
+            if let Some(mode) = mode {
+                std::fs::set_permissions(&upload.temp, std::fs::Permissions::from_mode(mode))
+                    .map_err(|e| map_io_error(e, &path_str))?;
+            }
+            std::fs::rename(&upload.temp, &upload.path).map_err(|e| map_io_error(e, &path_str))
+        })();
+
+        if result.is_err() {
+            let _ = std::fs::remove_file(&upload.temp);
+        }
+        forget(&upload.temp);
+        result?;
+        stat_cache::invalidate_blocking(&upload.path);
+        Ok(msgpack_map! {
+            "size" => upload.total_size
+        })
+    })
+    .await?
+}
+
+/// How long `system.install_binary` waits for `--version` of a new binary
+const VERSION_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Install an uploaded server binary: `system.install_binary {id, sha256}`.
+///
+/// Finishes upload `id` like `file.write_commit`, but the contents must
+/// match `sha256` (hex), the mode becomes 0755 and the binary has to run
+/// with `--version` before it is renamed over the target.  A corrupt or
+/// foreign binary therefore never replaces a working one: on any failure
+/// the partial file is removed and the target left as it was.
+///
+/// Returns `{path, size, sha256, version}`; `version` is nil for a server
+/// too old to print one.
+pub async fn install_binary(params: Value) -> HandlerResult {
+    #[derive(Deserialize)]
+    struct Params {
+        id: u32,
+        sha256: String,
+    }
+
+    let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
+
+    let upload = take_complete(params.id).await?;
+    let result = async {
+        let (temp, total_size) = (upload.temp.clone(), upload.total_size);
+        let sha256 = crate::blocking::run(temp.clone(), move || {
+            let file = std::fs::OpenOptions::new()
+                .write(true)
+                .open(&temp)
+                .map_err(|e| map_io_error(e, &temp))?;
+            // A resumed partial file may be longer than the declared size.
+            file.set_len(total_size)
+                .and_then(|()| file.sync_all())
+                .map_err(|e| map_io_error(e, &temp))?;
+            drop(file);
+            let actual = super::io::sha256_file(&temp).map_err(|e| map_io_error(e, &temp))?;
+            if !actual.eq_ignore_ascii_case(params.sha256.trim()) {
+                let mut error = RpcError::conflict(format!(
+                    "Uploaded binary does not match sha256 {}",
+                    params.sha256
+                ));
+                error.data = Some(msgpack_map! {
+                    "reason" => "checksum",
+                    "expected" => params.sha256,
+                    "actual" => actual
+                });
+                return Err(error);
+            }
+            std::fs::set_permissions(&temp, std::fs::Permissions::from_mode(0o755))
+                .map_err(|e| map_io_error(e, &temp))?;
+            Ok(actual)
+        })
+        .await??;
+
+        let version = binary_version(&upload.temp).await?;
+        let _guard = crate::write_lock::lock(&upload.path).await;
+        tokio::fs::rename(&upload.temp, &upload.path)
+            .await
+            .map_err(|e| map_io_error(e, &upload.path))?;
+        Ok::<_, RpcError>((sha256, version))
+    }
+    .await;
+
+    if result.is_err() {
+        let _ = tokio::fs::remove_file(&upload.temp).await;
+    }
+    forget(&upload.temp);
+    let (sha256, version) = result?;
+    stat_cache::invalidate(&upload.path).await;
+    Ok(msgpack_map! {
+        "path" => upload.path.to_string_lossy().into_owned(),
+        "size" => upload.total_size,
+        "sha256" => sha256,
+        "version" => version.into_value()
+    })
+}
+
+/// The version `binary --version` prints, without the program name, or
+/// none if it prints nothing.  Fails if the binary does not run.
+async fn binary_version(binary: &Path) -> Result<Option<String>, RpcError> {
+    let mut busy_retries = 5;
+    let output = loop {
+        let run = tokio::process::Command::new(binary)
+            .arg("--version")
+            .stdin(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .kill_on_drop(true)
+            .output();
+        match tokio::time::timeout(VERSION_TIMEOUT, run).await {
+            // A process forked meanwhile may still hold the file open for
+            // writing until it execs.
+            Ok(Err(e)) if e.raw_os_error() == Some(libc::ETXTBSY) && busy_retries > 0 => {
+                busy_retries -= 1;
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+            Ok(Err(e)) => {
+                return Err(RpcError::process_error(format!(
+                    "Cannot run {}: {}",
+                    binary.display(),
+                    e
+                )));
+            }
+            Ok(Ok(output)) => break output,
+            Err(_) => {
+                return Err(RpcError::process_error(format!(
+                    "{} --version timed out",
+                    binary.display()
+                )));
+            }
+        }
+    };
+    if !output.status.success() {
+        return Err(RpcError::process_error(format!(
+            "{} --version failed: {}",
+            binary.display(),
+            output.status
+        )));
+    }
+    let text = String::from_utf8_lossy(&output.stdout);
+    let text = text.trim();
+    let version = text.strip_prefix("tramp-rpc-server").unwrap_or(text).trim();
+    Ok(Some(version.to_string()).filter(|v| !v.is_empty()))
+}
+
+/// Abort an upload and delete its partial file.
+pub async fn write_abort(params: Value) -> HandlerResult {
+    #[derive(Deserialize)]
+    struct Params {
+        id: u32,
+    }
+
+    let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
+
+    let upload = get_upload_map()
+        .lock()
+        .await
+        .remove(&params.id)
+        .ok_or_else(|| upload_not_found(params.id))?;
+    forget(&upload.temp);
+    match tokio::fs::remove_file(&upload.temp).await {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(map_io_error(e, &upload.temp)),
+    }
+    Ok(Value::Boolean(true))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::map_get;
+
+    fn get_u64(value: &Value, key: &str) -> u64 {
+        map_get(value, key).and_then(Value::as_u64).expect(key)
+    }
+
+    async fn begin(path: &Path, total_size: u64) -> Value {
+        write_begin(msgpack_map! {
+            "path" => path.to_string_lossy().into_owned(),
+            "total_size" => total_size
+        })
+        .await
+        .expect("write_begin")
+    }
+
+    async fn chunk(id: u64, offset: u64, data: &[u8]) -> HandlerResult {
+        write_chunk(msgpack_map! {
+            "id" => id,
+            "offset" => offset,
+            "data" => Value::Binary(data.to_vec())
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn install_binary_replaces_the_target_only_when_it_checks_out() {
+        let tmp = tempfile::tempdir().unwrap();
+        let target = tmp.path().join("tramp-rpc-server");
+        std::fs::write(&target, b"old").unwrap();
+        let upload = |script: &'static [u8]| {
+            let target = target.clone();
+            async move {
+                let begun = begin(&target, script.len() as u64).await;
+                let id = get_u64(&begun, "id");
+                chunk(id, 0, script).await.unwrap();
+                id
+            }
+        };
+        let install =
+            |id: u64, sha256: &str| install_binary(msgpack_map! { "id" => id, "sha256" => sha256 });
+        let sha256 = |data: &[u8]| {
+            use sha2::{Digest, Sha256};
+            Sha256::digest(data)
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect::<String>()
+        };
+
+        // A wrong digest or a binary that does not run leaves the target be
+        let good: &[u8] = b"#!/bin/sh\necho 'tramp-rpc-server 9.9.9'\n";
+        let id = upload(good).await;
+        let err = install(id, &"0".repeat(64))
+            .await
+            .expect_err("wrong digest");
+        assert_eq!(err.code, RpcError::CONFLICT);
+        let broken: &[u8] = b"#!/bin/sh\nexit 3\n";
+        let id = upload(broken).await;
+        let err = install(id, &sha256(broken))
+            .await
+            .expect_err("fails to run");
+        assert_eq!(err.code, RpcError::PROCESS_ERROR);
+        assert_eq!(std::fs::read(&target).unwrap(), b"old");
+        assert_eq!(std::fs::read_dir(tmp.path()).unwrap().count(), 1);
+
+        let id = upload(good).await;
+        let installed = install(id, &sha256(good)).await.unwrap();
+        assert_eq!(
+            map_get(&installed, "version").and_then(Value::as_str),
+            Some("9.9.9")
+        );
+        assert_eq!(std::fs::read(&target).unwrap(), good);
+        let mode = std::fs::metadata(&target).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o755);
+    }
+
+    #[test]
+    fn upload_tracks_contiguous_prefix() {
+        let mut upload = Upload {
+            path: PathBuf::new(),
+            temp: PathBuf::new(),
+            total_size: 10,
+            mode: None,
+            ranges: Vec::new(),
+            last_activity: Instant::now(),
+        };
+        upload.record(4, 6);
+        assert_eq!(upload.received(), 0);
+        upload.record(0, 4);
+        upload.record(0, 4);
+        assert_eq!(upload.received(), 6);
+        upload.record(5, 10);
+        assert_eq!(upload.ranges, vec![(0, 10)]);
+    }
+
+    #[tokio::test]
+    async fn chunked_upload_resumes_retries_and_commits() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("upload.bin");
+        std::fs::write(&path, b"old").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600)).unwrap();
+        let content = b"hello chunked world";
+
+        let first = begin(&path, content.len() as u64).await;
+        let id = get_u64(&first, "id");
+        assert_eq!(get_u64(&first, "received"), 0);
+
+        chunk(id, 0, &content[..6]).await.unwrap();
+        // Retried chunk is idempotent.
+        let retried = chunk(id, 0, &content[..6]).await.unwrap();
+        assert_eq!(get_u64(&retried, "received"), 6);
+
+        // Reconnecting with the same path and size resumes the same upload.
+        let again = begin(&path, content.len() as u64).await;
+        assert_eq!(get_u64(&again, "id"), id);
+        assert_eq!(get_u64(&again, "received"), 6);
+
+        assert!(chunk(id, 15, b"too long!").await.is_err());
+        chunk(id, 6, &content[6..]).await.unwrap();
+
+        let commit = write_commit(msgpack_map! {
+            "id" => id,
+            "md5" => format!("{:x}", Md5::digest(content))
+        })
+        .await
+        .expect("commit");
+        assert_eq!(get_u64(&commit, "size"), content.len() as u64);
+        assert_eq!(std::fs::read(&path).unwrap(), content);
+        assert_eq!(
+            std::fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+        assert!(!partial_path(&path, content.len() as u64).exists());
+    }
+
+    #[tokio::test]
+    async fn chunked_upload_adopts_partial_file_from_earlier_process() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("resume.bin");
+        std::fs::write(partial_path(&path, 8), b"1234").unwrap();
+
+        let begun = begin(&path, 8).await;
+        let id = get_u64(&begun, "id");
+        assert_eq!(get_u64(&begun, "received"), 4);
+
+        let incomplete = write_commit(msgpack_map! { "id" => id }).await;
+        assert_eq!(
+            incomplete.expect_err("incomplete").code,
+            RpcError::INVALID_REQUEST
+        );
+
+        write_abort(msgpack_map! { "id" => id }).await.unwrap();
+        assert!(!partial_path(&path, 8).exists());
+        assert!(chunk(id, 4, b"5678").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn chunked_upload_rejects_bad_checksum() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("bad.bin");
+        let begun = begin(&path, 3).await;
+        let id = get_u64(&begun, "id");
+        chunk(id, 0, b"abc").await.unwrap();
+
+        let error = write_commit(msgpack_map! { "id" => id, "md5" => "00" })
+            .await
+            .expect_err("bad checksum");
+        assert_eq!(error.code, RpcError::CONFLICT);
+        assert!(!path.exists());
+        assert!(!partial_path(&path, 3).exists());
+    }
+
+    #[test]
+    fn gc_removes_stale_recorded_partials_only() {
+        let tmp = tempfile::tempdir().unwrap();
+        let records = tmp.path().join("records");
+        std::fs::create_dir(&records).unwrap();
+        let record = |temp: &Path| {
+            std::fs::write(records.join(record_name(temp)), temp.as_os_str().as_bytes()).unwrap();
+        };
+        let age = |path: &Path, secs| {
+            std::fs::File::options()
+                .write(true)
+                .open(path)
+                .unwrap()
+                .set_modified(SystemTime::now() - Duration::from_secs(secs))
+                .unwrap();
+        };
+
+        let stale = partial_path(&tmp.path().join("stale"), 4);
+        let fresh = partial_path(&tmp.path().join("fresh"), 4);
+        let active = partial_path(&tmp.path().join("active"), 4);
+        let gone = partial_path(&tmp.path().join("gone"), 4);
+        let foreign = tmp.path().join("not-a-partial");
+        for path in [&stale, &fresh, &active, &foreign] {
+            std::fs::write(path, b"data").unwrap();
+            record(path);
+        }
+        record(&gone);
+        for path in [&stale, &active, &foreign] {
+            age(path, 3600);
+        }
+
+        let active_set = HashSet::from([active.clone()]);
+        let freed = gc_records(&records, &active_set, Duration::from_secs(60));
+        assert_eq!(
+            freed,
+            Freed {
+                removed: 1,
+                bytes: 4
+            }
+        );
+        assert!(!stale.exists());
+        assert!(fresh.exists() && active.exists() && foreign.exists());
+        // Records of removed, missing and foreign files are dropped
+        assert!(!records.join(record_name(&stale)).exists());
+        assert!(!records.join(record_name(&gone)).exists());
+        assert!(!records.join(record_name(&foreign)).exists());
+        assert!(records.join(record_name(&fresh)).exists());
+    }
+
+    #[test]
+    fn idle_uploads_are_taken_out_and_their_files_removed() {
+        let long_idle = UPLOAD_IDLE_TIMEOUT + Duration::from_secs(1);
+        if Instant::now().checked_sub(long_idle).is_none() {
+            return; // Booted too recently for an upload to have idled
+        }
+        let tmp = tempfile::tempdir().unwrap();
+        let upload = |name: &str, idle: Duration| {
+            let path = tmp.path().join(name);
+            let temp = partial_path(&path, 4);
+            std::fs::write(&temp, b"data").unwrap();
+            Upload {
+                path,
+                temp,
+                total_size: 4,
+                mode: None,
+                ranges: Vec::new(),
+                last_activity: Instant::now() - idle,
+            }
+        };
+        let mut uploads = HashMap::from([
+            (
+                1,
+                upload("idle", UPLOAD_IDLE_TIMEOUT + Duration::from_secs(1)),
+            ),
+            (2, upload("busy", Duration::ZERO)),
+        ]);
+
+        let idle = take_idle(&mut uploads);
+        assert_eq!(uploads.keys().collect::<Vec<_>>(), vec![&2]);
+        assert_eq!(idle.len(), 1);
+        let temp = idle[0].temp.clone();
+        discard(idle);
+        assert!(!temp.exists());
+        assert!(uploads[&2].temp.exists());
+    }
+}
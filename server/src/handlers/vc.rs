@@ -0,0 +1,812 @@
+//! Version control helpers for TRAMP-RPC
+//!
+//! This module provides:
+//! - `git.log`: Paginated, structured `git log` output for Magit log buffers
+//! - `vc.status`: Backend detection and file state for `vc-mode`
+
+use crate::jail;
+use crate::msgpack_map;
+use crate::protocol::{IntoValue, RpcError, from_value};
+use rmpv::Value;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::process::{Output, Stdio};
+use tokio::process::Command;
+
+use super::HandlerResult;
+
+/// Upper bound on entries returned by a single `git.log` page.
+const MAX_LOG_LIMIT: usize = 10_000;
+
+/// Record separator emitted at the start of every formatted commit.  With
+/// `--graph`, anything before it on the same line is the graph prefix.
+const RECORD_SEP: u8 = 0x1e;
+
+/// Run a VCS program in `directory` and return its raw output.
+async fn run_vcs(program: &str, directory: &str, args: &[String]) -> Result<Output, RpcError> {
+    let directory = super::expand_tilde(directory);
+    jail::check(Path::new(&directory))?;
+    Command::new(program)
+        .args(args)
+        .current_dir(directory)
+        .stdin(Stdio::null())
+        .output()
+        .await
+        .map_err(|e| RpcError::process_error(format!("Failed to run {}: {}", program, e)))
+}
+
+/// Run git in `directory` and return its raw output.
+///
+/// Color, pagers and signature display are disabled so the output only
+/// depends on the repository state, not on the user's configuration.
+async fn run_git(directory: &str, args: &[String]) -> Result<Output, RpcError> {
+    let mut full_args = [
+        "--no-pager",
+        "-c",
+        "color.ui=false",
+        "-c",
+        "log.showSignature=false",
+    ]
+    .map(String::from)
+    .to_vec();
+    full_args.extend_from_slice(args);
+    run_vcs("git", directory, &full_args).await
+}
+
+fn vcs_failure(program: &str, output: &Output) -> RpcError {
+    RpcError::process_error(format!(
+        "{} exited with {}: {}",
+        program,
+        crate::protocol::exit_code_from_status(output.status),
+        String::from_utf8_lossy(&output.stderr).trim()
+    ))
+}
+
+fn git_failure(output: &Output) -> RpcError {
+    vcs_failure("git", output)
+}
+
+/// Resolve HEAD to a full commit hash, or None for an unborn branch.
+async fn resolve_head(directory: &str) -> Result<Option<String>, RpcError> {
+    let args = ["rev-parse", "--verify", "-q", "HEAD"].map(String::from);
+    let output = run_git(directory, &args).await?;
+    if !output.status.success() {
+        return Ok(None);
+    }
+    let head = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    Ok((!head.is_empty()).then_some(head))
+}
+
+/// Return a page of structured `git log` entries.
+///
+/// Without an explicit `range` the log is pinned to the HEAD hash resolved at
+/// the start of the call, so `offset`/`limit` pages stay consistent for the
+/// same HEAD.  The resolved hash is returned as `head`; clients compare it
+/// between pages to detect that the log moved underneath them.  A `range`
+/// starting with `-` is refused, as git would parse it as an option.
+pub async fn git_log(params: Value) -> HandlerResult {
+    #[derive(Deserialize)]
+    struct Params {
+        /// Repository working directory
+        directory: String,
+        /// Revision range (e.g. "main..feature"); defaults to HEAD
+        #[serde(default)]
+        range: Option<String>,
+        /// Restrict the log to these paths
+        #[serde(default)]
+        paths: Vec<String>,
+        /// Maximum number of entries to return
+        #[serde(default = "default_limit")]
+        limit: usize,
+        /// Number of entries to skip (`--skip`)
+        #[serde(default)]
+        offset: usize,
+        /// Include `--graph` prefixes
+        #[serde(default)]
+        graph: bool,
+        /// Include ref decorations
+        #[serde(default)]
+        decorate: bool,
+        /// Filter by commit message (`--grep`)
+        #[serde(default)]
+        search: Option<String>,
+        /// Filter by author (`--author`)
+        #[serde(default)]
+        author: Option<String>,
+    }
+
+    fn default_limit() -> usize {
+        100
+    }
+
+    let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
+
+    if params.limit == 0 || params.limit > MAX_LOG_LIMIT {
+        return Err(RpcError::invalid_params(format!(
+            "limit must be between 1 and {}",
+            MAX_LOG_LIMIT
+        )));
+    }
+
+    // git would take it for an option such as --output=FILE
+    if params
+        .range
+        .as_ref()
+        .is_some_and(|range| range.starts_with('-'))
+    {
+        return Err(RpcError::invalid_params("range must not start with '-'"));
+    }
+
+    let head = resolve_head(&params.directory).await?;
+    let revision = match (&params.range, &head) {
+        (Some(range), _) => range.clone(),
+        (None, Some(head)) => head.clone(),
+        // Unborn branch: nothing to log yet.
+        (None, None) => {
+            return Ok(msgpack_map! {
+                "head" => Value::Nil,
+                "entries" => Value::Array(vec![]),
+                "has_more" => false
+            });
+        }
+    };
+
+    let refs_field = if params.decorate { "%D" } else { "" };
+    let mut args = vec![
+        "log".to_string(),
+        format!("--format=%x1e%H%x00%h%x00%P%x00%an%x00%ae%x00%at%x00%s%x00{refs_field}"),
+        // Ask for one extra entry to learn whether another page exists.
+        format!("--max-count={}", params.limit + 1),
+        format!("--skip={}", params.offset),
+    ];
+    if params.graph {
+        args.push("--graph".to_string());
+    }
+    if let Some(search) = &params.search {
+        args.push(format!("--grep={}", search));
+    }
+    if let Some(author) = &params.author {
+        args.push(format!("--author={}", author));
+    }
+    args.push(revision);
+    args.push("--".to_string());
+    args.extend(params.paths);
+
+    let output = run_git(&params.directory, &args).await?;
+    if !output.status.success() {
+        return Err(git_failure(&output));
+    }
+
+    let mut entries = parse_log(&output.stdout, params.graph);
+    let has_more = entries.len() > params.limit;
+    entries.truncate(params.limit);
+
+    Ok(msgpack_map! {
+        "head" => head.into_value(),
+        "entries" => Value::Array(entries.into_iter().map(|e| e.to_value()).collect()),
+        "has_more" => has_more
+    })
+}
+
+#[derive(Debug, Default)]
+struct LogEntry {
+    hash: String,
+    abbrev: String,
+    parents: Vec<String>,
+    author_name: String,
+    author_email: String,
+    author_date: i64,
+    subject: String,
+    refs: Vec<String>,
+    /// Graph prefix of the commit line followed by any connector-only lines
+    graph: Vec<String>,
+}
+
+impl LogEntry {
+    fn to_value(&self) -> Value {
+        let mut value = msgpack_map! {
+            "hash" => self.hash.clone(),
+            "abbrev" => self.abbrev.clone(),
+            "parents" => self.parents.clone().into_value(),
+            "author_name" => self.author_name.clone(),
+            "author_email" => self.author_email.clone(),
+            "author_date" => self.author_date,
+            "subject" => self.subject.clone(),
+            "refs" => self.refs.clone().into_value()
+        };
+        if !self.graph.is_empty()
+            && let Value::Map(ref mut pairs) = value
+        {
+            pairs.push(("graph".into(), self.graph.clone().into_value()));
+        }
+        value
+    }
+}
+
+/// Parse `git log` output produced with the format used by [`git_log`].
+fn parse_log(stdout: &[u8], graph: bool) -> Vec<LogEntry> {
+    let mut entries: Vec<LogEntry> = Vec::new();
+
+    for line in stdout.split(|&b| b == b'\n') {
+        let Some(sep) = line.iter().position(|&b| b == RECORD_SEP) else {
+            // Graph connector line between commits.
+            if graph
+                && !line.is_empty()
+                && let Some(last) = entries.last_mut()
+            {
+                last.graph.push(String::from_utf8_lossy(line).into_owned());
+            }
+            continue;
+        };
+
+        let mut fields = line[sep + 1..]
+            .split(|&b| b == 0)
+            .map(|f| String::from_utf8_lossy(f).into_owned());
+        let mut next = || fields.next().unwrap_or_default();
+
+        let mut entry = LogEntry {
+            hash: next(),
+            abbrev: next(),
+            parents: split_words(&next()),
+            author_name: next(),
+            author_email: next(),
+            author_date: next().parse().unwrap_or(0),
+            subject: next(),
+            refs: next()
+                .split(", ")
+                .filter(|r| !r.is_empty())
+                .map(str::to_string)
+                .collect(),
+            graph: Vec::new(),
+        };
+        if graph {
+            entry
+                .graph
+                .push(String::from_utf8_lossy(&line[..sep]).into_owned());
+        }
+        entries.push(entry);
+    }
+
+    entries
+}
+
+fn split_words(s: &str) -> Vec<String> {
+    s.split_whitespace().map(str::to_string).collect()
+}
+
+/// VCS metadata entries recognised by `vc.status`, with their backend name.
+///
+/// When a root contains more than one (e.g. a colocated jj/git repository),
+/// the earlier entry wins.
+const VC_MARKERS: &[(&str, &str)] = &[
+    (".jj", "jj"),
+    (".git", "git"),
+    (".hg", "hg"),
+    (".svn", "svn"),
+    ("_darcs", "darcs"),
+];
+
+/// File states reported by `vc.status`, named after `vc-state` symbols.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum VcState {
+    UpToDate,
+    Edited,
+    Added,
+    Removed,
+    Conflict,
+    Missing,
+    Ignored,
+    Unregistered,
+}
+
+impl VcState {
+    fn as_str(self) -> &'static str {
+        match self {
+            VcState::UpToDate => "up-to-date",
+            VcState::Edited => "edited",
+            VcState::Added => "added",
+            VcState::Removed => "removed",
+            VcState::Conflict => "conflict",
+            VcState::Missing => "missing",
+            VcState::Ignored => "ignored",
+            VcState::Unregistered => "unregistered",
+        }
+    }
+
+    /// State for a file the backend did not mention in its status output.
+    fn unlisted(exists: bool) -> Self {
+        if exists {
+            VcState::UpToDate
+        } else {
+            VcState::Unregistered
+        }
+    }
+}
+
+/// Innermost VCS root above a file.
+struct VcRoot {
+    backend: &'static str,
+    root: PathBuf,
+    marker: &'static str,
+    /// File path relative to `root` ("." for the root itself)
+    relative: String,
+    exists: bool,
+}
+
+fn locate_vc_root(path: &Path) -> Result<Option<VcRoot>, RpcError> {
+    let Some(start_dir) =
+        super::commands::find_existing_start(path).and_then(super::commands::as_search_dir)
+    else {
+        return Ok(None);
+    };
+
+    let names: Vec<String> = VC_MARKERS.iter().map(|(m, _)| m.to_string()).collect();
+    let Some((root, found)) = super::commands::find_dominating_dir(&start_dir, &names)? else {
+        return Ok(None);
+    };
+    let Some(&(marker, backend)) = VC_MARKERS
+        .iter()
+        .find(|(m, _)| found.iter().any(|f| f == m))
+    else {
+        return Ok(None);
+    };
+
+    let relative = match path.strip_prefix(&root) {
+        Ok(rel) if rel.as_os_str().is_empty() => ".".to_string(),
+        Ok(rel) => rel.to_string_lossy().into_owned(),
+        Err(_) => path.to_string_lossy().into_owned(),
+    };
+
+    Ok(Some(VcRoot {
+        backend,
+        marker,
+        relative,
+        exists: path.symlink_metadata().is_ok(),
+        root,
+    }))
+}
+
+/// Report the VC backend, root and state of a single file.
+///
+/// The innermost ancestor containing a VCS metadata directory decides the
+/// backend, and only that backend's status command is run.  `generation` is
+/// the mtime of the metadata directory; clients can key a per-root cache on
+/// `root` + `generation` and reuse it for other files under the same root.
+/// Returns nil when the file is not under version control.
+pub async fn vc_status(params: Value) -> HandlerResult {
+    #[derive(Deserialize)]
+    struct Params {
+        /// File to report on
+        path: String,
+    }
+
+    let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
+
+    let path = PathBuf::from(super::expand_tilde(&params.path));
+    jail::check(&path)?;
+    let located = crate::blocking::run(path.clone(), move || locate_vc_root(&path)).await??;
+    let Some(vc_root) = located else {
+        return Ok(Value::Nil);
+    };
+
+    let root = vc_root.root.to_string_lossy().into_owned();
+    let generation = super::commands::mtime_seconds(&vc_root.root.join(vc_root.marker));
+    let (state, revision) = match vc_root.backend {
+        "git" => git_file_status(&root, &vc_root.relative, vc_root.exists).await?,
+        "hg" => hg_file_status(&root, &vc_root.relative, vc_root.exists).await?,
+        "svn" => svn_file_status(&root, &vc_root.relative, vc_root.exists).await?,
+        "jj" => jj_file_status(&root, &vc_root.relative, vc_root.exists).await?,
+        _ => darcs_file_status(&root, &vc_root.relative, vc_root.exists).await?,
+    };
+
+    Ok(msgpack_map! {
+        "backend" => vc_root.backend,
+        "root" => root,
+        "generation" => generation.into_value(),
+        "state" => state.as_str(),
+        "revision" => revision.into_value()
+    })
+}
+
+type FileStatus = (VcState, Option<String>);
+
+async fn git_file_status(root: &str, relative: &str, exists: bool) -> Result<FileStatus, RpcError> {
+    let args = [
+        "status",
+        "--porcelain=v2",
+        "--branch",
+        "--ignored=matching",
+        "-z",
+        "--",
+        relative,
+    ]
+    .map(String::from);
+    let output = run_git(root, &args).await?;
+    if !output.status.success() {
+        return Err(git_failure(&output));
+    }
+    Ok(parse_git_status(&output.stdout, exists))
+}
+
+fn parse_git_status(stdout: &[u8], exists: bool) -> FileStatus {
+    let mut revision = None;
+    for record in stdout.split(|&b| b == 0) {
+        let record = String::from_utf8_lossy(record);
+        if let Some(oid) = record.strip_prefix("# branch.oid ") {
+            if oid != "(initial)" {
+                revision = Some(oid.to_string());
+            }
+            continue;
+        }
+        // Headers come first, so the first entry is the file itself.
+        let state = match record.as_bytes() {
+            [b'?', b' ', ..] => VcState::Unregistered,
+            [b'!', b' ', ..] => VcState::Ignored,
+            [b'u', b' ', ..] => VcState::Conflict,
+            [b'1' | b'2', b' ', x, y, ..] => match (x, y) {
+                (b'D', _) => VcState::Removed,
+                (_, b'D') => VcState::Missing,
+                (b'A' | b'R' | b'C', _) => VcState::Added,
+                _ => VcState::Edited,
+            },
+            _ => continue,
+        };
+        return (state, revision);
+    }
+    (VcState::unlisted(exists), revision)
+}
+
+async fn hg_file_status(root: &str, relative: &str, exists: bool) -> Result<FileStatus, RpcError> {
+    let args = ["status", "-A", "--", relative].map(String::from);
+    let output = run_vcs("hg", root, &args).await?;
+    if !output.status.success() {
+        return Err(vcs_failure("hg", &output));
+    }
+    let state = match output.stdout.first() {
+        Some(b'C') => VcState::UpToDate,
+        Some(b'M') => VcState::Edited,
+        Some(b'A') => VcState::Added,
+        Some(b'R') => VcState::Removed,
+        Some(b'!') => VcState::Missing,
+        Some(b'I') => VcState::Ignored,
+        Some(b'?') => VcState::Unregistered,
+        _ => VcState::unlisted(exists),
+    };
+
+    let args = ["log", "-r", ".", "-T", "{node}"].map(String::from);
+    let output = run_vcs("hg", root, &args).await?;
+    let node = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    let revision = (output.status.success() && !node.is_empty() && node.bytes().any(|b| b != b'0'))
+        .then_some(node);
+    Ok((state, revision))
+}
+
+async fn svn_file_status(root: &str, relative: &str, exists: bool) -> Result<FileStatus, RpcError> {
+    let args = ["status", "-v", "--depth=empty", "--", relative].map(String::from);
+    let output = run_vcs("svn", root, &args).await?;
+    if !output.status.success() {
+        return Err(vcs_failure("svn", &output));
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let Some(line) = stdout.lines().find(|l| !l.is_empty()) else {
+        return Ok((VcState::unlisted(exists), None));
+    };
+    let bytes = line.as_bytes();
+    let state = match bytes[0] {
+        b'A' => VcState::Added,
+        b'D' => VcState::Removed,
+        b'C' => VcState::Conflict,
+        b'!' => VcState::Missing,
+        b'I' => VcState::Ignored,
+        b'?' => VcState::Unregistered,
+        b'M' | b'R' | b'~' => VcState::Edited,
+        // Property changes and tree conflicts live in later columns.
+        _ if bytes.get(1) == Some(&b'M') => VcState::Edited,
+        _ if bytes.get(6) == Some(&b'C') => VcState::Conflict,
+        _ => VcState::UpToDate,
+    };
+    let revision = match state {
+        VcState::Unregistered | VcState::Ignored => None,
+        _ => line
+            .get(8..)
+            .and_then(|rest| rest.split_whitespace().next())
+            .filter(|rev| rev.bytes().all(|b| b.is_ascii_digit()))
+            .map(str::to_string),
+    };
+    Ok((state, revision))
+}
+
+async fn jj_file_status(root: &str, relative: &str, exists: bool) -> Result<FileStatus, RpcError> {
+    let fileset = format!(
+        "root-file:\"{}\"",
+        relative.replace('\\', "\\\\").replace('"', "\\\"")
+    );
+    let args = ["--no-pager", "--color=never", "diff", "--summary", "--"]
+        .map(String::from)
+        .into_iter()
+        .chain([fileset])
+        .collect::<Vec<_>>();
+    let output = run_vcs("jj", root, &args).await?;
+    if !output.status.success() {
+        return Err(vcs_failure("jj", &output));
+    }
+    // jj snapshots and tracks new files automatically, so a file missing from
+    // the summary is either unchanged or absent.
+    let state = match output.stdout.first() {
+        Some(b'M') | Some(b'R') | Some(b'C') => VcState::Edited,
+        Some(b'A') => VcState::Added,
+        Some(b'D') => VcState::Removed,
+        _ => VcState::unlisted(exists),
+    };
+
+    // The working copy was just snapshotted by `jj diff`.
+    let args = [
+        "--no-pager",
+        "--ignore-working-copy",
+        "log",
+        "-r",
+        "@",
+        "--no-graph",
+        "-T",
+        "change_id",
+    ]
+    .map(String::from);
+    let output = run_vcs("jj", root, &args).await?;
+    let change_id = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    let revision = (output.status.success() && !change_id.is_empty()).then_some(change_id);
+    Ok((state, revision))
+}
+
+async fn darcs_file_status(
+    root: &str,
+    relative: &str,
+    exists: bool,
+) -> Result<FileStatus, RpcError> {
+    let args = ["whatsnew", "--summary", "--look-for-adds", "--", relative].map(String::from);
+    let output = run_vcs("darcs", root, &args).await?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    // darcs exits non-zero when there is nothing to report.
+    if !output.status.success() && !stdout.starts_with("No changes") {
+        return Err(vcs_failure("darcs", &output));
+    }
+    let state = match stdout.as_bytes().first() {
+        Some(b'M') => VcState::Edited,
+        Some(b'A') => VcState::Added,
+        Some(b'R') => VcState::Removed,
+        Some(b'a') => VcState::Unregistered,
+        _ => VcState::unlisted(exists),
+    };
+    // darcs has no single identifier for the working state.
+    Ok((state, None))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{git, map_get};
+    use std::path::Path;
+
+    fn init_repo(commits: usize) -> tempfile::TempDir {
+        let tmp = tempfile::tempdir().expect("create tempdir");
+        git(tmp.path(), &["init", "-q"]);
+        for i in 0..commits {
+            std::fs::write(tmp.path().join("file.txt"), format!("{i}\n")).unwrap();
+            git(tmp.path(), &["add", "file.txt"]);
+            git(tmp.path(), &["commit", "-q", "-m", &format!("commit {i}")]);
+        }
+        tmp
+    }
+
+    fn subjects(result: &Value) -> Vec<String> {
+        map_get(result, "entries")
+            .and_then(Value::as_array)
+            .expect("entries")
+            .iter()
+            .map(|e| {
+                map_get(e, "subject")
+                    .and_then(Value::as_str)
+                    .unwrap()
+                    .to_string()
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn git_log_paginates_with_stable_head() {
+        let repo = init_repo(5);
+        let dir = repo.path().to_string_lossy().into_owned();
+
+        let first = git_log(msgpack_map! {
+            "directory" => dir.clone(),
+            "limit" => 2,
+            "decorate" => true,
+        })
+        .await
+        .expect("first page");
+        assert_eq!(subjects(&first), vec!["commit 4", "commit 3"]);
+        assert_eq!(
+            map_get(&first, "has_more").and_then(Value::as_bool),
+            Some(true)
+        );
+
+        let entry = &map_get(&first, "entries")
+            .and_then(Value::as_array)
+            .unwrap()[0];
+        assert_eq!(
+            map_get(entry, "author_email").and_then(Value::as_str),
+            Some("author@example.com")
+        );
+        assert_eq!(
+            map_get(entry, "parents")
+                .and_then(Value::as_array)
+                .map(Vec::len),
+            Some(1)
+        );
+        assert!(
+            map_get(entry, "refs")
+                .and_then(Value::as_array)
+                .is_some_and(|refs| refs
+                    .iter()
+                    .any(|r| r.as_str().is_some_and(|r| r.contains("HEAD"))))
+        );
+
+        let last = git_log(msgpack_map! {
+            "directory" => dir,
+            "limit" => 2,
+            "offset" => 4,
+        })
+        .await
+        .expect("last page");
+        assert_eq!(subjects(&last), vec!["commit 0"]);
+        assert_eq!(
+            map_get(&last, "has_more").and_then(Value::as_bool),
+            Some(false)
+        );
+        assert_eq!(map_get(&first, "head"), map_get(&last, "head"));
+    }
+
+    #[tokio::test]
+    async fn git_log_filters_by_search() {
+        let repo = init_repo(3);
+        let result = git_log(msgpack_map! {
+            "directory" => repo.path().to_string_lossy().into_owned(),
+            "search" => "commit 1",
+            "graph" => true,
+        })
+        .await
+        .expect("search");
+        assert_eq!(subjects(&result), vec!["commit 1"]);
+    }
+
+    #[tokio::test]
+    async fn git_log_refuses_ranges_that_look_like_options() {
+        let repo = init_repo(1);
+        let output = repo.path().join("written");
+        let err = git_log(msgpack_map! {
+            "directory" => repo.path().to_string_lossy().into_owned(),
+            "range" => format!("--output={}", output.display()),
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(err.code, RpcError::INVALID_PARAMS);
+        assert!(!output.exists());
+    }
+
+    #[tokio::test]
+    async fn git_log_unborn_branch_returns_no_entries() {
+        let repo = init_repo(0);
+        let result = git_log(msgpack_map! {
+            "directory" => repo.path().to_string_lossy().into_owned(),
+        })
+        .await
+        .expect("unborn branch");
+        assert_eq!(map_get(&result, "head"), Some(&Value::Nil));
+        assert!(subjects(&result).is_empty());
+    }
+
+    async fn state_of(path: &Path) -> Value {
+        vc_status(msgpack_map! {
+            "path" => path.to_string_lossy().into_owned(),
+        })
+        .await
+        .expect("vc.status")
+    }
+
+    #[tokio::test]
+    async fn vc_status_reports_git_file_states() {
+        let repo = init_repo(1);
+        let root = repo.path();
+        let file = root.join("file.txt");
+
+        let clean = state_of(&file).await;
+        assert_eq!(
+            map_get(&clean, "backend").and_then(Value::as_str),
+            Some("git")
+        );
+        assert_eq!(
+            map_get(&clean, "state").and_then(Value::as_str),
+            Some("up-to-date")
+        );
+        assert_eq!(
+            map_get(&clean, "root").and_then(Value::as_str),
+            Some(root.to_string_lossy().as_ref())
+        );
+        assert!(map_get(&clean, "generation").is_some_and(|g| g.as_i64().is_some()));
+        assert_eq!(
+            map_get(&clean, "revision")
+                .and_then(Value::as_str)
+                .map(str::len),
+            Some(40)
+        );
+
+        std::fs::write(&file, "changed\n").unwrap();
+        let edited = state_of(&file).await;
+        assert_eq!(
+            map_get(&edited, "state").and_then(Value::as_str),
+            Some("edited")
+        );
+
+        std::fs::create_dir(root.join("sub")).unwrap();
+        let untracked = root.join("sub").join("new.txt");
+        std::fs::write(&untracked, "new\n").unwrap();
+        let unregistered = state_of(&untracked).await;
+        assert_eq!(
+            map_get(&unregistered, "state").and_then(Value::as_str),
+            Some("unregistered")
+        );
+
+        std::fs::write(root.join(".gitignore"), "*.log\n").unwrap();
+        std::fs::write(root.join("build.log"), "").unwrap();
+        let ignored = state_of(&root.join("build.log")).await;
+        assert_eq!(
+            map_get(&ignored, "state").and_then(Value::as_str),
+            Some("ignored")
+        );
+
+        git(root, &["add", "sub/new.txt"]);
+        let added = state_of(&untracked).await;
+        assert_eq!(
+            map_get(&added, "state").and_then(Value::as_str),
+            Some("added")
+        );
+    }
+
+    #[tokio::test]
+    async fn vc_status_outside_repository_is_nil() {
+        let tmp = tempfile::tempdir().unwrap();
+        let file = tmp.path().join("plain.txt");
+        std::fs::write(&file, "").unwrap();
+        // The temp dir itself may live under a checkout; only assert when not.
+        if locate_vc_root(&file).unwrap().is_none() {
+            assert_eq!(state_of(&file).await, Value::Nil);
+        }
+    }
+
+    #[test]
+    fn parse_git_status_maps_porcelain_entries() {
+        let head = b"# branch.oid 0123\0# branch.head main\0";
+        let entry = |e: &[u8]| [head.as_slice(), e].concat();
+        assert_eq!(
+            parse_git_status(&entry(b"1 .D N... 100644 100644 000000 a b f\0"), false).0,
+            VcState::Missing
+        );
+        assert_eq!(
+            parse_git_status(&entry(b"1 D. N... 100644 000000 000000 a b f\0"), false).0,
+            VcState::Removed
+        );
+        assert_eq!(
+            parse_git_status(
+                &entry(b"2 R. N... 100644 100644 100644 a b R100 new\0old\0"),
+                true
+            )
+            .0,
+            VcState::Added
+        );
+        assert_eq!(
+            parse_git_status(&entry(b"u UU N... 1 2 3 4 a b c f\0"), true).0,
+            VcState::Conflict
+        );
+        let (state, revision) = parse_git_status(b"# branch.oid (initial)\0", true);
+        assert_eq!((state, revision), (VcState::UpToDate, None));
+    }
+}
@@ -0,0 +1,304 @@
+//! Waiting for a file or directory to change: `file.wait_changed`.
+//!
+//! A long poll for clients that do not take `fs.events` notifications:
+//! the request returns once the path no longer matches a fingerprint, or
+//! when its timeout runs out.  When a watch covers the path (see
+//! `watch.add`), the waiter wakes on the watcher's events and rechecks
+//! only now and then in case one was missed; otherwise it stats the path
+//! once a second.  Either way it sleeps on the runtime between checks, so
+//! waiters hold no thread.
+
+use crate::jail;
+use crate::msgpack_map;
+use crate::protocol::{RpcError, from_value, path_or_bytes, path_value};
+use rmpv::Value;
+use serde::Deserialize;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use tokio::time::{self, Duration, Instant};
+
+use super::HandlerResult;
+use super::file::{bytes_to_path, map_io_error};
+
+/// Default `timeout_ms`.
+const DEFAULT_TIMEOUT_MS: u64 = 30_000;
+
+/// Longest `timeout_ms` accepted.
+const MAX_TIMEOUT_MS: u64 = 600_000;
+
+/// Time between checks of a path no watch covers.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Time between checks of a watched path without events.
+const WATCHED_RECHECK: Duration = Duration::from_secs(5);
+
+/// The fingerprint fields, in the order they are reported.
+const FIELDS: [&str; 7] = [
+    "exists",
+    "type",
+    "size",
+    "mtime",
+    "mtime_nsec",
+    "inode",
+    "entries",
+];
+
+/// Handle `file.wait_changed {path, fingerprint?, timeout_ms?}`.
+///
+/// A fingerprint is a map of `exists`, `type`, `size`, `mtime`,
+/// `mtime_nsec`, `inode` and, for directories, `entries` (the listing
+/// fingerprint of `dir.list`).  Only the fields the given fingerprint has
+/// are compared, so `{mtime, size}` from `file.stat` works as well as one
+/// returned earlier.  Symlinks are followed.
+///
+/// Returns `{changed, fingerprint, fields}` with the current fingerprint
+/// and the names of the fields that differ, as soon as any do, or with
+/// `changed: false` after `timeout_ms` (default 30000, at most 600000).
+/// Without `fingerprint` it returns the current one at once.
+pub async fn wait_changed(params: Value) -> HandlerResult {
+    #[derive(Deserialize)]
+    struct Params {
+        #[serde(with = "path_or_bytes")]
+        path: Vec<u8>,
+        #[serde(default)]
+        fingerprint: Option<Value>,
+        #[serde(default = "default_timeout")]
+        timeout_ms: u64,
+    }
+
+    fn default_timeout() -> u64 {
+        DEFAULT_TIMEOUT_MS
+    }
+
+    let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
+    if params.timeout_ms > MAX_TIMEOUT_MS {
+        return Err(RpcError::invalid_params(format!(
+            "timeout_ms must be at most {}",
+            MAX_TIMEOUT_MS
+        )));
+    }
+    let path = bytes_to_path(&params.path);
+    jail::check(&path)?;
+    let Some(expected) = params.fingerprint else {
+        return Ok(reply(fingerprint(&path).await?, Vec::new()));
+    };
+    let Value::Map(fields) = &expected else {
+        return Err(RpcError::invalid_params("fingerprint must be a map"));
+    };
+    if !fields
+        .iter()
+        .any(|(key, _)| key.as_str().is_some_and(|key| FIELDS.contains(&key)))
+    {
+        return Err(RpcError::invalid_params(format!(
+            "fingerprint needs one of {}",
+            FIELDS.join(", ")
+        )));
+    }
+
+    // Subscribed before the first check, so no event in between is lost
+    let mut changes = crate::watcher::subscribe();
+    let target = canonical_target(&path);
+    let watched = crate::watcher::get().is_some_and(|manager| {
+        manager.covers(&target) || target.parent().is_some_and(|parent| manager.covers(parent))
+    });
+    let interval = if watched {
+        WATCHED_RECHECK
+    } else {
+        POLL_INTERVAL
+    };
+    let deadline = Instant::now() + Duration::from_millis(params.timeout_ms);
+
+    loop {
+        let current = fingerprint(&path).await?;
+        let differing = differences(&expected, &current);
+        let now = Instant::now();
+        if !differing.is_empty() || now >= deadline {
+            return Ok(reply(current, differing));
+        }
+        let next = (now + interval).min(deadline);
+        loop {
+            tokio::select! {
+                _ = time::sleep_until(next) => break,
+                batch = changes.recv(), if watched => match batch {
+                    Ok(batch) if !batch.touch(&target) => continue,
+                    // Touched, or events were dropped
+                    _ => break,
+                },
+            }
+        }
+    }
+}
+
+fn reply(fingerprint: Value, differing: Vec<&'static str>) -> Value {
+    msgpack_map! {
+        "changed" => !differing.is_empty(),
+        "fingerprint" => fingerprint,
+        "fields" => Value::Array(differing.into_iter().map(Value::from).collect())
+    }
+}
+
+/// The fields of `expected` whose values `current` does not share.
+fn differences(expected: &Value, current: &Value) -> Vec<&'static str> {
+    let has = |key: &str| {
+        expected
+            .as_map()
+            .is_some_and(|fields| fields.iter().any(|(k, _)| k.as_str() == Some(key)))
+    };
+    FIELDS
+        .into_iter()
+        .filter(|field| has(field) && expected[*field] != current[*field])
+        .collect()
+}
+
+/// `path` as watch events name it: canonical, or its canonical parent
+/// joined with its name while it does not exist.
+fn canonical_target(path: &Path) -> PathBuf {
+    if let Ok(canonical) = path.canonicalize() {
+        return canonical;
+    }
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => parent
+            .canonicalize()
+            .map_or_else(|_| path.to_path_buf(), |parent| parent.join(name)),
+        _ => path.to_path_buf(),
+    }
+}
+
+/// The fingerprint of `path` now.
+async fn fingerprint(path: &Path) -> Result<Value, RpcError> {
+    let owned = path.to_path_buf();
+    let metadata = match crate::blocking::run(path, move || std::fs::metadata(owned)).await? {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Ok(msgpack_map! { "exists" => false });
+        }
+        Err(e) => return Err(map_io_error(e, path)),
+    };
+    let file_type = super::dir::file_type_from_metadata_ft(&metadata.file_type());
+    let mut value = msgpack_map! {
+        "exists" => true,
+        "type" => file_type.as_str(),
+        "size" => metadata.size(),
+        "mtime" => metadata.mtime(),
+        "mtime_nsec" => metadata.mtime_nsec(),
+        "inode" => metadata.ino()
+    };
+    if metadata.is_dir() {
+        let listing = super::dir::list(msgpack_map! {
+            "path" => path_value(path),
+            "fingerprint_only" => true
+        })
+        .await;
+        let entries = match listing {
+            Ok(listing) => listing["fingerprint"].clone(),
+            // Removed since the stat
+            Err(e) if e.code == RpcError::FILE_NOT_FOUND => {
+                return Ok(msgpack_map! { "exists" => false });
+            }
+            Err(e) => return Err(e),
+        };
+        if let Value::Map(fields) = &mut value {
+            fields.push(("entries".into(), entries));
+        }
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn waits_return_once_the_path_changes() {
+        let tmp = tempfile::tempdir().unwrap();
+        let file = tmp.path().join("f");
+        std::fs::write(&file, b"one").unwrap();
+        let path = path_value(&file);
+
+        let first = wait_changed(msgpack_map! { "path" => path.clone() })
+            .await
+            .unwrap();
+        assert_eq!(first["changed"], Value::Boolean(false));
+        let fingerprint = first["fingerprint"].clone();
+        assert_eq!(fingerprint["size"].as_u64(), Some(3));
+
+        // Unchanged until the timeout
+        let started = std::time::Instant::now();
+        let result = wait_changed(msgpack_map! {
+            "path" => path.clone(),
+            "fingerprint" => fingerprint.clone(),
+            "timeout_ms" => 50
+        })
+        .await
+        .unwrap();
+        assert_eq!(result["changed"], Value::Boolean(false));
+        assert!(started.elapsed() >= Duration::from_millis(50));
+
+        let writer = {
+            let file = file.clone();
+            tokio::spawn(async move {
+                time::sleep(Duration::from_millis(100)).await;
+                std::fs::write(&file, b"three").unwrap();
+            })
+        };
+        let result = wait_changed(msgpack_map! {
+            "path" => path.clone(),
+            "fingerprint" => fingerprint,
+            "timeout_ms" => 10_000
+        })
+        .await
+        .unwrap();
+        writer.await.unwrap();
+        assert_eq!(result["changed"], Value::Boolean(true));
+        assert!(
+            result["fields"]
+                .as_array()
+                .unwrap()
+                .contains(&Value::from("size"))
+        );
+        assert_eq!(result["fingerprint"]["size"].as_u64(), Some(5));
+
+        // Partial fingerprints, and deletion
+        std::fs::remove_file(&file).unwrap();
+        let result = wait_changed(msgpack_map! {
+            "path" => path,
+            "fingerprint" => msgpack_map! { "exists" => true, "size" => 5 },
+            "timeout_ms" => 1000
+        })
+        .await
+        .unwrap();
+        assert_eq!(
+            result["fields"],
+            Value::Array(vec!["exists".into(), "size".into()])
+        );
+        assert_eq!(result["fingerprint"]["exists"], Value::Boolean(false));
+    }
+
+    #[tokio::test]
+    async fn directory_fingerprints_cover_entries() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = path_value(tmp.path());
+        let first = wait_changed(msgpack_map! { "path" => path.clone() })
+            .await
+            .unwrap();
+        assert!(first["fingerprint"]["entries"].is_str());
+
+        std::fs::write(tmp.path().join("new"), b"").unwrap();
+        let result = wait_changed(msgpack_map! {
+            "path" => path.clone(),
+            "fingerprint" => msgpack_map! { "entries" => first["fingerprint"]["entries"].clone() },
+            "timeout_ms" => 1000
+        })
+        .await
+        .unwrap();
+        assert_eq!(result["fields"], Value::Array(vec!["entries".into()]));
+
+        let error = wait_changed(msgpack_map! {
+            "path" => path,
+            "fingerprint" => msgpack_map! { "color" => "red" }
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(error.code, RpcError::INVALID_PARAMS);
+    }
+}
@@ -0,0 +1,122 @@
+//! Capability handshake.
+//!
+//! A client calls `system.hello {features, limits?}` with the protocol
+//! features it understands.  The server replies `{version, features,
+//! limits}` with the ones it supports and turns on those both sides know.
+//! `limits` sets the concurrency limits of [`crate::limits`].  Features
+//! change the shape of responses, so each stays off until a client asks for
+//! it and clients that never say hello see the shapes they always did.
+//!
+//! The server speaks to exactly one client, so the negotiated features are
+//! process-wide.
+
+use crate::handlers::HandlerResult;
+use crate::msgpack_map;
+use crate::protocol::{RpcError, from_value};
+use rmpv::Value;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/// Protocol features a client can ask for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Feature {
+    /// List-like methods return `{entries, total, truncated, reason}`
+    /// instead of their bare results; see [`crate::protocol::Listing`].
+    ListEnvelope,
+    /// Every frame the server writes carries a CRC32C; see
+    /// [`crate::framing`].
+    FrameCrc32c,
+    /// Responses carry `timing: {queued_us, handler_us}`; see
+    /// [`crate::timing`].
+    Timing,
+}
+
+impl Feature {
+    const ALL: [Feature; 3] = [Feature::ListEnvelope, Feature::FrameCrc32c, Feature::Timing];
+
+    fn name(self) -> &'static str {
+        match self {
+            Feature::ListEnvelope => "list_envelope",
+            Feature::FrameCrc32c => "frame_crc32c",
+            Feature::Timing => "timing",
+        }
+    }
+
+    fn bit(self) -> u32 {
+        1 << self as u32
+    }
+}
+
+/// Bits of the features the client turned on
+static ENABLED: AtomicU32 = AtomicU32::new(0);
+
+/// Whether the client negotiated `feature`
+pub fn enabled(feature: Feature) -> bool {
+    ENABLED.load(Ordering::Relaxed) & feature.bit() != 0
+}
+
+/// The features named in `requested` that the server knows, in its order.
+/// Unknown names are ignored, so newer clients can talk to older servers.
+fn negotiate(requested: &[String]) -> Vec<Feature> {
+    Feature::ALL
+        .into_iter()
+        .filter(|feature| requested.iter().any(|name| name == feature.name()))
+        .collect()
+}
+
+/// Handle `system.hello {features, limits?}`: enable the features both
+/// sides know and apply the limits given.
+///
+/// A later hello replaces the features of an earlier one; limits it does
+/// not name stay as they were.
+pub fn handle_hello(params: Value) -> HandlerResult {
+    #[derive(Deserialize, Default)]
+    struct Params {
+        #[serde(default)]
+        features: Vec<String>,
+        /// Concurrency limits per request class; see [`crate::limits`]
+        #[serde(default)]
+        limits: BTreeMap<String, usize>,
+    }
+
+    let params: Params = if params.is_nil() {
+        Params::default()
+    } else {
+        from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?
+    };
+
+    let features = negotiate(&params.features);
+    let bits = features
+        .iter()
+        .fold(0, |bits, feature| bits | feature.bit());
+    ENABLED.store(bits, Ordering::Relaxed);
+    crate::limits::configure(&params.limits);
+
+    Ok(msgpack_map! {
+        "version" => env!("CARGO_PKG_VERSION"),
+        "features" => Value::Array(features.iter().map(|f| f.name().into()).collect()),
+        "limits" => crate::limits::current()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiation_keeps_known_features_only() {
+        let requested =
+            |names: &[&str]| negotiate(&names.iter().map(|n| n.to_string()).collect::<Vec<_>>());
+        assert_eq!(
+            requested(&["from_the_future", "list_envelope"]),
+            [Feature::ListEnvelope]
+        );
+        assert_eq!(
+            requested(&["frame_crc32c", "list_envelope"]),
+            [Feature::ListEnvelope, Feature::FrameCrc32c]
+        );
+        assert!(requested(&[]).is_empty());
+        assert!(requested(&["LIST_ENVELOPE"]).is_empty());
+    }
+}
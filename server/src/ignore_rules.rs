@@ -0,0 +1,213 @@
+//! Gitignore-aware path filtering for recursive listings
+//!
+//! Follows ripgrep's precedence: within a directory `.ignore` beats
+//! `.gitignore`, rules in deeper directories beat shallower ones, and
+//! `.git/info/exclude` and the global excludes file (`core.excludesFile`)
+//! are consulted last.  Git sources only apply inside a git worktree, while
+//! `.ignore` files apply anywhere.  Everything below an ignored directory is
+//! ignored too, since git cannot re-include it either.
+
+use ignore::Match;
+use ignore::gitignore::{Gitignore, GitignoreBuilder, Glob};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// Why a path is ignored
+#[derive(Debug, Clone, PartialEq)]
+pub struct Exclusion {
+    /// The pattern as written in its ignore file
+    pub pattern: String,
+    /// The ignore file the pattern came from
+    pub source: Option<PathBuf>,
+    /// The ignored ancestor directory, when the path itself did not match
+    pub ancestor: Option<PathBuf>,
+}
+
+impl Exclusion {
+    fn from_glob(glob: &Glob) -> Self {
+        Self {
+            pattern: glob.original().to_string(),
+            source: glob.from().map(Path::to_path_buf),
+            ancestor: None,
+        }
+    }
+}
+
+/// Ignore rules for the paths below one root.
+///
+/// Per-directory ignore files are read the first time a path below them is
+/// checked, so a walk only pays for the directories it visits.  Paths passed
+/// in must be absolute and below `root`.
+pub struct IgnoreRules {
+    root: PathBuf,
+    /// Top of the git worktree containing `root`, if any
+    worktree: Option<PathBuf>,
+    /// `.git/info/exclude` followed by the global excludes file
+    repo_rules: Vec<Gitignore>,
+    /// `[.ignore, .gitignore]` per directory
+    dir_rules: HashMap<PathBuf, [Gitignore; 2]>,
+    /// Verdicts for directories already checked as ancestors
+    dir_verdicts: HashMap<PathBuf, Option<Exclusion>>,
+}
+
+impl IgnoreRules {
+    pub fn new(root: &Path) -> Self {
+        let worktree = root
+            .ancestors()
+            .find(|dir| dir.join(".git").exists())
+            .map(Path::to_path_buf);
+
+        let mut repo_rules = Vec::new();
+        if let Some(ref top) = worktree {
+            repo_rules.push(load_rules(
+                top,
+                &top.join(".git").join("info").join("exclude"),
+            ));
+            repo_rules.push(GitignoreBuilder::new(top).build_global().0);
+        }
+
+        Self {
+            root: root.to_path_buf(),
+            worktree,
+            repo_rules,
+            dir_rules: HashMap::new(),
+            dir_verdicts: HashMap::new(),
+        }
+    }
+
+    /// Return why `path` is ignored, or `None` if it is not.
+    pub fn check(&mut self, path: &Path, is_dir: bool) -> Option<Exclusion> {
+        if let Some(parent) = path.parent()
+            && parent != self.root
+            && parent.starts_with(&self.root)
+            && let Some(exclusion) = self.check_dir(parent)
+        {
+            return Some(Exclusion {
+                ancestor: exclusion.ancestor.or_else(|| Some(parent.to_path_buf())),
+                ..exclusion
+            });
+        }
+        self.matched(path, is_dir)
+    }
+
+    fn check_dir(&mut self, dir: &Path) -> Option<Exclusion> {
+        if let Some(verdict) = self.dir_verdicts.get(dir) {
+            return verdict.clone();
+        }
+        let verdict = self.check(dir, true);
+        self.dir_verdicts.insert(dir.to_path_buf(), verdict.clone());
+        verdict
+    }
+
+    /// Match `path` itself, ignoring whether its ancestors are ignored.
+    fn matched(&mut self, path: &Path, is_dir: bool) -> Option<Exclusion> {
+        let top = self.worktree.clone().unwrap_or_else(|| self.root.clone());
+        for dir in path.ancestors().skip(1) {
+            if !dir.starts_with(&top) {
+                break;
+            }
+            for rules in self.rules_for(dir) {
+                match rules.matched(path, is_dir) {
+                    Match::None => {}
+                    Match::Ignore(glob) => return Some(Exclusion::from_glob(glob)),
+                    Match::Whitelist(_) => return None,
+                }
+            }
+        }
+        for rules in &self.repo_rules {
+            match rules.matched(path, is_dir) {
+                Match::None => {}
+                Match::Ignore(glob) => return Some(Exclusion::from_glob(glob)),
+                Match::Whitelist(_) => return None,
+            }
+        }
+        None
+    }
+
+    fn rules_for(&mut self, dir: &Path) -> &[Gitignore; 2] {
+        if !self.dir_rules.contains_key(dir) {
+            let gitignore = match self.worktree {
+                Some(_) => load_rules(dir, &dir.join(".gitignore")),
+                None => Gitignore::empty(),
+            };
+            let rules = [load_rules(dir, &dir.join(".ignore")), gitignore];
+            self.dir_rules.insert(dir.to_path_buf(), rules);
+        }
+        &self.dir_rules[dir]
+    }
+}
+
+/// Read the ignore file `file` with patterns relative to `dir`.  Missing or
+/// unreadable files and bad lines are skipped, as git does.
+fn load_rules(dir: &Path, file: &Path) -> Gitignore {
+    if !file.is_file() {
+        return Gitignore::empty();
+    }
+    let mut builder = GitignoreBuilder::new(dir);
+    builder.add(file);
+    builder.build().unwrap_or_else(|_| Gitignore::empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nested_rules_negation_and_ancestors() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        std::fs::create_dir_all(root.join(".git").join("info")).unwrap();
+        std::fs::create_dir_all(root.join("sub").join("build")).unwrap();
+        std::fs::write(root.join(".gitignore"), "*.log\nbuild/\n").unwrap();
+        std::fs::write(root.join("sub").join(".gitignore"), "!keep.log\n").unwrap();
+        std::fs::write(root.join("sub").join(".ignore"), "scratch*\n").unwrap();
+        std::fs::write(root.join(".git").join("info").join("exclude"), "*.tmp\n").unwrap();
+
+        let mut rules = IgnoreRules::new(root);
+        assert!(rules.check(&root.join("main.rs"), false).is_none());
+
+        let log = rules
+            .check(&root.join("sub").join("debug.log"), false)
+            .unwrap();
+        assert_eq!(log.pattern, "*.log");
+        assert_eq!(log.source, Some(root.join(".gitignore")));
+        assert!(
+            rules
+                .check(&root.join("sub").join("keep.log"), false)
+                .is_none()
+        );
+        assert_eq!(
+            rules
+                .check(&root.join("sub").join("scratch.rs"), false)
+                .unwrap()
+                .source,
+            Some(root.join("sub").join(".ignore"))
+        );
+        assert_eq!(
+            rules.check(&root.join("x.tmp"), false).unwrap().pattern,
+            "*.tmp"
+        );
+
+        let inside = rules
+            .check(&root.join("sub").join("build").join("out.rs"), false)
+            .unwrap();
+        assert_eq!(inside.pattern, "build/");
+        assert_eq!(inside.ancestor, Some(root.join("sub").join("build")));
+    }
+
+    #[test]
+    fn gitignore_needs_a_worktree() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        std::fs::write(root.join(".gitignore"), "*.log\n").unwrap();
+        std::fs::write(root.join(".ignore"), "*.tmp\n").unwrap();
+
+        let mut rules = IgnoreRules::new(root);
+        // Skip if the temp dir happens to live inside a git checkout.
+        if rules.worktree.is_some() {
+            return;
+        }
+        assert!(rules.check(&root.join("a.log"), false).is_none());
+        assert!(rules.check(&root.join("a.tmp"), false).is_some());
+    }
+}
@@ -0,0 +1,254 @@
+//! Optional path jail.
+//!
+//! When the server is started with `--jail DIR` (or `TRAMP_RPC_JAIL=DIR`),
+//! every path a handler receives must resolve, after following symlinks, to
+//! DIR or something below it.  Paths that do not exist yet are resolved as
+//! far as they exist and the remaining components are applied lexically, so
+//! writing a new file or `dir.create` with parents keeps working.
+//!
+//! On Linux, `openat2(RESOLVE_BENEATH)` accepts existing paths in a single
+//! syscall.  Everything else (missing tails, absolute symlinks that point
+//! back into the jail, other platforms) goes through a component-wise
+//! resolver that follows symlinks the way the kernel does.
+//!
+//! The jail only constrains path arguments.  Processes are started in a
+//! jailed working directory but can of course access anything their uid
+//! can.
+//!
+//! The jail is advisory: [`check`] resolves a path, and the handler then
+//! opens it again by name.  A symlink swapped into the path between the two
+//! escapes it, so it holds against what a client sends, not against local
+//! processes that can change the tree meanwhile.  Listings leave out what
+//! symlinks pointing outside lead to; see [`contains`].
+
+use crate::protocol::RpcError;
+use std::ffi::OsString;
+use std::path::{Component, Path, PathBuf};
+use std::sync::OnceLock;
+
+/// Maximum number of symlinks followed while resolving one path (MAXSYMLINKS)
+const MAX_SYMLINKS: usize = 40;
+
+static ROOT: OnceLock<PathBuf> = OnceLock::new();
+
+/// Jail the server to `root` and make it the working directory, so relative
+/// paths and processes started without a `cwd` stay inside it.
+pub fn init(root: &Path) -> std::io::Result<()> {
+    let root = std::fs::canonicalize(root)?;
+    if !root.is_dir() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("jail root is not a directory: {}", root.display()),
+        ));
+    }
+    std::env::set_current_dir(&root)?;
+    let _ = ROOT.set(root);
+    Ok(())
+}
+
+/// The canonical jail root, if the server is jailed.
+pub fn root() -> Option<&'static Path> {
+    ROOT.get().map(PathBuf::as_path)
+}
+
+/// Whether `path` resolves inside the jail (always true when unjailed).
+/// Handlers that follow symlinks they did not receive as arguments, such
+/// as the entries of a listing, ask this before following one.
+pub fn contains(path: &Path) -> bool {
+    root().is_none_or(|root| is_beneath(root, path))
+}
+
+/// Fail with ACCESS_DENIED unless `path` resolves inside the jail.
+pub fn check(path: &Path) -> Result<(), RpcError> {
+    if contains(path) {
+        Ok(())
+    } else {
+        Err(RpcError::access_denied(&path.to_string_lossy()))
+    }
+}
+
+/// Check the target of a symlink about to be created at `link`.  Relative
+/// targets are resolved from the directory containing the link.
+pub fn check_link_target(link: &Path, target: &Path) -> Result<(), RpcError> {
+    if root().is_none() {
+        return Ok(());
+    }
+    let base = link.parent().unwrap_or(Path::new("/"));
+    check(&base.join(target))
+}
+
+fn is_beneath(root: &Path, path: &Path) -> bool {
+    let absolute = if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        match std::env::current_dir() {
+            Ok(cwd) => cwd.join(path),
+            Err(_) => return false,
+        }
+    };
+
+    #[cfg(target_os = "linux")]
+    if openat2_beneath(root, &absolute) {
+        return true;
+    }
+
+    resolve(&absolute).is_some_and(|resolved| resolved.starts_with(root))
+}
+
+/// Ask the kernel whether `path` exists and resolves beneath `root`.
+///
+/// Only a positive answer is trusted: ENOENT (missing tail), EXDEV (which
+/// RESOLVE_BENEATH also reports for absolute symlinks pointing back into the
+/// jail) and ENOSYS on older kernels all defer to [`resolve`].
+#[cfg(target_os = "linux")]
+fn openat2_beneath(root: &Path, path: &Path) -> bool {
+    use std::os::unix::ffi::OsStrExt;
+
+    let Ok(relative) = path.strip_prefix(root) else {
+        return false;
+    };
+    let relative = if relative.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        relative
+    };
+
+    let cstring = |p: &Path| std::ffi::CString::new(p.as_os_str().as_bytes()).ok();
+    let (Some(root_c), Some(relative_c)) = (cstring(root), cstring(relative)) else {
+        return false;
+    };
+
+    let root_fd = unsafe {
+        libc::open(
+            root_c.as_ptr(),
+            libc::O_PATH | libc::O_DIRECTORY | libc::O_CLOEXEC,
+        )
+    };
+    if root_fd < 0 {
+        return false;
+    }
+
+    let mut how: libc::open_how = unsafe { std::mem::zeroed() };
+    how.flags = (libc::O_PATH | libc::O_CLOEXEC) as u64;
+    how.resolve = libc::RESOLVE_BENEATH | libc::RESOLVE_NO_MAGICLINKS;
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_openat2,
+            root_fd,
+            relative_c.as_ptr(),
+            &how as *const libc::open_how,
+            std::mem::size_of::<libc::open_how>(),
+        )
+    };
+    unsafe {
+        libc::close(root_fd);
+        if fd >= 0 {
+            libc::close(fd as libc::c_int);
+        }
+    }
+    fd >= 0
+}
+
+/// Resolve `path` like realpath(3), except that resolution continues
+/// lexically once a component does not exist (or cannot be inspected).
+/// Returns `None` on symlink loops.
+fn resolve(path: &Path) -> Option<PathBuf> {
+    fn push_components(pending: &mut Vec<OsString>, path: &Path) {
+        for component in path.components().rev() {
+            match component {
+                Component::Normal(name) => pending.push(name.to_os_string()),
+                Component::ParentDir => pending.push("..".into()),
+                Component::RootDir | Component::CurDir | Component::Prefix(_) => {}
+            }
+        }
+    }
+
+    let mut pending = Vec::new();
+    push_components(&mut pending, path);
+
+    let mut resolved = PathBuf::from("/");
+    let mut links = 0;
+    let mut exists = true;
+    while let Some(name) = pending.pop() {
+        if name == ".." {
+            resolved.pop();
+            continue;
+        }
+        resolved.push(&name);
+        if !exists {
+            continue;
+        }
+        match std::fs::symlink_metadata(&resolved) {
+            Ok(meta) if meta.file_type().is_symlink() => {
+                links += 1;
+                if links > MAX_SYMLINKS {
+                    return None;
+                }
+                let target = std::fs::read_link(&resolved).ok()?;
+                resolved.pop();
+                if target.is_absolute() {
+                    resolved = PathBuf::from("/");
+                }
+                push_components(&mut pending, &target);
+            }
+            Ok(_) => {}
+            Err(_) => exists = false,
+        }
+    }
+    Some(resolved)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn jail_rejects_traversal_and_symlink_escapes() {
+        let outer = tempfile::tempdir().unwrap();
+        let outer_path = std::fs::canonicalize(outer.path()).unwrap();
+        let root = outer_path.join("jail");
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        std::fs::write(root.join("sub/file"), b"").unwrap();
+        std::fs::write(outer_path.join("secret"), b"").unwrap();
+
+        symlink(outer_path.join("secret"), root.join("escape")).unwrap();
+        symlink("../secret", root.join("relative-escape")).unwrap();
+        symlink(outer_path.join("nowhere"), root.join("dangling-escape")).unwrap();
+        symlink(root.join("sub"), root.join("absolute-inside")).unwrap();
+        symlink("sub/file", root.join("relative-inside")).unwrap();
+
+        // Existing and not-yet-existing paths inside the jail
+        assert!(is_beneath(&root, &root));
+        assert!(is_beneath(&root, &root.join("sub/file")));
+        assert!(is_beneath(&root, &root.join("sub/new/deeper")));
+        assert!(is_beneath(&root, &root.join("sub/../sub/file")));
+        assert!(is_beneath(&root, &root.join("absolute-inside/file")));
+        assert!(is_beneath(&root, &root.join("relative-inside")));
+
+        // `..` traversal, including through missing components
+        assert!(!is_beneath(&root, &root.join("../secret")));
+        assert!(!is_beneath(&root, &root.join("sub/../../secret")));
+        assert!(!is_beneath(&root, &root.join("missing/../../secret")));
+        assert!(!is_beneath(&root, &outer_path));
+
+        // Symlinks pointing outside, even when the target does not exist
+        assert!(!is_beneath(&root, &root.join("escape")));
+        assert!(!is_beneath(&root, &root.join("relative-escape")));
+        assert!(!is_beneath(&root, &root.join("dangling-escape")));
+        assert!(!is_beneath(
+            &root,
+            &root.join("absolute-inside/../../secret")
+        ));
+    }
+
+    #[test]
+    fn jail_resolver_detects_symlink_loops() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = std::fs::canonicalize(dir.path()).unwrap();
+        symlink("b", root.join("a")).unwrap();
+        symlink("a", root.join("b")).unwrap();
+        assert!(resolve(&root.join("a")).is_none());
+        assert!(!is_beneath(&root, &root.join("a")));
+    }
+}
@@ -0,0 +1,307 @@
+//! Concurrency limits per class of request.
+//!
+//! Heavy requests are sorted into classes, each with its own limit on how
+//! many run at once: `io` (reads, writes, copies, archives), `process`
+//! (spawning and waiting on commands) and `metadata` (stats and listings).
+//! A request over its class's limit waits for a slot before its handler
+//! runs, or fails at once with BUSY if its params say `no_wait: true`.  The
+//! classes do not share slots, so stats and listings never queue behind a
+//! burst of reads.  Methods without a class,
+//! such as `process.write` or `system.*`, are never limited.
+//!
+//! The client can change the limits with `system.hello {limits: {io: N}}`;
+//! 0 removes a limit.  Usage is reported under `limits` in `system.stats`.
+
+use crate::msgpack_map;
+use crate::protocol::RpcError;
+use rmpv::Value;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use tokio::sync::Notify;
+
+/// Classes of requests with their own concurrency limit
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Class {
+    Io,
+    Process,
+    Metadata,
+}
+
+impl Class {
+    const ALL: [Class; 3] = [Class::Io, Class::Process, Class::Metadata];
+
+    fn name(self) -> &'static str {
+        match self {
+            Class::Io => "io",
+            Class::Process => "process",
+            Class::Metadata => "metadata",
+        }
+    }
+
+    fn gate(self) -> &'static Gate {
+        &GATES[self as usize]
+    }
+}
+
+/// The class of `method`, if it has one.
+pub fn class_of(method: &str) -> Option<Class> {
+    match method {
+        "file.read"
+        | "file.read_multi_ranges"
+        | "file.convert_encoding"
+        | "file.write"
+        | "file.signature"
+        | "file.write_delta"
+        | "file.write_chunk"
+        | "file.write_commit"
+        | "file.copy"
+        | "file.fallocate"
+        | "dir.disk_usage"
+        | "dir.audit_perms"
+        | "archive.list"
+        | "archive.extract"
+        | "archive.create"
+        | "archive.read"
+        | "network.fetch"
+        | "project.files"
+        | "grep.search"
+        | "tags.generate"
+        | "system.install_binary" => Some(Class::Io),
+        "process.run"
+        | "process.start"
+        | "process.start_pty"
+        | "process.run_sudo"
+        | "shell.session_open"
+        | "shell.session_run"
+        | "commands.run_parallel"
+        | "git.log"
+        | "vc.status" => Some(Class::Process),
+        "file.stat"
+        | "file.stat_batch"
+        | "file.exists_ex"
+        | "file.access_batch"
+        | "file.expand_wildcards"
+        | "file.truename"
+        | "file.get_flags"
+        | "file.hole_map"
+        | "file.lockinfo"
+        | "file.list_autosaves"
+        | "dir.list"
+        | "dir.list_multi"
+        | "dir.generation"
+        | "dir.completions"
+        | "ancestors.scan"
+        | "highlevel.test_files_in_dir"
+        | "highlevel.locate_dominating_file_multi"
+        | "highlevel.dir_locals_find_file_cache_update" => Some(Class::Metadata),
+        _ => None,
+    }
+}
+
+/// Slots of one class
+struct Gate {
+    state: Mutex<GateState>,
+    /// Woken whenever a slot frees up or the limit changes
+    changed: Notify,
+}
+
+#[derive(Debug)]
+struct GateState {
+    /// 0 is unlimited
+    limit: usize,
+    in_use: usize,
+    waiting: usize,
+    peak: usize,
+    /// Requests refused with BUSY
+    busy: u64,
+}
+
+impl Gate {
+    const fn new(limit: usize) -> Self {
+        Self {
+            state: Mutex::new(GateState {
+                limit,
+                in_use: 0,
+                waiting: 0,
+                peak: 0,
+                busy: 0,
+            }),
+            changed: Notify::const_new(),
+        }
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, GateState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Default limits, in the order of [`Class::ALL`]
+static GATES: [Gate; 3] = [Gate::new(8), Gate::new(16), Gate::new(64)];
+
+/// A slot held by a running request, given back when dropped
+#[derive(Debug)]
+pub struct Permit(Class);
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let gate = self.0.gate();
+        gate.state().in_use -= 1;
+        gate.changed.notify_waiters();
+    }
+}
+
+/// Counts a request as waiting for as long as it waits, even if it is
+/// dropped while waiting.
+struct Waiting(Class);
+
+impl Drop for Waiting {
+    fn drop(&mut self) {
+        self.0.gate().state().waiting -= 1;
+    }
+}
+
+/// Take a slot for `method`, waiting for one unless `no_wait`.  Methods
+/// without a class get `None` at once.
+pub async fn acquire(method: &str, no_wait: bool) -> Result<Option<Permit>, RpcError> {
+    let Some(class) = class_of(method) else {
+        return Ok(None);
+    };
+    let gate = class.gate();
+    let mut waiting = None;
+    loop {
+        let changed = gate.changed.notified();
+        let mut changed = std::pin::pin!(changed);
+        // Registered before looking, so a slot freed in between is not missed
+        changed.as_mut().enable();
+        {
+            let mut state = gate.state();
+            if state.limit == 0 || state.in_use < state.limit {
+                state.in_use += 1;
+                state.peak = state.peak.max(state.in_use);
+                return Ok(Some(Permit(class)));
+            }
+            if no_wait {
+                state.busy += 1;
+                return Err(RpcError::busy(class.name(), state.limit, state.in_use));
+            }
+            if waiting.is_none() {
+                state.waiting += 1;
+                waiting = Some(Waiting(class));
+            }
+        }
+        changed.await;
+    }
+}
+
+/// Whether the request's params ask to fail with BUSY instead of waiting.
+pub fn no_wait(params: &Value) -> bool {
+    params.as_map().is_some_and(|map| {
+        map.iter()
+            .any(|(k, v)| k.as_str() == Some("no_wait") && v.as_bool() == Some(true))
+    })
+}
+
+/// Set the limits named in `limits`; other classes keep theirs and unknown
+/// names are ignored.
+pub fn configure(limits: &BTreeMap<String, usize>) {
+    for class in Class::ALL {
+        if let Some(&limit) = limits.get(class.name()) {
+            let gate = class.gate();
+            gate.state().limit = limit;
+            gate.changed.notify_waiters();
+        }
+    }
+}
+
+/// The limit of each class, as `{io, process, metadata}`.
+pub fn current() -> Value {
+    Value::Map(
+        Class::ALL
+            .into_iter()
+            .map(|class| {
+                (
+                    class.name().into(),
+                    Value::from(class.gate().state().limit as u64),
+                )
+            })
+            .collect(),
+    )
+}
+
+/// Limits and usage per class for `system.stats`.
+pub fn stats() -> Value {
+    Value::Map(
+        Class::ALL
+            .into_iter()
+            .map(|class| {
+                let state = class.gate().state();
+                let usage = msgpack_map! {
+                    "limit" => state.limit as u64,
+                    "in_use" => state.in_use as u64,
+                    "waiting" => state.waiting as u64,
+                    "peak" => state.peak as u64,
+                    "busy" => state.busy
+                };
+                (class.name().into(), usage)
+            })
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn interactive_methods_do_not_share_slots_with_heavy_ones() {
+        assert_eq!(class_of("file.read"), Some(Class::Io));
+        assert_eq!(class_of("file.copy"), Some(Class::Io));
+        assert_eq!(class_of("process.run"), Some(Class::Process));
+        assert_eq!(class_of("file.stat"), Some(Class::Metadata));
+        assert_eq!(class_of("dir.list"), Some(Class::Metadata));
+        assert_eq!(class_of("process.write"), None);
+        assert_eq!(class_of("system.stats"), None);
+    }
+
+    #[test]
+    fn no_wait_must_be_true() {
+        assert!(no_wait(&msgpack_map! { "no_wait" => true }));
+        assert!(!no_wait(&msgpack_map! { "no_wait" => false }));
+        assert!(!no_wait(&msgpack_map! { "path" => "/tmp" }));
+        assert!(!no_wait(&Value::Nil));
+    }
+
+    // The only test that changes a limit, and on the process class, which
+    // no other test dispatches through.
+    #[tokio::test]
+    async fn requests_over_the_limit_wait_or_fail_fast() {
+        let limits = |n: usize| BTreeMap::from([("process".to_string(), n)]);
+        configure(&limits(1));
+
+        let held = acquire("process.run", false).await.unwrap();
+        assert!(held.is_some());
+        let err = acquire("git.log", true).await.expect_err("busy");
+        assert_eq!(err.code, RpcError::BUSY);
+        assert_eq!(err.data.unwrap()["class"].as_str(), Some("process"));
+
+        let waiter = tokio::spawn(async { acquire("vc.status", false).await.map(|p| p.is_some()) });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!waiter.is_finished());
+        assert_eq!(Class::Process.gate().state().waiting, 1);
+        drop(held);
+        assert!(waiter.await.unwrap().unwrap());
+        assert_eq!(Class::Process.gate().state().waiting, 0);
+
+        // Raising the limit lets waiters through without a slot freeing up
+        let held = acquire("process.run", false).await.unwrap();
+        let waiter = tokio::spawn(async { acquire("vc.status", false).await.map(|p| p.is_some()) });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        configure(&limits(0));
+        assert!(waiter.await.unwrap().unwrap());
+        drop(held);
+
+        configure(&limits(16));
+        assert_eq!(current()["process"].as_u64(), Some(16));
+    }
+}
@@ -4,17 +4,47 @@
 //! Communicates over stdin/stdout using length-prefixed MessagePack messages.
 //!
 //! Protocol framing:
-//!   <4-byte big-endian length><msgpack payload>
+//!   <4-byte big-endian length><msgpack payload>[<4-byte CRC32C>]
+//!
+//! The checksum is optional; see [`framing`].
 //!
 //! Uses tokio for async concurrent request processing - multiple requests
 //! can be processed in parallel while waiting on I/O.
 
+mod audit;
+mod auth;
+mod blocking;
+mod cancel;
+mod config;
+mod env_policy;
+mod framing;
 mod handlers;
+mod handshake;
+mod ignore_rules;
+mod jail;
+mod limits;
+mod mutating;
+mod notifications;
 mod protocol;
+mod quota;
+mod recent;
+mod reexec;
+mod server_dirs;
+mod server_log;
+mod shutdown;
+mod stat_cache;
+#[cfg(test)]
+mod test_util;
+mod timing;
+mod trace;
+mod utmp;
 mod watcher;
+mod write_lock;
 
 use protocol::{Request, Response, RpcError};
-use std::sync::Arc;
+use std::ffi::OsString;
+use std::path::Path;
+use std::sync::{Arc, OnceLock};
 use tokio::io::{AsyncReadExt, AsyncWriteExt, BufWriter};
 use tokio::sync::Mutex;
 use tokio::task::JoinSet;
@@ -23,10 +53,208 @@ use tokio::task::JoinSet;
 /// and the watcher's notification sending.
 pub type WriterHandle = Arc<Mutex<BufWriter<tokio::io::Stdout>>>;
 
+/// Writer for notifications sent by request handlers, set at startup.
+static NOTIFICATION_WRITER: OnceLock<WriterHandle> = OnceLock::new();
+
+/// Send a notification to the client.  A notification that cannot be
+/// written is dropped, as is any sent before startup (as in tests) or over
+/// the notification budget.
+pub async fn send_notification(notification: protocol::Notification) {
+    let Some(writer) = NOTIFICATION_WRITER.get() else {
+        return;
+    };
+    let _ = notifications::send(writer, &notification).await;
+}
+
+/// Like [`send_notification`], but written even over the budget.  For
+/// notifications the client cannot do without, such as password prompts.
+pub async fn send_notification_forced(notification: protocol::Notification) {
+    let Some(writer) = NOTIFICATION_WRITER.get() else {
+        return;
+    };
+    let _ = notifications::send_forced(writer, &notification).await;
+}
+
+/// Run `f` with stdout flushed and locked, so that nothing else is written
+/// until it returns.  Without a writer (as in tests) `f` just runs.
+pub async fn with_stdout_flushed<T>(f: impl FnOnce() -> T) -> T {
+    let Some(writer) = NOTIFICATION_WRITER.get() else {
+        return f();
+    };
+    let mut writer = writer.lock().await;
+    let _ = writer.flush().await;
+    f()
+}
+
+/// Write one length-prefixed frame to stdout and flush it, with the
+/// checksum trailer when [`framing`] asks for one.  Once a write has failed
+/// nothing more is written; see [`shutdown`].
+pub async fn write_frame(writer: &WriterHandle, bytes: &[u8]) -> std::io::Result<()> {
+    let broken = || std::io::Error::from(std::io::ErrorKind::BrokenPipe);
+    if shutdown::is_broken() {
+        return Err(broken());
+    }
+    let mut writer = writer.lock().await;
+    // Checked again, as the writer may have broken while we waited for it
+    if shutdown::is_broken() {
+        return Err(broken());
+    }
+    let (prefix, checksum) = framing::envelope(bytes);
+    let written = async {
+        writer.write_all(&prefix).await?;
+        writer.write_all(bytes).await?;
+        if let Some(checksum) = checksum {
+            writer.write_all(&checksum).await?;
+        }
+        writer.flush().await
+    }
+    .await;
+    if written.is_err() {
+        shutdown::stdout_broken();
+    }
+    written
+}
+
+/// Fill `buf` from stdin.  Returns false at EOF, on a read error or once
+/// stdout is broken.
+async fn read_or_stop(stdin: &mut tokio::io::Stdin, buf: &mut [u8]) -> bool {
+    tokio::select! {
+        biased;
+        _ = shutdown::stopped() => false,
+        read = stdin.read_exact(buf) => read.is_ok(),
+    }
+}
+
+/// Value of a non-empty environment variable.
+fn env_value(name: &str) -> Option<OsString> {
+    std::env::var_os(name).filter(|value| !value.is_empty())
+}
+
+/// Auth token from `--auth-token-file PATH` or `TRAMP_RPC_AUTH_TOKEN`.
+fn auth_token(file: Option<&Path>) -> std::io::Result<Option<Vec<u8>>> {
+    if let Some(path) = file {
+        return auth::token_from_file(path).map(Some);
+    }
+    let token = env_value("TRAMP_RPC_AUTH_TOKEN");
+    if token.is_some() {
+        // SAFETY: nothing else reads the environment yet; the runtime's
+        // worker threads are idle until the first task is spawned.  Dropping
+        // the variable keeps it out of the environment of spawned processes.
+        unsafe { std::env::remove_var("TRAMP_RPC_AUTH_TOKEN") };
+    }
+    Ok(token.map(|t| {
+        use std::os::unix::ffi::OsStringExt;
+        t.into_vec()
+    }))
+}
+
+/// Put `settings` into force.  Refuses to serve at all rather than run,
+/// say, unjailed when a jail was asked for but cannot be set up.
+fn apply(settings: &config::Settings) -> Result<(), String> {
+    let failed =
+        |what: &str, path: &Path, e: std::io::Error| format!("{} {}: {}", what, path.display(), e);
+    if let Some(path) = &settings.log_file {
+        server_log::set(path).map_err(|e| failed("log file", path, e))?;
+    }
+    if let Some(root) = &settings.jail {
+        jail::init(root).map_err(|e| failed("jail", root, e))?;
+    }
+    if let Some(path) = &settings.audit_log {
+        audit::set_log(Some(path)).map_err(|e| failed("audit log", path, e))?;
+    }
+    if let Some(policy) = &settings.env_policy {
+        let allow = settings.env_allowlist.as_ref().map(|list| {
+            list.split(',')
+                .map(str::trim)
+                .filter(|pattern| !pattern.is_empty())
+                .map(String::from)
+                .collect()
+        });
+        env_policy::set(env_policy::Policy::parse(policy.trim(), allow)?);
+    }
+    config::set_read_only(settings.read_only.unwrap_or(false));
+    shutdown::set_idle_timeout(settings.idle_timeout_secs.unwrap_or(0));
+    if let Some(limit) = settings.max_message_size {
+        framing::set_max_frame(limit as usize);
+    }
+    if let Some(limit) = settings.max_read_size {
+        handlers::io::set_max_read_size(limit);
+    }
+    let limits = [
+        ("io", settings.limit_io),
+        ("process", settings.limit_process),
+        ("metadata", settings.limit_metadata),
+    ];
+    limits::configure(
+        &limits
+            .into_iter()
+            .filter_map(|(class, limit)| Some((class.to_string(), limit?)))
+            .collect(),
+    );
+    notifications::set_limits(
+        settings
+            .notify_max_messages_per_sec
+            .unwrap_or(notifications::DEFAULT_MAX_MESSAGES_PER_SEC),
+        settings
+            .notify_max_bytes_per_sec
+            .unwrap_or(notifications::DEFAULT_MAX_BYTES_PER_SEC),
+    );
+    blocking::set_limits(
+        settings
+            .blocking_warn_secs
+            .unwrap_or(blocking::DEFAULT_WARN_SECS),
+        settings.blocking_timeout_secs.unwrap_or(0),
+    );
+    if let Some(capacity) = settings.recent_requests {
+        recent::set_capacity(capacity);
+    }
+    Ok(())
+}
+
 #[tokio::main]
 async fn main() {
+    // Started by sudo as the askpass helper of a `process.run_sudo`
+    if let Some(socket) = std::env::var_os(handlers::sudo::ASKPASS_SOCKET_ENV) {
+        std::process::exit(handlers::sudo::askpass_main(&socket));
+    }
+    // `--help`, and `--version`, with which `system.install_binary` runs a
+    // freshly uploaded server, print and return
+    let loaded = match config::load() {
+        Ok(loaded) => loaded,
+        Err(config::Failure::Display(e)) => {
+            let _ = e.print();
+            return;
+        }
+        Err(config::Failure::Invalid { message, log_file }) => {
+            if let Some(path) = log_file
+                && server_log::set(&path).is_ok()
+            {
+                server_log::error(&message);
+            }
+            std::process::exit(2);
+        }
+    };
+    let settings = loaded.settings;
+    if let Err(message) = apply(&settings) {
+        server_log::error(&message);
+        std::process::exit(2);
+    }
+    config::loaded(loaded.file, loaded.unknown_keys);
+    let token = match auth_token(settings.auth_token_file.as_deref()) {
+        Ok(Some(token)) if token.is_empty() => std::process::exit(2),
+        Ok(token) => token,
+        Err(_) => std::process::exit(2),
+    };
+    // Handler panics are reported to the client as errors; the default hook
+    // would also print them to stderr (see the NOTE below).
+    std::panic::set_hook(Box::new(|_| {}));
+
+    // The server speaks to exactly one client over stdin/stdout.
+    let session = Arc::new(auth::Session::new(token));
+
     let mut stdin = tokio::io::stdin();
     let stdout: WriterHandle = Arc::new(Mutex::new(BufWriter::new(tokio::io::stdout())));
+    let _ = NOTIFICATION_WRITER.set(Arc::clone(&stdout));
 
     // Initialize the filesystem watcher for cache invalidation notifications.
     // If this fails (e.g. inotify not available), we continue without watching.
@@ -36,27 +264,40 @@ async fn main() {
     if let Ok(manager) = watcher::WatchManager::new(Arc::clone(&stdout)) {
         watcher::init(manager);
     }
+    handlers::upload::start_sweeper();
+    if let Some(previous) = settings.restarted_from {
+        reexec::announce(previous).await;
+    }
 
     let mut tasks: JoinSet<()> = JoinSet::new();
 
     // Process requests concurrently
+    let mut idle = false;
     loop {
         // Read 4-byte length prefix (big-endian)
         let mut len_buf = [0u8; 4];
-        if stdin.read_exact(&mut len_buf).await.is_err() {
-            break; // EOF or error
+        let more = tokio::select! {
+            read = read_or_stop(&mut stdin, &mut len_buf) => read,
+            _ = shutdown::idle() => {
+                idle = true;
+                false
+            }
+        };
+        if !more {
+            break; // EOF, error or idle
         }
-        let len = u32::from_be_bytes(len_buf) as usize;
+        let (len, checked) = framing::parse_prefix(len_buf);
+        let trailer = if checked { 4 } else { 0 };
 
         // Sanity check - reject obviously invalid lengths
-        if len > 100 * 1024 * 1024 {
-            // 100MB max message size - drain the payload to keep framing in sync
+        if len > framing::max_frame() {
+            // Over --max-message-size - drain the payload to keep framing in sync
             // (cannot use eprintln! as SSH merges stderr with stdout)
             let mut discard = vec![0u8; 8192];
-            let mut remaining = len;
+            let mut remaining = len + trailer;
             while remaining > 0 {
                 let to_read = remaining.min(discard.len());
-                if stdin.read_exact(&mut discard[..to_read]).await.is_err() {
+                if !read_or_stop(&mut stdin, &mut discard[..to_read]).await {
                     break;
                 }
                 remaining -= to_read;
@@ -66,35 +307,72 @@ async fn main() {
 
         // Read payload
         let mut payload = vec![0u8; len];
-        if stdin.read_exact(&mut payload).await.is_err() {
+        if !read_or_stop(&mut stdin, &mut payload).await {
             break; // EOF or error
         }
+        let mut checksum = [0u8; 4];
+        if checked && !read_or_stop(&mut stdin, &mut checksum).await {
+            break;
+        }
+        if let Err(rejected) = framing::verify(&payload, checked.then_some(checksum)) {
+            if let Some(id) = framing::salvage_id(&payload) {
+                let response = Response::error(Some(id), RpcError::parse_error(rejected.message()));
+                if let Ok(msgpack_bytes) = rmp_serde::to_vec_named(&response) {
+                    let _ = write_frame(&stdout, &msgpack_bytes).await;
+                }
+                continue;
+            }
+            // Nothing read from here on can be trusted
+            let _ = notifications::send_forced(&stdout, &rejected.notification()).await;
+            shutdown::stdout_broken();
+            break;
+        }
 
-        // Clone writer for this task
+        // Clone writer and session for this task
         let writer = Arc::clone(&stdout);
+        let session = Arc::clone(&session);
+        let received = std::time::Instant::now();
+        let running = shutdown::request_started();
 
         // Spawn a task for each request - allows concurrent processing
-        tasks.spawn(async move {
-            let response = process_request(&payload).await;
+        tasks.spawn(timing::scope(received, async move {
+            let _running = running;
+            let response = process_request(&payload, &session).await;
 
-            // Serialize response with MessagePack
+            // Serialize response with MessagePack; a failed write is
+            // recorded by write_frame and ends the read loop
             if let Ok(msgpack_bytes) = rmp_serde::to_vec_named(&response) {
-                let mut writer = writer.lock().await;
-                // Write length prefix
-                let len_bytes = (msgpack_bytes.len() as u32).to_be_bytes();
-                let _ = writer.write_all(&len_bytes).await;
-                // Write payload
-                let _ = writer.write_all(&msgpack_bytes).await;
-                let _ = writer.flush().await;
+                let _ = write_frame(&writer, &msgpack_bytes).await;
             }
-        });
+        }));
+    }
+
+    if shutdown::is_broken() {
+        // Nobody reads the replies any more: give pending requests a moment
+        // to finish what they are doing, then drop them.
+        let drained = async { while tasks.join_next().await.is_some() {} };
+        let _ = tokio::time::timeout(shutdown::DRAIN, drained).await;
+        tasks.shutdown().await;
+        shutdown::cleanup().await;
+        // Returning would wait for the blocking read of stdin, which only
+        // ends when the client closes it, and for stuck blocking operations.
+        std::process::exit(1);
     }
 
     // Wait for all pending tasks to complete before exiting
     while tasks.join_next().await.is_some() {}
+    shutdown::cleanup().await;
+    if idle {
+        server_log::info(&format!(
+            "no request for {}s, exiting",
+            shutdown::idle_timeout_secs()
+        ));
+        // stdin is still open, and returning would wait for its read
+        std::process::exit(0);
+    }
 }
 
-async fn process_request(payload: &[u8]) -> Response {
+async fn process_request(payload: &[u8], session: &auth::Session) -> Response {
     // Parse the request from MessagePack
     let request: Request = match rmp_serde::from_slice(payload) {
         Ok(r) => r,
@@ -111,6 +389,30 @@ async fn process_request(payload: &[u8]) -> Response {
         );
     }
 
+    // Nothing but system.auth is served before the client authenticates
+    if request.method == "system.auth" {
+        let stopwatch = timing::start();
+        let mut response = match session.authenticate(request.params).await {
+            Ok(value) => Response::success(request.id, value),
+            Err(error) => Response::error(Some(request.id), error),
+        };
+        stopwatch.finish(&request.method, &mut response);
+        return response;
+    }
+    if !session.is_authenticated() {
+        return Response::error(
+            Some(request.id),
+            RpcError::auth_required("Authentication required"),
+        );
+    }
+
+    // Needs the token to hand it to the new server
+    if request.method == "system.reexec" {
+        let token = session.token();
+        return handlers::dispatch_with(request, |params| reexec::handle_reexec(params, token))
+            .await;
+    }
+
     // Dispatch to handler
     handlers::dispatch(request).await
 }
@@ -118,6 +420,7 @@ async fn process_request(payload: &[u8]) -> Response {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_util::map_get;
     use rmpv::Value;
 
     fn make_request(method: &str, params: Value) -> Vec<u8> {
@@ -137,13 +440,13 @@ mod tests {
             Value::String("/tmp".into()),
         )]);
         let payload = make_request("file.stat", params);
-        let response = process_request(&payload).await;
+        let response = process_request(&payload, &auth::Session::new(None)).await;
         assert!(response.error.is_none());
     }
 
     #[tokio::test]
     async fn test_invalid_msgpack() {
-        let response = process_request(b"not msgpack").await;
+        let response = process_request(b"not msgpack", &auth::Session::new(None)).await;
         assert!(response.error.is_some());
         assert_eq!(response.error.unwrap().code, RpcError::PARSE_ERROR);
     }
@@ -152,17 +455,59 @@ mod tests {
     async fn test_method_not_found() {
         let params = Value::Map(vec![]);
         let payload = make_request("nonexistent.method", params);
-        let response = process_request(&payload).await;
+        let response = process_request(&payload, &auth::Session::new(None)).await;
         assert!(response.error.is_some());
         assert_eq!(response.error.unwrap().code, RpcError::METHOD_NOT_FOUND);
     }
 
-    fn map_get<'a>(value: &'a Value, key: &str) -> Option<&'a Value> {
-        value.as_map().and_then(|m| {
-            m.iter()
-                .find(|(k, _)| k.as_str() == Some(key))
-                .map(|(_, v)| v)
-        })
+    #[tokio::test]
+    async fn test_reexec_is_recorded_like_other_methods() {
+        let tmp = tempfile::tempdir().unwrap();
+        let missing = tmp.path().join("tramp-rpc-server");
+        // Other tests may have processes open
+        let params = Value::Map(vec![
+            (
+                Value::String("path".into()),
+                Value::String(missing.to_str().unwrap().into()),
+            ),
+            (Value::String("force".into()), Value::Boolean(true)),
+        ]);
+        let payload = make_request("system.reexec", params);
+        let response = process_request(&payload, &auth::Session::new(None)).await;
+        assert_eq!(response.error.unwrap().code, RpcError::FILE_NOT_FOUND);
+
+        let recent = recent::handle_recent_requests(Value::Map(vec![(
+            Value::String("method".into()),
+            Value::String("system.reexec".into()),
+        )]))
+        .unwrap();
+        let recorded = recent["requests"].as_array().unwrap().iter().any(|r| {
+            r["params"]["path"].as_slice() == Some(missing.as_os_str().as_encoded_bytes())
+                && r["code"].as_i64() == Some(RpcError::FILE_NOT_FOUND as i64)
+        });
+        assert!(recorded, "{:?}", recent);
+    }
+
+    #[tokio::test]
+    async fn test_methods_require_auth_until_token_presented() {
+        let session = auth::Session::new(Some(b"token".to_vec()));
+        let info = make_request("system.info", Value::Map(vec![]));
+
+        let response = process_request(&info, &session).await;
+        assert_eq!(response.error.unwrap().code, RpcError::AUTH_REQUIRED);
+
+        let auth = make_request(
+            "system.auth",
+            Value::Map(vec![(
+                Value::String("token".into()),
+                Value::String("token".into()),
+            )]),
+        );
+        let response = process_request(&auth, &session).await;
+        assert!(response.error.is_none());
+
+        let response = process_request(&info, &session).await;
+        assert!(response.error.is_none());
     }
 
     #[tokio::test]
@@ -172,7 +517,7 @@ mod tests {
             (Value::String("cwd".into()), Value::String("/tmp".into())),
         ]);
         let start_payload = make_request("process.start", start_params);
-        let start_response = process_request(&start_payload).await;
+        let start_response = process_request(&start_payload, &auth::Session::new(None)).await;
         assert!(
             start_response.error.is_none(),
             "process.start should not error"
@@ -191,7 +536,10 @@ mod tests {
                 ),
             ]),
         );
-        let read_task = tokio::spawn(async move { process_request(&read_payload).await });
+        let read_task =
+            tokio::spawn(
+                async move { process_request(&read_payload, &auth::Session::new(None)).await },
+            );
 
         // Give the long-polling read request time to enter the handler.  If it
         // holds the global process map lock across the read timeout,
@@ -209,7 +557,7 @@ mod tests {
             ]),
         );
         let start = std::time::Instant::now();
-        let write_response = process_request(&write_payload).await;
+        let write_response = process_request(&write_payload, &auth::Session::new(None)).await;
         let elapsed = start.elapsed();
         assert!(
             write_response.error.is_none(),
@@ -229,7 +577,7 @@ mod tests {
                 (Value::String("signal".into()), Value::Integer(9.into())),
             ]),
         );
-        let _ = process_request(&kill_payload).await;
+        let _ = process_request(&kill_payload, &auth::Session::new(None)).await;
     }
 
     /// Test that process.run returns 128+signal for signal-killed processes.
@@ -249,7 +597,7 @@ mod tests {
             (Value::String("cwd".into()), Value::String("/tmp".into())),
         ]);
         let payload = make_request("process.run", params);
-        let response = process_request(&payload).await;
+        let response = process_request(&payload, &auth::Session::new(None)).await;
         assert!(response.error.is_none(), "process.run should not error");
 
         let result = response.result.expect("should have result");
@@ -280,7 +628,7 @@ mod tests {
             (Value::String("cwd".into()), Value::String("/tmp".into())),
         ]);
         let payload = make_request("process.run", params);
-        let response = process_request(&payload).await;
+        let response = process_request(&payload, &auth::Session::new(None)).await;
         assert!(response.error.is_none(), "process.run should not error");
 
         let result = response.result.expect("should have result");
@@ -310,7 +658,7 @@ mod tests {
             (Value::String("cwd".into()), Value::String("/tmp".into())),
         ]);
         let payload = make_request("process.run", params);
-        let response = process_request(&payload).await;
+        let response = process_request(&payload, &auth::Session::new(None)).await;
         assert!(response.error.is_none(), "process.run should not error");
 
         let result = response.result.expect("should have result");
@@ -371,5 +719,13 @@ mod tests {
             130,
             "SIGINT with core dump should still give 128+2=130"
         );
+        let signal = protocol::ExitSignal::from_status(status).unwrap();
+        assert_eq!(signal.number, 2);
+        assert!(signal.core_dumped);
+        assert_eq!(signal.name(), Some("SIGINT"));
+        assert_eq!(
+            protocol::ExitSignal::from_status(ExitStatus::from_raw(42 << 8)),
+            None
+        );
     }
 }
@@ -0,0 +1,132 @@
+//! The methods that change state, shared by the audit log and read-only mode.
+//!
+//! Every method listed here is audited.  Read-only mode refuses those that
+//! change files, run a program the client names or replace the server; it
+//! still serves the ones that only adjust the server or signal processes
+//! that were already started.  Methods that run a fixed `git` command to
+//! read a repository (`vc.status`, `git.log`, `project.files`, ...) are not
+//! listed.  `file.write_chunk` and `file.write_abort` are not listed either:
+//! they only touch uploads that `file.write_begin` started.
+
+/// What a mutating method changes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Effect {
+    /// Writes, moves or deletes files
+    Files,
+    /// Runs a program that the client names
+    Spawns,
+    /// Replaces the running server
+    Replaces,
+    /// Changes server settings or signals processes already started
+    Server,
+}
+
+const METHODS: &[(&str, Effect)] = &[
+    ("file.write", Effect::Files),
+    ("file.write_autosave", Effect::Files),
+    ("file.write_delta", Effect::Files),
+    ("file.write_begin", Effect::Files),
+    ("file.write_commit", Effect::Files),
+    ("file.copy", Effect::Files),
+    ("file.rename", Effect::Files),
+    ("file.delete", Effect::Files),
+    ("file.rename_batch", Effect::Files),
+    ("file.copy_batch", Effect::Files),
+    ("file.delete_batch", Effect::Files),
+    ("file.set_modes", Effect::Files),
+    ("file.set_flags", Effect::Files),
+    ("file.fallocate", Effect::Files),
+    ("file.lock_claim", Effect::Files),
+    ("file.lock_release", Effect::Files),
+    ("file.set_times", Effect::Files),
+    ("file.make_symlink", Effect::Files),
+    ("file.make_hardlink", Effect::Files),
+    ("file.chown", Effect::Files),
+    ("file.convert_encoding", Effect::Files),
+    ("dir.create", Effect::Files),
+    ("dir.remove", Effect::Files),
+    ("archive.extract", Effect::Files),
+    ("archive.create", Effect::Files),
+    ("network.fetch", Effect::Files),
+    ("system.install_binary", Effect::Files),
+    ("process.run", Effect::Spawns),
+    ("process.run_sudo", Effect::Spawns),
+    ("process.start", Effect::Spawns),
+    ("process.start_pty", Effect::Spawns),
+    ("shell.session_open", Effect::Spawns),
+    ("shell.session_run", Effect::Spawns),
+    ("commands.run_parallel", Effect::Spawns),
+    ("tags.generate", Effect::Spawns),
+    ("system.reexec", Effect::Replaces),
+    ("process.kill", Effect::Server),
+    ("process.kill_pty", Effect::Server),
+    ("process.interrupt_pty", Effect::Server),
+    ("shell.session_close", Effect::Server),
+    ("system.set_env_policy", Effect::Server),
+    ("system.set_audit_log", Effect::Server),
+    ("system.gc", Effect::Server),
+];
+
+/// What `method` changes, or `None` if it only reads.
+pub fn effect(method: &str) -> Option<Effect> {
+    METHODS
+        .iter()
+        .find(|(name, _)| *name == method)
+        .map(|&(_, effect)| effect)
+}
+
+/// Whether read-only mode refuses `method`.
+pub fn refused_when_read_only(method: &str) -> bool {
+    matches!(
+        effect(method),
+        Some(Effect::Files | Effect::Spawns | Effect::Replaces)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_only_refuses_writes_processes_and_reexec() {
+        for method in [
+            "file.write",
+            "file.delete_batch",
+            "system.install_binary",
+            "process.run",
+            "process.start",
+            "process.start_pty",
+            "process.run_sudo",
+            "shell.session_open",
+            "shell.session_run",
+            "commands.run_parallel",
+            "tags.generate",
+            "system.reexec",
+        ] {
+            assert!(refused_when_read_only(method), "{}", method);
+        }
+        for method in [
+            "file.read",
+            "vc.status",
+            "process.kill",
+            "process.read",
+            "shell.session_close",
+            "system.set_env_policy",
+        ] {
+            assert!(!refused_when_read_only(method), "{}", method);
+        }
+        assert_eq!(effect("process.interrupt_pty"), Some(Effect::Server));
+        assert_eq!(effect("file.stat"), None);
+    }
+
+    #[test]
+    fn every_method_is_listed_once() {
+        for (i, (name, _)) in METHODS.iter().enumerate() {
+            assert!(
+                !METHODS[..i].iter().any(|(other, _)| other == name),
+                "{} listed twice",
+                name
+            );
+        }
+    }
+}
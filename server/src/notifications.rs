@@ -0,0 +1,171 @@
+//! Shared sender and rate budget for server-to-client notifications.
+//!
+//! Responses and notifications share one stdout writer, so a burst of
+//! notifications delays every response queued behind it.  All notifications
+//! go through [`send`], which charges them against a one-second budget of
+//! messages and bytes, set with `--notify-max-messages-per-sec` /
+//! `TRAMP_RPC_NOTIFY_MAX_MESSAGES_PER_SEC` and `--notify-max-bytes-per-sec` /
+//! `TRAMP_RPC_NOTIFY_MAX_BYTES_PER_SEC` (0 means unlimited).
+//!
+//! A notification over budget is not written.  The caller either drops it or
+//! sends a smaller replacement with [`send_forced`], which is always written
+//! but still counts against the budget, as the watcher does with `fs.resync`.
+
+use crate::WriterHandle;
+use crate::msgpack_map;
+use crate::protocol::Notification;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Default limit on notifications written per second.
+pub const DEFAULT_MAX_MESSAGES_PER_SEC: u64 = 100;
+
+/// Default limit on notification bytes written per second.
+pub const DEFAULT_MAX_BYTES_PER_SEC: u64 = 1024 * 1024;
+
+static MAX_MESSAGES_PER_SEC: AtomicU64 = AtomicU64::new(DEFAULT_MAX_MESSAGES_PER_SEC);
+static MAX_BYTES_PER_SEC: AtomicU64 = AtomicU64::new(DEFAULT_MAX_BYTES_PER_SEC);
+
+static BUDGET: Mutex<Option<Budget>> = Mutex::new(None);
+
+static SENT: AtomicU64 = AtomicU64::new(0);
+static SENT_BYTES: AtomicU64 = AtomicU64::new(0);
+static OVER_BUDGET: AtomicU64 = AtomicU64::new(0);
+static FORCED: AtomicU64 = AtomicU64::new(0);
+
+/// Set the per-second limits; 0 disables a limit.
+pub fn set_limits(max_messages: u64, max_bytes: u64) {
+    MAX_MESSAGES_PER_SEC.store(max_messages, Ordering::Relaxed);
+    MAX_BYTES_PER_SEC.store(max_bytes, Ordering::Relaxed);
+}
+
+/// Usage within the current one-second window
+#[derive(Debug)]
+struct Budget {
+    start: Instant,
+    messages: u64,
+    bytes: u64,
+}
+
+impl Budget {
+    fn new(now: Instant) -> Self {
+        Self {
+            start: now,
+            messages: 0,
+            bytes: 0,
+        }
+    }
+
+    /// Charge one message of `bytes` at `now`, returning whether it fits
+    /// the limits.  A message that does not fit is only charged if `force`.
+    fn charge(&mut self, now: Instant, bytes: u64, limits: (u64, u64), force: bool) -> bool {
+        if now.duration_since(self.start) >= Duration::from_secs(1) {
+            *self = Self::new(now);
+        }
+        let (max_messages, max_bytes) = limits;
+        let over = (max_messages > 0 && self.messages + 1 > max_messages)
+            || (max_bytes > 0 && self.bytes + bytes > max_bytes);
+        if !over || force {
+            self.messages += 1;
+            self.bytes += bytes;
+        }
+        !over
+    }
+}
+
+fn charge(bytes: u64, force: bool) -> bool {
+    let limits = (
+        MAX_MESSAGES_PER_SEC.load(Ordering::Relaxed),
+        MAX_BYTES_PER_SEC.load(Ordering::Relaxed),
+    );
+    let now = Instant::now();
+    let mut budget = BUDGET.lock().unwrap_or_else(|e| e.into_inner());
+    budget
+        .get_or_insert_with(|| Budget::new(now))
+        .charge(now, bytes, limits, force)
+}
+
+/// Write `notification` if it fits the budget.  Returns `Ok(false)` if it
+/// was held back, and an error if the writer is broken.
+pub async fn send(writer: &WriterHandle, notification: &Notification) -> std::io::Result<bool> {
+    let bytes = rmp_serde::to_vec_named(notification).map_err(std::io::Error::other)?;
+    if !charge(bytes.len() as u64 + 4, false) {
+        OVER_BUDGET.fetch_add(1, Ordering::Relaxed);
+        return Ok(false);
+    }
+    write(writer, &bytes).await?;
+    Ok(true)
+}
+
+/// Write `notification` even if it exceeds the budget.
+pub async fn send_forced(
+    writer: &WriterHandle,
+    notification: &Notification,
+) -> std::io::Result<()> {
+    let bytes = rmp_serde::to_vec_named(notification).map_err(std::io::Error::other)?;
+    if !charge(bytes.len() as u64 + 4, true) {
+        FORCED.fetch_add(1, Ordering::Relaxed);
+    }
+    write(writer, &bytes).await
+}
+
+async fn write(writer: &WriterHandle, bytes: &[u8]) -> std::io::Result<()> {
+    crate::write_frame(writer, bytes).await?;
+    SENT.fetch_add(1, Ordering::Relaxed);
+    SENT_BYTES.fetch_add(bytes.len() as u64 + 4, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Limits, current usage and counters for `system.stats`.
+pub fn stats() -> rmpv::Value {
+    let (messages, bytes) = BUDGET
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .as_ref()
+        .filter(|b| b.start.elapsed() < Duration::from_secs(1))
+        .map_or((0, 0), |b| (b.messages, b.bytes));
+    msgpack_map! {
+        "max_messages_per_sec" => MAX_MESSAGES_PER_SEC.load(Ordering::Relaxed),
+        "max_bytes_per_sec" => MAX_BYTES_PER_SEC.load(Ordering::Relaxed),
+        "window_messages" => messages,
+        "window_bytes" => bytes,
+        "sent" => SENT.load(Ordering::Relaxed),
+        "sent_bytes" => SENT_BYTES.load(Ordering::Relaxed),
+        "over_budget" => OVER_BUDGET.load(Ordering::Relaxed),
+        "forced" => FORCED.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn budget_limits_messages_and_bytes_per_window() {
+        let start = Instant::now();
+        let mut budget = Budget::new(start);
+        let limits = (3, 100);
+
+        assert!(budget.charge(start, 40, limits, false));
+        assert!(budget.charge(start, 40, limits, false));
+        // 120 bytes would exceed the byte limit
+        assert!(!budget.charge(start, 40, limits, false));
+        assert!(budget.charge(start, 20, limits, false));
+        // A fourth message exceeds the message limit; forcing charges it anyway
+        assert!(!budget.charge(start, 0, limits, false));
+        assert!(!budget.charge(start, 0, limits, true));
+        assert_eq!((budget.messages, budget.bytes), (4, 100));
+
+        // A new window starts afresh
+        let later = start + Duration::from_secs(1);
+        assert!(budget.charge(later, 100, limits, false));
+        assert_eq!((budget.messages, budget.bytes), (1, 100));
+
+        // Zero disables a limit
+        let mut unlimited = Budget::new(start);
+        for _ in 0..1000 {
+            assert!(unlimited.charge(start, 1 << 20, (0, 0), false));
+        }
+    }
+}
@@ -2,6 +2,7 @@
 
 use rmpv::Value;
 use serde::{Deserialize, Serialize};
+use std::path::Path;
 
 /// Default empty params (nil/null)
 fn default_params() -> Value {
@@ -16,6 +17,10 @@ pub struct Request {
     pub method: String,
     #[serde(default = "default_params")]
     pub params: Value,
+    /// Opaque value echoed in notifications about resources the request
+    /// creates; see `trace`
+    #[serde(default)]
+    pub trace: Option<Value>,
 }
 
 /// Request ID can be a number or string
@@ -36,6 +41,10 @@ pub struct Response {
     pub result: Option<Value>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub error: Option<RpcError>,
+    /// `{queued_us, handler_us}` with the `timing` feature; see
+    /// [`crate::timing`]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timing: Option<Value>,
 }
 
 impl Response {
@@ -45,6 +54,7 @@ impl Response {
             id: Some(id),
             result: Some(result.into()),
             error: None,
+            timing: None,
         }
     }
 
@@ -54,6 +64,7 @@ impl Response {
             id,
             result: None,
             error: Some(error),
+            timing: None,
         }
     }
 }
@@ -80,6 +91,42 @@ impl RpcError {
     pub const PERMISSION_DENIED: i32 = -32002;
     pub const IO_ERROR: i32 = -32003;
     pub const PROCESS_ERROR: i32 = -32004;
+    /// The target changed or vanished since the client last observed it
+    pub const CONFLICT: i32 = -32005;
+    /// The path resolves outside the directory the server is jailed to
+    pub const ACCESS_DENIED: i32 = -32006;
+    /// The connection has not presented the server's auth token yet
+    pub const AUTH_REQUIRED: i32 = -32007;
+    /// A whole-file read exceeds the server's maximum read size
+    pub const FILE_TOO_LARGE: i32 = -32008;
+    /// A ranged read found the file changed since the fingerprint the
+    /// client expected
+    pub const STALE_FILE: i32 = -32009;
+    /// An archive could not be parsed
+    pub const INVALID_ARCHIVE: i32 = -32010;
+    /// Text could not be converted between character encodings
+    pub const ENCODING_ERROR: i32 = -32011;
+    /// A download failed: a transport error, an HTTP error status, or a
+    /// checksum mismatch
+    pub const NETWORK_ERROR: i32 = -32012;
+    /// A copied file did not match its source when verified
+    pub const VERIFY_FAILED: i32 = -32013;
+    /// A read was refused because the path is a FIFO, socket or device
+    pub const NOT_REGULAR_FILE: i32 = -32014;
+    /// A write was refused up front because its filesystem lacks the space
+    /// or inodes it asked for
+    pub const NO_SPACE: i32 = -32015;
+    /// A blocking operation ran past `--blocking-timeout-secs`; it may still
+    /// be running
+    pub const TIMEOUT: i32 = -32016;
+    /// A `no_wait` request found its class of requests at its concurrency
+    /// limit
+    pub const BUSY: i32 = -32017;
+    /// The request was stopped by `rpc.cancel`
+    pub const CANCELLED: i32 = -32018;
+    /// The filesystem or platform cannot do the operation; the client may
+    /// fall back to another way
+    pub const UNSUPPORTED: i32 = -32019;
 
     pub fn parse_error(msg: impl Into<String>) -> Self {
         Self {
@@ -121,22 +168,230 @@ impl RpcError {
         }
     }
 
-    pub fn file_not_found(path: &str) -> Self {
+    pub fn file_not_found(path: impl AsRef<Path>) -> Self {
+        let path = path.as_ref();
         Self {
             code: Self::FILE_NOT_FOUND,
-            message: format!("File not found: {}", path),
-            data: None,
+            message: format!("File not found: {}", path.display()),
+            data: Some(errno_and_path(libc::ENOENT, path)),
+        }
+    }
+
+    /// A follow-stat of a dangling symlink.  This is still a
+    /// `FILE_NOT_FOUND` (ENOENT) error, but the data carries the link's own
+    /// `lstat` attributes.
+    pub fn broken_symlink(path: &str, lstat: &FileAttributes) -> Self {
+        Self {
+            code: Self::FILE_NOT_FOUND,
+            message: format!("Broken symlink: {}", path),
+            data: Some(Value::Map(vec![
+                (
+                    Value::String("os_errno".into()),
+                    Value::Integer(libc::ENOENT.into()),
+                ),
+                (Value::String("broken_symlink".into()), Value::Boolean(true)),
+                (Value::String("lstat".into()), lstat.to_value()),
+            ])),
         }
     }
 
-    pub fn permission_denied(path: &str) -> Self {
+    pub fn permission_denied(path: impl AsRef<Path>) -> Self {
+        let path = path.as_ref();
         Self {
             code: Self::PERMISSION_DENIED,
-            message: format!("Permission denied: {}", path),
+            message: format!("Permission denied: {}", path.display()),
+            data: Some(errno_and_path(libc::EACCES, path)),
+        }
+    }
+
+    pub fn access_denied(path: &str) -> Self {
+        Self {
+            code: Self::ACCESS_DENIED,
+            message: format!("Access denied (outside jail): {}", path),
+            data: None,
+        }
+    }
+
+    pub fn auth_required(msg: impl Into<String>) -> Self {
+        Self {
+            code: Self::AUTH_REQUIRED,
+            message: msg.into(),
+            data: None,
+        }
+    }
+
+    /// `size` is `None` for files whose size is not known up front (pipes,
+    /// procfs), which are only known to exceed `limit`.
+    pub fn file_too_large(path: &str, size: Option<u64>, limit: u64) -> Self {
+        let message = match size {
+            Some(size) => format!("File too large ({} bytes, limit {}): {}", size, limit, path),
+            None => format!("File too large (over limit {}): {}", limit, path),
+        };
+        let mut data = vec![(Value::String("limit".into()), Value::from(limit))];
+        if let Some(size) = size {
+            data.push((Value::String("size".into()), Value::from(size)));
+        }
+        Self {
+            code: Self::FILE_TOO_LARGE,
+            message,
+            data: Some(Value::Map(data)),
+        }
+    }
+
+    /// `fingerprint` is the file's current fingerprint, for the client to
+    /// restart from.
+    pub fn stale_file(path: &str, fingerprint: &str) -> Self {
+        Self {
+            code: Self::STALE_FILE,
+            message: format!("File changed during ranged read: {}", path),
+            data: Some(Value::Map(vec![(
+                Value::String("fingerprint".into()),
+                Value::String(fingerprint.into()),
+            )])),
+        }
+    }
+
+    /// `file_type` is the type the path turned out to have, as in `file.stat`.
+    pub fn not_regular_file(path: &str, file_type: FileType) -> Self {
+        Self {
+            code: Self::NOT_REGULAR_FILE,
+            message: format!("Not a regular file ({}): {}", file_type.as_str(), path),
+            data: Some(Value::Map(vec![(
+                Value::String("type".into()),
+                Value::String(file_type.as_str().into()),
+            )])),
+        }
+    }
+
+    /// `elapsed` is how long the operation on `path` had run when the
+    /// caller stopped waiting for it.
+    pub fn timeout(path: &Path, elapsed: std::time::Duration) -> Self {
+        Self {
+            code: Self::TIMEOUT,
+            message: format!(
+                "Timed out after {} ms: {}",
+                elapsed.as_millis(),
+                path.display()
+            ),
+            data: Some(Value::Map(vec![
+                (Value::String("path".into()), path_value(path)),
+                (
+                    Value::String("elapsed_ms".into()),
+                    Value::from(elapsed.as_millis() as u64),
+                ),
+            ])),
+        }
+    }
+
+    /// `class` is the class of requests that is full, running `in_use` of
+    /// its `limit`.
+    pub fn busy(class: &str, limit: usize, in_use: usize) -> Self {
+        Self {
+            code: Self::BUSY,
+            message: format!(
+                "Too many {} requests running ({} of {})",
+                class, in_use, limit
+            ),
+            data: Some(Value::Map(vec![
+                (Value::String("class".into()), Value::from(class)),
+                (Value::String("limit".into()), Value::from(limit as u64)),
+                (Value::String("in_use".into()), Value::from(in_use as u64)),
+            ])),
+        }
+    }
+
+    /// `method` is the request that stopped; handlers add what it had done.
+    pub fn cancelled(method: &str) -> Self {
+        Self {
+            code: Self::CANCELLED,
+            message: format!("{} was cancelled", method),
+            data: None,
+        }
+    }
+
+    pub fn unsupported(operation: &str, path: &str) -> Self {
+        Self {
+            code: Self::UNSUPPORTED,
+            message: format!("{} is unsupported on this filesystem: {}", operation, path),
+            data: Some(Value::Map(vec![(
+                Value::String("os_errno".into()),
+                Value::Integer(libc::EOPNOTSUPP.into()),
+            )])),
+        }
+    }
+
+    /// `required` and `available` are in bytes; `shortfall` in the data is
+    /// how many more bytes are needed, 0 when only inodes ran out.
+    pub fn no_space(path: &str, required: u64, available: u64, inodes_available: u64) -> Self {
+        let shortfall = required.saturating_sub(available);
+        let message = if shortfall > 0 {
+            format!(
+                "Not enough free space ({} bytes needed, {} available, {} short): {}",
+                required, available, shortfall, path
+            )
+        } else {
+            format!("No free inodes: {}", path)
+        };
+        Self {
+            code: Self::NO_SPACE,
+            message,
+            data: Some(Value::Map(vec![
+                (Value::String("required".into()), Value::from(required)),
+                (Value::String("available".into()), Value::from(available)),
+                (Value::String("shortfall".into()), Value::from(shortfall)),
+                (
+                    Value::String("inodes_available".into()),
+                    Value::from(inodes_available),
+                ),
+            ])),
+        }
+    }
+
+    /// `data` holds what was compared: `{src_size, dest_size}` or
+    /// `{src_sha256, dest_sha256}`, and whether the destination was removed.
+    pub fn verify_failed(path: &str, data: Value) -> Self {
+        Self {
+            code: Self::VERIFY_FAILED,
+            message: format!("Copy verification failed: {}", path),
+            data: Some(data),
+        }
+    }
+
+    /// `offset` is where parsing failed; for compressed tars, an offset into
+    /// the decompressed stream.
+    pub fn invalid_archive(path: &str, format: &str, offset: u64, msg: &str) -> Self {
+        Self {
+            code: Self::INVALID_ARCHIVE,
+            message: format!(
+                "Invalid {} archive at offset {}: {}: {}",
+                format, offset, msg, path
+            ),
+            data: Some(Value::Map(vec![
+                (Value::String("format".into()), Value::String(format.into())),
+                (Value::String("offset".into()), Value::from(offset)),
+            ])),
+        }
+    }
+
+    pub fn encoding_error(msg: impl Into<String>) -> Self {
+        Self {
+            code: Self::ENCODING_ERROR,
+            message: msg.into(),
             data: None,
         }
     }
 
+    /// `status` is the HTTP status, for responses that were errors.
+    pub fn network_error(msg: impl Into<String>, status: Option<u16>) -> Self {
+        Self {
+            code: Self::NETWORK_ERROR,
+            message: msg.into(),
+            data: status.map(|status| {
+                Value::Map(vec![(Value::String("status".into()), Value::from(status))])
+            }),
+        }
+    }
+
     pub fn process_error(msg: impl Into<String>) -> Self {
         Self {
             code: Self::PROCESS_ERROR,
@@ -145,6 +400,14 @@ impl RpcError {
         }
     }
 
+    pub fn conflict(msg: impl Into<String>) -> Self {
+        Self {
+            code: Self::CONFLICT,
+            message: msg.into(),
+            data: None,
+        }
+    }
+
     pub fn io_error(err: std::io::Error) -> Self {
         // Include the raw OS errno in the data field so clients can
         // match on it structurally rather than parsing the message text.
@@ -160,6 +423,42 @@ impl RpcError {
             data,
         }
     }
+
+    /// Set `key` in the error data, turning missing data into a map.  Data
+    /// that is not a map is left alone.
+    pub fn with_data(mut self, key: &str, value: Value) -> Self {
+        match self.data {
+            None => self.data = Some(Value::Map(vec![(key.into(), value)])),
+            Some(Value::Map(ref mut pairs)) => {
+                match pairs.iter_mut().find(|(k, _)| k.as_str() == Some(key)) {
+                    Some((_, old)) => *old = value,
+                    None => pairs.push((key.into(), value)),
+                }
+            }
+            Some(_) => {}
+        }
+        self
+    }
+
+    /// Whether the error data has `key`
+    pub fn has_data(&self, key: &str) -> bool {
+        matches!(&self.data, Some(Value::Map(pairs)) if pairs.iter().any(|(k, _)| k.as_str() == Some(key)))
+    }
+}
+
+/// A path as error data: the raw bytes, which survive non-UTF-8 names
+pub fn path_value(path: &Path) -> Value {
+    Value::Binary(path.as_os_str().as_encoded_bytes().to_vec())
+}
+
+fn errno_and_path(errno: i32, path: &Path) -> Value {
+    Value::Map(vec![
+        (
+            Value::String("os_errno".into()),
+            Value::Integer(errno.into()),
+        ),
+        (Value::String("path".into()), path_value(path)),
+    ])
 }
 
 /// Server-initiated notification (no id, no response expected)
@@ -169,6 +468,10 @@ pub struct Notification {
     pub version: String,
     pub method: String,
     pub params: Value,
+    /// `{trace, resource, id}` of the resource the notification is about,
+    /// if the request that created it had a trace
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub context: Option<Value>,
 }
 
 impl Notification {
@@ -177,8 +480,15 @@ impl Notification {
             version: "2.0".to_string(),
             method: method.into(),
             params,
+            context: None,
         }
     }
+
+    /// Set the context, as made by [`crate::trace::context`].
+    pub fn with_context(mut self, context: Option<Value>) -> Self {
+        self.context = context;
+        self
+    }
 }
 
 // ============================================================================
@@ -186,8 +496,7 @@ impl Notification {
 // ============================================================================
 
 /// File type enumeration
-#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
-#[serde(rename_all = "lowercase")]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum FileType {
     File,
     Directory,
@@ -216,10 +525,9 @@ impl FileType {
 }
 
 /// File attributes (similar to Emacs file-attributes)
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone)]
 pub struct FileAttributes {
     /// File type
-    #[serde(rename = "type")]
     pub file_type: FileType,
     /// Number of hard links
     pub nlinks: u64,
@@ -228,10 +536,8 @@ pub struct FileAttributes {
     /// Group ID
     pub gid: u32,
     /// User name (resolved from uid)
-    #[serde(skip_serializing_if = "Option::is_none")]
     pub uname: Option<String>,
     /// Group name (resolved from gid)
-    #[serde(skip_serializing_if = "Option::is_none")]
     pub gname: Option<String>,
     /// Last access time (seconds since epoch)
     pub atime: i64,
@@ -248,8 +554,15 @@ pub struct FileAttributes {
     /// Device number
     pub dev: u64,
     /// Symlink target as raw bytes (if symlink)
-    #[serde(skip_serializing_if = "Option::is_none")]
     pub link_target: Option<Vec<u8>>,
+    /// Creation time (seconds since epoch), where the filesystem records it
+    pub btime: Option<i64>,
+    /// Nanosecond part of `btime`
+    pub btime_nsec: Option<u32>,
+    /// File flags: BSD `st_flags` on macOS and FreeBSD; on Linux the
+    /// immutable, append-only and no-dump `FS_*_FL` bits, where statx
+    /// reports them
+    pub flags: Option<u32>,
 }
 
 impl FileAttributes {
@@ -311,31 +624,60 @@ impl FileAttributes {
                 Value::Binary(link_target.clone()),
             ));
         }
+        if let Some(btime) = self.btime {
+            pairs.push((Value::String("btime".into()), Value::Integer(btime.into())));
+        }
+        if let Some(btime_nsec) = self.btime_nsec {
+            pairs.push((
+                Value::String("btime_nsec".into()),
+                Value::Integer(btime_nsec.into()),
+            ));
+        }
+        if let Some(flags) = self.flags {
+            pairs.push((Value::String("flags".into()), Value::Integer(flags.into())));
+        }
 
         Value::Map(pairs)
     }
 }
 
 /// Directory entry - filenames are now raw bytes (binary in MessagePack)
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug)]
 pub struct DirEntry {
     /// Filename as raw bytes (MessagePack bin type handles non-UTF8)
-    #[serde(with = "serde_bytes")]
     pub name: Vec<u8>,
-    #[serde(rename = "type")]
     pub file_type: FileType,
-    #[serde(skip_serializing_if = "Option::is_none")]
     pub attrs: Option<FileAttributes>,
+    /// What a symlink entry points to; `None` for other entries
+    pub target_type: Option<TargetType>,
+    /// Attributes of the symlink target (`resolve_symlinks: "full"`)
+    pub target_attrs: Option<FileAttributes>,
+}
+
+/// Type of the file a symlink resolves to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TargetType {
+    Resolved(FileType),
+    /// The link is dangling (or loops)
+    Missing,
+}
+
+impl TargetType {
+    /// Return the string representation of this target type.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TargetType::Resolved(file_type) => file_type.as_str(),
+            TargetType::Missing => "missing",
+        }
+    }
 }
 
 impl DirEntry {
-    /// Convert to a MessagePack Value with named fields
-    pub fn to_value(&self) -> Value {
+    /// Convert to a MessagePack Value with named fields, the name encoded
+    /// as `names`
+    pub fn to_value(&self, names: NamesAs) -> Value {
         let mut pairs: Vec<(Value, Value)> = vec![
-            (
-                Value::String("name".into()),
-                Value::Binary(self.name.clone()),
-            ),
+            (Value::String("name".into()), names.encode(&self.name)),
             (
                 Value::String("type".into()),
                 Value::String(self.file_type.as_str().into()),
@@ -346,10 +688,73 @@ impl DirEntry {
             pairs.push((Value::String("attrs".into()), attrs.to_value()));
         }
 
+        if let Some(target_type) = self.target_type {
+            pairs.push((
+                Value::String("target_type".into()),
+                Value::String(target_type.as_str().into()),
+            ));
+        }
+
+        if let Some(ref attrs) = self.target_attrs {
+            pairs.push((Value::String("target_attrs".into()), attrs.to_value()));
+        }
+
         Value::Map(pairs)
     }
 }
 
+/// Why a listing stopped before it had everything
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Truncation {
+    /// The result reached the number of entries asked for
+    Limit,
+    /// The result ran out of time
+    Timeout,
+}
+
+impl Truncation {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Truncation::Limit => "limit",
+            Truncation::Timeout => "timeout",
+        }
+    }
+}
+
+/// The result of a list-like method that can be cut short.
+///
+/// Clients that negotiated the "list_envelope" feature get
+/// `{entries, total, truncated, reason}`; `total` is the number of entries
+/// there were in all, when the method knows it.  Methods whose results are
+/// maps keep their other keys next to these.
+#[derive(Debug)]
+pub struct Listing {
+    pub entries: Vec<Value>,
+    pub total: Option<u64>,
+    pub truncation: Option<Truncation>,
+}
+
+impl IntoValue for Listing {
+    fn into_value(self) -> Value {
+        Value::Map(vec![
+            (Value::String("entries".into()), Value::Array(self.entries)),
+            (
+                Value::String("total".into()),
+                self.total.map_or(Value::Nil, Value::from),
+            ),
+            (
+                Value::String("truncated".into()),
+                Value::Boolean(self.truncation.is_some()),
+            ),
+            (
+                Value::String("reason".into()),
+                self.truncation
+                    .map_or(Value::Nil, |reason| Value::from(reason.as_str())),
+            ),
+        ])
+    }
+}
+
 // ============================================================================
 // Process operation types
 // ============================================================================
@@ -358,6 +763,9 @@ impl DirEntry {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ProcessResult {
     pub exit_code: i32,
+    /// The signal that ended the process, if one did
+    #[serde(skip)]
+    pub signal: Option<ExitSignal>,
     /// stdout content as raw bytes
     #[serde(with = "serde_bytes")]
     pub stdout: Vec<u8>,
@@ -369,21 +777,128 @@ pub struct ProcessResult {
 impl ProcessResult {
     /// Convert to a MessagePack Value with named fields
     pub fn to_value(&self) -> Value {
-        Value::Map(vec![
+        self.to_value_as(OutputAs::Auto)
+    }
+
+    /// Like `to_value`, with stdout and stderr sent as `output_as` says
+    pub fn to_value_as(&self, output_as: OutputAs) -> Value {
+        let value = Value::Map(vec![
             (
                 Value::String("exit_code".into()),
                 Value::Integer(self.exit_code.into()),
             ),
             (
                 Value::String("stdout".into()),
-                Value::Binary(self.stdout.clone()),
+                output_as.encode(self.stdout.clone()),
             ),
             (
                 Value::String("stderr".into()),
-                Value::Binary(self.stderr.clone()),
+                output_as.encode(self.stderr.clone()),
             ),
-        ])
+        ]);
+        with_signal(value, self.signal)
+    }
+}
+
+/// How process output is sent: the `output_as` parameter of `process.run`,
+/// `process.read` and `process.read_pty`.  One mode applies to every stream
+/// of a response.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum OutputAs {
+    /// The server's default, which is binary
+    #[default]
+    Auto,
+    /// A UTF-8 string, with invalid bytes replaced by U+FFFD
+    TextLossy,
+    /// MessagePack binary
+    Binary,
+}
+
+impl OutputAs {
+    pub fn parse(name: Option<&str>) -> Result<Self, RpcError> {
+        match name {
+            None | Some("auto") => Ok(Self::Auto),
+            Some("text_lossy") => Ok(Self::TextLossy),
+            Some("binary") => Ok(Self::Binary),
+            Some(other) => Err(RpcError::invalid_params(format!(
+                "output_as must be \"auto\", \"text_lossy\" or \"binary\", got \"{}\"",
+                other
+            ))),
+        }
+    }
+
+    /// `bytes` as this mode sends them
+    pub fn encode(self, bytes: Vec<u8>) -> Value {
+        match self {
+            Self::Auto | Self::Binary => Value::Binary(bytes),
+            Self::TextLossy => match String::from_utf8(bytes) {
+                Ok(text) => Value::String(text.into()),
+                Err(e) => Value::String(String::from_utf8_lossy(e.as_bytes()).into_owned().into()),
+            },
+        }
+    }
+}
+
+/// A signal that ended a process
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ExitSignal {
+    pub number: i32,
+    /// Whether the process dumped core (WCOREDUMP)
+    pub core_dumped: bool,
+}
+
+impl ExitSignal {
+    /// The signal that ended a process with `status`, if one did.
+    pub fn from_status(status: std::process::ExitStatus) -> Option<Self> {
+        use std::os::unix::process::ExitStatusExt;
+
+        if status.code().is_some() {
+            return None;
+        }
+        if let Some(number) = status.signal() {
+            return Some(Self {
+                number,
+                core_dumped: status.core_dumped(),
+            });
+        }
+
+        // Fallback: parse the raw wait status directly.
+        // This handles edge cases where signal() might return None
+        // despite the process being killed by a signal (observed on
+        // some platforms/configurations).
+        let raw = status.into_raw();
+        let termsig = raw & 0x7f;
+        // WIFSIGNALED: terminated by signal
+        (termsig != 0 && termsig != 0x7f).then_some(Self {
+            number: termsig,
+            core_dumped: raw & 0x80 != 0,
+        })
+    }
+
+    /// The signal's name, such as "SIGKILL"
+    pub fn name(self) -> Option<&'static str> {
+        nix::sys::signal::Signal::try_from(self.number)
+            .ok()
+            .map(|signal| signal.as_str())
+    }
+}
+
+/// Add the `signal`, `signal_name` and `core_dumped` fields to the process
+/// result map `value`: the number and name of the signal that ended the
+/// process and whether it dumped core, or nil, nil and false if no signal
+/// did.  The `exit_code` of a process ended by a signal stays 128 + signal.
+pub fn with_signal(mut value: Value, signal: Option<ExitSignal>) -> Value {
+    if let Value::Map(fields) = &mut value {
+        let number = signal.map_or(Value::Nil, |s| Value::from(s.number));
+        let name = signal
+            .and_then(ExitSignal::name)
+            .map_or(Value::Nil, Value::from);
+        let core_dumped = signal.is_some_and(|s| s.core_dumped);
+        fields.push((Value::from("signal"), number));
+        fields.push((Value::from("signal_name"), name));
+        fields.push((Value::from("core_dumped"), Value::from(core_dumped)));
     }
+    value
 }
 
 // ============================================================================
@@ -493,29 +1008,11 @@ pub fn exit_code_from_status(status: std::process::ExitStatus) -> i32 {
         return code;
     }
 
-    // Signal termination (Unix only)
-    #[cfg(unix)]
-    {
-        use std::os::unix::process::ExitStatusExt;
-
-        // Primary: use signal() API
-        if let Some(sig) = status.signal() {
-            return 128 + sig;
-        }
-
-        // Fallback: parse the raw wait status directly.
-        // This handles edge cases where signal() might return None
-        // despite the process being killed by a signal (observed on
-        // some platforms/configurations).
-        let raw = status.into_raw();
-        let termsig = raw & 0x7f;
-        if termsig != 0 && termsig != 0x7f {
-            // WIFSIGNALED: terminated by signal
-            return 128 + termsig;
-        }
+    // Signal termination
+    match ExitSignal::from_status(status) {
+        Some(signal) => 128 + signal.number,
+        None => -1,
     }
-
-    -1
 }
 
 /// Helper to deserialize from rmpv::Value to a typed struct
@@ -546,3 +1043,72 @@ pub mod path_or_bytes {
         }
     }
 }
+
+/// A string or binary parameter as raw bytes, like [`path_or_bytes`], for
+/// places a `with` attribute cannot reach: `Option`s, `Vec`s and map values.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(transparent)]
+pub struct PathBytes(#[serde(with = "path_or_bytes")] pub Vec<u8>);
+
+impl AsRef<std::ffi::OsStr> for PathBytes {
+    fn as_ref(&self) -> &std::ffi::OsStr {
+        use std::os::unix::ffi::OsStrExt;
+        std::ffi::OsStr::from_bytes(&self.0)
+    }
+}
+
+/// An environment variable value in a request: a string, binary, or the
+/// `{value, binary: true}` map `system.getenv` returns for a value that is
+/// not valid UTF-8, so such a value can be passed back as it came.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum EnvValue {
+    Plain(PathBytes),
+    Tagged { value: PathBytes },
+}
+
+impl EnvValue {
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            EnvValue::Plain(value) | EnvValue::Tagged { value } => &value.0,
+        }
+    }
+}
+
+impl AsRef<std::ffi::OsStr> for EnvValue {
+    fn as_ref(&self) -> &std::ffi::OsStr {
+        use std::os::unix::ffi::OsStrExt;
+        std::ffi::OsStr::from_bytes(self.as_bytes())
+    }
+}
+
+/// How file names and paths are encoded in results (`names_as`)
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum NamesAs {
+    /// Always msgpack binary
+    #[default]
+    Binary,
+    /// A msgpack string when the bytes are valid UTF-8, binary otherwise
+    Auto,
+}
+
+impl NamesAs {
+    pub fn parse(value: Option<&str>) -> Result<Self, RpcError> {
+        match value {
+            None | Some("binary") => Ok(Self::Binary),
+            Some("auto") => Ok(Self::Auto),
+            Some(other) => Err(RpcError::invalid_params(format!(
+                "names_as must be \"binary\" or \"auto\", got \"{}\"",
+                other
+            ))),
+        }
+    }
+
+    /// Encode the name or path `bytes`.
+    pub fn encode(self, bytes: &[u8]) -> Value {
+        match (self, std::str::from_utf8(bytes)) {
+            (Self::Auto, Ok(text)) => Value::String(text.into()),
+            _ => Value::Binary(bytes.to_vec()),
+        }
+    }
+}
@@ -0,0 +1,455 @@
+//! Disk quotas of the filesystem holding a path.
+//!
+//! `system.quota` reports the user and group quotas of the calling user on
+//! the filesystem containing a path, so a write refused with plenty of
+//! space free (as `system.statvfs` shows it) can be explained.  On Linux
+//! the quotas are read with `quotactl(2)` on the filesystem's device, using
+//! the XFS quota interface on XFS.  Other systems report "unsupported".
+//!
+//! Failed writes consult the same state: an `EDQUOT` or `ENOSPC` error on a
+//! filesystem whose quota is exceeded carries that quota in its error data.
+
+use crate::msgpack_map;
+use crate::protocol::{RpcError, from_value, path_or_bytes};
+use rmpv::Value;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The kinds of quota reported
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    User,
+    Group,
+}
+
+impl Kind {
+    fn name(self) -> &'static str {
+        match self {
+            Kind::User => "user",
+            Kind::Group => "group",
+        }
+    }
+
+    /// The effective id the quota of this kind applies to
+    fn id(self) -> u32 {
+        match self {
+            Kind::User => unsafe { libc::geteuid() },
+            Kind::Group => unsafe { libc::getegid() },
+        }
+    }
+}
+
+/// Usage and limits of one resource; a limit of 0 means none
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+struct Limits {
+    used: u64,
+    soft: u64,
+    hard: u64,
+    /// When the grace period over the soft limit ends, in seconds since the
+    /// epoch, or 0 when not over it
+    grace_expires: i64,
+}
+
+impl Limits {
+    /// Whether more of the resource would be refused at `now`.
+    fn exceeded(&self, now: i64) -> bool {
+        (self.hard != 0 && self.used >= self.hard)
+            || (self.soft != 0
+                && self.used > self.soft
+                && self.grace_expires != 0
+                && self.grace_expires <= now)
+    }
+
+    fn to_value(self) -> Value {
+        let limit = |limit: u64| if limit == 0 { Value::Nil } else { limit.into() };
+        msgpack_map! {
+            "used" => self.used,
+            "soft" => limit(self.soft),
+            "hard" => limit(self.hard),
+            "grace_expires" => if self.grace_expires == 0 {
+                Value::Nil
+            } else {
+                self.grace_expires.into()
+            }
+        }
+    }
+}
+
+/// The outcome of querying one quota
+#[derive(Debug, PartialEq)]
+enum Quota {
+    /// Block usage in bytes and inode usage in files
+    Active {
+        blocks: Limits,
+        inodes: Limits,
+    },
+    /// Quotas of this kind are not enabled on the filesystem
+    None,
+    /// The filesystem or system has no quotas we can read
+    Unsupported,
+    Error(String),
+}
+
+impl Quota {
+    fn exceeded(&self, now: i64) -> bool {
+        match self {
+            Quota::Active { blocks, inodes } => blocks.exceeded(now) || inodes.exceeded(now),
+            _ => false,
+        }
+    }
+
+    fn to_value(&self, kind: Kind, now: i64) -> Value {
+        let id = kind.id();
+        match self {
+            Quota::Active { blocks, inodes } => msgpack_map! {
+                "status" => "ok",
+                "id" => id,
+                "blocks" => blocks.to_value(),
+                "inodes" => inodes.to_value(),
+                "exceeded" => self.exceeded(now)
+            },
+            Quota::None => msgpack_map! { "status" => "none", "id" => id },
+            Quota::Unsupported => msgpack_map! { "status" => "unsupported", "id" => id },
+            Quota::Error(message) => msgpack_map! {
+                "status" => "error",
+                "id" => id,
+                "message" => message.as_str()
+            },
+        }
+    }
+}
+
+/// A mounted filesystem, from `/proc/self/mountinfo`
+#[derive(Debug, Clone, PartialEq)]
+struct Mount {
+    point: PathBuf,
+    fs_type: String,
+    source: String,
+}
+
+/// Unescape the octal escapes (`\040`) mountinfo uses for spaces and the
+/// like.
+fn unescape_mount_field(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\'
+            && let Some(octal) = bytes.get(i + 1..i + 4)
+            && let Ok(byte) = u8::from_str_radix(&String::from_utf8_lossy(octal), 8)
+        {
+            out.push(byte);
+            i += 4;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// The mount of device `major:minor` that contains `path`: the last one
+/// mounted, preferring one whose mount point is an ancestor of `path`.
+fn find_mount(mountinfo: &str, major: u32, minor: u32, path: &Path) -> Option<Mount> {
+    let device = format!("{}:{}", major, minor);
+    let mut found: Option<Mount> = None;
+    for line in mountinfo.lines() {
+        let fields: Vec<&str> = line.split(' ').collect();
+        let Some(separator) = fields.iter().position(|&f| f == "-") else {
+            continue;
+        };
+        if fields.get(2) != Some(&device.as_str()) || fields.len() < separator + 3 {
+            continue;
+        }
+        let mount = Mount {
+            point: PathBuf::from(unescape_mount_field(fields[4])),
+            fs_type: fields[separator + 1].to_string(),
+            source: unescape_mount_field(fields[separator + 2]),
+        };
+        let contains = path.starts_with(&mount.point);
+        if contains || found.as_ref().is_none_or(|m| !path.starts_with(&m.point)) {
+            found = Some(mount);
+        }
+    }
+    found
+}
+
+/// The nearest ancestor of `path` (or `path` itself) that exists, so the
+/// quota for a file that could not be created can still be found.
+fn existing_ancestor(path: &Path) -> Option<PathBuf> {
+    path.ancestors()
+        .find(|p| !p.as_os_str().is_empty() && std::fs::symlink_metadata(p).is_ok())
+        .and_then(|p| std::fs::canonicalize(p).ok())
+}
+
+#[cfg(target_os = "linux")]
+fn mount_of(path: &Path) -> std::io::Result<Mount> {
+    use std::os::unix::fs::MetadataExt;
+    let dev = std::fs::metadata(path)?.dev();
+    let mountinfo = std::fs::read_to_string("/proc/self/mountinfo")?;
+    find_mount(&mountinfo, libc::major(dev), libc::minor(dev), path)
+        .ok_or_else(|| std::io::Error::other("No mount found"))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn mount_of(_path: &Path) -> std::io::Result<Mount> {
+    Err(std::io::Error::from(std::io::ErrorKind::Unsupported))
+}
+
+/// `struct fs_disk_quota` from `<linux/dqblk_xfs.h>`
+#[cfg(target_os = "linux")]
+#[repr(C)]
+#[derive(Default)]
+struct FsDiskQuota {
+    d_version: i8,
+    d_flags: i8,
+    d_fieldmask: u16,
+    d_id: u32,
+    d_blk_hardlimit: u64,
+    d_blk_softlimit: u64,
+    d_ino_hardlimit: u64,
+    d_ino_softlimit: u64,
+    d_bcount: u64,
+    d_icount: u64,
+    d_itimer: i32,
+    d_btimer: i32,
+    d_iwarns: u16,
+    d_bwarns: u16,
+    d_itimer_hi: i8,
+    d_btimer_hi: i8,
+    d_rtbtimer_hi: i8,
+    d_padding2: i8,
+    d_rtb_hardlimit: u64,
+    d_rtb_softlimit: u64,
+    d_rtbcount: u64,
+    d_rtbtimer: i32,
+    d_rtbwarns: u16,
+    d_padding3: i16,
+    d_padding4: [u8; 8],
+}
+
+/// Query the quota of `kind` for the current user on `mount`.
+#[cfg(target_os = "linux")]
+fn query(mount: &Mount, kind: Kind) -> Quota {
+    // QCMD(cmd, type) from <sys/quota.h>
+    let qcmd = |cmd: u32| ((cmd << 8) | kind as u32) as libc::c_int;
+    // Q_XGETQUOTA from <linux/dqblk_xfs.h>
+    const Q_XGETQUOTA: u32 = ((b'X' as u32) << 8) | 3;
+    /// Unit of the generic block limits
+    const QIF_DQBLKSIZE: u64 = 1024;
+    /// Unit of all XFS block counts
+    const XFS_BBSIZE: u64 = 512;
+
+    let Ok(device) = std::ffi::CString::new(mount.source.as_str()) else {
+        return Quota::Unsupported;
+    };
+    let id = kind.id() as libc::c_int;
+    let result = if mount.fs_type == "xfs" {
+        let mut quota = FsDiskQuota::default();
+        let rc = unsafe {
+            libc::quotactl(
+                qcmd(Q_XGETQUOTA),
+                device.as_ptr(),
+                id,
+                &mut quota as *mut FsDiskQuota as *mut libc::c_char,
+            )
+        };
+        let timer = |low: i32, high: i8| (i64::from(high) << 32) | i64::from(low as u32);
+        (rc == 0).then(|| Quota::Active {
+            blocks: Limits {
+                used: quota.d_bcount * XFS_BBSIZE,
+                soft: quota.d_blk_softlimit * XFS_BBSIZE,
+                hard: quota.d_blk_hardlimit * XFS_BBSIZE,
+                grace_expires: timer(quota.d_btimer, quota.d_btimer_hi),
+            },
+            inodes: Limits {
+                used: quota.d_icount,
+                soft: quota.d_ino_softlimit,
+                hard: quota.d_ino_hardlimit,
+                grace_expires: timer(quota.d_itimer, quota.d_itimer_hi),
+            },
+        })
+    } else {
+        let mut quota: libc::dqblk = unsafe { std::mem::zeroed() };
+        let rc = unsafe {
+            libc::quotactl(
+                qcmd(libc::Q_GETQUOTA as u32),
+                device.as_ptr(),
+                id,
+                &mut quota as *mut libc::dqblk as *mut libc::c_char,
+            )
+        };
+        (rc == 0).then(|| Quota::Active {
+            blocks: Limits {
+                used: quota.dqb_curspace,
+                soft: quota.dqb_bsoftlimit * QIF_DQBLKSIZE,
+                hard: quota.dqb_bhardlimit * QIF_DQBLKSIZE,
+                grace_expires: quota.dqb_btime as i64,
+            },
+            inodes: Limits {
+                used: quota.dqb_curinodes,
+                soft: quota.dqb_isoftlimit,
+                hard: quota.dqb_ihardlimit,
+                grace_expires: quota.dqb_itime as i64,
+            },
+        })
+    };
+    result.unwrap_or_else(|| {
+        let err = std::io::Error::last_os_error();
+        match err.raw_os_error() {
+            // Quotas of this kind are off, or XFS has no record for the id
+            Some(libc::ESRCH | libc::ENOENT) => Quota::None,
+            Some(libc::ENOSYS | libc::ENOTSUP | libc::ENOTBLK | libc::ENODEV | libc::EINVAL) => {
+                Quota::Unsupported
+            }
+            _ => Quota::Error(err.to_string()),
+        }
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn query(_mount: &Mount, _kind: Kind) -> Quota {
+    Quota::Unsupported
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64)
+}
+
+/// The exceeded quota of the filesystem holding `path`, as
+/// `{kind, mount, ...}`, or None if no quota of ours is exceeded there.
+pub fn exceeded(path: &Path) -> Option<Value> {
+    let path = existing_ancestor(path)?;
+    let mount = mount_of(&path).ok()?;
+    let now = now();
+    [Kind::User, Kind::Group].into_iter().find_map(|kind| {
+        let quota = query(&mount, kind);
+        quota.exceeded(now).then(|| {
+            let mut value = quota.to_value(kind, now);
+            if let Value::Map(ref mut pairs) = value {
+                pairs.insert(0, ("kind".into(), kind.name().into()));
+                pairs.insert(1, ("mount".into(), mount.point.to_string_lossy().into()));
+            }
+            value
+        })
+    })
+}
+
+/// Handle `system.quota {path}`.
+///
+/// Returns `{mount, device, fs_type, user, group}`, where `user` and
+/// `group` are `{status, id, blocks, inodes, exceeded}` for the effective
+/// uid and gid.  `blocks` (in bytes) and `inodes` hold `{used, soft, hard,
+/// grace_expires}`, with nil for a limit that is not set and for a grace
+/// period that is not running.  `status` is "ok", "none" when that kind of
+/// quota is not enabled, "unsupported", or "error" with a `message`.
+pub async fn handle_quota(params: Value) -> Result<Value, RpcError> {
+    #[derive(serde::Deserialize)]
+    struct Params {
+        #[serde(with = "path_or_bytes")]
+        path: Vec<u8>,
+    }
+
+    let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
+    let path = crate::handlers::file::bytes_to_path(&params.path);
+    crate::jail::check(&path)?;
+
+    crate::blocking::run(path.clone(), move || {
+        let path = std::fs::canonicalize(&path)
+            .map_err(|e| crate::handlers::file::map_io_error(e, &path))?;
+        let now = now();
+        let (mount, quota) = match mount_of(&path) {
+            Ok(mount) => {
+                let quota = |kind| query(&mount, kind).to_value(kind, now);
+                let quota = (quota(Kind::User), quota(Kind::Group));
+                (Some(mount), quota)
+            }
+            Err(_) => {
+                let unsupported = |kind| Quota::Unsupported.to_value(kind, now);
+                (None, (unsupported(Kind::User), unsupported(Kind::Group)))
+            }
+        };
+        let field =
+            |get: fn(&Mount) -> String| mount.as_ref().map_or(Value::Nil, |m| get(m).into());
+        Ok(msgpack_map! {
+            "mount" => field(|m| m.point.to_string_lossy().into_owned()),
+            "device" => field(|m| m.source.clone()),
+            "fs_type" => field(|m| m.fs_type.clone()),
+            "user" => quota.0,
+            "group" => quota.1
+        })
+    })
+    .await?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mounts_are_found_by_device_and_path() {
+        let mountinfo = "\
+22 1 8:1 / / rw,relatime shared:1 - ext4 /dev/sda1 rw
+30 22 8:1 /srv /mnt/my\\040data rw - ext4 /dev/sda1 rw
+31 22 8:2 / /home rw,relatime shared:2 master:1 - xfs /dev/sda2 rw,usrquota
+";
+        let home = find_mount(mountinfo, 8, 2, Path::new("/home/u/file")).unwrap();
+        assert_eq!(home.point, Path::new("/home"));
+        assert_eq!(home.fs_type, "xfs");
+        assert_eq!(home.source, "/dev/sda2");
+
+        let data = find_mount(mountinfo, 8, 1, Path::new("/mnt/my data/x")).unwrap();
+        assert_eq!(data.point, Path::new("/mnt/my data"));
+        // A bind mount elsewhere does not shadow the mount holding the path
+        let root = find_mount(mountinfo, 8, 1, Path::new("/etc/passwd")).unwrap();
+        assert_eq!(root.point, Path::new("/"));
+        assert!(find_mount(mountinfo, 9, 0, Path::new("/")).is_none());
+    }
+
+    #[test]
+    fn limits_are_exceeded_at_hard_or_after_grace() {
+        let limits = |used, soft, hard, grace_expires| Limits {
+            used,
+            soft,
+            hard,
+            grace_expires,
+        };
+        assert!(!limits(10, 0, 0, 0).exceeded(100));
+        assert!(limits(10, 0, 10, 0).exceeded(100));
+        assert!(!limits(9, 0, 10, 0).exceeded(100));
+        // Over the soft limit only counts once the grace period is over
+        assert!(!limits(6, 5, 10, 200).exceeded(100));
+        assert!(limits(6, 5, 10, 50).exceeded(100));
+
+        let quota = Quota::Active {
+            blocks: limits(2048, 1024, 0, 50),
+            inodes: limits(3, 0, 0, 0),
+        };
+        let value = quota.to_value(Kind::User, 100);
+        assert_eq!(value["status"].as_str(), Some("ok"));
+        assert_eq!(value["exceeded"].as_bool(), Some(true));
+        assert_eq!(value["blocks"]["soft"].as_u64(), Some(1024));
+        assert!(value["blocks"]["hard"].is_nil());
+        assert!(value["inodes"]["grace_expires"].is_nil());
+    }
+
+    #[tokio::test]
+    async fn quota_reports_a_status_for_each_kind() {
+        let tmp = tempfile::tempdir().unwrap();
+        let result = handle_quota(msgpack_map! { "path" => tmp.path().to_str().unwrap() })
+            .await
+            .unwrap();
+        for kind in ["user", "group"] {
+            let status = result[kind]["status"].as_str().unwrap();
+            assert!(
+                ["ok", "none", "unsupported", "error"].contains(&status),
+                "{}",
+                status
+            );
+        }
+        let missing = handle_quota(msgpack_map! { "path" => "/nonexistent/quota" }).await;
+        assert_eq!(missing.unwrap_err().code, RpcError::FILE_NOT_FOUND);
+    }
+}
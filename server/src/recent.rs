@@ -0,0 +1,308 @@
+//! In-memory log of recently dispatched requests, for debugging.
+//!
+//! Always on, unlike the audit log: every dispatched request is kept in a
+//! ring buffer of `--recent-requests N` / `TRAMP_RPC_RECENT_REQUESTS` entries
+//! (256 by default, 0 turns it off) and read back with
+//! `system.recent_requests`.
+//!
+//! An entry holds the method, its path parameters (truncated), the start
+//! time, the duration and the result code.  Nothing else from the params is
+//! kept, so file contents, stdin data and environment values are never
+//! recorded.
+
+use crate::msgpack_map;
+use crate::protocol::{RpcError, from_value};
+use rmpv::Value;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Entries kept by default.
+pub const DEFAULT_CAPACITY: usize = 256;
+
+/// Recorded path parameters are cut to this many bytes.
+const MAX_PATH_BYTES: usize = 256;
+
+/// Parameters holding paths; the only values recorded.
+const PATH_PARAMS: &[&str] = &[
+    "path",
+    "src",
+    "dest",
+    "destination",
+    "output",
+    "link_path",
+    "target",
+    "cwd",
+    "directory",
+    "cmd",
+];
+
+/// Array parameters whose length is recorded instead of their contents.
+const COUNTED_PARAMS: &[&str] = &["paths", "requests", "commands"];
+
+static LOG: Mutex<Log> = Mutex::new(Log::new(DEFAULT_CAPACITY));
+
+fn log() -> std::sync::MutexGuard<'static, Log> {
+    LOG.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Set how many entries are kept; 0 stops recording.
+pub fn set_capacity(capacity: usize) {
+    log().set_capacity(capacity);
+}
+
+/// How many entries are kept.
+pub fn capacity() -> usize {
+    log().capacity
+}
+
+/// One finished request.
+#[derive(Debug)]
+struct Record {
+    seq: u64,
+    method: String,
+    summary: Vec<(&'static str, Value)>,
+    start: SystemTime,
+    duration: Duration,
+    code: i32,
+}
+
+impl Record {
+    fn to_value(&self) -> Value {
+        let start = self
+            .start
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        let params = self
+            .summary
+            .iter()
+            .map(|(k, v)| (Value::from(*k), v.clone()))
+            .collect();
+        msgpack_map! {
+            "seq" => self.seq,
+            "method" => self.method.as_str(),
+            "params" => Value::Map(params),
+            "start" => start,
+            "duration_us" => self.duration.as_micros() as u64,
+            "code" => self.code
+        }
+    }
+}
+
+/// Ring buffer of the most recent records.
+#[derive(Debug)]
+struct Log {
+    capacity: usize,
+    records: VecDeque<Record>,
+    /// Requests recorded so far, including evicted ones
+    total: u64,
+}
+
+impl Log {
+    const fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            records: VecDeque::new(),
+            total: 0,
+        }
+    }
+
+    fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        while self.records.len() > capacity {
+            self.records.pop_front();
+        }
+    }
+
+    fn push(&mut self, mut record: Record) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.total += 1;
+        record.seq = self.total;
+        if self.records.len() == self.capacity {
+            self.records.pop_front();
+        }
+        self.records.push_back(record);
+    }
+
+    /// The newest `limit` records matching `method`, oldest first.
+    fn query(&self, method: Option<&str>, limit: Option<usize>) -> Vec<Value> {
+        let mut matching: Vec<&Record> = self
+            .records
+            .iter()
+            .rev()
+            .filter(|r| method.is_none_or(|m| method_matches(m, &r.method)))
+            .take(limit.unwrap_or(usize::MAX))
+            .collect();
+        matching.reverse();
+        matching.into_iter().map(Record::to_value).collect()
+    }
+}
+
+/// Whether `filter` selects `method`: an exact name, or a namespace ending
+/// in `.` such as `file.`.
+fn method_matches(filter: &str, method: &str) -> bool {
+    if filter.ends_with('.') {
+        method.starts_with(filter)
+    } else {
+        method == filter
+    }
+}
+
+/// A request being recorded.
+pub struct Pending {
+    method: String,
+    summary: Vec<(&'static str, Value)>,
+    start: SystemTime,
+    started: Instant,
+}
+
+/// Start recording `method`, or `None` if recording is off.  Call
+/// [`Pending::finish`] with the outcome.
+pub fn begin(method: &str, params: &Value) -> Option<Pending> {
+    if log().capacity == 0 {
+        return None;
+    }
+    Some(Pending {
+        method: method.to_string(),
+        summary: summarize(params),
+        start: SystemTime::now(),
+        started: Instant::now(),
+    })
+}
+
+impl Pending {
+    pub fn finish(self, result: &Result<Value, RpcError>) {
+        let record = Record {
+            seq: 0,
+            method: self.method,
+            summary: self.summary,
+            start: self.start,
+            duration: self.started.elapsed(),
+            code: result.as_ref().err().map_or(0, |e| e.code),
+        };
+        log().push(record);
+    }
+}
+
+/// The path parameters of `params`, truncated, plus the lengths of
+/// [`COUNTED_PARAMS`].
+fn summarize(params: &Value) -> Vec<(&'static str, Value)> {
+    let fields = params.as_map().map(Vec::as_slice).unwrap_or_default();
+    let mut summary = Vec::new();
+    for (key, value) in fields {
+        let Some(key) = key.as_str() else {
+            continue;
+        };
+        if let Some(&name) = PATH_PARAMS.iter().find(|&&p| p == key) {
+            let truncated = match value {
+                Value::String(s) => {
+                    let bytes = s.as_bytes();
+                    Value::Binary(bytes[..bytes.len().min(MAX_PATH_BYTES)].to_vec())
+                }
+                Value::Binary(b) => Value::Binary(b[..b.len().min(MAX_PATH_BYTES)].to_vec()),
+                _ => continue,
+            };
+            summary.push((name, truncated));
+        } else if let Some(&name) = COUNTED_PARAMS.iter().find(|&&p| p == key)
+            && let Some(items) = value.as_array()
+        {
+            summary.push((name, Value::from(items.len() as u64)));
+        }
+    }
+    summary
+}
+
+/// Handle `system.recent_requests {method?, limit?}`.
+///
+/// Returns the newest entries oldest first; `method` is an exact method
+/// name or a namespace such as `file.`.
+pub fn handle_recent_requests(params: Value) -> Result<Value, RpcError> {
+    #[derive(serde::Deserialize, Default)]
+    struct Params {
+        #[serde(default)]
+        method: Option<String>,
+        #[serde(default)]
+        limit: Option<usize>,
+    }
+
+    let params: Params = if params.is_nil() {
+        Params::default()
+    } else {
+        from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?
+    };
+    let log = log();
+    Ok(msgpack_map! {
+        "capacity" => log.capacity as u64,
+        "total" => log.total,
+        "requests" => Value::Array(log.query(params.method.as_deref(), params.limit)),
+        "slow_blocking" => crate::blocking::slow()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(method: &str, params: &Value, code: i32) -> Record {
+        Record {
+            seq: 0,
+            method: method.to_string(),
+            summary: summarize(params),
+            start: SystemTime::now(),
+            duration: Duration::from_micros(5),
+            code,
+        }
+    }
+
+    #[test]
+    fn recent_requests_are_bounded_filtered_and_redacted() {
+        let mut log = Log::new(3);
+        let write = msgpack_map! {
+            "path" => "/tmp/a",
+            "content" => Value::Binary(b"secret".to_vec()),
+        };
+        let run = msgpack_map! {
+            "cmd" => "git",
+            "stdin" => "password",
+            "env" => msgpack_map! { "TOKEN" => "hunter2" },
+            "cwd" => Value::Binary(vec![b'x'; 1000]),
+        };
+        log.push(record("file.stat", &msgpack_map! { "path" => "/old" }, 0));
+        log.push(record("file.write", &write, 0));
+        log.push(record("process.run", &run, 0));
+        log.push(record(
+            "file.stat",
+            &msgpack_map! { "path" => "/tmp/b" },
+            -32001,
+        ));
+
+        let all = log.query(None, None);
+        assert_eq!(all.len(), 3);
+        assert_eq!(log.total, 4);
+        assert_eq!(all[0]["method"].as_str(), Some("file.write"));
+        assert_eq!(all[2]["seq"].as_u64(), Some(4));
+        assert_eq!(all[2]["code"].as_i64(), Some(-32001));
+
+        // Only paths are kept, and long ones are truncated
+        assert_eq!(all[0]["params"].as_map().unwrap().len(), 1);
+        let run = &all[1]["params"];
+        assert_eq!(run.as_map().unwrap().len(), 2);
+        assert_eq!(run["cwd"].as_slice().unwrap().len(), MAX_PATH_BYTES);
+        let dumped = format!("{:?}", all);
+        for secret in ["secret", "password", "hunter2", "TOKEN"] {
+            assert!(!dumped.contains(secret), "{} was recorded", secret);
+        }
+
+        let stats = log.query(Some("file.stat"), None);
+        assert_eq!(stats.len(), 1);
+        assert_eq!(log.query(Some("file."), None).len(), 2);
+        assert_eq!(log.query(None, Some(1))[0]["seq"].as_u64(), Some(4));
+
+        log.set_capacity(0);
+        log.push(record("file.stat", &Value::Nil, 0));
+        assert!(log.query(None, None).is_empty());
+    }
+}
@@ -0,0 +1,206 @@
+//! Restarting the server in place.
+//!
+//! `system.reexec {path, sha256?, force?}` replaces the running server with
+//! the binary at `path` through `exec()`, so an upgrade keeps the SSH
+//! connection.  The new server is started with the same arguments plus
+//! `--restarted-from VERSION` and announces itself with a
+//! `server.restarted {version, previous_version, commit}` notification,
+//! which stands in for the reply.
+//!
+//! Nothing managed survives the exec: managed processes, PTYs and shell
+//! sessions lose their pipes, and watches, negotiated features and the
+//! authentication of the connection are gone.  The request is refused
+//! while processes, PTYs or shell sessions are open unless `force` is set.
+
+use crate::handlers::HandlerResult;
+use crate::handlers::file::bytes_to_path;
+use crate::jail;
+use crate::msgpack_map;
+use crate::protocol::{Notification, RpcError, from_value, path_or_bytes};
+use rmpv::Value;
+use serde::Deserialize;
+use std::ffi::OsString;
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+
+/// Flag passed to the new server, with the version of the old one
+const RESTARTED_FROM: &str = "--restarted-from";
+
+/// The arguments for the new server: ours without `--auth-token-file` (the
+/// token is passed in the environment, as the file may be gone) and an
+/// earlier `--restarted-from`, then `--restarted-from VERSION`.
+fn reexec_args(args: impl IntoIterator<Item = OsString>, version: &str) -> Vec<OsString> {
+    let dropped = ["--auth-token-file", RESTARTED_FROM];
+    let mut kept = Vec::new();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let text = arg.to_string_lossy();
+        if dropped.contains(&text.as_ref()) {
+            args.next();
+        } else if !dropped.iter().any(|flag| {
+            text.strip_prefix(flag)
+                .is_some_and(|rest| rest.starts_with('='))
+        }) {
+            kept.push(arg);
+        }
+    }
+    kept.push(RESTARTED_FROM.into());
+    kept.push(version.into());
+    kept
+}
+
+/// Check that `path` is an executable regular file and, if `sha256` is
+/// given, that its contents have that hex digest.
+fn validate(path: &Path, sha256: Option<&str>) -> Result<(), RpcError> {
+    let meta = std::fs::metadata(path).map_err(|e| crate::handlers::file::map_io_error(e, path))?;
+    if !meta.is_file() || meta.permissions().mode() & 0o111 == 0 {
+        return Err(RpcError::invalid_params(format!(
+            "Not an executable file: {}",
+            path.display()
+        )));
+    }
+    if let Some(expected) = sha256 {
+        let actual = crate::handlers::io::sha256_file(path)
+            .map_err(|e| crate::handlers::file::map_io_error(e, path))?;
+        if !actual.eq_ignore_ascii_case(expected.trim()) {
+            return Err(RpcError {
+                code: RpcError::INVALID_PARAMS,
+                message: format!("sha256 mismatch for {}", path.display()),
+                data: Some(msgpack_map! {
+                    "expected" => expected,
+                    "actual" => actual
+                }),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Handle `system.reexec {path, sha256?, force?}`.  On success this does
+/// not return: the new server sends `server.restarted` instead.
+///
+/// `token` is the authentication token of this server, handed to the new
+/// one in `TRAMP_RPC_AUTH_TOKEN`.
+pub async fn handle_reexec(params: Value, token: Option<&[u8]>) -> HandlerResult {
+    #[derive(Deserialize)]
+    struct Params {
+        #[serde(with = "path_or_bytes")]
+        path: Vec<u8>,
+        /// Expected hex sha256 of the binary
+        #[serde(default)]
+        sha256: Option<String>,
+        /// Restart even though processes, PTYs or shell sessions are open
+        #[serde(default)]
+        force: bool,
+    }
+
+    let logged = params.clone();
+    let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
+    let path = bytes_to_path(&params.path);
+    jail::check(&path)?;
+
+    let open =
+        crate::handlers::process::open_count().await + crate::handlers::shell::open_count().await;
+    if open > 0 && !params.force {
+        return Err(RpcError {
+            code: RpcError::PROCESS_ERROR,
+            message: format!(
+                "{} processes, PTYs or shell sessions would be lost (pass force to restart anyway)",
+                open
+            ),
+            data: Some(msgpack_map! { "open" => open }),
+        });
+    }
+
+    let sha256 = params.sha256;
+    let check = path.clone();
+    crate::blocking::run(&path, move || validate(&check, sha256.as_deref())).await??;
+
+    let mut command = std::process::Command::new(&path);
+    command.args(reexec_args(
+        std::env::args_os().skip(1),
+        env!("CARGO_PKG_VERSION"),
+    ));
+    if let Some(token) = token {
+        command.env("TRAMP_RPC_AUTH_TOKEN", OsString::from_vec(token.to_vec()));
+    }
+
+    // Past the exec the request never finishes, so log it as done now.
+    if let Some(audit) = crate::audit::begin("system.reexec", &logged) {
+        audit.finish(&Ok(Value::Nil));
+    }
+    crate::audit::close().await;
+    // Flush what is queued and keep stdout locked, so the old server cannot
+    // start another frame that the exec would cut in half.
+    let err = crate::with_stdout_flushed(|| command.exec()).await;
+    Err(RpcError::process_error(format!(
+        "Failed to exec {}: {}",
+        path.display(),
+        err
+    )))
+}
+
+/// Tell the client that a restart is done: `server.restarted {version,
+/// previous_version, commit}`.
+pub async fn announce(previous: OsString) {
+    crate::send_notification_forced(Notification::new(
+        "server.restarted",
+        msgpack_map! {
+            "version" => env!("CARGO_PKG_VERSION"),
+            "previous_version" => previous.to_string_lossy().into_owned(),
+            "commit" => env!("TRAMP_RPC_GIT_COMMIT")
+        },
+    ))
+    .await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn new_server_keeps_arguments_but_not_the_token_file() {
+        let args = [
+            "--jail",
+            "/srv",
+            "--auth-token-file",
+            "/run/token",
+            "--restarted-from=0.1.0",
+            "--audit-log=/tmp/audit",
+        ]
+        .map(OsString::from);
+        let expected = [
+            "--jail",
+            "/srv",
+            "--audit-log=/tmp/audit",
+            "--restarted-from",
+            "0.2.0",
+        ]
+        .map(OsString::from);
+        assert_eq!(reexec_args(args, "0.2.0"), expected);
+    }
+
+    #[test]
+    fn only_matching_executables_are_accepted() {
+        let tmp = tempfile::tempdir().unwrap();
+        let binary = tmp.path().join("tramp-rpc-server");
+        std::fs::write(&binary, b"#!/bin/sh\n").unwrap();
+
+        let err = validate(&binary, None).expect_err("not executable");
+        assert_eq!(err.code, RpcError::INVALID_PARAMS);
+        std::fs::set_permissions(&binary, std::fs::Permissions::from_mode(0o755)).unwrap();
+        validate(&binary, None).unwrap();
+
+        let digest = crate::handlers::io::sha256_file(&binary).unwrap();
+        validate(&binary, Some(&digest.to_uppercase())).unwrap();
+        let err = validate(&binary, Some(&"0".repeat(64))).expect_err("wrong digest");
+        assert_eq!(err.data.unwrap()["actual"].as_str(), Some(digest.as_str()));
+
+        let err = validate(tmp.path(), None).expect_err("a directory");
+        assert_eq!(err.code, RpcError::INVALID_PARAMS);
+        let err = validate(&tmp.path().join("missing"), None).expect_err("missing");
+        assert_eq!(err.code, RpcError::FILE_NOT_FOUND);
+    }
+}
@@ -0,0 +1,297 @@
+//! Per-user directories for state the server keeps on the remote host.
+//!
+//! Follows the XDG base directory spec, with everything below a
+//! `tramp-rpc` subdirectory:
+//!
+//! - cache (`$XDG_CACHE_HOME`, else `~/.cache`): data that can be
+//!   regenerated, such as tag files
+//! - state (`$XDG_STATE_HOME`, else `~/.local/state`): data that outlives a
+//!   connection, such as upload records and fallback auto-saves
+//! - runtime (`$XDG_RUNTIME_DIR`, else the temp dir): sockets and other
+//!   files that only live as long as the server
+//!
+//! Relative XDG values are ignored, as the spec requires.  Without a home
+//! directory, cache and state live in `$TMPDIR/tramp-rpc-UID` too.
+//! Directories are created with mode 0700 on first use, and `system.gc`
+//! removes expired state.
+
+use crate::handlers::HandlerResult;
+use crate::msgpack_map;
+use crate::protocol::{RpcError, from_value};
+use rmpv::Value;
+use std::ffi::OsString;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// The kinds of server directories
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    Cache,
+    State,
+    Runtime,
+}
+
+impl Kind {
+    fn name(self) -> &'static str {
+        match self {
+            Kind::Cache => "cache",
+            Kind::State => "state",
+            Kind::Runtime => "runtime",
+        }
+    }
+}
+
+/// Where the directory of `kind` lives: `(root, dir)`, where `root` is the
+/// directory the server owns and `dir` is `root` or a directory below it.
+fn resolve(
+    kind: Kind,
+    var: impl Fn(&str) -> Option<OsString>,
+    tmp: &Path,
+    uid: u32,
+) -> (PathBuf, PathBuf) {
+    let absolute = |name: &str| var(name).map(PathBuf::from).filter(|dir| dir.is_absolute());
+    let home = || absolute("HOME");
+    let base = match kind {
+        Kind::Cache => absolute("XDG_CACHE_HOME").or_else(|| Some(home()?.join(".cache"))),
+        Kind::State => {
+            absolute("XDG_STATE_HOME").or_else(|| Some(home()?.join(".local").join("state")))
+        }
+        Kind::Runtime => absolute("XDG_RUNTIME_DIR"),
+    };
+    match base {
+        Some(base) => {
+            let root = base.join("tramp-rpc");
+            (root.clone(), root)
+        }
+        None => {
+            let root = tmp.join(format!("tramp-rpc-{}", uid));
+            let dir = root.join(kind.name());
+            (root, dir)
+        }
+    }
+}
+
+/// Where the directory of `kind` lives, as `(root, dir)` from [`resolve`].
+pub(crate) fn locate(kind: Kind) -> (PathBuf, PathBuf) {
+    let var = |name: &str| std::env::var_os(name).filter(|v| !v.is_empty());
+    // A runtime dir that does not exist (as after `su`) is as good as none.
+    let var = |name: &str| var(name).filter(|v| name != "XDG_RUNTIME_DIR" || Path::new(v).is_dir());
+    resolve(kind, var, &std::env::temp_dir(), unsafe { libc::geteuid() })
+}
+
+/// The directory of `kind`, whether or not it exists yet.
+pub fn path(kind: Kind) -> PathBuf {
+    locate(kind).1
+}
+
+/// The directory of `kind`, created if needed.
+pub fn dir(kind: Kind) -> std::io::Result<PathBuf> {
+    let (root, dir) = locate(kind);
+    create_private(&root, &dir)?;
+    Ok(dir)
+}
+
+/// The subdirectory `name` of the directory of `kind`, created if needed.
+pub fn subdir(kind: Kind, name: &str) -> std::io::Result<PathBuf> {
+    let (root, dir) = locate(kind);
+    let sub = dir.join(name);
+    create_private(&root, &sub)?;
+    Ok(sub)
+}
+
+/// Create `dir` and any missing parents with mode 0700, refusing a `root`
+/// that belongs to someone else, as one planted in a shared temp dir would.
+pub(crate) fn create_private(root: &Path, dir: &Path) -> std::io::Result<()> {
+    if dir.is_dir() {
+        return Ok(());
+    }
+    std::fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(root)?;
+    let meta = std::fs::symlink_metadata(root)?;
+    if !meta.is_dir() || meta.uid() != unsafe { libc::geteuid() } {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            format!("{} is not a directory owned by us", root.display()),
+        ));
+    }
+    if meta.mode() & 0o077 != 0 {
+        std::fs::set_permissions(root, std::fs::Permissions::from_mode(0o700))?;
+    }
+    std::fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(dir)
+}
+
+/// The directories for `system.info`.
+pub fn info() -> Value {
+    let path = |kind| path(kind).to_string_lossy().into_owned();
+    msgpack_map! {
+        "cache" => path(Kind::Cache),
+        "state" => path(Kind::State),
+        "runtime" => path(Kind::Runtime)
+    }
+}
+
+/// What a garbage collection pass removed
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Freed {
+    pub removed: u64,
+    pub bytes: u64,
+}
+
+impl Freed {
+    /// Remove the file at `path`, counting it if it was removed.
+    pub fn remove_file(&mut self, path: &Path) {
+        let size = std::fs::symlink_metadata(path).map_or(0, |m| m.len());
+        if std::fs::remove_file(path).is_ok() {
+            self.removed += 1;
+            self.bytes += size;
+        }
+    }
+
+    /// Remove the files in `dir` last modified more than `max_age` ago.
+    pub fn remove_older_than(&mut self, dir: &Path, max_age: Duration) {
+        let Ok(entries) = std::fs::read_dir(dir) else {
+            return;
+        };
+        let now = SystemTime::now();
+        for entry in entries.flatten() {
+            let expired = entry.metadata().ok().is_some_and(|meta| {
+                meta.is_file()
+                    && meta
+                        .modified()
+                        .ok()
+                        .and_then(|m| now.duration_since(m).ok())
+                        .is_some_and(|age| age > max_age)
+            });
+            if expired {
+                self.remove_file(&entry.path());
+            }
+        }
+    }
+
+    fn to_value(self) -> Value {
+        msgpack_map! {
+            "removed" => self.removed,
+            "bytes_freed" => self.bytes
+        }
+    }
+}
+
+/// Handle `system.gc {max_age_secs?}`: remove expired server state.
+///
+/// Removes partial uploads that can no longer be resumed, tag caches and
+/// askpass sockets left by servers that are gone.  `max_age_secs`
+/// overrides the age after which uploads and tag caches expire.
+pub async fn handle_gc(params: Value) -> HandlerResult {
+    #[derive(serde::Deserialize, Default)]
+    struct Params {
+        #[serde(default)]
+        max_age_secs: Option<u64>,
+    }
+
+    let params: Params = if params.is_nil() {
+        Params::default()
+    } else {
+        from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?
+    };
+    let max_age = params.max_age_secs.map(Duration::from_secs);
+
+    let uploads = crate::handlers::upload::gc(max_age).await;
+    let (tags, askpass) = tokio::task::spawn_blocking(move || {
+        (
+            crate::handlers::tags::gc(max_age),
+            crate::handlers::sudo::gc(),
+        )
+    })
+    .await
+    .map_err(crate::blocking::join_error)?;
+
+    Ok(msgpack_map! {
+        "bytes_freed" => uploads.bytes + tags.bytes + askpass.bytes,
+        "uploads" => uploads.to_value(),
+        "tags" => tags.to_value(),
+        "askpass" => askpass.to_value()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dirs_follow_xdg_with_fallbacks() {
+        let tmp = Path::new("/tmp");
+        let env = |vars: &'static [(&'static str, &'static str)]| {
+            move |name: &str| {
+                vars.iter()
+                    .find(|(k, _)| *k == name)
+                    .map(|(_, v)| OsString::from(v))
+            }
+        };
+
+        let xdg = env(&[
+            ("HOME", "/home/u"),
+            ("XDG_CACHE_HOME", "/xdg/cache"),
+            ("XDG_STATE_HOME", "relative/state"),
+            ("XDG_RUNTIME_DIR", "/run/user/1000"),
+        ]);
+        let at = |kind| resolve(kind, xdg, tmp, 1000).1;
+        assert_eq!(at(Kind::Cache), Path::new("/xdg/cache/tramp-rpc"));
+        // Relative XDG values are ignored
+        assert_eq!(at(Kind::State), Path::new("/home/u/.local/state/tramp-rpc"));
+        assert_eq!(at(Kind::Runtime), Path::new("/run/user/1000/tramp-rpc"));
+
+        let bare = env(&[]);
+        let (root, dir) = resolve(Kind::Cache, bare, tmp, 1000);
+        assert_eq!(root, Path::new("/tmp/tramp-rpc-1000"));
+        assert_eq!(dir, Path::new("/tmp/tramp-rpc-1000/cache"));
+        assert_eq!(
+            resolve(Kind::Runtime, env(&[("HOME", "/home/u")]), tmp, 1000).1,
+            Path::new("/tmp/tramp-rpc-1000/runtime")
+        );
+    }
+
+    #[test]
+    fn created_dirs_are_private() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().join("tramp-rpc-1");
+        std::fs::create_dir(&root).unwrap();
+        std::fs::set_permissions(&root, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let dir = root.join("state").join("uploads");
+        create_private(&root, &dir).unwrap();
+        for created in [&root, &dir] {
+            let mode = std::fs::metadata(created).unwrap().mode();
+            assert_eq!(mode & 0o777, 0o700, "{}", created.display());
+        }
+
+        // A root that is not a directory is refused
+        let file = tmp.path().join("file");
+        std::fs::write(&file, b"").unwrap();
+        assert!(create_private(&file, &file.join("cache")).is_err());
+
+        let mut freed = Freed::default();
+        std::fs::write(dir.join("old"), b"12345").unwrap();
+        std::fs::write(dir.join("new"), b"1").unwrap();
+        let old = std::fs::File::options()
+            .write(true)
+            .open(dir.join("old"))
+            .unwrap();
+        old.set_modified(SystemTime::now() - Duration::from_secs(3600))
+            .unwrap();
+        freed.remove_older_than(&dir, Duration::from_secs(60));
+        assert_eq!(
+            freed,
+            Freed {
+                removed: 1,
+                bytes: 5
+            }
+        );
+        assert!(dir.join("new").exists());
+    }
+}
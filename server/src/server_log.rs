@@ -0,0 +1,59 @@
+//! The server's own log file.
+//!
+//! Set with `--log-file PATH` (see [`crate::config`]), for events worth
+//! keeping that no client is told about, such as keys in the config file
+//! that this server does not know.  Each line is `time=... level=...
+//! message="..."`; without a log file, lines are dropped.  Nothing is ever
+//! written to stderr instead, as SSH mixes it into the protocol stream.
+//!
+//! Lines are rare, so they are appended synchronously.
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+static LOG: Mutex<Option<(PathBuf, std::fs::File)>> = Mutex::new(None);
+
+fn log() -> std::sync::MutexGuard<'static, Option<(PathBuf, std::fs::File)>> {
+    LOG.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Append to the log file at `path` from now on.
+pub fn set(path: &Path) -> std::io::Result<()> {
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    *log() = Some((std::path::absolute(path)?, file));
+    Ok(())
+}
+
+/// The log file, if there is one.
+pub fn path() -> Option<PathBuf> {
+    log().as_ref().map(|(path, _)| path.clone())
+}
+
+fn write(level: &str, message: &str) {
+    let mut log = log();
+    let Some((_, file)) = log.as_mut() else {
+        return;
+    };
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64();
+    let _ = writeln!(file, "time={time:.3} level={level} message={message:?}");
+}
+
+pub fn info(message: &str) {
+    write("info", message);
+}
+
+pub fn warn(message: &str) {
+    write("warn", message);
+}
+
+pub fn error(message: &str) {
+    write("error", message);
+}
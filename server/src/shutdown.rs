@@ -0,0 +1,125 @@
+//! Shutting down when the client goes away.
+//!
+//! The server stops at EOF on stdin, or at the first failed write to
+//! stdout: SIGPIPE is ignored, so a client that is gone mid-response would
+//! otherwise leave the server reading requests and writing into a broken
+//! pipe for as long as stdin stays open.  The failure is recorded with
+//! [`stdout_broken`], which stops the read loop; later writes are skipped
+//! rather than attempted.  A frame from the client that fails its
+//! integrity check ends the connection the same way (see
+//! [`crate::framing`]).  Requests still running get [`DRAIN`] to finish
+//! before they are dropped, and either way the server then runs
+//! [`cleanup`].
+//!
+//! With `--idle-timeout-secs N` the server also exits, with status 0, once
+//! no request has arrived or run for N seconds, so a client that vanished
+//! without closing the connection does not leave it running forever.
+
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+/// How long requests still running may take once stdout is broken.
+pub const DRAIN: Duration = Duration::from_secs(2);
+
+static BROKEN: AtomicBool = AtomicBool::new(false);
+static STOP: Notify = Notify::const_new();
+
+static IDLE_TIMEOUT_SECS: AtomicU64 = AtomicU64::new(0);
+static RUNNING: AtomicUsize = AtomicUsize::new(0);
+static LAST_ACTIVE: LazyLock<Mutex<Instant>> = LazyLock::new(|| Mutex::new(Instant::now()));
+
+fn touch() {
+    *LAST_ACTIVE.lock().unwrap_or_else(|e| e.into_inner()) = Instant::now();
+}
+
+fn since_active() -> Duration {
+    LAST_ACTIVE
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .elapsed()
+}
+
+/// Record that a write to stdout failed.
+pub fn stdout_broken() {
+    if !BROKEN.swap(true, Ordering::SeqCst) {
+        STOP.notify_waiters();
+    }
+}
+
+/// Whether a write to stdout has failed.
+pub fn is_broken() -> bool {
+    BROKEN.load(Ordering::SeqCst)
+}
+
+/// Wait until stdout is broken.
+pub async fn stopped() {
+    loop {
+        let notified = STOP.notified();
+        let mut notified = std::pin::pin!(notified);
+        // Registered before looking, so a failure in between is not missed
+        notified.as_mut().enable();
+        if is_broken() {
+            return;
+        }
+        notified.await;
+    }
+}
+
+/// Set the idle timeout; 0 turns it off.  The server counts as active
+/// from this call on.
+pub fn set_idle_timeout(secs: u64) {
+    IDLE_TIMEOUT_SECS.store(secs, Ordering::Relaxed);
+    touch();
+}
+
+pub fn idle_timeout_secs() -> u64 {
+    IDLE_TIMEOUT_SECS.load(Ordering::Relaxed)
+}
+
+/// Marks a request as running until dropped.
+pub struct Running(());
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        touch();
+        RUNNING.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Record that a request has arrived.
+pub fn request_started() -> Running {
+    RUNNING.fetch_add(1, Ordering::SeqCst);
+    touch();
+    Running(())
+}
+
+/// Wait until the idle timeout has passed with no request running; never,
+/// without a timeout.
+pub async fn idle() {
+    let secs = idle_timeout_secs();
+    if secs == 0 {
+        return std::future::pending().await;
+    }
+    let timeout = Duration::from_secs(secs);
+    loop {
+        let since = since_active();
+        if RUNNING.load(Ordering::SeqCst) == 0 && since >= timeout {
+            return;
+        }
+        // A running request touches the clock again when it ends
+        tokio::time::sleep(timeout.saturating_sub(since).max(Duration::from_millis(10))).await;
+    }
+}
+
+/// Kill managed processes, PTYs and shell sessions, drop the watches and
+/// close the audit log.  Detached processes are left running.
+pub async fn cleanup() {
+    crate::handlers::process::kill_all().await;
+    crate::handlers::shell::close_all().await;
+    if let Some(manager) = crate::watcher::get() {
+        manager.unwatch_all();
+    }
+    crate::audit::close().await;
+}
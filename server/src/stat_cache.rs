@@ -0,0 +1,584 @@
+//! In-memory stat cache.
+//!
+//! Magit and dired refreshes stat the same paths many times per second.
+//! Attributes are cached per (path, lstat) for a short TTL and dropped as
+//! soon as this server mutates the path or the watcher reports a change.
+//! A change drops the entries of every spelling of the file it finds: the
+//! path with symlinks resolved, and paths cached with the same device and
+//! inode.  The whole cache is cleared whenever a program the client runs
+//! finishes or a process it started is seen to exit, as those may have
+//! changed any path.  Changes made by other processes in unwatched
+//! directories, and by started processes while they still run, are only
+//! bounded by the TTL.
+//!
+//! Paths found missing are cached too, as TRAMP probes far more paths that
+//! do not exist (lock files, backups, VC markers) than ones that do.  Each
+//! directory has a generation, which changes whenever an entry below it is
+//! invalidated; a missing entry records the generation of its parent as of
+//! before the stat, and is only believed while that is unchanged.  So a
+//! path created while the stat was running, or at any time after, is not
+//! reported missing once the creation has been seen.  `dir.list` and
+//! `dir.generation` hand the generation to clients, which can compare it
+//! to tell whether a watched directory changed.
+
+use crate::msgpack_map;
+use crate::protocol::FileAttributes;
+use rmpv::Value;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// How long an entry stays valid without an invalidation.
+const STAT_TTL: Duration = Duration::from_secs(2);
+
+/// Maximum number of cached entries.
+const CAPACITY: usize = 4096;
+
+/// Fraction of entries evicted at once when the cache is full, so eviction
+/// cost is amortized over many inserts.
+const EVICT_BATCH: usize = CAPACITY / 8;
+
+/// Directories whose generation is tracked; beyond that every directory
+/// gets a new generation at once.
+const GENERATION_CAPACITY: usize = 16384;
+
+enum Cached {
+    Attrs(FileAttributes),
+    /// The path did not exist when its parent had this generation
+    Missing {
+        generation: u64,
+    },
+}
+
+struct Entry {
+    value: Cached,
+    inserted: Instant,
+    last_used: u64,
+}
+
+/// What the cache knows about a path
+#[derive(Debug)]
+pub enum Lookup {
+    Found(FileAttributes),
+    Missing,
+}
+
+struct StatCache {
+    entries: HashMap<(PathBuf, bool), Entry>,
+    /// Logical clock for LRU ordering
+    tick: u64,
+    /// Generation of directories changed since `base_generation`
+    generations: HashMap<PathBuf, u64>,
+    /// Generation of trees invalidated as a whole since `base_generation`,
+    /// which applies to every directory below them
+    tree_generations: HashMap<PathBuf, u64>,
+    /// Generation of directories not changed since the cache was cleared
+    base_generation: u64,
+    /// Last generation handed out
+    generation_clock: u64,
+    hits: u64,
+    misses: u64,
+    negative_hits: u64,
+    evictions: u64,
+    invalidations: u64,
+}
+
+impl StatCache {
+    fn new() -> Self {
+        // Start from the time, so generations of an earlier server are not
+        // mistaken for current ones.
+        let start = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(1, |d| d.as_micros() as u64);
+        Self {
+            entries: HashMap::new(),
+            tick: 0,
+            generations: HashMap::new(),
+            tree_generations: HashMap::new(),
+            base_generation: start,
+            generation_clock: start,
+            hits: 0,
+            misses: 0,
+            negative_hits: 0,
+            evictions: 0,
+            invalidations: 0,
+        }
+    }
+
+    fn evict_lru(&mut self) {
+        let mut by_age: Vec<(u64, (PathBuf, bool))> = self
+            .entries
+            .iter()
+            .map(|(k, e)| (e.last_used, k.clone()))
+            .collect();
+        by_age.sort_unstable_by_key(|(used, _)| *used);
+        for (_, key) in by_age.into_iter().take(EVICT_BATCH) {
+            self.entries.remove(&key);
+            self.evictions += 1;
+        }
+    }
+
+    fn remove_path(&mut self, path: &Path) {
+        for lstat in [false, true] {
+            if self.entries.remove(&(path.to_path_buf(), lstat)).is_some() {
+                self.invalidations += 1;
+            }
+        }
+    }
+
+    /// The device and inode of what is cached for `path`
+    fn file_ids(&self, path: &Path) -> Vec<(u64, u64)> {
+        [false, true]
+            .into_iter()
+            .filter_map(
+                |lstat| match &self.entries.get(&(path.to_path_buf(), lstat))?.value {
+                    Cached::Attrs(attrs) => Some((attrs.dev, attrs.inode)),
+                    Cached::Missing { .. } => None,
+                },
+            )
+            .collect()
+    }
+
+    /// Drop the entries of every path that is one of the files `ids`.
+    fn remove_files(&mut self, ids: &[(u64, u64)]) {
+        if ids.is_empty() {
+            return;
+        }
+        let before = self.entries.len();
+        self.entries.retain(|_, entry| match &entry.value {
+            Cached::Attrs(attrs) => !ids.contains(&(attrs.dev, attrs.inode)),
+            Cached::Missing { .. } => true,
+        });
+        self.invalidations += (before - self.entries.len()) as u64;
+    }
+
+    fn generation(&self, dir: &Path) -> u64 {
+        let own = self.generations.get(dir).copied();
+        dir.ancestors()
+            .filter_map(|dir| self.tree_generations.get(dir).copied())
+            .chain(own)
+            .fold(self.base_generation, u64::max)
+    }
+
+    fn next_generation(&mut self) -> u64 {
+        if self.generations.len() + self.tree_generations.len() >= GENERATION_CAPACITY {
+            self.reset_generations();
+        }
+        self.generation_clock += 1;
+        self.generation_clock
+    }
+
+    /// Give every directory a new generation.
+    fn reset_generations(&mut self) {
+        self.generations.clear();
+        self.tree_generations.clear();
+        self.generation_clock += 1;
+        self.base_generation = self.generation_clock;
+    }
+
+    /// Record that the entries of `dir` changed.
+    fn bump(&mut self, dir: &Path) {
+        let generation = self.next_generation();
+        self.generations.insert(dir.to_path_buf(), generation);
+    }
+
+    /// Record that everything below `root` may have changed.
+    fn bump_tree(&mut self, root: &Path) {
+        let generation = self.next_generation();
+        self.tree_generations.insert(root.to_path_buf(), generation);
+    }
+
+    fn insert(&mut self, path: &Path, lstat: bool, value: Cached) {
+        if self.entries.len() >= CAPACITY {
+            self.evict_lru();
+        }
+        self.tick += 1;
+        let entry = Entry {
+            value,
+            inserted: Instant::now(),
+            last_used: self.tick,
+        };
+        self.entries.insert((path.to_path_buf(), lstat), entry);
+    }
+}
+
+static CACHE: LazyLock<Mutex<StatCache>> = LazyLock::new(|| Mutex::new(StatCache::new()));
+
+fn lock() -> std::sync::MutexGuard<'static, StatCache> {
+    CACHE.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Look up what is cached for `path`: its attributes, or that it is
+/// missing.
+pub fn lookup(path: &Path, lstat: bool) -> Option<Lookup> {
+    let mut cache = lock();
+    cache.tick += 1;
+    let tick = cache.tick;
+    let key = (path.to_path_buf(), lstat);
+    let parent_generation = match cache.entries.get(&key) {
+        Some(Entry {
+            value: Cached::Missing { .. },
+            ..
+        }) => path.parent().map(|parent| cache.generation(parent)),
+        _ => None,
+    };
+    let found = match cache.entries.get_mut(&key) {
+        Some(entry) if entry.inserted.elapsed() < STAT_TTL => match &entry.value {
+            Cached::Attrs(attrs) => {
+                entry.last_used = tick;
+                Some(Lookup::Found(attrs.clone()))
+            }
+            Cached::Missing { generation } if parent_generation == Some(*generation) => {
+                entry.last_used = tick;
+                Some(Lookup::Missing)
+            }
+            Cached::Missing { .. } => None,
+        },
+        _ => None,
+    };
+    match found {
+        Some(Lookup::Found(_)) => cache.hits += 1,
+        Some(Lookup::Missing) => cache.negative_hits += 1,
+        None => {
+            cache.entries.remove(&key);
+            cache.misses += 1;
+        }
+    }
+    found
+}
+
+/// Look up cached attributes for `path`.
+pub fn get(path: &Path, lstat: bool) -> Option<FileAttributes> {
+    match lookup(path, lstat)? {
+        Lookup::Found(attrs) => Some(attrs),
+        Lookup::Missing => None,
+    }
+}
+
+/// Cache attributes for `path`.
+pub fn insert(path: &Path, lstat: bool, attrs: &FileAttributes) {
+    lock().insert(path, lstat, Cached::Attrs(attrs.clone()));
+}
+
+/// The generation of the directory holding `path`, to take before a stat
+/// whose miss is passed to [`insert_missing`].
+pub fn parent_generation(path: &Path) -> u64 {
+    path.parent().map_or(0, |parent| lock().generation(parent))
+}
+
+/// Cache that `path` does not exist, as found by a stat begun when its
+/// parent had `generation`.  If the parent has changed since, the path may
+/// have been created meanwhile and nothing is cached.
+pub fn insert_missing(path: &Path, lstat: bool, generation: u64) {
+    let Some(parent) = path.parent() else {
+        return;
+    };
+    let mut cache = lock();
+    if cache.generation(parent) == generation {
+        cache.insert(path, lstat, Cached::Missing { generation });
+    }
+}
+
+/// The generation of directory `dir`, which changes whenever one of its
+/// entries is invalidated.
+pub fn generation(dir: &Path) -> u64 {
+    lock().generation(dir)
+}
+
+/// Drop cached attributes of `path` and its parent directory, whose mtime
+/// and link count change when entries are added or removed.
+///
+/// The same is done for the spelling of `path` with symlinks resolved, and
+/// entries of any path that is the same file (by device and inode) are
+/// dropped too, so a change made through a symlink does not leave the
+/// other spelling stale.  Finding those takes a stat, which runs on the
+/// blocking pool; code already there uses [`invalidate_blocking`].
+pub async fn invalidate(path: &Path) {
+    let found = find_spellings(vec![path.to_path_buf()]).await;
+    lock().invalidate(path, found.into_iter().next().unwrap_or_default());
+}
+
+/// [`invalidate`] for code running on a blocking thread.
+pub fn invalidate_blocking(path: &Path) {
+    let found = Spellings::of(path);
+    lock().invalidate(path, found);
+}
+
+/// Like [`invalidate`], but also drop everything below `path`.
+///
+/// Used for recursive operations and for renames or deletions that may
+/// affect whole directory trees.
+pub async fn invalidate_tree(path: &Path) {
+    invalidate_trees(&[path.to_path_buf()]).await;
+}
+
+/// [`invalidate_tree`] for each of `paths`, with one trip to the blocking
+/// pool for all of them.
+pub async fn invalidate_trees(paths: &[PathBuf]) {
+    if paths.is_empty() {
+        return;
+    }
+    let found = find_spellings(paths.to_vec()).await;
+    let mut cache = lock();
+    for (i, path) in paths.iter().enumerate() {
+        let found = found.get(i).cloned().unwrap_or_default();
+        cache.invalidate_tree(path, found);
+    }
+}
+
+impl StatCache {
+    fn invalidate(&mut self, path: &Path, found: Spellings) {
+        let mut ids: Vec<(u64, u64)> = found.current.into_iter().collect();
+        for path in std::iter::once(path).chain(found.resolved.as_deref()) {
+            ids.extend(self.file_ids(path));
+            self.remove_path(path);
+            self.bump(path);
+            if let Some(parent) = path.parent() {
+                self.remove_path(parent);
+                self.bump(parent);
+            }
+        }
+        self.remove_files(&ids);
+    }
+
+    fn invalidate_tree(&mut self, path: &Path, found: Spellings) {
+        let mut ids: Vec<(u64, u64)> = found.current.into_iter().collect();
+        for path in std::iter::once(path).chain(found.resolved.as_deref()) {
+            ids.extend(self.file_ids(path));
+            let before = self.entries.len();
+            self.entries.retain(|(p, _), _| !p.starts_with(path));
+            self.invalidations += (before - self.entries.len()) as u64;
+            self.bump_tree(path);
+            if let Some(parent) = path.parent() {
+                self.remove_path(parent);
+                self.bump(parent);
+            }
+        }
+        self.remove_files(&ids);
+    }
+}
+
+/// What the filesystem says about a path being invalidated, beyond its
+/// own spelling
+#[derive(Clone, Default)]
+struct Spellings {
+    /// The path with symlinks resolved, if that differs
+    resolved: Option<PathBuf>,
+    /// The device and inode it leads to now, if it exists
+    current: Option<(u64, u64)>,
+}
+
+impl Spellings {
+    fn of(path: &Path) -> Self {
+        Self {
+            resolved: resolved_spelling(path),
+            current: file_id(path),
+        }
+    }
+}
+
+/// [`Spellings::of`] each of `paths`, on the blocking pool.  If that times
+/// out, only the paths as given are invalidated.
+async fn find_spellings(paths: Vec<PathBuf>) -> Vec<Spellings> {
+    let Some(first) = paths.first().cloned() else {
+        return Vec::new();
+    };
+    crate::blocking::run(first, move || {
+        paths.iter().map(|path| Spellings::of(path)).collect()
+    })
+    .await
+    .unwrap_or_default()
+}
+
+/// `path` with symlinks resolved, if that differs: the path itself when it
+/// exists, otherwise its parent.
+fn resolved_spelling(path: &Path) -> Option<PathBuf> {
+    let resolved = std::fs::canonicalize(path).ok().or_else(|| {
+        let parent = std::fs::canonicalize(path.parent()?).ok()?;
+        Some(parent.join(path.file_name()?))
+    })?;
+    (resolved != path).then_some(resolved)
+}
+
+/// The device and inode `path` leads to now, if it exists.
+fn file_id(path: &Path) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+
+    let meta = std::fs::metadata(path).ok()?;
+    Some((meta.dev(), meta.ino()))
+}
+
+/// Drop every cached entry (e.g. after the watcher lost events).
+pub fn clear() {
+    let mut cache = lock();
+    cache.invalidations += cache.entries.len() as u64;
+    cache.entries.clear();
+    cache.reset_generations();
+}
+
+/// Counters for `system.stats`.
+pub fn stats() -> Value {
+    let cache = lock();
+    let lookups = cache.hits + cache.misses;
+    let hit_rate = if lookups == 0 {
+        0.0
+    } else {
+        cache.hits as f64 / lookups as f64
+    };
+    let negative = cache
+        .entries
+        .values()
+        .filter(|e| matches!(e.value, Cached::Missing { .. }))
+        .count();
+    msgpack_map! {
+        "entries" => cache.entries.len(),
+        "negative_entries" => negative,
+        "capacity" => CAPACITY,
+        "ttl_ms" => STAT_TTL.as_millis() as u64,
+        "hits" => cache.hits,
+        "misses" => cache.misses,
+        "hit_rate" => hit_rate,
+        "negative_hits" => cache.negative_hits,
+        "generations" => cache.generations.len() + cache.tree_generations.len(),
+        "evictions" => cache.evictions,
+        "invalidations" => cache.invalidations
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::FileType;
+
+    fn attrs(size: u64) -> FileAttributes {
+        FileAttributes {
+            file_type: FileType::File,
+            nlinks: 1,
+            uid: 0,
+            gid: 0,
+            uname: None,
+            gname: None,
+            atime: 0,
+            mtime: 0,
+            ctime: 0,
+            size,
+            mode: 0o100644,
+            // No real file's, so only the paths it is cached for match
+            inode: size,
+            dev: u64::MAX,
+            link_target: None,
+            btime: None,
+            btime_nsec: None,
+            flags: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn stat_cache_invalidates_paths_parents_and_trees() {
+        // Use unique paths: the cache is process-global.
+        let root = PathBuf::from("/stat-cache-test/root");
+        let file = root.join("dir").join("file");
+        insert(&root, false, &attrs(1));
+        insert(&root.join("dir"), false, &attrs(2));
+        insert(&file, true, &attrs(3));
+
+        assert_eq!(get(&file, true).map(|a| a.size), Some(3));
+        assert!(get(&file, false).is_none());
+
+        invalidate(&file).await;
+        assert!(get(&file, true).is_none());
+        assert!(get(&root.join("dir"), false).is_none());
+        assert_eq!(get(&root, false).map(|a| a.size), Some(1));
+
+        insert(&file, false, &attrs(4));
+        invalidate_tree(&root.join("dir")).await;
+        assert!(get(&file, false).is_none());
+        assert!(get(&root, false).is_none());
+    }
+
+    #[tokio::test]
+    async fn changes_through_a_symlink_drop_both_spellings() {
+        use std::os::unix::fs::MetadataExt;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = std::fs::canonicalize(tmp.path()).unwrap();
+        let target = dir.join("target");
+        let link = dir.join("link");
+        std::fs::write(&target, b"one").unwrap();
+        std::os::unix::fs::symlink("target", &link).unwrap();
+        let meta = std::fs::metadata(&target).unwrap();
+        let real = FileAttributes {
+            inode: meta.ino(),
+            dev: meta.dev(),
+            ..attrs(3)
+        };
+
+        for (changed, other) in [(&target, &link), (&link, &target)] {
+            insert(&target, true, &real);
+            insert(&link, false, &real);
+            invalidate(changed).await;
+            assert!(get(other, other == &target).is_none(), "{:?}", other);
+        }
+        // Resolved even when the file is gone
+        insert(&target, true, &real);
+        std::fs::remove_file(&target).unwrap();
+        invalidate(&dir.join(".").join("target")).await;
+        assert!(get(&target, true).is_none());
+    }
+
+    fn missing(path: &Path) -> bool {
+        matches!(lookup(path, true), Some(Lookup::Missing))
+    }
+
+    #[tokio::test]
+    async fn missing_paths_are_cached_until_their_directory_changes() {
+        let dir = PathBuf::from("/stat-cache-test/negative");
+        let lock_file = dir.join(".#file");
+        let backup = dir.join("file~");
+
+        insert_missing(&lock_file, true, parent_generation(&lock_file));
+        insert_missing(&backup, true, parent_generation(&backup));
+        assert!(missing(&lock_file) && missing(&backup));
+        assert!(lookup(&lock_file, false).is_none());
+
+        // Creating the path drops its entry
+        invalidate(&lock_file).await;
+        assert!(!missing(&lock_file));
+        // and any other change in the directory drops the others
+        assert!(!missing(&backup));
+
+        insert_missing(&backup, true, parent_generation(&backup));
+        invalidate_tree(&dir).await;
+        assert!(!missing(&backup));
+    }
+
+    #[tokio::test]
+    async fn a_miss_found_before_a_change_is_not_cached() {
+        let file = PathBuf::from("/stat-cache-test/race/file");
+        // The stat starts and finds nothing...
+        let generation = parent_generation(&file);
+        // ...while the file is created and the change is seen...
+        invalidate(&file).await;
+        // ...so the late miss is dropped.
+        insert_missing(&file, true, generation);
+        assert!(lookup(&file, true).is_none());
+    }
+
+    #[tokio::test]
+    async fn generations_change_with_the_directory_only() {
+        let root = PathBuf::from("/stat-cache-test/generations");
+        let (dir, other) = (root.join("dir"), root.join("other"));
+        let before = (generation(&dir), generation(&other));
+
+        invalidate(&dir.join("file")).await;
+        let after = generation(&dir);
+        assert!(after > before.0);
+        assert_eq!(generation(&other), before.1);
+
+        // A whole tree changes with its root
+        invalidate_tree(&root).await;
+        assert!(generation(&dir) > after);
+        assert!(generation(&other) > before.1);
+    }
+}
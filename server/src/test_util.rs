@@ -0,0 +1,30 @@
+//! Helpers shared by the unit tests.
+
+use rmpv::Value;
+use std::path::Path;
+use std::process::Command;
+
+/// The value under `key` in a msgpack map, if `value` is one and has it
+pub(crate) fn map_get<'a>(value: &'a Value, key: &str) -> Option<&'a Value> {
+    value
+        .as_map()?
+        .iter()
+        .find(|(k, _)| k.as_str() == Some(key))
+        .map(|(_, v)| v)
+}
+
+/// Run git in `dir` with a fixed identity and unsigned commits, failing the
+/// test if it fails
+pub(crate) fn git(dir: &Path, args: &[&str]) {
+    let status = Command::new("git")
+        .args(["-c", "commit.gpgsign=false"])
+        .args(args)
+        .current_dir(dir)
+        .env("GIT_AUTHOR_NAME", "Test Author")
+        .env("GIT_AUTHOR_EMAIL", "author@example.com")
+        .env("GIT_COMMITTER_NAME", "Test Author")
+        .env("GIT_COMMITTER_EMAIL", "author@example.com")
+        .status()
+        .expect("run git");
+    assert!(status.success(), "git {:?} failed", args);
+}
@@ -0,0 +1,189 @@
+//! Server-side request durations.
+//!
+//! A request is timed from the moment its frame has been read: `queued_us`
+//! until its handler starts, which covers waiting for a task and for a
+//! concurrency slot, then `handler_us` for the handler itself.  The
+//! durations of every method go into a histogram reported as `latency` in
+//! `system.stats`.  With the `timing` feature (see [`crate::handshake`])
+//! each response also carries them as `timing: {queued_us, handler_us}`,
+//! next to `result` or `error`, so a client can tell server work from
+//! network latency.
+
+use crate::handshake::{self, Feature};
+use crate::msgpack_map;
+use crate::protocol::{Response, RpcError};
+use rmpv::Value;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Upper bounds of the histogram buckets, in microseconds; the last bucket
+/// holds everything slower.
+const BOUNDS_US: [u64; 6] = [100, 1_000, 10_000, 100_000, 1_000_000, 10_000_000];
+
+static METHODS: Mutex<BTreeMap<String, Latency>> = Mutex::new(BTreeMap::new());
+
+tokio::task_local! {
+    static RECEIVED: Instant;
+}
+
+/// Durations of one method's requests
+#[derive(Default)]
+struct Latency {
+    count: u64,
+    queued_us: u64,
+    handler_us: u64,
+    max_handler_us: u64,
+    buckets: [u64; BOUNDS_US.len() + 1],
+}
+
+impl Latency {
+    fn add(&mut self, queued_us: u64, handler_us: u64) {
+        self.count += 1;
+        self.queued_us = self.queued_us.saturating_add(queued_us);
+        self.handler_us = self.handler_us.saturating_add(handler_us);
+        self.max_handler_us = self.max_handler_us.max(handler_us);
+        let bucket = BOUNDS_US
+            .iter()
+            .position(|&bound| handler_us < bound)
+            .unwrap_or(BOUNDS_US.len());
+        self.buckets[bucket] += 1;
+    }
+
+    fn to_value(&self) -> Value {
+        msgpack_map! {
+            "count" => self.count,
+            "queued_us" => self.queued_us,
+            "handler_us" => self.handler_us,
+            "max_handler_us" => self.max_handler_us,
+            "buckets" => Value::Array(self.buckets.iter().map(|&n| n.into()).collect())
+        }
+    }
+}
+
+/// Run `future`, the handling of a frame read at `received`.
+pub async fn scope<F: Future>(received: Instant, future: F) -> F::Output {
+    RECEIVED.scope(received, future).await
+}
+
+/// Times a handler from its start.
+pub struct Stopwatch {
+    received: Instant,
+    started: Instant,
+}
+
+/// Start timing a handler.  Outside of [`scope`], as for the items of a
+/// batch run by a test, the request counts as received now.
+pub fn start() -> Stopwatch {
+    let started = Instant::now();
+    Stopwatch {
+        received: RECEIVED.try_with(|received| *received).unwrap_or(started),
+        started,
+    }
+}
+
+fn micros(duration: Duration) -> u64 {
+    duration.as_micros().try_into().unwrap_or(u64::MAX)
+}
+
+impl Stopwatch {
+    /// Record the handler of `method` as done with `response`.
+    pub fn finish(self, method: &str, response: &mut Response) {
+        self.report(method, response, handshake::enabled(Feature::Timing));
+    }
+
+    fn report(self, method: &str, response: &mut Response, attach: bool) {
+        let queued_us = micros(self.started.saturating_duration_since(self.received));
+        let handler_us = micros(self.started.elapsed());
+        // Unknown methods would grow the table without bound
+        if response
+            .error
+            .as_ref()
+            .is_none_or(|error| error.code != RpcError::METHOD_NOT_FOUND)
+        {
+            METHODS
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .entry(method.to_string())
+                .or_default()
+                .add(queued_us, handler_us);
+        }
+        if attach {
+            response.timing = Some(msgpack_map! {
+                "queued_us" => queued_us,
+                "handler_us" => handler_us
+            });
+        }
+    }
+}
+
+/// `{bounds_us, methods}` for `system.stats`, with `{count, queued_us,
+/// handler_us, max_handler_us, buckets}` per method.  Sums are in
+/// microseconds; `buckets[i]` counts handlers faster than `bounds_us[i]`
+/// and not faster than the bound before, the last one all slower ones.
+pub fn stats() -> Value {
+    let methods = METHODS.lock().unwrap_or_else(|e| e.into_inner());
+    msgpack_map! {
+        "bounds_us" => Value::Array(BOUNDS_US.iter().map(|&n| n.into()).collect()),
+        "methods" => Value::Map(
+            methods
+                .iter()
+                .map(|(method, latency)| (method.as_str().into(), latency.to_value()))
+                .collect()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::RequestId;
+
+    fn encoded_keys(response: &Response) -> Vec<String> {
+        let bytes = rmp_serde::to_vec_named(response).unwrap();
+        let value = rmpv::decode::read_value(&mut &bytes[..]).unwrap();
+        value
+            .as_map()
+            .unwrap()
+            .iter()
+            .filter_map(|(key, _)| key.as_str().map(str::to_string))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn durations_are_attached_only_when_enabled() {
+        let received = Instant::now() - Duration::from_millis(5);
+        let mut response = Response::success(RequestId::Number(1), true);
+        scope(received, async { start() })
+            .await
+            .report("timing.test", &mut response, false);
+        assert!(response.timing.is_none());
+        assert!(!encoded_keys(&response).contains(&"timing".to_string()));
+
+        let mut response = Response::error(Some(RequestId::Number(2)), RpcError::parse_error("x"));
+        scope(received, async { start() })
+            .await
+            .report("timing.test", &mut response, true);
+        let timing = response.timing.clone().unwrap();
+        assert!(timing["queued_us"].as_u64().unwrap() >= 5_000);
+        assert!(timing["handler_us"].as_u64().is_some());
+        assert!(encoded_keys(&response).contains(&"timing".to_string()));
+
+        let stats = stats();
+        let latency = &stats["methods"]["timing.test"];
+        assert_eq!(latency["count"].as_u64(), Some(2));
+        assert_eq!(
+            latency["buckets"].as_array().unwrap().len(),
+            BOUNDS_US.len() + 1
+        );
+
+        // Unknown methods are not recorded
+        let mut response = Response::error(
+            Some(RequestId::Number(3)),
+            RpcError::method_not_found("timing.unknown"),
+        );
+        start().report("timing.unknown", &mut response, false);
+        assert_eq!(super::stats()["methods"]["timing.unknown"], Value::Nil);
+    }
+}
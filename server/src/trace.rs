@@ -0,0 +1,40 @@
+//! Request traces echoed in notifications.
+//!
+//! A request may carry an opaque `trace` value next to its `id`.  Long-lived
+//! resources the request creates (watches, stat streams, downloads, password
+//! prompts, managed processes) keep it, and notifications sent for them
+//! carry `context: {trace, resource, id}`: the trace, the kind of resource
+//! and its id (the watch root, stream id, URL, prompt id or pid).  Clients
+//! can route notifications by looking the trace up instead of guessing from
+//! their contents.  Resources created without a trace have no context.
+
+use crate::msgpack_map;
+use rmpv::Value;
+use std::future::Future;
+
+tokio::task_local! {
+    /// Trace of the request being handled
+    static TRACE: Option<Value>;
+}
+
+/// Run `future` as the handler of a request with `trace`.
+pub async fn scope<F: Future>(trace: Option<Value>, future: F) -> F::Output {
+    TRACE.scope(trace, future).await
+}
+
+/// The trace of the request being handled, if it has one.
+pub fn current() -> Option<Value> {
+    TRACE.try_with(Clone::clone).ok().flatten()
+}
+
+/// The `context` of a notification about the resource `id` of kind
+/// `resource`, or `None` without a trace.
+pub fn context(trace: Option<Value>, resource: &str, id: impl Into<Value>) -> Option<Value> {
+    trace.map(|trace| {
+        msgpack_map! {
+            "trace" => trace,
+            "resource" => resource,
+            "id" => id.into()
+        }
+    })
+}
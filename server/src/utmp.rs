@@ -0,0 +1,149 @@
+//! Login records for PTY sessions.
+//!
+//! `process.start_pty` with `register_utmp` writes a `USER_PROCESS` utmpx
+//! record for the session's tty, so it shows in `who` and `w` and counts
+//! for login accounting, and a `DEAD_PROCESS` record once the session is
+//! over: when its process exits, when it is closed or killed, or when the
+//! server shuts down.  On glibc both are also appended to wtmp.  Writing the
+//! records usually needs membership in the `utmp` group; without it the
+//! session starts anyway and reports why it is not registered.
+
+use std::sync::Mutex;
+
+/// The utmpx functions share one open database, so they are serialized.
+static DATABASE: Mutex<()> = Mutex::new(());
+
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+const WTMP: &std::ffi::CStr = c"/var/log/wtmp";
+
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+unsafe extern "C" {
+    fn updwtmpx(wtmpx_file: *const libc::c_char, utmpx: *const libc::utmpx);
+}
+
+/// A registered session, recorded as dead when dropped.
+pub struct Login {
+    line: String,
+    pid: libc::pid_t,
+    user: String,
+}
+
+impl Login {
+    /// Register the session of process `pid` on the tty `tty_name` (as
+    /// "/dev/pts/3") for the current user.
+    pub fn register(tty_name: &str, pid: libc::pid_t) -> Result<Self, String> {
+        let user = crate::handlers::file::get_user_name(unsafe { libc::geteuid() })
+            .ok_or("the current user has no name")?;
+        let login = Login {
+            line: tty_name
+                .strip_prefix("/dev/")
+                .unwrap_or(tty_name)
+                .to_string(),
+            pid,
+            user,
+        };
+        login.write(libc::USER_PROCESS)?;
+        Ok(login)
+    }
+
+    /// The record of kind `kind` for this session.
+    fn record(&self, kind: libc::c_short) -> libc::utmpx {
+        let mut record: libc::utmpx = unsafe { std::mem::zeroed() };
+        record.ut_type = kind;
+        record.ut_pid = self.pid;
+        copy(&mut record.ut_line, self.line.as_bytes());
+        // The last four characters of the line, as sshd and login use
+        let id = &self.line.as_bytes()[self.line.len().saturating_sub(4)..];
+        copy(&mut record.ut_id, id);
+        if kind == libc::USER_PROCESS {
+            copy(&mut record.ut_user, self.user.as_bytes());
+            if let Ok(client) = std::env::var("SSH_CLIENT")
+                && let Some(host) = client.split_whitespace().next()
+            {
+                copy(&mut record.ut_host, host.as_bytes());
+            }
+        }
+        let mut now = libc::timeval {
+            tv_sec: 0,
+            tv_usec: 0,
+        };
+        unsafe { libc::gettimeofday(&mut now, std::ptr::null_mut()) };
+        record.ut_tv.tv_sec = now.tv_sec as _;
+        record.ut_tv.tv_usec = now.tv_usec as _;
+        record
+    }
+
+    fn write(&self, kind: libc::c_short) -> Result<(), String> {
+        let record = self.record(kind);
+        let _database = DATABASE.lock().unwrap_or_else(|e| e.into_inner());
+        let written = unsafe {
+            libc::setutxent();
+            let written = libc::pututxline(&record);
+            let error = std::io::Error::last_os_error();
+            libc::endutxent();
+            if written.is_null() {
+                Err(error)
+            } else {
+                Ok(())
+            }
+        };
+        written.map_err(|e| format!("Failed to write the utmp record: {}", e))?;
+        #[cfg(all(target_os = "linux", target_env = "gnu"))]
+        unsafe {
+            updwtmpx(WTMP.as_ptr(), &record)
+        };
+        Ok(())
+    }
+}
+
+impl Drop for Login {
+    fn drop(&mut self) {
+        let _ = self.write(libc::DEAD_PROCESS);
+    }
+}
+
+/// Copy `bytes` into the fixed-size field `field`, truncated and
+/// NUL-padded as utmpx fields are.
+fn copy(field: &mut [libc::c_char], bytes: &[u8]) {
+    for (to, from) in field.iter_mut().zip(bytes) {
+        *to = *from as libc::c_char;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The contents of the utmpx field `field`.
+    fn field(field: &[libc::c_char]) -> String {
+        let bytes: Vec<u8> = field
+            .iter()
+            .take_while(|c| **c != 0)
+            .map(|c| *c as u8)
+            .collect();
+        String::from_utf8(bytes).unwrap()
+    }
+
+    #[test]
+    fn records_name_the_line_and_user() {
+        let login = Login {
+            line: "pts/12".to_string(),
+            pid: 4242,
+            user: "alice".to_string(),
+        };
+
+        let record = login.record(libc::USER_PROCESS);
+        assert_eq!(record.ut_type, libc::USER_PROCESS);
+        assert_eq!(record.ut_pid, 4242);
+        assert_eq!(field(&record.ut_line), "pts/12");
+        assert_eq!(field(&record.ut_id), "s/12");
+        assert_eq!(field(&record.ut_user), "alice");
+        assert!(record.ut_tv.tv_sec > 0);
+
+        // The dead record matches the entry by its id and line only
+        let record = login.record(libc::DEAD_PROCESS);
+        assert_eq!(field(&record.ut_id), "s/12");
+        assert_eq!(field(&record.ut_user), "");
+        std::mem::forget(login);
+    }
+}
@@ -4,16 +4,18 @@
 //! directories for changes. When changes are detected, a debounced
 //! notification is sent to the Emacs client so it can invalidate its caches.
 
-use crate::protocol::{Notification, RpcError};
+use crate::jail;
+use crate::notifications;
+use crate::protocol::{NamesAs, Notification, RpcError};
+use crate::stat_cache;
 use crate::{WriterHandle, msgpack_map};
 use notify::event::{DataChange, MetadataKind, ModifyKind, RemoveKind, RenameMode};
 use notify::{Config, Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
 use rmpv::Value;
 use std::collections::{HashMap, HashSet};
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, Mutex, OnceLock, Weak};
-use tokio::io::AsyncWriteExt;
-use tokio::sync::mpsc;
+use std::sync::{Arc, LazyLock, Mutex, OnceLock, Weak};
+use tokio::sync::{broadcast, mpsc};
 use tokio::time::{self, Duration};
 
 use crate::handlers::file::bytes_to_path;
@@ -37,6 +39,46 @@ pub fn init(manager: Arc<WatchManager>) {
     let _ = WATCH_MANAGER.set(manager);
 }
 
+/// Each debounced batch of events, for waiters inside the server such as
+/// `file.wait_changed`.
+static CHANGES: LazyLock<broadcast::Sender<Arc<Changes>>> =
+    LazyLock::new(|| broadcast::channel(64).0);
+
+/// The paths one debounced batch of events touched.
+pub struct Changes {
+    paths: Vec<PathBuf>,
+    rescan: bool,
+}
+
+impl Changes {
+    fn of(events: &[WatchEvent]) -> Self {
+        Self {
+            paths: events
+                .iter()
+                .flat_map(|event| event.path.iter().chain(&event.path1))
+                .cloned()
+                .collect(),
+            rescan: events.iter().any(|event| event.action == "rescan"),
+        }
+    }
+
+    /// Whether `path` (canonical), one of its entries or one of its
+    /// parents may have changed.
+    pub fn touch(&self, path: &Path) -> bool {
+        self.rescan
+            || self
+                .paths
+                .iter()
+                .any(|changed| path.starts_with(changed) || changed.parent() == Some(path))
+    }
+}
+
+/// Receive every later batch of watch events.  Events only arrive for
+/// watched paths, see [`WatchManager::covers`].
+pub fn subscribe() -> broadcast::Receiver<Arc<Changes>> {
+    CHANGES.subscribe()
+}
+
 /// Helper to lock a std::sync::Mutex, recovering from poisoning.
 /// The data is still valid after a panic, so we just unwrap the poison error.
 fn lock_or_recover<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
@@ -104,7 +146,7 @@ impl FilteredWatcher {
     fn watch_recursive(&mut self, path: &Path) -> Result<(), notify::Error> {
         let dirs = Self::collect_recursive_dirs(path);
         if self.recursive_roots.contains_key(path) {
-            return self.apply_recursive_dirs(path, dirs);
+            return self.apply_recursive_dirs(path, dirs).map(drop);
         }
 
         // Seed the root with an empty set so initial registration can use the
@@ -123,6 +165,15 @@ impl FilteredWatcher {
         self.recursive_roots.keys().cloned().collect()
     }
 
+    /// Directories the backend watches for `root`: those below a recursive
+    /// root, itself included, or just the path of a direct watch.
+    fn directory_count(&self, root: &Path) -> usize {
+        self.recursive_roots.get(root).map_or(
+            usize::from(self.direct_watches.contains(root)),
+            HashSet::len,
+        )
+    }
+
     fn recursive_roots_for_paths(&self, paths: &[PathBuf]) -> Vec<PathBuf> {
         self.recursive_roots
             .iter()
@@ -148,14 +199,15 @@ impl FilteredWatcher {
             .collect()
     }
 
-    /// Reconcile one recursive root to a freshly scanned directory set.
+    /// Reconcile one recursive root to a freshly scanned directory set,
+    /// returning the directories that were not watched before.
     fn apply_recursive_dirs(
         &mut self,
         root: &Path,
         next: HashSet<PathBuf>,
-    ) -> Result<(), notify::Error> {
+    ) -> Result<Vec<PathBuf>, notify::Error> {
         let Some(current) = self.recursive_roots.get(root).cloned() else {
-            return Ok(());
+            return Ok(Vec::new());
         };
 
         let to_remove: Vec<_> = current.difference(&next).cloned().collect();
@@ -183,7 +235,7 @@ impl FilteredWatcher {
         }
 
         self.recursive_roots.insert(root.to_path_buf(), next);
-        Ok(())
+        Ok(added)
     }
 
     fn add_path_watch(&mut self, path: &Path) -> Result<(), notify::Error> {
@@ -323,17 +375,25 @@ impl WatchEvent {
         }
     }
 
-    fn to_value(&self) -> Value {
+    fn to_value(&self, names: NamesAs) -> Value {
+        use std::os::unix::ffi::OsStrExt;
+
         let mut fields = vec![(
             Value::String("action".into()),
             Value::String(self.action.into()),
         )];
 
         if let Some(path) = &self.path {
-            fields.push((Value::String("path".into()), path_to_value(path)));
+            fields.push((
+                Value::String("path".into()),
+                names.encode(path.as_os_str().as_bytes()),
+            ));
         }
         if let Some(path1) = &self.path1 {
-            fields.push((Value::String("path1".into()), path_to_value(path1)));
+            fields.push((
+                Value::String("path1".into()),
+                names.encode(path1.as_os_str().as_bytes()),
+            ));
         }
         if let Some(cookie) = self.cookie {
             fields.push((Value::String("cookie".into()), Value::from(cookie as u64)));
@@ -608,6 +668,12 @@ pub struct WatchManager {
 
     /// Nofollow symlink watches for file-notify descriptors.
     symlink_watcher: Mutex<Option<NofollowSymlinkWatcher>>,
+
+    /// How paths are encoded in notifications, as last set by `watch.add`.
+    names_as: Mutex<NamesAs>,
+
+    /// Traces of the requests that added watches, by watched path.
+    traces: Mutex<HashMap<PathBuf, Value>>,
 }
 
 impl WatchManager {
@@ -637,6 +703,8 @@ impl WatchManager {
             watcher: Mutex::new(watcher),
             watched_paths: Mutex::new(HashMap::new()),
             symlink_watcher: Mutex::new(NofollowSymlinkWatcher::new(tx).ok()),
+            names_as: Mutex::new(NamesAs::Binary),
+            traces: Mutex::new(HashMap::new()),
         });
 
         // Spawn the debounce background task
@@ -723,6 +791,7 @@ impl WatchManager {
             if let Some(watcher) = symlink_watcher.as_mut()
                 && watcher.contains(path)
             {
+                lock_or_recover(&self.traces).remove(path);
                 return watcher.unwatch(path);
             }
         }
@@ -745,19 +814,135 @@ impl WatchManager {
 
         watcher.unwatch(&canonical)?;
         paths.remove(&canonical);
+        lock_or_recover(&self.traces).remove(&canonical);
 
         Ok(())
     }
 
-    /// List currently watched paths and whether they are recursive.
-    pub fn list(&self) -> Vec<(PathBuf, bool)> {
+    /// Remove every watch, as the server does when it shuts down.
+    pub fn unwatch_all(&self) {
+        lock_or_recover(&self.symlink_watcher).take();
+        let mut watcher = lock_or_recover(&self.watcher);
+        for (path, _) in lock_or_recover(&self.watched_paths).drain() {
+            let _ = watcher.unwatch(&path);
+        }
+        lock_or_recover(&self.traces).clear();
+    }
+
+    /// Keep `trace` for the watch on `path`, or forget an earlier one.
+    fn set_trace(&self, path: &Path, trace: Option<Value>) {
+        let mut traces = lock_or_recover(&self.traces);
+        match trace {
+            Some(trace) => traces.insert(path.to_path_buf(), trace),
+            None => traces.remove(path),
+        };
+    }
+
+    /// Split `events` by the watch they belong to, each group with the
+    /// `context` of its watch's trace.
+    ///
+    /// Events map to the innermost watched path containing them; rescan
+    /// events and events of watches without a trace share the group
+    /// without a context.  Without any traces this is a single group.
+    fn group_by_trace(&self, events: &[WatchEvent]) -> Vec<(Option<Value>, Vec<WatchEvent>)> {
+        let traces = lock_or_recover(&self.traces).clone();
+        if traces.is_empty() {
+            return vec![(None, events.to_vec())];
+        }
+        let mut roots: Vec<PathBuf> = lock_or_recover(&self.watched_paths)
+            .keys()
+            .cloned()
+            .collect();
+        roots.extend(traces.keys().cloned());
+        let mut groups: Vec<(Option<PathBuf>, Vec<WatchEvent>)> = Vec::new();
+        for event in events {
+            let root = event.path.as_ref().and_then(|path| {
+                roots
+                    .iter()
+                    .filter(|root| path.starts_with(root))
+                    .max_by_key(|root| root.as_os_str().len())
+                    .filter(|root| traces.contains_key(*root))
+                    .cloned()
+            });
+            match groups.iter_mut().find(|(key, _)| *key == root) {
+                Some((_, group)) => group.push(event.clone()),
+                None => groups.push((root, vec![event.clone()])),
+            }
+        }
+        groups
+            .into_iter()
+            .map(|(root, events)| {
+                let context = root.and_then(|root| {
+                    crate::trace::context(traces.get(&root).cloned(), "watch", path_to_value(&root))
+                });
+                (context, events)
+            })
+            .collect()
+    }
+
+    /// List currently watched paths, whether they are recursive and how
+    /// many directories each has the backend watch.
+    pub fn list(&self) -> Vec<(PathBuf, bool, usize)> {
+        let watcher = lock_or_recover(&self.watcher);
         let paths = lock_or_recover(&self.watched_paths);
         paths
             .iter()
-            .map(|(p, m)| (p.clone(), matches!(m, RecursiveMode::Recursive)))
+            .map(|(p, m)| {
+                (
+                    p.clone(),
+                    matches!(m, RecursiveMode::Recursive),
+                    watcher.directory_count(p),
+                )
+            })
             .collect()
     }
 
+    /// Directories watched by the backend, implicit children of recursive
+    /// watches included.
+    pub fn directory_count(&self) -> usize {
+        lock_or_recover(&self.watcher).path_watch_counts.len()
+    }
+
+    /// Whether changes to the entries of directory `path` are watched: it
+    /// is watched itself or lies below a recursive watch.
+    pub fn covers(&self, path: &Path) -> bool {
+        let canonical = std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+        let paths = lock_or_recover(&self.watched_paths);
+        paths.iter().any(|(root, mode)| {
+            *root == canonical
+                || matches!(mode, RecursiveMode::Recursive) && canonical.starts_with(root)
+        })
+    }
+
+    /// Watch roots covering `events`, for an `fs.resync` notification.
+    ///
+    /// Each path maps to the innermost watched directory containing it, or
+    /// to itself if none does (as for symlink watches).  A rescan event
+    /// covers every root.
+    fn resync_roots(&self, events: &[WatchEvent]) -> Vec<PathBuf> {
+        let watched: Vec<PathBuf> = lock_or_recover(&self.watched_paths)
+            .keys()
+            .cloned()
+            .collect();
+        let mut roots = HashSet::new();
+        for event in events {
+            if event.path.is_none() {
+                roots.extend(watched.iter().cloned());
+            }
+            for path in event.path.iter().chain(&event.path1) {
+                let root = watched
+                    .iter()
+                    .filter(|root| path.starts_with(root))
+                    .max_by_key(|root| root.as_os_str().len())
+                    .unwrap_or(path);
+                roots.insert(root.clone());
+            }
+        }
+        let mut roots: Vec<_> = roots.into_iter().collect();
+        roots.sort();
+        roots
+    }
+
     fn recursive_roots_for_event(&self, event: &Event) -> HashSet<PathBuf> {
         let refresh_paths = directory_tree_refresh_paths(event);
         let ignore_paths = ignore_rule_paths(event);
@@ -783,9 +968,17 @@ impl WatchManager {
         roots_to_refresh
     }
 
-    fn refresh_recursive_roots(&self, roots_to_refresh: HashSet<PathBuf>) {
+    /// Rescan `roots_to_refresh` and watch the directories that appeared
+    /// below them, returning those directories.
+    ///
+    /// Created directories are watched here rather than left to the
+    /// backend, which only watches each directory registered with it, so
+    /// all backends behave alike.  The new directories may already hold
+    /// entries created before their watch was in place, as with `mkdir -p`;
+    /// see [`entries_of_new_dirs`].
+    fn refresh_recursive_roots(&self, roots_to_refresh: HashSet<PathBuf>) -> Vec<PathBuf> {
         if roots_to_refresh.is_empty() {
-            return;
+            return Vec::new();
         }
 
         let refreshed_roots: Vec<_> = roots_to_refresh
@@ -797,9 +990,13 @@ impl WatchManager {
             .collect();
 
         let mut watcher = lock_or_recover(&self.watcher);
+        let mut added = Vec::new();
         for (root, dirs) in refreshed_roots {
-            let _ = watcher.apply_recursive_dirs(&root, dirs);
+            if let Ok(dirs) = watcher.apply_recursive_dirs(&root, dirs) {
+                added.extend(dirs);
+            }
         }
+        added
     }
 
     /// Rebind still-existing watches after Linux/inotify inode replacement.
@@ -835,6 +1032,31 @@ impl WatchManager {
     }
 }
 
+/// `created` events for the entries of the newly watched directories
+/// `dirs` that `pending` does not report yet.  Entries made before a
+/// directory's watch was in place produce no events of their own.
+fn entries_of_new_dirs(dirs: &[PathBuf], pending: &[WatchEvent]) -> Vec<WatchEvent> {
+    let known: HashSet<&Path> = pending
+        .iter()
+        .filter(|event| event.action == "created")
+        .filter_map(|event| event.path.as_deref())
+        .collect();
+    let mut events = Vec::new();
+    for dir in dirs {
+        let Ok(entries) = std::fs::read_dir(dir) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if !known.contains(path.as_path()) {
+                events.push(WatchEvent::path("created", path));
+            }
+        }
+    }
+    events.sort_by(|a, b| a.path.cmp(&b.path));
+    events
+}
+
 fn directory_tree_refresh_paths(event: &Event) -> Vec<PathBuf> {
     match event.kind {
         EventKind::Create(_) | EventKind::Modify(ModifyKind::Any | ModifyKind::Other) => {
@@ -1024,7 +1246,8 @@ async fn debounce_loop(
             &mut pending_events,
             &mut roots_to_refresh,
             &mut suspect_paths,
-        );
+        )
+        .await;
 
         // Phase 2: Collect more events during the debounce window
         let deadline = time::Instant::now() + DEBOUNCE_DURATION;
@@ -1042,7 +1265,7 @@ async fn debounce_loop(
                                 &mut pending_events,
                                 &mut roots_to_refresh,
                                 &mut suspect_paths,
-                            );
+                            ).await;
                         }
                         None => return, // Channel closed
                     }
@@ -1051,12 +1274,22 @@ async fn debounce_loop(
         }
 
         if let Some(manager) = manager.upgrade() {
-            manager.refresh_recursive_roots(roots_to_refresh);
+            let new_dirs = manager.refresh_recursive_roots(roots_to_refresh);
+            let missed = entries_of_new_dirs(&new_dirs, &pending_events);
+            pending_events.extend(missed);
             manager.rearm_suspect_paths(suspect_paths);
         }
+        if !pending_events.is_empty() {
+            // Nobody listening is fine
+            let _ = CHANGES.send(Arc::new(Changes::of(&pending_events)));
+        }
 
-        // Phase 3: Send notification with all collected events
-        if !pending_events.is_empty() && send_notification(&writer, &pending_events).await.is_err()
+        // Phase 3: Send notification with all collected events, or just the
+        // affected roots if that would exceed the notification budget
+        if !pending_events.is_empty()
+            && send_events(&writer, &manager, &pending_events)
+                .await
+                .is_err()
         {
             // Stdout is broken (Emacs disconnected), stop the loop.
             // Cannot use eprintln! as SSH merges stderr with stdout.
@@ -1065,7 +1298,7 @@ async fn debounce_loop(
     }
 }
 
-fn collect_input(
+async fn collect_input(
     input: WatchInput,
     manager: &Weak<WatchManager>,
     pending_events: &mut Vec<WatchEvent>,
@@ -1074,6 +1307,10 @@ fn collect_input(
 ) {
     match input {
         WatchInput::Notify(event) => {
+            if event.need_rescan() {
+                stat_cache::clear();
+            }
+            stat_cache::invalidate_trees(&event.paths).await;
             if let Some(manager) = manager.upgrade() {
                 roots_to_refresh.extend(manager.recursive_roots_for_event(&event));
             }
@@ -1082,12 +1319,19 @@ fn collect_input(
             pending_events.extend(event_to_watch_events(&event));
         }
         #[cfg(any(target_os = "linux", target_os = "android"))]
-        WatchInput::Direct(events) => pending_events.extend(events),
+        WatchInput::Direct(events) => {
+            let paths: Vec<PathBuf> = events
+                .iter()
+                .flat_map(|event| event.path.iter().chain(&event.path1).cloned())
+                .collect();
+            stat_cache::invalidate_trees(&paths).await;
+            pending_events.extend(events)
+        }
     }
 }
 
-fn fs_events_notification(events: &[WatchEvent]) -> Notification {
-    let events_value: Vec<Value> = events.iter().map(WatchEvent::to_value).collect();
+fn fs_events_notification(events: &[WatchEvent], names: NamesAs) -> Notification {
+    let events_value: Vec<Value> = events.iter().map(|e| e.to_value(names)).collect();
 
     Notification::new(
         "fs.events",
@@ -1098,20 +1342,51 @@ fn fs_events_notification(events: &[WatchEvent]) -> Notification {
     )
 }
 
-/// Serialize and send an `fs.events` notification over the stdout writer.
-/// Returns an error if serialization or writing fails.
-async fn send_notification(
+fn fs_resync_notification(roots: &[PathBuf], names: NamesAs) -> Notification {
+    use std::os::unix::ffi::OsStrExt;
+
+    Notification::new(
+        "fs.resync",
+        msgpack_map! {
+            "roots" => Value::Array(
+                roots
+                    .iter()
+                    .map(|root| names.encode(root.as_os_str().as_bytes()))
+                    .collect()
+            )
+        },
+    )
+}
+
+/// Send `events` as an `fs.events` notification.  If that is over the
+/// notification budget, send `fs.resync` naming the watch roots the events
+/// fall under instead, so the client invalidates those roots wholesale.
+/// Returns an error if writing fails.
+async fn send_events(
     writer: &WriterHandle,
+    manager: &Weak<WatchManager>,
     events: &[WatchEvent],
-) -> Result<(), Box<dyn std::error::Error>> {
-    let notification = fs_events_notification(events);
-
-    let bytes = rmp_serde::to_vec_named(&notification)?;
-    let mut w = writer.lock().await;
-    let len_bytes = (bytes.len() as u32).to_be_bytes();
-    w.write_all(&len_bytes).await?;
-    w.write_all(&bytes).await?;
-    w.flush().await?;
+) -> std::io::Result<()> {
+    let manager = manager.upgrade();
+    let names = manager.as_ref().map_or(NamesAs::Binary, |manager| {
+        *lock_or_recover(&manager.names_as)
+    });
+    let groups = match &manager {
+        Some(manager) => manager.group_by_trace(events),
+        None => vec![(None, events.to_vec())],
+    };
+    for (context, events) in groups {
+        let notification = fs_events_notification(&events, names).with_context(context.clone());
+        if notifications::send(writer, &notification).await? {
+            continue;
+        }
+        let roots = manager
+            .as_ref()
+            .map(|manager| manager.resync_roots(&events))
+            .unwrap_or_default();
+        let notification = fs_resync_notification(&roots, names).with_context(context);
+        notifications::send_forced(writer, &notification).await?;
+    }
     Ok(())
 }
 
@@ -1124,8 +1399,14 @@ use crate::handlers::HandlerResult;
 /// Handle `watch.add` - start watching a directory for changes.
 ///
 /// Params: { "path": "/path/to/dir", "recursive": true|false,
-/// "nofollow": true|false }
-pub fn handle_add(params: Value) -> HandlerResult {
+/// "nofollow": true|false, "names_as": "binary"|"auto" }
+///
+/// `names_as`, when given, sets how paths are encoded in all later
+/// `fs.events` and `fs.resync` notifications.
+///
+/// With `initial_snapshot`, the reply also holds the `snapshot` described
+/// at [`snapshot`], taken once the watch is in place.
+pub async fn handle_add(params: Value) -> HandlerResult {
     #[derive(serde::Deserialize)]
     struct Params {
         #[serde(with = "path_or_bytes")]
@@ -1134,15 +1415,28 @@ pub fn handle_add(params: Value) -> HandlerResult {
         recursive: bool,
         #[serde(default)]
         nofollow: bool,
+        #[serde(default)]
+        names_as: Option<String>,
+        #[serde(default)]
+        initial_snapshot: bool,
+        /// Include the attributes of each entry in the snapshot
+        #[serde(default)]
+        snapshot_attrs: bool,
     }
     fn default_recursive() -> bool {
         true
     }
 
     let params: Params = from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))?;
+    let names = params
+        .names_as
+        .as_deref()
+        .map(|names| NamesAs::parse(Some(names)))
+        .transpose()?;
 
     // `bytes_to_path` preserves the legacy ~ expansion used by watch paths.
     let path = bytes_to_path(&params.path);
+    jail::check(&path)?;
 
     let manager = get().ok_or_else(|| RpcError::internal_error("File watcher not available"))?;
 
@@ -1152,12 +1446,78 @@ pub fn handle_add(params: Value) -> HandlerResult {
         manager.watch(&path, params.recursive)
     }
     .map_err(|e| RpcError::internal_error(format!("Failed to watch: {}", e)))?;
+    manager.set_trace(&canonical, crate::trace::current());
+    if let Some(names) = names {
+        *lock_or_recover(&manager.names_as) = names;
+    }
 
-    Ok(msgpack_map! {
+    let mut result = msgpack_map! {
         "path" => path_to_value(&canonical),
         "recursive" => Value::Boolean(params.recursive),
         "nofollow" => Value::Boolean(params.nofollow)
+    };
+    if params.initial_snapshot {
+        let snapshot = snapshot(
+            &canonical,
+            params.nofollow,
+            params.snapshot_attrs,
+            params.names_as,
+        )
+        .await?;
+        if let Value::Map(ref mut fields) = result {
+            fields.push(("snapshot".into(), snapshot));
+        }
+    }
+    Ok(result)
+}
+
+/// The state of the newly watched `path` for `watch.add` with
+/// `initial_snapshot`.
+///
+/// A directory gives `{entries, total, generation, fingerprint}`: its
+/// listing as `dir.list {generation: true}` returns it, with the
+/// `dir.list {fingerprint_only: true}` fingerprint taken just before.
+/// Anything else gives `{attrs}`.  Since the watch is already in place,
+/// every change after the snapshot is reported, and so may be changes it
+/// already shows: delivery is at least once, and clients comparing the
+/// `generation` or `fingerprint` can tell what they have seen.
+async fn snapshot(
+    path: &Path,
+    nofollow: bool,
+    attrs: bool,
+    names_as: Option<String>,
+) -> HandlerResult {
+    use crate::handlers::{dir, file};
+
+    let is_dir = if nofollow {
+        std::fs::symlink_metadata(path)
+    } else {
+        std::fs::metadata(path)
+    }
+    .is_ok_and(|meta| meta.is_dir());
+    if !is_dir {
+        let attrs = file::get_file_attributes(path, nofollow).await?;
+        return Ok(msgpack_map! { "attrs" => attrs.to_value() });
+    }
+
+    let path_value = path_to_value(path);
+    let fingerprint = dir::list(msgpack_map! {
+        "path" => path_value.clone(),
+        "fingerprint_only" => true
+    })
+    .await?;
+    let names_as = names_as.map_or(Value::Nil, Value::from);
+    let mut listing = dir::list(msgpack_map! {
+        "path" => path_value,
+        "include_attrs" => attrs,
+        "generation" => true,
+        "names_as" => names_as
     })
+    .await?;
+    if let Value::Map(ref mut fields) = listing {
+        fields.push(("fingerprint".into(), fingerprint["fingerprint"].clone()));
+    }
+    Ok(listing)
 }
 
 /// Handle `watch.remove` - stop watching a directory.
@@ -1193,10 +1553,11 @@ pub fn handle_list(_params: Value) -> HandlerResult {
     let watches: Vec<Value> = manager
         .list()
         .into_iter()
-        .map(|(path, recursive)| {
+        .map(|(path, recursive, directories)| {
             msgpack_map! {
                 "path" => path_to_value(&path),
-                "recursive" => Value::Boolean(recursive)
+                "recursive" => Value::Boolean(recursive),
+                "directories" => directories as u64
             }
         })
         .collect();
@@ -1220,6 +1581,8 @@ mod tests {
             watcher: Mutex::new(FilteredWatcher::new(|_: notify::Result<Event>| {}).unwrap()),
             watched_paths: Mutex::new(HashMap::new()),
             symlink_watcher: Mutex::new(NofollowSymlinkWatcher::new(tx).ok()),
+            names_as: Mutex::new(NamesAs::Binary),
+            traces: Mutex::new(HashMap::new()),
         }
     }
 
@@ -1233,6 +1596,123 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_create_drops_missing_entry_before_the_window_closes() {
+        let temp = tempfile::tempdir().unwrap();
+        let file = temp.path().join("created");
+        let generation = stat_cache::parent_generation(&file);
+        stat_cache::insert_missing(&file, true, generation);
+        assert!(matches!(
+            stat_cache::lookup(&file, true),
+            Some(stat_cache::Lookup::Missing)
+        ));
+
+        // A stat racing with the creation, whose miss is cached only after
+        // the watcher has seen the event.
+        let racing = stat_cache::parent_generation(&file);
+        fs::write(&file, "").unwrap();
+        let mut pending = Vec::new();
+        collect_input(
+            WatchInput::Notify(
+                Event::new(EventKind::Create(CreateKind::File)).add_path(file.clone()),
+            ),
+            &Weak::new(),
+            &mut pending,
+            &mut HashSet::new(),
+            &mut HashSet::new(),
+        )
+        .await;
+        stat_cache::insert_missing(&file, true, racing);
+
+        // The notification is still pending, but the cache already knows.
+        assert_eq!(pending, [WatchEvent::path("created", file.clone())]);
+        assert!(stat_cache::lookup(&file, true).is_none());
+        assert!(stat_cache::generation(temp.path()) > generation);
+    }
+
+    #[test]
+    fn test_resync_roots_cover_events() {
+        let manager = test_manager();
+        {
+            let mut paths = lock_or_recover(&manager.watched_paths);
+            paths.insert(PathBuf::from("/w"), RecursiveMode::Recursive);
+            paths.insert(PathBuf::from("/w/sub"), RecursiveMode::NonRecursive);
+            paths.insert(PathBuf::from("/v"), RecursiveMode::NonRecursive);
+        }
+
+        let roots = manager.resync_roots(&[
+            WatchEvent::path("created", PathBuf::from("/w/a")),
+            WatchEvent::path("changed", PathBuf::from("/w/sub/b")),
+            WatchEvent::rename(PathBuf::from("/w/c"), PathBuf::from("/elsewhere/c")),
+        ]);
+        assert_eq!(
+            roots,
+            [
+                PathBuf::from("/elsewhere/c"),
+                PathBuf::from("/w"),
+                PathBuf::from("/w/sub")
+            ]
+        );
+        assert_eq!(manager.resync_roots(&[WatchEvent::rescan()]).len(), 3);
+
+        let notification = fs_resync_notification(&roots, NamesAs::Binary);
+        assert_eq!(notification.method, "fs.resync");
+        assert_eq!(
+            map_value(&notification.params, "roots")
+                .and_then(Value::as_array)
+                .map(Vec::len),
+            Some(3)
+        );
+    }
+
+    #[test]
+    fn test_events_are_grouped_by_the_trace_of_their_watch() {
+        let manager = test_manager();
+        {
+            let mut paths = lock_or_recover(&manager.watched_paths);
+            paths.insert(PathBuf::from("/w"), RecursiveMode::Recursive);
+            paths.insert(PathBuf::from("/w/sub"), RecursiveMode::NonRecursive);
+            paths.insert(PathBuf::from("/v"), RecursiveMode::NonRecursive);
+        }
+        let events = [
+            WatchEvent::path("created", PathBuf::from("/w/a")),
+            WatchEvent::path("changed", PathBuf::from("/w/sub/b")),
+            WatchEvent::path("deleted", PathBuf::from("/v/c")),
+        ];
+        // Without traces nothing is split
+        assert_eq!(manager.group_by_trace(&events), [(None, events.to_vec())]);
+
+        manager.set_trace(Path::new("/w"), Some(Value::from("outer")));
+        manager.set_trace(Path::new("/w/sub"), Some(Value::from(7)));
+        let groups = manager.group_by_trace(&events);
+        assert_eq!(groups.len(), 3);
+        let context = |group: &(Option<Value>, Vec<WatchEvent>)| {
+            group.0.as_ref().map(|context| {
+                (
+                    map_value(context, "trace").cloned().unwrap(),
+                    map_value(context, "resource").cloned().unwrap(),
+                    map_value(context, "id").cloned().unwrap(),
+                )
+            })
+        };
+        assert_eq!(
+            context(&groups[0]),
+            Some((
+                "outer".into(),
+                "watch".into(),
+                path_to_value(Path::new("/w"))
+            ))
+        );
+        assert_eq!(groups[0].1, [events[0].clone()]);
+        assert_eq!(context(&groups[1]).unwrap().0, Value::from(7));
+        assert_eq!(groups[1].1, [events[1].clone()]);
+        assert_eq!(groups[2], (None, vec![events[2].clone()]));
+
+        // Watching again without a trace forgets it
+        manager.set_trace(Path::new("/w/sub"), None);
+        assert_eq!(manager.group_by_trace(&events).len(), 2);
+    }
+
     #[test]
     fn test_watch_event_mapping_basic_actions() {
         let path = PathBuf::from("/tmp/file");
@@ -1371,7 +1851,7 @@ mod tests {
     #[test]
     fn test_watch_event_serializes_paths_as_binary() {
         let event = WatchEvent::rename(PathBuf::from("/tmp/old"), PathBuf::from("/tmp/new"));
-        let value = event.to_value();
+        let value = event.to_value(NamesAs::Binary);
 
         assert_eq!(
             map_value(&value, "action"),
@@ -1385,6 +1865,19 @@ mod tests {
             map_value(&value, "path1"),
             Some(&Value::Binary(b"/tmp/new".to_vec()))
         );
+
+        // "auto" sends UTF-8 paths as strings and keeps the rest binary
+        use std::os::unix::ffi::OsStrExt;
+        let odd = PathBuf::from(std::ffi::OsStr::from_bytes(b"/tmp/\xff"));
+        let value = WatchEvent::rename(PathBuf::from("/tmp/old"), odd).to_value(NamesAs::Auto);
+        assert_eq!(
+            map_value(&value, "path"),
+            Some(&Value::String("/tmp/old".into()))
+        );
+        assert_eq!(
+            map_value(&value, "path1"),
+            Some(&Value::Binary(b"/tmp/\xff".to_vec()))
+        );
     }
 
     #[test]
@@ -1395,11 +1888,29 @@ mod tests {
     }
 
     #[test]
-    fn test_fs_events_notification_envelope() {
-        let notification = fs_events_notification(&[
-            WatchEvent::path("created", PathBuf::from("/tmp/new")),
-            WatchEvent::rescan(),
+    fn test_changes_touch_paths_their_entries_and_parents() {
+        let changes = Changes::of(&[
+            WatchEvent::path("modified", PathBuf::from("/w/dir/file")),
+            WatchEvent::rename(PathBuf::from("/w/old"), PathBuf::from("/w/new")),
         ]);
+        assert!(changes.touch(Path::new("/w/dir/file")));
+        assert!(changes.touch(Path::new("/w/dir")));
+        assert!(changes.touch(Path::new("/w/old/below")));
+        assert!(changes.touch(Path::new("/w/new")));
+        assert!(!changes.touch(Path::new("/w/dir/other")));
+        assert!(!changes.touch(Path::new("/w/elsewhere")));
+        assert!(Changes::of(&[WatchEvent::rescan()]).touch(Path::new("/anything")));
+    }
+
+    #[test]
+    fn test_fs_events_notification_envelope() {
+        let notification = fs_events_notification(
+            &[
+                WatchEvent::path("created", PathBuf::from("/tmp/new")),
+                WatchEvent::rescan(),
+            ],
+            NamesAs::Binary,
+        );
 
         assert_eq!(notification.version, "2.0");
         assert_eq!(notification.method, "fs.events");
@@ -1422,6 +1933,40 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn test_snapshots_list_directories_and_stat_files() {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path().canonicalize().unwrap();
+        fs::write(root.join("one"), "1").unwrap();
+        fs::write(root.join("two"), "22").unwrap();
+
+        let listing = snapshot(&root, false, true, Some("auto".into()))
+            .await
+            .unwrap();
+        let entries = map_value(&listing, "entries").unwrap().as_array().unwrap();
+        // "." and ".." included, as dir.list has them
+        assert_eq!(entries.len(), 4);
+        assert!(entries.iter().all(|entry| entry["attrs"].is_map()));
+        assert!(
+            entries
+                .iter()
+                .any(|entry| entry["name"].as_str() == Some("two"))
+        );
+        assert!(map_value(&listing, "generation").unwrap().is_u64());
+        let fingerprint = crate::handlers::dir::list(msgpack_map! {
+            "path" => path_to_value(&root),
+            "fingerprint_only" => true
+        })
+        .await
+        .unwrap();
+        assert_eq!(listing["fingerprint"], fingerprint["fingerprint"]);
+
+        let file = snapshot(&root.join("two"), false, false, None)
+            .await
+            .unwrap();
+        assert_eq!(file["attrs"]["size"].as_u64(), Some(2));
+    }
+
     fn refresh_for_event(manager: &WatchManager, event: &Event) {
         let roots = manager.recursive_roots_for_event(event);
         manager.refresh_recursive_roots(roots);
@@ -1893,6 +2438,8 @@ mod tests {
             ),
             watched_paths: Mutex::new(HashMap::new()),
             symlink_watcher: Mutex::new(None),
+            names_as: Mutex::new(NamesAs::Binary),
+            traces: Mutex::new(HashMap::new()),
         };
         manager.watch(&root, true).unwrap();
 
@@ -1933,6 +2480,65 @@ mod tests {
         manager.unwatch(&root).unwrap();
     }
 
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_directories_created_below_recursive_watches_are_watched() {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path().canonicalize().unwrap();
+        let top = root.join("a");
+        let deepest = top.join("b/c");
+
+        let (tx, rx) = std_mpsc::channel();
+        let manager = WatchManager {
+            watcher: Mutex::new(
+                FilteredWatcher::new(move |event: notify::Result<Event>| {
+                    if let Ok(event) = event {
+                        let _ = tx.send(event);
+                    }
+                })
+                .unwrap(),
+            ),
+            watched_paths: Mutex::new(HashMap::new()),
+            symlink_watcher: Mutex::new(None),
+            names_as: Mutex::new(NamesAs::Binary),
+            traces: Mutex::new(HashMap::new()),
+        };
+        manager.watch(&root, true).unwrap();
+        assert_eq!(manager.directory_count(), 1);
+
+        // Only the creation of a is seen; b, c and early land before their
+        // parents are watched
+        fs::create_dir_all(&deepest).unwrap();
+        fs::write(deepest.join("early"), "").unwrap();
+        let create_event = Event::new(EventKind::Create(CreateKind::Folder)).add_path(top.clone());
+        let pending = event_to_watch_events(&create_event);
+        let roots = manager.recursive_roots_for_event(&create_event);
+        let mut new_dirs = manager.refresh_recursive_roots(roots);
+        new_dirs.sort();
+        assert_eq!(new_dirs, vec![top.clone(), top.join("b"), deepest.clone()]);
+
+        let missed: Vec<_> = entries_of_new_dirs(&new_dirs, &pending)
+            .into_iter()
+            .filter_map(|event| event.path)
+            .collect();
+        assert_eq!(
+            missed,
+            vec![top.join("b"), deepest.clone(), deepest.join("early")]
+        );
+        assert_eq!(manager.list(), vec![(root.clone(), true, 4)]);
+
+        std::thread::sleep(Duration::from_millis(100));
+        drain_events(&rx);
+        fs::write(deepest.join("late"), "").unwrap();
+        recv_event_matching(&rx, Duration::from_secs(2), |event| {
+            event.paths.contains(&deepest.join("late"))
+        });
+
+        // The implicit children go with their root
+        manager.unwatch(&root).unwrap();
+        assert_eq!(manager.directory_count(), 0);
+    }
+
     #[cfg(target_os = "linux")]
     #[test]
     fn test_coalesced_rename_into_place_within_window_rewatches_for_real_events() {
@@ -1960,6 +2566,8 @@ mod tests {
             ),
             watched_paths: Mutex::new(HashMap::new()),
             symlink_watcher: Mutex::new(None),
+            names_as: Mutex::new(NamesAs::Binary),
+            traces: Mutex::new(HashMap::new()),
         };
         manager.watch(&root, true).unwrap();
 
@@ -2009,7 +2617,10 @@ mod tests {
         let watched = manager.watch(&link, false).unwrap();
 
         assert_eq!(watched, real.canonicalize().unwrap());
-        assert_eq!(manager.list(), vec![(real.canonicalize().unwrap(), false)]);
+        assert_eq!(
+            manager.list(),
+            vec![(real.canonicalize().unwrap(), false, 1)]
+        );
     }
 
     #[test]
@@ -0,0 +1,145 @@
+//! Per-path write serialization.
+//!
+//! Requests run concurrently, so two `file.write` calls to one file could
+//! otherwise interleave their truncates and writes and leave a mix of both.
+//! Every handler that replaces or modifies a file's content holds the lock
+//! of that path while doing so; writes to different paths stay parallel.
+//! Readers only take it when asked to, by `file.read {consistent_read}`.
+//!
+//! Paths are keyed by their canonical parent directory plus their name, so
+//! `a/../f` and `f` share a lock while a symlink and its target do not.  An
+//! entry lives only as long as someone holds or waits for its lock.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock, Mutex, Weak};
+use tokio::sync::OwnedMutexGuard;
+
+type PathMutex = tokio::sync::Mutex<()>;
+
+static LOCKS: LazyLock<Mutex<HashMap<PathBuf, Weak<PathMutex>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Holds the write lock of a path until dropped.
+pub struct WriteGuard {
+    key: PathBuf,
+    guard: Option<OwnedMutexGuard<()>>,
+}
+
+impl Drop for WriteGuard {
+    fn drop(&mut self) {
+        // Release first, so the entry is dead unless someone else waits
+        self.guard.take();
+        remove_if_unused(&self.key);
+    }
+}
+
+/// Drop the entry of `key` unless someone holds or waits for its lock.
+fn remove_if_unused(key: &Path) {
+    let mut locks = LOCKS.lock().unwrap_or_else(|e| e.into_inner());
+    if locks.get(key).is_some_and(|lock| lock.strong_count() == 0) {
+        locks.remove(key);
+    }
+}
+
+/// Removes the entry of a lock whose waiter is dropped before getting it.
+/// Declared before the wait, so the waiting future goes first.
+struct Waiting<'a>(&'a Path);
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        remove_if_unused(self.0);
+    }
+}
+
+/// The registry key of `path`.
+async fn key(path: &Path) -> PathBuf {
+    let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+        return path.to_path_buf();
+    };
+    let parent = if parent.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        parent
+    };
+    match tokio::fs::canonicalize(parent).await {
+        Ok(parent) => parent.join(name),
+        Err(_) => path.to_path_buf(),
+    }
+}
+
+/// Wait for the write lock of `path`.
+pub async fn lock(path: &Path) -> WriteGuard {
+    let key = key(path).await;
+    let mutex = {
+        let mut locks = LOCKS.lock().unwrap_or_else(|e| e.into_inner());
+        match locks.get(&key).and_then(Weak::upgrade) {
+            Some(mutex) => mutex,
+            None => {
+                let mutex = Arc::new(PathMutex::new(()));
+                locks.insert(key.clone(), Arc::downgrade(&mutex));
+                mutex
+            }
+        }
+    };
+    let guard = {
+        let _waiting = Waiting(&key);
+        mutex.lock_owned().await
+    };
+    WriteGuard {
+        key,
+        guard: Some(guard),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn writes_to_one_path_serialize_and_entries_go_away() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("file");
+        let alias = tmp.path().join("sub/../file");
+        std::fs::create_dir(tmp.path().join("sub")).unwrap();
+
+        let held = lock(&path).await;
+        assert_eq!(key(&alias).await, key(&path).await);
+        // The same file under another spelling waits
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), lock(&alias))
+                .await
+                .is_err()
+        );
+        // Another file does not
+        let other = lock(&tmp.path().join("other")).await;
+        drop(other);
+        drop(held);
+        drop(lock(&alias).await);
+
+        let key = key(&path).await;
+        assert!(!LOCKS.lock().unwrap().contains_key(&key));
+    }
+
+    #[tokio::test]
+    async fn abandoned_waiters_leave_no_entry() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("file");
+        let key = key(&path).await;
+
+        let held = lock(&path).await;
+        let mut waiting = Box::pin(lock(&path));
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), waiting.as_mut())
+                .await
+                .is_err()
+        );
+        // The waiter keeps the entry alive past the holder...
+        drop(held);
+        assert!(LOCKS.lock().unwrap().contains_key(&key));
+        // ...but not past itself
+        drop(waiting);
+        assert!(!LOCKS.lock().unwrap().contains_key(&key));
+    }
+}
@@ -0,0 +1,50 @@
+//! The server must exit once nobody reads its replies, even while stdin
+//! stays open.
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+fn frame(method: &str, id: u64) -> Vec<u8> {
+    let request = rmpv::Value::Map(vec![
+        ("version".into(), "2.0".into()),
+        ("id".into(), id.into()),
+        ("method".into(), method.into()),
+        ("params".into(), rmpv::Value::Map(vec![])),
+    ]);
+    let mut payload = Vec::new();
+    rmpv::encode::write_value(&mut payload, &request).unwrap();
+    let mut frame = (payload.len() as u32).to_be_bytes().to_vec();
+    frame.extend(payload);
+    frame
+}
+
+#[test]
+fn exits_when_stdout_is_closed() {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_tramp-rpc-server"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("start server");
+    drop(child.stdout.take());
+    let mut stdin = child.stdin.take().unwrap();
+
+    // The reply to this goes into the closed pipe
+    stdin.write_all(&frame("system.stats", 1)).unwrap();
+    stdin.flush().unwrap();
+
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let status = loop {
+        if let Some(status) = child.try_wait().unwrap() {
+            break status;
+        }
+        if Instant::now() > deadline {
+            let _ = child.kill();
+            panic!("server still running 10s after its stdout was closed");
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    };
+    assert!(!status.success());
+    drop(stdin);
+}
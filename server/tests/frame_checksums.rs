@@ -0,0 +1,111 @@
+//! With `frame_crc32c` negotiated, frames carry checksums both ways and a
+//! corrupted one is never served.
+
+use std::io::{Read, Write};
+use std::process::{Command, Stdio};
+
+const CHECKED: u32 = 1 << 31;
+const CASTAGNOLI: crc::Crc<u32> = crc::Crc::<u32>::new(&crc::CRC_32_ISCSI);
+
+fn payload(method: &str, id: Option<u64>, params: rmpv::Value) -> Vec<u8> {
+    let mut fields = vec![
+        ("version".into(), "2.0".into()),
+        ("method".into(), method.into()),
+        ("params".into(), params),
+    ];
+    if let Some(id) = id {
+        fields.push(("id".into(), id.into()));
+    }
+    let mut payload = Vec::new();
+    rmpv::encode::write_value(&mut payload, &rmpv::Value::Map(fields)).unwrap();
+    payload
+}
+
+fn checked_frame(payload: &[u8], checksum: u32) -> Vec<u8> {
+    let mut frame = (payload.len() as u32 | CHECKED).to_be_bytes().to_vec();
+    frame.extend(payload);
+    frame.extend(checksum.to_be_bytes());
+    frame
+}
+
+/// Read one frame, returning its message and whether it was checked (and
+/// its checksum right).
+fn read_frame(stdout: &mut impl Read) -> (rmpv::Value, bool) {
+    let mut prefix = [0u8; 4];
+    stdout.read_exact(&mut prefix).unwrap();
+    let prefix = u32::from_be_bytes(prefix);
+    let mut payload = vec![0u8; (prefix & !CHECKED) as usize];
+    stdout.read_exact(&mut payload).unwrap();
+    let checked = prefix & CHECKED != 0;
+    if checked {
+        let mut checksum = [0u8; 4];
+        stdout.read_exact(&mut checksum).unwrap();
+        assert_eq!(u32::from_be_bytes(checksum), CASTAGNOLI.checksum(&payload));
+    }
+    let value = rmpv::decode::read_value(&mut &payload[..]).unwrap();
+    (value, checked)
+}
+
+#[test]
+fn corrupted_frames_are_rejected() {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_tramp-rpc-server"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("start server");
+    let mut stdin = child.stdin.take().unwrap();
+    let mut stdout = child.stdout.take().unwrap();
+
+    // The hello itself goes unchecked; its reply is checked already
+    let hello = payload(
+        "system.hello",
+        Some(1),
+        rmpv::Value::Map(vec![(
+            "features".into(),
+            rmpv::Value::Array(vec!["frame_crc32c".into()]),
+        )]),
+    );
+    let mut frame = (hello.len() as u32).to_be_bytes().to_vec();
+    frame.extend(&hello);
+    stdin.write_all(&frame).unwrap();
+    let (reply, checked) = read_frame(&mut stdout);
+    assert!(checked);
+    assert_eq!(
+        reply["result"]["features"],
+        rmpv::Value::Array(vec!["frame_crc32c".into()])
+    );
+
+    let stats = payload("system.stats", Some(2), rmpv::Value::Map(vec![]));
+    stdin
+        .write_all(&checked_frame(&stats, CASTAGNOLI.checksum(&stats)))
+        .unwrap();
+    let (reply, _) = read_frame(&mut stdout);
+    assert_eq!(reply["id"].as_u64(), Some(2));
+    assert!(reply["result"].is_map());
+
+    // A bad checksum with a readable id gets a parse error
+    let mut corrupted = payload("system.stats", Some(3), rmpv::Value::Map(vec![]));
+    let checksum = CASTAGNOLI.checksum(&corrupted);
+    // In the "version" key, leaving the id readable
+    corrupted[2] ^= 1;
+    stdin
+        .write_all(&checked_frame(&corrupted, checksum))
+        .unwrap();
+    let (reply, _) = read_frame(&mut stdout);
+    assert_eq!(reply["id"].as_u64(), Some(3));
+    assert_eq!(reply["error"]["code"].as_i64(), Some(-32700));
+
+    // Without one the connection ends
+    let anonymous = payload("system.stats", None, rmpv::Value::Map(vec![]));
+    stdin.write_all(&checked_frame(&anonymous, 0)).unwrap();
+    let (notification, _) = read_frame(&mut stdout);
+    assert_eq!(notification["method"].as_str(), Some("transport.error"));
+    assert_eq!(
+        notification["params"]["reason"].as_str(),
+        Some("checksum_mismatch")
+    );
+    let status = child.wait().unwrap();
+    assert!(!status.success());
+    drop(stdin);
+}
@@ -0,0 +1,85 @@
+//! A jailed server lists symlinks that point out of the jail without
+//! reporting anything about their targets.
+
+use std::io::{Read, Write};
+use std::process::{Command, Stdio};
+
+fn request(method: &str, id: u64, params: rmpv::Value) -> Vec<u8> {
+    let request = rmpv::Value::Map(vec![
+        ("version".into(), "2.0".into()),
+        ("id".into(), id.into()),
+        ("method".into(), method.into()),
+        ("params".into(), params),
+    ]);
+    let mut payload = Vec::new();
+    rmpv::encode::write_value(&mut payload, &request).unwrap();
+    let mut frame = (payload.len() as u32).to_be_bytes().to_vec();
+    frame.extend(payload);
+    frame
+}
+
+fn read_reply(stdout: &mut impl Read) -> rmpv::Value {
+    let mut prefix = [0u8; 4];
+    stdout.read_exact(&mut prefix).unwrap();
+    let mut payload = vec![0u8; u32::from_be_bytes(prefix) as usize];
+    stdout.read_exact(&mut payload).unwrap();
+    rmpv::decode::read_value(&mut &payload[..]).unwrap()
+}
+
+#[test]
+fn listings_do_not_follow_links_out_of_the_jail() {
+    let tmp = tempfile::tempdir().unwrap();
+    let jail = tmp.path().join("jail");
+    std::fs::create_dir(&jail).unwrap();
+    std::fs::write(tmp.path().join("secret"), b"outside").unwrap();
+    std::fs::write(jail.join("inside"), b"in").unwrap();
+    std::os::unix::fs::symlink("../secret", jail.join("escape")).unwrap();
+    std::os::unix::fs::symlink("inside", jail.join("local")).unwrap();
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_tramp-rpc-server"))
+        .arg("--jail")
+        .arg(&jail)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("start server");
+    let mut stdin = child.stdin.take().unwrap();
+    let mut stdout = child.stdout.take().unwrap();
+
+    for lstat in [true, false] {
+        let params = rmpv::Value::Map(vec![
+            ("path".into(), jail.to_str().unwrap().into()),
+            ("include_attrs".into(), true.into()),
+            ("lstat".into(), lstat.into()),
+            ("resolve_symlinks".into(), "full".into()),
+        ]);
+        stdin.write_all(&request("dir.list", 1, params)).unwrap();
+        let reply = read_reply(&mut stdout);
+        let entries = reply["result"].as_array().expect("entries");
+        let entry = |name: &str| {
+            entries
+                .iter()
+                .find(|e| e["name"].as_slice() == Some(name.as_bytes()))
+                .unwrap_or_else(|| panic!("no {} in {:?}", name, entries))
+        };
+        let field = |entry: &rmpv::Value, key: &str| {
+            entry
+                .as_map()
+                .unwrap()
+                .iter()
+                .find(|(k, _)| k.as_str() == Some(key))
+                .map(|(_, v)| v.clone())
+        };
+
+        let escape = entry("escape");
+        assert!(field(escape, "target_attrs").is_none(), "{:?}", escape);
+        // Its own attributes: the size of the link, not of the secret
+        assert_eq!(escape["attrs"]["size"].as_u64(), Some(9));
+        let local = entry("local");
+        assert_eq!(local["target_attrs"]["size"].as_u64(), Some(2));
+    }
+
+    drop(stdin);
+    child.wait().unwrap();
+}
@@ -0,0 +1,89 @@
+//! Handler panics become error replies only when the shipped binary unwinds,
+//! so the release profiles must keep `panic = "unwind"`.
+
+use std::io::{Read, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+fn workspace_manifest() -> toml::Table {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("../Cargo.toml");
+    std::fs::read_to_string(path).unwrap().parse().unwrap()
+}
+
+/// The `panic` setting of `profile`, following `inherits`.
+fn panic_strategy(profiles: &toml::Table, profile: &str) -> String {
+    let settings = profiles.get(profile).and_then(|p| p.as_table());
+    let setting = settings
+        .and_then(|s| s.get("panic"))
+        .and_then(|p| p.as_str());
+    let parent = settings
+        .and_then(|s| s.get("inherits"))
+        .and_then(|p| p.as_str());
+    match (setting, parent) {
+        (Some(setting), _) => setting.to_string(),
+        (None, Some(parent)) => panic_strategy(profiles, parent),
+        (None, None) => "unwind".to_string(),
+    }
+}
+
+#[test]
+fn release_profiles_unwind() {
+    let manifest = workspace_manifest();
+    let profiles = manifest["profile"].as_table().unwrap();
+    for profile in profiles.keys() {
+        assert_eq!(panic_strategy(profiles, profile), "unwind", "{}", profile);
+    }
+    assert_eq!(panic_strategy(profiles, "release"), "unwind");
+}
+
+/// Build with the release profile's panic strategy (but without its slow
+/// optimizations) and ask the binary which one it got.  Run with
+/// `cargo test -- --ignored`.
+#[test]
+#[ignore = "builds the server again"]
+fn release_build_unwinds() {
+    let manifest_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let target_dir = manifest_dir.join("../target/release-panic");
+    let status = Command::new(env!("CARGO"))
+        .args(["build", "--release", "--bin", "tramp-rpc-server"])
+        .args(["--config", "profile.release.lto=false"])
+        .args(["--config", "profile.release.opt-level=0"])
+        .args(["--config", "profile.release.codegen-units=16"])
+        .arg("--target-dir")
+        .arg(&target_dir)
+        .current_dir(manifest_dir)
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let mut child = Command::new(target_dir.join("release/tramp-rpc-server"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("start server");
+    let mut stdin = child.stdin.take().unwrap();
+    let mut stdout = child.stdout.take().unwrap();
+
+    let request = rmpv::Value::Map(vec![
+        ("version".into(), "2.0".into()),
+        ("id".into(), 1.into()),
+        ("method".into(), "system.info".into()),
+        ("params".into(), rmpv::Value::Map(vec![])),
+    ]);
+    let mut payload = Vec::new();
+    rmpv::encode::write_value(&mut payload, &request).unwrap();
+    let mut frame = (payload.len() as u32).to_be_bytes().to_vec();
+    frame.extend(payload);
+    stdin.write_all(&frame).unwrap();
+
+    let mut prefix = [0u8; 4];
+    stdout.read_exact(&mut prefix).unwrap();
+    let mut reply = vec![0u8; u32::from_be_bytes(prefix) as usize];
+    stdout.read_exact(&mut reply).unwrap();
+    let reply = rmpv::decode::read_value(&mut &reply[..]).unwrap();
+    assert_eq!(reply["result"]["build"]["panic"].as_str(), Some("unwind"));
+
+    drop(stdin);
+    child.wait().unwrap();
+}